@@ -1,21 +1,15 @@
-use clap::Parser;
 use common::metrics::process_unix::ProcessRecorder;
 use common::ShutdownMessage;
-use proxy::backend::backend_mgr::get_or_init_backend_mgr;
-use proxy::backend::router::new_backend_router;
-use proxy::cp;
-use proxy::cp::active_users::UserActivityWindow;
-use proxy::server::auth::authenticator::ProxyAuthenticator;
-use proxy::server::haentgl_server::HaentglServer;
-use proxy::server::proxy_cli_args::ProxyServerArgs;
+use proxy::server::proxy_cli_args::{BackendConfigArgs, ProxyServerArgs};
+use proxy::server::proxy_config::{config_schema, load_proxy_config};
+use proxy::server::reload::{config_reloader, run_reload_on_hangup, RuntimeConfig};
+use proxy::server::ProxyRuntime;
 use std::str::FromStr;
-use std::sync::Arc;
-use tokio::net::TcpListener;
+use std::time::Duration;
 use tokio::runtime::Runtime;
-use tokio::sync::watch;
 use tokio::sync::watch::Receiver;
 use tracing::{info, warn, Level};
-use tracing_subscriber::EnvFilter;
+use tracing_subscriber::{reload, EnvFilter};
 use web_service::http_server::HaentglProxyRestState;
 
 #[cfg(unix)]
@@ -58,39 +52,44 @@ async fn shutdown_signal() -> ShutdownMessage {
     }
 }
 
-#[allow(dead_code)]
-async fn start_cp_target(
-    proxy_config: ProxyServerArgs,
-    shutdown_rx: &Receiver<ShutdownMessage>,
-) -> Option<Arc<UserActivityWindow>> {
-    let cp_args = proxy_config.cp_args;
-    if cp_args.enable_cp {
-        let rpc_server_addr = cp_args.cp_listen_addr().unwrap();
-        let shutdown_rx_clone = Box::new(shutdown_rx.clone());
-        let active_users = Arc::new(UserActivityWindow::default());
-        let borrow_moved_active_users = Arc::clone(&active_users);
-        cp::start_cp_target_reporter(
-            borrow_moved_active_users,
-            rpc_server_addr,
-            shutdown_rx_clone,
-        )
-        .await;
-        Some(active_users)
-    } else {
-        None
+fn log_filter(level: Level) -> EnvFilter {
+    // setup tracing, disable grpc debug log.
+    EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| EnvFilter::new("DEBUG,hyper=INFO,tower=INFO,h2=INFO,chitchat=INFO"))
+        .add_directive(level.into())
+        .add_directive("hyper=INFO".parse().unwrap())
+        .add_directive("h2=INFO".parse().unwrap())
+        .add_directive("tower=INFO".parse().unwrap())
+        .add_directive("chitchat=INFO".parse().unwrap())
+}
+
+/// Applies the log level and the slow query threshold of every configuration reload.
+async fn apply_runtime_config<S>(
+    log_filter_handle: reload::Handle<EnvFilter, S>,
+    mut runtime_rx: Receiver<RuntimeConfig>,
+) {
+    while runtime_rx.changed().await.is_ok() {
+        let runtime_config = runtime_rx.borrow_and_update().clone();
+        let level = runtime_config.log_level.as_deref().unwrap_or("DEBUG");
+        match Level::from_str(level) {
+            Ok(level) => {
+                if let Err(e) = log_filter_handle.reload(log_filter(level)) {
+                    warn!("ProxySrv log level not reloaded. cause by {e:?}");
+                }
+            }
+            Err(e) => warn!("ProxySrv log level {level} not reloaded. cause by {e:?}"),
+        }
+        proxy::server::slow_log::slow_query_log()
+            .set_threshold(Duration::from_millis(runtime_config.slow_query_ms));
     }
 }
 
-fn start_metrics_and_rest(
-    proxy_config: ProxyServerArgs,
+fn start_metrics(
+    proxy_config: &ProxyServerArgs,
     runtime: &Runtime,
-    app_state: HaentglProxyRestState,
     shutdown_rx: &Receiver<ShutdownMessage>,
 ) {
-    let http_port = proxy_config.http_port;
     if proxy_config.enable_metrics {
-        let app_state_cloned = app_state.clone();
-
         common::metrics::init_metrics_context();
         let mut process_recorder = ProcessRecorder::new(
             common::metrics::common_labels().clone(),
@@ -99,12 +98,26 @@ fn start_metrics_and_rest(
         runtime.spawn(async move {
             process_recorder.start_auto_collect().await;
         });
+    }
+}
+
+fn start_rest(
+    proxy_config: ProxyServerArgs,
+    runtime: &Runtime,
+    app_state: HaentglProxyRestState,
+    shutdown_rx: &Receiver<ShutdownMessage>,
+) {
+    let http_port = proxy_config.http_port;
+    let enable_fault_injection = proxy_config.enable_fault_injection;
+    if proxy_config.enable_metrics {
+        let app_state_cloned = app_state.clone();
         let shutdown_rx_clone = Box::new(shutdown_rx.clone());
         runtime.spawn(async move {
             web_service::http_server::HaentglProxyRest::start_server(
                 "0.0.0.0".to_string(),
                 http_port,
                 true,
+                enable_fault_injection,
                 app_state_cloned,
                 shutdown_await(shutdown_rx_clone),
             )
@@ -118,6 +131,7 @@ fn start_metrics_and_rest(
                 "0.0.0.0".to_string(),
                 http_port,
                 false,
+                enable_fault_injection,
                 app_state,
                 shutdown_await(shutdown_rx_clone),
             )
@@ -127,24 +141,29 @@ fn start_metrics_and_rest(
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let proxy_config = ProxyServerArgs::parse();
+    let proxy_config = load_proxy_config().unwrap_or_else(|e| {
+        eprintln!("{e}");
+        std::process::exit(2);
+    });
+    if proxy_config.print_config_schema {
+        println!("{:#}", config_schema()?);
+        return Ok(());
+    }
+    if let Some(BackendConfigArgs::EncodeTenant(encode_args)) = &proxy_config.backend {
+        println!("{}", encode_args.username()?);
+        return Ok(());
+    }
     let log_level_string = proxy_config
         .log_level
         .clone()
         .unwrap_or("DEBUG".to_string());
     let level = Level::from_str(log_level_string.as_str())?;
-    // setup tracing, disable grpc debug log.
-    let filter = EnvFilter::try_from_default_env()
-        .unwrap_or_else(|_| EnvFilter::new("DEBUG,hyper=INFO,tower=INFO,h2=INFO,chitchat=INFO"))
-        .add_directive(level.into())
-        .add_directive("hyper=INFO".parse().unwrap())
-        .add_directive("h2=INFO".parse().unwrap())
-        .add_directive("tower=INFO".parse().unwrap())
-        .add_directive("chitchat=INFO".parse().unwrap());
-    tracing_subscriber::fmt()
-        .with_env_filter(filter)
-        .with_line_number(true)
-        .init();
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(log_filter(level))
+        .with_filter_reloading()
+        .with_line_number(true);
+    let log_filter_handle = subscriber.reload_handle();
+    subscriber.init();
 
     let works = proxy_config.works;
     let runtime = tokio::runtime::Builder::new_multi_thread()
@@ -153,55 +172,36 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         .worker_threads(works)
         .build()?;
 
-    info!("ProxySrv running config args={:?}", proxy_config);
-    // start metrics service
-    let (shutdown_tx, shutdown_rx) = watch::channel(ShutdownMessage::Init);
-    runtime.block_on(async {
-        let backend_options = proxy_config.new_backend_opts();
-        let router = new_backend_router(&proxy_config, &shutdown_rx.clone()).await;
+    if let Some(BackendConfigArgs::Bench(bench_args)) = &proxy_config.backend {
+        let report = runtime.block_on(proxy::bench::run_bench(bench_args))?;
+        println!("{report}");
+        return Ok(());
+    }
 
-        if !router.is_static() {
-            info!("ProxySrv backend router is not static. Waiting to add Tenant.");
-            std::env::set_var(proxy::server::PROXY_ENV_SYNC_ROUTER, "true");
+    let mut proxy_runtime = ProxyRuntime::new(proxy_config.clone());
+    let shutdown_rx = proxy_runtime.shutdown_receiver();
+    runtime.block_on(async {
+        // Before the proxy records any metric.
+        start_metrics(&proxy_config, &runtime, &shutdown_rx);
+        proxy_runtime.start().await?;
+        if let Some(reloader) = config_reloader() {
+            runtime.spawn(apply_runtime_config(
+                log_filter_handle,
+                reloader.subscribe(),
+            ));
+        }
+        runtime.spawn(run_reload_on_hangup(shutdown_rx.clone()));
+        if let Some(backend_mgr) = proxy_runtime.backend_mgr() {
+            start_rest(
+                proxy_config.clone(),
+                &runtime,
+                HaentglProxyRestState::new(backend_mgr),
+                &shutdown_rx,
+            );
         }
-        let backend_mgr = get_or_init_backend_mgr(router, backend_options.clone());
-
-        start_metrics_and_rest(proxy_config.clone(), &runtime, HaentglProxyRestState::new(Arc::clone(&backend_mgr)), &shutdown_rx);
-
-
-        let proxy_srv = HaentglServer::new(
-            backend_mgr,
-            ProxyAuthenticator,
-        );
-
-        let proxy_srv_arc_ref = Arc::new(proxy_srv);
-        let proxy_srv_arc = Arc::clone(&proxy_srv_arc_ref);
-        runtime.spawn(async move {
-            proxy_srv_arc_ref.initialize_async().await
-        });
 
-        let port = proxy_config.port;
-        let tcp_listener = TcpListener::bind(format!("0.0.0.0:{port}")).await.unwrap();
-        loop {
-            tokio::select! {
-                shutdown_msg = shutdown_signal() => {
-                    shutdown_tx.send(shutdown_msg.clone()).unwrap();
-                    break ;
-                }
-                rs = tcp_listener.accept() => {
-                   match rs {
-                      Ok((stream,  _addr)) => {
-                         let (client_reader, client_writer) = stream.into_split();
-                         let proxy_arc_clone = Arc::clone(&proxy_srv_arc);
-                         runtime.spawn(async move {proxy_arc_clone.connect(client_reader, client_writer, &None).await});
-                      }
-                      Err(e)=> {
-                          warn!("ProxySrv accept connection err. cause by {e:?}");
-                      }
-                   }
-                }
-            }
-        };
+        let shutdown_msg = shutdown_signal().await;
+        proxy_runtime.shutdown(shutdown_msg).await;
         Ok(())
     })
 }
@@ -1,3 +1,4 @@
+pub mod clock;
 pub mod metrics;
 pub mod profiling;
 pub mod sys_utils;
@@ -2,9 +2,61 @@ pub const PROCESS_MEM_RSS_SIZE: &str = "proxy_process_mem_rss_bytes";
 pub const PROCESS_VIRTUAL_MEM_SIZE: &str = "proxy_process_mem_virtual_bytes";
 pub const CPU_CORE_NUM: &str = "proxy_process_cpu_core_num";
 pub const CPU_TOTAL: &str = "proxy_process_cpu_seconds_total";
+pub const PROCESS_OPEN_FDS: &str = "proxy_process_open_fds";
 pub const PROXY_MAX_CONN: &str = "proxy_max_connections";
 pub const PROXY_CURR_CONN: &str = "proxy_curr_connections";
 pub const PROXY_COM_LATENCY: &str = "proxy_com_latency";
+pub const PROXY_BACKEND_COMPRESS_SAVED_BYTES: &str = "proxy_backend_compress_saved_bytes";
+pub const PROXY_REPLICA_LAG_MS: &str = "proxy_replica_lag_ms";
+pub const PROXY_REPLICA_LAG_EXCLUDED: &str = "proxy_replica_lag_excluded";
+pub const PROXY_READ_FALLBACK_PRIMARY: &str = "proxy_read_fallback_primary";
+pub const PROXY_SHARD_ROUTED: &str = "proxy_shard_routed";
+pub const PROXY_LONG_DATA_BYTES: &str = "proxy_long_data_bytes";
+pub const PROXY_LONG_DATA_REJECTED: &str = "proxy_long_data_rejected";
+pub const PROXY_WATCHDOG_SHED: &str = "proxy_watchdog_shed";
+pub const PROXY_SESSION_CLOSED: &str = "proxy_session_closed";
+pub const PROXY_MIRROR_QUERIES: &str = "proxy_mirror_queries";
+pub const PROXY_MIRROR_LATENCY: &str = "proxy_mirror_latency";
+pub const PROXY_ACTIVE_USERS_EPOCH_ENTRIES: &str = "proxy_active_users_epoch_entries";
+pub const PROXY_ACTIVE_USERS_EPOCH_BYTES: &str = "proxy_active_users_epoch_bytes";
+pub const PROXY_TLS_HANDSHAKE_LATENCY: &str = "proxy_tls_handshake_latency";
+pub const PROXY_TLS_HANDSHAKE_FAILED: &str = "proxy_tls_handshake_failed";
+pub const PROXY_BACKEND_QUARANTINED: &str = "proxy_backend_quarantined";
+pub const PROXY_ACTIVE_USERS_DROPPED: &str = "proxy_active_users_dropped";
+pub const PROXY_FLOW_CONTROL_PAUSED: &str = "proxy_flow_control_paused";
+pub const PROXY_EGRESS_REJECTED: &str = "proxy_egress_rejected";
+pub const PROXY_POOL_EVENTS: &str = "proxy_pool_events";
+pub const PROXY_MAINTENANCE_NOTICES: &str = "proxy_maintenance_notices";
+pub const PROXY_POOL_WARMUP_READY: &str = "proxy_pool_warmup_ready";
+pub const PROXY_BACKEND_ERRORS: &str = "proxy_backend_errors";
+pub const PROXY_BACKEND_KEEPALIVE: &str = "proxy_backend_keepalive";
+pub const PROXY_PROTOCOL_LIMIT_EXCEEDED: &str = "proxy_protocol_limit_exceeded";
+pub const PROXY_STICKY_SESSIONS: &str = "proxy_sticky_sessions";
+pub const PROXY_WRONG_PROTOCOL_CONN: &str = "proxy_wrong_protocol_conn";
+pub const PROXY_TENANTS: &str = "proxy_tenants";
+pub const PROXY_BACKEND_CONN_INVALIDATED: &str = "proxy_backend_conn_invalidated";
+pub const PROXY_BACKEND_EVENT_QUEUE: &str = "proxy_backend_event_queue";
+pub const PROXY_BACKEND_EVENT_LATENCY: &str = "proxy_backend_event_latency";
+pub const PROXY_ACME_ORDERS: &str = "proxy_acme_orders";
+pub const PROXY_TLS_CERT_EXPIRY: &str = "proxy_tls_cert_expiry";
+pub const PROXY_COM_LATENCY_DROPPED: &str = "proxy_com_latency_dropped";
+pub const PROXY_REPLICATION_REJECTED: &str = "proxy_replication_rejected";
+pub const PROXY_TOPOLOGY_EVENT_AGE: &str = "proxy_topology_event_age";
+pub const PROXY_TOPOLOGY_STALE_TENANTS: &str = "proxy_topology_stale_tenants";
+pub const PROXY_TRANSPARENT_CONNS: &str = "proxy_transparent_conns";
+pub const PROXY_AUTH_REJECTED: &str = "proxy_auth_rejected";
+pub const PROXY_POOL_HEALTH_CHECKS: &str = "proxy_pool_health_checks";
+pub const PROXY_PROTOCOL_FEATURE: &str = "proxy_protocol_feature";
+pub const PROXY_READ_SPLIT_QUERIES: &str = "proxy_read_split_queries";
+pub const PROXY_BACKEND_FAILOVERS: &str = "proxy_backend_failovers";
+pub const PROXY_COM_BYTES: &str = "proxy_com_bytes";
+pub const PROXY_COM_ROWS: &str = "proxy_com_rows";
+pub const PROXY_CLIENT_ACL_REJECTED: &str = "proxy_client_acl_rejected";
+pub const PROXY_COMMAND_RATE_LIMITED: &str = "proxy_command_rate_limited";
+pub const PROXY_QUERY_DIGEST_CALLS: &str = "proxy_query_digest_calls";
+pub const PROXY_QUERY_DIGEST_TIME: &str = "proxy_query_digest_time_us";
+pub const PROXY_IDLE_SESSIONS_CLOSED: &str = "proxy_idle_sessions_closed";
+pub const PROXY_SNI_ROUTED_CONNS: &str = "proxy_sni_routed_conns";
 
 #[macro_export]
 macro_rules! metrics_const {
@@ -53,7 +105,59 @@ metrics_const!(
     { ProcessVirtralMemSize, virtual_mem_size,MetricType::Gauge, PROCESS_VIRTUAL_MEM_SIZE, "Process virtual memory size in bytes"},
     { CpuCoreNum, cpu_core_num, MetricType::Gauge, CPU_CORE_NUM, "cpu core num."},
     { CpuTotal, cpu_total, MetricType::Gauge, CPU_TOTAL, "total user and system cpu time spend in seconds."},
+    { ProcessOpenFds, open_fds, MetricType::Gauge, PROCESS_OPEN_FDS, "Open file descriptors of the process."},
     { ProxyMaxConnections, max_connections, MetricType::Gauge, PROXY_MAX_CONN, "The max number of connections allowed by the Proxy."},
     { ProxyCurrentConnections, current_connections, MetricType::Gauge, PROXY_CURR_CONN, "The current connection count by the Proxy."},
-    { ProxyComLatency, com_latncy, MetricType::Histogram, PROXY_COM_LATENCY, "Latency of command execution."}
+    { ProxyComLatency, com_latncy, MetricType::Histogram, PROXY_COM_LATENCY, "Latency of command execution."},
+    { ProxyBackendCompressSavedBytes, backend_compress_saved_bytes, MetricType::Counter, PROXY_BACKEND_COMPRESS_SAVED_BYTES, "Bytes saved by protocol compression on the backend leg."},
+    { ProxyReplicaLagMs, replica_lag_ms, MetricType::Gauge, PROXY_REPLICA_LAG_MS, "Replica lag in milliseconds reported by the control plane."},
+    { ProxyReplicaLagExcluded, replica_lag_excluded, MetricType::Counter, PROXY_REPLICA_LAG_EXCLUDED, "Times a replica was excluded from read selection by its lag."},
+    { ProxyReadFallbackPrimary, read_fallback_primary, MetricType::Counter, PROXY_READ_FALLBACK_PRIMARY, "Reads routed to the primary because every replica lags behind."},
+    { ProxyShardRouted, shard_routed, MetricType::Counter, PROXY_SHARD_ROUTED, "Connections of sharded tenants routed to each shard."},
+    { ProxyLongDataBytes, long_data_bytes, MetricType::Counter, PROXY_LONG_DATA_BYTES, "Bytes of COM_STMT_SEND_LONG_DATA forwarded to the backends."},
+    { ProxyLongDataRejected, long_data_rejected, MetricType::Counter, PROXY_LONG_DATA_REJECTED, "Statements whose long data exceeded the per-statement or per-session limit."},
+    { ProxyWatchdogShed, watchdog_shed, MetricType::Counter, PROXY_WATCHDOG_SHED, "Idle pooled connections shrunk or closed by the resource watchdog."},
+    { ProxySessionClosed, session_closed, MetricType::Counter, PROXY_SESSION_CLOSED, "Client sessions that ended with COM_QUIT, by close reason."},
+    { ProxyMirrorQueries, mirror_queries, MetricType::Counter, PROXY_MIRROR_QUERIES, "Read-only queries mirrored to the shadow backend of a tenant, by result."},
+    { ProxyMirrorLatency, mirror_latency, MetricType::Histogram, PROXY_MIRROR_LATENCY, "Latency of mirrored queries on the primary and the shadow backend."},
+    { ProxyActiveUsersEpochEntries, active_users_epoch_entries, MetricType::Gauge, PROXY_ACTIVE_USERS_EPOCH_ENTRIES, "Users in the last frozen active user window."},
+    { ProxyActiveUsersEpochBytes, active_users_epoch_bytes, MetricType::Gauge, PROXY_ACTIVE_USERS_EPOCH_BYTES, "Bytes of the last frozen active user window."},
+    { ProxyTlsHandshakeLatency, tls_handshake_latency, MetricType::Histogram, PROXY_TLS_HANDSHAKE_LATENCY, "Latency of client TLS handshakes, full or resumed."},
+    { ProxyTlsHandshakeFailed, tls_handshake_failed, MetricType::Counter, PROXY_TLS_HANDSHAKE_FAILED, "Client TLS handshakes that failed."},
+    { ProxyBackendQuarantined, backend_quarantined, MetricType::Counter, PROXY_BACKEND_QUARANTINED, "Backends quarantined after repeated connection failures."},
+    { ProxyActiveUsersDropped, active_users_dropped, MetricType::Counter, PROXY_ACTIVE_USERS_DROPPED, "Active user commands dropped because the reporting queue was full."},
+    { ProxyFlowControlPaused, flow_control_paused, MetricType::Counter, PROXY_FLOW_CONTROL_PAUSED, "Times a session stopped reading from the backend until its client drained the buffered results."},
+    { ProxyEgressRejected, egress_rejected, MetricType::Counter, PROXY_EGRESS_REJECTED, "Outbound connections rejected by the egress allowlists, by target."},
+    { ProxyPoolEvents, pool_events, MetricType::Counter, PROXY_POOL_EVENTS, "Pooled backend connections created, failed to create, recycled or detached, by backend."},
+    { ProxyMaintenanceNotices, maintenance_notices, MetricType::Counter, PROXY_MAINTENANCE_NOTICES, "Maintenance warnings attached to statements and sessions closed by a started maintenance, by tenant."},
+    { ProxyPoolWarmupReady, pool_warmup_ready, MetricType::Gauge, PROXY_POOL_WARMUP_READY, "Share of the Ready backends whose pools finished the startup warm-up."},
+    { ProxyBackendErrors, backend_errors, MetricType::Counter, PROXY_BACKEND_ERRORS, "ERR packets read from backends, by tenant, backend and error class."},
+    { ProxyBackendKeepalive, backend_keepalive, MetricType::Counter, PROXY_BACKEND_KEEPALIVE, "Keepalive pings on the backend connections of idle sessions, by tenant and result."},
+    { ProxyProtocolLimitExceeded, protocol_limit_exceeded, MetricType::Counter, PROXY_PROTOCOL_LIMIT_EXCEEDED, "Sessions closed for exceeding a protocol limit, by tenant, leg and limit."},
+    { ProxyStickySessions, sticky_sessions, MetricType::Counter, PROXY_STICKY_SESSIONS, "Sessions whose backend connection took state that pins it to the session until a reset, by tenant and reason."},
+    { ProxyWrongProtocolConn, wrong_protocol_conn, MetricType::Counter, PROXY_WRONG_PROTOCOL_CONN, "Connections closed for speaking TLS or HTTP instead of MySQL, by listener and protocol."},
+    { ProxyTenants, tenants, MetricType::Gauge, PROXY_TENANTS, "Tenants whose pools are open (hot) or closed after an idle TTL (cold)."},
+    { ProxyBackendConnInvalidated, backend_conn_invalidated, MetricType::Counter, PROXY_BACKEND_CONN_INVALIDATED, "Pooled backend connections closed instead of recycled after a connection-fatal backend error, by backend."},
+    { ProxyBackendEventQueue, backend_event_queue, MetricType::Gauge, PROXY_BACKEND_EVENT_QUEUE, "Backend status events waiting to be applied to the pools."},
+    { ProxyBackendEventLatency, backend_event_latency, MetricType::Histogram, PROXY_BACKEND_EVENT_LATENCY, "Time to apply a backend status event to the pools."},
+    { ProxyAcmeOrders, acme_orders, MetricType::Counter, PROXY_ACME_ORDERS, "ACME certificate orders of the client listener, by result."},
+    { ProxyTlsCertExpiry, tls_cert_expiry, MetricType::Gauge, PROXY_TLS_CERT_EXPIRY, "Seconds until the client listener certificate expires."},
+    { ProxyComLatencyDropped, com_latency_dropped, MetricType::Counter, PROXY_COM_LATENCY_DROPPED, "Command latency samples dropped because the aggregator queue was full."},
+    { ProxyReplicationRejected, replication_rejected, MetricType::Counter, PROXY_REPLICATION_REJECTED, "Replication attempts rejected for tenants without replication enabled."},
+    { ProxyTopologyEventAge, topology_event_age, MetricType::Gauge, PROXY_TOPOLOGY_EVENT_AGE, "Seconds since the last backend change event of a tenant."},
+    { ProxyTopologyStaleTenants, topology_stale_tenants, MetricType::Gauge, PROXY_TOPOLOGY_STALE_TENANTS, "Tenants with a stale backend list while their connections fail."},
+    { ProxyTransparentConns, transparent_conns, MetricType::Counter, PROXY_TRANSPARENT_CONNS, "Intercepted connections of the transparent mode, by routing result."},
+    { ProxyAuthRejected, auth_rejected, MetricType::Counter, PROXY_AUTH_REJECTED, "Clients rejected while the concurrent handshakes were at their limit."},
+    { ProxyPoolHealthChecks, pool_health_checks, MetricType::Counter, PROXY_POOL_HEALTH_CHECKS, "Idle pooled connections pinged or closed by the health checks, by backend and result."},
+    { ProxyProtocolFeature, protocol_feature, MetricType::Gauge, PROXY_PROTOCOL_FEATURE, "Protocol features of the proxy, 1 for their current handled and enabled state."},
+    { ProxyReadSplitQueries, read_split_queries, MetricType::Counter, PROXY_READ_SPLIT_QUERIES, "Read-only queries of read/write split sessions, by the backend role that served them."},
+    { ProxyBackendFailovers, backend_failovers, MetricType::Counter, PROXY_BACKEND_FAILOVERS, "Sessions that lost their backend connection mid-session, by tenant and failover result."},
+    { ProxyComBytes, com_bytes, MetricType::Counter, PROXY_COM_BYTES, "Bytes commands sent to and received from the backends, by tenant, command and direction."},
+    { ProxyComRows, com_rows, MetricType::Counter, PROXY_COM_ROWS, "Result set rows returned by commands, by tenant and command."},
+    { ProxyClientAclRejected, client_acl_rejected, MetricType::Counter, PROXY_CLIENT_ACL_REJECTED, "Client connections rejected by the client address allow and deny lists, by scope."},
+    { ProxyCommandRateLimited, command_rate_limited, MetricType::Counter, PROXY_COMMAND_RATE_LIMITED, "Commands delayed or rejected above the rate limit of their user, by tenant and action."},
+    { ProxyQueryDigestCalls, query_digest_calls, MetricType::Counter, PROXY_QUERY_DIGEST_CALLS, "Statements executed, by tenant and statement fingerprint."},
+    { ProxyQueryDigestTime, query_digest_time, MetricType::Counter, PROXY_QUERY_DIGEST_TIME, "Microseconds spent in statements, by tenant and statement fingerprint."},
+    { ProxyIdleSessionsClosed, idle_sessions_closed, MetricType::Counter, PROXY_IDLE_SESSIONS_CLOSED, "Client sessions closed after the idle timeout, by tenant and whether their backend connection was reset."},
+    { ProxySniRoutedConns, sni_routed_conns, MetricType::Counter, PROXY_SNI_ROUTED_CONNS, "TLS connections routed to a tenant by their SNI hostname, by routing result."}
 );
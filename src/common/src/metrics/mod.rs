@@ -1,10 +1,10 @@
 pub mod metric_def;
 pub mod process_unix;
 
+use crate::clock::{clock, Timestamp};
 use crate::sys_utils::sys::hostname;
-use metrics::{
-    describe_counter, describe_gauge, describe_histogram, histogram, Histogram, IntoLabels,
-};
+use metrics::{describe_counter, describe_gauge, describe_histogram, histogram, IntoLabels};
+pub use metrics::{Counter, Gauge, Histogram};
 use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
 // use once_cell::sync::Lazy;
 use parking_lot::RwLock;
@@ -92,8 +92,11 @@ pub fn try_handle() -> Option<PrometheusHandle> {
     PROMETHEUS_HANDLE.as_ref().read().clone()
 }
 
+/// Records the milliseconds it lives into a histogram. Timers read the coarse clock, except
+/// [`MetricsTimer::precise`] ones timing operations shorter than a clock tick.
 pub struct MetricsTimer {
-    start: coarsetime::Instant,
+    start: Timestamp,
+    precise: bool,
     histogram: Histogram,
     observed: bool,
 }
@@ -108,6 +111,7 @@ impl fmt::Debug for MetricsTimer {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("MetricsTimer")
             .field("start", &self.start)
+            .field("precise", &self.precise)
             .field("observed", &self.observed)
             .finish()
     }
@@ -115,9 +119,8 @@ impl fmt::Debug for MetricsTimer {
 
 impl Drop for MetricsTimer {
     fn drop(&mut self) {
-        let elapsed = self.start.elapsed().as_millis();
         if !self.observed {
-            self.histogram.record(elapsed as f64);
+            self.histogram.record(self.elapsed_ms());
         }
     }
 }
@@ -125,7 +128,18 @@ impl Drop for MetricsTimer {
 impl MetricsTimer {
     pub fn from_histogram(histogram: Histogram) -> Self {
         Self {
-            start: coarsetime::Instant::now(),
+            start: clock().coarse_now(),
+            precise: false,
+            histogram,
+            observed: false,
+        }
+    }
+
+    /// A timer reading the precise clock, recording fractions of milliseconds.
+    pub fn precise(histogram: Histogram) -> Self {
+        Self {
+            start: clock().precise_now(),
+            precise: true,
             histogram,
             observed: false,
         }
@@ -133,7 +147,8 @@ impl MetricsTimer {
 
     pub fn new(name: &'static str) -> Self {
         Self {
-            start: coarsetime::Instant::now(),
+            start: clock().coarse_now(),
+            precise: false,
             histogram: histogram!(name), //register_histogram!(name),
             observed: false,
         }
@@ -141,14 +156,23 @@ impl MetricsTimer {
 
     pub fn new_with_labels<L: IntoLabels>(name: &'static str, labels: L) -> Self {
         Self {
-            start: coarsetime::Instant::now(),
+            start: clock().coarse_now(),
+            precise: false,
             histogram: histogram!(name, labels), //register_histogram!(name, labels),
             observed: false,
         }
     }
 
     pub fn elapsed(&self) -> u64 {
-        self.start.elapsed().as_millis()
+        self.elapsed_ms() as u64
+    }
+
+    fn elapsed_ms(&self) -> f64 {
+        if self.precise {
+            clock().precise_elapsed(self.start).as_secs_f64() * 1e3
+        } else {
+            clock().coarse_elapsed(self.start).as_millis() as f64
+        }
     }
 
     pub fn discard(mut self) {
@@ -196,6 +220,27 @@ pub fn gauge_dec(name: &'static str, value: f64, labels: Option<&Vec<(&'static s
     gauge.decrement(value)
 }
 
+#[inline]
+pub fn counter_inc(name: &'static str, value: u64, labels: Option<&Vec<(&'static str, String)>>) {
+    let counter = if let Some(label) = labels {
+        metrics::counter!(name, label)
+    } else {
+        metrics::counter!(name)
+    };
+    counter.increment(value)
+}
+
+/// Resolves a counter once, so hot paths increment it without hashing the labels again.
+/// Handles resolved before [`init_metrics_context`] are no-ops.
+pub fn counter_handle(name: &'static str, labels: &[(&'static str, String)]) -> Counter {
+    metrics::counter!(name, labels)
+}
+
+/// Like [`counter_handle`], for histograms.
+pub fn histogram_handle(name: &'static str, labels: &[(&'static str, String)]) -> Histogram {
+    metrics::histogram!(name, labels)
+}
+
 pub fn describe_and_register_metrics(
     metric_type: MetricType,
     name: &'static str,
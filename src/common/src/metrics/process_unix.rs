@@ -16,11 +16,63 @@ static LINUX_PAGE_SIZE: std::sync::LazyLock<i64> =
 static CLOCK_TICK: std::sync::LazyLock<u64> =
     std::sync::LazyLock::new(|| unsafe { libc::sysconf(libc::_SC_CLK_TCK) as u64 });
 
+/// Resources held by the proxy process.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ProcessUsage {
+    pub rss_bytes: u64,
+    pub open_fds: u64,
+    /// The soft `RLIMIT_NOFILE`, 0 if unknown.
+    pub max_fds: u64,
+}
+
+fn max_open_fds() -> u64 {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } == 0 {
+        limit.rlim_cur
+    } else {
+        0
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub fn process_usage() -> Result<ProcessUsage, std::io::Error> {
+    let p = procfs::process::Process::myself().map_err(std::io::Error::other)?;
+    let stat = p.stat().map_err(std::io::Error::other)?;
+    let open_fds = p.fd_count().map_err(std::io::Error::other)?;
+    Ok(ProcessUsage {
+        rss_bytes: (stat.rss as i64 * *LINUX_PAGE_SIZE) as u64,
+        open_fds: open_fds as u64,
+        max_fds: max_open_fds(),
+    })
+}
+
+#[cfg(target_os = "macos")]
+fn open_fd_count() -> u64 {
+    std::fs::read_dir("/dev/fd")
+        .map(|entries| entries.count() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(target_os = "macos")]
+pub fn process_usage() -> Result<ProcessUsage, std::io::Error> {
+    let my_pid = unsafe { libc::getpid() };
+    let proc_info = darwin_libproc::task_info(my_pid)?;
+    Ok(ProcessUsage {
+        rss_bytes: proc_info.pti_resident_size,
+        open_fds: open_fd_count(),
+        max_fds: max_open_fds(),
+    })
+}
+
 pub struct ProcessRecorder {
     mem_rss: Gauge,
     mem_virtual_size: Gauge,
     cpu_total: Gauge,
     cpu_core_num: Gauge,
+    open_fds: Gauge,
     stop_rx: watch::Receiver<ShutdownMessage>,
     #[cfg(target_os = "linux")]
     cpu_total_value: GaugeValue,
@@ -43,12 +95,14 @@ impl ProcessRecorder {
         let mem_virtual_size_metric = MetricsConsts::virtual_mem_size();
         let cpu_total_metric = MetricsConsts::cpu_total();
         let cpu_core_num_metric = MetricsConsts::cpu_core_num();
+        let open_fds_metric = MetricsConsts::open_fds();
 
         Self {
             mem_rss: register_process_metric!(mem_rss_metric, &labels),
             mem_virtual_size: register_process_metric!(mem_virtual_size_metric, &labels),
             cpu_total: register_process_metric!(cpu_total_metric, &labels),
             cpu_core_num: register_process_metric!(cpu_core_num_metric, &labels),
+            open_fds: register_process_metric!(open_fds_metric, &labels),
             stop_rx,
             #[cfg(target_os = "linux")]
             cpu_total_value: GaugeValue::Absolute(0_f64),
@@ -82,6 +136,9 @@ impl ProcessRecorder {
         self.mem_virtual_size.set(stat.vsize as f64);
         self.mem_rss
             .set((stat.rss as i64 * *LINUX_PAGE_SIZE) as f64);
+        if let Ok(open_fds) = p.fd_count() {
+            self.open_fds.set(open_fds as f64);
+        }
 
         // cpu
         let total = (stat.utime + stat.stime) / *CLOCK_TICK;
@@ -121,6 +178,7 @@ impl ProcessRecorder {
         // memory collect
         self.mem_rss.set(proc_info.pti_resident_size as f64);
         self.mem_virtual_size.set(proc_info.pti_virtual_size as f64);
+        self.open_fds.set(open_fd_count() as f64);
 
         // cpu metric collect
         let cpu_total =
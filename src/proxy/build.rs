@@ -43,6 +43,14 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 "common_proto.TenantKey",
                 "#[derive(Eq, Hash, serde::Serialize, serde::Deserialize)]",
             )
+            .type_attribute(
+                "control_plane.BillingRecord",
+                "#[derive(serde::Serialize, serde::Deserialize)]",
+            )
+            .type_attribute(
+                "control_plane.AuditEvent",
+                "#[derive(serde::Serialize, serde::Deserialize)]",
+            )
             .out_dir(output_dir.as_path())
             .compile_protos(&[proto_file], &["protos"])
             .unwrap_or_else(|_| panic!("Failed to compile protobuf files! {}", proto));
@@ -0,0 +1,81 @@
+//! Measures what reporting active users costs a command, batched per session as the proxy does
+//! and with a message per command.
+//!
+//! ```shell
+//! cargo run --release -p proxy --example active_users_overhead
+//! ```
+use proxy::cp::active_users::{ActivityBatcher, UserActivityWindow};
+use proxy::prost::common_proto::TenantKey;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const SESSIONS: usize = 64;
+const COMMANDS_PER_SESSION: usize = 100_000;
+
+fn tenant(session: usize) -> TenantKey {
+    TenantKey {
+        region: "us-east-2".to_string(),
+        available_zone: "us-east-2a".to_string(),
+        namespace: "default".to_string(),
+        cluster_name: format!("cluster-{}", session % 8),
+    }
+}
+
+/// Waits for the aggregation task, returns how long it took and the commands it aggregated.
+/// Commands dropped by a full queue are never aggregated.
+async fn wait_reported(window: &UserActivityWindow) -> (Duration, u64) {
+    let started = Instant::now();
+    let mut reported = window.count();
+    loop {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        if window.pending_batches() == 0 && window.count() == reported {
+            return (started.elapsed(), reported);
+        }
+        reported = window.count();
+    }
+}
+
+fn report(name: &str, send: Duration, (drain, reported): (Duration, u64)) {
+    let commands = (SESSIONS * COMMANDS_PER_SESSION) as f64;
+    println!(
+        "{name:<12} {:>8.1} ns/command on the session, {:>8.1} ns/command until aggregated, {:.1}% dropped",
+        send.as_nanos() as f64 / commands,
+        (send + drain).as_nanos() as f64 / commands,
+        100.0 * (1.0 - reported as f64 / commands),
+    );
+}
+
+#[tokio::main]
+async fn main() {
+    let window = Arc::new(UserActivityWindow::default());
+    let started = Instant::now();
+    let sessions = (0..SESSIONS).map(|session| {
+        let window = Arc::clone(&window);
+        tokio::spawn(async move {
+            let mut batcher =
+                ActivityBatcher::new(window, tenant(session), format!("user-{session}"));
+            for _ in 0..COMMANDS_PER_SESSION {
+                batcher.record(3);
+            }
+        })
+    });
+    futures::future::join_all(sessions).await;
+    let send = started.elapsed();
+    report("batched", send, wait_reported(&window).await);
+
+    let window = Arc::new(UserActivityWindow::default());
+    let started = Instant::now();
+    let sessions = (0..SESSIONS).map(|session| {
+        let window = Arc::clone(&window);
+        tokio::spawn(async move {
+            let tenant = tenant(session);
+            let user = format!("user-{session}");
+            for _ in 0..COMMANDS_PER_SESSION {
+                window.add_active_users(tenant.clone(), user.clone(), 3, 0);
+            }
+        })
+    });
+    futures::future::join_all(sessions).await;
+    let send = started.elapsed();
+    report("per command", send, wait_reported(&window).await);
+}
@@ -0,0 +1,80 @@
+//! Measures what recording the latency of a command costs the session, into the histograms
+//! right away and through the queue of the aggregator task.
+//!
+//! ```shell
+//! cargo run --release -p proxy --example com_latency_overhead
+//! ```
+use common::metrics::metric_def::PROXY_COM_LATENCY_DROPPED;
+use proxy::prost::common_proto::TenantKey;
+use proxy::protocol::mysql::constants::CommandCode;
+use proxy::server::session_metrics::{ComLatencyRecorder, SessionMetrics};
+use std::time::{Duration, Instant};
+
+const SESSIONS: usize = 64;
+const COMMANDS_PER_SESSION: usize = 100_000;
+const COMMANDS_PER_YIELD: usize = 16;
+const QUEUE_CAPACITY: usize = 65536;
+
+/// Waits for the aggregator to record the queued samples, returns how long it took.
+async fn wait_recorded(recorder: &ComLatencyRecorder) -> Duration {
+    let started = Instant::now();
+    while recorder.queued_samples() > 0 {
+        tokio::time::sleep(Duration::from_millis(1)).await;
+    }
+    started.elapsed()
+}
+
+async fn run_sessions(recorder: &ComLatencyRecorder) -> Duration {
+    let started = Instant::now();
+    let sessions = (0..SESSIONS).map(|session| {
+        let metrics = SessionMetrics::new(
+            &TenantKey {
+                namespace: "default".to_string(),
+                cluster_name: format!("cluster-{}", session % 8),
+                ..Default::default()
+            },
+            recorder.clone(),
+        );
+        tokio::spawn(async move {
+            for command in 0..COMMANDS_PER_SESSION {
+                let _com_latency = metrics.com_timer(CommandCode::ComQuery as u8);
+                // Sessions wait for their client and backend between commands.
+                if command % COMMANDS_PER_YIELD == 0 {
+                    tokio::task::yield_now().await;
+                }
+            }
+        })
+    });
+    futures::future::join_all(sessions).await;
+    started.elapsed()
+}
+
+fn report(name: &str, on_session: Duration, until_recorded: Duration) {
+    let commands = (SESSIONS * COMMANDS_PER_SESSION) as f64;
+    println!(
+        "{name:<8} {:>8.1} ns/command on the session, {:>8.1} ns/command until recorded",
+        on_session.as_nanos() as f64 / commands,
+        (on_session + until_recorded).as_nanos() as f64 / commands,
+    );
+}
+
+#[tokio::main]
+async fn main() {
+    // Without a recorder the histograms are no-ops.
+    common::metrics::init_metrics_context();
+
+    let direct = ComLatencyRecorder::default();
+    let on_session = run_sessions(&direct).await;
+    report("direct", on_session, Duration::ZERO);
+
+    let queued = ComLatencyRecorder::queued(QUEUE_CAPACITY);
+    let on_session = run_sessions(&queued).await;
+    report("queued", on_session, wait_recorded(&queued).await);
+    // Samples finding the queue full are dropped instead of waiting.
+    let rendered = common::metrics::try_handle().map(|handle| handle.render());
+    for line in rendered.iter().flat_map(|rendered| rendered.lines()) {
+        if line.starts_with(PROXY_COM_LATENCY_DROPPED) {
+            println!("{line}");
+        }
+    }
+}
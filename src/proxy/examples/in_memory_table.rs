@@ -0,0 +1,157 @@
+//! A tiny MySQL compatible server that keeps a single `kv` table in memory.
+//!
+//! ```shell
+//! cargo run -p proxy --example in_memory_table
+//! mysql -h127.0.0.1 -P3320 -uroot -e "INSERT INTO kv VALUES ('k1', 'v1'); SELECT * FROM kv"
+//! ```
+use mysql_common::constants::{CapabilityFlags, ColumnFlags, ColumnType, StatusFlags};
+use proxy::protocol::mysql::basic::Column;
+use proxy::protocol::mysql::error_codes::ErrorKind;
+use proxy::protocol::mysql::packet::packet_writer::PacketWriter;
+use proxy::protocol::mysql::packet::writers;
+use proxy::server::cmd_handler::{cmd_error, CmdHandler};
+use proxy::server::static_proxy::StaticProxyServer;
+use std::sync::{Arc, RwLock};
+use tokio::io::AsyncWrite;
+use tokio::net::TcpListener;
+use tracing::{info, warn};
+
+type Table = Arc<RwLock<Vec<(String, String)>>>;
+
+struct InMemoryTable {
+    table: Table,
+    capabilities: CapabilityFlags,
+}
+
+fn unquote(value: &str) -> String {
+    value.trim().trim_matches('\'').trim_matches('"').to_string()
+}
+
+/// Parses `INSERT INTO kv VALUES ('k', 'v')`.
+fn parse_insert(query: &str) -> Option<(String, String)> {
+    let values = query.split_once('(')?.1.rsplit_once(')')?.0;
+    let (key, value) = values.split_once(',')?;
+    Some((unquote(key), unquote(value)))
+}
+
+#[async_trait::async_trait]
+impl CmdHandler for InMemoryTable {
+    fn on_connect(&mut self, conn_id: u64, capabilities: CapabilityFlags) {
+        info!("InMemoryTable new connection {conn_id}");
+        self.capabilities = capabilities;
+    }
+
+    async fn auth(
+        &mut self,
+        _auth_plugin: &str,
+        _user: &[u8],
+        _salt: &[u8],
+        _auth_data: &[u8],
+    ) -> Result<bool, std::io::Error> {
+        Ok(true)
+    }
+
+    async fn on_init<'a, W>(
+        &mut self,
+        _database: &[u8],
+        pkt_writer: &mut PacketWriter<W>,
+    ) -> Result<(), std::io::Error>
+    where
+        W: AsyncWrite + Send + Unpin,
+    {
+        writers::write_ok_packet(pkt_writer, 0, 0, StatusFlags::SERVER_STATUS_AUTOCOMMIT).await
+    }
+
+    async fn on_prepare<'a, W>(
+        &mut self,
+        _packet: &[u8],
+        _pkt_writer: &mut PacketWriter<W>,
+    ) -> Result<(), std::io::Error>
+    where
+        W: AsyncWrite + Send + Unpin,
+    {
+        Err(cmd_error(
+            ErrorKind::ER_NOT_SUPPORTED_YET,
+            "prepared statements are not supported",
+        ))
+    }
+
+    async fn on_query<'a, W>(
+        &mut self,
+        packet: &[u8],
+        pkt_writer: &mut PacketWriter<W>,
+    ) -> Result<(), std::io::Error>
+    where
+        W: AsyncWrite + Send + Unpin,
+    {
+        let query = String::from_utf8_lossy(packet).trim().to_string();
+        let lower_query = query.to_lowercase();
+        if lower_query.starts_with("select") && lower_query.contains("from kv") {
+            let columns = ["k", "v"].map(|name| Column {
+                table: "kv".to_string(),
+                column: name.to_string(),
+                column_type: ColumnType::MYSQL_TYPE_VAR_STRING,
+                column_flags: ColumnFlags::empty(),
+            });
+            let rows = self
+                .table
+                .read()
+                .unwrap()
+                .iter()
+                .map(|(k, v)| vec![Some(k.clone()), Some(v.clone())])
+                .collect::<Vec<_>>();
+            writers::write_text_result_set(&columns, &rows, pkt_writer, self.capabilities).await
+        } else if lower_query.starts_with("insert into kv") {
+            let (key, value) = parse_insert(&query).ok_or_else(|| {
+                cmd_error(ErrorKind::ER_PARSE_ERROR, format!("can't parse {query}"))
+            })?;
+            self.table.write().unwrap().push((key, value));
+            writers::write_ok_packet(pkt_writer, 1, 0, StatusFlags::SERVER_STATUS_AUTOCOMMIT).await
+        } else if lower_query.starts_with("set") || lower_query.starts_with("use") {
+            writers::write_ok_packet(pkt_writer, 0, 0, StatusFlags::SERVER_STATUS_AUTOCOMMIT).await
+        } else {
+            Err(cmd_error(
+                ErrorKind::ER_NOT_SUPPORTED_YET,
+                format!("unsupported query {query}"),
+            ))
+        }
+    }
+
+    async fn on_execute<'a, W>(
+        &mut self,
+        _stmt_id: u32,
+        _params: &[u8],
+        _pkt_writer: &mut PacketWriter<W>,
+    ) -> Result<(), std::io::Error>
+    where
+        W: AsyncWrite + Send + Unpin,
+    {
+        Err(cmd_error(
+            ErrorKind::ER_UNKNOWN_STMT_HANDLER,
+            "prepared statements are not supported",
+        ))
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), std::io::Error> {
+    tracing_subscriber::fmt().init();
+    let server = Arc::new(StaticProxyServer::new());
+    let table: Table = Arc::new(RwLock::new(Vec::new()));
+    let listener = TcpListener::bind("127.0.0.1:3320").await?;
+    info!("InMemoryTable listening on 127.0.0.1:3320");
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        let server = Arc::clone(&server);
+        let handler = InMemoryTable {
+            table: Arc::clone(&table),
+            capabilities: CapabilityFlags::empty(),
+        };
+        tokio::spawn(async move {
+            let (reader, writer) = stream.into_split();
+            if let Err(e) = server.run(reader, writer, handler).await {
+                warn!("InMemoryTable connection {addr} closed with error {e:?}");
+            }
+        });
+    }
+}
@@ -0,0 +1,334 @@
+use crate::prost::common_proto::TenantKey;
+use crate::prost::control_plane::AuditEvent;
+
+use chrono::Local;
+use std::collections::VecDeque;
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+
+/// Events kept for the control plane until it pulls them, the oldest are dropped first.
+pub const AUDIT_PENDING_CAPACITY: usize = 65536;
+
+/// Where the audit events go.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuditSink {
+    /// One JSON line per event on the standard output.
+    Stdout,
+    /// One JSON line per event appended to the file.
+    JsonFile(PathBuf),
+    /// Keeps the events until the control plane pulls them on the control plane stream.
+    ControlPlane,
+}
+
+/// Parses `stdout`, `file:<PATH>` or `control-plane`.
+impl FromStr for AuditSink {
+    type Err = Error;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        match spec.trim() {
+            "stdout" => Ok(AuditSink::Stdout),
+            "control-plane" => Ok(AuditSink::ControlPlane),
+            spec => match spec.strip_prefix("file:") {
+                Some(path) if !path.is_empty() => Ok(AuditSink::JsonFile(PathBuf::from(path))),
+                _ => Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "unknown audit sink {spec}, expected stdout, file:<PATH> or control-plane"
+                    ),
+                )),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditEventKind {
+    ConnectionAccepted,
+    AuthSuccess,
+    AuthFailure,
+    BackendSelected,
+    ConnectionClosed,
+}
+
+impl AuditEventKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditEventKind::ConnectionAccepted => "connection_accepted",
+            AuditEventKind::AuthSuccess => "auth_success",
+            AuditEventKind::AuthFailure => "auth_failure",
+            AuditEventKind::BackendSelected => "backend_selected",
+            AuditEventKind::ConnectionClosed => "connection_closed",
+        }
+    }
+}
+
+/// `AuditLog` hands the authentication and connection events of the clients to the configured
+/// sink, for the security teams to keep track of who connected to which tenant.
+pub struct AuditLog {
+    lines_tx: Option<mpsc::UnboundedSender<AuditEvent>>,
+    pending: Option<Mutex<VecDeque<AuditEvent>>>,
+}
+
+static AUDIT_LOG_ONCE: OnceLock<AuditLog> = OnceLock::new();
+
+/// Starts the audit log if a sink is configured, must be called within the tokio runtime.
+pub fn init_audit_log(sink: Option<AuditSink>) {
+    let Some(sink) = sink else {
+        return;
+    };
+    let audit_log = match sink {
+        AuditSink::Stdout => {
+            let (tx, rx) = mpsc::unbounded_channel();
+            tokio::spawn(write_json_lines(tokio::io::stdout(), rx));
+            AuditLog::new(Some(tx), false)
+        }
+        AuditSink::JsonFile(path) => {
+            let (tx, rx) = mpsc::unbounded_channel();
+            tokio::spawn(write_json_file(path, rx));
+            AuditLog::new(Some(tx), false)
+        }
+        AuditSink::ControlPlane => AuditLog::new(None, true),
+    };
+    if AUDIT_LOG_ONCE.set(audit_log).is_err() {
+        warn!("ProxySrv audit log already initialized");
+    }
+}
+
+pub fn audit_log() -> Option<&'static AuditLog> {
+    AUDIT_LOG_ONCE.get()
+}
+
+async fn write_json_file(path: PathBuf, rx: mpsc::UnboundedReceiver<AuditEvent>) {
+    match tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .await
+    {
+        Ok(file) => {
+            info!("ProxySrv audit events are appended to {:?}", path);
+            write_json_lines(file, rx).await
+        }
+        Err(e) => warn!(
+            "ProxySrv audit log failed to open {:?}. cause by {e:?}",
+            path
+        ),
+    }
+}
+
+async fn write_json_lines<W>(mut out: W, mut rx: mpsc::UnboundedReceiver<AuditEvent>)
+where
+    W: AsyncWrite + Unpin,
+{
+    while let Some(event) = rx.recv().await {
+        let mut line = match serde_json::to_vec(&event) {
+            Ok(line) => line,
+            Err(e) => {
+                warn!("ProxySrv audit event serialize err. cause by {e:?}");
+                continue;
+            }
+        };
+        line.push(b'\n');
+        let written = match out.write_all(&line).await {
+            Ok(()) => out.flush().await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = written {
+            warn!("ProxySrv audit event write err. cause by {e:?}");
+        }
+    }
+}
+
+impl AuditLog {
+    pub fn new(lines_tx: Option<mpsc::UnboundedSender<AuditEvent>>, control_plane: bool) -> Self {
+        Self {
+            lines_tx,
+            pending: control_plane.then(|| Mutex::new(VecDeque::new())),
+        }
+    }
+
+    pub fn record(&self, event: AuditEvent) {
+        if let Some(pending) = &self.pending {
+            let mut pending = pending.lock().unwrap();
+            if pending.len() >= AUDIT_PENDING_CAPACITY {
+                let dropped = pending.pop_front();
+                warn!("ProxySrv audit event dropped, control plane lagging {dropped:?}");
+            }
+            pending.push_back(event.clone());
+        }
+        if let Some(lines_tx) = &self.lines_tx {
+            if let Err(e) = lines_tx.send(event) {
+                warn!("ProxySrv audit event dropped, writer stopped {:?}", e.0);
+            }
+        }
+    }
+
+    /// Takes the events waiting for the control plane.
+    pub fn drain(&self) -> Vec<AuditEvent> {
+        match &self.pending {
+            Some(pending) => pending.lock().unwrap().drain(..).collect(),
+            None => Vec::new(),
+        }
+    }
+}
+
+static NEXT_CONN_ID: AtomicU64 = AtomicU64::new(1);
+
+/// `AuditConn` records the events of one client connection, each with what is known about the
+/// connection so far. Its `connection_closed` event is recorded when it is dropped, so every
+/// accepted connection is closed in the audit log however it ends.
+pub struct AuditConn {
+    conn_id: u64,
+    client_addr: String,
+    tenant: Option<TenantKey>,
+    user: String,
+    backend_addr: String,
+    close_reason: String,
+    bytes_in: u64,
+    bytes_out: u64,
+}
+
+impl AuditConn {
+    /// Records the `connection_accepted` event of a new client connection.
+    pub fn accepted(client_addr: Option<SocketAddr>) -> Self {
+        let conn = Self {
+            conn_id: NEXT_CONN_ID.fetch_add(1, Ordering::Relaxed),
+            client_addr: client_addr.map(|addr| addr.to_string()).unwrap_or_default(),
+            tenant: None,
+            user: String::new(),
+            backend_addr: String::new(),
+            close_reason: String::new(),
+            bytes_in: 0,
+            bytes_out: 0,
+        };
+        conn.record(AuditEventKind::ConnectionAccepted, "");
+        conn
+    }
+
+    pub fn conn_id(&self) -> u64 {
+        self.conn_id
+    }
+
+    /// Sets the tenant and the user the handshake response asks for.
+    pub fn set_user(&mut self, tenant: TenantKey, user: String) {
+        self.tenant = Some(tenant);
+        self.user = user;
+    }
+
+    pub fn backend_selected(&mut self, backend_addr: &str) {
+        self.backend_addr = backend_addr.to_string();
+        self.record(AuditEventKind::BackendSelected, "");
+    }
+
+    pub fn auth_success(&self) {
+        self.record(AuditEventKind::AuthSuccess, "");
+    }
+
+    /// Records an `auth_failure` event, `reason` is also the reason the connection closes with.
+    pub fn auth_failure(&mut self, reason: &str) {
+        self.record(AuditEventKind::AuthFailure, reason);
+        self.close_reason = reason.to_string();
+    }
+
+    pub fn set_close_reason(&mut self, reason: &str) {
+        self.close_reason = reason.to_string();
+    }
+
+    pub fn set_bytes(&mut self, bytes_in: u64, bytes_out: u64) {
+        self.bytes_in = bytes_in;
+        self.bytes_out = bytes_out;
+    }
+
+    fn record(&self, kind: AuditEventKind, reason: &str) {
+        if let Some(audit_log) = audit_log() {
+            audit_log.record(self.to_event(kind, reason));
+        }
+    }
+
+    pub fn to_event(&self, kind: AuditEventKind, reason: &str) -> AuditEvent {
+        let closed = kind == AuditEventKind::ConnectionClosed;
+        AuditEvent {
+            ts: Local::now().timestamp_millis() as u64,
+            event: kind.as_str().to_string(),
+            conn_id: self.conn_id,
+            client_addr: self.client_addr.clone(),
+            cluster: self.tenant.clone(),
+            user: self.user.clone(),
+            backend_addr: self.backend_addr.clone(),
+            reason: reason.to_string(),
+            bytes_in: if closed { self.bytes_in } else { 0 },
+            bytes_out: if closed { self.bytes_out } else { 0 },
+        }
+    }
+}
+
+impl Drop for AuditConn {
+    fn drop(&mut self) {
+        self.record(AuditEventKind::ConnectionClosed, &self.close_reason);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::audit::{AuditConn, AuditEventKind, AuditLog, AuditSink};
+    use crate::backend::test_tenant_key;
+    use std::path::PathBuf;
+    use tokio::sync::mpsc;
+
+    #[test]
+    pub fn test_audit_log() {
+        assert_eq!("stdout".parse::<AuditSink>().unwrap(), AuditSink::Stdout);
+        assert_eq!(
+            "file:/var/log/audit.jsonl".parse::<AuditSink>().unwrap(),
+            AuditSink::JsonFile(PathBuf::from("/var/log/audit.jsonl"))
+        );
+        assert!("file:".parse::<AuditSink>().is_err());
+        assert!("syslog".parse::<AuditSink>().is_err());
+
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let audit_log = AuditLog::new(Some(tx), true);
+        let mut conn = AuditConn::accepted(Some("198.51.100.7:51234".parse().unwrap()));
+        audit_log.record(conn.to_event(AuditEventKind::ConnectionAccepted, ""));
+        conn.set_user(test_tenant_key(), "root".to_string());
+        conn.backend_selected("10.0.0.1:3306");
+        audit_log.record(conn.to_event(AuditEventKind::BackendSelected, ""));
+        conn.auth_failure("Access denied for user 'root'");
+        audit_log.record(conn.to_event(AuditEventKind::AuthFailure, "denied"));
+        conn.set_bytes(120, 80);
+        audit_log.record(conn.to_event(AuditEventKind::ConnectionClosed, "denied"));
+
+        let events = audit_log.drain();
+        assert_eq!(events.len(), 4);
+        assert!(audit_log.drain().is_empty());
+        let kinds = events.iter().map(|e| e.event.as_str()).collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            [
+                "connection_accepted",
+                "backend_selected",
+                "auth_failure",
+                "connection_closed"
+            ]
+        );
+        assert!(events.iter().all(|e| e.conn_id == conn.conn_id()));
+        assert_eq!(events[0].client_addr, "198.51.100.7:51234");
+        assert!(events[0].cluster.is_none());
+        assert_eq!(events[2].user, "root");
+        assert_eq!(events[2].backend_addr, "10.0.0.1:3306");
+        // Only the closed event carries the byte counts.
+        assert_eq!((events[2].bytes_in, events[3].bytes_in), (0, 120));
+        assert_eq!(events[3].bytes_out, 80);
+
+        // The JSON line sinks receive the same events.
+        let line = serde_json::to_value(rx.try_recv().unwrap()).unwrap();
+        assert_eq!(line["event"], "connection_accepted");
+        assert_eq!(line["client_addr"], "198.51.100.7:51234");
+    }
+}
@@ -1,15 +1,21 @@
 use crate::backend::control_plane_resolver::{CpChannel, CpResolver};
+use crate::backend::replica::{replica_registry, ReplicaStatus};
+use crate::backend::tenant_activity::tenant_activity;
+use crate::backend::topology_freshness::topology_freshness;
 use crate::backend::BackendInstance;
 use crate::prost::common_proto::response::Payload;
 use crate::prost::common_proto::{ClusterName, DBLocation, Response, SubscribeId, TenantKey};
 use crate::prost::topology::topology_client::TopologyClient;
 use crate::prost::topology::SubscribeNamespaceRequest;
+use crate::server::notifier::{notify, ProxyEventKind};
 
 use anyhow::anyhow;
 use common::ShutdownMessage;
 use dashmap::DashMap;
 use futures_async_stream::stream;
+use itertools::Itertools;
 use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, OnceLock};
 use thiserror::Error;
 use tokio::sync;
@@ -50,6 +56,8 @@ pub struct BackendDiscovery {
     tenants: DashMap<TenantKey, SharedBackendList>,
     db_instance_tx: sync::watch::Sender<BackendInstance>,
     db_instance_rx: sync::watch::Receiver<BackendInstance>,
+    /// Whether the topology stream of the control plane is up.
+    connected: AtomicBool,
 }
 
 impl BackendDiscovery {
@@ -65,9 +73,15 @@ impl BackendDiscovery {
             tenants: DashMap::new(),
             db_instance_tx,
             db_instance_rx,
+            connected: AtomicBool::new(false),
         }
     }
 
+    /// Whether the topology stream of the control plane is up, the backends reported are current.
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::Acquire)
+    }
+
     pub async fn discover_with_retry(
         &self,
         cp_srv_resolver: Arc<CpResolver>,
@@ -83,7 +97,12 @@ impl BackendDiscovery {
             let subscribe_rs = self.send_subscribe(&cp_channel, shutdown_rx.clone()).await;
             match subscribe_rs {
                 Ok(_) => return Ok(()),
-                Err(_e) => {
+                Err(e) => {
+                    notify(
+                        ProxyEventKind::DiscoveryLost,
+                        &cp_channel.backend_name,
+                        format!("discovery stream lost: {e}"),
+                    );
                     cp_srv_resolver
                         .mark_backend_unavailable(&cp_channel.backend_name)
                         .await;
@@ -92,9 +111,11 @@ impl BackendDiscovery {
                         "BackendDiscovery retry to subscribe. The new cp backend {:?}",
                         cp_channel.backend_name,
                     );
-                    self.tenants.iter().for_each(|entry| {
-                        let tenant_key = entry.key();
-                        self.subscribe_for(tenant_key.clone()).unwrap();
+                    // The tenants in use get their backends back first.
+                    let tenant_keys = self.tenants.iter().map(|entry| entry.key().clone());
+                    let tenant_keys = tenant_activity().by_recency(tenant_keys.collect_vec());
+                    tenant_keys.into_iter().for_each(|tenant_key| {
+                        self.subscribe_for(tenant_key).unwrap();
                     });
                     // send subscribe request with new cp_channel
                 }
@@ -107,6 +128,7 @@ impl BackendDiscovery {
     }
 
     pub fn unsubscribed_tenants(&self, tenant_key: &TenantKey) {
+        topology_freshness().track(tenant_key);
         self.tenants
             .insert(tenant_key.clone(), Arc::new(RwLock::new(VecDeque::new())));
     }
@@ -176,6 +198,10 @@ impl BackendDiscovery {
                     let namespace = location.namespace.clone();
                     let cluster_name = &db_service.cluster;
                     let db_service_status = db_service.status();
+                    replica_registry().update(ReplicaStatus::from_labels(
+                        addr.clone(),
+                        &db_service.payload,
+                    ));
                     let tenant_key = TenantKey {
                         region: "".to_string(),
                         available_zone: "".to_string(),
@@ -193,6 +219,7 @@ impl BackendDiscovery {
                             cluster_name: cluster_name.to_string(),
                         },
                     );
+                    topology_freshness().record_event(&tenant_key);
                     let cluster_db_instance_list_ref = self.tenants.get(&tenant_key).unwrap();
                     let mut cluster_db_instance_list = cluster_db_instance_list_ref.write().await;
                     cluster_db_instance_list.retain(|e| e.addr != addr);
@@ -233,7 +260,8 @@ impl BackendDiscovery {
         match response_rs {
             Ok(response) => {
                 let mut streaming = response.into_inner();
-                tokio::select! {
+                self.connected.store(true, Ordering::Release);
+                let subscribe_rs = tokio::select! {
                     recv_rs = self.recv_stream(&mut streaming) => {
                         match recv_rs {
                             Err(e)=> {
@@ -253,7 +281,9 @@ impl BackendDiscovery {
                         }
                         Ok(())
                     }
-                }
+                };
+                self.connected.store(false, Ordering::Release);
+                subscribe_rs
             }
             Err(e) => {
                 warn!(
@@ -1,17 +1,43 @@
-use crate::backend::pool::pooled_conn_mgr::PooledConnMgr;
-use crate::backend::pool::BackendPoolConfig;
+use crate::backend::pool::pooled_conn_mgr::{spawn_health_check, PooledConnMgr};
+use crate::backend::pool::{
+    BackendPoolConfig, PoolEventCounts, PoolEventHook, PoolWarmup, PooledConn,
+};
 
+use crate::backend::capability::capability_cache;
+use crate::backend::quarantine::quarantine_registry;
+use crate::backend::router::p2c::backend_conns;
 use crate::backend::router::{BackendLoadBalancerType, BackendRouter, BackendRouterTrait};
-use crate::backend::{decode_tenant_key, test_tenant_key, BackendInstance};
+use crate::backend::status_events::StatusEventQueue;
+use crate::backend::tenant_activity::tenant_activity;
+use crate::backend::{backend_tenant_key, BackendInstance};
 use crate::prost::common_proto::{ServiceStatus, TenantKey};
 use crate::protocol::mysql::basic::HandshakeResponse;
+use crate::server::drain::{drain_registry, serves_tenant};
+use crate::server::notifier::{notify, ProxyEventKind};
+use crate::server::reload::RuntimeConfig;
+use crate::server::request_id::{current_request_id, with_deferred_request_id};
+use crate::server::watchdog::ShedAction;
 
+use chrono::{Local, SecondsFormat};
+use common::metrics::metric_def::{
+    PROXY_BACKEND_EVENT_LATENCY, PROXY_BACKEND_EVENT_QUEUE, PROXY_POOL_WARMUP_READY,
+};
+use common::metrics::{common_labels, gauge, histogram_handle, MetricsTimer};
 use dashmap::DashMap;
-use deadpool::managed::{Object, Pool};
+use deadpool::managed::{Metrics, Object, Pool, PoolError};
+use futures::stream::FuturesUnordered;
+use futures::StreamExt;
 use itertools::Itertools;
+use serde::Serialize;
+use std::collections::VecDeque;
 use std::io::ErrorKind;
-use std::str;
-use std::sync::{Arc, OnceLock};
+use std::ops::DerefMut;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
 use tracing::{debug, info, warn};
 
 #[derive(Debug, Clone)]
@@ -21,6 +47,13 @@ pub struct BackendManagerOptions {
     pub static_router: bool,
     pub balance_type: BackendLoadBalancerType,
     pub pool_config: BackendPoolConfig,
+    /// Backends whose status events are applied to the pools at the same time.
+    pub status_event_parallelism: usize,
+    /// Sessions hand their backend connection back to the pool between transactions.
+    pub multiplexing: bool,
+    /// Time the sessions of a backend marked Offline have to hand back their connections
+    /// before its pool is closed, see [`BackendMgr::draining_pools`].
+    pub pool_drain_timeout: Duration,
 }
 
 impl Default for BackendManagerOptions {
@@ -31,12 +64,92 @@ impl Default for BackendManagerOptions {
             static_router: true,
             balance_type: BackendLoadBalancerType::Random,
             pool_config: BackendPoolConfig::default(),
+            status_event_parallelism: 8,
+            multiplexing: false,
+            pool_drain_timeout: DEFAULT_POOL_DRAIN_TIMEOUT,
         }
     }
 }
 
+pub const DEFAULT_POOL_DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+/// How often a draining pool closes the connections handed back.
+const POOL_DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// The pool of a backend marked Offline, drained before it is closed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PoolDrainStatus {
+    pub addr: String,
+    pub cluster: String,
+    /// RFC 3339 time the drain started.
+    pub started_at: String,
+    pub elapsed_ms: u64,
+    pub timeout_ms: u64,
+    /// Connections still checked out by sessions.
+    pub in_use: usize,
+    /// Connections closed once their sessions handed them back.
+    pub closed: usize,
+}
+
+struct PoolDrain {
+    /// Tells the drains of the same backend apart, the backend may go Offline again while the
+    /// pool it got meanwhile is still drained.
+    id: u64,
+    pool: Pool<PooledConnMgr>,
+    started_at: String,
+    started: Instant,
+    timeout: Duration,
+    closed: usize,
+}
+
+type DrainingPools = Arc<DashMap<BackendInstance, PoolDrain>>;
+
+static NEXT_DRAIN_ID: AtomicU64 = AtomicU64::new(0);
+
+/// A connection pool of a backend as shown on the status page.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BackendPoolStatus {
+    pub addr: String,
+    pub cluster: String,
+    pub status: String,
+    pub max_size: usize,
+    pub size: usize,
+    pub available: usize,
+    /// Sessions waiting for a connection of the exhausted pool.
+    pub waiting: usize,
+    /// Connections checked out by client sessions, the load the P2C balancer compares.
+    pub in_flight: usize,
+}
+
+/// A connection pool of a backend with the events counted since it was created.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BackendPoolStats {
+    #[serde(flatten)]
+    pub pool: BackendPoolStatus,
+    /// Connections of the pool checked out, including those of sessions still authenticating.
+    pub in_use: usize,
+    #[serde(flatten)]
+    pub events: PoolEventCounts,
+}
+
 static BE_MGR_ONCE: OnceLock<Arc<BackendMgr>> = OnceLock::new();
 
+fn pool_status(backend: &BackendInstance, pool: &Pool<PooledConnMgr>) -> BackendPoolStatus {
+    let pool_status = pool.status();
+    BackendPoolStatus {
+        addr: backend.addr.clone(),
+        cluster: format!(
+            "{}/{}",
+            backend.cluster.namespace, backend.cluster.cluster_name
+        ),
+        status: backend.status.as_str_name().to_string(),
+        max_size: pool_status.max_size,
+        size: pool_status.size,
+        available: pool_status.available,
+        waiting: pool_status.waiting,
+        in_flight: backend_conns().in_flight(&backend.addr),
+    }
+}
+
 pub fn get_or_init_backend_mgr(
     router: BackendRouterTrait,
     mgr_options: BackendManagerOptions,
@@ -48,38 +161,85 @@ pub struct BackendMgr {
     mgr_options: BackendManagerOptions,
     router: BackendRouterTrait,
     be_conn_pool: DashMap<BackendInstance, Pool<PooledConnMgr>>,
+    /// Pools of Offline backends still used by sessions, they are closed once handed back.
+    draining_pools: DrainingPools,
+    pool_event_hooks: RwLock<Vec<PoolEventHook>>,
+    /// The size of the pools, changed by a configuration reload.
+    pool_max_size: AtomicUsize,
 }
 
 impl BackendMgr {
     pub fn new(router: BackendRouterTrait, mgr_options: BackendManagerOptions) -> Self {
         Self {
+            pool_max_size: AtomicUsize::new(mgr_options.pool_config.max_size as usize),
             mgr_options,
             router,
             be_conn_pool: DashMap::new(),
+            draining_pools: Arc::new(DashMap::new()),
+            pool_event_hooks: RwLock::new(vec![]),
         }
     }
 
+    /// Adds a hook called on the events of every backend pool initialized afterwards.
+    pub fn add_pool_event_hook(&self, hook: PoolEventHook) {
+        self.pool_event_hooks.write().unwrap().push(hook);
+    }
+
     async fn init_backend_pool(
         &self,
         backend_instance: BackendInstance,
     ) -> Result<(), std::io::Error> {
         let backend_status = backend_instance.status;
-        let max_size = self.mgr_options.pool_config.max_size;
+        let pool_config = &self.mgr_options.pool_config;
         match backend_status {
+            // A backend reported Ready again keeps its pool and the connections warmed up in it.
+            ServiceStatus::Ready if self.be_conn_pool.contains_key(&backend_instance) => Ok(()),
+            // The pool is initialized by the next connection of the tenant.
+            ServiceStatus::Ready if tenant_activity().is_cold_backend(&backend_instance) => {
+                debug!(
+                    "ProxySrv backend_mgr skips the pool of cold {:?}",
+                    backend_instance.addr
+                );
+                Ok(())
+            }
+            // The pool is initialized once the drain of the tenant is removed.
+            ServiceStatus::Ready if drain_registry().drains_backend(&backend_instance) => {
+                debug!(
+                    "ProxySrv backend_mgr skips the pool of drained {:?}",
+                    backend_instance.addr
+                );
+                Ok(())
+            }
             ServiceStatus::Ready => {
-                let conn_mgr = PooledConnMgr::new(backend_instance.clone());
-                let inner_pool_rs = Pool::builder(conn_mgr).max_size(max_size as usize).build();
+                let conn_mgr = self.pool_event_hooks.read().unwrap().iter().fold(
+                    PooledConnMgr::new(backend_instance.clone(), pool_config),
+                    |conn_mgr, hook| conn_mgr.with_event_hook(Arc::clone(hook)),
+                );
+                let inner_pool_rs = Pool::builder(conn_mgr)
+                    .max_size(self.pool_max_size.load(Ordering::Relaxed))
+                    .build();
                 match inner_pool_rs {
                     Ok(inner_pool) => {
                         info!(
                             "ProxySrv backend_mgr conn pool initialized successfully. {:?}",
                             backend_instance.addr
                         );
+                        spawn_health_check(&inner_pool, pool_config.health_check);
+                        spawn_initial_conns(
+                            &inner_pool,
+                            pool_config.initial_size as usize,
+                            pool_config.warmup.backend_timeout,
+                        );
                         self.be_conn_pool.insert(backend_instance, inner_pool);
                         Ok(())
                     }
                     Err(e) => {
                         warn!("ProxySrv backend_mgr init backend_conn_pool Err {:?}", e);
+                        notify(
+                            ProxyEventKind::BackendUnhealthy,
+                            &backend_instance.addr,
+                            format!("connection pool build failed: {e}"),
+                        );
                         Err(std::io::Error::new(
                             ErrorKind::ConnectionRefused,
                             e.to_string(),
@@ -88,27 +248,209 @@ impl BackendMgr {
                 }
             }
             ServiceStatus::Offline => {
-                if let Some(entry) = self.be_conn_pool.get(&backend_instance) {
-                    let pool = entry.value();
-                    pool.close();
+                notify(
+                    ProxyEventKind::BackendUnhealthy,
+                    &backend_instance.addr,
+                    "backend went offline".to_string(),
+                );
+                capability_cache().remove(&backend_instance.addr);
+                // The pool is keyed by the backend as it was reported Ready.
+                let pooled = self
+                    .be_conn_pool
+                    .iter()
+                    .map(|entry| entry.key().clone())
+                    .find(|backend| {
+                        backend.addr == backend_instance.addr
+                            && backend.cluster == backend_instance.cluster
+                    });
+                if let Some((backend, pool)) =
+                    pooled.and_then(|backend| self.be_conn_pool.remove(&backend))
+                {
+                    self.drain_pool(backend, pool);
                 }
-                self.be_conn_pool.remove(&backend_instance);
                 Ok(())
             }
             ServiceStatus::UnKnowStatus | ServiceStatus::NotReady => Ok(()),
         }
     }
 
-    pub async fn prepare_backend_conn_pool(&self) -> Result<(), std::io::Error> {
-        self.router
-            .status_change_notify(|be| async move {
-                let rs = self.init_backend_pool(be.clone()).await;
-                if rs.is_err() {
-                    warn!("Failed to notify backend instance change");
+    /// Stops new checkouts from the pool of an Offline backend and closes it once its sessions
+    /// handed back their connections, or once the drain timeout passed. Those still using a
+    /// connection then lose it.
+    fn drain_pool(&self, backend: BackendInstance, pool: Pool<PooledConnMgr>) {
+        let timeout = self.mgr_options.pool_drain_timeout;
+        let in_use = pool.status().size.saturating_sub(pool.status().available);
+        info!(
+            "ProxySrv backend_mgr drains the pool of {:?}, {in_use} connections in use",
+            backend.addr
+        );
+        let drain = PoolDrain {
+            id: NEXT_DRAIN_ID.fetch_add(1, Ordering::Relaxed),
+            pool: pool.clone(),
+            started_at: Local::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            started: Instant::now(),
+            timeout,
+            closed: 0,
+        };
+        let drain_id = drain.id;
+        if let Some(previous) = self.draining_pools.insert(backend.clone(), drain) {
+            previous.pool.close();
+        }
+        tokio::spawn(drain_pool(
+            Arc::clone(&self.draining_pools),
+            backend,
+            drain_id,
+        ));
+    }
+
+    /// The pools of Offline backends still drained, their progress for the control plane
+    /// scaling the backends down.
+    pub fn draining_pools(&self) -> Vec<PoolDrainStatus> {
+        self.draining_pools
+            .iter()
+            .map(|entry| {
+                let (backend, drain) = (entry.key(), entry.value());
+                let pool_status = drain.pool.status();
+                PoolDrainStatus {
+                    addr: backend.addr.clone(),
+                    cluster: format!(
+                        "{}/{}",
+                        backend.cluster.namespace, backend.cluster.cluster_name
+                    ),
+                    started_at: drain.started_at.clone(),
+                    elapsed_ms: drain.started.elapsed().as_millis() as u64,
+                    timeout_ms: drain.timeout.as_millis() as u64,
+                    in_use: pool_status.size.saturating_sub(pool_status.available),
+                    closed: drain.closed,
                 }
-                rs
             })
-            .await
+            .sorted_by(|a, b| a.addr.cmp(&b.addr))
+            .collect()
+    }
+
+    /// Applies the backend status events of the router to the pools until the router stops. The
+    /// router only queues the events, a burst of them or a slow pool build does not hold back
+    /// the events that follow.
+    pub async fn prepare_backend_conn_pool(&self) -> Result<(), std::io::Error> {
+        let (event_tx, event_rx) = mpsc::unbounded_channel();
+        let notifying = async move {
+            self.router
+                .status_change_notify(|be| {
+                    let queued = event_tx.send(be).map_err(|_| {
+                        std::io::Error::new(ErrorKind::BrokenPipe, "status events not applied")
+                    });
+                    async move { queued }
+                })
+                .await
+            // The sender is dropped here, the events queued are still applied.
+        };
+        let (notify_rs, ()) = tokio::join!(notifying, self.apply_status_events(event_rx));
+        notify_rs
+    }
+
+    /// Applies the events of `events` to the pools, up to `status_event_parallelism` backends
+    /// at a time and in order per backend, see [`StatusEventQueue`].
+    async fn apply_status_events(&self, mut events: UnboundedReceiver<BackendInstance>) {
+        let parallelism = self.mgr_options.status_event_parallelism.max(1);
+        let mut queue = StatusEventQueue::default();
+        let mut applying = FuturesUnordered::new();
+        let mut receiving = true;
+        loop {
+            while applying.len() < parallelism {
+                let Some(backend) = queue.next_ready() else {
+                    break;
+                };
+                applying.push(self.apply_status_event(backend));
+            }
+            gauge(
+                PROXY_BACKEND_EVENT_QUEUE,
+                queue.depth() as f64,
+                Some(common_labels()),
+            );
+            tokio::select! {
+                event = events.recv(), if receiving => match event {
+                    Some(backend) => queue.push(backend),
+                    None => receiving = false,
+                },
+                Some(addr) = applying.next(), if !applying.is_empty() => queue.done(&addr),
+                else => return,
+            }
+        }
+    }
+
+    /// Applies one status event, returns the address of its backend.
+    async fn apply_status_event(&self, backend: BackendInstance) -> String {
+        let addr = backend.addr.clone();
+        let _timer = MetricsTimer::precise(histogram_handle(
+            PROXY_BACKEND_EVENT_LATENCY,
+            common_labels(),
+        ));
+        if self.init_backend_pool(backend).await.is_err() {
+            warn!("Failed to notify backend instance change");
+        }
+        addr
+    }
+
+    /// Opens the warm-up connections of every Ready backend, `parallelism` backends at a time,
+    /// creating their pools ahead of the status events. Returns the connections opened.
+    pub async fn warm_up_pools(&self) -> usize {
+        let warmup = &self.mgr_options.pool_config.warmup;
+        if warmup.conns_per_backend == 0 {
+            gauge(PROXY_POOL_WARMUP_READY, 1.0, Some(common_labels()));
+            return 0;
+        }
+        let backends = match self.router.load_backends(None).await {
+            Ok(backends) => backends
+                .into_iter()
+                .filter(|backend| backend.status == ServiceStatus::Ready)
+                .collect_vec(),
+            Err(e) => {
+                warn!("ProxySrv pool warm-up cannot load the backends {e:?}");
+                Vec::new()
+            }
+        };
+        let total = backends.len();
+        info!(
+            "ProxySrv pool warm-up of {total} backends, {} connections each, {} at a time",
+            warmup.conns_per_backend, warmup.parallelism
+        );
+        gauge(PROXY_POOL_WARMUP_READY, 0.0, Some(common_labels()));
+        let mut warmed = futures::stream::iter(backends)
+            .map(|backend| self.warm_up_pool(backend, warmup))
+            .buffer_unordered(warmup.parallelism.max(1));
+        let (mut done, mut opened) = (0, 0);
+        while let Some((addr, conns)) = warmed.next().await {
+            done += 1;
+            opened += conns;
+            gauge(
+                PROXY_POOL_WARMUP_READY,
+                done as f64 / total as f64,
+                Some(common_labels()),
+            );
+            info!("ProxySrv pool warm-up {done}/{total} backends, {addr} opened {conns}");
+        }
+        gauge(PROXY_POOL_WARMUP_READY, 1.0, Some(common_labels()));
+        info!("ProxySrv pool warm-up opened {opened} connections");
+        opened
+    }
+
+    async fn warm_up_pool(&self, backend: BackendInstance, warmup: &PoolWarmup) -> (String, usize) {
+        if !self.be_conn_pool.contains_key(&backend) {
+            if let Err(e) = self.init_backend_pool(backend.clone()).await {
+                warn!("ProxySrv pool warm-up {} failed {e:?}", backend.addr);
+                return (backend.addr, 0);
+            }
+        }
+        let Ok(pool) = self.backend_pool(&backend) else {
+            return (backend.addr, 0);
+        };
+        let conns = open_warm_conns(
+            &pool,
+            warmup.conns_per_backend as usize,
+            warmup.backend_timeout,
+        )
+        .await;
+        (backend.addr, conns)
     }
 
     pub async fn connect_to_backend(
@@ -117,22 +459,107 @@ impl BackendMgr {
     ) -> Result<Pool<PooledConnMgr, Object<PooledConnMgr>>, std::io::Error> {
         let balancer_type = &self.mgr_options.balance_type;
         // 1. get BackendAddr list by user
-        let tenant = if let Some(tenant_encode_key) = &client_handshake_rsp.tenant_key {
-            let tenant_encode_str = str::from_utf8(tenant_encode_key).unwrap();
-            decode_tenant_key(tenant_encode_str)
-        } else {
-            test_tenant_key()
-        };
+        let tenant = backend_tenant_key(client_handshake_rsp);
         debug!(
             "ProxySrv backend_mgr connect_to_backend tenant {:?}",
             &tenant
         );
+        if let Some(backend) = client_handshake_rsp
+            .reconnect
+            .as_ref()
+            .and_then(|grant| self.affine_backend(&tenant, &grant.backend_addr))
+        {
+            return self.tenant_pool(&tenant, &backend).await;
+        }
         let backend_addr = self.router.selector(&tenant, balancer_type).await?;
         // debug!(
         //     "ProxySrv backend_mgr selected backend_addr {:?}",
         //     &backend_addr.addr
         // );
-        return if let Some(pool) = self.be_conn_pool.get(&backend_addr) {
+        self.tenant_pool(&tenant, &backend_addr).await
+    }
+
+    /// Checks a connection out of the pool of the backend the router picks for a session, waiting
+    /// up to `timeout` for one. Returns the address of the backend with the connection.
+    pub async fn checkout_conn(
+        &self,
+        client_handshake_rsp: &HandshakeResponse,
+        timeout: Duration,
+    ) -> Result<(String, Object<PooledConnMgr>), std::io::Error> {
+        let pool = self.connect_to_backend(client_handshake_rsp).await?;
+        let addr = pool.manager().get_addr().await;
+        let conn = tokio::time::timeout(timeout, pool.get())
+            .await
+            .map_err(|_| {
+                std::io::Error::new(ErrorKind::TimedOut, format!("{addr} pool exhausted"))
+            })?
+            .map_err(|e| std::io::Error::new(ErrorKind::NotConnected, e.to_string()))?;
+        Ok((addr, conn))
+    }
+
+    /// Like [`connect_to_backend`](BackendMgr::connect_to_backend), but picks a backend for
+    /// read-only traffic that is not lagging behind.
+    pub async fn connect_to_read_backend(
+        &self,
+        client_handshake_rsp: &HandshakeResponse,
+    ) -> Result<Pool<PooledConnMgr, Object<PooledConnMgr>>, std::io::Error> {
+        let balancer_type = &self.mgr_options.balance_type;
+        let tenant = backend_tenant_key(client_handshake_rsp);
+        let backend_addr = self.router.read_selector(&tenant, balancer_type).await?;
+        self.tenant_pool(&tenant, &backend_addr).await
+    }
+
+    /// Like [`connect_to_backend`](BackendMgr::connect_to_backend), but on the backend at `addr`
+    /// the route policy chose, which has to serve the tenant.
+    pub async fn connect_to_backend_at(
+        &self,
+        client_handshake_rsp: &HandshakeResponse,
+        addr: &str,
+    ) -> Result<Pool<PooledConnMgr, Object<PooledConnMgr>>, std::io::Error> {
+        let tenant = backend_tenant_key(client_handshake_rsp);
+        let backend = self.affine_backend(&tenant, addr).ok_or_else(|| {
+            std::io::Error::new(
+                ErrorKind::NotFound,
+                format!("backend {addr} of the route policy is unavailable to {tenant:?}"),
+            )
+        })?;
+        self.tenant_pool(&tenant, &backend).await
+    }
+
+    /// The backend at `addr` of `tenant`, e.g. the one a reconnecting client returns to, `None`
+    /// if it no longer serves the tenant or is quarantined.
+    fn affine_backend(&self, tenant: &TenantKey, addr: &str) -> Option<BackendInstance> {
+        if quarantine_registry().is_quarantined(addr) {
+            return None;
+        }
+        self.be_conn_pool
+            .iter()
+            .map(|entry| entry.key().clone())
+            .find(|backend| backend.addr == addr && serves_tenant(backend, tenant))
+    }
+
+    /// The pool of a backend of `tenant`, reopening the pools of the tenant if it was cold.
+    async fn tenant_pool(
+        &self,
+        tenant: &TenantKey,
+        backend_addr: &BackendInstance,
+    ) -> Result<Pool<PooledConnMgr, Object<PooledConnMgr>>, std::io::Error> {
+        let activity = tenant_activity();
+        if activity.touch(tenant) {
+            let restored = self.restore_tenant_pools(tenant).await.inspect_err(|_| {
+                // Retried by the next connection.
+                activity.cool(tenant);
+            })?;
+            info!("ProxySrv backend_mgr reopened {restored} pools of cold tenant {tenant:?}");
+        }
+        self.backend_pool(backend_addr)
+    }
+
+    fn backend_pool(
+        &self,
+        backend_addr: &BackendInstance,
+    ) -> Result<Pool<PooledConnMgr, Object<PooledConnMgr>>, std::io::Error> {
+        if let Some(pool) = self.be_conn_pool.get(backend_addr) {
             let pool_values = pool.value().clone();
             Ok(pool_values)
         } else {
@@ -140,7 +567,223 @@ impl BackendMgr {
                 ErrorKind::NotConnected,
                 "no backend_addr found",
             ))
+        }
+    }
+
+    /// Calls `f` with the idle connections of every backend pool and their pool metrics, those
+    /// it returns false for are detached. Returns the number of connections detached.
+    pub fn retain_conns(&self, mut f: impl FnMut(&PooledConn, Metrics) -> bool) -> usize {
+        self.be_conn_pool
+            .iter()
+            .map(|entry| entry.value().retain(&mut f).removed.len())
+            .sum()
+    }
+
+    /// The number of idle connections still holding state of the session that last used them,
+    /// see [`SessionStateTracker`](crate::server::forwarder::session_state::SessionStateTracker).
+    pub fn idle_conns_holding_state(&self) -> usize {
+        let mut holding = 0;
+        self.retain_conns(|pooled_conn, _| {
+            if !pooled_conn.session_state.is_clean() {
+                holding += 1;
+            }
+            true
+        });
+        holding
+    }
+
+    /// Applies the watchdog `action` to the idle connections of every backend pool, connections
+    /// in use are left alone. Returns the number of connections shed.
+    pub fn shed_idle_conns(&self, action: ShedAction) -> usize {
+        match action {
+            ShedAction::ShrinkBuffers => {
+                let mut shrunk = 0;
+                self.retain_conns(|pooled_conn, _| {
+                    if let Ok(mut inner_guard) = pooled_conn.inner_conn.try_lock() {
+                        let (reader, writer) = inner_guard.deref_mut();
+                        reader.shrink_buffers();
+                        writer.shrink_buffers();
+                        shrunk += 1;
+                    }
+                    true
+                });
+                shrunk
+            }
+            ShedAction::CloseIdle => self.retain_conns(|_, _| false),
+        }
+    }
+
+    /// Closes the pools of the backends of a tenant. Returns the number of pools closed.
+    pub fn release_tenant_pools(&self, tenant: &TenantKey) -> usize {
+        let backends = self
+            .be_conn_pool
+            .iter()
+            .filter(|entry| serves_tenant(entry.key(), tenant))
+            .map(|entry| entry.key().clone())
+            .collect_vec();
+        for backend in &backends {
+            if let Some((_, pool)) = self.be_conn_pool.remove(backend) {
+                info!(
+                    "ProxySrv backend_mgr released the pool of {:?}",
+                    backend.addr
+                );
+                pool.close();
+            }
+        }
+        backends.len()
+    }
+
+    /// Closes every backend pool on shutdown, once the sessions drained: the idle connections
+    /// quit their backend, those still checked out are closed when returned. Returns the number
+    /// of connections closed.
+    pub async fn close_pools(&self) -> usize {
+        let pools = self
+            .be_conn_pool
+            .iter()
+            .map(|entry| entry.value().clone())
+            .collect_vec();
+        self.be_conn_pool.clear();
+        for entry in self.draining_pools.iter() {
+            entry.value().pool.close();
+        }
+        self.draining_pools.clear();
+        let mut closed = 0;
+        for pool in pools {
+            let idle = pool.retain(|_, _| false).removed;
+            pool.close();
+            for pooled_conn in idle {
+                // Detaching closes it in the background too, whichever comes first quits.
+                let _ = pooled_conn.close().await;
+                closed += 1;
+            }
+        }
+        closed
+    }
+
+    /// Initializes the pools of the Ready backends of a tenant, e.g. once its drain was removed.
+    /// Returns the number of pools initialized.
+    pub async fn restore_tenant_pools(&self, tenant: &TenantKey) -> Result<usize, std::io::Error> {
+        let backends = self.router.load_backends(Some(tenant.clone())).await?;
+        let mut restored = 0;
+        for backend in backends {
+            if backend.status == ServiceStatus::Ready
+                && serves_tenant(&backend, tenant)
+                && !self.be_conn_pool.contains_key(&backend)
+            {
+                self.init_backend_pool(backend).await?;
+                restored += 1;
+            }
+        }
+        Ok(restored)
+    }
+
+    /// Applies the pool size and the static backends of every configuration reload, see
+    /// [`ConfigReloader`](crate::server::reload::ConfigReloader), with the id of the request
+    /// that reloaded it.
+    pub async fn apply_runtime_config(&self, mut runtime_rx: watch::Receiver<RuntimeConfig>) {
+        let mut applied = runtime_rx.borrow_and_update().clone();
+        while runtime_rx.changed().await.is_ok() {
+            let config = runtime_rx.borrow_and_update().clone();
+            with_deferred_request_id(config.request_id.clone(), async {
+                if config.pool_max_size != applied.pool_max_size {
+                    self.resize_pools(config.pool_max_size as usize);
+                }
+                if config.static_backends != applied.static_backends {
+                    self.set_static_backends(config.static_backends.clone())
+                        .await;
+                }
+            })
+            .await;
+            applied = config;
+        }
+    }
+
+    /// Resizes every pool, those over the size close the connections they hand back.
+    pub fn resize_pools(&self, max_size: usize) {
+        self.pool_max_size.store(max_size, Ordering::Relaxed);
+        for entry in self.be_conn_pool.iter() {
+            entry.value().resize(max_size);
+        }
+        info!(
+            "ProxySrv backend_mgr pools resized to {max_size} request_id={:?}",
+            current_request_id()
+        );
+    }
+
+    /// Replaces the backends of the static router: the pools of the backends added are
+    /// initialized and those of the backends removed closed.
+    pub async fn set_static_backends(&self, backends: VecDeque<BackendInstance>) {
+        let Some((added, removed)) = self.router.set_static_backends(backends) else {
+            warn!("ProxySrv backend_mgr keeps the backends, the router discovers them");
+            return;
         };
+        for backend in removed {
+            info!(
+                "ProxySrv backend_mgr removes static backend {}",
+                backend.addr
+            );
+            capability_cache().remove(&backend.addr);
+            if let Some((_, pool)) = self.be_conn_pool.remove(&backend) {
+                pool.close();
+            }
+        }
+        for backend in added {
+            info!("ProxySrv backend_mgr adds static backend {}", backend.addr);
+            self.apply_status_event(backend).await;
+        }
+    }
+
+    pub fn is_static_router(&self) -> bool {
+        self.router.is_static()
+    }
+
+    pub fn is_multiplexing(&self) -> bool {
+        self.mgr_options.multiplexing
+    }
+
+    pub fn is_read_write_router(&self) -> bool {
+        self.router.is_read_write()
+    }
+
+    pub fn cp_connected(&self) -> Option<bool> {
+        self.router.cp_connected()
+    }
+
+    /// Every backend the router knows, with or without a pool.
+    pub async fn discovered_backends(&self) -> Vec<BackendInstance> {
+        match self.router.load_backends(None).await {
+            Ok(backends) => backends.into(),
+            Err(e) => {
+                warn!("ProxySrv backend_mgr cannot load the backends {e:?}");
+                Vec::new()
+            }
+        }
+    }
+
+    /// The pool of every backend, ordered by address.
+    pub fn pool_statuses(&self) -> Vec<BackendPoolStatus> {
+        self.be_conn_pool
+            .iter()
+            .map(|entry| pool_status(entry.key(), entry.value()))
+            .sorted_by(|a, b| a.addr.cmp(&b.addr))
+            .collect()
+    }
+
+    /// Like [`pool_statuses`](BackendMgr::pool_statuses), with the connections in use and the
+    /// pool events.
+    pub fn pool_stats(&self) -> Vec<BackendPoolStats> {
+        self.be_conn_pool
+            .iter()
+            .map(|entry| {
+                let pool = pool_status(entry.key(), entry.value());
+                BackendPoolStats {
+                    in_use: pool.size.saturating_sub(pool.available),
+                    events: entry.value().manager().event_counts(),
+                    pool,
+                }
+            })
+            .sorted_by(|a, b| a.pool.addr.cmp(&b.pool.addr))
+            .collect()
     }
 
     pub fn tenant_status(&self, tenant: TenantKey) -> ServiceStatus {
@@ -160,3 +803,271 @@ impl BackendMgr {
             .unwrap_or(ServiceStatus::UnKnowStatus)
     }
 }
+
+/// Opens up to `conns` connections in `pool` within `timeout` and hands them back to it idle.
+/// Returns the connections opened.
+async fn open_warm_conns(pool: &Pool<PooledConnMgr>, conns: usize, timeout: Duration) -> usize {
+    let conns = conns.min(pool.status().max_size);
+    let mut warm_conns = Vec::with_capacity(conns);
+    let open_rs = tokio::time::timeout(timeout, async {
+        while warm_conns.len() < conns {
+            warm_conns.push(pool.get().await?);
+        }
+        Ok::<_, PoolError<std::io::Error>>(())
+    })
+    .await;
+    match open_rs {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => warn!("ProxySrv pool warm-up connect failed {e:?}"),
+        Err(_) => warn!(
+            "ProxySrv pool warm-up opened {} of {conns} connections within {timeout:?}",
+            warm_conns.len()
+        ),
+    }
+    warm_conns.len()
+}
+
+/// Opens the `initial_size` connections of a new pool in the background, up to the initial
+/// handshake of the backend, and hands them back to it idle. `None` if there are none to open.
+fn spawn_initial_conns(
+    pool: &Pool<PooledConnMgr>,
+    initial_size: usize,
+    timeout: Duration,
+) -> Option<JoinHandle<usize>> {
+    if initial_size == 0 {
+        return None;
+    }
+    let pool = pool.clone();
+    Some(tokio::spawn(async move {
+        let backend_addr = pool.manager().get_addr().await;
+        let opened = open_warm_conns(&pool, initial_size, timeout).await;
+        info!(
+            "ProxySrv pool of {backend_addr} opened {opened} of {initial_size} initial connections"
+        );
+        opened
+    }))
+}
+
+/// Runs the drain `drain_id` of a pool until the connections are handed back or the timeout
+/// passed, then closes the pool. Stops early if the drain was replaced or the pool closed.
+async fn drain_pool(draining: DrainingPools, backend: BackendInstance, drain_id: u64) {
+    let mut ticker = tokio::time::interval(POOL_DRAIN_POLL_INTERVAL);
+    loop {
+        ticker.tick().await;
+        let Some((pool, timed_out)) = draining
+            .get(&backend)
+            .filter(|drain| drain.id == drain_id)
+            .map(|drain| (drain.pool.clone(), drain.started.elapsed() >= drain.timeout))
+        else {
+            return;
+        };
+        let handed_back = pool.retain(|_, _| false).removed;
+        if let Some(mut drain) = draining.get_mut(&backend) {
+            drain.closed += handed_back.len();
+        }
+        for pooled_conn in handed_back {
+            let _ = pooled_conn.close().await;
+        }
+        let in_use = pool.status().size;
+        if in_use == 0 || timed_out {
+            pool.close();
+            draining.remove_if(&backend, |_, drain| drain.id == drain_id);
+            if in_use > 0 {
+                warn!(
+                    "ProxySrv backend_mgr closed the pool of {:?} with {in_use} connections in use",
+                    backend.addr
+                );
+            } else {
+                info!(
+                    "ProxySrv backend_mgr drained the pool of {:?}",
+                    backend.addr
+                );
+            }
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::backend_mgr::{
+        drain_pool, open_warm_conns, spawn_initial_conns, BackendManagerOptions, BackendMgr,
+        PoolDrain,
+    };
+    use crate::backend::pool::pooled_conn_mgr::PooledConnMgr;
+    use crate::backend::pool::{BackendPoolConfig, PoolEventCounts};
+    use crate::backend::router::new_backend_router;
+    use crate::backend::BackendInstance;
+    use crate::server::proxy_cli_args::{BackendConfigArgs, ProxyServerArgs};
+    use common::ShutdownMessage;
+    use dashmap::DashMap;
+    use deadpool::managed::Pool;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+    use tokio::net::TcpListener;
+    use tokio::sync::watch;
+
+    #[tokio::test]
+    pub async fn test_open_warm_conns() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend = BackendInstance {
+            addr: listener.local_addr().unwrap().to_string(),
+            ..Default::default()
+        };
+        let accepted = tokio::spawn(async move {
+            let mut peers = vec![];
+            while let Ok((peer, _)) = listener.accept().await {
+                peers.push(peer);
+            }
+        });
+        let pool = Pool::builder(PooledConnMgr::new(backend, &BackendPoolConfig::default()))
+            .max_size(2)
+            .build()
+            .unwrap();
+        assert_eq!(open_warm_conns(&pool, 3, Duration::from_secs(5)).await, 2);
+        let status = pool.status();
+        assert_eq!((status.size, status.available), (2, 2));
+        accepted.abort();
+    }
+
+    #[tokio::test]
+    pub async fn test_spawn_initial_conns() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend = BackendInstance {
+            addr: listener.local_addr().unwrap().to_string(),
+            ..Default::default()
+        };
+        let accepted = tokio::spawn(async move {
+            let mut peers = vec![];
+            while let Ok((peer, _)) = listener.accept().await {
+                peers.push(peer);
+            }
+        });
+        let pool = Pool::builder(PooledConnMgr::new(backend, &BackendPoolConfig::default()))
+            .max_size(4)
+            .build()
+            .unwrap();
+        let timeout = Duration::from_secs(5);
+        assert!(spawn_initial_conns(&pool, 0, timeout).is_none());
+        let opened = spawn_initial_conns(&pool, 3, timeout)
+            .unwrap()
+            .await
+            .unwrap();
+        assert_eq!(opened, 3);
+        let status = pool.status();
+        assert_eq!((status.size, status.available), (3, 3));
+        accepted.abort();
+    }
+
+    #[tokio::test]
+    pub async fn test_drain_pool() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend = BackendInstance {
+            addr: listener.local_addr().unwrap().to_string(),
+            ..Default::default()
+        };
+        let accepted = tokio::spawn(async move {
+            let mut peers = vec![];
+            while let Ok((peer, _)) = listener.accept().await {
+                peers.push(peer);
+            }
+        });
+        let pool = Pool::builder(PooledConnMgr::new(
+            backend.clone(),
+            &BackendPoolConfig::default(),
+        ))
+        .max_size(2)
+        .build()
+        .unwrap();
+        assert_eq!(open_warm_conns(&pool, 2, Duration::from_secs(5)).await, 2);
+        let in_use = pool.get().await.unwrap();
+
+        let draining = Arc::new(DashMap::new());
+        let drain = PoolDrain {
+            id: 7,
+            pool: pool.clone(),
+            started_at: String::new(),
+            started: Instant::now(),
+            timeout: Duration::from_secs(30),
+            closed: 0,
+        };
+        draining.insert(backend.clone(), drain);
+        let drained = tokio::spawn(drain_pool(Arc::clone(&draining), backend.clone(), 7));
+        // The idle connection is closed, the one in use waits for its session.
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(draining.get(&backend).unwrap().closed, 1);
+        assert!(!pool.is_closed());
+        drop(in_use);
+        tokio::time::timeout(Duration::from_secs(5), drained)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(draining.is_empty());
+        assert!(pool.is_closed());
+        accepted.abort();
+    }
+
+    #[tokio::test]
+    pub async fn test_pool_stats() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend = BackendInstance {
+            addr: listener.local_addr().unwrap().to_string(),
+            ..Default::default()
+        };
+        let accepted = tokio::spawn(async move {
+            let mut peers = vec![];
+            while let Ok((peer, _)) = listener.accept().await {
+                peers.push(peer);
+            }
+        });
+        let (_shutdown_tx, shutdown_rx) = watch::channel(ShutdownMessage::Init);
+        let args = ProxyServerArgs {
+            backend: Some(BackendConfigArgs::Backend {
+                backend_addr: backend.addr.clone(),
+            }),
+            ..Default::default()
+        };
+        let router = new_backend_router(&args, &shutdown_rx).await;
+        let backend_mgr = BackendMgr::new(router, BackendManagerOptions::default());
+        let pool = Pool::builder(PooledConnMgr::new(
+            backend.clone(),
+            &BackendPoolConfig::default(),
+        ))
+        .max_size(3)
+        .build()
+        .unwrap();
+        backend_mgr
+            .be_conn_pool
+            .insert(backend.clone(), pool.clone());
+        assert_eq!(open_warm_conns(&pool, 2, Duration::from_secs(5)).await, 2);
+        let in_use = pool.get().await.unwrap();
+
+        let stats = backend_mgr.pool_stats();
+        assert_eq!(stats.len(), 1);
+        let stats = &stats[0];
+        assert_eq!(stats.pool.addr, backend.addr);
+        assert_eq!(
+            (stats.pool.max_size, stats.pool.size, stats.pool.available),
+            (3, 2, 1)
+        );
+        assert_eq!(stats.in_use, 1);
+        // The checked out connection was recycled first.
+        assert_eq!(
+            stats.events,
+            PoolEventCounts {
+                created: 2,
+                recycled: 1,
+                ..Default::default()
+            }
+        );
+        // The status and the events are flattened into one object per pool.
+        let json = serde_json::to_value(stats).unwrap();
+        assert_eq!(json["addr"], backend.addr.as_str());
+        assert_eq!(json["in_use"], 1);
+        assert_eq!(json["created"], 2);
+        assert_eq!(json["detached"], 0);
+
+        drop(in_use);
+        accepted.abort();
+    }
+}
@@ -0,0 +1,149 @@
+use crate::server::{default_capabilities, DEFAULT_BACKEND_VERSION};
+
+use dashmap::DashMap;
+use itertools::Itertools;
+use mysql_common::constants::CapabilityFlags;
+use mysql_common::io::ParseBuf;
+use mysql_common::packets::HandshakePacket;
+use mysql_common::proto::MyDeserialize;
+use std::io::{Error, ErrorKind};
+use std::sync::OnceLock;
+use tracing::info;
+
+/// What a backend advertised in its initial handshake.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BackendCapabilities {
+    pub server_version: Vec<u8>,
+    pub capabilities: CapabilityFlags,
+    pub auth_plugin: String,
+    pub default_collation: u8,
+}
+
+impl BackendCapabilities {
+    /// Parses the initial handshake packet of a backend.
+    pub fn parse(handshake_init: &[u8]) -> Result<Self, Error> {
+        let pkt = HandshakePacket::deserialize((), &mut ParseBuf(handshake_init))
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        Ok(Self {
+            server_version: pkt.server_version_ref().to_vec(),
+            capabilities: pkt.capabilities(),
+            auth_plugin: pkt
+                .auth_plugin_name_str()
+                .map(|plugin| plugin.trim_end_matches('\0').to_string())
+                .unwrap_or_default(),
+            default_collation: pkt.default_collation(),
+        })
+    }
+}
+
+/// `CapabilityCache` keeps the handshake of every backend the pools connected to, keyed by the
+/// backend address. The proxy answers clients before it knows their backend, so it offers the
+/// capabilities all known backends support and, if they agree, their server version. Backends of
+/// different flavors (MySQL 5.7, 8.0, MariaDB) then never see a client capability they lack.
+#[derive(Default)]
+pub struct CapabilityCache {
+    backends: DashMap<String, BackendCapabilities>,
+}
+
+static CAPABILITY_CACHE_ONCE: OnceLock<CapabilityCache> = OnceLock::new();
+
+pub fn capability_cache() -> &'static CapabilityCache {
+    CAPABILITY_CACHE_ONCE.get_or_init(CapabilityCache::default)
+}
+
+impl CapabilityCache {
+    pub fn record(&self, backend_addr: &str, backend_caps: BackendCapabilities) {
+        if self.backends.get(backend_addr).as_deref() != Some(&backend_caps) {
+            info!(
+                "ProxySrv backend {backend_addr} capabilities {:?} version {:?} auth plugin {}",
+                backend_caps.capabilities,
+                String::from_utf8_lossy(&backend_caps.server_version),
+                backend_caps.auth_plugin
+            );
+            self.backends.insert(backend_addr.to_string(), backend_caps);
+        }
+    }
+
+    /// Forgets a backend, e.g. once it went offline to be upgraded.
+    pub fn remove(&self, backend_addr: &str) -> Option<BackendCapabilities> {
+        self.backends
+            .remove(backend_addr)
+            .map(|(_, backend_caps)| backend_caps)
+    }
+
+    pub fn get(&self, backend_addr: &str) -> Option<BackendCapabilities> {
+        self.backends.get(backend_addr).map(|e| e.value().clone())
+    }
+
+    /// The proxy capabilities every known backend supports.
+    pub fn client_capabilities(&self) -> CapabilityFlags {
+        self.backends
+            .iter()
+            .fold(default_capabilities(), |caps, e| caps & e.capabilities)
+    }
+
+    /// The version of the known backends if they all run the same one, otherwise the default.
+    pub fn server_version(&self) -> Vec<u8> {
+        match self
+            .backends
+            .iter()
+            .map(|e| e.server_version.clone())
+            .unique()
+            .exactly_one()
+        {
+            Ok(server_version) => server_version,
+            Err(_) => DEFAULT_BACKEND_VERSION.to_vec(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::capability::{BackendCapabilities, CapabilityCache};
+    use crate::server::{default_capabilities, DEFAULT_BACKEND_VERSION};
+    use mysql_common::constants::{CapabilityFlags, StatusFlags};
+    use mysql_common::packets::HandshakePacket;
+    use mysql_common::proto::MySerialize;
+
+    fn handshake(server_version: &[u8], capabilities: CapabilityFlags) -> Vec<u8> {
+        let pkt = HandshakePacket::new(
+            10,
+            server_version,
+            1,
+            [0; 8],
+            Some(&[0_u8; 13][..]),
+            capabilities,
+            45,
+            StatusFlags::SERVER_STATUS_AUTOCOMMIT,
+            Some(&b"caching_sha2_password"[..]),
+        );
+        let mut buf = Vec::new();
+        pkt.serialize(&mut buf);
+        buf
+    }
+
+    #[test]
+    pub fn test_capability_cache() {
+        let cache = CapabilityCache::default();
+        assert_eq!(cache.client_capabilities(), default_capabilities());
+        assert_eq!(cache.server_version(), DEFAULT_BACKEND_VERSION);
+
+        let mysql8 =
+            BackendCapabilities::parse(&handshake(b"8.0.36", default_capabilities())).unwrap();
+        assert_eq!(mysql8.auth_plugin, "caching_sha2_password");
+        assert_eq!(mysql8.default_collation, 45);
+        cache.record("10.0.0.1:3306", mysql8);
+        assert_eq!(cache.client_capabilities(), default_capabilities());
+        assert_eq!(cache.server_version(), b"8.0.36");
+
+        let without_metadata =
+            default_capabilities() - CapabilityFlags::CLIENT_OPTIONAL_RESULTSET_METADATA;
+        let mysql57 = BackendCapabilities::parse(&handshake(b"5.7.44", without_metadata)).unwrap();
+        cache.record("10.0.0.2:3306", mysql57);
+        assert_eq!(cache.client_capabilities(), without_metadata);
+        assert_eq!(cache.server_version(), DEFAULT_BACKEND_VERSION);
+
+        assert!(cache.remove("10.0.0.2:3306").is_some());
+        assert_eq!(cache.client_capabilities(), default_capabilities());
+    }
+}
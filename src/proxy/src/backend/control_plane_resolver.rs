@@ -1,3 +1,5 @@
+use crate::backend::egress::{egress_policy, EgressTarget};
+
 use common::ShutdownMessage;
 use dashmap::DashMap;
 use futures::StreamExt;
@@ -50,6 +52,10 @@ pub struct CpChannel {
 
 impl CpChannel {
     pub async fn new(cp_backend: &CpBackend) -> anyhow::Result<Self> {
+        // The members are reported by the control plane itself, they are checked like backends.
+        egress_policy()
+            .resolve(EgressTarget::ControlPlane, &cp_backend.service_addr)
+            .await?;
         let rpc_addr = format!("http://{}", cp_backend.service_addr);
         let channel = Channel::from_shared(rpc_addr.clone())
             .map_err(|e| anyhow::anyhow!("Failed to create channel: {:?}", e))?
@@ -114,6 +120,21 @@ impl CpResolver {
             "CpServiceResolver started ticker with interval {:?}",
             self.interval
         );
+        let cp_srv_uri = uri::Uri::try_from(self.cp_service.as_str())?;
+        if let Some(authority) = cp_srv_uri.authority() {
+            let default_port = match cp_srv_uri.scheme_str() {
+                Some("https") => 443,
+                _ => 80,
+            };
+            let cp_addr = format!(
+                "{}:{}",
+                authority.host(),
+                authority.port_u16().unwrap_or(default_port)
+            );
+            egress_policy()
+                .resolve(EgressTarget::ControlPlane, &cp_addr)
+                .await?;
+        }
         let cluster_members_uri = format!("{}/api/v1/cluster-members", &self.cp_service);
         let client = reqwest::ClientBuilder::new()
             .no_proxy()
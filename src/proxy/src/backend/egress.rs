@@ -0,0 +1,210 @@
+use crate::server::notifier::{notify, ProxyEventKind};
+
+use common::metrics::metric_def::PROXY_EGRESS_REJECTED;
+use common::metrics::{common_labels, counter_inc};
+use ipnet::IpNet;
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::OnceLock;
+use tracing::{debug, warn};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EgressConfig {
+    /// Networks the proxy may connect to.
+    pub allowed_cidrs: Vec<IpNet>,
+    /// Host names the proxy may connect to, whatever they resolve to. `*.example.com` matches
+    /// every subdomain of `example.com`.
+    pub allowed_hosts: Vec<String>,
+}
+
+impl EgressConfig {
+    /// Parses allowlist entries: CIDRs, IP addresses or host names.
+    pub fn from_entries(entries: &[String]) -> Result<Self, Error> {
+        let mut config = EgressConfig::default();
+        for entry in entries.iter().map(|entry| entry.trim()) {
+            if entry.is_empty() {
+                continue;
+            }
+            if let Ok(net) = entry.parse::<IpNet>() {
+                config.allowed_cidrs.push(net);
+            } else if let Ok(ip) = entry.parse::<IpAddr>() {
+                config.allowed_cidrs.push(IpNet::from(ip));
+            } else if is_host_pattern(entry) {
+                config.allowed_hosts.push(entry.to_ascii_lowercase());
+            } else {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("invalid egress allowlist entry {entry}"),
+                ));
+            }
+        }
+        Ok(config)
+    }
+
+    /// Without an allowlist the proxy may connect anywhere.
+    pub fn is_enabled(&self) -> bool {
+        !self.allowed_cidrs.is_empty() || !self.allowed_hosts.is_empty()
+    }
+}
+
+fn is_host_pattern(entry: &str) -> bool {
+    let host = entry.strip_prefix("*.").unwrap_or(entry);
+    !host.is_empty()
+        && host.split('.').all(|label| {
+            !label.is_empty() && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+/// What an outbound connection is for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EgressTarget {
+    ControlPlane,
+    Backend,
+}
+
+impl EgressTarget {
+    pub fn label(&self) -> &'static str {
+        match self {
+            EgressTarget::ControlPlane => "control_plane",
+            EgressTarget::Backend => "backend",
+        }
+    }
+}
+
+/// `EgressPolicy` validates the targets of outbound connections before the proxy dials them, so
+/// addresses handed out by a compromised control plane cannot take backend credentials elsewhere.
+pub struct EgressPolicy {
+    config: EgressConfig,
+}
+
+static EGRESS_POLICY_ONCE: OnceLock<EgressPolicy> = OnceLock::new();
+
+/// Initializes the global egress policy, must be called before the proxy connects anywhere.
+pub fn init_egress_policy(config: EgressConfig) -> &'static EgressPolicy {
+    EGRESS_POLICY_ONCE.get_or_init(|| EgressPolicy::new(config))
+}
+
+pub fn egress_policy() -> &'static EgressPolicy {
+    EGRESS_POLICY_ONCE.get_or_init(|| EgressPolicy::new(EgressConfig::default()))
+}
+
+impl EgressPolicy {
+    pub fn new(config: EgressConfig) -> Self {
+        Self { config }
+    }
+
+    fn is_host_allowed(&self, host: &str) -> bool {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        self.config
+            .allowed_hosts
+            .iter()
+            .any(|allowed| match allowed.strip_prefix("*.") {
+                Some(domain) => host
+                    .strip_suffix(domain)
+                    .is_some_and(|sub| sub.len() > 1 && sub.ends_with('.')),
+                None => host == *allowed,
+            })
+    }
+
+    /// Returns the addresses `host` resolved to that the proxy may connect to.
+    pub fn allowed_addrs(&self, host: &str, resolved: &[SocketAddr]) -> Vec<SocketAddr> {
+        if !self.config.is_enabled() || self.is_host_allowed(host) {
+            return resolved.to_vec();
+        }
+        resolved
+            .iter()
+            .filter(|addr| {
+                self.config
+                    .allowed_cidrs
+                    .iter()
+                    .any(|net| net.contains(&addr.ip()))
+            })
+            .copied()
+            .collect()
+    }
+
+    /// Resolves `addr`, a `host:port`, to the socket addresses the proxy may connect to. Targets
+    /// outside the allowlists are rejected and reported. Callers dial the returned addresses
+    /// instead of resolving `addr` again, which could give another answer.
+    pub async fn resolve(
+        &self,
+        target: EgressTarget,
+        addr: &str,
+    ) -> Result<Vec<SocketAddr>, Error> {
+        let resolved = tokio::net::lookup_host(addr).await?.collect::<Vec<_>>();
+        let allowed = self.allowed_addrs(host_of(addr), &resolved);
+        if allowed.is_empty() {
+            return Err(self.reject(target, addr, &resolved));
+        }
+        if allowed.len() < resolved.len() {
+            debug!("ProxySrv egress {addr} allowed {allowed:?} of {resolved:?}");
+        }
+        Ok(allowed)
+    }
+
+    fn reject(&self, target: EgressTarget, addr: &str, resolved: &[SocketAddr]) -> Error {
+        let message = format!(
+            "{} {addr} resolving to {resolved:?} is not allowed by the egress policy",
+            target.label()
+        );
+        warn!("ProxySrv {message}");
+        let mut labels = common_labels().clone();
+        labels.push(("target", target.label().to_string()));
+        counter_inc(PROXY_EGRESS_REJECTED, 1, Some(&labels));
+        notify(ProxyEventKind::EgressRejected, addr, message.clone());
+        Error::new(ErrorKind::PermissionDenied, message)
+    }
+}
+
+/// The host of a `host:port` or `[ipv6]:port` address.
+fn host_of(addr: &str) -> &str {
+    let host = addr.rsplit_once(':').map_or(addr, |(host, _)| host);
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::egress::{EgressConfig, EgressPolicy, EgressTarget};
+    use std::io::ErrorKind;
+    use std::net::SocketAddr;
+
+    #[tokio::test]
+    pub async fn test_egress_policy() {
+        let entries = ["10.0.0.0/8", "192.168.1.7", "*.db.internal", "cp.svc"].map(String::from);
+        let config = EgressConfig::from_entries(&entries).unwrap();
+        assert_eq!(config.allowed_cidrs.len(), 2);
+        assert_eq!(config.allowed_hosts, vec!["*.db.internal", "cp.svc"]);
+        assert!(EgressConfig::from_entries(&["http://evil".to_string()]).is_err());
+
+        let policy = EgressPolicy::new(config);
+        let public: SocketAddr = "203.0.113.9:3306".parse().unwrap();
+        let private: SocketAddr = "10.1.2.3:3306".parse().unwrap();
+        assert_eq!(
+            policy.allowed_addrs("mixed.example", &[public, private]),
+            vec![private]
+        );
+        assert_eq!(
+            policy.allowed_addrs("a.db.internal", &[public]),
+            vec![public]
+        );
+        assert_eq!(policy.allowed_addrs("CP.SVC.", &[public]), vec![public]);
+        assert!(policy.allowed_addrs("db.internal", &[public]).is_empty());
+        assert!(policy
+            .allowed_addrs("evildb.internal", &[public])
+            .is_empty());
+
+        let allowed = policy
+            .resolve(EgressTarget::Backend, "192.168.1.7:3306")
+            .await
+            .unwrap();
+        assert_eq!(allowed, vec!["192.168.1.7:3306".parse().unwrap()]);
+        let rejected = policy
+            .resolve(EgressTarget::ControlPlane, "203.0.113.9:8080")
+            .await
+            .unwrap_err();
+        assert_eq!(rejected.kind(), ErrorKind::PermissionDenied);
+
+        let open = EgressPolicy::new(EgressConfig::default());
+        assert_eq!(open.allowed_addrs("anywhere", &[public]), vec![public]);
+    }
+}
@@ -1,14 +1,25 @@
 use crate::backend::backend_discovery::{get_backend_discovery, BackendDiscovery};
 use crate::prost::common_proto::{ClusterName, DBLocation, ServiceStatus, TenantKey};
+use crate::protocol::mysql::basic::HandshakeResponse;
+use std::io::{Error, ErrorKind};
 use std::sync::Arc;
 use tokio::sync::watch::Receiver;
 use tracing::{error, info};
 
 pub mod backend_discovery;
 pub mod backend_mgr;
+pub mod capability;
+pub mod egress;
 pub mod pool;
 // pub mod prost;
+pub mod quarantine;
+pub mod replica;
 pub mod router;
+pub mod shard;
+pub mod status_events;
+pub mod stream;
+pub mod tenant_activity;
+pub mod topology_freshness;
 mod control_plane_resolver;
 
 // only for test.
@@ -80,29 +91,70 @@ pub fn encode_tenant_key(tenant_key: &TenantKey) -> String {
 }
 
 pub fn decode_tenant_key(tenant_key: &str) -> TenantKey {
-    let key_string = tenant_key.to_string();
-    let header = &key_string[0..8];
-    let key_len = hex::decode(header).unwrap();
+    try_decode_tenant_key(tenant_key).unwrap()
+}
+
+/// [`decode_tenant_key`] for keys that come from outside the proxy, an error instead of a panic
+/// if `tenant_key` is not an encoded tenant key.
+pub fn try_decode_tenant_key(tenant_key: &str) -> Result<TenantKey, Error> {
+    let invalid = || {
+        Error::new(
+            ErrorKind::InvalidInput,
+            format!("{tenant_key:?} is not an encoded tenant key"),
+        )
+    };
+    let header = tenant_key.get(0..8).ok_or_else(invalid)?;
+    let key_len = hex::decode(header).map_err(|_| invalid())?;
 
-    let mut tenant_key = TenantKey::default();
-    key_len.iter().enumerate().for_each(|(idx, len)| {
-        let start = 8 + key_len[..idx].iter().sum::<u8>() as usize;
+    let mut decoded = TenantKey::default();
+    let mut start = 8;
+    for (idx, len) in key_len.iter().enumerate() {
         let end = start + *len as usize;
-        let decode_string = &key_string[start..end];
-        let field = restore_string(decode_string);
+        let field = restore_string(tenant_key.get(start..end).ok_or_else(invalid)?);
         match idx {
-            0 => tenant_key.region = field,
-            1 => tenant_key.available_zone = field,
-            2 => tenant_key.namespace = field,
-            3 => tenant_key.cluster_name = field,
-            _ => {}
+            0 => decoded.region = field,
+            1 => decoded.available_zone = field,
+            2 => decoded.namespace = field,
+            _ => decoded.cluster_name = field,
         }
-    });
-    tenant_key
+        start = end;
+    }
+    Ok(decoded)
+}
+/// Resolve the tenant of a client connection from its (already split) handshake response. The
+/// server checks the tenant key of a client with [`try_handshake_tenant_key`] first.
+pub fn handshake_tenant_key(handshake_rsp: &HandshakeResponse) -> TenantKey {
+    try_handshake_tenant_key(handshake_rsp).expect("the tenant key of the handshake is checked")
 }
+
+/// [`handshake_tenant_key`] for the handshake as the client sent it, an error instead of a panic
+/// if the user does not carry a valid tenant key.
+pub fn try_handshake_tenant_key(handshake_rsp: &HandshakeResponse) -> Result<TenantKey, Error> {
+    let Some(tenant_encode_key) = &handshake_rsp.tenant_key else {
+        return Ok(test_tenant_key());
+    };
+    let tenant_encode_str = std::str::from_utf8(tenant_encode_key).map_err(|_| {
+        Error::new(
+            ErrorKind::InvalidInput,
+            "the tenant key of the user is not valid UTF-8",
+        )
+    })?;
+    try_decode_tenant_key(tenant_encode_str)
+}
+
+/// The tenant whose backends serve a client connection: the shard cluster for sharded tenants,
+/// otherwise the tenant of the handshake.
+pub fn backend_tenant_key(handshake_rsp: &HandshakeResponse) -> TenantKey {
+    match &handshake_rsp.shard {
+        Some(route) => route.tenant.clone(),
+        None => handshake_tenant_key(handshake_rsp),
+    }
+}
+
 #[derive(Clone, Default, Debug, Eq, PartialEq, Hash)]
 pub struct BackendInstance {
     pub location: DBLocation,
+    /// A TCP `host:port`, or a `unix://` or `mem://` address, see [`stream`].
     pub addr: String,
     pub status: ServiceStatus,
     pub cluster: ClusterName,
@@ -195,7 +247,10 @@ pub async fn start_backend_discovery(
 
 #[cfg(test)]
 mod tests {
+    use crate::backend::{encode_tenant_key, try_handshake_tenant_key};
     use crate::prost::common_proto::TenantKey;
+    use crate::protocol::mysql::basic::HandshakeResponse;
+    use mysql_common::constants::CapabilityFlags;
 
     #[test]
     pub fn test_tenant_key() {
@@ -214,4 +269,38 @@ mod tests {
         println!("decode_tenant_key: {:?}", decode_tenant_key);
         assert_eq!(tenant_key, decode_tenant_key);
     }
+    #[test]
+    pub fn test_try_handshake_tenant_key() {
+        let tenant_key = TenantKey {
+            region: "".to_string(),
+            available_zone: "".to_string(),
+            namespace: "test-proxy-system".to_string(),
+            cluster_name: "test-cluster-1".to_string(),
+        };
+        let mut handshake = HandshakeResponse {
+            client_flag: CapabilityFlags::empty(),
+            max_packet_len: 0,
+            collation: 0,
+            tenant_key: Some(encode_tenant_key(&tenant_key).into_bytes()),
+            username: Some(b"app".to_vec()),
+            auth_response: vec![],
+            auth_plugin: vec![],
+            database: None,
+            connect_attributes: None,
+            shard: None,
+            identity: None,
+            reconnect: None,
+            reconnect_token: None,
+            server_name: None,
+        };
+        assert_eq!(try_handshake_tenant_key(&handshake).unwrap(), tenant_key);
+        // Tenant keys come from the client, malformed ones are errors rather than panics.
+        for malformed in [&b"zz"[..], b"0000000g", b"02030000ab", &[0xff, 0xfe]] {
+            handshake.tenant_key = Some(malformed.to_vec());
+            assert!(
+                try_handshake_tenant_key(&handshake).is_err(),
+                "{malformed:?}"
+            );
+        }
+    }
 }
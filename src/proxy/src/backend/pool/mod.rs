@@ -1,34 +1,180 @@
-use crate::backend::DbUserConnLifeCycle;
+use crate::backend::pool::stmt_cache::{PreparedStmtCache, SharedStmtCache};
+use crate::backend::stream::{
+    connect_backend, write_proxy_header, BackendReadHalf, BackendWriteHalf,
+};
+use crate::backend::{DbConnPhase, DbUserConnLifeCycle};
 use crate::protocol::mysql::packet::packet_reader::PacketReader;
 use crate::protocol::mysql::packet::packet_writer::PacketWriter;
+use crate::protocol::mysql::packet::writers;
+use crate::server::forwarder::session_state::{SessionStateTracker, SharedSessionState};
+use serde::Serialize;
 use std::ops::DerefMut;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::io::AsyncWriteExt;
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::sync::Mutex;
 
 pub mod pooled_conn_mgr;
+pub mod stmt_cache;
 
 pub const BACKEND_CLIENT_DEFAULT_IDLE: Duration = Duration::from_secs(60 * 10);
+
+/// Connections opened for the Ready backends before the proxy serves clients. The connections do
+/// not authenticate until a session checks them out, backends close those left unauthenticated
+/// beyond their connect timeout.
+#[derive(Debug, Clone)]
+pub struct PoolWarmup {
+    /// Connections opened per backend, 0 disables the warm-up.
+    pub conns_per_backend: u32,
+    /// Backends warmed up at the same time.
+    pub parallelism: usize,
+    /// Time a backend may take to open its connections.
+    pub backend_timeout: Duration,
+}
+
+impl Default for PoolWarmup {
+    fn default() -> Self {
+        Self {
+            conns_per_backend: 0,
+            parallelism: 8,
+            backend_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Checks the idle connections of every pool in the background, so a session does not check out
+/// a connection the backend or a NAT dropped while it sat in the pool.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PoolHealthCheck {
+    /// How often the idle connections are checked, those idle this long are pinged. 0 disables
+    /// the checks.
+    pub interval: Duration,
+    /// Idle connections beyond this are closed instead of pinged, 0 keeps them.
+    pub max_idle: Duration,
+}
+
+impl PoolHealthCheck {
+    pub fn is_enabled(&self) -> bool {
+        !self.interval.is_zero()
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct BackendPoolConfig {
+    /// Connections opened in the background once the pool of a Ready backend is created, so the
+    /// first sessions skip the connect. 0 creates the pools empty.
     pub initial_size: u32,
     pub max_size: u32,
     pub time_to_idle: Duration,
+    /// Capacity of the per-connection prepared statement cache, 0 disables it.
+    pub stmt_cache_size: usize,
+    /// Backend addresses that negotiate the compressed protocol, `*` matches every backend.
+    pub compress_backends: Vec<String>,
+    /// Backend addresses whose connections start with a PROXY protocol header, `*` matches
+    /// every backend.
+    pub proxy_protocol_backends: Vec<String>,
+    pub warmup: PoolWarmup,
+    pub health_check: PoolHealthCheck,
+}
+
+impl BackendPoolConfig {
+    pub fn is_compression_enabled(&self, backend_addr: &str) -> bool {
+        self.compress_backends
+            .iter()
+            .any(|addr| addr == "*" || addr == backend_addr)
+    }
+
+    pub fn is_proxy_protocol_enabled(&self, backend_addr: &str) -> bool {
+        self.proxy_protocol_backends
+            .iter()
+            .any(|addr| addr == "*" || addr == backend_addr)
+    }
 }
 
 impl Default for BackendPoolConfig {
     fn default() -> Self {
         Self {
-            initial_size: 5,
+            initial_size: 0,
             max_size: 50,
             time_to_idle: BACKEND_CLIENT_DEFAULT_IDLE,
+            stmt_cache_size: 0,
+            compress_backends: vec![],
+            proxy_protocol_backends: vec![],
+            warmup: PoolWarmup::default(),
+            health_check: PoolHealthCheck::default(),
         }
     }
 }
 
-pub type BackendConn = (PacketReader<OwnedReadHalf>, PacketWriter<OwnedWriteHalf>);
+/// Lifecycle events of the pooled connections of a backend.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PoolEvent {
+    Created,
+    CreateFailed,
+    /// A connection passed the recycle check and went back into the pool.
+    Recycled,
+    /// A connection was taken out of the pool for good, it is closed in the background.
+    Detached,
+}
+
+impl PoolEvent {
+    pub fn label(&self) -> &'static str {
+        match self {
+            PoolEvent::Created => "created",
+            PoolEvent::CreateFailed => "create_failed",
+            PoolEvent::Recycled => "recycled",
+            PoolEvent::Detached => "detached",
+        }
+    }
+}
+
+/// The events of a pool counted since it was created.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct PoolEventCounts {
+    pub created: u64,
+    pub create_failed: u64,
+    pub recycled: u64,
+    pub detached: u64,
+}
+
+/// Counts the events of a pool, shared by the clones of its manager.
+#[derive(Debug, Default)]
+pub struct PoolEventCounters {
+    created: AtomicU64,
+    create_failed: AtomicU64,
+    recycled: AtomicU64,
+    detached: AtomicU64,
+}
+
+impl PoolEventCounters {
+    pub fn record(&self, event: PoolEvent) {
+        let counter = match event {
+            PoolEvent::Created => &self.created,
+            PoolEvent::CreateFailed => &self.create_failed,
+            PoolEvent::Recycled => &self.recycled,
+            PoolEvent::Detached => &self.detached,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn counts(&self) -> PoolEventCounts {
+        PoolEventCounts {
+            created: self.created.load(Ordering::Relaxed),
+            create_failed: self.create_failed.load(Ordering::Relaxed),
+            recycled: self.recycled.load(Ordering::Relaxed),
+            detached: self.detached.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Called with the backend address on every pool event, after the event is counted.
+pub type PoolEventHook = Arc<dyn Fn(&str, PoolEvent) + Send + Sync>;
+
+pub type BackendConn = (
+    PacketReader<BackendReadHalf>,
+    PacketWriter<BackendWriteHalf>,
+);
 
 pub type SafeBackendConn = Arc<Mutex<BackendConn>>;
 
@@ -37,17 +183,53 @@ pub struct PooledConn {
     pub id: String,
     pub inner_conn: SafeBackendConn,
     pub conn_life_cycle: Arc<Mutex<DbUserConnLifeCycle>>,
+    pub stmt_cache: SharedStmtCache,
+    /// Whether the backend leg of this connection negotiates the compressed protocol.
+    pub compression: bool,
+    /// Set once the backend returned an error the connection does not survive, it is then
+    /// closed instead of recycled.
+    pub invalidated: Arc<AtomicBool>,
+    /// What the connection holds for the session using it, see [`SessionStateTracker`].
+    pub session_state: SharedSessionState,
 }
 
 impl PooledConn {
+    pub fn new(
+        id: String,
+        inner_conn: SafeBackendConn,
+        stmt_cache_size: usize,
+        compression: bool,
+    ) -> Self {
+        Self {
+            id,
+            inner_conn,
+            conn_life_cycle: Arc::new(Mutex::new(DbUserConnLifeCycle::default())),
+            stmt_cache: Arc::new(Mutex::new(PreparedStmtCache::new(stmt_cache_size))),
+            compression,
+            invalidated: Arc::new(AtomicBool::new(false)),
+            session_state: Arc::new(SessionStateTracker::default()),
+        }
+    }
+
+    pub fn is_invalidated(&self) -> bool {
+        self.invalidated.load(Ordering::Acquire)
+    }
+
     pub async fn get_conn_life_cycle(&self) -> DbUserConnLifeCycle {
         let conn_life_cycle_guard = self.conn_life_cycle.lock().await;
         conn_life_cycle_guard.clone()
     }
 
+    /// Closes the connection, an authenticated one quits first so the backend does not count it
+    /// as aborted.
     pub async fn close(&self) -> Result<(), std::io::Error> {
+        let conn_phase = self.get_conn_life_cycle().await.conn_phase();
         let mut inner_guard = self.inner_conn.lock().await;
         let (_, writer) = inner_guard.deref_mut();
+        if conn_phase == Some(DbConnPhase::Command) {
+            writer.reset_seq();
+            let _ = writers::write_quit(writer).await;
+        }
         writer.shutdown().await
     }
 
@@ -63,11 +245,12 @@ pub struct BackendIO {
 }
 
 impl BackendIO {
-    pub async fn new(backend_addr: String) -> Result<Self, std::io::Error> {
-        let std_tcp_stream = std::net::TcpStream::connect(backend_addr.clone())?;
-        std_tcp_stream.set_nonblocking(true)?;
-        let tcp_stream_rs = tokio::net::TcpStream::from_std(std_tcp_stream)?;
-        let (reader, writer) = tcp_stream_rs.into_split();
+    /// Connects to `backend_addr`, sending a PROXY protocol header first with `proxy_protocol`.
+    pub async fn new(backend_addr: String, proxy_protocol: bool) -> Result<Self, std::io::Error> {
+        let (reader, mut writer) = connect_backend(&backend_addr).await?;
+        if proxy_protocol {
+            write_proxy_header(&mut writer).await?;
+        }
         Ok(Self {
             backend_client: Arc::new(Mutex::new((
                 PacketReader::new(reader),
@@ -1,26 +1,70 @@
-use crate::backend::pool::{BackendIO, PooledConn};
-use crate::backend::{BackendInstance, DbConnPhase, DbUserConnLifeCycle};
+use crate::backend::pool::{
+    BackendIO, BackendPoolConfig, PoolEvent, PoolEventCounters, PoolEventCounts, PoolEventHook,
+    PoolHealthCheck, PooledConn,
+};
+use crate::backend::quarantine::{quarantine_registry, BackendFailure};
+use crate::backend::{BackendInstance, DbConnPhase};
+use crate::server::fault_injection::{apply_connect_fault, fault_injector};
+use crate::server::keepalive::ping_conn;
 
-use deadpool::managed::{Metrics, RecycleError, RecycleResult};
+use common::metrics::metric_def::{
+    PROXY_BACKEND_CONN_INVALIDATED, PROXY_POOL_EVENTS, PROXY_POOL_HEALTH_CHECKS,
+};
+use common::metrics::{common_labels, counter_inc};
+use deadpool::managed::{Metrics, Pool, RecycleError, RecycleResult};
 use futures::FutureExt;
 use nanoid::nanoid;
 use std::future::Future;
+use std::ops::DerefMut;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
+use std::time::Instant;
 use tokio::sync::Mutex;
-use tracing::info;
+use tokio::time::MissedTickBehavior;
+use tracing::{debug, info, warn};
 
 #[derive(Clone)]
 pub struct PooledConnMgr {
     backend_addr: Arc<Mutex<BackendInstance>>,
+    stmt_cache_size: usize,
+    compression: bool,
+    proxy_protocol: bool,
+    event_hooks: Vec<PoolEventHook>,
+    event_counters: Arc<PoolEventCounters>,
 }
 
 impl PooledConnMgr {
-    pub fn new(backend_addr: BackendInstance) -> Self {
+    pub fn new(backend_addr: BackendInstance, pool_config: &BackendPoolConfig) -> Self {
         Self {
+            compression: pool_config.is_compression_enabled(&backend_addr.addr),
+            proxy_protocol: pool_config.is_proxy_protocol_enabled(&backend_addr.addr),
             backend_addr: Arc::new(Mutex::new(backend_addr)),
+            stmt_cache_size: pool_config.stmt_cache_size,
+            event_hooks: vec![],
+            event_counters: Arc::default(),
         }
     }
 
+    pub fn with_event_hook(mut self, hook: PoolEventHook) -> Self {
+        self.event_hooks.push(hook);
+        self
+    }
+
+    fn emit(&self, addr: &str, event: PoolEvent) {
+        let mut labels = common_labels().clone();
+        labels.push(("backend", addr.to_string()));
+        labels.push(("event", event.label().to_string()));
+        counter_inc(PROXY_POOL_EVENTS, 1, Some(&labels));
+        self.event_counters.record(event);
+        for hook in &self.event_hooks {
+            hook(addr, event);
+        }
+    }
+
+    pub fn event_counts(&self) -> PoolEventCounts {
+        self.event_counters.counts()
+    }
+
     pub async fn get_addr(&self) -> String {
         self.backend_addr.lock().await.addr.clone()
     }
@@ -39,13 +83,32 @@ impl deadpool::managed::Manager for PooledConnMgr {
     fn create(&self) -> impl Future<Output = Result<Self::Type, Self::Error>> + Send {
         async move {
             let backed_addr = self.get_addr().await;
-            let backend_io = BackendIO::new(backed_addr.to_owned()).await.unwrap();
+            let started = Instant::now();
+            // Injected connect faults count as failures of the backend too.
+            let backend_io = async {
+                if let Some(fault) = fault_injector().backend_fault(&backed_addr) {
+                    apply_connect_fault(fault).await?;
+                }
+                BackendIO::new(backed_addr.to_owned(), self.proxy_protocol).await
+            }
+            .await
+            .inspect_err(|e| {
+                quarantine_registry().record_failure(
+                    &backed_addr,
+                    BackendFailure::Connect,
+                    e.to_string(),
+                );
+                self.emit(&backed_addr, PoolEvent::CreateFailed);
+            })?;
+            quarantine_registry().record_connect(&backed_addr, started.elapsed());
+            self.emit(&backed_addr, PoolEvent::Created);
             let backend_conn = backend_io.get_backend_client();
-            Ok(PooledConn {
-                id: nanoid!(),
-                inner_conn: backend_conn,
-                conn_life_cycle: Arc::new(Mutex::new(DbUserConnLifeCycle::default())),
-            })
+            Ok(PooledConn::new(
+                nanoid!(),
+                backend_conn,
+                self.stmt_cache_size,
+                self.compression,
+            ))
         }
         .boxed()
     }
@@ -56,9 +119,30 @@ impl deadpool::managed::Manager for PooledConnMgr {
         metrics: &Metrics,
     ) -> impl Future<Output = RecycleResult<Self::Error>> + Send {
         info!("ProxySrv recycle metrics={:?}", metrics);
+        let conn_mgr = self.clone();
         async move {
+            if pooled_conn.is_invalidated() {
+                let addr = conn_mgr.get_addr().await;
+                warn!(
+                    "ProxySrv conn_id={:?} of {addr} invalidated by a backend error",
+                    &pooled_conn.id
+                );
+                let mut labels = common_labels().clone();
+                labels.push(("backend", addr));
+                counter_inc(PROXY_BACKEND_CONN_INVALIDATED, 1, Some(&labels));
+                return Err(RecycleError::message("invalidated by a backend error"));
+            }
+            if let Some(reason) = pooled_conn.session_state.state().sticky_reason() {
+                warn!(
+                    "ProxySrv conn_id={:?} discarded, returned without a reset holding {reason}",
+                    &pooled_conn.id
+                );
+                return Err(RecycleError::message(
+                    "sticky session state without a reset",
+                ));
+            }
             let conn_life_cycle = &pooled_conn.conn_life_cycle.lock().await;
-            if conn_life_cycle.is_none() {
+            let recycle_rs = if conn_life_cycle.is_none() {
                 info!(
                     "ProxySrv conn_id={:?} back into pool.",
                     &pooled_conn.id
@@ -77,19 +161,257 @@ impl deadpool::managed::Manager for PooledConnMgr {
                     ))),
                     _ => Ok(()),
                 }
+            };
+            if recycle_rs.is_ok() {
+                conn_mgr.emit(&conn_mgr.get_addr().await, PoolEvent::Recycled);
             }
+            recycle_rs
         }
         .boxed()
     }
 
+    /// Detaching runs on a runtime worker, e.g. when the pool is retained, so the connection is
+    /// closed by a spawned task. Blocking on the close would stall the worker, or deadlock it
+    /// while a session still holds the connection.
     fn detach(&self, pooled_conn: &mut PooledConn) {
-        futures::executor::block_on(async {
-            let conn_id = pooled_conn.id.clone();
-            let close_rs = pooled_conn.close().await;
+        let conn_mgr = self.clone();
+        let detached = pooled_conn.clone();
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            // Outside a runtime the socket closes once the last handle of it is dropped.
+            warn!(
+                "ProxySrv detached backend-end id={:?} without a runtime",
+                detached.id
+            );
+            return;
+        };
+        runtime.spawn(async move {
+            let close_rs = detached.close().await;
             info!(
                 "ProxySrv Detached backend-end id={:?} close_rs is err {:?}",
-                conn_id, close_rs
+                detached.id, close_rs
             );
+            conn_mgr.emit(&conn_mgr.get_addr().await, PoolEvent::Detached);
+        });
+    }
+}
+
+/// Checks the idle connections of `pool` every health check interval, until the pool is closed or
+/// dropped.
+pub fn spawn_health_check(pool: &Pool<PooledConnMgr>, health_check: PoolHealthCheck) {
+    if !health_check.is_enabled() {
+        return;
+    }
+    let pool = pool.weak();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(health_check.interval);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            let Some(pool) = pool.upgrade().filter(|pool| !pool.is_closed()) else {
+                break;
+            };
+            check_pool_health(&pool, health_check).await;
+        }
+    });
+}
+
+/// Closes the idle connections of `pool` beyond the max idle, and pings the authenticated ones idle
+/// for an interval; those the backend does not answer are closed too. Unauthenticated connections
+/// still have the greeting of the backend to read, they are left alone. Returns the number of
+/// connections closed.
+pub async fn check_pool_health(pool: &Pool<PooledConnMgr>, health_check: PoolHealthCheck) -> usize {
+    let addr = pool.manager().get_addr().await;
+    let mut idle_conns = vec![];
+    let expired = pool
+        .retain(|pooled_conn, metrics| {
+            let idle = metrics.last_used();
+            if !health_check.max_idle.is_zero() && idle > health_check.max_idle {
+                return false;
+            }
+            if idle >= health_check.interval {
+                idle_conns.push(pooled_conn.clone());
+            }
+            true
         })
+        .removed
+        .len();
+    let (mut pinged, mut dead) = (0, vec![]);
+    for pooled_conn in idle_conns {
+        if pooled_conn.get_conn_life_cycle().await.conn_phase() != Some(DbConnPhase::Command) {
+            continue;
+        }
+        // A session checked it out in the meantime.
+        let Ok(mut inner_guard) = pooled_conn.inner_conn.try_lock() else {
+            continue;
+        };
+        let (reader, writer) = inner_guard.deref_mut();
+        pinged += 1;
+        if let Err(e) = ping_conn(writer, reader).await {
+            warn!(
+                "ProxySrv health check of conn_id={:?} of {addr} failed {e:?}",
+                &pooled_conn.id
+            );
+            // Closed on its recycle if a session checks it out before it is removed.
+            pooled_conn.invalidated.store(true, Ordering::Release);
+            dead.push(pooled_conn.id.clone());
+        }
+    }
+    if !dead.is_empty() {
+        pool.retain(|pooled_conn, _| !dead.contains(&pooled_conn.id));
+    }
+    for (result, conns) in [
+        ("ok", pinged - dead.len()),
+        ("failed", dead.len()),
+        ("expired", expired),
+    ] {
+        if conns > 0 {
+            let mut labels = common_labels().clone();
+            labels.push(("backend", addr.clone()));
+            labels.push(("result", result.to_string()));
+            counter_inc(PROXY_POOL_HEALTH_CHECKS, conns as u64, Some(&labels));
+        }
+    }
+    debug!(
+        "ProxySrv health check of {addr} pinged {pinged}, closed {} dead and {expired} expired",
+        dead.len()
+    );
+    expired + dead.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::pool::pooled_conn_mgr::{check_pool_health, PooledConnMgr};
+    use crate::backend::pool::{
+        BackendIO, BackendPoolConfig, PoolEvent, PoolHealthCheck, PooledConn,
+    };
+    use crate::backend::{BackendInstance, DbConnPhase, DbUserConnLifeCycle};
+    use deadpool::managed::{Manager, Metrics, Pool};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    #[tokio::test(flavor = "current_thread")]
+    pub async fn test_detach_does_not_block() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let backend_io = BackendIO::new(addr.clone(), false).await.unwrap();
+        let (mut peer, _) = listener.accept().await.unwrap();
+        let detached = Arc::new(AtomicUsize::new(0));
+        let detached_count = Arc::clone(&detached);
+        let backend = BackendInstance {
+            addr,
+            ..Default::default()
+        };
+        let conn_mgr = PooledConnMgr::new(backend, &BackendPoolConfig::default()).with_event_hook(
+            Arc::new(move |_, event| {
+                if event == PoolEvent::Detached {
+                    detached_count.fetch_add(1, Ordering::SeqCst);
+                }
+            }),
+        );
+        let mut pooled_conn = PooledConn::new(
+            "detached".to_string(),
+            backend_io.get_backend_client(),
+            0,
+            false,
+        );
+        // A session still holds the connection on the only worker of the runtime.
+        let session_guard = Arc::clone(&pooled_conn.inner_conn).lock_owned().await;
+        conn_mgr.detach(&mut pooled_conn);
+        drop(session_guard);
+
+        let mut buf = [0u8; 1];
+        let read = tokio::time::timeout(Duration::from_secs(5), peer.read(&mut buf))
+            .await
+            .expect("detached connection was not closed")
+            .unwrap();
+        assert_eq!(read, 0);
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while detached.load(Ordering::SeqCst) == 0 {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    pub async fn test_recycle_invalidated() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let backend_io = BackendIO::new(addr.clone(), false).await.unwrap();
+        let backend = BackendInstance {
+            addr,
+            ..Default::default()
+        };
+        let conn_mgr = PooledConnMgr::new(backend, &BackendPoolConfig::default());
+        let mut pooled_conn = PooledConn::new(
+            "invalidated".to_string(),
+            backend_io.get_backend_client(),
+            0,
+            false,
+        );
+        let metrics = Metrics::default();
+        assert!(conn_mgr.recycle(&mut pooled_conn, &metrics).await.is_ok());
+        pooled_conn.invalidated.store(true, Ordering::Release);
+        assert!(conn_mgr.recycle(&mut pooled_conn, &metrics).await.is_err());
+        // Counted by every clone of the manager, the pool holds one of them.
+        assert_eq!(conn_mgr.clone().event_counts().recycled, 1);
+    }
+
+    #[tokio::test]
+    pub async fn test_check_pool_health() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend = BackendInstance {
+            addr: listener.local_addr().unwrap().to_string(),
+            ..Default::default()
+        };
+        let pool: Pool<PooledConnMgr> =
+            Pool::builder(PooledConnMgr::new(backend, &BackendPoolConfig::default()))
+                .max_size(2)
+                .build()
+                .unwrap();
+        let pooled_conn = pool.get().await.unwrap();
+        pooled_conn
+            .update_conn_life_cycle(DbUserConnLifeCycle::new_conn_life_cycle(
+                "app".to_string(),
+                DbConnPhase::Command,
+            ))
+            .await;
+        drop(pooled_conn);
+        let (mut peer, _) = listener.accept().await.unwrap();
+        let health_check = PoolHealthCheck::default();
+
+        // The backend answers the ping.
+        let answered = tokio::spawn(async move {
+            let mut ping = [0u8; 5];
+            peer.read_exact(&mut ping).await.unwrap();
+            assert_eq!(ping, [1, 0, 0, 0, 14]);
+            peer.write_all(&[7, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0])
+                .await
+                .unwrap();
+            peer
+        });
+        assert_eq!(check_pool_health(&pool, health_check).await, 0);
+        assert_eq!(pool.status().size, 1);
+
+        // The backend dropped the connection.
+        drop(answered.await.unwrap());
+        assert_eq!(check_pool_health(&pool, health_check).await, 1);
+        assert_eq!(pool.status().size, 0);
+
+        // Unauthenticated connections are not pinged, but expire.
+        drop(pool.get().await.unwrap());
+        assert_eq!(check_pool_health(&pool, health_check).await, 0);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let health_check = PoolHealthCheck {
+            max_idle: Duration::from_millis(10),
+            ..health_check
+        };
+        assert_eq!(check_pool_health(&pool, health_check).await, 1);
+        assert_eq!(pool.status().size, 0);
     }
 }
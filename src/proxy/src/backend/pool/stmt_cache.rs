@@ -0,0 +1,443 @@
+use crate::protocol::mysql::constants::CommandCode;
+use crate::protocol::mysql::packet::Packet;
+use hashbrown::HashMap;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+pub type SharedStmtCache = Arc<Mutex<PreparedStmtCache>>;
+
+/// Collapses whitespace outside quoted literals and identifiers, so that statements which only
+/// differ in formatting share a cache entry.
+pub fn normalize_stmt(sql: &[u8]) -> String {
+    let sql = String::from_utf8_lossy(sql);
+    let mut normalized = String::with_capacity(sql.len());
+    let mut quote: Option<char> = None;
+    let mut escaped = false;
+    let mut pending_space = false;
+    for c in sql.trim().chars() {
+        if let Some(q) = quote {
+            normalized.push(c);
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == q {
+                quote = None;
+            }
+            continue;
+        }
+        if c.is_whitespace() {
+            pending_space = true;
+            continue;
+        }
+        if pending_space {
+            normalized.push(' ');
+            pending_space = false;
+        }
+        if c == '\'' || c == '"' || c == '`' {
+            quote = Some(c);
+        }
+        normalized.push(c);
+    }
+    normalized
+}
+
+#[derive(Debug, Clone)]
+pub struct CachedStmt {
+    pub backend_id: u32,
+    /// The original statement text, used to re-prepare after schema changes.
+    pub sql: Vec<u8>,
+    /// COM_STMT_PREPARE_OK followed by the parameter and column definitions.
+    pub response: Vec<Packet>,
+    /// The backend deallocated the statement, it is prepared again on first use.
+    pub stale: bool,
+    last_used: u64,
+}
+
+impl CachedStmt {
+    pub fn new(backend_id: u32, sql: Vec<u8>, response: Vec<Packet>) -> Self {
+        Self {
+            backend_id,
+            sql,
+            response,
+            stale: false,
+            last_used: 0,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct ClientStmt {
+    key: String,
+    /// A backend statement of its own, see [`PreparedStmtCache::is_held_by_other`].
+    private: Option<CachedStmt>,
+}
+
+/// What the backend must do once a client statement is closed.
+#[derive(Debug, PartialEq, Eq)]
+pub enum StmtRelease {
+    /// COM_STMT_CLOSE the private backend statement of the client statement.
+    Close(u32),
+    /// COM_STMT_RESET the shared backend statement, dropping the long data or the cursor the
+    /// client statement left on it.
+    Reset(u32),
+}
+
+/// `PreparedStmtCache` deduplicates COM_STMT_PREPARE on one backend connection.
+///
+/// Statements are keyed by their normalized text and handed out to the client under proxy
+/// allocated ids, so the same backend statement can back several client statements. Long data
+/// and cursors live on the backend statement though, so a client statement that would disturb
+/// those of another one gets a private backend statement.
+///
+/// Authentication, COM_RESET_CONNECTION and COM_CHANGE_USER deallocate every statement on the
+/// backend. The cache survives an [`authentication`](PreparedStmtCache::authenticated) of the
+/// same user on the same schema, its statements are then prepared again on first use.
+#[derive(Debug)]
+pub struct PreparedStmtCache {
+    capacity: usize,
+    stmts: HashMap<String, CachedStmt>,
+    client_stmts: HashMap<u32, ClientStmt>,
+    /// The client statement whose long data or cursor is pending on a shared backend statement.
+    holders: HashMap<u32, u32>,
+    /// The user and the schema the cached statements were prepared for.
+    owner: Option<(String, Option<Vec<u8>>)>,
+    next_client_id: u32,
+    tick: u64,
+}
+
+impl PreparedStmtCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            stmts: HashMap::new(),
+            client_stmts: HashMap::new(),
+            holders: HashMap::new(),
+            owner: None,
+            next_client_id: 1,
+            tick: 0,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    pub fn len(&self) -> usize {
+        self.stmts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stmts.is_empty()
+    }
+
+    /// Approximate bytes held by the cached statements and their prepare responses.
+    pub fn memory_bytes(&self) -> usize {
+        let stmts = self
+            .stmts
+            .iter()
+            .map(|(key, stmt)| {
+                key.len()
+                    + stmt.sql.capacity()
+                    + stmt.response.iter().map(|pkt| pkt.len()).sum::<usize>()
+            })
+            .sum::<usize>();
+        let client_stmts = self
+            .client_stmts
+            .values()
+            .map(|client_stmt| {
+                client_stmt.key.len()
+                    + std::mem::size_of::<u32>()
+                    + client_stmt
+                        .private
+                        .as_ref()
+                        .map_or(0, |stmt| stmt.sql.capacity())
+            })
+            .sum::<usize>();
+        stmts + client_stmts
+    }
+
+    pub fn clear(&mut self) {
+        self.stmts.clear();
+        self.owner = None;
+        self.deallocated();
+    }
+
+    /// The backend deallocated its statements: the client statements are gone, the cached ones
+    /// are kept but prepared again on first use.
+    pub fn deallocated(&mut self) {
+        self.stmts.values_mut().for_each(|stmt| stmt.stale = true);
+        self.client_stmts.clear();
+        self.holders.clear();
+        self.next_client_id = 1;
+    }
+
+    /// `user` authenticated on the backend connection with `database` as its schema. The cached
+    /// statements are kept if they were prepared for the same user and schema.
+    pub fn authenticated(&mut self, user: &str, database: Option<&[u8]>) {
+        let owner = (user.to_string(), database.map(<[u8]>::to_vec));
+        if self.owner.as_ref() == Some(&owner) {
+            self.deallocated();
+        } else {
+            self.clear();
+            self.owner = Some(owner);
+        }
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.stmts.contains_key(key)
+    }
+
+    pub fn lookup(&mut self, key: &str) -> Option<CachedStmt> {
+        self.tick += 1;
+        let tick = self.tick;
+        self.stmts.get_mut(key).map(|stmt| {
+            stmt.last_used = tick;
+            stmt.clone()
+        })
+    }
+
+    /// Inserts a freshly prepared statement and returns the backend ids evicted to make room.
+    /// Statements still referenced by a client statement are never evicted.
+    pub fn insert(&mut self, key: String, mut stmt: CachedStmt) -> Vec<u32> {
+        self.tick += 1;
+        stmt.last_used = self.tick;
+        self.stmts.insert(key, stmt);
+        let mut evicted = vec![];
+        while self.stmts.len() > self.capacity {
+            let victim = self
+                .stmts
+                .iter()
+                .filter(|(k, _)| !self.client_stmts.values().any(|used| &used.key == *k))
+                .min_by_key(|(_, v)| v.last_used)
+                .map(|(k, _)| k.clone());
+            match victim {
+                Some(victim_key) => {
+                    // Stale statements are already deallocated on the backend.
+                    if let Some(victim_stmt) = self.stmts.remove(&victim_key) {
+                        if !victim_stmt.stale {
+                            evicted.push(victim_stmt.backend_id);
+                        }
+                    }
+                }
+                None => break,
+            }
+        }
+        evicted
+    }
+
+    /// Allocates a client statement id for the cached statement `key`.
+    pub fn register_client_stmt(&mut self, key: String) -> u32 {
+        let client_id = self.next_client_id;
+        self.next_client_id = self.next_client_id.wrapping_add(1).max(1);
+        self.client_stmts
+            .insert(client_id, ClientStmt { key, private: None });
+        client_id
+    }
+
+    fn stmt(&self, client_id: u32) -> Option<&CachedStmt> {
+        let client_stmt = self.client_stmts.get(&client_id)?;
+        client_stmt
+            .private
+            .as_ref()
+            .or_else(|| self.stmts.get(&client_stmt.key))
+    }
+
+    pub fn backend_id(&self, client_id: u32) -> Option<u32> {
+        self.stmt(client_id).map(|stmt| stmt.backend_id)
+    }
+
+    /// The text of the statement behind `client_id`.
+    pub fn sql(&self, client_id: u32) -> Option<&[u8]> {
+        self.stmt(client_id).map(|stmt| stmt.sql.as_slice())
+    }
+
+    /// The statement behind `client_id` was deallocated on the backend.
+    pub fn is_stale(&self, client_id: u32) -> bool {
+        self.stmt(client_id).is_some_and(|stmt| stmt.stale)
+    }
+
+    /// Another client statement left its long data or its cursor on the backend statement
+    /// behind `client_id`, using it would consume or discard them.
+    pub fn is_held_by_other(&self, client_id: u32) -> bool {
+        self.backend_id(client_id)
+            .and_then(|backend_id| self.holders.get(&backend_id))
+            .is_some_and(|holder| *holder != client_id)
+    }
+
+    /// The normalized text of the statement behind `client_id`.
+    pub fn stmt_key(&self, client_id: u32) -> Option<&str> {
+        self.client_stmts
+            .get(&client_id)
+            .map(|client_stmt| client_stmt.key.as_str())
+    }
+
+    /// Records the command `client_id` sends to its backend statement: long data and cursors
+    /// stay pending on the backend statement until the next execute, reset or close.
+    pub fn on_command(&mut self, client_id: u32, com_code: CommandCode, opens_cursor: bool) {
+        let Some(backend_id) = self.backend_id(client_id) else {
+            return;
+        };
+        match com_code {
+            CommandCode::ComStmtSendLongData => {
+                self.holders.insert(backend_id, client_id);
+            }
+            CommandCode::ComStmtExecute if opens_cursor => {
+                self.holders.insert(backend_id, client_id);
+            }
+            CommandCode::ComStmtExecute | CommandCode::ComStmtReset => {
+                self.holders.remove(&backend_id);
+            }
+            _ => {}
+        }
+    }
+
+    /// Binds `client_id` to a backend statement of its own.
+    pub fn set_private(&mut self, client_id: u32, stmt: CachedStmt) {
+        if let Some(client_stmt) = self.client_stmts.get_mut(&client_id) {
+            client_stmt.private = Some(stmt);
+        }
+    }
+
+    /// Unbinds the private backend statement of `client_id` and returns it.
+    pub fn take_private(&mut self, client_id: u32) -> Option<CachedStmt> {
+        let private = self.client_stmts.get_mut(&client_id)?.private.take()?;
+        self.holders.remove(&private.backend_id);
+        Some(private)
+    }
+
+    /// The client closed its statement; the shared backend statement stays cached for reuse.
+    pub fn close_client_stmt(&mut self, client_id: u32) -> Option<StmtRelease> {
+        if let Some(private) = self.take_private(client_id) {
+            self.client_stmts.remove(&client_id);
+            return Some(StmtRelease::Close(private.backend_id));
+        }
+        let backend_id = self.backend_id(client_id);
+        self.client_stmts.remove(&client_id);
+        let backend_id = backend_id?;
+        match self.holders.get(&backend_id) {
+            Some(holder) if *holder == client_id => {
+                self.holders.remove(&backend_id);
+                Some(StmtRelease::Reset(backend_id))
+            }
+            _ => None,
+        }
+    }
+
+    /// Drops the shared backend statement behind `client_id` (e.g. after a schema change) while
+    /// keeping the client id, and returns the statement so it can be prepared again.
+    pub fn invalidate(&mut self, client_id: u32) -> Option<(String, CachedStmt)> {
+        let key = self.client_stmts.get(&client_id)?.key.clone();
+        let stmt = self.stmts.remove(&key)?;
+        self.holders.remove(&stmt.backend_id);
+        Some((key, stmt))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::pool::stmt_cache::{
+        normalize_stmt, CachedStmt, PreparedStmtCache, StmtRelease,
+    };
+    use crate::protocol::mysql::constants::CommandCode;
+
+    #[test]
+    pub fn test_normalize_stmt() {
+        assert_eq!(
+            normalize_stmt(b"  SELECT *\n  FROM t   WHERE a = ? "),
+            "SELECT * FROM t WHERE a = ?"
+        );
+        assert_eq!(
+            normalize_stmt(b"SELECT 'a   b',  `c  d`"),
+            "SELECT 'a   b', `c  d`"
+        );
+        assert_ne!(
+            normalize_stmt(b"SELECT 'a  b'"),
+            normalize_stmt(b"SELECT 'a b'")
+        );
+    }
+
+    #[test]
+    pub fn test_stmt_cache_lru() {
+        let mut cache = PreparedStmtCache::new(2);
+        assert!(cache
+            .insert("q1".to_string(), CachedStmt::new(1, vec![], vec![]))
+            .is_empty());
+        assert!(cache
+            .insert("q2".to_string(), CachedStmt::new(2, vec![], vec![]))
+            .is_empty());
+        let client_id = cache.register_client_stmt("q1".to_string());
+        assert_eq!(cache.backend_id(client_id), Some(1));
+
+        // q2 is the least recently used statement without client references.
+        let evicted = cache.insert("q3".to_string(), CachedStmt::new(3, vec![], vec![]));
+        assert_eq!(evicted, vec![2]);
+        assert!(cache.contains("q1"));
+
+        cache.close_client_stmt(client_id);
+        cache.lookup("q3");
+        let evicted = cache.insert("q4".to_string(), CachedStmt::new(4, vec![], vec![]));
+        assert_eq!(evicted, vec![1]);
+        assert_eq!(cache.len(), 2);
+    }
+
+    #[test]
+    pub fn test_stmt_cache_survives_authentication() {
+        let mut cache = PreparedStmtCache::new(2);
+        cache.authenticated("app", Some(b"shop"));
+        cache.insert("q1".to_string(), CachedStmt::new(1, vec![], vec![]));
+        let client_id = cache.register_client_stmt("q1".to_string());
+        assert!(!cache.is_stale(client_id));
+
+        // The same user on the same schema keeps the statements, deallocated on the backend.
+        cache.authenticated("app", Some(b"shop"));
+        assert!(cache.contains("q1"));
+        assert_eq!(cache.backend_id(client_id), None);
+        let client_id = cache.register_client_stmt("q1".to_string());
+        assert!(cache.is_stale(client_id));
+
+        // A stale statement is not closed on the backend when evicted.
+        cache.close_client_stmt(client_id);
+        cache.insert("q2".to_string(), CachedStmt::new(2, vec![], vec![]));
+        let evicted = cache.insert("q3".to_string(), CachedStmt::new(3, vec![], vec![]));
+        assert!(evicted.is_empty());
+        assert!(!cache.contains("q1"));
+
+        cache.authenticated("app", Some(b"billing"));
+        assert!(cache.is_empty());
+        cache.insert("q1".to_string(), CachedStmt::new(1, vec![], vec![]));
+        cache.authenticated("other", Some(b"billing"));
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    pub fn test_stmt_cache_holders() {
+        let mut cache = PreparedStmtCache::new(2);
+        cache.insert("q1".to_string(), CachedStmt::new(1, b"q1".to_vec(), vec![]));
+        let first = cache.register_client_stmt("q1".to_string());
+        let second = cache.register_client_stmt("q1".to_string());
+
+        // Long data of the first client statement is pending on the shared backend statement.
+        cache.on_command(first, CommandCode::ComStmtSendLongData, false);
+        assert!(!cache.is_held_by_other(first));
+        assert!(cache.is_held_by_other(second));
+        cache.set_private(second, CachedStmt::new(2, b"q1".to_vec(), vec![]));
+        assert_eq!(cache.backend_id(second), Some(2));
+        assert!(!cache.is_held_by_other(second));
+        assert_eq!(cache.close_client_stmt(second), Some(StmtRelease::Close(2)));
+
+        // Executing consumes the long data, a cursor is pending until the next execute.
+        cache.on_command(first, CommandCode::ComStmtExecute, false);
+        let third = cache.register_client_stmt("q1".to_string());
+        assert!(!cache.is_held_by_other(third));
+        cache.on_command(first, CommandCode::ComStmtExecute, true);
+        assert!(cache.is_held_by_other(third));
+        cache.on_command(first, CommandCode::ComStmtReset, false);
+        assert!(!cache.is_held_by_other(third));
+
+        // Closing a client statement resets what it left on the shared backend statement.
+        cache.on_command(third, CommandCode::ComStmtSendLongData, false);
+        assert_eq!(cache.close_client_stmt(first), None);
+        assert_eq!(cache.close_client_stmt(third), Some(StmtRelease::Reset(1)));
+    }
+}
@@ -0,0 +1,329 @@
+use crate::backend::capability::capability_cache;
+use crate::backend::BackendInstance;
+use crate::server::default_capabilities;
+use crate::server::notifier::{notify, ProxyEventKind};
+
+use chrono::{Local, SecondsFormat};
+use common::metrics::metric_def::PROXY_BACKEND_QUARANTINED;
+use common::metrics::{common_labels, counter_inc};
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::io::{Error, ErrorKind};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+pub const DEFAULT_QUARANTINE_THRESHOLD: u32 = 5;
+pub const DEFAULT_QUARANTINE_DURATION: Duration = Duration::from_secs(30);
+/// A session failing this soon after its connection was checked out counts as a backend failure.
+pub const EARLY_FAILURE_WINDOW: Duration = Duration::from_secs(1);
+/// Errors and connect timings kept in the diagnostics of a backend.
+const DIAGNOSTIC_HISTORY: usize = 10;
+
+#[derive(Debug, Clone)]
+pub struct QuarantineConfig {
+    /// Consecutive failures that quarantine a backend, 0 disables quarantine.
+    pub threshold: u32,
+    pub duration: Duration,
+}
+
+impl Default for QuarantineConfig {
+    fn default() -> Self {
+        Self {
+            threshold: DEFAULT_QUARANTINE_THRESHOLD,
+            duration: DEFAULT_QUARANTINE_DURATION,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BackendFailure {
+    /// A pooled connection could not be opened.
+    Connect,
+    /// The backend failed the authentication of a session.
+    Auth,
+    /// The connection died right after it was checked out.
+    EarlyClose,
+    /// The connection died in the middle of a session, which failed over to another backend.
+    Lost,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BackendError {
+    /// RFC 3339 time the error occurred.
+    pub time: String,
+    pub failure: BackendFailure,
+    pub message: String,
+}
+
+/// The diagnostic bundle of a backend, captured when it fails and served by the REST API.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BackendDiagnostics {
+    pub addr: String,
+    pub quarantined: bool,
+    /// RFC 3339 time the last quarantine started.
+    pub quarantined_at: Option<String>,
+    pub quarantine_remaining_ms: u64,
+    pub consecutive_failures: u32,
+    pub total_failures: u64,
+    /// The last errors, newest first.
+    pub last_errors: Vec<BackendError>,
+    /// The last successful connects in milliseconds, newest first.
+    pub connect_timings_ms: Vec<u64>,
+    pub server_version: Option<String>,
+    pub auth_plugin: Option<String>,
+    /// Capabilities of the proxy the backend did not advertise.
+    pub missing_capabilities: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct QuarantineRelease {
+    pub addr: String,
+}
+
+#[derive(Default)]
+struct BackendHealth {
+    consecutive_failures: u32,
+    total_failures: u64,
+    quarantined_until: Option<Instant>,
+    quarantined_at: Option<String>,
+    last_errors: VecDeque<BackendError>,
+    connect_timings_ms: VecDeque<u64>,
+}
+
+impl BackendHealth {
+    fn is_quarantined(&self) -> bool {
+        self.quarantined_until
+            .is_some_and(|until| until > Instant::now())
+    }
+}
+
+fn push_bounded<T>(history: &mut VecDeque<T>, item: T) {
+    if history.len() >= DIAGNOSTIC_HISTORY {
+        history.pop_front();
+    }
+    history.push_back(item);
+}
+
+/// `QuarantineRegistry` takes backends that keep failing out of the routing for a while, instead
+/// of recycling failures against them. A quarantine ends after its duration, but the first
+/// failure afterwards puts the backend back until a session succeeds on it.
+pub struct QuarantineRegistry {
+    config: QuarantineConfig,
+    backends: DashMap<String, BackendHealth>,
+}
+
+static QUARANTINE_REGISTRY_ONCE: OnceLock<QuarantineRegistry> = OnceLock::new();
+
+/// Initializes the global quarantine registry, must be called before the proxy serves clients.
+pub fn init_quarantine_registry(config: QuarantineConfig) -> &'static QuarantineRegistry {
+    QUARANTINE_REGISTRY_ONCE.get_or_init(|| QuarantineRegistry::new(config))
+}
+
+pub fn quarantine_registry() -> &'static QuarantineRegistry {
+    QUARANTINE_REGISTRY_ONCE.get_or_init(|| QuarantineRegistry::new(QuarantineConfig::default()))
+}
+
+impl QuarantineRegistry {
+    pub fn new(config: QuarantineConfig) -> Self {
+        Self {
+            config,
+            backends: DashMap::new(),
+        }
+    }
+
+    pub fn record_connect(&self, addr: &str, elapsed: Duration) {
+        let mut health = self.backends.entry(addr.to_string()).or_default();
+        push_bounded(&mut health.connect_timings_ms, elapsed.as_millis() as u64);
+    }
+
+    /// A session authenticated on the backend, ending its failure streak.
+    pub fn record_success(&self, addr: &str) {
+        if let Some(mut health) = self.backends.get_mut(addr) {
+            if health.consecutive_failures > 0 {
+                info!("ProxySrv backend {addr} recovered");
+            }
+            health.consecutive_failures = 0;
+            health.quarantined_until = None;
+        }
+    }
+
+    /// Records a failure of the backend. Returns true if it quarantined the backend.
+    pub fn record_failure(&self, addr: &str, failure: BackendFailure, message: String) -> bool {
+        let mut health = self.backends.entry(addr.to_string()).or_default();
+        health.consecutive_failures += 1;
+        health.total_failures += 1;
+        push_bounded(
+            &mut health.last_errors,
+            BackendError {
+                time: Local::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+                failure,
+                message: message.clone(),
+            },
+        );
+        if self.config.threshold == 0
+            || health.consecutive_failures < self.config.threshold
+            || health.is_quarantined()
+        {
+            return false;
+        }
+        health.quarantined_until = Some(Instant::now() + self.config.duration);
+        health.quarantined_at = Some(Local::now().to_rfc3339_opts(SecondsFormat::Millis, true));
+        let consecutive_failures = health.consecutive_failures;
+        // The entry lock is released before notifying, which may take a while.
+        drop(health);
+        warn!("ProxySrv backend {addr} quarantined after {consecutive_failures} failures");
+        let mut labels = common_labels().clone();
+        labels.push(("backend", addr.to_string()));
+        counter_inc(PROXY_BACKEND_QUARANTINED, 1, Some(&labels));
+        notify(
+            ProxyEventKind::CircuitBreakerOpened,
+            addr,
+            format!(
+                "quarantined for {:?} after {consecutive_failures} consecutive failures, last {failure:?}: {message}",
+                self.config.duration
+            ),
+        );
+        true
+    }
+
+    pub fn is_quarantined(&self, addr: &str) -> bool {
+        self.backends
+            .get(addr)
+            .is_some_and(|health| health.is_quarantined())
+    }
+
+    /// Ends the quarantine of a backend ahead of time, e.g. once an operator fixed it.
+    pub fn release(&self, addr: &str) -> bool {
+        match self.backends.get_mut(addr) {
+            Some(mut health) if health.is_quarantined() => {
+                info!("ProxySrv backend {addr} released from quarantine");
+                health.quarantined_until = None;
+                health.consecutive_failures = 0;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns the backends that are not quarantined.
+    pub fn available(&self, backends: &VecDeque<BackendInstance>) -> Vec<BackendInstance> {
+        backends
+            .iter()
+            .filter(|backend| !self.is_quarantined(&backend.addr))
+            .cloned()
+            .collect()
+    }
+
+    pub fn diagnostics(&self, addr: &str) -> Option<BackendDiagnostics> {
+        let health = self.backends.get(addr)?;
+        let remaining = health
+            .quarantined_until
+            .map(|until| until.saturating_duration_since(Instant::now()))
+            .unwrap_or_default();
+        let backend_caps = capability_cache().get(addr);
+        Some(BackendDiagnostics {
+            addr: addr.to_string(),
+            quarantined: health.is_quarantined(),
+            quarantined_at: health.quarantined_at.clone(),
+            quarantine_remaining_ms: remaining.as_millis() as u64,
+            consecutive_failures: health.consecutive_failures,
+            total_failures: health.total_failures,
+            last_errors: health.last_errors.iter().rev().cloned().collect(),
+            connect_timings_ms: health.connect_timings_ms.iter().rev().copied().collect(),
+            server_version: backend_caps
+                .as_ref()
+                .map(|caps| String::from_utf8_lossy(&caps.server_version).to_string()),
+            auth_plugin: backend_caps.as_ref().map(|caps| caps.auth_plugin.clone()),
+            missing_capabilities: backend_caps
+                .map(|caps| format!("{:?}", default_capabilities() - caps.capabilities)),
+        })
+    }
+
+    /// The diagnostics of every backend that failed, quarantined backends first.
+    pub fn list(&self) -> Vec<BackendDiagnostics> {
+        let addrs: Vec<String> = self
+            .backends
+            .iter()
+            .filter(|entry| entry.total_failures > 0)
+            .map(|entry| entry.key().clone())
+            .collect();
+        let mut list: Vec<_> = addrs
+            .iter()
+            .filter_map(|addr| self.diagnostics(addr))
+            .collect();
+        list.sort_by(|a, b| b.quarantined.cmp(&a.quarantined).then(a.addr.cmp(&b.addr)));
+        list
+    }
+}
+
+/// Whether a failed authentication is the fault of the backend. A backend denying the
+/// credentials of a client is not, unless the proxy supplied them for a mapped identity.
+pub fn is_backend_auth_failure(e: &Error, mapped_identity: bool) -> bool {
+    e.kind() != ErrorKind::PermissionDenied || mapped_identity
+}
+
+/// Whether a session error means its backend connection broke.
+pub fn is_broken_conn(e: &Error) -> bool {
+    matches!(
+        e.kind(),
+        ErrorKind::UnexpectedEof
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::BrokenPipe
+    )
+}
+
+/// The error of a session routed to a tenant whose backends are all quarantined.
+pub fn all_quarantined_err() -> Error {
+    Error::new(
+        ErrorKind::NotConnected,
+        "every backend of the tenant is quarantined",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::quarantine::{BackendFailure, QuarantineConfig, QuarantineRegistry};
+    use crate::backend::BackendInstance;
+    use std::collections::VecDeque;
+    use std::time::Duration;
+
+    #[test]
+    pub fn test_quarantine() {
+        let registry = QuarantineRegistry::new(QuarantineConfig {
+            threshold: 2,
+            duration: Duration::from_secs(60),
+        });
+        let backends = ["10.0.0.1:3306", "10.0.0.2:3306"]
+            .into_iter()
+            .map(|addr| BackendInstance {
+                addr: addr.to_string(),
+                ..Default::default()
+            })
+            .collect::<VecDeque<_>>();
+        let addr = "10.0.0.1:3306";
+        registry.record_connect(addr, Duration::from_millis(3));
+        assert!(!registry.record_failure(addr, BackendFailure::Auth, "denied".to_string()));
+        registry.record_success(addr);
+        assert!(!registry.record_failure(addr, BackendFailure::Connect, "refused".to_string()));
+        assert!(registry.record_failure(addr, BackendFailure::EarlyClose, "eof".to_string()));
+        assert!(registry.is_quarantined(addr));
+        assert_eq!(registry.available(&backends).len(), 1);
+
+        let diagnostics = registry.diagnostics(addr).unwrap();
+        assert_eq!(diagnostics.total_failures, 3);
+        assert_eq!(
+            diagnostics.last_errors[0].failure,
+            BackendFailure::EarlyClose
+        );
+        assert_eq!(diagnostics.connect_timings_ms, vec![3]);
+        assert_eq!(registry.list().len(), 1);
+
+        assert!(registry.release(addr));
+        assert!(!registry.is_quarantined(addr));
+        assert!(!registry.release(addr));
+    }
+}
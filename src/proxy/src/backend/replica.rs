@@ -0,0 +1,274 @@
+use crate::backend::BackendInstance;
+use crate::prost::common_proto::TenantKey;
+use crate::server::slow_log::tenant_label;
+
+use common::metrics::metric_def::{
+    PROXY_READ_FALLBACK_PRIMARY, PROXY_REPLICA_LAG_EXCLUDED, PROXY_REPLICA_LAG_MS,
+};
+use common::metrics::{common_labels, counter_inc, gauge};
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::sync::OnceLock;
+use std::time::Duration;
+use tracing::{debug, info};
+
+/// Control plane label (`DBService.payload`) holding the replication role of a backend.
+pub const ROLE_LABEL: &str = "role";
+/// Control plane label holding the replica lag in milliseconds.
+pub const REPLICA_LAG_LABEL: &str = "replica_lag_ms";
+pub const DEFAULT_MAX_REPLICA_LAG: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BackendRole {
+    #[default]
+    Primary,
+    Replica,
+}
+
+impl BackendRole {
+    pub fn from_label(label: &str) -> Self {
+        if ["replica", "slave", "secondary"]
+            .iter()
+            .any(|role| label.eq_ignore_ascii_case(role))
+        {
+            BackendRole::Replica
+        } else {
+            BackendRole::Primary
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReplicaStatus {
+    pub addr: String,
+    #[serde(default)]
+    pub role: BackendRole,
+    /// `None` if the lag is unknown, a replica of unknown lag is not used for reads. A replica
+    /// that reports its lag as NULL (replication stopped) is stored with `u64::MAX`.
+    #[serde(default)]
+    pub lag_ms: Option<u64>,
+}
+
+impl ReplicaStatus {
+    pub fn from_labels(addr: String, labels: &HashMap<String, String>) -> Self {
+        let role = labels
+            .get(ROLE_LABEL)
+            .map(|role| BackendRole::from_label(role))
+            .unwrap_or_default();
+        let lag_ms = labels
+            .get(REPLICA_LAG_LABEL)
+            .map(|lag| lag.trim().parse::<u64>().unwrap_or(u64::MAX));
+        Self { addr, role, lag_ms }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TenantMaxLag {
+    pub tenant: TenantKey,
+    pub max_lag_ms: u64,
+}
+
+/// `ReplicaRegistry` keeps the role and lag of every backend, fed by control plane labels, and
+/// decides which backends may serve reads for a tenant.
+pub struct ReplicaRegistry {
+    default_max_lag: Duration,
+    tenant_max_lag: DashMap<TenantKey, Duration>,
+    backends: DashMap<String, ReplicaStatus>,
+}
+
+static REPLICA_REGISTRY_ONCE: OnceLock<ReplicaRegistry> = OnceLock::new();
+
+/// Initializes the global replica registry, must be called before the backend discovery starts.
+pub fn init_replica_registry(default_max_lag: Duration) -> &'static ReplicaRegistry {
+    REPLICA_REGISTRY_ONCE.get_or_init(|| ReplicaRegistry::new(default_max_lag))
+}
+
+pub fn replica_registry() -> &'static ReplicaRegistry {
+    REPLICA_REGISTRY_ONCE.get_or_init(|| ReplicaRegistry::new(DEFAULT_MAX_REPLICA_LAG))
+}
+
+fn backend_labels(addr: &str) -> Vec<(&'static str, String)> {
+    let mut labels = common_labels().clone();
+    labels.push(("backend", addr.to_string()));
+    labels
+}
+
+impl ReplicaRegistry {
+    pub fn new(default_max_lag: Duration) -> Self {
+        Self {
+            default_max_lag,
+            tenant_max_lag: DashMap::new(),
+            backends: DashMap::new(),
+        }
+    }
+
+    pub fn update(&self, status: ReplicaStatus) {
+        debug!("ProxySrv replica status {:?}", status);
+        if let Some(lag_ms) = status.lag_ms {
+            gauge(
+                PROXY_REPLICA_LAG_MS,
+                lag_ms as f64,
+                Some(&backend_labels(&status.addr)),
+            );
+        }
+        self.backends.insert(status.addr.clone(), status);
+    }
+
+    pub fn remove(&self, addr: &str) -> Option<ReplicaStatus> {
+        self.backends.remove(addr).map(|(_, status)| status)
+    }
+
+    pub fn list(&self) -> Vec<ReplicaStatus> {
+        self.backends.iter().map(|e| e.value().clone()).collect()
+    }
+
+    pub fn role(&self, addr: &str) -> BackendRole {
+        self.backends
+            .get(addr)
+            .map(|status| status.role)
+            .unwrap_or_default()
+    }
+
+    pub fn set_tenant_max_lag(&self, tenant: TenantKey, max_lag: Duration) {
+        info!("ProxySrv replica max lag {:?} for {:?}", max_lag, tenant);
+        self.tenant_max_lag.insert(tenant, max_lag);
+    }
+
+    pub fn remove_tenant_max_lag(&self, tenant: &TenantKey) -> Option<Duration> {
+        self.tenant_max_lag
+            .remove(tenant)
+            .map(|(_, max_lag)| max_lag)
+    }
+
+    pub fn max_lag(&self, tenant: &TenantKey) -> Duration {
+        self.tenant_max_lag
+            .get(tenant)
+            .map(|max_lag| *max_lag)
+            .unwrap_or(self.default_max_lag)
+    }
+
+    /// A replica lags if its lag exceeds the threshold of `tenant` or is unknown.
+    pub fn is_lagging(&self, tenant: &TenantKey, addr: &str) -> bool {
+        let max_lag_ms = self.max_lag(tenant).as_millis() as u64;
+        self.backends.get(addr).is_some_and(|status| {
+            status.role == BackendRole::Replica
+                && status.lag_ms.map_or(true, |lag_ms| lag_ms > max_lag_ms)
+        })
+    }
+
+    /// Returns the backends that may serve reads for `tenant`: the replicas within the lag
+    /// threshold, or the primaries if every replica lags behind.
+    pub fn read_candidates(
+        &self,
+        tenant: &TenantKey,
+        backends: &VecDeque<BackendInstance>,
+    ) -> Vec<BackendInstance> {
+        let (replicas, primaries): (Vec<_>, Vec<_>) = backends
+            .iter()
+            .cloned()
+            .partition(|backend| self.role(&backend.addr) == BackendRole::Replica);
+        let has_replicas = !replicas.is_empty();
+        let eligible = replicas
+            .into_iter()
+            .filter(|replica| {
+                let lagging = self.is_lagging(tenant, &replica.addr);
+                if lagging {
+                    debug!("ProxySrv replica {} excluded by lag", replica.addr);
+                    counter_inc(
+                        PROXY_REPLICA_LAG_EXCLUDED,
+                        1,
+                        Some(&backend_labels(&replica.addr)),
+                    );
+                }
+                !lagging
+            })
+            .collect::<Vec<_>>();
+        if !eligible.is_empty() {
+            return eligible;
+        }
+        if has_replicas {
+            let mut labels = common_labels().clone();
+            labels.push(("tenant", tenant_label(tenant)));
+            counter_inc(PROXY_READ_FALLBACK_PRIMARY, 1, Some(&labels));
+        }
+        primaries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::replica::{
+        BackendRole, ReplicaRegistry, ReplicaStatus, REPLICA_LAG_LABEL, ROLE_LABEL,
+    };
+    use crate::backend::{test_tenant_key, BackendInstance};
+    use std::collections::{HashMap, VecDeque};
+    use std::time::Duration;
+
+    #[test]
+    pub fn test_read_candidates() {
+        let registry = ReplicaRegistry::new(Duration::from_millis(100));
+        let tenant = test_tenant_key();
+        let backends = ["primary:3306", "replica1:3306", "replica2:3306"]
+            .into_iter()
+            .map(|addr| BackendInstance {
+                addr: addr.to_string(),
+                ..Default::default()
+            })
+            .collect::<VecDeque<_>>();
+        let addrs = |candidates: Vec<BackendInstance>| {
+            candidates.into_iter().map(|b| b.addr).collect::<Vec<_>>()
+        };
+        // Backends without a replica role are primaries.
+        assert_eq!(
+            addrs(registry.read_candidates(&tenant, &backends)),
+            vec!["primary:3306", "replica1:3306", "replica2:3306"]
+        );
+
+        let labels = HashMap::from([
+            (ROLE_LABEL.to_string(), "replica".to_string()),
+            (REPLICA_LAG_LABEL.to_string(), "50".to_string()),
+        ]);
+        registry.update(ReplicaStatus::from_labels(
+            "replica1:3306".to_string(),
+            &labels,
+        ));
+        registry.update(ReplicaStatus {
+            addr: "replica2:3306".to_string(),
+            role: BackendRole::Replica,
+            lag_ms: Some(500),
+        });
+        assert_eq!(
+            addrs(registry.read_candidates(&tenant, &backends)),
+            vec!["replica1:3306"]
+        );
+
+        registry.set_tenant_max_lag(tenant.clone(), Duration::from_millis(10));
+        assert_eq!(
+            addrs(registry.read_candidates(&tenant, &backends)),
+            vec!["primary:3306"]
+        );
+
+        let stopped = HashMap::from([
+            (ROLE_LABEL.to_string(), "replica".to_string()),
+            (REPLICA_LAG_LABEL.to_string(), "NULL".to_string()),
+        ]);
+        let status = ReplicaStatus::from_labels("replica1:3306".to_string(), &stopped);
+        assert_eq!(status.lag_ms, Some(u64::MAX));
+
+        // A replica that does not report its lag is not read from either.
+        registry.set_tenant_max_lag(tenant.clone(), Duration::from_millis(100));
+        registry.update(ReplicaStatus {
+            addr: "replica1:3306".to_string(),
+            role: BackendRole::Replica,
+            lag_ms: None,
+        });
+        assert!(registry.is_lagging(&tenant, "replica1:3306"));
+        assert!(!registry.is_lagging(&tenant, "primary:3306"));
+        assert_eq!(
+            addrs(registry.read_candidates(&tenant, &backends)),
+            vec!["primary:3306"]
+        );
+    }
+}
@@ -1,6 +1,12 @@
+pub mod p2c;
+mod read_write;
 mod static_router;
 mod sync_router;
 
+use crate::backend::quarantine::{all_quarantined_err, quarantine_registry};
+use crate::backend::replica::replica_registry;
+use crate::backend::router::p2c::P2cBalancer;
+use crate::backend::router::read_write::ReadWriteRouter;
 use crate::backend::router::static_router::StaticRouter;
 use crate::backend::router::sync_router::SyncRouter;
 use crate::backend::BackendInstance;
@@ -25,11 +31,15 @@ pub enum BackendRouterType {
     Static,
     #[strum(serialize = "sync-with-cp")]
     SyncWithCp,
+    /// Writes to the primaries and reads to the replicas, of the control plane if one is set.
+    #[strum(serialize = "read-write")]
+    ReadWrite,
 }
 
 pub enum BackendRouterTrait {
     Static(Box<StaticRouter>),
     Sync(Box<SyncRouter>),
+    ReadWrite(Box<ReadWriteRouter>),
 }
 
 impl BackendRouterTrait {
@@ -37,6 +47,34 @@ impl BackendRouterTrait {
         match self {
             BackendRouterTrait::Static(_) => true,
             BackendRouterTrait::Sync(_) => false,
+            BackendRouterTrait::ReadWrite(router) => router.is_static(),
+        }
+    }
+
+    /// Replaces the backends of a static router, returns those added and those removed. `None`
+    /// if the backends are discovered.
+    pub fn set_static_backends(
+        &self,
+        backends: VecDeque<BackendInstance>,
+    ) -> Option<(Vec<BackendInstance>, Vec<BackendInstance>)> {
+        match self {
+            BackendRouterTrait::Static(router) => Some(router.set_backends(backends)),
+            BackendRouterTrait::Sync(_) => None,
+            BackendRouterTrait::ReadWrite(router) => router.set_static_backends(backends),
+        }
+    }
+
+    /// Whether read-only statements are routed apart from the session, to the replicas.
+    pub fn is_read_write(&self) -> bool {
+        matches!(self, BackendRouterTrait::ReadWrite(_))
+    }
+
+    /// Whether the backends are synced from a connected control plane. `None` if they are static.
+    pub fn cp_connected(&self) -> Option<bool> {
+        match self {
+            BackendRouterTrait::Static(_) => None,
+            BackendRouterTrait::Sync(router) => Some(router.cp_connected()),
+            BackendRouterTrait::ReadWrite(router) => router.cp_connected(),
         }
     }
 }
@@ -54,6 +92,7 @@ impl BackendRouter for BackendRouterTrait {
                 s_router.status_change_notify(f).await
             }
             BackendRouterTrait::Sync(sync_router) => sync_router.status_change_notify(f).await,
+            BackendRouterTrait::ReadWrite(router) => router.status_change_notify(f).await,
         }
     }
 
@@ -69,6 +108,33 @@ impl BackendRouter for BackendRouterTrait {
             BackendRouterTrait::Sync(router) => {
                 router.selector(backend_location, backend_selector).await
             }
+            BackendRouterTrait::ReadWrite(router) => {
+                router.selector(backend_location, backend_selector).await
+            }
+        }
+    }
+
+    async fn read_selector(
+        &self,
+        backend_location: &TenantKey,
+        backend_selector: &BackendLoadBalancerType,
+    ) -> Result<BackendInstance, Error> {
+        match self {
+            BackendRouterTrait::Static(router) => {
+                router
+                    .read_selector(backend_location, backend_selector)
+                    .await
+            }
+            BackendRouterTrait::Sync(router) => {
+                router
+                    .read_selector(backend_location, backend_selector)
+                    .await
+            }
+            BackendRouterTrait::ReadWrite(router) => {
+                router
+                    .read_selector(backend_location, backend_selector)
+                    .await
+            }
         }
     }
 
@@ -79,6 +145,7 @@ impl BackendRouter for BackendRouterTrait {
         match self {
             BackendRouterTrait::Static(router) => router.load_backends(backend_location).await,
             BackendRouterTrait::Sync(router) => router.load_backends(backend_location).await,
+            BackendRouterTrait::ReadWrite(router) => router.load_backends(backend_location).await,
         }
     }
 }
@@ -92,8 +159,8 @@ pub enum BackendLoadBalancerType {
 }
 
 pub trait BackendLoadBalancer: Send + Sync {
-    // fn balance<T: Clone>(&self, backends: &VecDeque<T>) -> usize;
-    fn balance(&self, backends: usize) -> usize;
+    /// The index of the backend picked among `backends`, there are at least two of them.
+    fn balance(&self, backends: &[BackendInstance]) -> usize;
 }
 
 pub struct RandomBalancer {
@@ -117,9 +184,25 @@ impl RandomBalancer {
 }
 
 impl BackendLoadBalancer for RandomBalancer {
-    fn balance(&self, backends: usize) -> usize {
+    fn balance(&self, backends: &[BackendInstance]) -> usize {
         let mut mut_rand = self.rand.lock().unwrap();
-        mut_rand.gen_range(0..backends)
+        mut_rand.gen_range(0..backends.len())
+    }
+}
+
+/// One balancer of every type, the routers use the one of the type asked for.
+#[derive(Default)]
+pub struct Balancers {
+    random: RandomBalancer,
+    p2c: P2cBalancer,
+}
+
+impl Balancers {
+    pub fn get(&self, balancer_type: &BackendLoadBalancerType) -> &dyn BackendLoadBalancer {
+        match balancer_type {
+            BackendLoadBalancerType::Random => &self.random,
+            BackendLoadBalancerType::P2C => &self.p2c,
+        }
     }
 }
 
@@ -140,12 +223,58 @@ pub trait BackendRouter: Send + Sync {
         backend_selector: &BackendLoadBalancerType,
     ) -> Result<BackendInstance, Error>;
 
+    /// Like [`selector`](BackendRouter::selector) but for read-only traffic: replicas lagging
+    /// behind the tenant threshold are skipped, falling back to the primary.
+    async fn read_selector(
+        &self,
+        backend_location: &TenantKey,
+        backend_selector: &BackendLoadBalancerType,
+    ) -> Result<BackendInstance, Error>;
+
     async fn load_backends(
         &self,
         backend_location: Option<TenantKey>,
     ) -> Result<VecDeque<BackendInstance>, Error>;
 }
 
+/// Picks a backend among `backends` that is not quarantined.
+fn select_backend(
+    backends: &VecDeque<BackendInstance>,
+    balancer: &dyn BackendLoadBalancer,
+) -> Result<BackendInstance, Error> {
+    let mut candidates = quarantine_registry().available(backends);
+    match candidates.len() {
+        0 if backends.is_empty() => Err(Error::new(
+            std::io::ErrorKind::NotFound,
+            "No backends found",
+        )),
+        0 => Err(all_quarantined_err()),
+        1 => Ok(candidates.remove(0)),
+        _ => Ok(candidates.swap_remove(balancer.balance(&candidates))),
+    }
+}
+
+/// Picks a read backend of `tenant` among `backends`, see [`BackendRouter::read_selector`].
+fn select_read_backend(
+    tenant: &TenantKey,
+    backends: &VecDeque<BackendInstance>,
+    balancer: &dyn BackendLoadBalancer,
+) -> Result<BackendInstance, Error> {
+    let available = VecDeque::from(quarantine_registry().available(backends));
+    if available.is_empty() && !backends.is_empty() {
+        return Err(all_quarantined_err());
+    }
+    let mut candidates = replica_registry().read_candidates(tenant, &available);
+    match candidates.len() {
+        0 => Err(Error::new(
+            std::io::ErrorKind::NotFound,
+            "No read backends found",
+        )),
+        1 => Ok(candidates.remove(0)),
+        _ => Ok(candidates.swap_remove(balancer.balance(&candidates))),
+    }
+}
+
 pub async fn new_backend_router(
     proxy_args: &ProxyServerArgs,
     shutdown_rx: &Receiver<ShutdownMessage>,
@@ -160,6 +289,20 @@ pub async fn new_backend_router(
             BackendRouterType::SyncWithCp => {
                 BackendRouterTrait::Sync(Box::new(SyncRouter::new(proxy_args, shutdown_rx).await))
             }
+            BackendRouterType::ReadWrite => {
+                // Without a control plane, the roles of the static backends are set through the
+                // replica API.
+                let inner = if proxy_args.cp_addr.is_some() {
+                    BackendRouterTrait::Sync(Box::new(
+                        SyncRouter::new(proxy_args, shutdown_rx).await,
+                    ))
+                } else {
+                    BackendRouterTrait::Static(Box::new(StaticRouter::new(
+                        proxy_args.static_backend_list(),
+                    )))
+                };
+                BackendRouterTrait::ReadWrite(Box::new(ReadWriteRouter::new(inner)))
+            }
         }
     } else {
         let test_backend_list = proxy_args.static_backend_list();
@@ -169,14 +312,9 @@ pub async fn new_backend_router(
 
 pub fn new_balancer(
     balancer_type_opt: Option<BackendLoadBalancerType>,
-) -> impl BackendLoadBalancer {
-    if let Some(balancer_type) = balancer_type_opt {
-        match balancer_type {
-            BackendLoadBalancerType::Random => RandomBalancer::new(),
-            // for now only support random.
-            _ => unreachable!(),
-        }
-    } else {
-        RandomBalancer::new()
+) -> Box<dyn BackendLoadBalancer> {
+    match balancer_type_opt {
+        Some(BackendLoadBalancerType::P2C) => Box::new(P2cBalancer::new()),
+        Some(BackendLoadBalancerType::Random) | None => Box::new(RandomBalancer::new()),
     }
 }
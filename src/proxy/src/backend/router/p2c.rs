@@ -0,0 +1,139 @@
+use crate::backend::router::BackendLoadBalancer;
+use crate::backend::BackendInstance;
+
+use chrono::Utc;
+use dashmap::DashMap;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+
+/// `BackendConns` counts the backend connections checked out by client sessions, per backend
+/// address. A session holds its pooled connection until it ends, so the count is the load the
+/// proxy puts on the backend.
+#[derive(Default)]
+pub struct BackendConns {
+    conns: DashMap<String, Arc<AtomicUsize>>,
+}
+
+static BACKEND_CONNS_ONCE: OnceLock<BackendConns> = OnceLock::new();
+
+pub fn backend_conns() -> &'static BackendConns {
+    BACKEND_CONNS_ONCE.get_or_init(BackendConns::default)
+}
+
+/// Counts a checked out connection until dropped.
+pub struct BackendConnGuard {
+    count: Arc<AtomicUsize>,
+}
+
+impl Drop for BackendConnGuard {
+    fn drop(&mut self) {
+        self.count.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl BackendConns {
+    /// Counts a connection to `addr` checked out by a session, until the guard is dropped.
+    pub fn checkout(&self, addr: &str) -> BackendConnGuard {
+        let count = match self.conns.get(addr) {
+            Some(count) => Arc::clone(count.value()),
+            None => Arc::clone(self.conns.entry(addr.to_string()).or_default().value()),
+        };
+        count.fetch_add(1, Ordering::Relaxed);
+        BackendConnGuard { count }
+    }
+
+    /// The connections to `addr` currently checked out.
+    pub fn in_flight(&self, addr: &str) -> usize {
+        self.conns
+            .get(addr)
+            .map_or(0, |count| count.load(Ordering::Relaxed))
+    }
+}
+
+/// Power of two choices: picks two backends at random and takes the one with fewer connections
+/// in flight. Unlike a random pick, a backend that is slower to release its connections, e.g.
+/// a smaller instance, gets fewer new sessions, without the herd a least-connections pick sends
+/// to the backend that just became the least loaded.
+pub struct P2cBalancer {
+    rand: Mutex<StdRng>,
+    conns: &'static BackendConns,
+}
+
+impl Default for P2cBalancer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl P2cBalancer {
+    pub fn new() -> Self {
+        Self {
+            rand: Mutex::new(StdRng::seed_from_u64(
+                Utc::now().timestamp_subsec_nanos().into(),
+            )),
+            conns: backend_conns(),
+        }
+    }
+}
+
+impl BackendLoadBalancer for P2cBalancer {
+    fn balance(&self, backends: &[BackendInstance]) -> usize {
+        if backends.len() < 2 {
+            return 0;
+        }
+        let (first, second) = {
+            let mut rand = self.rand.lock().unwrap();
+            let first = rand.gen_range(0..backends.len());
+            // Two distinct candidates.
+            let second = (first + rand.gen_range(1..backends.len())) % backends.len();
+            (first, second)
+        };
+        let in_flight = |index: usize| self.conns.in_flight(&backends[index].addr);
+        if in_flight(second) < in_flight(first) {
+            second
+        } else {
+            first
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::router::p2c::{backend_conns, P2cBalancer};
+    use crate::backend::router::BackendLoadBalancer;
+    use crate::backend::BackendInstance;
+
+    fn backend(addr: &str) -> BackendInstance {
+        BackendInstance {
+            addr: addr.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    pub fn test_p2c_balancer() {
+        let backends = ["p2c-a:3306", "p2c-b:3306", "p2c-c:3306"].map(backend);
+        let conns = backend_conns();
+        let mut guards = vec![];
+        for (addr, count) in [("p2c-a:3306", 2), ("p2c-c:3306", 5)] {
+            guards.extend((0..count).map(|_| conns.checkout(addr)));
+        }
+        assert_eq!(conns.in_flight("p2c-c:3306"), 5);
+
+        let balancer = P2cBalancer::new();
+        let mut picks = [0; 3];
+        for _ in 0..300 {
+            picks[balancer.balance(&backends)] += 1;
+        }
+        // The most loaded backend is never picked, the least loaded wins every pair it is in.
+        assert_eq!(picks[2], 0);
+        assert!(picks[1] > picks[0]);
+        assert_eq!(balancer.balance(&backends[2..]), 0);
+
+        drop(guards);
+        assert_eq!(conns.in_flight("p2c-a:3306"), 0);
+        assert_eq!(conns.in_flight("p2c-d:3306"), 0);
+    }
+}
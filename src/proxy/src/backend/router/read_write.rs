@@ -0,0 +1,142 @@
+use crate::backend::replica::{replica_registry, BackendRole};
+use crate::backend::router::{
+    select_backend, BackendLoadBalancerType, BackendRouter, BackendRouterTrait, Balancers,
+};
+use crate::backend::BackendInstance;
+use crate::prost::common_proto::TenantKey;
+use async_trait::async_trait;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io::Error;
+
+/// `ReadWriteRouter` splits the backends of a tenant by the role the topology labels give them:
+/// sessions, and so writes, go to the primaries, while read-only statements go to the replicas
+/// within the lag threshold of the tenant through [`read_selector`](BackendRouter::read_selector).
+/// It discovers the backends with the router it wraps.
+pub struct ReadWriteRouter {
+    inner: BackendRouterTrait,
+    balancers: Balancers,
+}
+
+impl ReadWriteRouter {
+    pub fn new(inner: BackendRouterTrait) -> Self {
+        Self {
+            inner,
+            balancers: Balancers::default(),
+        }
+    }
+
+    pub fn is_static(&self) -> bool {
+        self.inner.is_static()
+    }
+
+    pub fn set_static_backends(
+        &self,
+        backends: VecDeque<BackendInstance>,
+    ) -> Option<(Vec<BackendInstance>, Vec<BackendInstance>)> {
+        self.inner.set_static_backends(backends)
+    }
+
+    pub fn cp_connected(&self) -> Option<bool> {
+        self.inner.cp_connected()
+    }
+}
+
+/// The backends of `backends` that are not replicas.
+fn primaries(backends: VecDeque<BackendInstance>) -> VecDeque<BackendInstance> {
+    let registry = replica_registry();
+    backends
+        .into_iter()
+        .filter(|backend| registry.role(&backend.addr) == BackendRole::Primary)
+        .collect()
+}
+
+#[async_trait]
+impl BackendRouter for ReadWriteRouter {
+    async fn status_change_notify<F, Fut>(&self, f: F) -> Result<(), Error>
+    where
+        F: Fn(BackendInstance) -> Fut + Send,
+        Fut: Future<Output = Result<(), Error>> + Send + Sync,
+    {
+        self.inner.status_change_notify(f).await
+    }
+
+    async fn selector(
+        &self,
+        backend_location: &TenantKey,
+        backend_selector: &BackendLoadBalancerType,
+    ) -> Result<BackendInstance, Error> {
+        let backends = self
+            .inner
+            .load_backends(Some(backend_location.clone()))
+            .await?;
+        let primaries = primaries(backends);
+        if primaries.is_empty() {
+            return Err(Error::new(
+                std::io::ErrorKind::NotFound,
+                "No primary backends found",
+            ));
+        }
+        select_backend(&primaries, self.balancers.get(backend_selector))
+    }
+
+    async fn read_selector(
+        &self,
+        backend_location: &TenantKey,
+        backend_selector: &BackendLoadBalancerType,
+    ) -> Result<BackendInstance, Error> {
+        self.inner
+            .read_selector(backend_location, backend_selector)
+            .await
+    }
+
+    async fn load_backends(
+        &self,
+        backend_location: Option<TenantKey>,
+    ) -> Result<VecDeque<BackendInstance>, Error> {
+        self.inner.load_backends(backend_location).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::replica::{replica_registry, BackendRole, ReplicaStatus};
+    use crate::backend::router::read_write::ReadWriteRouter;
+    use crate::backend::router::static_router::StaticRouter;
+    use crate::backend::router::{BackendLoadBalancerType, BackendRouter, BackendRouterTrait};
+    use crate::backend::{test_tenant_key, BackendInstance};
+    use std::collections::VecDeque;
+
+    #[tokio::test]
+    pub async fn test_read_write_router() {
+        let backends = ["rw-primary:3306", "rw-replica:3306"]
+            .into_iter()
+            .map(|addr| BackendInstance {
+                addr: addr.to_string(),
+                ..Default::default()
+            })
+            .collect::<VecDeque<_>>();
+        replica_registry().update(ReplicaStatus {
+            addr: "rw-replica:3306".to_string(),
+            role: BackendRole::Replica,
+            lag_ms: Some(0),
+        });
+        let router = ReadWriteRouter::new(BackendRouterTrait::Static(Box::new(StaticRouter::new(
+            backends,
+        ))));
+        let tenant = test_tenant_key();
+        for _ in 0..8 {
+            let write = router
+                .selector(&tenant, &BackendLoadBalancerType::Random)
+                .await
+                .unwrap();
+            assert_eq!(write.addr, "rw-primary:3306");
+            let read = router
+                .read_selector(&tenant, &BackendLoadBalancerType::Random)
+                .await
+                .unwrap();
+            assert_eq!(read.addr, "rw-replica:3306");
+        }
+        replica_registry().remove("rw-replica:3306");
+    }
+}
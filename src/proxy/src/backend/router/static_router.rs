@@ -1,5 +1,5 @@
 use crate::backend::router::{
-    BackendLoadBalancer, BackendLoadBalancerType, BackendRouter, RandomBalancer,
+    select_backend, select_read_backend, BackendLoadBalancerType, BackendRouter, Balancers,
 };
 use crate::backend::BackendInstance;
 use crate::prost::common_proto::TenantKey;
@@ -7,20 +7,41 @@ use async_trait::async_trait;
 use std::collections::VecDeque;
 use std::future::Future;
 use std::io::Error;
+use std::sync::RwLock;
 
 /// StaticRouter Only for testing purposes.
 pub struct StaticRouter {
-    backend_addrs: VecDeque<BackendInstance>,
-    balancer: RandomBalancer,
+    backend_addrs: RwLock<VecDeque<BackendInstance>>,
+    balancers: Balancers,
 }
 
 impl StaticRouter {
     pub fn new(backend_addrs: VecDeque<BackendInstance>) -> Self {
         Self {
-            backend_addrs,
-            balancer: RandomBalancer::new(),
+            backend_addrs: RwLock::new(backend_addrs),
+            balancers: Balancers::default(),
         }
     }
+
+    /// Replaces the backends, returns those added and those removed.
+    pub fn set_backends(
+        &self,
+        backends: VecDeque<BackendInstance>,
+    ) -> (Vec<BackendInstance>, Vec<BackendInstance>) {
+        let mut backend_addrs = self.backend_addrs.write().unwrap();
+        let added = backends
+            .iter()
+            .filter(|backend| !backend_addrs.contains(backend))
+            .cloned()
+            .collect();
+        let removed = backend_addrs
+            .iter()
+            .filter(|backend| !backends.contains(backend))
+            .cloned()
+            .collect();
+        *backend_addrs = backends;
+        (added, removed)
+    }
 }
 
 #[async_trait]
@@ -30,8 +51,9 @@ impl BackendRouter for StaticRouter {
         F: Fn(BackendInstance) -> Fut + Send,
         Fut: Future<Output = Result<(), Error>> + Send + Sync,
     {
-        for backend in self.backend_addrs.iter() {
-            f(backend.clone()).await.unwrap();
+        let backends = self.backend_addrs.read().unwrap().clone();
+        for backend in backends {
+            f(backend).await.unwrap();
         }
         Ok(())
     }
@@ -39,19 +61,30 @@ impl BackendRouter for StaticRouter {
     async fn selector(
         &self,
         _backend_location: &TenantKey,
-        _backend_selector: &BackendLoadBalancerType,
+        backend_selector: &BackendLoadBalancerType,
+    ) -> Result<BackendInstance, Error> {
+        select_backend(
+            &self.backend_addrs.read().unwrap(),
+            self.balancers.get(backend_selector),
+        )
+    }
+
+    async fn read_selector(
+        &self,
+        backend_location: &TenantKey,
+        backend_selector: &BackendLoadBalancerType,
     ) -> Result<BackendInstance, Error> {
-        let backend_deque = &self.backend_addrs;
-        let backend_count = backend_deque.len();
-        let selected_idx = self.balancer.balance(backend_count);
-        let backend_addr = backend_deque.get(selected_idx).unwrap();
-        Ok(backend_addr.clone())
+        select_read_backend(
+            backend_location,
+            &self.backend_addrs.read().unwrap(),
+            self.balancers.get(backend_selector),
+        )
     }
 
     async fn load_backends(
         &self,
         _backend_location: Option<TenantKey>,
     ) -> Result<VecDeque<BackendInstance>, Error> {
-        Ok(self.backend_addrs.clone())
+        Ok(self.backend_addrs.read().unwrap().clone())
     }
 }
@@ -1,6 +1,6 @@
 use crate::backend::backend_discovery::BackendDiscovery;
 use crate::backend::router::{
-    BackendLoadBalancer, BackendLoadBalancerType, BackendRouter, RandomBalancer,
+    select_backend, select_read_backend, BackendLoadBalancerType, BackendRouter, Balancers,
 };
 use crate::backend::{start_backend_discovery, BackendInstance};
 use crate::prost::common_proto::TenantKey;
@@ -17,7 +17,7 @@ use tracing::warn;
 
 pub struct SyncRouter {
     be_discovery: Arc<BackendDiscovery>,
-    balancer: Box<dyn BackendLoadBalancer>,
+    balancers: Balancers,
 }
 
 impl SyncRouter {
@@ -33,9 +33,13 @@ impl SyncRouter {
                 shutdown_rx,
             )
             .await,
-            balancer: Box::new(RandomBalancer::new()),
+            balancers: Balancers::default(),
         }
     }
+
+    pub fn cp_connected(&self) -> bool {
+        self.be_discovery.is_connected()
+    }
 }
 
 #[async_trait]
@@ -68,26 +72,20 @@ impl BackendRouter for SyncRouter {
     ) -> Result<BackendInstance, Error> {
         if let Some(entry) = self.be_discovery.all_cluster_list().get(tenant_key) {
             let cluster_list_read_guard = entry.value().read().await;
+            select_backend(&cluster_list_read_guard, self.balancers.get(lb))
+        } else {
+            Err(Error::new(std::io::ErrorKind::NotFound, "Tenant not found"))
+        }
+    }
 
-            if cluster_list_read_guard.is_empty() {
-                return Err(Error::new(
-                    std::io::ErrorKind::NotFound,
-                    "No backends found",
-                ));
-            } else if cluster_list_read_guard.len() == 1 {
-                return Ok(cluster_list_read_guard.front().unwrap().clone());
-            }
-            match lb {
-                BackendLoadBalancerType::Random => {
-                    let backend_count = cluster_list_read_guard.len();
-                    let selected_idx = self.balancer.balance(backend_count);
-                    let backend_instance = cluster_list_read_guard.get(selected_idx).unwrap();
-                    Ok(backend_instance.clone())
-                }
-                BackendLoadBalancerType::P2C => {
-                    unreachable!()
-                }
-            }
+    async fn read_selector(
+        &self,
+        tenant_key: &TenantKey,
+        lb: &BackendLoadBalancerType,
+    ) -> Result<BackendInstance, Error> {
+        if let Some(entry) = self.be_discovery.all_cluster_list().get(tenant_key) {
+            let cluster_list_read_guard = entry.value().read().await;
+            select_read_backend(tenant_key, &cluster_list_read_guard, self.balancers.get(lb))
         } else {
             Err(Error::new(std::io::ErrorKind::NotFound, "Tenant not found"))
         }
@@ -0,0 +1,323 @@
+use crate::backend::handshake_tenant_key;
+use crate::prost::common_proto::TenantKey;
+use crate::protocol::mysql::basic::HandshakeResponse;
+use crate::protocol::mysql::error_codes;
+use crate::protocol::mysql::packet::packet_writer::PacketWriter;
+use crate::protocol::mysql::packet::writers;
+use crate::server::slow_log::tenant_label;
+
+use common::metrics::common_labels;
+use common::metrics::metric_def::PROXY_SHARD_ROUTED;
+use dashmap::DashMap;
+use mysql_common::constants::CapabilityFlags;
+use serde::{Deserialize, Serialize};
+use std::hash::Hasher;
+use std::io::{Error, ErrorKind};
+use std::sync::OnceLock;
+use tokio::io::AsyncWrite;
+use tracing::{debug, info};
+
+/// `user@shard`, the shard key appended to the user name.
+pub const SHARD_KEY_DELIMITER: u8 = b'@';
+/// Connection attribute holding the shard key, e.g. `mysql --connect-attr shard=eu`.
+pub const SHARD_CONNECT_ATTR: &str = "shard";
+/// Statements may carry `/*+ SHARD(key) */` to assert the shard they are meant for.
+const SHARD_HINT_PREFIX: &str = "/*+ SHARD(";
+
+/// Where the shard key of a connection is taken from.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ShardKeySource {
+    /// The suffix of the user name after [`SHARD_KEY_DELIMITER`], stripped before the user name
+    /// is forwarded to the backend.
+    #[default]
+    Username,
+    /// The database of the handshake.
+    Database,
+    /// The [`SHARD_CONNECT_ATTR`] connection attribute.
+    ConnectAttr,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Shard {
+    pub name: String,
+    /// The cluster serving this shard, in the namespace of the tenant.
+    pub cluster_name: String,
+}
+
+/// A tenant whose data is split over several clusters. `tenant` is the key clients connect
+/// with, its `cluster_name` is a logical name that does not exist in the control plane.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ShardedTenant {
+    pub tenant: TenantKey,
+    #[serde(default)]
+    pub key_source: ShardKeySource,
+    pub shards: Vec<Shard>,
+}
+
+impl ShardedTenant {
+    /// A key naming a shard selects it, any other key is hashed over the shards so that
+    /// e.g. `customer_42` always lands on the same shard.
+    pub fn select(&self, key: &str) -> &Shard {
+        self.shards
+            .iter()
+            .find(|shard| shard.name == key)
+            .unwrap_or_else(|| {
+                let mut hasher = twox_hash::xxh3::Hash64::default();
+                hasher.write_str(key);
+                &self.shards[(hasher.finish() % self.shards.len() as u64) as usize]
+            })
+    }
+
+    fn shard_tenant(&self, shard: &Shard) -> TenantKey {
+        TenantKey {
+            cluster_name: shard.cluster_name.clone(),
+            ..self.tenant.clone()
+        }
+    }
+}
+
+/// The shard a connection has been routed to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShardRoute {
+    pub shard: String,
+    /// The tenant key of the shard cluster, used to select the backend.
+    pub tenant: TenantKey,
+}
+
+/// `ShardRegistry` keeps the sharded tenants registered by the control plane and routes their
+/// connections to a shard. The backend connection is bound at authentication, so a session stays
+/// on one shard; statements hinting another shard are rejected instead of running on the wrong
+/// data.
+#[derive(Default)]
+pub struct ShardRegistry {
+    tenants: DashMap<TenantKey, ShardedTenant>,
+}
+
+static SHARD_REGISTRY_ONCE: OnceLock<ShardRegistry> = OnceLock::new();
+
+pub fn shard_registry() -> &'static ShardRegistry {
+    SHARD_REGISTRY_ONCE.get_or_init(ShardRegistry::default)
+}
+
+/// Returns the key of a leading `/*+ SHARD(key) */` hint.
+pub fn shard_hint(sql: &[u8]) -> Option<&str> {
+    let sql = std::str::from_utf8(sql).ok()?.trim_start();
+    let hint = sql.get(..SHARD_HINT_PREFIX.len())?;
+    if !hint.eq_ignore_ascii_case(SHARD_HINT_PREFIX) {
+        return None;
+    }
+    let rest = &sql[SHARD_HINT_PREFIX.len()..];
+    let end = rest.find(')')?;
+    rest[end + 1..]
+        .trim_start()
+        .starts_with("*/")
+        .then(|| rest[..end].trim())
+}
+
+impl ShardRegistry {
+    pub fn set_tenant(&self, sharded: ShardedTenant) -> Result<(), Error> {
+        if sharded.shards.is_empty() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "a sharded tenant needs at least one shard",
+            ));
+        }
+        let mut names = sharded
+            .shards
+            .iter()
+            .map(|shard| shard.name.as_str())
+            .collect::<Vec<_>>();
+        names.sort_unstable();
+        names.dedup();
+        if names.len() != sharded.shards.len() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "shard names must be unique",
+            ));
+        }
+        info!("ProxySrv sharded tenant set {:?}", sharded);
+        self.tenants.insert(sharded.tenant.clone(), sharded);
+        Ok(())
+    }
+
+    pub fn remove_tenant(&self, tenant: &TenantKey) -> Option<ShardedTenant> {
+        info!("ProxySrv sharded tenant removed {:?}", tenant);
+        self.tenants.remove(tenant).map(|(_, sharded)| sharded)
+    }
+
+    pub fn list(&self) -> Vec<ShardedTenant> {
+        self.tenants.iter().map(|e| e.value().clone()).collect()
+    }
+
+    pub fn get(&self, tenant: &TenantKey) -> Option<ShardedTenant> {
+        self.tenants.get(tenant).map(|e| e.value().clone())
+    }
+
+    /// Routes the connection of a sharded tenant to a shard and records it in
+    /// `handshake_rsp.shard`. Connections of other tenants are left untouched.
+    pub fn route(&self, handshake_rsp: &mut HandshakeResponse) -> Result<(), Error> {
+        let tenant = handshake_tenant_key(handshake_rsp);
+        let Some(sharded) = self.get(&tenant) else {
+            return Ok(());
+        };
+        let key = match sharded.key_source {
+            ShardKeySource::Username => {
+                let username = handshake_rsp.username.clone().unwrap_or_default();
+                username
+                    .iter()
+                    .rposition(|c| *c == SHARD_KEY_DELIMITER)
+                    .map(|pos| {
+                        handshake_rsp.username = Some(username[..pos].to_vec());
+                        String::from_utf8_lossy(&username[pos + 1..]).to_string()
+                    })
+            }
+            ShardKeySource::Database => handshake_rsp
+                .database
+                .as_ref()
+                .map(|db| String::from_utf8_lossy(db).to_string()),
+            ShardKeySource::ConnectAttr => handshake_rsp
+                .connect_attributes
+                .as_ref()
+                .and_then(|attrs| attrs.get(SHARD_CONNECT_ATTR).cloned()),
+        }
+        .filter(|key| !key.is_empty())
+        .ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "tenant is sharded, a shard key is required in the {:?}",
+                    sharded.key_source
+                ),
+            )
+        })?;
+        let shard = sharded.select(&key);
+        debug!("ProxySrv shard key {key} routed to {:?}", shard);
+        let mut labels = common_labels().clone();
+        labels.push(("tenant", tenant_label(&tenant)));
+        labels.push(("shard", shard.name.clone()));
+        common::metrics::counter_inc(PROXY_SHARD_ROUTED, 1, Some(&labels));
+        handshake_rsp.shard = Some(ShardRoute {
+            shard: shard.name.clone(),
+            tenant: sharded.shard_tenant(shard),
+        });
+        Ok(())
+    }
+
+    /// Whether a statement hinting shard key `hint` may run on the shard of `route`.
+    pub fn hint_matches(&self, tenant: &TenantKey, route: &ShardRoute, hint: &str) -> bool {
+        self.tenants
+            .get(tenant)
+            .map_or(true, |sharded| sharded.select(hint).name == route.shard)
+    }
+}
+
+/// Refuses a statement of a session on the shard of `route` whose [`shard_hint`] selects another
+/// shard, with ER_WRONG_ARGUMENTS. Returns true if the statement was refused. `seq` is the
+/// sequence id of the client packet answered.
+pub async fn reject_shard_mismatch<W>(
+    tenant: &TenantKey,
+    route: &ShardRoute,
+    sql: &[u8],
+    seq: u8,
+    client_writer: &mut PacketWriter<W>,
+    client_capabilities: CapabilityFlags,
+) -> Result<bool, Error>
+where
+    W: AsyncWrite + Send + Unpin,
+{
+    let Some(hint) = shard_hint(sql) else {
+        return Ok(false);
+    };
+    if shard_registry().hint_matches(tenant, route, hint) {
+        return Ok(false);
+    }
+    let message = format!(
+        "Shard hint {hint} does not match the session shard {}",
+        route.shard
+    );
+    client_writer.set_seq(seq.wrapping_add(1));
+    writers::write_err_packet(
+        error_codes::ErrorKind::ER_WRONG_ARGUMENTS,
+        message.as_bytes(),
+        client_writer,
+        client_capabilities,
+    )
+    .await?;
+    client_writer.flush_all().await?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::shard::{shard_hint, Shard, ShardKeySource, ShardRegistry, ShardedTenant};
+    use crate::backend::{encode_tenant_key, test_tenant_key};
+    use crate::protocol::mysql::basic::HandshakeResponse;
+    use mysql_common::constants::CapabilityFlags;
+
+    fn handshake(username: &str, database: Option<&str>) -> HandshakeResponse {
+        HandshakeResponse {
+            client_flag: CapabilityFlags::empty(),
+            max_packet_len: 0,
+            collation: 0,
+            tenant_key: Some(encode_tenant_key(&test_tenant_key()).into_bytes()),
+            username: Some(username.as_bytes().to_vec()),
+            auth_response: vec![],
+            auth_plugin: vec![],
+            database: database.map(|db| db.as_bytes().to_vec()),
+            connect_attributes: None,
+            shard: None,
+            identity: None,
+            reconnect: None,
+            reconnect_token: None,
+            server_name: None,
+        }
+    }
+
+    #[test]
+    pub fn test_shard_route() {
+        let registry = ShardRegistry::default();
+        let sharded = ShardedTenant {
+            tenant: test_tenant_key(),
+            key_source: ShardKeySource::Username,
+            shards: ["eu", "us"]
+                .into_iter()
+                .map(|name| Shard {
+                    name: name.to_string(),
+                    cluster_name: format!("cluster-{name}"),
+                })
+                .collect(),
+        };
+        registry.set_tenant(sharded.clone()).unwrap();
+
+        let mut rsp = handshake("app@us", None);
+        registry.route(&mut rsp).unwrap();
+        let route = rsp.shard.clone().unwrap();
+        assert_eq!(route.shard, "us");
+        assert_eq!(route.tenant.cluster_name, "cluster-us");
+        assert_eq!(route.tenant.namespace, test_tenant_key().namespace);
+        assert_eq!(rsp.username.as_deref(), Some(&b"app"[..]));
+        assert!(registry.route(&mut handshake("app", None)).is_err());
+
+        // Hashed keys are stable.
+        let hashed = sharded.select("customer_42").name.clone();
+        assert_eq!(sharded.select("customer_42").name, hashed);
+        assert!(registry.hint_matches(&test_tenant_key(), &route, "us"));
+        assert!(!registry.hint_matches(&test_tenant_key(), &route, "eu"));
+
+        registry
+            .set_tenant(ShardedTenant {
+                key_source: ShardKeySource::Database,
+                ..sharded
+            })
+            .unwrap();
+        let mut rsp = handshake("app@us", Some("eu"));
+        registry.route(&mut rsp).unwrap();
+        assert_eq!(rsp.shard.unwrap().shard, "eu");
+        assert_eq!(rsp.username.as_deref(), Some(&b"app@us"[..]));
+
+        assert_eq!(shard_hint(b" /*+ shard(eu) */ SELECT 1"), Some("eu"));
+        assert_eq!(shard_hint(b"/*+ SHARD(eu) SELECT 1"), None);
+        assert_eq!(shard_hint(b"SELECT 1"), None);
+    }
+}
@@ -0,0 +1,77 @@
+use crate::backend::BackendInstance;
+
+use std::collections::{HashSet, VecDeque};
+
+/// `StatusEventQueue` orders the backend status events waiting to be applied to the pools. The
+/// events of one backend are applied one at a time in the order they arrived, a backend going
+/// Offline then Ready again ends up with a pool. Events of different backends may be applied in
+/// parallel, a slow pool build does not hold back the other backends of a rollout.
+#[derive(Debug, Default)]
+pub struct StatusEventQueue {
+    queue: VecDeque<BackendInstance>,
+    /// Addresses of the backends with an event being applied.
+    in_flight: HashSet<String>,
+}
+
+impl StatusEventQueue {
+    pub fn push(&mut self, backend: BackendInstance) {
+        self.queue.push_back(backend);
+    }
+
+    /// The oldest event of a backend without an event being applied, until [`done`] is called
+    /// for the backend.
+    ///
+    /// [`done`]: StatusEventQueue::done
+    pub fn next_ready(&mut self) -> Option<BackendInstance> {
+        let position = self
+            .queue
+            .iter()
+            .position(|backend| !self.in_flight.contains(&backend.addr))?;
+        let backend = self.queue.remove(position)?;
+        self.in_flight.insert(backend.addr.clone());
+        Some(backend)
+    }
+
+    /// The event of the backend at `addr` is applied, its next event is ready.
+    pub fn done(&mut self, addr: &str) {
+        self.in_flight.remove(addr);
+    }
+
+    /// Events waiting, those being applied excluded.
+    pub fn depth(&self) -> usize {
+        self.queue.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::status_events::StatusEventQueue;
+    use crate::backend::BackendInstance;
+    use crate::prost::common_proto::ServiceStatus;
+
+    #[test]
+    pub fn test_status_event_queue() {
+        let event = |addr: &str, status| BackendInstance {
+            addr: addr.to_string(),
+            status,
+            ..Default::default()
+        };
+        let mut queue = StatusEventQueue::default();
+        queue.push(event("a", ServiceStatus::Offline));
+        queue.push(event("a", ServiceStatus::Ready));
+        queue.push(event("b", ServiceStatus::Ready));
+        assert_eq!(queue.depth(), 3);
+
+        assert_eq!(queue.next_ready(), Some(event("a", ServiceStatus::Offline)));
+        // `a` waits for its first event, `b` does not.
+        assert_eq!(queue.next_ready(), Some(event("b", ServiceStatus::Ready)));
+        assert_eq!(queue.next_ready(), None);
+        assert_eq!(queue.depth(), 1);
+
+        queue.done("b");
+        assert_eq!(queue.next_ready(), None);
+        queue.done("a");
+        assert_eq!(queue.next_ready(), Some(event("a", ServiceStatus::Ready)));
+        assert_eq!(queue.depth(), 0);
+    }
+}
@@ -0,0 +1,314 @@
+//! Streams to the backends. A backend address is a TCP `host:port` by default; colocated
+//! deployments may instead point a backend at a unix socket, `unix:///run/mysqld/mysqld.sock`,
+//! or at an in-process backend, `mem://name`, that registered `name` with [`mem_backends`]. The
+//! pool and the forwarders read and write the halves of a [`BackendStream`] alike.
+
+use crate::backend::egress::{egress_policy, EgressTarget};
+use crate::server::proxy_protocol::{encode_v2_header, ProxiedAddrs};
+
+use dashmap::DashMap;
+use std::io::{Error, ErrorKind, IoSlice};
+use std::pin::Pin;
+use std::sync::OnceLock;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, DuplexStream, ReadBuf, ReadHalf, WriteHalf};
+use tokio::net::{tcp, TcpStream};
+use tokio::sync::mpsc;
+
+pub const UNIX_SCHEME: &str = "unix://";
+pub const MEM_SCHEME: &str = "mem://";
+/// Bytes buffered in each direction of an in-process connection.
+const MEM_BUFFER_SIZE: usize = 256 * 1024;
+
+/// Where a backend address points to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackendAddr<'a> {
+    /// A `host:port`.
+    Tcp(&'a str),
+    /// The path of a unix socket.
+    Unix(&'a str),
+    /// The name of an in-process backend.
+    Mem(&'a str),
+}
+
+impl<'a> BackendAddr<'a> {
+    pub fn parse(addr: &'a str) -> Self {
+        if let Some(path) = addr.strip_prefix(UNIX_SCHEME) {
+            BackendAddr::Unix(path)
+        } else if let Some(name) = addr.strip_prefix(MEM_SCHEME) {
+            BackendAddr::Mem(name)
+        } else {
+            BackendAddr::Tcp(addr)
+        }
+    }
+}
+
+pub enum BackendReadHalf {
+    Tcp(tcp::OwnedReadHalf),
+    #[cfg(unix)]
+    Unix(tokio::net::unix::OwnedReadHalf),
+    /// An in-process connection and the name of its backend.
+    Mem(ReadHalf<DuplexStream>, String),
+}
+
+impl BackendReadHalf {
+    /// The address of the backend, in the scheme the connection was opened with.
+    pub fn peer_addr(&self) -> Result<String, Error> {
+        match self {
+            BackendReadHalf::Tcp(r) => Ok(r.peer_addr()?.to_string()),
+            #[cfg(unix)]
+            BackendReadHalf::Unix(r) => {
+                let peer_addr = r.peer_addr()?;
+                let path = peer_addr.as_pathname().ok_or_else(|| {
+                    Error::new(ErrorKind::AddrNotAvailable, "unnamed unix socket backend")
+                })?;
+                Ok(format!("{UNIX_SCHEME}{}", path.display()))
+            }
+            BackendReadHalf::Mem(_, name) => Ok(format!("{MEM_SCHEME}{name}")),
+        }
+    }
+
+    /// Whether the backend treats the transport as secure, it then accepts passwords in clear
+    /// text, e.g. over a unix socket.
+    pub fn is_secure(&self) -> bool {
+        !matches!(self, BackendReadHalf::Tcp(_))
+    }
+}
+
+pub enum BackendWriteHalf {
+    Tcp(tcp::OwnedWriteHalf),
+    #[cfg(unix)]
+    Unix(tokio::net::unix::OwnedWriteHalf),
+    Mem(WriteHalf<DuplexStream>),
+}
+
+/// The two halves of a connection to a backend.
+pub type BackendStream = (BackendReadHalf, BackendWriteHalf);
+
+/// Connects to the backend at `addr`. TCP addresses go through the egress policy; unix sockets
+/// and in-process backends never leave the host.
+pub async fn connect_backend(addr: &str) -> Result<BackendStream, Error> {
+    match BackendAddr::parse(addr) {
+        BackendAddr::Tcp(addr) => {
+            let allowed_addrs = egress_policy().resolve(EgressTarget::Backend, addr).await?;
+            // Connecting must not block the worker, pools are warmed up concurrently.
+            let (reader, writer) = TcpStream::connect(&allowed_addrs[..]).await?.into_split();
+            Ok((BackendReadHalf::Tcp(reader), BackendWriteHalf::Tcp(writer)))
+        }
+        #[cfg(unix)]
+        BackendAddr::Unix(path) => {
+            let (reader, writer) = tokio::net::UnixStream::connect(path).await?.into_split();
+            Ok((
+                BackendReadHalf::Unix(reader),
+                BackendWriteHalf::Unix(writer),
+            ))
+        }
+        #[cfg(not(unix))]
+        BackendAddr::Unix(_) => Err(Error::new(
+            ErrorKind::Unsupported,
+            "unix socket backends are only supported on unix",
+        )),
+        BackendAddr::Mem(name) => {
+            let (reader, writer) = tokio::io::split(mem_backends().connect(name)?);
+            Ok((
+                BackendReadHalf::Mem(reader, name.to_string()),
+                BackendWriteHalf::Mem(writer),
+            ))
+        }
+    }
+}
+
+/// Starts a backend connection with a PROXY protocol v2 header. Pooled connections serve many
+/// clients, the header names the proxy end of the connection; a LOCAL header is sent over unix
+/// sockets and in-process connections.
+pub async fn write_proxy_header(writer: &mut BackendWriteHalf) -> Result<(), Error> {
+    let addrs = match writer {
+        BackendWriteHalf::Tcp(w) => Some(ProxiedAddrs {
+            source: w.local_addr()?,
+            destination: w.peer_addr()?,
+        }),
+        _ => None,
+    };
+    writer.write_all(&encode_v2_header(addrs)).await?;
+    writer.flush().await
+}
+
+impl AsyncRead for BackendReadHalf {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            BackendReadHalf::Tcp(r) => Pin::new(r).poll_read(cx, buf),
+            #[cfg(unix)]
+            BackendReadHalf::Unix(r) => Pin::new(r).poll_read(cx, buf),
+            BackendReadHalf::Mem(r, _) => Pin::new(r).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for BackendWriteHalf {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, Error>> {
+        match self.get_mut() {
+            BackendWriteHalf::Tcp(w) => Pin::new(w).poll_write(cx, buf),
+            #[cfg(unix)]
+            BackendWriteHalf::Unix(w) => Pin::new(w).poll_write(cx, buf),
+            BackendWriteHalf::Mem(w) => Pin::new(w).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<Result<usize, Error>> {
+        match self.get_mut() {
+            BackendWriteHalf::Tcp(w) => Pin::new(w).poll_write_vectored(cx, bufs),
+            #[cfg(unix)]
+            BackendWriteHalf::Unix(w) => Pin::new(w).poll_write_vectored(cx, bufs),
+            BackendWriteHalf::Mem(w) => Pin::new(w).poll_write_vectored(cx, bufs),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match self {
+            BackendWriteHalf::Tcp(w) => w.is_write_vectored(),
+            #[cfg(unix)]
+            BackendWriteHalf::Unix(w) => w.is_write_vectored(),
+            BackendWriteHalf::Mem(w) => w.is_write_vectored(),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        match self.get_mut() {
+            BackendWriteHalf::Tcp(w) => Pin::new(w).poll_flush(cx),
+            #[cfg(unix)]
+            BackendWriteHalf::Unix(w) => Pin::new(w).poll_flush(cx),
+            BackendWriteHalf::Mem(w) => Pin::new(w).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        match self.get_mut() {
+            BackendWriteHalf::Tcp(w) => Pin::new(w).poll_shutdown(cx),
+            #[cfg(unix)]
+            BackendWriteHalf::Unix(w) => Pin::new(w).poll_shutdown(cx),
+            BackendWriteHalf::Mem(w) => Pin::new(w).poll_shutdown(cx),
+        }
+    }
+}
+
+/// `MemBackends` keeps the in-process backends, e.g. a database embedded in the proxy process or
+/// a test double. Connecting to `mem://name` hands the other end of an in-memory duplex stream to
+/// the listener of `name`.
+#[derive(Default)]
+pub struct MemBackends {
+    listeners: DashMap<String, mpsc::UnboundedSender<DuplexStream>>,
+}
+
+static MEM_BACKENDS_ONCE: OnceLock<MemBackends> = OnceLock::new();
+
+pub fn mem_backends() -> &'static MemBackends {
+    MEM_BACKENDS_ONCE.get_or_init(MemBackends::default)
+}
+
+/// Accepts the connections to an in-process backend.
+pub struct MemListener {
+    rx: mpsc::UnboundedReceiver<DuplexStream>,
+}
+
+impl MemListener {
+    /// The next connection, `None` once the backend was unregistered.
+    pub async fn accept(&mut self) -> Option<DuplexStream> {
+        self.rx.recv().await
+    }
+}
+
+impl MemBackends {
+    /// Registers the in-process backend `name`, replacing a backend of the same name.
+    pub fn listen(&self, name: &str) -> MemListener {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.listeners.insert(name.to_string(), tx);
+        MemListener { rx }
+    }
+
+    pub fn remove(&self, name: &str) -> bool {
+        self.listeners.remove(name).is_some()
+    }
+
+    pub fn connect(&self, name: &str) -> Result<DuplexStream, Error> {
+        let listener = self.listeners.get(name).ok_or_else(|| {
+            Error::new(
+                ErrorKind::NotFound,
+                format!("no in-process backend {MEM_SCHEME}{name}"),
+            )
+        })?;
+        let (client, server) = tokio::io::duplex(MEM_BUFFER_SIZE);
+        listener.send(server).map_err(|_| {
+            Error::new(
+                ErrorKind::ConnectionRefused,
+                format!("in-process backend {MEM_SCHEME}{name} stopped accepting"),
+            )
+        })?;
+        Ok(client)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::stream::{connect_backend, mem_backends, BackendAddr};
+    use std::io::ErrorKind;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    pub async fn test_backend_stream() {
+        assert_eq!(
+            BackendAddr::parse("10.0.0.1:3306"),
+            BackendAddr::Tcp("10.0.0.1:3306")
+        );
+        assert_eq!(
+            BackendAddr::parse("unix:///run/mysqld/mysqld.sock"),
+            BackendAddr::Unix("/run/mysqld/mysqld.sock")
+        );
+        assert_eq!(BackendAddr::parse("mem://db"), BackendAddr::Mem("db"));
+
+        let mut listener = mem_backends().listen("stream-test");
+        let server = tokio::spawn(async move {
+            let mut conn = listener.accept().await.unwrap();
+            let mut buf = [0; 4];
+            conn.read_exact(&mut buf).await.unwrap();
+            conn.write_all(&buf).await.unwrap();
+        });
+        let (mut reader, mut writer) = connect_backend("mem://stream-test").await.unwrap();
+        assert_eq!(reader.peer_addr().unwrap(), "mem://stream-test");
+        writer.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        reader.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+        server.await.unwrap();
+
+        // The listener is gone with the server task.
+        let refused = connect_backend("mem://stream-test").await.err().unwrap();
+        assert_eq!(refused.kind(), ErrorKind::ConnectionRefused);
+        assert!(mem_backends().remove("stream-test"));
+        let missing = connect_backend("mem://stream-test").await.err().unwrap();
+        assert_eq!(missing.kind(), ErrorKind::NotFound);
+        #[cfg(unix)]
+        {
+            let path = std::env::temp_dir().join(format!("{}-backend.sock", std::process::id()));
+            let _ = std::fs::remove_file(&path);
+            let listener = tokio::net::UnixListener::bind(&path).unwrap();
+            let addr = format!("unix://{}", path.display());
+            let (reader, _writer) = connect_backend(&addr).await.unwrap();
+            assert!(listener.accept().await.is_ok());
+            assert_eq!(reader.peer_addr().unwrap(), addr);
+            std::fs::remove_file(&path).unwrap();
+            assert!(connect_backend(&addr).await.is_err());
+        }
+    }
+}
@@ -0,0 +1,191 @@
+use crate::backend::backend_mgr::BackendMgr;
+use crate::backend::BackendInstance;
+use crate::prost::common_proto::TenantKey;
+use crate::server::drain::serves_tenant;
+use crate::server::session::session_registry;
+
+use common::clock::{clock, Clock, Timestamp};
+use common::metrics::metric_def::PROXY_TENANTS;
+use common::metrics::{common_labels, gauge};
+use common::ShutdownMessage;
+use dashmap::{DashMap, DashSet};
+use itertools::Itertools;
+use std::cmp::Reverse;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tokio::sync::watch;
+use tracing::info;
+
+/// `TenantActivity` tracks when each tenant last connected to its backends. Tenants idle beyond
+/// a TTL cool down: their pools are closed and no new ones are opened for them, until their next
+/// connection reopens them. A proxy serving thousands of tenants then only keeps the pools of
+/// the hot ones. The backend lists of cold tenants are kept, the discovery subscribes per
+/// namespace and they are a few entries each.
+pub struct TenantActivity {
+    clock: Clock,
+    last_used: DashMap<TenantKey, Timestamp>,
+    cold: DashSet<TenantKey>,
+}
+
+static TENANT_ACTIVITY_ONCE: OnceLock<TenantActivity> = OnceLock::new();
+
+pub fn tenant_activity() -> &'static TenantActivity {
+    TENANT_ACTIVITY_ONCE.get_or_init(|| TenantActivity::with_clock(clock().clone()))
+}
+
+impl TenantActivity {
+    pub fn with_clock(clock: Clock) -> Self {
+        Self {
+            clock,
+            last_used: DashMap::new(),
+            cold: DashSet::new(),
+        }
+    }
+
+    /// Records a connection of `tenant`. Returns true if the tenant was cold, its pools have to
+    /// be reopened.
+    pub fn touch(&self, tenant: &TenantKey) -> bool {
+        self.last_used
+            .insert(tenant.clone(), self.clock.coarse_now());
+        !self.cold.is_empty() && self.cold.remove(tenant).is_some()
+    }
+
+    /// Marks `tenant` cold, its backends get no new pools.
+    pub fn cool(&self, tenant: &TenantKey) {
+        self.cold.insert(tenant.clone());
+    }
+
+    /// Whether `backend` serves a cold tenant, and gets no pool until the tenant connects again.
+    pub fn is_cold_backend(&self, backend: &BackendInstance) -> bool {
+        !self.cold.is_empty()
+            && self
+                .cold
+                .iter()
+                .any(|tenant| serves_tenant(backend, &tenant))
+    }
+
+    /// `tenants` ordered by their last connection, most recent first. Tenants that never
+    /// connected come last.
+    pub fn by_recency(&self, tenants: impl IntoIterator<Item = TenantKey>) -> Vec<TenantKey> {
+        tenants
+            .into_iter()
+            .sorted_by_cached_key(|tenant| Reverse(self.last_used.get(tenant).map(|t| *t)))
+            .collect()
+    }
+
+    /// The hot tenants idle for `ttl` without sessions, least recently used first.
+    fn idle_tenants(&self, ttl: Duration) -> Vec<TenantKey> {
+        let sessions = session_registry();
+        self.last_used
+            .iter()
+            .filter(|e| self.clock.coarse_elapsed(*e.value()) >= ttl)
+            .map(|e| (*e.value(), e.key().clone()))
+            .sorted_by_key(|(last_used, _)| *last_used)
+            .map(|(_, tenant)| tenant)
+            .filter(|tenant| !self.cold.contains(tenant))
+            .filter(|tenant| sessions.tenant_sessions(tenant).is_empty())
+            .collect()
+    }
+
+    /// Closes the pools of the tenants idle for `ttl`. Returns the tenants cooled down.
+    pub fn cool_down(&self, backend_mgr: &BackendMgr, ttl: Duration) -> usize {
+        let idle = self.idle_tenants(ttl);
+        for tenant in &idle {
+            // Cold before its pools are closed, a Ready backend event must not reopen them.
+            self.cool(tenant);
+            let pools = backend_mgr.release_tenant_pools(tenant);
+            info!("ProxySrv tenant {tenant:?} idle for {ttl:?}, closed {pools} pools");
+        }
+        self.report();
+        idle.len()
+    }
+
+    fn report(&self) {
+        let cold = self.cold.len();
+        let hot = self.last_used.len().saturating_sub(cold);
+        for (state, count) in [("hot", hot), ("cold", cold)] {
+            let mut labels = common_labels().clone();
+            labels.push(("state", state.to_string()));
+            gauge(PROXY_TENANTS, count as f64, Some(&labels));
+        }
+    }
+}
+
+/// Cools down the tenants idle for `ttl`, checking a few times per `ttl`.
+pub async fn run_tenant_cool_down(
+    backend_mgr: Arc<BackendMgr>,
+    ttl: Duration,
+    mut shutdown_rx: watch::Receiver<ShutdownMessage>,
+) {
+    let mut interval = tokio::time::interval((ttl / 4).max(Duration::from_secs(1)));
+    info!("ProxySrv tenant cool down started, idle ttl {ttl:?}");
+    loop {
+        tokio::select! {
+            _ = shutdown_rx.changed() => {
+                info!("ProxySrv tenant cool down shutdown");
+                return;
+            }
+            _ = interval.tick() => {
+                tenant_activity().cool_down(&backend_mgr, ttl);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::tenant_activity::TenantActivity;
+    use crate::backend::BackendInstance;
+    use crate::prost::common_proto::{ClusterName, TenantKey};
+    use common::clock::Clock;
+    use std::time::Duration;
+
+    fn tenant(cluster_name: &str) -> TenantKey {
+        TenantKey {
+            namespace: "tenant-activity".to_string(),
+            cluster_name: cluster_name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    pub fn test_tenant_activity() {
+        let (clock, time) = Clock::mock();
+        let activity = TenantActivity::with_clock(clock);
+        let (a, b, c) = (tenant("a"), tenant("b"), tenant("c"));
+        assert!(!activity.touch(&a));
+        time.advance(Duration::from_secs(30));
+        assert!(!activity.touch(&b));
+        time.advance(Duration::from_secs(30));
+        assert_eq!(
+            activity.by_recency([a.clone(), c.clone(), b.clone()]),
+            vec![b.clone(), a.clone(), c.clone()]
+        );
+        assert_eq!(
+            activity.idle_tenants(Duration::from_secs(45)),
+            vec![a.clone()]
+        );
+        assert_eq!(
+            activity.idle_tenants(Duration::from_secs(30)),
+            vec![a.clone(), b.clone()]
+        );
+
+        activity.cold.insert(a.clone());
+        assert_eq!(
+            activity.idle_tenants(Duration::from_secs(30)),
+            vec![b.clone()]
+        );
+        let backend = BackendInstance {
+            cluster: ClusterName {
+                namespace: "tenant-activity".to_string(),
+                cluster_name: "a".to_string(),
+            },
+            ..Default::default()
+        };
+        assert!(activity.is_cold_backend(&backend));
+        // The next connection warms the tenant up once.
+        assert!(activity.touch(&a));
+        assert!(!activity.touch(&a));
+        assert!(!activity.is_cold_backend(&backend));
+    }
+}
@@ -0,0 +1,199 @@
+use crate::prost::common_proto::TenantKey;
+use crate::server::notifier::{notify, ProxyEventKind};
+use crate::server::slow_log::tenant_label;
+
+use common::clock::{clock, Clock, Timestamp};
+use common::metrics::metric_def::{PROXY_TOPOLOGY_EVENT_AGE, PROXY_TOPOLOGY_STALE_TENANTS};
+use common::metrics::{common_labels, gauge};
+use common::ShutdownMessage;
+use dashmap::{DashMap, DashSet};
+use itertools::Itertools;
+use std::sync::OnceLock;
+use std::time::Duration;
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+/// Backend connection failures of a tenant, without a success in between, before its backend
+/// list counts as stale.
+pub const STALE_MIN_FAILURES: u32 = 3;
+
+/// `TopologyFreshness` tracks when the backend discovery last received a change event of each
+/// tenant. A tenant whose backend list was not refreshed within the staleness window while its
+/// connections keep failing is flagged stale: the discovery most likely stopped updating it, and
+/// the proxy keeps routing to backends that are gone.
+pub struct TopologyFreshness {
+    clock: Clock,
+    /// Last change event per tenant, or its subscription if it had none yet.
+    last_event: DashMap<TenantKey, Timestamp>,
+    /// Backend connection failures per tenant since its last successful connection.
+    failures: DashMap<TenantKey, u32>,
+    stale: DashSet<TenantKey>,
+}
+
+static TOPOLOGY_FRESHNESS_ONCE: OnceLock<TopologyFreshness> = OnceLock::new();
+
+pub fn topology_freshness() -> &'static TopologyFreshness {
+    TOPOLOGY_FRESHNESS_ONCE.get_or_init(|| TopologyFreshness::with_clock(clock().clone()))
+}
+
+impl TopologyFreshness {
+    pub fn with_clock(clock: Clock) -> Self {
+        Self {
+            clock,
+            last_event: DashMap::new(),
+            failures: DashMap::new(),
+            stale: DashSet::new(),
+        }
+    }
+
+    /// Starts tracking a subscribed tenant, its age counts from now until its first event.
+    pub fn track(&self, tenant: &TenantKey) {
+        self.last_event
+            .entry(tenant.clone())
+            .or_insert_with(|| self.clock.coarse_now());
+    }
+
+    /// Records a change event of `tenant`.
+    pub fn record_event(&self, tenant: &TenantKey) {
+        self.last_event
+            .insert(tenant.clone(), self.clock.coarse_now());
+        if self.stale.remove(tenant).is_some() {
+            info!("ProxySrv backend list of {tenant:?} refreshed, no longer stale");
+        }
+    }
+
+    pub fn record_connect_failure(&self, tenant: &TenantKey) {
+        *self.failures.entry(tenant.clone()).or_default() += 1;
+    }
+
+    pub fn record_connect_success(&self, tenant: &TenantKey) {
+        // Most connections succeed, skip the write lock when there is nothing to reset.
+        if self.failures.contains_key(tenant) {
+            self.failures.remove(tenant);
+        }
+    }
+
+    /// Time since the last change event of `tenant`, `None` if it is not tracked.
+    pub fn event_age(&self, tenant: &TenantKey) -> Option<Duration> {
+        self.last_event
+            .get(tenant)
+            .map(|last| self.clock.coarse_elapsed(*last))
+    }
+
+    /// The tenants without a change event for `window` whose connections keep failing.
+    pub fn stale_tenants(&self, window: Duration) -> Vec<TenantKey> {
+        self.failures
+            .iter()
+            .filter(|e| *e.value() >= STALE_MIN_FAILURES)
+            .filter(|e| self.event_age(e.key()).is_some_and(|age| age >= window))
+            .map(|e| e.key().clone())
+            .sorted_by_key(tenant_label)
+            .collect()
+    }
+
+    /// Reports the event age of every tenant and flags the stale ones. Returns the tenants that
+    /// turned stale.
+    pub fn check(&self, window: Duration) -> Vec<TenantKey> {
+        for e in self.last_event.iter() {
+            let mut labels = common_labels().clone();
+            labels.push(("tenant", tenant_label(e.key())));
+            let age = self.clock.coarse_elapsed(*e.value());
+            gauge(PROXY_TOPOLOGY_EVENT_AGE, age.as_secs_f64(), Some(&labels));
+        }
+        let turned_stale = self
+            .stale_tenants(window)
+            .into_iter()
+            .filter(|tenant| self.stale.insert(tenant.clone()))
+            .collect_vec();
+        for tenant in &turned_stale {
+            let age = self.event_age(tenant).unwrap_or_default();
+            let failures = self.failures.get(tenant).map_or(0, |e| *e.value());
+            let message = format!(
+                "no backend change event for {}s while {failures} connections failed",
+                age.as_secs()
+            );
+            warn!("ProxySrv backend list of {tenant:?} stale, {message}");
+            notify(
+                ProxyEventKind::TopologyStale,
+                &tenant_label(tenant),
+                message,
+            );
+        }
+        let stale = self.stale.len() as f64;
+        gauge(PROXY_TOPOLOGY_STALE_TENANTS, stale, Some(common_labels()));
+        turned_stale
+    }
+}
+
+/// Checks the freshness of the backend lists a few times per staleness `window`.
+pub async fn run_topology_freshness_check(
+    window: Duration,
+    mut shutdown_rx: watch::Receiver<ShutdownMessage>,
+) {
+    let mut interval = tokio::time::interval((window / 4).max(Duration::from_secs(1)));
+    info!("ProxySrv topology freshness check started, staleness window {window:?}");
+    loop {
+        tokio::select! {
+            _ = shutdown_rx.changed() => {
+                info!("ProxySrv topology freshness check shutdown");
+                return;
+            }
+            _ = interval.tick() => {
+                topology_freshness().check(window);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::topology_freshness::{TopologyFreshness, STALE_MIN_FAILURES};
+    use crate::prost::common_proto::TenantKey;
+    use common::clock::Clock;
+    use std::time::Duration;
+
+    fn tenant(cluster_name: &str) -> TenantKey {
+        TenantKey {
+            namespace: "topology-freshness".to_string(),
+            cluster_name: cluster_name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    pub fn test_topology_freshness() {
+        let (clock, time) = Clock::mock();
+        let freshness = TopologyFreshness::with_clock(clock);
+        let window = Duration::from_secs(60);
+        let (a, b, c) = (tenant("a"), tenant("b"), tenant("c"));
+        freshness.track(&a);
+        freshness.track(&c);
+        freshness.record_event(&b);
+        time.advance(Duration::from_secs(45));
+        freshness.record_event(&a);
+        // Tracking again keeps the last event.
+        freshness.track(&a);
+        assert_eq!(freshness.event_age(&a), Some(Duration::ZERO));
+        assert_eq!(freshness.event_age(&b), Some(Duration::from_secs(45)));
+        assert_eq!(freshness.event_age(&tenant("d")), None);
+
+        for _ in 0..STALE_MIN_FAILURES {
+            freshness.record_connect_failure(&a);
+            freshness.record_connect_failure(&b);
+        }
+        // Failures alone are not stale.
+        assert!(freshness.stale_tenants(window).is_empty());
+        time.advance(Duration::from_secs(30));
+        // Neither is an old backend list alone, c has no failures and a connected again.
+        freshness.record_connect_success(&a);
+        time.advance(Duration::from_secs(30));
+        assert_eq!(freshness.stale_tenants(window), vec![b.clone()]);
+
+        assert_eq!(freshness.check(window), vec![b.clone()]);
+        // Flagged once until refreshed.
+        assert!(freshness.check(window).is_empty());
+        freshness.record_event(&b);
+        assert!(freshness.stale_tenants(window).is_empty());
+        assert!(freshness.check(window).is_empty());
+    }
+}
@@ -0,0 +1,335 @@
+use crate::async_packet_read;
+use crate::protocol::mysql::constants::CommandCode;
+use crate::protocol::mysql::packet::packet_reader::PacketReader;
+use crate::protocol::mysql::packet::packet_writer::PacketWriter;
+use crate::protocol::mysql::packet::{writers, Packet};
+
+use byteorder::{LittleEndian, WriteBytesExt};
+use mysql_common::constants::CapabilityFlags;
+use mysql_common::io::ParseBuf;
+use mysql_common::packets::{AuthPlugin, ErrPacket, HandshakePacket, HandshakeResponse};
+use mysql_common::proto::{MyDeserialize, MySerialize};
+use mysql_common::scramble::{scramble_native, scramble_sha256};
+use std::borrow::Cow;
+use std::io::{Error, ErrorKind, Write};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+
+const AUTH_SWITCH_REQUEST: u8 = 0xfe;
+const AUTH_MORE_DATA: u8 = 0x01;
+const FAST_AUTH_SUCCESS: u8 = 0x03;
+const PERFORM_FULL_AUTH: u8 = 0x04;
+const SERVER_MORE_RESULTS_EXISTS: u16 = 0x0008;
+const MYSQL_TYPE_LONGLONG: u8 = 0x08;
+
+fn client_capabilities() -> CapabilityFlags {
+    CapabilityFlags::CLIENT_LONG_PASSWORD
+        | CapabilityFlags::CLIENT_PROTOCOL_41
+        | CapabilityFlags::CLIENT_SECURE_CONNECTION
+        | CapabilityFlags::CLIENT_PLUGIN_AUTH
+        | CapabilityFlags::CLIENT_PLUGIN_AUTH_LENENC_CLIENT_DATA
+        | CapabilityFlags::CLIENT_TRANSACTIONS
+        | CapabilityFlags::CLIENT_MULTI_RESULTS
+        | CapabilityFlags::CLIENT_PS_MULTI_RESULTS
+        | CapabilityFlags::CLIENT_DEPRECATE_EOF
+}
+
+fn scramble(plugin: &[u8], nonce: &[u8], password: &str) -> Result<Vec<u8>, Error> {
+    if password.is_empty() {
+        return Ok(Vec::new());
+    }
+    let scrambled = match plugin {
+        b"mysql_native_password" => scramble_native(nonce, password.as_bytes()).map(Vec::from),
+        b"caching_sha2_password" => scramble_sha256(nonce, password.as_bytes()).map(Vec::from),
+        _ => {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                format!(
+                    "auth plugin {} is not supported by bench",
+                    String::from_utf8_lossy(plugin)
+                ),
+            ))
+        }
+    };
+    Ok(scrambled.unwrap_or_default())
+}
+
+fn server_error(pkt: &Packet, capabilities: CapabilityFlags) -> Error {
+    match ErrPacket::deserialize(capabilities, &mut ParseBuf(pkt)) {
+        Ok(err_packet) => {
+            let srv_error = err_packet.server_error();
+            Error::other(format!(
+                "ERROR {}: {}",
+                srv_error.error_code(),
+                srv_error.message_str()
+            ))
+        }
+        Err(e) => Error::new(ErrorKind::InvalidData, e),
+    }
+}
+
+/// Skips a length-encoded integer and returns the remaining bytes.
+fn skip_lenenc_int(buf: &[u8]) -> &[u8] {
+    let len = match buf.first() {
+        Some(0xfc) => 3,
+        Some(0xfd) => 4,
+        Some(0xfe) => 9,
+        Some(_) => 1,
+        None => 0,
+    };
+    &buf[len.min(buf.len())..]
+}
+
+/// Status flags of an OK or EOF packet.
+fn status_flags(pkt: &Packet) -> u16 {
+    let rest = if pkt.is_eof_packet() {
+        // EOF: header, warnings, status flags.
+        pkt.get(3..).unwrap_or_default()
+    } else {
+        // OK: header, affected rows, last insert id, status flags.
+        skip_lenenc_int(skip_lenenc_int(&pkt[1..]))
+    };
+    match rest {
+        [low, high, ..] => u16::from_le_bytes([*low, *high]),
+        _ => 0,
+    }
+}
+
+/// `BenchConn` is a minimal MySQL client connection, just enough to drive the benchmark
+/// workloads: text queries, prepared statements with integer parameters, result sets are
+/// read and discarded.
+pub struct BenchConn {
+    reader: PacketReader<OwnedReadHalf>,
+    writer: PacketWriter<OwnedWriteHalf>,
+    capabilities: CapabilityFlags,
+}
+
+impl BenchConn {
+    pub async fn connect(
+        addr: &str,
+        user: &str,
+        password: &str,
+        database: Option<&str>,
+    ) -> Result<Self, Error> {
+        let stream = TcpStream::connect(addr).await?;
+        stream.set_nodelay(true)?;
+        let (r, w) = stream.into_split();
+        let mut conn = BenchConn {
+            reader: PacketReader::new(r),
+            writer: PacketWriter::new(w),
+            capabilities: client_capabilities(),
+        };
+        conn.handshake(user, password, database).await?;
+        Ok(conn)
+    }
+
+    async fn handshake(
+        &mut self,
+        user: &str,
+        password: &str,
+        database: Option<&str>,
+    ) -> Result<(), Error> {
+        let (seq, pkt) = async_packet_read!(self.reader);
+        if pkt.is_err_packet() {
+            return Err(server_error(&pkt, self.capabilities));
+        }
+        let handshake = HandshakePacket::deserialize((), &mut ParseBuf(&pkt))
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        self.capabilities &= handshake.capabilities();
+        let mut nonce = handshake.nonce();
+        let mut plugin = handshake
+            .auth_plugin_name_ref()
+            .unwrap_or(b"mysql_native_password")
+            .to_vec();
+        let auth_data = scramble(&plugin, &nonce, password)?;
+        let response = HandshakeResponse::new(
+            Some(auth_data),
+            handshake.server_version_parsed().unwrap_or((8, 0, 36)),
+            Some(user.as_bytes()),
+            database.map(|db| db.as_bytes()),
+            Some(AuthPlugin::Other(Cow::from(plugin.as_slice()))),
+            self.capabilities,
+            None,
+            16 * 1024 * 1024,
+        );
+        self.capabilities = response.capabilities();
+        let mut buf = Vec::new();
+        response.serialize(&mut buf);
+        self.writer.set_seq(seq.wrapping_add(1));
+        self.writer.write_all(&buf)?;
+        self.writer.end_packet().await?;
+        self.writer.flush_all().await?;
+
+        loop {
+            let (seq, pkt) = async_packet_read!(self.reader);
+            match pkt.first() {
+                Some(0x00) => return Ok(()),
+                Some(0xff) => return Err(server_error(&pkt, self.capabilities)),
+                Some(&AUTH_SWITCH_REQUEST) => {
+                    // plugin name, NUL, auth plugin data (NUL terminated for the builtin plugins).
+                    let body = &pkt[1..];
+                    let name_end = body.iter().position(|b| *b == 0).unwrap_or(body.len());
+                    plugin = body[..name_end].to_vec();
+                    let data = body.get(name_end + 1..).unwrap_or_default();
+                    nonce = data.strip_suffix(&[0]).unwrap_or(data).to_vec();
+                    let auth_data = scramble(&plugin, &nonce, password)?;
+                    self.writer.set_seq(seq.wrapping_add(1));
+                    self.writer.write_all(&auth_data)?;
+                    self.writer.end_packet().await?;
+                    self.writer.flush_all().await?;
+                }
+                Some(&AUTH_MORE_DATA) if pkt.get(1) == Some(&FAST_AUTH_SUCCESS) => continue,
+                Some(&AUTH_MORE_DATA) if pkt.get(1) == Some(&PERFORM_FULL_AUTH) => {
+                    return Err(Error::new(
+                        ErrorKind::PermissionDenied,
+                        "caching_sha2_password full authentication is not supported by bench, \
+                         connect once with the mysql client to warm up the auth cache",
+                    ))
+                }
+                _ => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("unexpected auth packet {:?}", pkt.first()),
+                    ))
+                }
+            }
+        }
+    }
+
+    /// Runs a text query and discards the result.
+    pub async fn query(&mut self, sql: &str) -> Result<(), Error> {
+        self.writer.reset_seq();
+        writers::write_query_request(&mut self.writer, sql.as_bytes()).await?;
+        self.writer.flush_all().await?;
+        self.read_results().await
+    }
+
+    /// Prepares `sql` and returns the statement id.
+    pub async fn prepare(&mut self, sql: &str) -> Result<u32, Error> {
+        self.writer.reset_seq();
+        self.writer.write_u8(CommandCode::ComStmtPrepare as u8)?;
+        self.writer.write_all(sql.as_bytes())?;
+        self.writer.end_packet().await?;
+        self.writer.flush_all().await?;
+        let (_, pkt) = async_packet_read!(self.reader);
+        if pkt.is_err_packet() {
+            return Err(server_error(&pkt, self.capabilities));
+        }
+        // COM_STMT_PREPARE_OK: status, stmt id, num columns, num params, ...
+        if pkt.len() < 9 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "short COM_STMT_PREPARE_OK",
+            ));
+        }
+        let stmt_id = u32::from_le_bytes([pkt[1], pkt[2], pkt[3], pkt[4]]);
+        let num_columns = u16::from_le_bytes([pkt[5], pkt[6]]);
+        let num_params = u16::from_le_bytes([pkt[7], pkt[8]]);
+        for defs in [num_params, num_columns] {
+            if defs > 0 {
+                self.skip_definitions(defs as usize).await?;
+            }
+        }
+        Ok(stmt_id)
+    }
+
+    /// Executes a prepared statement whose parameters are all integers and discards the result.
+    pub async fn execute(&mut self, stmt_id: u32, params: &[i64]) -> Result<(), Error> {
+        self.writer.reset_seq();
+        self.writer.write_u8(CommandCode::ComStmtExecute as u8)?;
+        self.writer.write_u32::<LittleEndian>(stmt_id)?;
+        // flags: CURSOR_TYPE_NO_CURSOR, iteration count: 1
+        self.writer.write_u8(0)?;
+        self.writer.write_u32::<LittleEndian>(1)?;
+        if !params.is_empty() {
+            self.writer.write_all(&vec![0; params.len().div_ceil(8)])?;
+            // new params bound
+            self.writer.write_u8(1)?;
+            for _ in params {
+                self.writer.write_all(&[MYSQL_TYPE_LONGLONG, 0])?;
+            }
+            for param in params {
+                self.writer.write_i64::<LittleEndian>(*param)?;
+            }
+        }
+        self.writer.end_packet().await?;
+        self.writer.flush_all().await?;
+        self.read_results().await
+    }
+
+    pub async fn close(mut self) -> Result<(), Error> {
+        self.writer.reset_seq();
+        self.writer.write_u8(CommandCode::ComQuit as u8)?;
+        self.writer.end_packet().await?;
+        self.writer.flush_all().await
+    }
+
+    fn deprecate_eof(&self) -> bool {
+        self.capabilities
+            .contains(CapabilityFlags::CLIENT_DEPRECATE_EOF)
+    }
+
+    async fn skip_definitions(&mut self, count: usize) -> Result<(), Error> {
+        for _ in 0..count {
+            async_packet_read!(self.reader);
+        }
+        if !self.deprecate_eof() {
+            async_packet_read!(self.reader);
+        }
+        Ok(())
+    }
+
+    /// Reads every result of the current command, the rows of a result set are discarded.
+    async fn read_results(&mut self) -> Result<(), Error> {
+        loop {
+            let (_, pkt) = async_packet_read!(self.reader);
+            let status = if pkt.is_err_packet() {
+                return Err(server_error(&pkt, self.capabilities));
+            } else if pkt.is_ok_packet() {
+                status_flags(&pkt)
+            } else {
+                // column count, column definitions, rows until EOF / OK.
+                let num_columns = match pkt[0] {
+                    n @ 0..0xfb => n as usize,
+                    _ => {
+                        return Err(Error::new(
+                            ErrorKind::InvalidData,
+                            "unexpected result set header",
+                        ))
+                    }
+                };
+                self.skip_definitions(num_columns).await?;
+                loop {
+                    let (_, row) = async_packet_read!(self.reader);
+                    if row.is_err_packet() {
+                        return Err(server_error(&row, self.capabilities));
+                    }
+                    if row.is_eof_packet() || row.is_result_set_eof_packet() {
+                        break status_flags(&row);
+                    }
+                }
+            };
+            if status & SERVER_MORE_RESULTS_EXISTS == 0 {
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bench::client::{skip_lenenc_int, status_flags};
+    use crate::protocol::mysql::packet::Packet;
+
+    #[test]
+    pub fn test_status_flags() {
+        // OK: affected rows 1, last insert id 0xfc 0x10 0x27, status SERVER_MORE_RESULTS_EXISTS.
+        let ok = Packet::from_vec(vec![0x00, 0x01, 0xfc, 0x10, 0x27, 0x08, 0x00, 0, 0]);
+        assert_eq!(status_flags(&ok), 0x0008);
+        // EOF: warnings 0, status SERVER_STATUS_AUTOCOMMIT.
+        let eof = Packet::from_vec(vec![0xfe, 0, 0, 0x02, 0x00]);
+        assert_eq!(status_flags(&eof), 0x0002);
+        assert_eq!(skip_lenenc_int(&[0xfd, 1, 2, 3, 4]), &[4]);
+        assert!(skip_lenenc_int(&[]).is_empty());
+    }
+}
@@ -0,0 +1,399 @@
+pub mod client;
+
+use crate::bench::client::BenchConn;
+
+use itertools::Itertools;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::fmt::{Display, Formatter};
+use std::io::{Error, ErrorKind};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+pub const BENCH_TABLE: &str = "sbtest1";
+const PREPARE_BATCH_SIZE: usize = 1000;
+const OLTP_POINT_SELECTS: usize = 10;
+const OLTP_RANGE_SIZE: i64 = 100;
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BenchWorkload {
+    /// `SELECT c FROM sbtest1 WHERE id = ?` as a text query.
+    #[default]
+    PointSelect,
+    /// The point select as a prepared statement, executed with `COM_STMT_EXECUTE`.
+    PreparedExecute,
+    /// A sysbench `oltp_read_write` like transaction, reported per transaction.
+    Oltp,
+}
+
+#[derive(clap::Args, Clone, Debug, PartialEq, Eq)]
+pub struct BenchArgs {
+    /// The proxy address, or a backend address for a baseline run.
+    #[clap(long, value_name = "ADDR", default_value = "127.0.0.1:3310")]
+    pub target: String,
+    #[clap(long, default_value = "root")]
+    pub user: String,
+    #[clap(long, default_value = "")]
+    pub password: String,
+    #[clap(long, default_value = "sbtest")]
+    pub database: String,
+    /// Number of concurrent sessions.
+    #[clap(long, default_value_t = 16)]
+    pub concurrency: usize,
+    #[clap(long, default_value_t = 30)]
+    pub duration_secs: u64,
+    #[clap(long, value_enum, default_value_t = BenchWorkload::PointSelect)]
+    pub workload: BenchWorkload,
+    /// Rows in `sbtest1`, ids are picked uniformly from `1..=table_size`.
+    #[clap(long, default_value_t = 10000)]
+    pub table_size: i64,
+    /// Creates the database and fills `sbtest1` before the run.
+    #[clap(long, default_value_t = false)]
+    pub prepare: bool,
+}
+
+/// Creates `database.sbtest1` with `table_size` rows, the same schema as sysbench.
+pub async fn prepare_table(args: &BenchArgs) -> Result<(), Error> {
+    let mut conn = BenchConn::connect(&args.target, &args.user, &args.password, None).await?;
+    conn.query(&format!("CREATE DATABASE IF NOT EXISTS {}", args.database))
+        .await?;
+    conn.query(&format!("USE {}", args.database)).await?;
+    conn.query(&format!("DROP TABLE IF EXISTS {BENCH_TABLE}"))
+        .await?;
+    conn.query(&format!(
+        "CREATE TABLE {BENCH_TABLE} (id INT NOT NULL AUTO_INCREMENT, k INT NOT NULL DEFAULT 0, \
+         c CHAR(120) NOT NULL DEFAULT '', pad CHAR(60) NOT NULL DEFAULT '', PRIMARY KEY (id), \
+         KEY k_1 (k))"
+    ))
+    .await?;
+    let mut rng = StdRng::from_entropy();
+    for chunk in &(1..=args.table_size).chunks(PREPARE_BATCH_SIZE) {
+        let values = chunk
+            .map(|id| {
+                format!(
+                    "({id}, {}, '{}', '{}')",
+                    rng.gen_range(1..=args.table_size),
+                    random_chars(&mut rng, 119),
+                    random_chars(&mut rng, 59)
+                )
+            })
+            .join(",");
+        conn.query(&format!(
+            "INSERT INTO {BENCH_TABLE} (id, k, c, pad) VALUES {values}"
+        ))
+        .await?;
+    }
+    info!(
+        "ProxySrv bench prepared {}.{BENCH_TABLE} with {} rows",
+        args.database, args.table_size
+    );
+    conn.close().await
+}
+
+fn random_chars(rng: &mut StdRng, len: usize) -> String {
+    (0..len)
+        .map(|_| char::from(b'0' + rng.gen_range(0..10)))
+        .collect()
+}
+
+/// One benchmark session. `stmt_id` is only set for the prepared execute workload.
+struct BenchSession {
+    conn: BenchConn,
+    stmt_id: Option<u32>,
+}
+
+#[derive(Default)]
+struct WorkerStats {
+    latencies_us: Vec<u64>,
+    queries: u64,
+    errors: u64,
+}
+
+impl BenchSession {
+    async fn open(args: &BenchArgs) -> Result<Self, Error> {
+        let mut conn = BenchConn::connect(
+            &args.target,
+            &args.user,
+            &args.password,
+            Some(&args.database),
+        )
+        .await?;
+        let stmt_id = if args.workload == BenchWorkload::PreparedExecute {
+            Some(
+                conn.prepare(&format!("SELECT c FROM {BENCH_TABLE} WHERE id = ?"))
+                    .await?,
+            )
+        } else {
+            None
+        };
+        Ok(Self { conn, stmt_id })
+    }
+
+    /// Runs one event of the workload and returns the number of statements sent.
+    async fn run_event(
+        &mut self,
+        workload: BenchWorkload,
+        table_size: i64,
+        rng: &mut StdRng,
+    ) -> Result<u64, Error> {
+        let id = |rng: &mut StdRng| rng.gen_range(1..=table_size);
+        match (workload, self.stmt_id) {
+            (BenchWorkload::PreparedExecute, Some(stmt_id)) => {
+                self.conn.execute(stmt_id, &[id(rng)]).await?;
+                Ok(1)
+            }
+            (BenchWorkload::Oltp, _) => {
+                let mut statements = vec!["BEGIN".to_string()];
+                for _ in 0..OLTP_POINT_SELECTS {
+                    statements.push(format!(
+                        "SELECT c FROM {BENCH_TABLE} WHERE id = {}",
+                        id(rng)
+                    ));
+                }
+                let range =
+                    |start: i64| format!("id BETWEEN {start} AND {}", start + OLTP_RANGE_SIZE - 1);
+                statements.push(format!(
+                    "SELECT c FROM {BENCH_TABLE} WHERE {}",
+                    range(id(rng))
+                ));
+                statements.push(format!(
+                    "SELECT SUM(k) FROM {BENCH_TABLE} WHERE {}",
+                    range(id(rng))
+                ));
+                statements.push(format!(
+                    "SELECT c FROM {BENCH_TABLE} WHERE {} ORDER BY c",
+                    range(id(rng))
+                ));
+                statements.push(format!(
+                    "SELECT DISTINCT c FROM {BENCH_TABLE} WHERE {} ORDER BY c",
+                    range(id(rng))
+                ));
+                statements.push(format!(
+                    "UPDATE {BENCH_TABLE} SET k = k + 1 WHERE id = {}",
+                    id(rng)
+                ));
+                statements.push(format!(
+                    "UPDATE {BENCH_TABLE} SET c = '{}' WHERE id = {}",
+                    random_chars(rng, 119),
+                    id(rng)
+                ));
+                let delete_id = id(rng);
+                statements.push(format!("DELETE FROM {BENCH_TABLE} WHERE id = {delete_id}"));
+                statements.push(format!(
+                    "INSERT INTO {BENCH_TABLE} (id, k, c, pad) VALUES ({delete_id}, {}, '{}', '{}')",
+                    rng.gen_range(1..=table_size),
+                    random_chars(rng, 119),
+                    random_chars(rng, 59)
+                ));
+                statements.push("COMMIT".to_string());
+                for (sent, sql) in statements.iter().enumerate() {
+                    if let Err(e) = self.conn.query(sql).await {
+                        if e.kind() == ErrorKind::Other {
+                            // A server error, e.g. a deadlock, the session is still usable.
+                            let _ = self.conn.query("ROLLBACK").await;
+                        }
+                        warn!("ProxySrv bench oltp failed after {sent} statements {e:?}");
+                        return Err(e);
+                    }
+                }
+                Ok(statements.len() as u64)
+            }
+            _ => {
+                self.conn
+                    .query(&format!(
+                        "SELECT c FROM {BENCH_TABLE} WHERE id = {}",
+                        id(rng)
+                    ))
+                    .await?;
+                Ok(1)
+            }
+        }
+    }
+}
+
+async fn run_worker(args: BenchArgs, deadline: Instant) -> Result<WorkerStats, Error> {
+    let mut session = BenchSession::open(&args).await?;
+    let mut rng = StdRng::from_entropy();
+    let mut stats = WorkerStats::default();
+    while Instant::now() < deadline {
+        let start = Instant::now();
+        match session
+            .run_event(args.workload, args.table_size, &mut rng)
+            .await
+        {
+            Ok(queries) => {
+                stats.latencies_us.push(start.elapsed().as_micros() as u64);
+                stats.queries += queries;
+            }
+            // A server error leaves the session usable, anything else means it is gone.
+            Err(e) if e.kind() == ErrorKind::Other => stats.errors += 1,
+            Err(e) => {
+                warn!("ProxySrv bench session lost {e:?}, reconnecting");
+                stats.errors += 1;
+                session = BenchSession::open(&args).await?;
+            }
+        }
+    }
+    let _ = session.conn.close().await;
+    Ok(stats)
+}
+
+/// Opens `concurrency` sessions against the target and runs the workload for `duration_secs`.
+pub async fn run_bench(args: &BenchArgs) -> Result<BenchReport, Error> {
+    if args.prepare {
+        prepare_table(args).await?;
+    }
+    info!(
+        "ProxySrv bench {:?} against {} with {} sessions for {}s",
+        args.workload, args.target, args.concurrency, args.duration_secs
+    );
+    let start = Instant::now();
+    let deadline = start + Duration::from_secs(args.duration_secs);
+    let workers = (0..args.concurrency)
+        .map(|_| tokio::spawn(run_worker(args.clone(), deadline)))
+        .collect_vec();
+    let mut latencies_us = Vec::new();
+    let mut queries = 0;
+    let mut errors = 0;
+    for worker in workers {
+        let stats = worker.await.map_err(Error::other)??;
+        latencies_us.extend(stats.latencies_us);
+        queries += stats.queries;
+        errors += stats.errors;
+    }
+    Ok(BenchReport::new(
+        args.workload,
+        latencies_us,
+        queries,
+        errors,
+        start.elapsed(),
+    ))
+}
+
+fn percentile(sorted: &[u64], p: f64) -> u64 {
+    if sorted.is_empty() {
+        return 0;
+    }
+    let rank = (p / 100.0 * (sorted.len() - 1) as f64).round() as usize;
+    sorted[rank.min(sorted.len() - 1)]
+}
+
+/// Throughput and latency of a benchmark run. An event is one statement, or one transaction for
+/// the OLTP workload.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BenchReport {
+    pub workload: BenchWorkload,
+    pub events: u64,
+    pub queries: u64,
+    pub errors: u64,
+    pub elapsed: Duration,
+    pub min_us: u64,
+    pub avg_us: u64,
+    pub p50_us: u64,
+    pub p95_us: u64,
+    pub p99_us: u64,
+    pub max_us: u64,
+}
+
+impl BenchReport {
+    pub fn new(
+        workload: BenchWorkload,
+        mut latencies_us: Vec<u64>,
+        queries: u64,
+        errors: u64,
+        elapsed: Duration,
+    ) -> Self {
+        latencies_us.sort_unstable();
+        let events = latencies_us.len() as u64;
+        let avg_us = latencies_us
+            .iter()
+            .sum::<u64>()
+            .checked_div(events)
+            .unwrap_or_default();
+        Self {
+            workload,
+            events,
+            queries,
+            errors,
+            elapsed,
+            min_us: latencies_us.first().copied().unwrap_or_default(),
+            avg_us,
+            p50_us: percentile(&latencies_us, 50.0),
+            p95_us: percentile(&latencies_us, 95.0),
+            p99_us: percentile(&latencies_us, 99.0),
+            max_us: latencies_us.last().copied().unwrap_or_default(),
+        }
+    }
+
+    pub fn events_per_sec(&self) -> f64 {
+        self.events as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+
+    pub fn queries_per_sec(&self) -> f64 {
+        self.queries as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+impl Display for BenchReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let ms = |us: u64| us as f64 / 1000.0;
+        let event = if self.workload == BenchWorkload::Oltp {
+            "transactions"
+        } else {
+            "queries"
+        };
+        writeln!(f, "workload:            {:?}", self.workload)?;
+        writeln!(f, "elapsed:             {:.2}s", self.elapsed.as_secs_f64())?;
+        writeln!(
+            f,
+            "{event}:{:width$}{} ({:.2} per sec.)",
+            "",
+            self.events,
+            self.events_per_sec(),
+            width = 20 - event.len()
+        )?;
+        writeln!(
+            f,
+            "statements:          {} ({:.2} per sec.)",
+            self.queries,
+            self.queries_per_sec()
+        )?;
+        writeln!(f, "errors:              {}", self.errors)?;
+        writeln!(f, "latency (ms):")?;
+        writeln!(f, "    min:             {:.2}", ms(self.min_us))?;
+        writeln!(f, "    avg:             {:.2}", ms(self.avg_us))?;
+        writeln!(f, "    p50:             {:.2}", ms(self.p50_us))?;
+        writeln!(f, "    p95:             {:.2}", ms(self.p95_us))?;
+        writeln!(f, "    p99:             {:.2}", ms(self.p99_us))?;
+        write!(f, "    max:             {:.2}", ms(self.max_us))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bench::{BenchReport, BenchWorkload};
+    use std::time::Duration;
+
+    #[test]
+    pub fn test_bench_report() {
+        let latencies_us = (1..=100).rev().map(|v| v * 1000).collect::<Vec<u64>>();
+        let report = BenchReport::new(
+            BenchWorkload::PointSelect,
+            latencies_us,
+            100,
+            2,
+            Duration::from_secs(2),
+        );
+        assert_eq!(report.events, 100);
+        assert_eq!((report.min_us, report.max_us), (1000, 100_000));
+        assert_eq!(report.avg_us, 50_500);
+        assert_eq!(report.p50_us, 51_000);
+        assert_eq!(report.p95_us, 95_000);
+        assert_eq!(report.p99_us, 99_000);
+        assert_eq!(report.events_per_sec(), 50.0);
+        assert!(report.to_string().contains("queries:             100"));
+
+        let empty = BenchReport::new(BenchWorkload::Oltp, vec![], 0, 0, Duration::ZERO);
+        assert_eq!((empty.avg_us, empty.p99_us), (0, 0));
+    }
+}
@@ -0,0 +1,121 @@
+//! Builds and parses the usernames clients log in with when the proxy routes by tenant
+//! (`--router sync`): `<encoded tenant key>.<user>`. Applications use these instead of
+//! re-implementing the encoding, `my-proxy encode-tenant` does the same from a shell.
+
+use crate::backend::{encode_tenant_key, try_decode_tenant_key};
+use crate::prost::common_proto::TenantKey;
+
+use std::io::{Error, ErrorKind};
+
+/// Version of the username encoding the proxy decodes. Bumped on any change of the format, a
+/// username built for another version will not route to the same tenant.
+pub const USERNAME_ENCODING_VERSION: u32 = 1;
+
+/// Splits the encoded tenant key from the user. The proxy splits at the last one, so the user
+/// itself cannot contain it.
+pub const TENANT_USER_SEPARATOR: char = '.';
+
+/// The username that logs `user` in to `tenant` through the proxy.
+pub fn proxy_username(tenant: &TenantKey, user: &str) -> Result<String, Error> {
+    if user.is_empty() || user.contains(TENANT_USER_SEPARATOR) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("user {user:?} must be non-empty and not contain {TENANT_USER_SEPARATOR:?}"),
+        ));
+    }
+    let fields = [
+        ("region", &tenant.region),
+        ("available_zone", &tenant.available_zone),
+        ("namespace", &tenant.namespace),
+        ("cluster_name", &tenant.cluster_name),
+    ];
+    // The encoded key prefixes each field with its length as one byte.
+    if let Some((name, _)) = fields
+        .iter()
+        .find(|(_, value)| value.len() > u8::MAX as usize)
+    {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("tenant {name} is longer than {} bytes", u8::MAX),
+        ));
+    }
+    Ok(format!(
+        "{}{TENANT_USER_SEPARATOR}{user}",
+        encode_tenant_key(tenant)
+    ))
+}
+
+/// The tenant and user of a username built by [`proxy_username`], decoded as the proxy does.
+pub fn parse_proxy_username(username: &str) -> Result<(TenantKey, String), Error> {
+    let (tenant, user) = username
+        .rsplit_once(TENANT_USER_SEPARATOR)
+        .filter(|(_, user)| !user.is_empty())
+        .ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "{username:?} is not of the form <tenant key>{TENANT_USER_SEPARATOR}<user>"
+                ),
+            )
+        })?;
+    Ok((try_decode_tenant_key(tenant)?, user.to_string()))
+}
+
+#[derive(clap::Args, Clone, Debug, PartialEq, Eq)]
+pub struct EncodeTenantArgs {
+    #[clap(long, default_value = "")]
+    pub region: String,
+    #[clap(long, default_value = "")]
+    pub available_zone: String,
+    #[clap(long)]
+    pub namespace: String,
+    #[clap(long)]
+    pub cluster_name: String,
+    /// The backend user, printed after the encoded tenant key.
+    #[clap(long)]
+    pub user: String,
+}
+
+impl EncodeTenantArgs {
+    pub fn username(&self) -> Result<String, Error> {
+        let tenant = TenantKey {
+            region: self.region.clone(),
+            available_zone: self.available_zone.clone(),
+            namespace: self.namespace.clone(),
+            cluster_name: self.cluster_name.clone(),
+        };
+        proxy_username(&tenant, &self.user)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::client::{parse_proxy_username, proxy_username};
+    use crate::prost::common_proto::TenantKey;
+
+    #[test]
+    pub fn test_proxy_username() {
+        let tenant = TenantKey {
+            region: "ap-northeast-1".to_string(),
+            available_zone: "ap-northeast-1a".to_string(),
+            namespace: "test-proxy.system".to_string(),
+            cluster_name: "test-cluster-1".to_string(),
+        };
+        let username = proxy_username(&tenant, "app_rw").unwrap();
+        assert_eq!(
+            parse_proxy_username(&username).unwrap(),
+            (tenant.clone(), "app_rw".to_string())
+        );
+
+        assert!(proxy_username(&tenant, "app.rw").is_err());
+        assert!(proxy_username(&tenant, "").is_err());
+        let long = TenantKey {
+            namespace: "n".repeat(256),
+            ..tenant
+        };
+        assert!(proxy_username(&long, "app_rw").is_err());
+        assert!(parse_proxy_username("app_rw").is_err());
+        assert!(parse_proxy_username("00zz.app_rw").is_err());
+        assert!(parse_proxy_username("0000000f.app_rw").is_err());
+    }
+}
@@ -1,96 +1,220 @@
-use crate::prost::control_plane::UserCom;
+use crate::prost::control_plane::{ComCount, UserCom};
 
 use crate::prost::common_proto::TenantKey;
+use common::clock::{clock, Clock, Timestamp};
+use common::metrics::metric_def::{
+    PROXY_ACTIVE_USERS_DROPPED, PROXY_ACTIVE_USERS_EPOCH_BYTES, PROXY_ACTIVE_USERS_EPOCH_ENTRIES,
+};
+use common::metrics::{common_labels, counter_handle, gauge, Counter};
 use dashmap::DashMap;
+use std::collections::VecDeque;
 use std::hash::Hasher;
 use std::mem;
-use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
-use std::sync::Arc;
-use tokio::sync::mpsc;
-use tracing::warn;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tracing::debug;
 
-fn user_com_hash(user_com: &UserCom) -> u64 {
+fn user_com_hash(tenant: &TenantKey, user: &str) -> u64 {
     let mut hasher = twox_hash::xxh3::Hash64::default();
-    let user_ref = user_com.cluster.as_ref().unwrap();
-    hasher.write_str(&user_ref.region);
-    hasher.write_str(&user_ref.available_zone);
-    hasher.write_str(&user_ref.cluster_name);
-    hasher.write_str(&user_ref.namespace);
-    hasher.write_str(&user_com.user);
+    hasher.write_str(&tenant.region);
+    hasher.write_str(&tenant.available_zone);
+    hasher.write_str(&tenant.cluster_name);
+    hasher.write_str(&tenant.namespace);
+    hasher.write_str(user);
     hasher.finish()
 }
 
+/// Adds the commands `coms`, as `(com_code, com_ts)`, each counting for `weight` commands, to the
+/// activity of a user within the window, returns the bytes it grew by. The batches of the
+/// sessions of a user may arrive out of order, the first and last commands are those of the
+/// earliest and latest timestamps.
+fn aggregate_coms(user_com: &mut UserCom, coms: &[(u8, u64)], weight: u64) -> usize {
+    let mut grown = 0;
+    for &(com_code, com_ts) in coms {
+        let com = u32::from(com_code);
+        match user_com.com_counts.binary_search_by_key(&com, |c| c.com) {
+            Ok(idx) => user_com.com_counts[idx].count += weight,
+            Err(idx) => {
+                let count = weight;
+                user_com.com_counts.insert(idx, ComCount { com, count });
+                grown += mem::size_of::<ComCount>();
+            }
+        }
+        user_com.first_ts = user_com.first_ts.min(com_ts);
+        if com_ts >= user_com.com_ts {
+            user_com.com = vec![com_code];
+            user_com.com_ts = com_ts;
+        }
+    }
+    grown
+}
+
+/// The activity of the users within one window.
+struct ActivityEpoch {
+    id: u64,
+    users: DashMap<u64, UserCom>,
+    /// Bytes the users take.
+    bytes: AtomicU64,
+}
+
+impl ActivityEpoch {
+    fn new(id: u64) -> Self {
+        Self {
+            id,
+            users: DashMap::new(),
+            bytes: AtomicU64::new(0),
+        }
+    }
+}
+
+/// A window switched out by [`SwitchableMaps::switch`], no writer reaches it anymore.
+struct FrozenEpoch {
+    id: u64,
+    users: Vec<UserCom>,
+    bytes: u64,
+}
+
 /// SwitchableMaps are concurrency-safe data structures that support snapshot-like features.
+/// Writers update the active epoch under a read lock, so once [`Self::switch`] replaced it under
+/// the write lock, the old epoch is complete: every update either reached it before the switch
+/// or reaches the next epoch.
 pub struct SwitchableMaps {
-    switchable_table: [DashMap<u64, UserCom>; 2],
-    active_idx: AtomicUsize,
+    active: RwLock<ActivityEpoch>,
 }
 
 impl SwitchableMaps {
     fn new() -> Self {
         Self {
-            switchable_table: [DashMap::new(), DashMap::new()],
-            active_idx: AtomicUsize::new(0),
+            active: RwLock::new(ActivityEpoch::new(0)),
         }
     }
 
     pub fn active_len(&self) -> usize {
-        let curr_idx = self.active_idx.load(Ordering::Acquire);
-        self.switchable_table[curr_idx].len()
-    }
-
-    /// Switch the active data structure and return the iterator of the old active data structure.
-    /// The memory release depends on the call from caller, so clean_inactive must be called after the switch.
-    fn switch(&self) -> Option<dashmap::iter::Iter<u64, UserCom>> {
-        let curr_idx = self.active_idx.load(Ordering::Acquire);
-        let new_active_idx = (curr_idx + 1) % 2;
-        if self
-            .active_idx
-            .compare_exchange(
-                curr_idx,
-                new_active_idx,
-                Ordering::AcqRel,
-                Ordering::Acquire,
-            )
-            .is_ok()
-        {
-            Some(self.switchable_table[curr_idx].iter())
-        } else {
-            None
-        }
+        self.active.read().unwrap().users.len()
     }
 
-    fn clean_inactive(&self) {
-        let inactive_idx = (self.active_idx.load(Ordering::Acquire) + 1) % 2;
-        self.switchable_table[inactive_idx].clear();
+    /// Bytes the users of the active epoch take.
+    pub fn active_bytes(&self) -> u64 {
+        self.active.read().unwrap().bytes.load(Ordering::Acquire)
     }
 
-    fn put(&self, key: u64, value: UserCom) {
-        let active_idx = self.active_idx.load(Ordering::Acquire);
-        self.switchable_table[active_idx].insert(key, value);
+    /// Switches to a new epoch and returns the users of the old one, whose memory is released
+    /// with it.
+    fn switch(&self) -> FrozenEpoch {
+        let frozen = {
+            let mut active = self.active.write().unwrap();
+            let next = ActivityEpoch::new(active.id + 1);
+            mem::replace(&mut *active, next)
+        };
+        FrozenEpoch {
+            id: frozen.id,
+            bytes: frozen.bytes.into_inner(),
+            users: frozen
+                .users
+                .into_iter()
+                .map(|(_, user_com)| user_com)
+                .collect(),
+        }
+    }
+
+    /// Adds the commands of `batch` to the activity of its user, returns the bytes the active
+    /// epoch grew by.
+    fn aggregate(&self, batch: ActivityBatch) -> usize {
+        let key = user_com_hash(&batch.tenant, &batch.user);
+        let active = self.active.read().unwrap();
+        let mut grown = 0;
+        let mut user_com = active.users.entry(key).or_insert_with(|| {
+            grown += mem::size_of::<UserCom>();
+            UserCom {
+                cluster: Some(batch.tenant),
+                user: batch.user,
+                com: vec![],
+                com_ts: 0,
+                first_ts: u64::MAX,
+                com_counts: vec![],
+            }
+        });
+        grown += aggregate_coms(&mut user_com, &batch.coms, batch.weight);
+        active.bytes.fetch_add(grown as u64, Ordering::AcqRel);
+        grown
     }
 
     pub fn get(&self, key: u64) -> Option<UserCom> {
-        let active_idx = self.active_idx.load(Ordering::Acquire);
-        self.switchable_table[active_idx]
+        self.active
+            .read()
+            .unwrap()
+            .users
             .get(&key)
             .map(|v| v.clone())
     }
+}
 
-    pub fn iter_active(&self) -> dashmap::iter::Iter<u64, UserCom> {
-        let active_idx = self.active_idx.load(Ordering::Acquire);
-        self.switchable_table[active_idx].iter()
+/// Batches waiting for the aggregation task, the oldest are dropped beyond it.
+pub const ACTIVITY_QUEUE_CAPACITY: usize = 1024;
+/// Commands a session accumulates before it hands them over.
+pub const DEFAULT_ACTIVITY_BATCH_RECORDS: usize = 64;
+/// How long a session keeps commands before it hands them over.
+pub const DEFAULT_ACTIVITY_FLUSH_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The commands of one user, as `(com_code, com_ts)` in the order they ran.
+struct ActivityBatch {
+    tenant: TenantKey,
+    user: String,
+    coms: Vec<(u8, u64)>,
+    /// The commands each one stands for, see [`UserActivityWindow::with_sample_every`].
+    weight: u64,
+}
+
+/// `BatchQueue` is a bounded queue that drops its oldest batch when full, so a stalled
+/// aggregation task never blocks or grows without bound on the command path.
+struct BatchQueue {
+    capacity: usize,
+    batches: Mutex<VecDeque<ActivityBatch>>,
+    ready: Notify,
+    dropped: Counter,
+}
+
+impl BatchQueue {
+    fn push(&self, batch: ActivityBatch) {
+        {
+            let mut batches = self.batches.lock().unwrap();
+            if batches.len() >= self.capacity {
+                if let Some(oldest) = batches.pop_front() {
+                    self.dropped.increment(oldest.coms.len() as u64);
+                }
+            }
+            batches.push_back(batch);
+        }
+        self.ready.notify_one();
+    }
+
+    async fn take_all(&self) -> VecDeque<ActivityBatch> {
+        loop {
+            {
+                let mut batches = self.batches.lock().unwrap();
+                if !batches.is_empty() {
+                    return mem::take(&mut *batches);
+                }
+            }
+            self.ready.notified().await;
+        }
     }
 }
 
 /// The active users are saved for a certain time window, within which the data is growing,
 /// and once the control plane has successfully crawled the data, the memory is freed. By design,
-/// freeze calls are low cost, except for CAS of atomic variables whose purpose is to have no blocks in the write path.
+/// freeze calls are low cost, they only hold off the aggregation while the epochs are swapped.
+/// Within the window the commands of a user are aggregated into one [`UserCom`]: its counts by
+/// command code, and its first and last commands, so the control plane can tell activity rates.
 pub struct UserActivityWindow {
     data: Arc<SwitchableMaps>,
-    size: Arc<AtomicU64>,
     count: Arc<AtomicU64>,
-    buf: mpsc::UnboundedSender<(TenantKey, String, u8, u64)>,
+    queue: Arc<BatchQueue>,
+    batch_records: usize,
+    flush_interval: Duration,
+    sample_every: u64,
 }
 
 impl Default for UserActivityWindow {
@@ -101,64 +225,92 @@ impl Default for UserActivityWindow {
 
 impl UserActivityWindow {
     pub fn new() -> Self {
-        let (tx, mut rx) = mpsc::unbounded_channel();
+        Self::with_batching(
+            DEFAULT_ACTIVITY_BATCH_RECORDS,
+            DEFAULT_ACTIVITY_FLUSH_INTERVAL,
+        )
+    }
+
+    pub fn with_batching(batch_records: usize, flush_interval: Duration) -> Self {
+        let queue = Arc::new(BatchQueue {
+            capacity: ACTIVITY_QUEUE_CAPACITY,
+            batches: Mutex::new(VecDeque::new()),
+            ready: Notify::new(),
+            dropped: counter_handle(PROXY_ACTIVE_USERS_DROPPED, common_labels()),
+        });
         let active_pkt = Arc::new(SwitchableMaps::new());
-        let size = Arc::new(AtomicU64::new(0));
         let count = Arc::new(AtomicU64::new(0));
 
-        let moved_size = Arc::clone(&size);
         let moved_count = Arc::clone(&count);
         let moved_active_pkt = Arc::clone(&active_pkt);
+        let moved_queue = Arc::clone(&queue);
         tokio::spawn(async move {
             loop {
-                let active_user_triple = rx.recv().await;
-                if let Some((cluster, user, com_code, ts)) = active_user_triple {
-                    let user_com = &UserCom {
-                        cluster: Some(cluster),
-                        user,
-                        com: vec![com_code],
-                        com_ts: ts,
-                    };
-                    let sized = mem::size_of_val(user_com);
-                    let user_key = user_com_hash(user_com);
-                    moved_active_pkt.put(user_key, user_com.clone());
-                    moved_count.fetch_add(1, Ordering::AcqRel);
-                    moved_size.fetch_add(sized as u64, Ordering::AcqRel);
+                for batch in moved_queue.take_all().await {
+                    if batch.coms.is_empty() {
+                        continue;
+                    }
+                    let coms = batch.coms.len() as u64;
+                    moved_active_pkt.aggregate(batch);
+                    moved_count.fetch_add(coms, Ordering::AcqRel);
                 }
             }
         });
         Self {
             data: active_pkt,
-            size,
             count,
-            buf: tx,
+            queue,
+            batch_records: batch_records.max(1),
+            flush_interval,
+            sample_every: 1,
         }
     }
 
+    /// Sessions record one of every `sample_every` commands, each counting for `sample_every`
+    /// commands, to bound the overhead on the command path. The first command of a session is
+    /// always recorded, so every active user is reported.
+    pub fn with_sample_every(mut self, sample_every: u32) -> Self {
+        self.sample_every = u64::from(sample_every.max(1));
+        self
+    }
+
     pub fn count(&self) -> u64 {
         self.count.load(Ordering::Acquire)
     }
 
+    /// Bytes of the activity aggregated within the current window.
     pub fn size(&self) -> u64 {
-        self.size.load(Ordering::Acquire)
+        self.data.active_bytes()
     }
 
+    /// Batches not aggregated yet.
+    pub fn pending_batches(&self) -> usize {
+        self.queue.batches.lock().unwrap().len()
+    }
+
+    /// Switches to a new window and returns the aggregated activity of the users of the old one.
     pub fn freeze(&self) -> Vec<UserCom> {
-        let old_data = self.data.switch();
-        if let Some(it) = old_data {
-            let mut frozen_data = Vec::new();
-            for entry in it {
-                let bytes = entry.value();
-                frozen_data.push(bytes.clone());
-            }
-            self.data.clean_inactive();
-            self.size.store(0, Ordering::Release);
-            frozen_data
-        } else {
-            Vec::new()
-        }
+        let frozen = self.data.switch();
+        debug!(
+            "ProxySrv active users epoch {} frozen with {} users, {} bytes",
+            frozen.id,
+            frozen.users.len(),
+            frozen.bytes
+        );
+        gauge(
+            PROXY_ACTIVE_USERS_EPOCH_ENTRIES,
+            frozen.users.len() as f64,
+            Some(common_labels()),
+        );
+        gauge(
+            PROXY_ACTIVE_USERS_EPOCH_BYTES,
+            frozen.bytes as f64,
+            Some(common_labels()),
+        );
+        frozen.users
     }
 
+    /// Reports a single command, sessions report theirs through an [`ActivityBatcher`].
     pub fn add_active_users(
         &self,
         cluster: TenantKey,
@@ -166,20 +318,310 @@ impl UserActivityWindow {
         com_code: u8,
         com_ts: u64,
     ) {
-        let tx = &self.buf;
-        // TODO avoid user clone.
-        let send_rs = tx.send((cluster, active_user, com_code, com_ts));
-        if send_rs.is_err() {
-            warn!("add_active_users failed {:?}", send_rs.err().unwrap());
+        self.queue.push(ActivityBatch {
+            tenant: cluster,
+            user: active_user,
+            coms: vec![(com_code, com_ts)],
+            weight: 1,
+        });
+    }
+}
+
+/// `ActivityBatcher` accumulates the commands of one session and hands them to the
+/// [`UserActivityWindow`] every `batch_records` commands or `flush_interval`, instead of a
+/// message per command. Pending commands are handed over when the batcher is dropped.
+pub struct ActivityBatcher {
+    window: Arc<UserActivityWindow>,
+    tenant: TenantKey,
+    user: String,
+    coms: Vec<(u8, u64)>,
+    /// Commands the session sent, recorded or not.
+    seen: u64,
+    clock: Clock,
+    first_at: Timestamp,
+}
+
+impl ActivityBatcher {
+    pub fn new(window: Arc<UserActivityWindow>, tenant: TenantKey, user: String) -> Self {
+        Self::with_clock(window, tenant, user, clock().clone())
+    }
+
+    pub fn with_clock(
+        window: Arc<UserActivityWindow>,
+        tenant: TenantKey,
+        user: String,
+        clock: Clock,
+    ) -> Self {
+        Self {
+            coms: Vec::with_capacity(window.batch_records),
+            window,
+            tenant,
+            user,
+            seen: 0,
+            first_at: clock.coarse_now(),
+            clock,
+        }
+    }
+
+    pub fn record(&mut self, com_code: u8) {
+        let sampled = self.seen % self.window.sample_every == 0;
+        self.seen += 1;
+        if !sampled {
+            return;
+        }
+        if self.coms.is_empty() {
+            self.first_at = self.clock.coarse_now();
+        }
+        self.coms.push((com_code, self.clock.unix_millis()));
+        if self.coms.len() >= self.window.batch_records
+            || self.clock.coarse_elapsed(self.first_at) >= self.window.flush_interval
+        {
+            self.flush();
+        }
+    }
+
+    pub fn flush(&mut self) {
+        if self.coms.is_empty() {
+            return;
+        }
+        let coms = mem::replace(
+            &mut self.coms,
+            Vec::with_capacity(self.window.batch_records),
+        );
+        self.window.queue.push(ActivityBatch {
+            tenant: self.tenant.clone(),
+            user: self.user.clone(),
+            coms,
+            weight: self.window.sample_every,
+        });
+    }
+
+    /// Resolves once the pending commands are due, never if there are none. Lets an idle session
+    /// hand over its last commands without waiting for the next one.
+    pub async fn flush_due(&self) {
+        if self.coms.is_empty() {
+            std::future::pending::<()>().await;
         }
+        let waited = self.clock.coarse_elapsed(self.first_at);
+        tokio::time::sleep(self.window.flush_interval.saturating_sub(waited)).await;
+    }
+}
+
+impl Drop for ActivityBatcher {
+    fn drop(&mut self) {
+        self.flush();
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::cp::active_users::UserActivityWindow;
+    use crate::backend::test_tenant_key;
+    use crate::cp::active_users::{
+        aggregate_coms, ActivityBatch, ActivityBatcher, BatchQueue, SwitchableMaps,
+        UserActivityWindow,
+    };
     use crate::prost::common_proto::TenantKey;
-    use std::sync::Arc;
+    use crate::prost::control_plane::UserCom;
+    use common::clock::Clock;
+    use common::metrics::{common_labels, counter_handle};
+    use std::collections::VecDeque;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use tokio::sync::Notify;
+
+    async fn wait_count(window: &UserActivityWindow, count: u64) {
+        while window.count() < count {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+    }
+
+    #[tokio::test]
+    pub async fn test_activity_batcher() {
+        let window = Arc::new(UserActivityWindow::with_batching(
+            3,
+            Duration::from_secs(3600),
+        ));
+        let mut batcher =
+            ActivityBatcher::new(Arc::clone(&window), test_tenant_key(), "root".to_string());
+        batcher.record(3);
+        batcher.record(3);
+        tokio::task::yield_now().await;
+        assert_eq!(window.count(), 0);
+        batcher.record(22);
+        wait_count(&window, 3).await;
+        batcher.record(3);
+        drop(batcher);
+        wait_count(&window, 4).await;
+        let frozen = window.freeze();
+        assert_eq!(frozen.len(), 1);
+        assert_eq!(frozen[0].com, vec![3]);
+        let counts = frozen[0]
+            .com_counts
+            .iter()
+            .map(|c| (c.com, c.count))
+            .collect::<Vec<_>>();
+        assert_eq!(counts, vec![(3, 3), (22, 1)]);
+        assert!(frozen[0].first_ts <= frozen[0].com_ts);
+        assert!(window.freeze().is_empty());
+
+        let (clock, source) = Clock::mock();
+        source.set_unix_millis(1_700_000_000_000);
+        let window = Arc::new(UserActivityWindow::with_batching(
+            100,
+            Duration::from_secs(5),
+        ));
+        let mut batcher = ActivityBatcher::with_clock(
+            Arc::clone(&window),
+            test_tenant_key(),
+            "root".to_string(),
+            clock,
+        );
+        batcher.record(3);
+        source.advance(Duration::from_secs(5));
+        // Due right away, the interval passed on the clock of the batcher.
+        batcher.flush_due().await;
+        batcher.record(3);
+        wait_count(&window, 2).await;
+        assert!(batcher.coms.is_empty());
+
+        let queue = BatchQueue {
+            capacity: 1,
+            batches: Mutex::new(VecDeque::new()),
+            ready: Notify::new(),
+            dropped: counter_handle("test_active_users_dropped", common_labels()),
+        };
+        for user in ["u1", "u2"] {
+            queue.push(ActivityBatch {
+                tenant: test_tenant_key(),
+                user: user.to_string(),
+                coms: vec![(3, 0)],
+                weight: 1,
+            });
+        }
+        let batches = queue.take_all().await;
+        assert_eq!(batches.len(), 1);
+        assert_eq!(batches[0].user, "u2");
+    }
+
+    #[tokio::test]
+    pub async fn test_activity_sampling() {
+        // One of every 3 commands is recorded, counting for 3.
+        let window = Arc::new(
+            UserActivityWindow::with_batching(100, Duration::from_secs(3600)).with_sample_every(3),
+        );
+        let mut batcher =
+            ActivityBatcher::new(Arc::clone(&window), test_tenant_key(), "root".to_string());
+        (0..7).for_each(|_| batcher.record(3));
+        drop(batcher);
+        wait_count(&window, 3).await;
+        let frozen = window.freeze();
+        assert_eq!(frozen[0].com_counts[0].count, 9);
+
+        // The first command of every session is recorded, a user sending a single command is
+        // still reported.
+        let mut batcher =
+            ActivityBatcher::new(Arc::clone(&window), test_tenant_key(), "one".to_string());
+        batcher.record(22);
+        // The commands in between are not, whatever their code.
+        [3, 3, 14, 3]
+            .into_iter()
+            .for_each(|com| batcher.record(com));
+        drop(batcher);
+        wait_count(&window, 5).await;
+        let frozen = window.freeze();
+        assert_eq!(frozen[0].user, "one");
+        let counts = frozen[0]
+            .com_counts
+            .iter()
+            .map(|c| (c.com, c.count))
+            .collect::<Vec<_>>();
+        assert_eq!(counts, vec![(14, 3), (22, 3)]);
+
+        // 0 samples every command, the way 1 does.
+        let window = Arc::new(
+            UserActivityWindow::with_batching(100, Duration::from_secs(3600)).with_sample_every(0),
+        );
+        let mut batcher =
+            ActivityBatcher::new(Arc::clone(&window), test_tenant_key(), "root".to_string());
+        (0..4).for_each(|_| batcher.record(3));
+        drop(batcher);
+        wait_count(&window, 4).await;
+        assert_eq!(window.freeze()[0].com_counts[0].count, 4);
+    }
+
+    #[test]
+    pub fn test_aggregate_coms() {
+        let mut user_com = UserCom {
+            first_ts: u64::MAX,
+            ..Default::default()
+        };
+        assert!(aggregate_coms(&mut user_com, &[(3, 20), (22, 30), (3, 40)], 1) > 0);
+        // A batch of another session of the user, older than the last command.
+        assert_eq!(aggregate_coms(&mut user_com, &[(3, 10), (22, 35)], 1), 0);
+        assert_eq!((user_com.first_ts, user_com.com_ts), (10, 40));
+        assert_eq!(user_com.com, vec![3]);
+        let counts = user_com
+            .com_counts
+            .iter()
+            .map(|c| (c.com, c.count))
+            .collect::<Vec<_>>();
+        assert_eq!(counts, vec![(3, 3), (22, 2)]);
+
+        // Each sampled command counts for the commands it stands for.
+        assert!(aggregate_coms(&mut user_com, &[(14, 50), (3, 60)], 4) > 0);
+        let counts = user_com
+            .com_counts
+            .iter()
+            .map(|c| (c.com, c.count))
+            .collect::<Vec<_>>();
+        assert_eq!(counts, vec![(3, 7), (14, 4), (22, 2)]);
+    }
+
+    #[test]
+    pub fn test_switch_under_writes() {
+        let maps = Arc::new(SwitchableMaps::new());
+        let (writers, batches) = (4, 5000);
+        let handles = (0..writers)
+            .map(|writer| {
+                let maps = Arc::clone(&maps);
+                std::thread::spawn(move || {
+                    for i in 0..batches {
+                        maps.aggregate(ActivityBatch {
+                            tenant: test_tenant_key(),
+                            user: format!("user-{writer}-{}", i % 50),
+                            coms: vec![(3, i)],
+                            weight: 1,
+                        });
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        let count = |users: &[UserCom]| -> u64 {
+            users
+                .iter()
+                .flat_map(|user_com| user_com.com_counts.iter())
+                .map(|c| c.count)
+                .sum()
+        };
+        let mut counted = 0;
+        let mut epochs = 0;
+        while handles.iter().any(|handle| !handle.is_finished()) {
+            let frozen = maps.switch();
+            assert_eq!(frozen.id, epochs);
+            assert!(frozen.users.len() <= writers * 50);
+            counted += count(&frozen.users);
+            epochs += 1;
+        }
+        handles
+            .into_iter()
+            .for_each(|handle| handle.join().unwrap());
+        let frozen = maps.switch();
+        counted += count(&frozen.users);
+        // No command is lost or counted twice across the switches.
+        assert_eq!(counted, writers as u64 * batches);
+        assert_eq!(maps.active_len(), 0);
+        assert_eq!(maps.active_bytes(), 0);
+    }
 
     #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
     pub async fn test_active_user_com() {
@@ -1,8 +1,12 @@
+use crate::audit::audit_log;
 use crate::cp::active_users::UserActivityWindow;
 use crate::prost::control_plane;
 use crate::prost::control_plane::control_plane_response::PacketData;
 use crate::prost::control_plane::control_plane_service_server::*;
-use crate::prost::control_plane::{ActiveUsers, ControlPlaneResponse, PacketHeader};
+use crate::prost::control_plane::{
+    ActiveUsers, AuditEvents, BillingRecords, ControlPlaneResponse, PacketHeader,
+};
+use crate::server::billing::billing;
 use itertools::Itertools;
 use std::pin::Pin;
 use std::sync::Arc;
@@ -23,6 +27,114 @@ impl ControlPlaneServiceImpl {
     }
 }
 
+type ResponseSender = tokio::sync::mpsc::Sender<Result<ControlPlaneResponse, Status>>;
+
+/// Sends the users active since the last pull, returns `false` once the stream is closed.
+async fn send_active_users(active_users: &UserActivityWindow, tx: &ResponseSender) -> bool {
+    let active_users = active_users.freeze();
+    if active_users.is_empty() {
+        debug!("No active users found.");
+        let none_active_users = ControlPlaneResponse {
+            header: Some(PacketHeader {
+                packet_type: control_plane::PacketType::ActiveUser as i32,
+                package_count: 0,
+                size_pre_package: 0,
+                size: 0,
+            }),
+            packet_data: None,
+        };
+        if let Err(e) = tx.send(Ok(none_active_users)).await {
+            warn!("Failed to send active user response: {:?}", e);
+            return false;
+        }
+        return true;
+    }
+    let total_size = active_users.len();
+    let grouped_users = active_users
+        .chunks(DEFAULT_CHUNK_SIZE)
+        .map(|chunk| chunk.to_vec())
+        .collect_vec();
+
+    let mut header = PacketHeader {
+        packet_type: control_plane::PacketType::ActiveUser as i32,
+        package_count: total_size as u32,
+        size_pre_package: DEFAULT_CHUNK_SIZE as u32,
+        size: 0,
+    };
+    for batch in grouped_users {
+        header.size = batch.len() as u32;
+        let response = ControlPlaneResponse {
+            header: Some(header),
+            packet_data: Some(PacketData::ActiveUser(ActiveUsers {
+                active_user_com: batch,
+            })),
+        };
+        if let Err(send_err) = tx.send(Ok(response)).await {
+            warn!("Failed to send active user response: {:?}", send_err);
+            return false;
+        }
+    }
+    true
+}
+
+/// Sends the billing records of the sessions ended since the last pull, nothing is sent if
+/// there are none.
+async fn send_billing_records(tx: &ResponseSender) -> bool {
+    let Some(exporter) = billing() else {
+        return true;
+    };
+    let records = exporter.drain();
+    let total_size = records.len();
+    let mut header = PacketHeader {
+        packet_type: control_plane::PacketType::Billing as i32,
+        package_count: total_size as u32,
+        size_pre_package: DEFAULT_CHUNK_SIZE as u32,
+        size: 0,
+    };
+    for batch in records.chunks(DEFAULT_CHUNK_SIZE) {
+        header.size = batch.len() as u32;
+        let response = ControlPlaneResponse {
+            header: Some(header),
+            packet_data: Some(PacketData::Billing(BillingRecords {
+                records: batch.to_vec(),
+            })),
+        };
+        if let Err(send_err) = tx.send(Ok(response)).await {
+            warn!("Failed to send billing response: {:?}", send_err);
+            return false;
+        }
+    }
+    true
+}
+
+/// Sends the audit events recorded since the last pull, nothing is sent if there are none.
+async fn send_audit_events(tx: &ResponseSender) -> bool {
+    let Some(audit_log) = audit_log() else {
+        return true;
+    };
+    let events = audit_log.drain();
+    let mut header = PacketHeader {
+        packet_type: control_plane::PacketType::Audit as i32,
+        package_count: events.len() as u32,
+        size_pre_package: DEFAULT_CHUNK_SIZE as u32,
+        size: 0,
+    };
+    for batch in events.chunks(DEFAULT_CHUNK_SIZE) {
+        header.size = batch.len() as u32;
+        let response = ControlPlaneResponse {
+            header: Some(header),
+            packet_data: Some(PacketData::Audit(AuditEvents {
+                events: batch.to_vec(),
+            })),
+        };
+        if let Err(send_err) = tx.send(Ok(response)).await {
+            warn!("Failed to send audit response: {:?}", send_err);
+            return false;
+        }
+    }
+    true
+}
+
 #[async_trait::async_trait]
 impl ControlPlaneService for ControlPlaneServiceImpl {
     type ActiveUsersStream =
@@ -37,49 +149,11 @@ impl ControlPlaneService for ControlPlaneServiceImpl {
         let (tx, rx) = tokio::sync::mpsc::channel(DEFAULT_CHUNK_SIZE);
         tokio::task::spawn(async move {
             while (stream_request.next().await).is_some() {
-                let active_users = active_user_arcs.freeze();
-                if active_users.is_empty() {
-                    debug!("No active users found.");
-                    let none_active_users = ControlPlaneResponse {
-                        header: Some(PacketHeader {
-                            packet_type: control_plane::PacketType::ActiveUser as i32,
-                            package_count: 0,
-                            size_pre_package: 0,
-                            size: 0,
-                        }),
-                        packet_data: None,
-                    };
-                    if let Err(e) = tx.send(Ok(none_active_users)).await {
-                        warn!("Failed to send active user response: {:?}", e);
-                        break;
-                    } else {
-                        continue;
-                    }
-                }
-                let total_size = active_users.len();
-                let grouped_users = active_users
-                    .chunks(DEFAULT_CHUNK_SIZE)
-                    .map(|chunk| chunk.to_vec())
-                    .collect_vec();
-
-                let mut header = PacketHeader {
-                    packet_type: control_plane::PacketType::ActiveUser as i32,
-                    package_count: total_size as u32,
-                    size_pre_package: DEFAULT_CHUNK_SIZE as u32,
-                    size: 0,
-                };
-                for batch in grouped_users {
-                    header.size = batch.len() as u32;
-                    let response = ControlPlaneResponse {
-                        header: Some(header),
-                        packet_data: Some(PacketData::ActiveUser(ActiveUsers {
-                            active_user_com: batch,
-                        })),
-                    };
-                    if let Err(send_err) = tx.send(Ok(response)).await {
-                        warn!("Failed to send active user response: {:?}", send_err);
-                        break;
-                    }
+                if !send_active_users(&active_user_arcs, &tx).await
+                    || !send_billing_records(&tx).await
+                    || !send_audit_events(&tx).await
+                {
+                    break;
                 }
             }
         });
@@ -0,0 +1,74 @@
+use crate::backend::backend_mgr::BackendMgr;
+use crate::prost::control_plane::control_plane_service_server::SERVICE_NAME;
+use crate::server::readiness::Readiness;
+
+use common::ShutdownMessage;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch::Receiver;
+use tonic_health::server::HealthReporter;
+use tonic_health::ServingStatus;
+use tracing::info;
+
+/// How often the readiness of the proxy is checked for the health service.
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+fn serving_status(readiness: &Readiness) -> ServingStatus {
+    if readiness.is_ready() {
+        ServingStatus::Serving
+    } else {
+        ServingStatus::NotServing
+    }
+}
+
+/// Keeps the grpc.health.v1 status of the proxy, the overall one and the one of the
+/// ControlPlaneService, in line with its readiness until shutdown. The watchers of the health
+/// service are only notified of a change.
+pub async fn report_health(
+    mut reporter: HealthReporter,
+    backend_mgr: Arc<BackendMgr>,
+    mut shutdown_rx: Box<Receiver<ShutdownMessage>>,
+) {
+    let mut interval = tokio::time::interval(HEALTH_CHECK_INTERVAL);
+    let mut reported = None;
+    loop {
+        tokio::select! {
+            _ = shutdown_rx.changed() => {
+                reporter.set_service_status("", ServingStatus::NotServing).await;
+                reporter.set_service_status(SERVICE_NAME, ServingStatus::NotServing).await;
+                return;
+            }
+            _ = interval.tick() => {
+                let readiness = Readiness::of(&backend_mgr).await;
+                let status = serving_status(&readiness);
+                if reported != Some(status) {
+                    info!(
+                        "ControlPlaneService health {status:?} {}",
+                        readiness.not_ready_reason().unwrap_or("ready")
+                    );
+                    reporter.set_service_status("", status).await;
+                    reporter.set_service_status(SERVICE_NAME, status).await;
+                    reported = Some(status);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cp::health::serving_status;
+    use crate::server::readiness::Readiness;
+    use tonic_health::ServingStatus;
+
+    #[test]
+    pub fn test_serving_status() {
+        let mut readiness = Readiness::default();
+        assert_eq!(serving_status(&readiness), ServingStatus::NotServing);
+        readiness.router_initialized = true;
+        readiness.ready_backends = 1;
+        assert_eq!(serving_status(&readiness), ServingStatus::Serving);
+        readiness.cp_connected = Some(false);
+        assert_eq!(serving_status(&readiness), ServingStatus::NotServing);
+    }
+}
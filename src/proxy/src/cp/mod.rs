@@ -1,3 +1,4 @@
+use crate::backend::backend_mgr::BackendMgr;
 use crate::cp::active_users::UserActivityWindow;
 use common::ShutdownMessage;
 use std::sync::Arc;
@@ -7,19 +8,30 @@ use tracing::{error, info};
 
 pub mod active_users;
 mod cp_target;
+mod health;
 // pub mod prost;
 
+/// Serves the ControlPlaneService, along with the grpc.health.v1 service reporting whether the
+/// proxy is ready, so the control plane only routes clients to ready proxies.
 pub async fn start_cp_target_reporter(
     active_users: Arc<UserActivityWindow>,
+    backend_mgr: Arc<BackendMgr>,
     cp_addr: impl Into<String>,
     mut shutdown_rx: Box<Receiver<ShutdownMessage>>,
 ) {
     let cp_addr = cp_addr.into();
     let cp_srv_impl = crate::cp::cp_target::ControlPlaneServiceImpl::new(active_users);
     let cp_addr_socket = cp_addr.parse().unwrap();
+    let (health_reporter, health_service) = tonic_health::server::health_reporter();
+    tokio::task::spawn(health::report_health(
+        health_reporter,
+        backend_mgr,
+        shutdown_rx.clone(),
+    ));
     tokio::task::spawn(async move {
         tonic::transport::Server::builder()
             .timeout(Duration::from_secs(5))
+            .add_service(health_service)
             .add_service(crate::prost::control_plane::control_plane_service_server::ControlPlaneServiceServer::new(cp_srv_impl))
             .serve_with_shutdown(cp_addr_socket, async move {
                 tokio::select! {
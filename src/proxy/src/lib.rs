@@ -7,8 +7,12 @@
 #![feature(coroutines)]
 #![feature(thread_id_value)]
 
+pub mod audit;
 pub mod backend;
+pub mod bench;
+pub mod client;
 pub mod cp;
 pub mod prost;
 pub mod protocol;
 pub mod server;
+pub mod testing;
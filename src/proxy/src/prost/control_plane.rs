@@ -12,6 +12,17 @@ pub struct PacketHeader {
     #[prost(uint32, tag = "4")]
     pub size: u32,
 }
+/// The commands of one command code a user ran within the window.
+#[allow(non_camel_case_types)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, Copy, PartialEq, ::prost::Message)]
+pub struct ComCount {
+    #[prost(uint32, tag = "1")]
+    pub com: u32,
+    #[prost(uint64, tag = "2")]
+    pub count: u64,
+}
+/// The activity of a user within the window.
 #[allow(non_camel_case_types)]
 #[allow(clippy::derive_partial_eq_without_eq)]
 #[derive(Clone, PartialEq, ::prost::Message)]
@@ -20,10 +31,17 @@ pub struct UserCom {
     pub cluster: ::core::option::Option<super::common_proto::TenantKey>,
     #[prost(string, tag = "2")]
     pub user: ::prost::alloc::string::String,
+    /// The last command and its unix time in milliseconds.
     #[prost(bytes = "vec", tag = "3")]
     pub com: ::prost::alloc::vec::Vec<u8>,
     #[prost(uint64, tag = "4")]
     pub com_ts: u64,
+    /// Unix time in milliseconds of the first command.
+    #[prost(uint64, tag = "5")]
+    pub first_ts: u64,
+    /// The commands, by command code in ascending order.
+    #[prost(message, repeated, tag = "6")]
+    pub com_counts: ::prost::alloc::vec::Vec<ComCount>,
 }
 #[allow(non_camel_case_types)]
 #[allow(clippy::derive_partial_eq_without_eq)]
@@ -31,7 +49,7 @@ pub struct UserCom {
 pub struct ControlPlaneResponse {
     #[prost(message, optional, tag = "1")]
     pub header: ::core::option::Option<PacketHeader>,
-    #[prost(oneof = "control_plane_response::PacketData", tags = "3")]
+    #[prost(oneof = "control_plane_response::PacketData", tags = "3, 4, 5")]
     pub packet_data: ::core::option::Option<control_plane_response::PacketData>,
 }
 /// Nested message and enum types in `ControlPlaneResponse`.
@@ -42,6 +60,10 @@ pub mod control_plane_response {
     pub enum PacketData {
         #[prost(message, tag = "3")]
         ActiveUser(super::ActiveUsers),
+        #[prost(message, tag = "4")]
+        Billing(super::BillingRecords),
+        #[prost(message, tag = "5")]
+        Audit(super::AuditEvents),
     }
 }
 #[allow(non_camel_case_types)]
@@ -51,12 +73,86 @@ pub struct ActiveUsers {
     #[prost(message, repeated, tag = "1")]
     pub active_user_com: ::prost::alloc::vec::Vec<UserCom>,
 }
+/// Usage of one client session, reported when the session ends.
+#[allow(non_camel_case_types)]
+#[derive(serde::Serialize, serde::Deserialize)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct BillingRecord {
+    #[prost(message, optional, tag = "1")]
+    pub cluster: ::core::option::Option<super::common_proto::TenantKey>,
+    #[prost(string, tag = "2")]
+    pub user: ::prost::alloc::string::String,
+    #[prost(uint64, tag = "3")]
+    pub session_id: u64,
+    /// Unix time in milliseconds.
+    #[prost(uint64, tag = "4")]
+    pub start_ts: u64,
+    #[prost(uint64, tag = "5")]
+    pub end_ts: u64,
+    #[prost(uint64, tag = "6")]
+    pub queries: u64,
+    #[prost(uint64, tag = "7")]
+    pub bytes_in: u64,
+    #[prost(uint64, tag = "8")]
+    pub bytes_out: u64,
+    #[prost(uint64, tag = "9")]
+    pub wall_time_us: u64,
+    #[prost(uint64, tag = "10")]
+    pub backend_time_us: u64,
+}
+#[allow(non_camel_case_types)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct BillingRecords {
+    #[prost(message, repeated, tag = "1")]
+    pub records: ::prost::alloc::vec::Vec<BillingRecord>,
+}
+/// An authentication or connection event of a client, for the audit log.
+#[allow(non_camel_case_types)]
+#[derive(serde::Serialize, serde::Deserialize)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct AuditEvent {
+    /// Unix time in milliseconds.
+    #[prost(uint64, tag = "1")]
+    pub ts: u64,
+    /// connection_accepted, auth_success, auth_failure, backend_selected or connection_closed.
+    #[prost(string, tag = "2")]
+    pub event: ::prost::alloc::string::String,
+    #[prost(uint64, tag = "3")]
+    pub conn_id: u64,
+    #[prost(string, tag = "4")]
+    pub client_addr: ::prost::alloc::string::String,
+    #[prost(message, optional, tag = "5")]
+    pub cluster: ::core::option::Option<super::common_proto::TenantKey>,
+    #[prost(string, tag = "6")]
+    pub user: ::prost::alloc::string::String,
+    #[prost(string, tag = "7")]
+    pub backend_addr: ::prost::alloc::string::String,
+    /// Why the authentication failed or the connection closed.
+    #[prost(string, tag = "8")]
+    pub reason: ::prost::alloc::string::String,
+    #[prost(uint64, tag = "9")]
+    pub bytes_in: u64,
+    #[prost(uint64, tag = "10")]
+    pub bytes_out: u64,
+}
+#[allow(non_camel_case_types)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct AuditEvents {
+    #[prost(message, repeated, tag = "1")]
+    pub events: ::prost::alloc::vec::Vec<AuditEvent>,
+}
 #[allow(non_camel_case_types)]
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
 #[repr(i32)]
 pub enum PacketType {
     Unspecified = 0,
     ActiveUser = 1,
+    Billing = 2,
+    Audit = 3,
 }
 impl PacketType {
     /// String value of the enum field names used in the ProtoBuf definition.
@@ -67,6 +163,8 @@ impl PacketType {
         match self {
             PacketType::Unspecified => "PACKET_TYPE_UNSPECIFIED",
             PacketType::ActiveUser => "PACKET_TYPE_ACTIVE_USER",
+            PacketType::Billing => "PACKET_TYPE_BILLING",
+            PacketType::Audit => "PACKET_TYPE_AUDIT",
         }
     }
     /// Creates an enum from field names used in the ProtoBuf definition.
@@ -74,6 +172,8 @@ impl PacketType {
         match value {
             "PACKET_TYPE_UNSPECIFIED" => Some(Self::Unspecified),
             "PACKET_TYPE_ACTIVE_USER" => Some(Self::ActiveUser),
+            "PACKET_TYPE_BILLING" => Some(Self::Billing),
+            "PACKET_TYPE_AUDIT" => Some(Self::Audit),
             _ => None,
         }
     }
@@ -1,9 +1,16 @@
+use crate::backend::shard::ShardRoute;
+use crate::client::TENANT_USER_SEPARATOR;
 use crate::protocol::mysql::constants::CommandCode as ComInfo;
 use crate::protocol::mysql::packet::packet_reader::PacketReader;
 use crate::protocol::mysql::packet::packet_writer::PacketWriter;
+use crate::server::auth::identity::MappedIdentity;
+use crate::server::auth::reconnect_token::ReconnectGrant;
 
 use hashbrown::HashMap;
 use mysql_common::constants::{CapabilityFlags, StatusFlags};
+use mysql_common::io::ParseBuf;
+use mysql_common::packets::ErrPacket;
+use mysql_common::proto::MyDeserialize;
 use pin_project::pin_project;
 use tokio::io::{AsyncRead, AsyncWrite};
 use winnow::binary::{le_u16, le_u32, le_u8};
@@ -79,8 +86,8 @@ pub struct OkPacket {
     pub warnings: u16,
     /// Extra information
     pub info: String,
-    /// session state change information
-    pub session_state_info: String,
+    /// session state change information, the raw session-track payload
+    pub session_state_info: Vec<u8>,
 }
 
 /// `HandshakeResponse` represents the client's reply to the handshake response packet.
@@ -95,6 +102,21 @@ pub struct HandshakeResponse {
     pub auth_plugin: Vec<u8>,
     pub database: Option<Vec<u8>>,
     pub connect_attributes: Option<HashMap<String, String>>,
+    /// Set by [`ShardRegistry::route`](crate::backend::shard::ShardRegistry::route) for
+    /// connections of sharded tenants.
+    pub shard: Option<ShardRoute>,
+    /// Set by [`IdentityRegistry::map_identity`](crate::server::auth::identity::IdentityRegistry::map_identity)
+    /// for clients of mapped users, `username` is then the backend user.
+    pub identity: Option<MappedIdentity>,
+    /// Set by [`ReconnectTokens::redeem`](crate::server::auth::reconnect_token::ReconnectTokens::redeem)
+    /// for clients reconnecting with a valid token.
+    pub reconnect: Option<ReconnectGrant>,
+    /// The token handed to the client once authenticated, set by
+    /// [`ReconnectTokens::grant`](crate::server::auth::reconnect_token::ReconnectTokens::grant).
+    pub reconnect_token: Option<String>,
+    /// The SNI hostname of a client connected over TLS, it names the tenant with
+    /// [`SniRouter::route`](crate::server::sni_router::SniRouter::route).
+    pub server_name: Option<String>,
 }
 
 impl HandshakeResponse {
@@ -120,6 +142,14 @@ impl HandshakeResponse {
         }
     }
 
+    /// The user the client connected as, which differs from the backend user if it is mapped.
+    pub fn client_user_string(&self) -> String {
+        match &self.identity {
+            Some(identity) => identity.client_user.clone(),
+            None => self.db_user_string(),
+        }
+    }
+
     pub fn change_tenant_if_need(&mut self) {
         let is_not_static = std::env::var(crate::server::PROXY_ENV_SYNC_ROUTER)
             .unwrap_or("false".to_string())
@@ -128,7 +158,10 @@ impl HandshakeResponse {
         if is_not_static {
             if let Some(conn_user) = &self.username {
                 let conn_user_slice = conn_user.as_slice();
-                if let Some(pos) = conn_user_slice.iter().rposition(|&c| c == b'.') {
+                if let Some(pos) = conn_user_slice
+                    .iter()
+                    .rposition(|&c| c == TENANT_USER_SEPARATOR as u8)
+                {
                     let tenant_keys = &conn_user[..pos];
                     let user_name = &conn_user[pos + 1..];
                     self.tenant_key = Some(tenant_keys.to_vec());
@@ -139,7 +172,7 @@ impl HandshakeResponse {
     }
 }
 
-fn read_length_encoded_string(i: &[u8]) -> IResult<&[u8], &[u8]> {
+pub fn read_length_encoded_string(i: &[u8]) -> IResult<&[u8], &[u8]> {
     let (input, len) = read_length_encoded_number(i)?;
     take(len).parse_peek(input)
 }
@@ -183,16 +216,16 @@ pub fn ok_packet(i: &[u8], capabilities: CapabilityFlags) -> winnow::IResult<&[u
                 if status_flags.contains(StatusFlags::SERVER_SESSION_STATE_CHANGED) {
                     let (i, s_t_size) = read_length_encoded_number(i)?;
                     let (_i, session_state_info) = take(s_t_size).parse_peek(i)?;
-                    std::str::from_utf8(session_state_info).unwrap_or("")
+                    session_state_info
                 } else {
-                    ""
+                    &[]
                 };
             (
                 std::str::from_utf8(info).unwrap_or("").to_string(),
-                session_state_info.to_string(),
+                session_state_info.to_vec(),
             )
         } else {
-            ("".to_string(), "".to_string())
+            ("".to_string(), Vec::new())
         };
 
     Ok((
@@ -209,6 +242,16 @@ pub fn ok_packet(i: &[u8], capabilities: CapabilityFlags) -> winnow::IResult<&[u
     ))
 }
 
+/// Parses the message of an ERR packet with the capabilities negotiated by the session, the
+/// SQLSTATE is only present with `CLIENT_PROTOCOL_41`.
+pub fn err_packet_message(pkt: &[u8], capabilities: CapabilityFlags) -> std::io::Result<String> {
+    let err_packet = ErrPacket::deserialize(capabilities, &mut ParseBuf(pkt))?;
+    Ok(match err_packet {
+        ErrPacket::Error(server_error) => server_error.message_str().into_owned(),
+        ErrPacket::Progress(progress_report) => progress_report.to_string(),
+    })
+}
+
 pub fn client_handshake_response(
     i: &[u8],
     is_after_tls: bool,
@@ -240,6 +283,11 @@ pub fn client_handshake_response(
                     auth_plugin: vec![],
                     database: None,
                     connect_attributes: None,
+                    shard: None,
+                    identity: None,
+                    reconnect: None,
+                    reconnect_token: None,
+                    server_name: None,
                 },
             ));
         }
@@ -313,6 +361,11 @@ pub fn client_handshake_response(
                 auth_plugin: auth_plugin.to_vec(),
                 database: db.map(|c| c.to_vec()),
                 connect_attributes,
+                shard: None,
+                identity: None,
+                reconnect: None,
+                reconnect_token: None,
+                server_name: None,
             },
         ))
     } else {
@@ -348,6 +401,11 @@ pub fn client_handshake_response(
                 auth_plugin: vec![],
                 database: db.map(|c| c.to_vec()),
                 connect_attributes: None,
+                shard: None,
+                identity: None,
+                reconnect: None,
+                reconnect_token: None,
+                server_name: None,
             },
         ))
     }
@@ -1,5 +1,6 @@
 use hashbrown::HashMap;
 use num_derive::{FromPrimitive, ToPrimitive};
+use serde::{Deserialize, Serialize};
 use strum_macros::AsRefStr;
 
 // see: https://dev.mysql.com/doc/refman/8.0/en/identifier-length.html
@@ -42,7 +43,9 @@ pub enum HeaderInfo {
     LocalInFileHeader = 0xfb,
 }
 
-#[derive(Debug, PartialEq, Eq, Clone, Copy, FromPrimitive, ToPrimitive)]
+#[derive(
+    Debug, PartialEq, Eq, Hash, Clone, Copy, FromPrimitive, ToPrimitive, Serialize, Deserialize,
+)]
 #[repr(u8)]
 pub enum CommandCode {
     ComSleep = 0,
@@ -0,0 +1,226 @@
+use serde::{Serialize, Serializer};
+use std::fmt;
+use std::hash::Hasher;
+use std::iter::Peekable;
+use std::str::Chars;
+use twox_hash::xxh3::Hash64;
+
+/// The shape of a statement: the hash of its text with the literals stripped, the whitespace
+/// collapsed, the keywords and identifiers lowercased and the lists of literals folded into
+/// one. Statements that only differ in their literals, e.g. `IN (1, 2)` and `IN (3)`, share a
+/// fingerprint, so it aggregates the statements of a workload with a bounded cardinality.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct SqlFingerprint(pub u64);
+
+impl fmt::Display for SqlFingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+impl Serialize for SqlFingerprint {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+fn is_ident_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '$'
+}
+
+/// Skips a quoted string or identifier whose opening `quote` was consumed, a doubled quote or
+/// an escaped one does not end it. The characters skipped, without the quotes, go to `keep`.
+fn skip_quoted(chars: &mut Peekable<Chars>, quote: char, mut keep: impl FnMut(char)) {
+    while let Some(c) = chars.next() {
+        if c == '\\' && quote != '`' {
+            keep(c);
+            chars.next().into_iter().for_each(&mut keep);
+        } else if c == quote {
+            if chars.peek() != Some(&quote) {
+                break;
+            }
+            keep(c);
+            chars.next().into_iter().for_each(&mut keep);
+        } else {
+            keep(c);
+        }
+    }
+}
+
+/// The sink of [`normalize`], remembers the last character for the literals that follow an
+/// identifier, e.g. the `1` of `t1`.
+struct Normalized<F> {
+    push: F,
+    last: Option<char>,
+}
+
+impl<F: FnMut(char)> Normalized<F> {
+    fn push(&mut self, c: char) {
+        (self.push)(c);
+        self.last = Some(c);
+    }
+}
+
+/// Passes the characters of `sql` to `push` with its string and numeric literals replaced by `?`,
+/// its comments removed and its whitespace collapsed. Quoted identifiers are kept; a string cut
+/// short, e.g. by truncation, is replaced up to the end.
+fn normalize(sql: &str, push: impl FnMut(char)) {
+    let mut normalized = Normalized { push, last: None };
+    let mut chars = sql.chars().peekable();
+    let mut pending_space = false;
+    while let Some(c) = chars.next() {
+        let comment = match (c, chars.peek()) {
+            ('#', _) | ('-', Some('-')) => {
+                chars.by_ref().find(|c| *c == '\n');
+                true
+            }
+            ('/', Some('*')) => {
+                chars.next();
+                let mut prev = ' ';
+                chars.by_ref().find(|c| {
+                    let end = prev == '*' && *c == '/';
+                    prev = *c;
+                    end
+                });
+                true
+            }
+            _ => false,
+        };
+        if comment || c.is_whitespace() {
+            pending_space = normalized.last.is_some();
+            continue;
+        }
+        if pending_space {
+            normalized.push(' ');
+            pending_space = false;
+        }
+        match c {
+            '\'' | '"' => {
+                skip_quoted(&mut chars, c, |_| {});
+                normalized.push('?');
+            }
+            '`' => {
+                normalized.push(c);
+                skip_quoted(&mut chars, c, |quoted| normalized.push(quoted));
+                normalized.push(c);
+            }
+            c if c.is_ascii_digit() && !normalized.last.is_some_and(is_ident_char) => {
+                while chars
+                    .peek()
+                    .is_some_and(|c| c.is_alphanumeric() || *c == '.')
+                {
+                    chars.next();
+                }
+                normalized.push('?');
+            }
+            c => normalized.push(c),
+        }
+    }
+}
+
+/// Replaces the string and numeric literals of `sql` with `?`, removes its comments and collapses
+/// its whitespace. Quoted identifiers are kept; a string cut short, e.g. by truncation, is
+/// replaced up to the end.
+pub fn strip_literals(sql: &str) -> String {
+    let mut normalized = String::with_capacity(sql.len());
+    normalize(sql, |c| normalized.push(c));
+    normalized
+}
+
+/// Hashes the normalized text of a statement, lowercased, with `?, ?` folded into `?`.
+struct ShapeHasher {
+    hasher: Hash64,
+    last: char,
+    /// A `,` after a `?`, dropped if another `?` follows.
+    held_comma: bool,
+    held_space: bool,
+}
+
+impl ShapeHasher {
+    fn write(&mut self, c: char) {
+        let mut utf8 = [0; 4];
+        self.hasher.write(c.encode_utf8(&mut utf8).as_bytes());
+        self.last = c;
+    }
+
+    fn flush_held(&mut self) {
+        if self.held_comma {
+            self.held_comma = false;
+            self.write(',');
+            if self.held_space {
+                self.held_space = false;
+                self.write(' ');
+            }
+        }
+    }
+
+    fn push(&mut self, c: char) {
+        let c = c.to_ascii_lowercase();
+        match c {
+            ' ' if self.held_comma => self.held_space = true,
+            '?' if self.held_comma => {
+                self.held_comma = false;
+                self.held_space = false;
+            }
+            ',' if self.last == '?' && !self.held_comma => self.held_comma = true,
+            c => {
+                self.flush_held();
+                self.write(c);
+            }
+        }
+    }
+
+    fn finish(mut self) -> SqlFingerprint {
+        self.flush_held();
+        SqlFingerprint(self.hasher.finish())
+    }
+}
+
+/// The fingerprint of the statement `sql`, computed in one pass without copying it.
+pub fn fingerprint(sql: &[u8]) -> SqlFingerprint {
+    let mut shape = ShapeHasher {
+        hasher: Hash64::default(),
+        last: ' ',
+        held_comma: false,
+        held_space: false,
+    };
+    normalize(&String::from_utf8_lossy(sql), |c| shape.push(c));
+    shape.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::protocol::mysql::fingerprint::{fingerprint, strip_literals};
+
+    #[test]
+    pub fn test_sql_fingerprint() {
+        assert_eq!(
+            strip_literals("select * from t1 where id in (1, 2) and k = 'a'"),
+            "select * from t1 where id in (?, ?) and k = ?"
+        );
+        let shape = fingerprint(b"SELECT c FROM sbtest1 WHERE id IN (1, 2, 3) AND k = 'x'");
+        for same in [
+            "select c from sbtest1 where id in (7) and k = 'y'",
+            "/* app */ SELECT c\n  FROM sbtest1 WHERE id IN (4,5) AND k = \"z\" -- trailing",
+        ] {
+            assert_eq!(fingerprint(same.as_bytes()), shape, "{same}");
+        }
+        for different in [
+            "SELECT c FROM sbtest2 WHERE id IN (1, 2, 3) AND k = 'x'",
+            "SELECT c FROM sbtest1 WHERE id IN (1, 2, 3) OR k = 'x'",
+            "SELECT c FROM sbtest1 WHERE id IN (1, 2, 3), k = 'x'",
+        ] {
+            assert_ne!(fingerprint(different.as_bytes()), shape, "{different}");
+        }
+        // A comma between other tokens than literals is kept.
+        assert_ne!(
+            fingerprint(b"SELECT a, b FROM t"),
+            fingerprint(b"SELECT ab FROM t")
+        );
+        assert_eq!(shape.to_string().len(), 16);
+        assert_eq!(
+            serde_json::to_value(shape).unwrap(),
+            serde_json::json!(shape.to_string())
+        );
+    }
+}
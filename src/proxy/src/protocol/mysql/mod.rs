@@ -2,4 +2,5 @@ pub mod basic;
 pub mod charset;
 pub mod constants;
 pub mod error_codes;
+pub mod fingerprint;
 pub mod packet;
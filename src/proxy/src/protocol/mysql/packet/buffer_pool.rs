@@ -0,0 +1,76 @@
+use bytes::BytesMut;
+use std::cell::RefCell;
+
+/// Buffers kept per thread at most.
+const POOLED_BUFFERS: usize = 64;
+/// Buffers larger than this, e.g. of a large row, are freed rather than kept.
+const MAX_POOLED_CAPACITY: usize = 64 * 1024;
+
+thread_local! {
+    static BUFFER_POOL: RefCell<Vec<BytesMut>> = const { RefCell::new(Vec::new()) };
+}
+
+/// An empty buffer for at least `capacity` bytes, reused from the pool of the thread if it has
+/// one. Small queries then parse and write their packets without allocating.
+pub fn take(capacity: usize) -> BytesMut {
+    let pooled = BUFFER_POOL.with(|pool| pool.borrow_mut().pop());
+    match pooled {
+        Some(mut buf) => {
+            buf.reserve(capacity);
+            buf
+        }
+        None => BytesMut::with_capacity(capacity),
+    }
+}
+
+/// Hands `buf` back to the pool of the thread, unless the pool is full or `buf` is too large to
+/// keep around.
+pub fn recycle(mut buf: BytesMut) {
+    if buf.capacity() == 0 || buf.capacity() > MAX_POOLED_CAPACITY {
+        return;
+    }
+    buf.clear();
+    BUFFER_POOL.with(|pool| {
+        let mut pool = pool.borrow_mut();
+        if pool.len() < POOLED_BUFFERS {
+            pool.push(buf);
+        }
+    });
+}
+
+/// Buffers pooled by the current thread.
+pub fn pooled() -> usize {
+    BUFFER_POOL.with(|pool| pool.borrow().len())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::protocol::mysql::packet::{buffer_pool, packet};
+    use bytes::BytesMut;
+
+    #[test]
+    pub fn test_buffer_pool() {
+        let wire = [3, 0, 0, 1, b'a', b'b', b'c'];
+        let pooled = buffer_pool::pooled();
+        let (_, (_, first)) = packet(&wire).unwrap();
+        let first_ptr = first.as_ptr();
+        drop(first);
+        assert_eq!(buffer_pool::pooled(), pooled + 1);
+
+        // The next packet parsed on the thread reuses the buffer.
+        let (_, (_, second)) = packet(&wire).unwrap();
+        assert_eq!(second.as_ptr(), first_ptr);
+        assert_eq!(&second[..], b"abc");
+        assert_eq!(buffer_pool::pooled(), pooled);
+
+        // A frozen packet shares its payload with its clones, it is not pooled again.
+        let frozen = second.freeze();
+        let shared = frozen.clone();
+        assert_eq!(shared.as_ptr(), first_ptr);
+        drop(frozen);
+        assert_eq!(buffer_pool::pooled(), pooled);
+
+        buffer_pool::recycle(BytesMut::with_capacity(1 << 20));
+        assert_eq!(buffer_pool::pooled(), pooled);
+    }
+}
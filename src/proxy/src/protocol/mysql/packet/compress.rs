@@ -0,0 +1,379 @@
+use crate::protocol::mysql::constants;
+
+use byteorder::{ByteOrder, LittleEndian};
+use common::clock::{clock, Clock, Timestamp};
+use common::metrics::metric_def::PROXY_BACKEND_COMPRESS_SAVED_BYTES;
+use common::metrics::{common_labels, counter_handle, Counter};
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use std::fmt::{Debug, Formatter};
+use std::io;
+use std::io::{Read, Write};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tracing::warn;
+
+/// 3 bytes compressed length, 1 byte compressed sequence id and 3 bytes uncompressed length.
+pub const COMPRESSED_HEADER_LEN: usize = 7;
+/// Payloads shorter than this are sent uncompressed, the same threshold as libmysqlclient.
+pub const MIN_COMPRESS_LEN: usize = 50;
+const COMPRESSED_READ_SIZE: usize = 16 * 1024;
+
+/// Caps the bytes a session inflates from compressed packets, so a peer cannot make the proxy
+/// expand small packets to 16MB over and over. 0 means unlimited.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct InflateLimits {
+    pub max_bytes_per_command: u64,
+    pub max_bytes_per_sec: u64,
+}
+
+impl InflateLimits {
+    pub fn is_unlimited(&self) -> bool {
+        self.max_bytes_per_command == 0 && self.max_bytes_per_sec == 0
+    }
+}
+
+/// Called with the name of the limit an [`InflateBudget`] exceeded.
+pub type InflateLimitHook = Arc<dyn Fn(&'static str) + Send + Sync>;
+
+/// The bytes a session inflated against its [`InflateLimits`]. Every frame is charged before it is
+/// inflated, with the uncompressed length of its header.
+#[derive(Clone)]
+pub struct InflateBudget {
+    limits: InflateLimits,
+    clock: Clock,
+    command_bytes: u64,
+    window_start: Timestamp,
+    window_bytes: u64,
+    on_exceeded: Option<InflateLimitHook>,
+}
+
+impl Debug for InflateBudget {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("InflateBudget")
+            .field("limits", &self.limits)
+            .field("command_bytes", &self.command_bytes)
+            .field("window_bytes", &self.window_bytes)
+            .finish_non_exhaustive()
+    }
+}
+
+impl InflateBudget {
+    pub fn new(limits: InflateLimits, on_exceeded: Option<InflateLimitHook>) -> Self {
+        Self::with_clock(limits, on_exceeded, clock().clone())
+    }
+
+    pub fn with_clock(
+        limits: InflateLimits,
+        on_exceeded: Option<InflateLimitHook>,
+        clock: Clock,
+    ) -> Self {
+        Self {
+            limits,
+            command_bytes: 0,
+            window_start: clock.coarse_now(),
+            window_bytes: 0,
+            on_exceeded,
+            clock,
+        }
+    }
+
+    /// The per command limit starts over with each command.
+    pub fn start_command(&mut self) {
+        self.command_bytes = 0;
+    }
+
+    pub fn charge(&mut self, inflated: usize) -> io::Result<()> {
+        if self.clock.coarse_elapsed(self.window_start) >= Duration::from_secs(1) {
+            self.window_start = self.clock.coarse_now();
+            self.window_bytes = 0;
+        }
+        self.command_bytes += inflated as u64;
+        self.window_bytes += inflated as u64;
+        let exceeded = if self.limits.max_bytes_per_command > 0
+            && self.command_bytes > self.limits.max_bytes_per_command
+        {
+            Some(("inflate_per_command", self.limits.max_bytes_per_command))
+        } else if self.limits.max_bytes_per_sec > 0
+            && self.window_bytes > self.limits.max_bytes_per_sec
+        {
+            Some(("inflate_per_sec", self.limits.max_bytes_per_sec))
+        } else {
+            None
+        };
+        let Some((limit, max)) = exceeded else {
+            return Ok(());
+        };
+        warn!("ProxySrv compressed packets exceeded {limit} limit {max}");
+        if let Some(on_exceeded) = &self.on_exceeded {
+            on_exceeded(limit);
+        }
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("compressed packets exceeded the {limit} limit of {max} bytes"),
+        ))
+    }
+}
+
+/// The uncompressed length of the first compressed packet in `wire`, once it is complete.
+fn complete_frame_inflated_len(wire: &[u8]) -> Option<usize> {
+    if wire.len() < COMPRESSED_HEADER_LEN {
+        return None;
+    }
+    let compressed_len = LittleEndian::read_u24(wire) as usize;
+    (wire.len() >= COMPRESSED_HEADER_LEN + compressed_len)
+        .then(|| LittleEndian::read_u24(&wire[4..]) as usize)
+}
+
+/// `CompressCodec` implements the MySQL compressed protocol for one backend connection.
+///
+/// The reader and writer of the connection share one codec, because the compressed sequence id
+/// continues across both directions within a command and restarts from 0 with each new command.
+#[derive(Clone)]
+pub struct CompressCodec {
+    seq: Arc<AtomicU8>,
+    send_saved: Counter,
+    recv_saved: Counter,
+}
+
+impl Debug for CompressCodec {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CompressCodec")
+            .field("seq", &self.seq)
+            .finish_non_exhaustive()
+    }
+}
+
+impl CompressCodec {
+    pub fn new(backend_addr: String) -> Self {
+        let saved_bytes = |direction: &str| {
+            let mut labels = common_labels().clone();
+            labels.push(("backend", backend_addr.clone()));
+            labels.push(("direction", direction.to_string()));
+            counter_handle(PROXY_BACKEND_COMPRESS_SAVED_BYTES, &labels)
+        };
+        Self {
+            seq: Arc::new(AtomicU8::new(0)),
+            send_saved: saved_bytes("send"),
+            recv_saved: saved_bytes("recv"),
+        }
+    }
+
+    /// Wraps already framed MySQL packets into compressed packets.
+    pub fn encode(&self, packets: &[u8]) -> io::Result<Vec<u8>> {
+        // A packet with sequence id 0 starts a new command.
+        let mut seq = match packets.get(3) {
+            Some(0) => 0,
+            _ => self.seq.load(Ordering::Acquire).wrapping_add(1),
+        };
+        let mut frames = Vec::with_capacity(packets.len() + COMPRESSED_HEADER_LEN);
+        let mut saved = 0_u64;
+        for chunk in packets.chunks(constants::MAX_PAYLOAD_LEN) {
+            let compressed = if chunk.len() >= MIN_COMPRESS_LEN {
+                let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(chunk)?;
+                Some(encoder.finish()?).filter(|compressed| compressed.len() < chunk.len())
+            } else {
+                None
+            };
+            let mut header = [0_u8; COMPRESSED_HEADER_LEN];
+            header[3] = seq;
+            match &compressed {
+                Some(compressed) => {
+                    LittleEndian::write_u24(&mut header, compressed.len() as u32);
+                    LittleEndian::write_u24(&mut header[4..], chunk.len() as u32);
+                    frames.extend_from_slice(&header);
+                    frames.extend_from_slice(compressed);
+                    saved += (chunk.len() - compressed.len()) as u64;
+                }
+                None => {
+                    LittleEndian::write_u24(&mut header, chunk.len() as u32);
+                    frames.extend_from_slice(&header);
+                    frames.extend_from_slice(chunk);
+                }
+            }
+            self.seq.store(seq, Ordering::Release);
+            seq = seq.wrapping_add(1);
+        }
+        if saved > 0 {
+            self.send_saved.increment(saved);
+        }
+        Ok(frames)
+    }
+
+    /// Decodes the first compressed packet in `wire`. Returns the number of consumed bytes and the
+    /// uncompressed payload, or `None` if the compressed packet is incomplete.
+    pub fn decode(&self, wire: &[u8]) -> io::Result<Option<(usize, Vec<u8>)>> {
+        if wire.len() < COMPRESSED_HEADER_LEN {
+            return Ok(None);
+        }
+        let compressed_len = LittleEndian::read_u24(wire) as usize;
+        let uncompressed_len = LittleEndian::read_u24(&wire[4..]) as usize;
+        let frame_len = COMPRESSED_HEADER_LEN + compressed_len;
+        if wire.len() < frame_len {
+            return Ok(None);
+        }
+        self.seq.store(wire[3], Ordering::Release);
+        let body = &wire[COMPRESSED_HEADER_LEN..frame_len];
+        if uncompressed_len == 0 {
+            return Ok(Some((frame_len, body.to_vec())));
+        }
+        let mut payload = Vec::with_capacity(uncompressed_len);
+        // Stops one byte past the announced length instead of inflating whatever the body holds.
+        ZlibDecoder::new(body)
+            .take(uncompressed_len as u64 + 1)
+            .read_to_end(&mut payload)?;
+        if payload.len() != uncompressed_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "compressed packet inflated to {} bytes, expected {uncompressed_len}",
+                    payload.len()
+                ),
+            ));
+        }
+        self.recv_saved
+            .increment(uncompressed_len.saturating_sub(compressed_len) as u64);
+        Ok(Some((frame_len, payload)))
+    }
+
+    /// Reads from `r` until a whole compressed packet is buffered in `wire` and returns its
+    /// payload, charged to `budget` before it is inflated. Returns `None` once `r` reached EOF on
+    /// a packet boundary.
+    pub async fn read_payload<R>(
+        &self,
+        r: &mut R,
+        wire: &mut Vec<u8>,
+        mut budget: Option<&mut InflateBudget>,
+    ) -> io::Result<Option<Vec<u8>>>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let mut buf = [0_u8; COMPRESSED_READ_SIZE];
+        loop {
+            if let (Some(budget), Some(inflated)) =
+                (budget.as_deref_mut(), complete_frame_inflated_len(wire))
+            {
+                budget.charge(inflated)?;
+            }
+            if let Some((consumed, payload)) = self.decode(wire)? {
+                wire.drain(..consumed);
+                if payload.is_empty() {
+                    continue;
+                }
+                return Ok(Some(payload));
+            }
+            let read = r.read(&mut buf).await?;
+            if read == 0 {
+                return if wire.is_empty() {
+                    Ok(None)
+                } else {
+                    Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        format!("{} unhandled compressed bytes", wire.len()),
+                    ))
+                };
+            }
+            wire.extend_from_slice(&buf[..read]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::protocol::mysql::packet::compress::{
+        CompressCodec, InflateBudget, InflateLimits, COMPRESSED_HEADER_LEN, MIN_COMPRESS_LEN,
+    };
+    use crate::protocol::mysql::packet::packet_reader::PacketReader;
+    use byteorder::{ByteOrder, LittleEndian};
+    use common::clock::Clock;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[tokio::test]
+    pub async fn test_compress_round_trip() {
+        let codec = CompressCodec::new("127.0.0.1:3306".to_string());
+        // COM_QUERY with sequence id 0 starts a new command.
+        let mut query = vec![0, 0, 0, 0, 0x03];
+        query.extend_from_slice("select 1 from dual where 1 = 1 ".repeat(8).as_bytes());
+        let payload_len = (query.len() - 4) as u32;
+        query[..3].copy_from_slice(&payload_len.to_le_bytes()[..3]);
+
+        let frames = codec.encode(&query).unwrap();
+        assert_eq!(frames[3], 0);
+        assert!(frames.len() < query.len());
+        let (consumed, payload) = codec.decode(&frames).unwrap().unwrap();
+        assert_eq!(consumed, frames.len());
+        assert_eq!(payload, query);
+
+        // Short packets are sent uncompressed and continue the compressed sequence.
+        let short = vec![1, 0, 0, 1, 0x01];
+        let frames = codec.encode(&short).unwrap();
+        assert!(short.len() < MIN_COMPRESS_LEN);
+        assert_eq!(frames[3], 1);
+        assert_eq!(&frames[4..COMPRESSED_HEADER_LEN], &[0, 0, 0]);
+        assert!(codec.decode(&frames[..frames.len() - 1]).unwrap().is_none());
+
+        let mut wire = codec.encode(&query).unwrap();
+        wire.extend_from_slice(&frames);
+        let mut reader = PacketReader::new(&wire[..]).with_compression(codec);
+        let (seq, packet) = reader.next_async().await.unwrap().unwrap();
+        assert_eq!((seq, &packet[..]), (0, &query[4..]));
+        let (seq, packet) = reader.next_async().await.unwrap().unwrap();
+        assert_eq!((seq, &packet[..]), (1, &short[4..]));
+        assert!(reader.next_async().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    pub async fn test_inflate_budget() {
+        let codec = CompressCodec::new("127.0.0.1:3306".to_string());
+        let mut row = vec![0, 0, 0, 1];
+        row.extend_from_slice(&[b'a'; 4000]);
+        LittleEndian::write_u24(&mut row, 4000);
+        let frame = codec.encode(&row).unwrap();
+        assert!(frame.len() < 100);
+
+        // A frame announcing fewer bytes than its body inflates to is rejected after one more byte.
+        let mut bomb = frame.clone();
+        LittleEndian::write_u24(&mut bomb[4..], 100);
+        assert!(codec.decode(&bomb).is_err());
+
+        let (clock, source) = Clock::mock();
+        let exceeded = Arc::new(AtomicUsize::new(0));
+        let hook_exceeded = Arc::clone(&exceeded);
+        let limits = InflateLimits {
+            max_bytes_per_command: 10_000,
+            max_bytes_per_sec: 25_000,
+        };
+        let mut budget = InflateBudget::with_clock(
+            limits,
+            Some(Arc::new(move |_| {
+                hook_exceeded.fetch_add(1, Ordering::Relaxed);
+            })),
+            clock,
+        );
+        let wire = frame.repeat(3);
+        let mut reader = PacketReader::new(&wire[..])
+            .with_compression(codec.clone())
+            .with_inflate_budget(budget.clone());
+        assert!(reader.next_async().await.unwrap().is_some());
+        assert!(reader.next_async().await.unwrap().is_some());
+        assert!(reader.next_async().await.is_err());
+        assert_eq!(exceeded.load(Ordering::Relaxed), 1);
+
+        for _ in 0..6 {
+            budget.start_command();
+            budget.charge(4004).unwrap();
+        }
+        budget.start_command();
+        assert!(budget.charge(4004).is_err());
+        source.advance(Duration::from_secs(1));
+        budget.start_command();
+        budget.charge(4004).unwrap();
+        assert_eq!(exceeded.load(Ordering::Relaxed), 2);
+    }
+}
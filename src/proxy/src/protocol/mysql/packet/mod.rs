@@ -1,23 +1,49 @@
+pub mod buffer_pool;
+pub mod compress;
 pub mod packet_reader;
 pub mod packet_writer;
 pub mod writers;
 
 use crate::protocol::mysql::constants;
 use crate::protocol::mysql::constants::HeaderInfo;
+use bytes::{Bytes, BytesMut};
 use std::ops::Deref;
+use winnow::error::{ErrMode, ErrorKind, InputError, ParserError};
 use winnow::token::take;
 use winnow::Parser;
 
 /// `Packet` Represents the packet format of the MySql wire protocol.
 /// The maximum size of a MySQL packet is 16M; if the data is >16M, it needs to be split
 /// until it is less than 16 M.[MySQL Packet](https://dev.mysql.com/doc/dev/mysql-server/latest/page_protocol_basic_packets.html)
+///
+/// The payload buffer comes from the [`buffer_pool`] of the thread and goes back to it when the
+/// packet is dropped, unless the packet was [frozen](Packet::freeze).
 #[derive(Clone, Debug)]
-//TODO: bench Vec vs BytesMut
-pub struct Packet(Vec<u8>);
+pub struct Packet(BytesMut);
 
 impl Packet {
     pub fn from_vec(vec: Vec<u8>) -> Self {
-        Packet(vec)
+        // A `Bytes` of a `Vec` it owns alone converts without copying.
+        Packet(BytesMut::from(Bytes::from(vec)))
+    }
+
+    /// Turns the packet into a buffer whose clones share the payload, e.g. for the forwarders
+    /// and the failover of a command to hold on to its request.
+    pub fn freeze(mut self) -> Bytes {
+        std::mem::take(&mut self.0).freeze()
+    }
+}
+
+impl From<Bytes> for Packet {
+    /// Copies the payload only if other clones of `bytes` are still alive.
+    fn from(bytes: Bytes) -> Self {
+        Packet(BytesMut::from(bytes))
+    }
+}
+
+impl Drop for Packet {
+    fn drop(&mut self) {
+        buffer_pool::recycle(std::mem::take(&mut self.0));
     }
 }
 
@@ -39,23 +65,19 @@ fn one_packet(i: &[u8]) -> winnow::IResult<&[u8], (u8, &[u8])> {
 
 impl Packet {
     pub fn extend(&mut self, bytes: &[u8]) {
-        self.0.extend(bytes);
+        self.0.extend_from_slice(bytes);
     }
 
     /// See [MySQL EOF_Packet](https://dev.mysql.com/doc/dev/mysql-server/latest/page_protocol_basic_eof_packet.html)
     pub fn is_eof_packet(&self) -> bool {
-        let pkt_len = self.0.len();
-        !self.0.is_empty() && self.0[0] == (HeaderInfo::EOFHeader as u8) && pkt_len <= 5
+        is_eof_payload(&self.0)
     }
 
     /// See: [MariaDB](https://mariadb.com/kb/en/result-set-packets/) or [MySQL](https://dev.mysql.com/doc/dev/mysql-server/latest/page_protocol_basic_ok_packet.html)
     /// Packet header is 0xfe, and we need check the packet length.
     /// return true OK packet after the result set when CLIENT_DEPRECATE_EOF is enabled
     pub fn is_result_set_eof_packet(&self) -> bool {
-        let pkt_len = self.0.len();
-        !self.0.is_empty()
-            && self.0[0] == (HeaderInfo::EOFHeader as u8)
-            && (7..0xFFFFFF).contains(&pkt_len)
+        is_result_set_eof_payload(&self.0)
     }
 
     pub fn is_ok_packet(&self) -> bool {
@@ -63,7 +85,7 @@ impl Packet {
     }
 
     pub fn is_err_packet(&self) -> bool {
-        !self.0.is_empty() && self.0[0] == (HeaderInfo::ErrHeader as u8)
+        is_err_payload(&self.0)
     }
 
     pub fn is_local_in_file_packet(&self) -> bool {
@@ -71,6 +93,23 @@ impl Packet {
     }
 }
 
+/// [`Packet::is_eof_packet`] of a payload still in the buffer of a reader.
+pub fn is_eof_payload(payload: &[u8]) -> bool {
+    !payload.is_empty() && payload[0] == (HeaderInfo::EOFHeader as u8) && payload.len() <= 5
+}
+
+/// [`Packet::is_result_set_eof_packet`] of a payload still in the buffer of a reader.
+pub fn is_result_set_eof_payload(payload: &[u8]) -> bool {
+    !payload.is_empty()
+        && payload[0] == (HeaderInfo::EOFHeader as u8)
+        && (7..0xFFFFFF).contains(&payload.len())
+}
+
+/// [`Packet::is_err_packet`] of a payload still in the buffer of a reader.
+pub fn is_err_payload(payload: &[u8]) -> bool {
+    !payload.is_empty() && payload[0] == (HeaderInfo::ErrHeader as u8)
+}
+
 impl AsRef<[u8]> for Packet {
     fn as_ref(&self) -> &[u8] {
         &self.0
@@ -90,6 +129,9 @@ impl Deref for Packet {
     }
 }
 
+/// Parses a payload split into packets of [`MAX_PAYLOAD_LEN`](constants::MAX_PAYLOAD_LEN)
+/// bytes, up to the first shorter one, empty if the payload is a multiple of it. Returns the
+/// sequence id of the last packet.
 pub fn packet(i: &[u8]) -> winnow::IResult<&[u8], (u8, Packet)> {
     let mut input = i;
     let mut full_packets = Vec::new();
@@ -101,18 +143,20 @@ pub fn packet(i: &[u8]) -> winnow::IResult<&[u8], (u8, Packet)> {
     // Parse one final packet
     let (input, (last_seq, last_p)) = one_packet(input)?;
     // Combine the full packets with the last packet
-    let mut pkt_data = Vec::new();
-    let mut prev_seq = None;
-    for (seq, p) in full_packets {
-        if let Some(prev_seq) = prev_seq {
-            // Ensure sequence numbers are consecutive
-            assert_eq!(seq, prev_seq + 1);
+    let mut pkt_data =
+        buffer_pool::take(full_packets.len() * constants::MAX_PAYLOAD_LEN + last_p.len());
+    let mut prev_seq: Option<u8> = None;
+    for (seq, p) in full_packets.into_iter().chain([(last_seq, last_p)]) {
+        // Sequence ids of the packets of a payload are consecutive, and wrap around.
+        if prev_seq.is_some_and(|prev_seq| seq != prev_seq.wrapping_add(1)) {
+            return Err(ErrMode::Cut(InputError::from_error_kind(
+                &i,
+                ErrorKind::Verify,
+            )));
         }
         pkt_data.extend_from_slice(p);
         prev_seq = Some(seq);
     }
-
-    pkt_data.extend_from_slice(last_p);
     let pkt = Packet(pkt_data);
     Ok((input, (last_seq, pkt)))
 }
@@ -179,4 +223,22 @@ mod tests {
         );
         assert_eq!(&p.1[constants::MAX_PAYLOAD_LEN..], &[0x10]);
     }
+
+    #[test]
+    fn test_long_seq() {
+        let mut data = vec![0xff, 0xff, 0xff, 0xff];
+        data.extend(&[0; constants::MAX_PAYLOAD_LEN][..]);
+        data.extend([0x00, 0x00, 0x00, 0x00]);
+        let (rest, p) = packet(&data[..]).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(p.0, 0);
+        assert_eq!(p.1.len(), constants::MAX_PAYLOAD_LEN);
+
+        // The packets of a payload must not skip a sequence id.
+        data[constants::MAX_PAYLOAD_LEN + 7] = 2;
+        assert!(matches!(
+            packet(&data[..]),
+            Err(winnow::error::ErrMode::Cut(_))
+        ));
+    }
 }
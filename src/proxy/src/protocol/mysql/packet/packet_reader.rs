@@ -1,7 +1,12 @@
-use crate::protocol::mysql::packet::{packet, Packet};
+use crate::protocol::mysql::constants;
+use crate::protocol::mysql::packet::compress::{CompressCodec, InflateBudget};
+use crate::protocol::mysql::packet::{is_err_payload, packet, Packet};
 
+use byteorder::{ByteOrder, LittleEndian};
+use std::fmt;
 use std::io;
 use std::io::prelude::*;
+use std::sync::Arc;
 
 use tokio::io::AsyncRead;
 use tokio::io::AsyncReadExt;
@@ -27,12 +32,82 @@ macro_rules! async_packet_read {
     }};
 }
 
+/// Called with the error code of every ERR packet a [`PacketReader`] reads.
+pub type ErrCodeHook = Arc<dyn Fn(u16) + Send + Sync>;
+
+/// Called with the sequence id and payload of every packet a [`PacketReader`] reads.
+pub type PacketHook = Arc<dyn Fn(u8, &[u8]) + Send + Sync>;
+
+/// The error a [`PacketReader`] returns for a payload beyond its max packet size, announced by
+/// the packet headers before the payload is buffered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PacketTooLarge {
+    /// The payload length announced so far, a payload split into several packets may be longer.
+    pub len: usize,
+    pub max: usize,
+    /// The sequence id of the last packet header read.
+    pub seq: u8,
+}
+
+impl fmt::Display for PacketTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "packet of {} bytes exceeds the max packet size of {} bytes",
+            self.len, self.max
+        )
+    }
+}
+
+impl std::error::Error for PacketTooLarge {}
+
+impl PacketTooLarge {
+    /// The payload `e` was returned for, if it is too large.
+    pub fn of(e: &io::Error) -> Option<&PacketTooLarge> {
+        e.get_ref()?.downcast_ref()
+    }
+}
+
+/// The packet at the front of the unparsed bytes of a [`PacketReader`], as far as its headers
+/// are buffered.
+struct PendingFrame {
+    payload_len: usize,
+    /// The bytes of its packets, headers included, a lower bound while a header is missing.
+    frame_len: usize,
+    seq: u8,
+}
+
+/// Complete packets taken out of the buffer of a [`PacketReader`] without parsing them, see
+/// [`PacketReader::take_frames`].
+pub struct Frames<'a> {
+    /// The packets as read, headers included.
+    pub bytes: &'a [u8],
+    pub packets: usize,
+    /// The sequence id following the one of the last packet.
+    pub next_seq: u8,
+}
+
 /// [PacketReader] represents reading data from a TcpStream and parsing it into a MySQL [`Packet`](Packet)
+///
+/// The bytes read but not parsed yet are `bytes[start..start + remaining]`, what follows them is
+/// scratch space the next read fills. Parsing a packet only moves `start` forward, the unparsed
+/// bytes are moved to the front of the buffer before a read. Packets own their payload, they never
+/// borrow from the buffer.
 #[derive(Clone)]
 pub struct PacketReader<R> {
     bytes: Vec<u8>,
     start: usize,
     remaining: usize,
+    /// Set once the compressed protocol has been negotiated, `wire` buffers compressed bytes.
+    compress: Option<CompressCodec>,
+    wire: Vec<u8>,
+    inflate_budget: Option<InflateBudget>,
+    /// Payloads beyond this fail the read before they are buffered, 0 accepts any.
+    max_packet_size: usize,
+    /// Uncompressed bytes of the packets read, headers included.
+    bytes_read: u64,
+    err_hook: Option<ErrCodeHook>,
+    packet_hook: Option<PacketHook>,
     pub r: R,
 }
 
@@ -42,64 +117,269 @@ impl<R> PacketReader<R> {
             bytes: Vec::new(),
             start: 0,
             remaining: 0,
+            compress: None,
+            wire: Vec::new(),
+            inflate_budget: None,
+            max_packet_size: 0,
+            bytes_read: 0,
+            err_hook: None,
+            packet_hook: None,
             r,
         }
     }
+
+    pub fn with_compression(mut self, codec: CompressCodec) -> Self {
+        self.enable_compression(codec);
+        self
+    }
+
+    /// Every packet read after this call is expected to be wrapped in a compressed packet.
+    pub fn enable_compression(&mut self, codec: CompressCodec) {
+        self.compress = Some(codec);
+    }
+
+    pub fn is_compressed(&self) -> bool {
+        self.compress.is_some()
+    }
+
+    pub fn with_inflate_budget(mut self, budget: InflateBudget) -> Self {
+        self.set_inflate_budget(Some(budget));
+        self
+    }
+
+    /// Charges the compressed packets read until the budget is replaced.
+    pub fn set_inflate_budget(&mut self, budget: Option<InflateBudget>) {
+        self.inflate_budget = budget;
+    }
+
+    /// Fails the reads of payloads beyond `max_packet_size` with a [`PacketTooLarge`] error, 0
+    /// accepts any. Only [`next_async`](PacketReader::next_async) and
+    /// [`next_read`](PacketReader::next_read) check it.
+    pub fn set_max_packet_size(&mut self, max_packet_size: usize) {
+        self.max_packet_size = max_packet_size;
+    }
+
+    /// Called before each command, the per command inflate limit starts over.
+    pub fn start_command(&mut self) {
+        if let Some(budget) = &mut self.inflate_budget {
+            budget.start_command();
+        }
+    }
+
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read
+    }
+
+    /// Reports the error codes of the ERR packets read until the hook is replaced.
+    pub fn set_err_hook(&mut self, err_hook: Option<ErrCodeHook>) {
+        self.err_hook = err_hook;
+    }
+
+    pub fn set_packet_hook(&mut self, packet_hook: Option<PacketHook>) {
+        self.packet_hook = packet_hook;
+    }
+
+    fn observe(&self, seq: u8, payload: &[u8]) {
+        if let Some(packet_hook) = &self.packet_hook {
+            packet_hook(seq, payload);
+        }
+        if let Some(err_hook) = &self.err_hook {
+            if is_err_payload(payload) && payload.len() >= 3 {
+                err_hook(LittleEndian::read_u16(&payload[1..3]));
+            }
+        }
+    }
+
+    /// Takes the bytes buffered but not parsed yet, e.g. the start of the TLS handshake a client
+    /// sent right after asking for TLS.
+    pub fn take_buffered(&mut self) -> Vec<u8> {
+        let buffered = self.bytes[self.start..self.start + self.remaining].to_vec();
+        self.consume(self.remaining);
+        buffered
+    }
+
+    /// Reads `buffered` before the bytes of the inner reader.
+    pub fn with_buffered(mut self, buffered: Vec<u8>) -> Self {
+        self.start = 0;
+        self.remaining = buffered.len();
+        self.bytes = buffered;
+        self
+    }
+
+    /// Bytes allocated for buffered packets, including the compressed wire buffer.
+    pub fn buffer_capacity(&self) -> usize {
+        self.bytes.capacity() + self.wire.capacity()
+    }
+
+    /// Releases the memory of consumed packets, e.g. while the connection is idle in the pool.
+    pub fn shrink_buffers(&mut self) {
+        self.compact();
+        self.bytes.truncate(self.remaining);
+        self.bytes.shrink_to_fit();
+        self.wire.shrink_to_fit();
+    }
+
+    /// Marks the first `len` unparsed bytes consumed.
+    fn consume(&mut self, len: usize) {
+        self.start += len;
+        self.remaining -= len;
+        if self.remaining == 0 {
+            self.start = 0;
+        }
+    }
+
+    /// Moves the unparsed bytes to the front of the buffer, so a read appends to them.
+    fn compact(&mut self) {
+        self.bytes
+            .copy_within(self.start..self.start + self.remaining, 0);
+        self.start = 0;
+    }
+
+    /// The packet at the front of the unparsed bytes, `None` until its first header is buffered.
+    fn pending_frame(&self) -> Option<PendingFrame> {
+        let unparsed = &self.bytes[self.start..self.start + self.remaining];
+        let mut pending = None;
+        let mut offset = 0;
+        while unparsed.len() >= offset + constants::PACKET_HEADER_LEN {
+            let len = LittleEndian::read_u24(&unparsed[offset..]) as usize;
+            let frame = pending.get_or_insert(PendingFrame {
+                payload_len: 0,
+                frame_len: 0,
+                seq: 0,
+            });
+            frame.payload_len += len;
+            frame.frame_len += constants::PACKET_HEADER_LEN + len;
+            frame.seq = unparsed[offset + 3];
+            offset = frame.frame_len;
+            // A packet of the max payload length is followed by the rest of the payload.
+            if len < constants::MAX_PAYLOAD_LEN {
+                break;
+            }
+        }
+        pending
+    }
+
+    /// Fails once the headers buffered announce a payload beyond the max packet size.
+    fn check_packet_size(&self) -> io::Result<()> {
+        if self.max_packet_size == 0 {
+            return Ok(());
+        }
+        match self.pending_frame() {
+            Some(frame) if frame.payload_len > self.max_packet_size => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                PacketTooLarge {
+                    len: frame.payload_len,
+                    max: self.max_packet_size,
+                    seq: frame.seq,
+                },
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// The length to grow the buffer to before a read of `buffer_size` bytes: doubled for a
+    /// packet that does not fit, but not beyond the packet and one more read, the headers tell
+    /// its length. The buffer never shrinks here.
+    fn grown_len(&self, buffer_size: usize) -> usize {
+        let mut grown = std::cmp::max(buffer_size, self.remaining * 2);
+        if let Some(frame) = self.pending_frame() {
+            grown = grown.min(frame.frame_len + buffer_size);
+        }
+        grown.max(self.bytes.len())
+    }
+
+    /// Grows the buffer to [`grown_len`](Self::grown_len), without the slack `Vec` adds.
+    fn grow(&mut self, buffer_size: usize) {
+        let new_len = self.grown_len(buffer_size);
+        self.bytes.reserve_exact(new_len - self.bytes.len());
+        self.bytes.resize(new_len, 0);
+    }
+
+    /// Parses the next packet out of the unparsed bytes, `None` if they hold no complete packet.
+    fn parse_buffered(&mut self) -> io::Result<Option<(u8, Packet)>> {
+        if self.remaining == 0 {
+            return Ok(None);
+        }
+        self.check_packet_size()?;
+        let unparsed = &self.bytes[self.start..self.start + self.remaining];
+        match packet(unparsed) {
+            Ok((rest, p)) => {
+                let len = unparsed.len() - rest.len();
+                self.consume(len);
+                self.bytes_read += len as u64;
+                self.observe(p.0, &p.1);
+                Ok(Some(p))
+            }
+            Err(winnow::error::ErrMode::Incomplete(_))
+            | Err(winnow::error::ErrMode::Backtrack(_)) => Ok(None),
+            Err(winnow::error::ErrMode::Cut(ctx)) => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{:?}", ctx),
+            )),
+        }
+    }
+
+    /// Takes the complete packets buffered, up to the first one `stop` returns true for, without
+    /// parsing or copying them. The packet `stop` matched, an incomplete one and a payload split
+    /// into several packets stay buffered for [`next_async`](PacketReader::next_async).
+    pub fn take_frames(&mut self, stop: impl Fn(&[u8]) -> bool) -> Frames<'_> {
+        let (start, end) = (self.start, self.start + self.remaining);
+        let mut offset = start;
+        let mut packets = 0;
+        let mut next_seq = 0;
+        while end - offset >= constants::PACKET_HEADER_LEN {
+            let len = LittleEndian::read_u24(&self.bytes[offset..]) as usize;
+            let frame_len = constants::PACKET_HEADER_LEN + len;
+            if len >= constants::MAX_PAYLOAD_LEN || end - offset < frame_len {
+                break;
+            }
+            let seq = self.bytes[offset + 3];
+            let payload = &self.bytes[offset + constants::PACKET_HEADER_LEN..offset + frame_len];
+            if stop(payload) {
+                break;
+            }
+            self.observe(seq, payload);
+            offset += frame_len;
+            packets += 1;
+            next_seq = seq.wrapping_add(1);
+        }
+        let taken = offset - start;
+        self.consume(taken);
+        self.bytes_read += taken as u64;
+        Frames {
+            bytes: &self.bytes[start..offset],
+            packets,
+            next_seq,
+        }
+    }
+
+    /// The result of a read of 0 bytes, an error if it cut a packet short.
+    fn end_of_stream(&self) -> io::Result<Option<(u8, Packet)>> {
+        if self.remaining == 0 {
+            Ok(None)
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("{} unhandled bytes", self.remaining),
+            ))
+        }
+    }
 }
 
 impl<R: Read> PacketReader<R> {
-    // #[allow(dead_code)]
     pub fn next_read(&mut self) -> io::Result<Option<(u8, Packet)>> {
-        self.start = self.bytes.len() - self.remaining;
-
         loop {
-            if self.remaining != 0 {
-                let bytes = {
-                    // NOTE: this is all sorts of unfortunate. what we really want to do is to give
-                    // &self.bytes[self.start..] to `packet()`, and the lifetimes should all work
-                    // out. however, without NLL, borrowck doesn't realize that self.bytes is no
-                    // longer borrowed after the match, and so can be mutated.
-                    let bytes = &self.bytes[self.start..];
-                    unsafe { ::std::slice::from_raw_parts(bytes.as_ptr(), bytes.len()) }
-                };
-
-                match packet(bytes) {
-                    Ok((rest, p)) => {
-                        self.remaining = rest.len();
-                        return Ok(Some(p));
-                    }
-                    Err(winnow::error::ErrMode::Incomplete(_))
-                    | Err(winnow::error::ErrMode::Backtrack(_)) => {}
-                    Err(winnow::error::ErrMode::Cut(ctx)) => {
-                        return Err(io::Error::new(
-                            io::ErrorKind::InvalidData,
-                            format!("{:?}", ctx),
-                        ));
-                    }
-                }
+            if let Some(p) = self.parse_buffered()? {
+                return Ok(Some(p));
             }
-
             // we need to read some more
-            self.bytes.drain(0..self.start);
-            self.start = 0;
-            let end = self.bytes.len();
-            self.bytes.resize(std::cmp::max(4096, end * 2), 0);
-            let read = {
-                let buf = &mut self.bytes[end..];
-                self.r.read(buf)?
-            };
-            self.bytes.truncate(end + read);
-            self.remaining = self.bytes.len();
-
+            self.compact();
+            let end = self.remaining;
+            self.grow(PACKET_BUFFER_SIZE);
+            let read = self.r.read(&mut self.bytes[end..])?;
+            self.remaining = end + read;
             if read == 0 {
-                if self.bytes.is_empty() {
-                    return Ok(None);
-                } else {
-                    return Err(io::Error::new(
-                        io::ErrorKind::UnexpectedEof,
-                        format!("{} unhandled bytes", self.bytes.len()),
-                    ));
-                }
+                return self.end_of_stream();
             }
         }
     }
@@ -112,10 +392,10 @@ impl<R: AsyncRead + Unpin> AsyncRead for PacketReader<R> {
         buf: &mut tokio::io::ReadBuf<'_>,
     ) -> std::task::Poll<io::Result<()>> {
         if self.remaining != 0 {
-            buf.put_slice(&self.bytes[self.start..]);
-            self.bytes.clear();
-            self.start = 0;
-            self.remaining = 0;
+            let len = self.remaining.min(buf.remaining());
+            let start = self.start;
+            buf.put_slice(&self.bytes[start..start + len]);
+            self.consume(len);
             std::task::Poll::Ready(Ok(()))
         } else {
             std::pin::Pin::new(&mut self.r).poll_read(cx, buf)
@@ -124,68 +404,245 @@ impl<R: AsyncRead + Unpin> AsyncRead for PacketReader<R> {
 }
 
 impl<R: AsyncRead + Unpin> PacketReader<R> {
-    pub async fn next_async(&mut self) -> io::Result<Option<(u8, Packet)>> {
-        self.start = self.bytes.len() - self.remaining;
+    /// Buffers at least `len` bytes, fewer if the peer closes first, without consuming them. Only
+    /// for the uncompressed start of a connection, e.g. to sniff what the client speaks.
+    pub async fn peek(&mut self, len: usize) -> io::Result<&[u8]> {
+        self.compact();
+        while self.remaining < len {
+            let end = self.remaining;
+            if self.bytes.len() < end + PACKET_BUFFER_SIZE {
+                self.bytes.resize(end + PACKET_BUFFER_SIZE, 0);
+            }
+            let read = self.r.read(&mut self.bytes[end..]).await?;
+            self.remaining = end + read;
+            if read == 0 {
+                break;
+            }
+        }
+        Ok(&self.bytes[..self.remaining])
+    }
 
+    pub async fn next_async(&mut self) -> io::Result<Option<(u8, Packet)>> {
         let mut buffer_size = PACKET_BUFFER_SIZE;
         loop {
-            if self.remaining != 0 {
-                let bytes = {
-                    // NOTE: this is all sorts of unfortunate. what we really want to do is to give
-                    // &self.bytes[self.start..] to `packet()`, and the lifetimes should all work
-                    // out. however, without NLL, borrowck doesn't realize that self.bytes is no
-                    // longer borrowed after the match, and so can be mutated.
-                    let bytes = &self.bytes[self.start..];
-                    unsafe { ::std::slice::from_raw_parts(bytes.as_ptr(), self.remaining) }
-                };
-                match packet(bytes) {
-                    Ok((rest, p)) => {
-                        self.remaining = rest.len();
-                        if self.remaining > 0 {
-                            self.bytes = rest.to_vec();
-                            self.start = 0;
-                        }
-                        return Ok(Some(p));
-                    }
-                    Err(winnow::error::ErrMode::Incomplete(_))
-                    | Err(winnow::error::ErrMode::Backtrack(_)) => {}
-                    Err(winnow::error::ErrMode::Cut(ctx)) => {
-                        self.bytes.truncate(self.remaining);
-                        return Err(io::Error::new(
-                            io::ErrorKind::InvalidData,
-                            format!("{:?}", ctx),
-                        ));
-                    }
-                }
+            if let Some(p) = self.parse_buffered()? {
+                return Ok(Some(p));
             }
-
             // we need to read some more
-            self.bytes.drain(0..self.start);
-            self.start = 0;
+            self.compact();
             let end = self.remaining;
-
+            // The buffer is kept across packets, it only grows if it has little room left.
             if self.bytes.len() - end < buffer_size {
-                let new_len = std::cmp::max(buffer_size, end * 2);
-                self.bytes.resize(new_len, 0);
+                self.grow(buffer_size);
             }
-            let read = {
-                let buf = &mut self.bytes[end..];
-                self.r.read(buf).await?
+            let read = match &self.compress {
+                Some(codec) => match codec
+                    .read_payload(&mut self.r, &mut self.wire, self.inflate_budget.as_mut())
+                    .await?
+                {
+                    Some(payload) => {
+                        if self.bytes.len() < end + payload.len() {
+                            self.bytes.resize(end + payload.len(), 0);
+                        }
+                        self.bytes[end..end + payload.len()].copy_from_slice(&payload);
+                        payload.len()
+                    }
+                    None => 0,
+                },
+                None => {
+                    let buf = &mut self.bytes[end..];
+                    self.r.read(buf).await?
+                }
             };
             self.remaining = end + read;
             // use a larger buffer size to reduce bytes resize times.
             buffer_size = PACKET_LARGE_BUFFER_SIZE;
             if read == 0 {
-                self.bytes.truncate(self.remaining);
-                if self.bytes.is_empty() {
-                    return Ok(None);
-                } else {
-                    return Err(io::Error::new(
-                        io::ErrorKind::UnexpectedEof,
-                        format!("{} unhandled bytes", self.bytes.len()),
-                    ));
-                }
+                return self.end_of_stream();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::protocol::mysql::packet::packet_reader::{
+        PacketReader, PacketTooLarge, PACKET_LARGE_BUFFER_SIZE,
+    };
+    use std::io;
+    use std::io::Read;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use tokio::io::{AsyncRead, AsyncReadExt, ReadBuf};
+
+    /// Hands out `data` at most `chunk` bytes per read, so packets are split across reads. Only
+    /// in-memory I/O, the tests also run under Miri.
+    struct ChunkedReader {
+        data: Vec<u8>,
+        pos: usize,
+        chunk: usize,
+    }
+
+    impl ChunkedReader {
+        fn new(data: Vec<u8>, chunk: usize) -> Self {
+            Self {
+                data,
+                pos: 0,
+                chunk,
             }
         }
+
+        fn next_chunk(&mut self, max: usize) -> &[u8] {
+            let len = self.chunk.min(max).min(self.data.len() - self.pos);
+            self.pos += len;
+            &self.data[self.pos - len..self.pos]
+        }
+    }
+
+    impl Read for ChunkedReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let chunk = self.next_chunk(buf.len());
+            buf[..chunk.len()].copy_from_slice(chunk);
+            Ok(chunk.len())
+        }
+    }
+
+    impl AsyncRead for ChunkedReader {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            let max = buf.remaining();
+            buf.put_slice(self.next_chunk(max));
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn frame(seq: u8, payload: &[u8]) -> Vec<u8> {
+        let mut bytes = (payload.len() as u32).to_le_bytes()[..3].to_vec();
+        bytes.push(seq);
+        bytes.extend_from_slice(payload);
+        bytes
+    }
+
+    fn payloads() -> Vec<Vec<u8>> {
+        // Empty, tiny, and larger than the initial buffer.
+        vec![
+            vec![],
+            vec![0x0e],
+            (0..10_000).map(|i| i as u8).collect(),
+            vec![0xfe; 5],
+        ]
+    }
+
+    fn stream() -> Vec<u8> {
+        payloads()
+            .iter()
+            .enumerate()
+            .flat_map(|(seq, payload)| frame(seq as u8, payload))
+            .collect()
+    }
+
+    #[tokio::test]
+    pub async fn test_packet_reader() {
+        let stream = stream();
+        for chunk in [1, 3, 7, 4096, stream.len()] {
+            let mut reader = PacketReader::new(ChunkedReader::new(stream.clone(), chunk));
+            let mut async_reader = PacketReader::new(ChunkedReader::new(stream.clone(), chunk));
+            for (seq, payload) in payloads().iter().enumerate() {
+                let (read_seq, packet) = reader.next_read().unwrap().unwrap();
+                assert_eq!((read_seq, &packet[..]), (seq as u8, &payload[..]));
+                let (read_seq, packet) = async_reader.next_async().await.unwrap().unwrap();
+                assert_eq!((read_seq, &packet[..]), (seq as u8, &payload[..]));
+            }
+            assert!(reader.next_read().unwrap().is_none());
+            assert!(async_reader.next_async().await.unwrap().is_none());
+            assert_eq!(async_reader.bytes_read(), stream.len() as u64);
+        }
+
+        // A packet cut short by the peer.
+        let truncated = stream[..stream.len() - 2].to_vec();
+        let mut reader = PacketReader::new(ChunkedReader::new(truncated.clone(), 5));
+        for _ in 0..3 {
+            reader.next_async().await.unwrap().unwrap();
+        }
+        let err = reader.next_async().await.err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        let mut reader = PacketReader::new(ChunkedReader::new(truncated, 4096));
+        for _ in 0..3 {
+            reader.next_read().unwrap().unwrap();
+        }
+        assert!(reader.next_read().is_err());
+    }
+
+    #[tokio::test]
+    pub async fn test_packet_reader_max_packet_size() {
+        let stream = stream();
+        for chunk in [7, stream.len()] {
+            let mut reader = PacketReader::new(ChunkedReader::new(stream.clone(), chunk));
+            let mut sync_reader = PacketReader::new(ChunkedReader::new(stream.clone(), chunk));
+            reader.set_max_packet_size(1000);
+            sync_reader.set_max_packet_size(1000);
+            for _ in 0..2 {
+                reader.next_async().await.unwrap().unwrap();
+                sync_reader.next_read().unwrap().unwrap();
+            }
+            let err = reader.next_async().await.err().unwrap();
+            let too_large = PacketTooLarge {
+                len: 10_000,
+                max: 1000,
+                seq: 2,
+            };
+            assert_eq!(PacketTooLarge::of(&err), Some(&too_large));
+            let err = sync_reader.next_read().err().unwrap();
+            assert_eq!(PacketTooLarge::of(&err), Some(&too_large));
+        }
+        // Refused on its header, the payload is not buffered.
+        let mut reader = PacketReader::new(ChunkedReader::new(stream, 7));
+        reader.set_max_packet_size(1000);
+        while reader.next_async().await.is_ok() {}
+        assert!(reader.buffer_capacity() < 10_000);
+
+        // A large packet grows the buffer to its length and one more read, not twice what is
+        // buffered.
+        let mut reader = PacketReader::new(&[0_u8; 0][..]);
+        reader.bytes = vec![0; 4 << 20];
+        reader.bytes[..3].copy_from_slice(&4_500_000_u32.to_le_bytes()[..3]);
+        reader.remaining = reader.bytes.len();
+        assert_eq!(
+            reader.grown_len(PACKET_LARGE_BUFFER_SIZE),
+            4_500_004 + PACKET_LARGE_BUFFER_SIZE
+        );
+        reader.set_max_packet_size(4_000_000);
+        assert!(reader.parse_buffered().is_err());
+    }
+
+    #[tokio::test]
+    pub async fn test_packet_reader_peek_and_drain() {
+        let stream = stream();
+        let mut reader = PacketReader::new(ChunkedReader::new(stream.clone(), 2));
+        assert_eq!(reader.peek(6).await.unwrap(), &stream[..6]);
+        // Peeked bytes are parsed, then the unparsed ones are read raw in small pieces.
+        let (seq, packet) = reader.next_async().await.unwrap().unwrap();
+        assert_eq!((seq, packet.len()), (0, 0));
+        reader.peek(4).await.unwrap();
+        let mut raw = vec![];
+        let mut buf = [0; 3];
+        loop {
+            let read = reader.read(&mut buf).await.unwrap();
+            if read == 0 {
+                break;
+            }
+            raw.extend_from_slice(&buf[..read]);
+        }
+        assert_eq!(raw, &stream[4..]);
+
+        let mut reader = PacketReader::new(ChunkedReader::new(stream.clone(), 4096));
+        reader.next_async().await.unwrap().unwrap();
+        reader.shrink_buffers();
+        assert!(reader.buffer_capacity() < stream.len());
+        let (seq, packet) = reader.next_async().await.unwrap().unwrap();
+        assert_eq!((seq, &packet[..]), (1, &[0x0e][..]));
     }
 }
@@ -1,23 +1,74 @@
 use crate::protocol::mysql::constants;
+use crate::protocol::mysql::packet::buffer_pool;
+use crate::protocol::mysql::packet::compress::CompressCodec;
+use crate::protocol::mysql::packet::packet_reader::Frames;
+use crate::protocol::mysql::packet::Packet;
 #[allow(unused_imports)]
 use bitflags::Flags;
 use byteorder::{ByteOrder, LittleEndian};
+use bytes::BytesMut;
 
+use common::metrics::Counter;
 use pin_project::pin_project;
 use std::io;
 use std::io::prelude::*;
 use std::io::IoSlice;
 use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::task::{Context, Poll};
 
 use tokio::io::{AsyncWrite, AsyncWriteExt};
 
+/// Relayed packets written together in one `write_vectored` call at most, two slices each, well
+/// below the `IOV_MAX` of the platforms.
+const RELAY_BATCH_PACKETS: usize = 64;
+/// Relayed bytes after which the batch is written even if it has fewer packets.
+const RELAY_BATCH_BYTES: usize = 64 * 1024;
+
+/// Bounds the bytes a [`PacketWriter`] buffers for a slow peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watermarks {
+    /// Buffered bytes at which the writer stops taking packets until the peer drained them.
+    pub high: usize,
+    /// Buffered bytes at which the writer takes packets again.
+    pub low: usize,
+}
+
+/// Flow control of a [`PacketWriter`]: packets are coalesced in memory and written out once the
+/// high watermark is reached. `end_packet` then waits until the connection accepted enough bytes
+/// to get below the low watermark, so a forwarding loop stops reading the other leg meanwhile.
+#[derive(Clone)]
+pub struct FlowControl {
+    pub watermarks: Watermarks,
+    /// Bytes currently buffered, shared with whoever reports them.
+    pub buffered: Arc<AtomicUsize>,
+    /// Incremented every time the writer waits for the peer to drain its buffer.
+    pub paused: Counter,
+}
+
 #[derive(Clone)]
 #[pin_project]
 pub struct PacketWriter<W> {
-    // buf: bytes::BytesMut,
-    buf: Vec<u8>,
+    /// The payload being written, from the [`buffer_pool`] of the thread.
+    buf: BytesMut,
     seq: u8,
+    /// Set once the compressed protocol has been negotiated, packets are then collected in
+    /// `pending` and compressed together on [`flush_all`](PacketWriter::flush_all). Otherwise
+    /// `pending` only collects the small runs of frames relayed, see
+    /// [`PacketWriter::relay_frames`].
+    compress: Option<CompressCodec>,
+    pending: Vec<u8>,
+    /// Set to coalesce packets in `pending` even without compression.
+    flow_control: Option<FlowControl>,
+    /// Complete packets relayed unchanged with their header, see [`PacketWriter::relay_packet`].
+    relay: Vec<([u8; constants::PACKET_HEADER_LEN], Packet)>,
+    relay_bytes: usize,
+    /// Uncompressed bytes of the packets written, headers included.
+    bytes_written: u64,
+    /// Packets written since the last flush, see [`PacketWriter::set_max_unflushed_packets`].
+    unflushed_packets: usize,
+    max_unflushed_packets: usize,
     #[pin]
     pub inner_writer: W,
 }
@@ -25,22 +76,76 @@ pub struct PacketWriter<W> {
 impl<W> PacketWriter<W> {
     pub fn new(write: W) -> Self {
         Self {
-            buf: Vec::new(),
+            buf: BytesMut::new(),
             seq: 0,
+            compress: None,
+            pending: Vec::new(),
+            flow_control: None,
+            relay: Vec::new(),
+            relay_bytes: 0,
+            bytes_written: 0,
+            unflushed_packets: 0,
+            max_unflushed_packets: 0,
             inner_writer: write,
         }
     }
 
-    fn is_empty(&self) -> bool {
-        self.buf.is_empty()
+    pub fn enable_compression(&mut self, codec: CompressCodec) {
+        self.compress = Some(codec);
+    }
+
+    pub fn is_compressed(&self) -> bool {
+        self.compress.is_some()
+    }
+
+    pub fn enable_flow_control(&mut self, flow_control: FlowControl) {
+        self.flow_control = Some(flow_control);
+    }
+
+    /// Flushes and yields to the other tasks of the worker every `max` packets, so relaying a
+    /// huge result set neither buffers it whole nor holds the worker. 0 disables the budget.
+    pub fn set_max_unflushed_packets(&mut self, max: usize) {
+        self.max_unflushed_packets = max;
+    }
+
+    /// Bytes of complete packets not yet written to the connection.
+    pub fn buffered_bytes(&self) -> usize {
+        self.pending.len() + self.relay_bytes
     }
 
-    fn take_buffer(&mut self) -> Vec<u8> {
-        // TODO: bench vec<u8> vs bytes
-        std::mem::take(&mut self.buf)
-        // let limit = self.buf.len();
-        // let take_buf = self.buf.as_mut().take(limit);
-        // take_buf.into_inner().to_vec()
+    /// Packets are collected in `pending` instead of being written right away.
+    fn is_coalescing(&self) -> bool {
+        self.compress.is_some() || self.flow_control.is_some()
+    }
+
+    fn publish_buffered(&self) {
+        if let Some(flow_control) = &self.flow_control {
+            flow_control
+                .buffered
+                .store(self.pending.len(), Ordering::Relaxed);
+        }
+    }
+
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written
+    }
+
+    /// Bytes allocated for the packet being written and the packets pending compression.
+    pub fn buffer_capacity(&self) -> usize {
+        self.buf.capacity() + self.pending.capacity() + self.relay_bytes
+    }
+
+    /// Releases the memory of written packets, e.g. while the connection is idle in the pool.
+    pub fn shrink_buffers(&mut self) {
+        buffer_pool::recycle(std::mem::take(&mut self.buf));
+        self.pending.shrink_to_fit();
+        self.relay.shrink_to_fit();
+    }
+
+    /// Takes the payload written, `buf` keeps the spare capacity and gets the rest of its
+    /// allocation back once the payload is dropped.
+    fn take_buffer(&mut self) -> BytesMut {
+        self.buf.split()
     }
 
     pub fn set_seq(&mut self, seq: u8) {
@@ -80,8 +185,10 @@ impl<W: AsyncWrite> AsyncWrite for PacketWriter<W> {
 impl<W> Write for PacketWriter<W> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         let len = buf.len();
+        if self.buf.capacity() == 0 {
+            self.buf = buffer_pool::take(len);
+        }
         self.buf.extend_from_slice(buf);
-        // self.buf.extend(buf);
         Ok(len)
     }
 
@@ -91,55 +198,411 @@ impl<W> Write for PacketWriter<W> {
 }
 
 impl<W: AsyncWrite + Unpin> PacketWriter<W> {
+    /// Frames the payload written since the last call into packets of at most
+    /// [`MAX_PAYLOAD_LEN`](constants::MAX_PAYLOAD_LEN) bytes. A payload of a multiple of it,
+    /// the empty one included, ends with an empty packet, so the peer knows it is complete.
     pub async fn end_packet(&mut self) -> io::Result<()> {
+        self.write_relayed().await?;
         let mut header = [0; constants::PACKET_HEADER_LEN];
-        if !self.is_empty() {
-            let raw_packet = self.take_buffer();
-            // split the raw buffer at the boundary of size MAX_PAYLOAD_LEN
-            let chunks = raw_packet.chunks(constants::MAX_PAYLOAD_LEN);
-            for chunk in chunks {
-                // prepare the header
-                LittleEndian::write_u24(&mut header, chunk.len() as u32);
-                header[3] = self.seq();
-                self.increase_seq();
-                // write out the header and payload.
-                //
-                // depends on the AsyncWrite provided, this may trigger
-                // real system call or not (for examples, if AsyncWrite is buffered stream)
-                let written = self
-                    .inner_writer
-                    .write_vectored(&[IoSlice::new(&header), IoSlice::new(chunk)])
-                    .await?;
-
-                // if write buffer is not drained, fall back to write_all
-                if written != constants::PACKET_HEADER_LEN + chunk.len() {
-                    let remaining: Vec<u8> = header
-                        .iter()
-                        .chain(chunk.iter())
-                        .skip(written)
-                        .cloned()
-                        .collect();
-                    self.inner_writer.write_all(&remaining).await?
-                }
-            }
-            Ok(())
-        } else {
-            // Packet with empty payload. Usually, the payload is not empty. Currently, only the password is empty.
-            LittleEndian::write_u24(&mut header, 0);
+        let raw_packet = self.take_buffer();
+        let terminator = (raw_packet.len() % constants::MAX_PAYLOAD_LEN == 0).then_some(&[][..]);
+        // split the raw buffer at the boundary of size MAX_PAYLOAD_LEN
+        for chunk in raw_packet
+            .chunks(constants::MAX_PAYLOAD_LEN)
+            .chain(terminator)
+        {
+            // prepare the header
+            LittleEndian::write_u24(&mut header, chunk.len() as u32);
             header[3] = self.seq();
-            // info!(
-            //     "PacketWriter::end_packet: write empty packet. seq: {}",
-            //     header[3]
-            // );
-            let _size = self
+            self.increase_seq();
+            self.bytes_written += (constants::PACKET_HEADER_LEN + chunk.len()) as u64;
+            // write out the header and payload.
+            //
+            // depends on the AsyncWrite provided, this may trigger
+            // real system call or not (for examples, if AsyncWrite is buffered stream)
+            if self.is_coalescing() {
+                self.pending.extend_from_slice(&header);
+                self.pending.extend_from_slice(chunk);
+                continue;
+            }
+            let written = self
                 .inner_writer
-                .write_vectored(&[IoSlice::new(&header), IoSlice::new(&[])])
+                .write_vectored(&[IoSlice::new(&header), IoSlice::new(chunk)])
                 .await?;
-            Ok(())
+
+            // if write buffer is not drained, fall back to write_all
+            if written != constants::PACKET_HEADER_LEN + chunk.len() {
+                let remaining: Vec<u8> = header
+                    .iter()
+                    .chain(chunk.iter())
+                    .skip(written)
+                    .cloned()
+                    .collect();
+                self.inner_writer.write_all(&remaining).await?
+            }
+        }
+        self.apply_flow_control().await?;
+        self.apply_packet_budget(1).await
+    }
+
+    /// Relays a complete packet read from the other leg with the sequence id `seq` it arrived
+    /// with. The sequence ids of consecutive packets stay continuous, so their headers are valid
+    /// as they are: the packet is kept and written with the following ones in one
+    /// `write_vectored` call once the batch is full, or on [`flush_all`](PacketWriter::flush_all),
+    /// saving a system call per row of a large result set. Packets to compress or to coalesce
+    /// for flow control, and packets to split, are written the way
+    /// [`end_packet`](PacketWriter::end_packet) does.
+    pub async fn relay_packet(&mut self, seq: u8, packet: Packet) -> io::Result<()> {
+        if self.is_coalescing() || packet.len() >= constants::MAX_PAYLOAD_LEN {
+            self.set_seq(seq);
+            self.buf.extend_from_slice(&packet);
+            return self.end_packet().await;
+        }
+        let mut header = [0; constants::PACKET_HEADER_LEN];
+        LittleEndian::write_u24(&mut header, packet.len() as u32);
+        header[3] = seq;
+        self.seq = seq.wrapping_add(1);
+        let len = constants::PACKET_HEADER_LEN + packet.len();
+        self.bytes_written += len as u64;
+        self.relay_bytes += len;
+        self.relay.push((header, packet));
+        if self.relay.len() >= RELAY_BATCH_PACKETS || self.relay_bytes >= RELAY_BATCH_BYTES {
+            self.write_relayed().await?;
         }
+        self.apply_packet_budget(1).await
+    }
+
+    /// Relays complete packets taken from the buffer of the reader of the other leg, see
+    /// [`PacketReader::take_frames`](crate::protocol::mysql::packet::packet_reader::PacketReader::take_frames).
+    /// Their headers are valid as they are, like those of [`relay_packet`](Self::relay_packet).
+    /// Runs below the batch size are copied behind each other into `pending`, which is reused
+    /// across commands, and written once it holds a batch; larger ones are written straight from
+    /// the buffer of the reader. Frames to compress or to coalesce for flow control are added to
+    /// `pending` like any packet, the watermarks then decide when they are written.
+    pub async fn relay_frames(&mut self, frames: Frames<'_>) -> io::Result<()> {
+        if frames.packets == 0 {
+            return Ok(());
+        }
+        self.seq = frames.next_seq;
+        self.bytes_written += frames.bytes.len() as u64;
+        if self.is_coalescing() {
+            self.pending.extend_from_slice(frames.bytes);
+            self.apply_flow_control().await?;
+        } else {
+            self.write_relayed().await?;
+            if self.pending.len() + frames.bytes.len() < RELAY_BATCH_BYTES {
+                self.pending.extend_from_slice(frames.bytes);
+            } else {
+                self.write_with_pending(frames.bytes).await?;
+            }
+        }
+        self.apply_packet_budget(frames.packets).await
+    }
+
+    /// Writes `pending` followed by `bytes` with a single `write_vectored` call if the
+    /// connection takes them at once.
+    async fn write_with_pending(&mut self, bytes: &[u8]) -> io::Result<()> {
+        let pending_len = self.pending.len();
+        let written = self
+            .inner_writer
+            .write_vectored(&[IoSlice::new(&self.pending), IoSlice::new(bytes)])
+            .await?;
+        // if write buffer is not drained, fall back to write_all
+        if written < pending_len {
+            self.inner_writer
+                .write_all(&self.pending[written..])
+                .await?;
+        }
+        self.inner_writer
+            .write_all(&bytes[written.saturating_sub(pending_len)..])
+            .await?;
+        self.pending.clear();
+        Ok(())
+    }
+
+    /// Writes the relayed packets with a single `write_vectored` call if the connection takes
+    /// them at once, after the frames relayed before them.
+    async fn write_relayed(&mut self) -> io::Result<()> {
+        if !self.is_coalescing() && !self.pending.is_empty() {
+            self.write_with_pending(&[]).await?;
+        }
+        if self.relay.is_empty() {
+            return Ok(());
+        }
+        let slices = self
+            .relay
+            .iter()
+            .flat_map(|(header, packet)| [IoSlice::new(header), IoSlice::new(packet)])
+            .collect::<Vec<_>>();
+        let written = self.inner_writer.write_vectored(&slices).await?;
+        // if write buffer is not drained, fall back to write_all
+        if written != self.relay_bytes {
+            let remaining: Vec<u8> = self
+                .relay
+                .iter()
+                .flat_map(|(header, packet)| header.iter().chain(packet.iter()))
+                .skip(written)
+                .cloned()
+                .collect();
+            self.inner_writer.write_all(&remaining).await?
+        }
+        self.relay.clear();
+        self.relay_bytes = 0;
+        Ok(())
     }
 
     pub async fn flush_all(&mut self) -> io::Result<()> {
+        self.unflushed_packets = 0;
+        self.write_relayed().await?;
+        self.write_pending().await?;
         self.inner_writer.flush().await
     }
+
+    async fn apply_packet_budget(&mut self, packets: usize) -> io::Result<()> {
+        if self.max_unflushed_packets == 0 {
+            return Ok(());
+        }
+        self.unflushed_packets += packets;
+        if self.unflushed_packets < self.max_unflushed_packets {
+            return Ok(());
+        }
+        self.flush_all().await?;
+        tokio::task::yield_now().await;
+        Ok(())
+    }
+
+    async fn write_pending(&mut self) -> io::Result<()> {
+        if !self.pending.is_empty() {
+            match &self.compress {
+                Some(codec) => {
+                    let frames = codec.encode(&self.pending)?;
+                    self.inner_writer.write_all(&frames).await?;
+                }
+                None => self.inner_writer.write_all(&self.pending).await?,
+            }
+            self.pending.clear();
+        }
+        self.publish_buffered();
+        Ok(())
+    }
+
+    /// Waits for the peer to drain the buffer once it reached the high watermark, see
+    /// [`FlowControl`].
+    async fn apply_flow_control(&mut self) -> io::Result<()> {
+        let Some(flow_control) = &self.flow_control else {
+            return Ok(());
+        };
+        let watermarks = flow_control.watermarks;
+        if self.pending.len() < watermarks.high {
+            self.publish_buffered();
+            return Ok(());
+        }
+        flow_control.paused.increment(1);
+        if self.compress.is_some() {
+            // Compressed frames are encoded from whole packets, the buffer is drained at once.
+            return self.write_pending().await;
+        }
+        while self.pending.len() > watermarks.low {
+            let written = self.inner_writer.write(&self.pending).await?;
+            if written == 0 {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+            self.pending.drain(..written);
+        }
+        self.publish_buffered();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::protocol::mysql::constants::MAX_PAYLOAD_LEN;
+    use crate::protocol::mysql::packet::packet_reader::PacketReader;
+    use crate::protocol::mysql::packet::packet_writer::{
+        FlowControl, PacketWriter, Watermarks, RELAY_BATCH_PACKETS,
+    };
+    use crate::protocol::mysql::packet::Packet;
+    use common::metrics::Counter;
+    use std::io::{IoSlice, Write};
+    use std::pin::Pin;
+    use std::sync::atomic::Ordering;
+    use std::task::{Context, Poll};
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWrite};
+
+    /// Counts the writes reaching the connection, each a system call on a socket.
+    #[derive(Default)]
+    struct CountingWriter {
+        wire: Vec<u8>,
+        writes: usize,
+    }
+
+    impl AsyncWrite for CountingWriter {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            self.writes += 1;
+            self.wire.extend_from_slice(buf);
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_write_vectored(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            bufs: &[IoSlice<'_>],
+        ) -> Poll<std::io::Result<usize>> {
+            self.writes += 1;
+            let len = bufs.iter().map(|buf| buf.len()).sum();
+            for buf in bufs {
+                self.wire.extend_from_slice(buf);
+            }
+            Poll::Ready(Ok(len))
+        }
+
+        fn is_write_vectored(&self) -> bool {
+            true
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    pub async fn test_flow_control() {
+        let (client, mut peer) = tokio::io::duplex(64);
+        let mut writer = PacketWriter::new(client);
+        let flow_control = FlowControl {
+            watermarks: Watermarks { high: 256, low: 64 },
+            buffered: Default::default(),
+            paused: Counter::noop(),
+        };
+        writer.enable_flow_control(flow_control.clone());
+        let row = [0xab_u8; 60];
+
+        // Packets below the high watermark are only buffered.
+        for _ in 0..3 {
+            writer.write_all(&row).unwrap();
+            writer.end_packet().await.unwrap();
+        }
+        assert_eq!(writer.buffered_bytes(), 192);
+        assert_eq!(flow_control.buffered.load(Ordering::Relaxed), 192);
+
+        // The peer does not read, so the writer stops taking packets at the high watermark.
+        writer.write_all(&row).unwrap();
+        let paused = tokio::time::timeout(Duration::from_millis(50), writer.end_packet()).await;
+        assert!(paused.is_err());
+
+        let mut received = vec![];
+        let drain = async {
+            let mut buf = [0_u8; 512];
+            while received.len() < 5 * 64 {
+                let n = peer.read(&mut buf).await.unwrap();
+                received.extend_from_slice(&buf[..n]);
+            }
+        };
+        let (_, flushed) = tokio::join!(drain, async {
+            writer.write_all(&row).unwrap();
+            writer.end_packet().await?;
+            writer.flush_all().await
+        });
+        flushed.unwrap();
+        assert_eq!(writer.buffered_bytes(), 0);
+        assert_eq!(flow_control.buffered.load(Ordering::Relaxed), 0);
+        assert_eq!(received.len(), 5 * 64);
+
+        // The packet budget flushes buffered packets long before the high watermark.
+        writer.set_max_unflushed_packets(2);
+        writer.write_all(&row).unwrap();
+        writer.end_packet().await.unwrap();
+        assert_eq!(writer.buffered_bytes(), 64);
+        let mut buf = [0_u8; 128];
+        let (read, flushed) = tokio::join!(peer.read_exact(&mut buf), async {
+            writer.write_all(&row).unwrap();
+            writer.end_packet().await?;
+            Ok::<_, std::io::Error>(writer.buffered_bytes())
+        });
+        read.unwrap();
+        assert_eq!(flushed.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    pub async fn test_payload_boundaries() {
+        // Payload length, then the length of each packet it is framed into.
+        let cases = [
+            (0, vec![0]),
+            (1, vec![1]),
+            (MAX_PAYLOAD_LEN - 1, vec![MAX_PAYLOAD_LEN - 1]),
+            (MAX_PAYLOAD_LEN, vec![MAX_PAYLOAD_LEN, 0]),
+            (MAX_PAYLOAD_LEN + 1, vec![MAX_PAYLOAD_LEN, 1]),
+            (
+                2 * MAX_PAYLOAD_LEN,
+                vec![MAX_PAYLOAD_LEN, MAX_PAYLOAD_LEN, 0],
+            ),
+        ];
+        for (len, packet_lens) in cases {
+            let payload = (0..len).map(|i| i as u8).collect::<Vec<u8>>();
+            let mut writer = PacketWriter::new(vec![]);
+            writer.set_seq(254);
+            writer.write_all(&payload).unwrap();
+            writer.end_packet().await.unwrap();
+            assert_eq!(writer.seq(), 254_u8.wrapping_add(packet_lens.len() as u8));
+
+            let wire = writer.inner_writer;
+            let mut offset = 0;
+            for (i, packet_len) in packet_lens.iter().enumerate() {
+                let header = &wire[offset..offset + 4];
+                assert_eq!(header[..3], (*packet_len as u32).to_le_bytes()[..3]);
+                assert_eq!(header[3], 254_u8.wrapping_add(i as u8));
+                offset += 4 + packet_len;
+            }
+            assert_eq!(offset, wire.len());
+
+            let mut reader = PacketReader::new(&wire[..]);
+            let (seq, packet) = reader.next_async().await.unwrap().unwrap();
+            assert_eq!(seq, 254_u8.wrapping_add(packet_lens.len() as u8 - 1));
+            assert!(packet[..] == payload[..], "payload of {len} bytes");
+            assert!(reader.next_async().await.unwrap().is_none());
+        }
+    }
+
+    #[tokio::test]
+    pub async fn test_relay_packet() {
+        let rows = (0..200_u8).map(|i| vec![i; 50]).collect::<Vec<_>>();
+        let mut packets = PacketWriter::new(CountingWriter::default());
+        for (i, row) in rows.iter().enumerate() {
+            packets.set_seq(i as u8 + 1);
+            packets.write_all(row).unwrap();
+            packets.end_packet().await.unwrap();
+        }
+        packets.flush_all().await.unwrap();
+        assert_eq!(packets.inner_writer.writes, rows.len());
+
+        let mut relayed = PacketWriter::new(CountingWriter::default());
+        for (i, row) in rows.iter().enumerate() {
+            relayed
+                .relay_packet(i as u8 + 1, Packet::from_vec(row.clone()))
+                .await
+                .unwrap();
+        }
+        assert_eq!(relayed.seq(), 201);
+        assert_eq!(relayed.buffered_bytes(), (200 % RELAY_BATCH_PACKETS) * 54);
+        // A packet written the usual way goes out after the relayed ones.
+        relayed.set_seq(201);
+        relayed.write_all(&[0xfe, 0, 0, 2, 0]).unwrap();
+        relayed.end_packet().await.unwrap();
+        assert_eq!(relayed.buffered_bytes(), 0);
+        relayed.flush_all().await.unwrap();
+        assert_eq!(relayed.inner_writer.writes, 200 / RELAY_BATCH_PACKETS + 2);
+        assert_eq!(relayed.bytes_written(), packets.bytes_written() + 9);
+        let wire = &relayed.inner_writer.wire;
+        assert_eq!(wire[..wire.len() - 9], packets.inner_writer.wire[..]);
+        assert_eq!(wire[wire.len() - 9..], [5, 0, 0, 201, 0xfe, 0, 0, 2, 0]);
+    }
 }
@@ -1,11 +1,10 @@
 use crate::protocol::mysql::basic::{Column, OkPacket};
-use crate::protocol::mysql::charset::DEFAULT_COLLATION_ID;
 use crate::protocol::mysql::constants::AuthPluginName::AuthNativePassword;
 use crate::protocol::mysql::constants::{CommandCode, AUTH_PLUGIN_DATA_PART_1_LENGTH};
 use crate::protocol::mysql::error_codes::ErrorKind;
 use crate::protocol::mysql::packet::packet_writer::PacketWriter;
 
-use crate::server::default_capabilities;
+use crate::server::handshake_profile::HandshakeProfile;
 use byteorder::{LittleEndian, WriteBytesExt};
 use mysql_common::constants::{CapabilityFlags, StatusFlags};
 use mysql_common::io::WriteMysqlExt;
@@ -16,15 +15,20 @@ use tokio_rustls::rustls;
 
 pub const CONN_ID: u32 = u32::from_le_bytes([0x08, 0x00, 0x00, 0x00]);
 
+/// Writes an ERR packet. The SQLSTATE marker and state are only sent to clients which negotiated
+/// `CLIENT_PROTOCOL_41`, older clients read everything after the error code as the message.
 pub async fn write_err_packet<W: AsyncWrite + Unpin>(
     err: ErrorKind,
     msg: &[u8],
     w: &mut PacketWriter<W>,
+    client_capabilities: CapabilityFlags,
 ) -> io::Result<()> {
     w.write_u8(0xff)?;
     w.write_u16::<LittleEndian>(err as u16)?;
-    w.write_u8(b'#')?;
-    w.write_all(err.sqlstate())?;
+    if client_capabilities.contains(CapabilityFlags::CLIENT_PROTOCOL_41) {
+        w.write_u8(b'#')?;
+        w.write_all(err.sqlstate())?;
+    }
     w.write_all(msg)?;
     w.end_packet().await
 }
@@ -74,7 +78,7 @@ pub async fn write_ok_packet_with_client_flags<W: AsyncWrite + Unpin>(
             .status_flags
             .contains(StatusFlags::SERVER_SESSION_STATE_CHANGED)
         {
-            w.write_lenenc_str(ok_packet.session_state_info.as_bytes())?;
+            w.write_lenenc_str(&ok_packet.session_state_info)?;
         }
     } else {
         w.write_all(ok_packet.info.as_bytes())?;
@@ -133,6 +137,38 @@ where
     write_column_definitions_41(i, w, client_capabilities, false).await
 }
 
+/// Writes a complete text protocol result set: the column count, the column definitions,
+/// one packet per row (`None` is encoded as NULL) and the terminating EOF/OK packet.
+pub async fn write_text_result_set<W: AsyncWrite + Unpin>(
+    columns: &[Column],
+    rows: &[Vec<Option<String>>],
+    w: &mut PacketWriter<W>,
+    client_capabilities: CapabilityFlags,
+) -> io::Result<()> {
+    write_column_definitions(columns, w, client_capabilities).await?;
+    for row in rows {
+        for value in row {
+            match value {
+                Some(v) => {
+                    w.write_lenenc_str(v.as_bytes())?;
+                }
+                None => w.write_u8(0xfb)?,
+            }
+        }
+        w.end_packet().await?;
+    }
+    if client_capabilities.contains(CapabilityFlags::CLIENT_DEPRECATE_EOF) {
+        let ok_packet = OkPacket {
+            header: 0xfe,
+            status_flags: StatusFlags::SERVER_STATUS_AUTOCOMMIT,
+            ..Default::default()
+        };
+        write_ok_packet_with_client_flags(w, client_capabilities, ok_packet).await
+    } else {
+        write_eof_packet(w, StatusFlags::SERVER_STATUS_AUTOCOMMIT).await
+    }
+}
+
 // works for Protocol::ColumnDefinition41 is set
 // see: https://dev.mysql.com/doc/dev/mysql-server/latest/page_protocol_com_query_response_text_resultset_column_definition.html
 pub async fn write_column_definitions_41<'a, I, W>(
@@ -175,29 +211,16 @@ where
     }
 }
 
-pub async fn write_err<W: AsyncWrite + Unpin>(
-    err: ErrorKind,
-    msg: &[u8],
-    w: &mut PacketWriter<W>,
-) -> io::Result<()> {
-    w.write_u8(0xff)?;
-    w.write_u16::<LittleEndian>(err as u16)?;
-    w.write_u8(b'#')?;
-    w.write_all(err.sqlstate())?;
-    w.write_all(msg)?;
-    w.end_packet().await
-}
-
 pub async fn write_initial_handshake<W: AsyncWrite + Unpin>(
     writer: &mut PacketWriter<W>,
     conn_id: u64,
     scramble: [u8; 20],
-    server_version: &[u8],
+    profile: &HandshakeProfile,
     #[cfg(feature = "tls")] tls_conf: &Option<std::sync::Arc<ServerConfig>>,
 ) -> io::Result<()> {
     writer.write_all(&[10])?; // protocol 10
 
-    writer.write_all(server_version)?;
+    writer.write_all(&profile.server_version())?;
     writer.write_all(&[0x00])?;
     // connection_id (4 bytes)
     let conn_id_bytes = &[
@@ -207,7 +230,7 @@ pub async fn write_initial_handshake<W: AsyncWrite + Unpin>(
         (conn_id >> 24) as u8,
     ];
     writer.write_all(conn_id_bytes)?;
-    let server_capabilities = default_capabilities();
+    let server_capabilities = profile.capabilities();
     #[cfg(feature = "tls")]
     let server_capabilities = if tls_conf.is_some() {
         server_capabilities | CapabilityFlags::CLIENT_SSL
@@ -221,7 +244,7 @@ pub async fn write_initial_handshake<W: AsyncWrite + Unpin>(
 
     writer.write_all(&server_capabilities_vec[..2])?; // The lower 2 bytes of the Capabilities Flags, 0x42
 
-    writer.write_all(&DEFAULT_COLLATION_ID.to_le_bytes())?; // UTF8_GENERAL_CI
+    writer.write_all(&[profile.collation])?;
     writer.write_all(&StatusFlags::SERVER_STATUS_AUTOCOMMIT.bits().to_le_bytes())?; // status_flags
     writer.write_all(&server_capabilities_vec[2..4])?; // The upper 2 bytes of the Capabilities Flags
 
@@ -259,3 +282,84 @@ pub async fn write_reset_connection<W: AsyncWrite + Unpin>(
     w.end_packet().await?;
     w.flush_all().await
 }
+
+pub async fn write_init_db<W: AsyncWrite + Unpin>(
+    w: &mut PacketWriter<W>,
+    database: &[u8],
+) -> io::Result<()> {
+    w.write_u8(CommandCode::ComInitDB as u8)?;
+    w.write_all(database)?;
+    w.end_packet().await?;
+    w.flush_all().await
+}
+
+pub async fn write_ping<W: AsyncWrite + Unpin>(w: &mut PacketWriter<W>) -> io::Result<()> {
+    w.write_u8(CommandCode::ComPing as u8)?;
+    w.end_packet().await?;
+    w.flush_all().await
+}
+
+pub async fn write_quit<W: AsyncWrite + Unpin>(w: &mut PacketWriter<W>) -> io::Result<()> {
+    w.write_u8(CommandCode::ComQuit as u8)?;
+    w.end_packet().await?;
+    w.flush_all().await
+}
+
+pub async fn write_stmt_reset<W: AsyncWrite + Unpin>(
+    w: &mut PacketWriter<W>,
+    stmt_id: u32,
+) -> io::Result<()> {
+    w.write_u8(CommandCode::ComStmtReset as u8)?;
+    w.write_u32::<LittleEndian>(stmt_id)?;
+    w.end_packet().await?;
+    w.flush_all().await
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::protocol::mysql::basic::err_packet_message;
+    use crate::protocol::mysql::error_codes::ErrorKind;
+    use crate::protocol::mysql::packet::packet_reader::PacketReader;
+    use crate::protocol::mysql::packet::packet_writer::PacketWriter;
+    use crate::protocol::mysql::packet::writers::write_err_packet;
+    use mysql_common::constants::CapabilityFlags;
+
+    async fn err_packet(capabilities: CapabilityFlags) -> Vec<u8> {
+        let mut writer = PacketWriter::new(Vec::new());
+        write_err_packet(
+            ErrorKind::ER_ACCESS_DENIED_ERROR,
+            b"Access denied",
+            &mut writer,
+            capabilities,
+        )
+        .await
+        .unwrap();
+        writer.flush_all().await.unwrap();
+        let mut reader = PacketReader::new(&writer.inner_writer[..]);
+        let (_, packet) = reader.next_async().await.unwrap().unwrap();
+        packet.to_vec()
+    }
+
+    #[tokio::test]
+    pub async fn test_err_packet_capabilities() {
+        let protocol_41 = CapabilityFlags::CLIENT_PROTOCOL_41;
+        let packet = err_packet(protocol_41).await;
+        assert_eq!(&packet[..4], &[0xff, 0x15, 0x04, b'#']);
+        assert_eq!(&packet[4..9], ErrorKind::ER_ACCESS_DENIED_ERROR.sqlstate());
+        assert_eq!(
+            err_packet_message(&packet, protocol_41).unwrap(),
+            "Access denied"
+        );
+
+        let pre_41 = CapabilityFlags::CLIENT_LONG_PASSWORD;
+        let packet = err_packet(pre_41).await;
+        assert_eq!(&packet[..3], &[0xff, 0x15, 0x04]);
+        assert_eq!(&packet[3..], b"Access denied");
+        assert_eq!(
+            err_packet_message(&packet, pre_41).unwrap(),
+            "Access denied"
+        );
+        // A pre-4.1 packet has no SQLSTATE marker, so parsing it as 4.1 must fail, not panic.
+        assert!(err_packet_message(&packet, protocol_41).is_err());
+    }
+}
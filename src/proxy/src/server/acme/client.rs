@@ -0,0 +1,485 @@
+use crate::server::acme::der::*;
+use crate::server::acme::solver::ChallengeSolver;
+
+use aws_lc_rs::rand::SystemRandom;
+use aws_lc_rs::signature::{
+    EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_ASN1_SIGNING, ECDSA_P256_SHA256_FIXED_SIGNING,
+};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use reqwest::header::{ACCEPT, CONTENT_TYPE, LOCATION};
+use reqwest::{Response, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::io::{Error, ErrorKind};
+use std::time::Duration;
+use tracing::{info, warn};
+
+const REPLAY_NONCE: &str = "replay-nonce";
+const JOSE_JSON: &str = "application/jose+json";
+const PEM_CHAIN: &str = "application/pem-certificate-chain";
+const BAD_NONCE: &str = "urn:ietf:params:acme:error:badNonce";
+/// Time between two polls of a pending authorization or order.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+const POLL_ATTEMPTS: usize = 60;
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Order {
+    status: String,
+    #[serde(default)]
+    authorizations: Vec<String>,
+    finalize: String,
+    certificate: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Identifier {
+    value: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Authorization {
+    identifier: Identifier,
+    status: String,
+    #[serde(default)]
+    challenges: Vec<Challenge>,
+    #[serde(default)]
+    wildcard: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct Challenge {
+    #[serde(rename = "type")]
+    kind: String,
+    url: String,
+    #[serde(default)]
+    token: String,
+}
+
+/// An RFC 7807 problem document, the body of the ACME errors.
+#[derive(Debug, Default, Deserialize)]
+struct Problem {
+    #[serde(rename = "type", default)]
+    kind: String,
+    #[serde(default)]
+    detail: String,
+}
+
+fn acme_err(message: impl Into<String>) -> Error {
+    Error::other(message.into())
+}
+
+fn b64(bytes: impl AsRef<[u8]>) -> String {
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+/// A new P-256 key, PKCS#8 encoded. Account keys sign the JWS of the requests, certificate keys
+/// the CSR and the TLS handshakes.
+pub fn new_pkcs8_key() -> Result<Vec<u8>, Error> {
+    EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &SystemRandom::new())
+        .map(|document| document.as_ref().to_vec())
+        .map_err(|e| acme_err(format!("generate key {e}")))
+}
+
+/// The DER certificate signing request of `domains` for the key `pkcs8`, the first domain is the
+/// common name.
+pub fn new_csr(domains: &[String], pkcs8: &[u8]) -> Result<Vec<u8>, Error> {
+    let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8)
+        .map_err(|e| acme_err(format!("certificate key {e}")))?;
+    let common_name = domains
+        .first()
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "no domain to certify"))?;
+    let subject = constructed(
+        SEQUENCE,
+        &[&constructed(
+            SET,
+            &[&constructed(
+                SEQUENCE,
+                &[
+                    &tlv(OID, OID_COMMON_NAME),
+                    &tlv(UTF8_STRING, common_name.as_bytes()),
+                ],
+            )],
+        )],
+    );
+    let public_key = constructed(
+        SEQUENCE,
+        &[
+            &constructed(
+                SEQUENCE,
+                &[&tlv(OID, OID_EC_PUBLIC_KEY), &tlv(OID, OID_P256)],
+            ),
+            &bit_string(key.public_key().as_ref()),
+        ],
+    );
+    let names: Vec<u8> = domains
+        .iter()
+        .flat_map(|domain| tlv(DNS_NAME, domain.as_bytes()))
+        .collect();
+    let extensions = constructed(
+        SEQUENCE,
+        &[&constructed(
+            SEQUENCE,
+            &[
+                &tlv(OID, OID_SUBJECT_ALT_NAME),
+                &tlv(OCTET_STRING, &tlv(SEQUENCE, &names)),
+            ],
+        )],
+    );
+    let attributes = constructed(
+        CONTEXT_0,
+        &[&constructed(
+            SEQUENCE,
+            &[
+                &tlv(OID, OID_EXTENSION_REQUEST),
+                &constructed(SET, &[&extensions]),
+            ],
+        )],
+    );
+    let info = constructed(
+        SEQUENCE,
+        &[&tlv(INTEGER, &[0]), &subject, &public_key, &attributes],
+    );
+    let signature = key
+        .sign(&SystemRandom::new(), &info)
+        .map_err(|e| acme_err(format!("sign csr {e}")))?;
+    Ok(constructed(
+        SEQUENCE,
+        &[
+            &info,
+            &constructed(SEQUENCE, &[&tlv(OID, OID_ECDSA_SHA256)]),
+            &bit_string(signature.as_ref()),
+        ],
+    ))
+}
+
+/// `AcmeClient` orders certificates from an RFC 8555 server, e.g. Let's Encrypt, with one
+/// account. Requests are JWS signed with ES256.
+pub struct AcmeClient {
+    http: reqwest::Client,
+    directory: Directory,
+    key: EcdsaKeyPair,
+    /// The public key of the account, for the requests before the account URL is known.
+    jwk: Value,
+    thumbprint: String,
+    /// The account URL.
+    kid: Option<String>,
+    nonce: Option<String>,
+}
+
+impl AcmeClient {
+    /// Fetches the directory at `directory_url` and registers the account of the key `pkcs8`,
+    /// or finds it if it is already registered.
+    pub async fn connect(
+        directory_url: &str,
+        pkcs8: &[u8],
+        contact: Option<&str>,
+    ) -> Result<Self, Error> {
+        let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8)
+            .map_err(|e| acme_err(format!("account key {e}")))?;
+        let (x, y) = key.public_key().as_ref()[1..].split_at(32);
+        let (x, y) = (b64(x), b64(y));
+        // RFC 7638: the required members in lexicographic order, without whitespace.
+        let thumbprint = b64(Sha256::digest(format!(
+            r#"{{"crv":"P-256","kty":"EC","x":"{x}","y":"{y}"}}"#
+        )));
+        let http = reqwest::Client::builder()
+            .timeout(Duration::from_secs(30))
+            .build()
+            .map_err(Error::other)?;
+        let directory = http
+            .get(directory_url)
+            .send()
+            .await
+            .and_then(Response::error_for_status)
+            .map_err(Error::other)?
+            .json()
+            .await
+            .map_err(Error::other)?;
+        let mut client = Self {
+            http,
+            directory,
+            key,
+            jwk: json!({"crv": "P-256", "kty": "EC", "x": x, "y": y}),
+            thumbprint,
+            kid: None,
+            nonce: None,
+        };
+        let mut account = json!({"termsOfServiceAgreed": true});
+        if let Some(contact) = contact {
+            account["contact"] = json!([format!("mailto:{contact}")]);
+        }
+        let new_account = client.directory.new_account.clone();
+        let rsp = client.post(&new_account, Some(&account)).await?;
+        let kid = rsp
+            .headers()
+            .get(LOCATION)
+            .and_then(|location| location.to_str().ok())
+            .ok_or_else(|| acme_err("account without location"))?;
+        info!("ProxySrv ACME account {kid}");
+        client.kid = Some(kid.to_string());
+        Ok(client)
+    }
+
+    /// The key authorization of the challenge `token`.
+    pub fn key_authorization(&self, token: &str) -> String {
+        format!("{token}.{}", self.thumbprint)
+    }
+
+    async fn nonce(&mut self) -> Result<String, Error> {
+        if let Some(nonce) = self.nonce.take() {
+            return Ok(nonce);
+        }
+        let rsp = self
+            .http
+            .head(&self.directory.new_nonce)
+            .send()
+            .await
+            .map_err(Error::other)?;
+        rsp.headers()
+            .get(REPLAY_NONCE)
+            .and_then(|nonce| nonce.to_str().ok())
+            .map(str::to_string)
+            .ok_or_else(|| acme_err("no replay nonce"))
+    }
+
+    fn jws(&self, url: &str, nonce: String, payload: Option<&Value>) -> Result<Value, Error> {
+        let mut protected = json!({"alg": "ES256", "nonce": nonce, "url": url});
+        match &self.kid {
+            Some(kid) => protected["kid"] = json!(kid),
+            None => protected["jwk"] = self.jwk.clone(),
+        }
+        let protected = b64(protected.to_string());
+        // POST-as-GET requests have an empty payload.
+        let payload = payload.map(|p| b64(p.to_string())).unwrap_or_default();
+        let signature = self
+            .key
+            .sign(
+                &SystemRandom::new(),
+                format!("{protected}.{payload}").as_bytes(),
+            )
+            .map_err(|e| acme_err(format!("sign request {e}")))?;
+        Ok(json!({"protected": protected, "payload": payload, "signature": b64(signature)}))
+    }
+
+    /// Posts `payload` to `url`, retrying once with a fresh nonce if the server rejected the
+    /// previous one.
+    async fn post(&mut self, url: &str, payload: Option<&Value>) -> Result<Response, Error> {
+        let mut retried = false;
+        loop {
+            let nonce = self.nonce().await?;
+            let body = self.jws(url, nonce, payload)?;
+            let rsp = self
+                .http
+                .post(url)
+                .header(CONTENT_TYPE, JOSE_JSON)
+                .header(ACCEPT, PEM_CHAIN)
+                .body(body.to_string())
+                .send()
+                .await
+                .map_err(Error::other)?;
+            self.nonce = rsp
+                .headers()
+                .get(REPLAY_NONCE)
+                .and_then(|nonce| nonce.to_str().ok())
+                .map(str::to_string);
+            if rsp.status().is_success() {
+                return Ok(rsp);
+            }
+            let status = rsp.status();
+            let problem: Problem = rsp.json().await.unwrap_or_default();
+            if status == StatusCode::BAD_REQUEST && problem.kind == BAD_NONCE && !retried {
+                retried = true;
+                continue;
+            }
+            return Err(acme_err(format!(
+                "{url} answered {status} {} {}",
+                problem.kind, problem.detail
+            )));
+        }
+    }
+
+    async fn post_json<T: DeserializeOwned>(
+        &mut self,
+        url: &str,
+        payload: Option<&Value>,
+    ) -> Result<T, Error> {
+        self.post(url, payload)
+            .await?
+            .json()
+            .await
+            .map_err(Error::other)
+    }
+
+    /// Orders a certificate of `domains` with the CSR `csr`, solving their challenges with
+    /// `solver`. Returns the PEM certificate chain.
+    pub async fn order(
+        &mut self,
+        domains: &[String],
+        csr: &[u8],
+        solver: &dyn ChallengeSolver,
+    ) -> Result<String, Error> {
+        let identifiers: Vec<Value> = domains
+            .iter()
+            .map(|domain| json!({"type": "dns", "value": domain}))
+            .collect();
+        let new_order = self.directory.new_order.clone();
+        let rsp = self
+            .post(&new_order, Some(&json!({"identifiers": identifiers})))
+            .await?;
+        let order_url = rsp
+            .headers()
+            .get(LOCATION)
+            .and_then(|location| location.to_str().ok())
+            .map(str::to_string)
+            .ok_or_else(|| acme_err("order without location"))?;
+        let order: Order = rsp.json().await.map_err(Error::other)?;
+        for authorization_url in &order.authorizations {
+            self.authorize(authorization_url, solver).await?;
+        }
+
+        let order = self.poll_order(&order_url, &["ready", "valid"]).await?;
+        if order.status == "ready" {
+            let finalize = json!({"csr": b64(csr)});
+            self.post(&order.finalize, Some(&finalize)).await?;
+        }
+        let order = self.poll_order(&order_url, &["valid"]).await?;
+        let certificate = order
+            .certificate
+            .ok_or_else(|| acme_err("valid order without certificate"))?;
+        self.post(&certificate, None)
+            .await?
+            .text()
+            .await
+            .map_err(Error::other)
+    }
+
+    async fn authorize(
+        &mut self,
+        authorization_url: &str,
+        solver: &dyn ChallengeSolver,
+    ) -> Result<(), Error> {
+        let authorization: Authorization = self.post_json(authorization_url, None).await?;
+        if authorization.status == "valid" {
+            return Ok(());
+        }
+        let domain = match authorization.wildcard {
+            true => format!("*.{}", authorization.identifier.value),
+            false => authorization.identifier.value,
+        };
+        let challenge = authorization
+            .challenges
+            .iter()
+            .find(|challenge| challenge.kind == solver.challenge_type())
+            .ok_or_else(|| {
+                acme_err(format!(
+                    "no {} challenge for {domain}",
+                    solver.challenge_type()
+                ))
+            })?;
+        let key_authorization = self.key_authorization(&challenge.token);
+        solver
+            .present(&domain, &challenge.token, &key_authorization)
+            .await?;
+        let validated = self
+            .validate(authorization_url, &challenge.url, &domain)
+            .await;
+        if let Err(e) = solver
+            .cleanup(&domain, &challenge.token, &key_authorization)
+            .await
+        {
+            warn!("ProxySrv ACME cleanup of {domain} failed {e:?}");
+        }
+        validated
+    }
+
+    async fn validate(
+        &mut self,
+        authorization_url: &str,
+        challenge_url: &str,
+        domain: &str,
+    ) -> Result<(), Error> {
+        self.post(challenge_url, Some(&json!({}))).await?;
+        for _ in 0..POLL_ATTEMPTS {
+            let authorization: Authorization = self.post_json(authorization_url, None).await?;
+            match authorization.status.as_str() {
+                "valid" => {
+                    info!("ProxySrv ACME validated {domain}");
+                    return Ok(());
+                }
+                "pending" => tokio::time::sleep(POLL_INTERVAL).await,
+                status => return Err(acme_err(format!("authorization of {domain} {status}"))),
+            }
+        }
+        Err(acme_err(format!("authorization of {domain} timed out")))
+    }
+
+    async fn poll_order(&mut self, order_url: &str, until: &[&str]) -> Result<Order, Error> {
+        for _ in 0..POLL_ATTEMPTS {
+            let order: Order = self.post_json(order_url, None).await?;
+            if until.contains(&order.status.as_str()) {
+                return Ok(order);
+            }
+            match order.status.as_str() {
+                "pending" | "ready" | "processing" => tokio::time::sleep(POLL_INTERVAL).await,
+                status => return Err(acme_err(format!("order {order_url} {status}"))),
+            }
+        }
+        Err(acme_err(format!("order {order_url} timed out")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::server::acme::client::{new_csr, new_pkcs8_key};
+    use crate::server::acme::der::*;
+    use aws_lc_rs::signature::{UnparsedPublicKey, ECDSA_P256_SHA256_ASN1};
+
+    #[test]
+    pub fn test_new_csr() {
+        let key = new_pkcs8_key().unwrap();
+        let domains = vec!["db.example.com".to_string(), "*.db.example.com".to_string()];
+        let csr = new_csr(&domains, &key).unwrap();
+        let (SEQUENCE, csr, _) = read_tlv(&csr).unwrap() else {
+            panic!("csr is not a sequence");
+        };
+        let (SEQUENCE, info_content, rest) = read_tlv(csr).unwrap() else {
+            panic!("request info is not a sequence");
+        };
+        let info = &csr[..csr.len() - rest.len()];
+        let (SEQUENCE, algorithm, rest) = read_tlv(rest).unwrap() else {
+            panic!("signature algorithm is not a sequence");
+        };
+        assert_eq!(algorithm, tlv(OID, OID_ECDSA_SHA256));
+        let (BIT_STRING, signature, _) = read_tlv(rest).unwrap() else {
+            panic!("signature is not a bit string");
+        };
+
+        // Version, subject, then the public key.
+        let (_, _, rest) = read_tlv(info_content).unwrap();
+        let (_, _, rest) = read_tlv(rest).unwrap();
+        let (_, public_key_info, attributes) = read_tlv(rest).unwrap();
+        let (_, _, public_key) = read_tlv(public_key_info).unwrap();
+        let (BIT_STRING, public_key, _) = read_tlv(public_key).unwrap() else {
+            panic!("public key is not a bit string");
+        };
+        UnparsedPublicKey::new(&ECDSA_P256_SHA256_ASN1, &public_key[1..])
+            .verify(info, &signature[1..])
+            .unwrap();
+        for domain in &domains {
+            let name = tlv(DNS_NAME, domain.as_bytes());
+            assert!(attributes.windows(name.len()).any(|window| window == name));
+        }
+        assert!(new_csr(&[], &key).is_err());
+    }
+}
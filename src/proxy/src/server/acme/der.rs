@@ -0,0 +1,165 @@
+//! The little DER the ACME client needs: encoding a certificate signing request and reading the
+//! expiry of the issued certificate.
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+
+pub const SEQUENCE: u8 = 0x30;
+pub const SET: u8 = 0x31;
+pub const INTEGER: u8 = 0x02;
+pub const BIT_STRING: u8 = 0x03;
+pub const OCTET_STRING: u8 = 0x04;
+pub const OID: u8 = 0x06;
+pub const UTF8_STRING: u8 = 0x0c;
+const UTC_TIME: u8 = 0x17;
+const GENERALIZED_TIME: u8 = 0x18;
+/// `[0]`, constructed.
+pub const CONTEXT_0: u8 = 0xa0;
+/// `[2]` implicit, primitive: the `dNSName` of a `GeneralName`.
+pub const DNS_NAME: u8 = 0x82;
+
+/// 2.5.4.3
+pub const OID_COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+/// 1.2.840.10045.2.1
+pub const OID_EC_PUBLIC_KEY: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+/// 1.2.840.10045.3.1.7
+pub const OID_P256: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+/// 1.2.840.10045.4.3.2
+pub const OID_ECDSA_SHA256: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02];
+/// 1.2.840.113549.1.9.14
+pub const OID_EXTENSION_REQUEST: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x0e];
+/// 2.5.29.17
+pub const OID_SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1d, 0x11];
+
+pub fn tlv(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(content.len() + 4);
+    out.push(tag);
+    match content.len() {
+        len @ 0..=0x7f => out.push(len as u8),
+        len => {
+            let bytes = len.to_be_bytes();
+            let skip = bytes.iter().take_while(|b| **b == 0).count();
+            out.push(0x80 | (bytes.len() - skip) as u8);
+            out.extend_from_slice(&bytes[skip..]);
+        }
+    }
+    out.extend_from_slice(content);
+    out
+}
+
+/// A constructed value of `tag` made of `parts`.
+pub fn constructed(tag: u8, parts: &[&[u8]]) -> Vec<u8> {
+    tlv(tag, &parts.concat())
+}
+
+/// A BIT STRING of whole bytes.
+pub fn bit_string(bytes: &[u8]) -> Vec<u8> {
+    tlv(BIT_STRING, &[&[0], bytes].concat())
+}
+
+/// Splits the first value of `input` into its tag, content and the bytes after it.
+pub fn read_tlv(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = input.split_first()?;
+    let (&first, rest) = rest.split_first()?;
+    let (len, rest) = if first & 0x80 == 0 {
+        (first as usize, rest)
+    } else {
+        let octets = (first & 0x7f) as usize;
+        if octets == 0 || octets > std::mem::size_of::<usize>() || rest.len() < octets {
+            return None;
+        }
+        let len = rest[..octets]
+            .iter()
+            .fold(0usize, |len, b| (len << 8) | *b as usize);
+        (len, &rest[octets..])
+    };
+    (rest.len() >= len).then(|| (tag, &rest[..len], &rest[len..]))
+}
+
+/// The `notAfter` of an X.509 certificate.
+pub fn not_after(cert: &[u8]) -> Option<DateTime<Utc>> {
+    let (SEQUENCE, cert, _) = read_tlv(cert)? else {
+        return None;
+    };
+    let (SEQUENCE, mut tbs, _) = read_tlv(cert)? else {
+        return None;
+    };
+    // The version is optional, the serial number, signature algorithm and issuer are not.
+    if tbs.first() == Some(&CONTEXT_0) {
+        tbs = read_tlv(tbs)?.2;
+    }
+    for _ in 0..3 {
+        tbs = read_tlv(tbs)?.2;
+    }
+    let (SEQUENCE, validity, _) = read_tlv(tbs)? else {
+        return None;
+    };
+    let (_, _, validity) = read_tlv(validity)?;
+    let (tag, time, _) = read_tlv(validity)?;
+    let time = std::str::from_utf8(time).ok()?;
+    let time = match tag {
+        // Two digit years of UTCTime are 1950 to 2049.
+        UTC_TIME if time.len() == 13 => match time[..2].parse::<u8>().ok()? {
+            year @ 50.. => format!("19{year}{}", &time[2..]),
+            year => format!("20{year:02}{}", &time[2..]),
+        },
+        GENERALIZED_TIME => time.to_string(),
+        _ => return None,
+    };
+    NaiveDateTime::parse_from_str(&time, "%Y%m%d%H%M%SZ")
+        .ok()
+        .map(|time| time.and_utc())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::server::acme::der::*;
+    use chrono::{TimeZone, Utc};
+
+    fn certificate(validity: &[u8]) -> Vec<u8> {
+        let tbs = constructed(
+            SEQUENCE,
+            &[
+                &constructed(CONTEXT_0, &[&tlv(INTEGER, &[2])]),
+                &tlv(INTEGER, &[0x01, 0x02]),
+                &constructed(SEQUENCE, &[&tlv(OID, OID_ECDSA_SHA256)]),
+                &constructed(SEQUENCE, &[]),
+                validity,
+                // A subject long enough for a two byte length.
+                &tlv(UTF8_STRING, &[b'a'; 300]),
+            ],
+        );
+        constructed(SEQUENCE, &[&tbs, &bit_string(&[0; 8])])
+    }
+
+    #[test]
+    pub fn test_not_after() {
+        assert_eq!(
+            &tlv(OCTET_STRING, &[0; 300])[..4],
+            &[0x04, 0x82, 0x01, 0x2c]
+        );
+        let validity = constructed(
+            SEQUENCE,
+            &[
+                &tlv(UTC_TIME, b"991231235959Z"),
+                &tlv(UTC_TIME, b"270115083000Z"),
+            ],
+        );
+        assert_eq!(
+            not_after(&certificate(&validity)),
+            Utc.with_ymd_and_hms(2027, 1, 15, 8, 30, 0).single()
+        );
+        let validity = constructed(
+            SEQUENCE,
+            &[
+                &tlv(UTC_TIME, b"991231235959Z"),
+                &tlv(GENERALIZED_TIME, b"20510101000000Z"),
+            ],
+        );
+        assert_eq!(
+            not_after(&certificate(&validity)),
+            Utc.with_ymd_and_hms(2051, 1, 1, 0, 0, 0).single()
+        );
+        let truncated = certificate(&validity);
+        assert_eq!(not_after(&truncated[..truncated.len() / 2]), None);
+    }
+}
@@ -0,0 +1,293 @@
+//! Automatic certificates of the client listener. [`AcmeManager`] orders a certificate of the
+//! configured domains from an ACME server, keeps it with its key in the secrets directory and
+//! renews it ahead of its expiry. The certificate is served through [`AcmeCertResolver`], a
+//! renewal only changes the certificate of the next handshakes, connected clients keep theirs.
+
+use crate::server::acme::client::{new_csr, new_pkcs8_key, AcmeClient};
+use crate::server::acme::der::not_after;
+use crate::server::acme::solver::ChallengeSolver;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use common::metrics::metric_def::{PROXY_ACME_ORDERS, PROXY_TLS_CERT_EXPIRY};
+use common::metrics::{common_labels, counter_inc, gauge};
+use common::ShutdownMessage;
+use std::fs;
+use std::io::{Error, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock, RwLock};
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio_rustls::rustls::crypto::aws_lc_rs::sign::any_supported_type;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use tokio_rustls::rustls::sign::CertifiedKey;
+use tracing::{info, warn};
+
+pub mod client;
+pub mod der;
+pub mod solver;
+
+pub const LETS_ENCRYPT_DIRECTORY: &str = "https://acme-v02.api.letsencrypt.org/directory";
+const ACCOUNT_KEY_FILE: &str = "acme-account.key";
+const CERT_FILE: &str = "acme-cert.pem";
+const KEY_FILE: &str = "acme-key.pem";
+/// Time before the next attempt after a failed order.
+const RETRY_INTERVAL: Duration = Duration::from_secs(600);
+
+#[derive(Debug, Clone)]
+pub struct AcmeOptions {
+    pub directory_url: String,
+    /// Domains of the certificate, the first is its common name.
+    pub domains: Vec<String>,
+    /// Email of the account, for the expiry notices of the ACME server.
+    pub contact: Option<String>,
+    /// Where the account key, the certificate and its key are kept.
+    pub secrets_dir: PathBuf,
+    /// A certificate is renewed once it expires within this.
+    pub renew_before: Duration,
+    /// Time between two expiry checks.
+    pub check_interval: Duration,
+}
+
+impl AcmeOptions {
+    pub fn new(domains: Vec<String>, secrets_dir: PathBuf) -> Self {
+        Self {
+            directory_url: LETS_ENCRYPT_DIRECTORY.to_string(),
+            domains,
+            contact: None,
+            secrets_dir,
+            renew_before: Duration::from_secs(30 * 24 * 3600),
+            check_interval: Duration::from_secs(12 * 3600),
+        }
+    }
+}
+
+/// `AcmeCertResolver` serves the current certificate of the client listener, set with
+/// [`ClientTlsOptions::cert_resolver`]. Handshakes before the first certificate fail.
+///
+/// [`ClientTlsOptions::cert_resolver`]: crate::server::client_tls::ClientTlsOptions
+#[derive(Debug, Default)]
+pub struct AcmeCertResolver {
+    current: RwLock<Option<Arc<CertifiedKey>>>,
+}
+
+static ACME_CERT_RESOLVER_ONCE: OnceLock<Arc<AcmeCertResolver>> = OnceLock::new();
+
+/// The resolver [`AcmeManager::new`] provisions.
+pub fn acme_cert_resolver() -> Arc<AcmeCertResolver> {
+    Arc::clone(ACME_CERT_RESOLVER_ONCE.get_or_init(Arc::default))
+}
+
+impl AcmeCertResolver {
+    /// Serves `certified` to the next handshakes.
+    pub fn swap(&self, certified: CertifiedKey) {
+        *self.current.write().unwrap() = Some(Arc::new(certified));
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.current.read().unwrap().is_some()
+    }
+}
+
+impl ResolvesServerCert for AcmeCertResolver {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        self.current.read().unwrap().clone()
+    }
+}
+
+fn pem(label: &str, der: &[u8]) -> String {
+    let base64 = STANDARD.encode(der);
+    let mut pem = format!("-----BEGIN {label}-----\n");
+    for line in base64.as_bytes().chunks(64) {
+        pem.push_str(std::str::from_utf8(line).unwrap());
+        pem.push('\n');
+    }
+    pem.push_str(&format!("-----END {label}-----\n"));
+    pem
+}
+
+/// Writes a secret readable by the proxy user only. The file is replaced at once, a proxy
+/// restarting meanwhile reads the previous one.
+fn write_secret(path: &Path, contents: &[u8]) -> Result<(), Error> {
+    let tmp = path.with_extension("tmp");
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(&tmp)?.write_all(contents)?;
+    fs::rename(tmp, path)
+}
+
+/// The certificate chain and key of `cert_pem` and `key_pem`, and the expiry of the
+/// certificate.
+fn certified_key(cert_pem: &[u8], key_pem: &[u8]) -> Result<(CertifiedKey, DateTime<Utc>), Error> {
+    let invalid = |e| Error::new(ErrorKind::InvalidData, format!("{e:?}"));
+    let certs = CertificateDer::pem_slice_iter(cert_pem)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(invalid)?;
+    let expiry = certs
+        .first()
+        .and_then(|cert| not_after(cert))
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "certificate without expiry"))?;
+    let key = PrivateKeyDer::from_pem_slice(key_pem).map_err(invalid)?;
+    let signing_key =
+        any_supported_type(&key).map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))?;
+    Ok((CertifiedKey::new(certs, signing_key), expiry))
+}
+
+/// `AcmeManager` provisions and renews the certificate served by its [`AcmeCertResolver`].
+pub struct AcmeManager {
+    options: AcmeOptions,
+    solver: Arc<dyn ChallengeSolver>,
+    resolver: Arc<AcmeCertResolver>,
+    /// Expiry of the certificate served.
+    expiry: Option<DateTime<Utc>>,
+}
+
+impl AcmeManager {
+    /// A manager of the certificate served by [`acme_cert_resolver`].
+    pub fn new(options: AcmeOptions, solver: Arc<dyn ChallengeSolver>) -> Self {
+        Self::with_resolver(options, solver, acme_cert_resolver())
+    }
+
+    pub fn with_resolver(
+        options: AcmeOptions,
+        solver: Arc<dyn ChallengeSolver>,
+        resolver: Arc<AcmeCertResolver>,
+    ) -> Self {
+        Self {
+            options,
+            solver,
+            resolver,
+            expiry: None,
+        }
+    }
+
+    fn secret_path(&self, file: &str) -> PathBuf {
+        self.options.secrets_dir.join(file)
+    }
+
+    /// Serves the certificate kept in the secrets directory, if any. Returns its expiry.
+    pub fn load(&mut self) -> Result<Option<DateTime<Utc>>, Error> {
+        let (cert_path, key_path) = (self.secret_path(CERT_FILE), self.secret_path(KEY_FILE));
+        if !cert_path.exists() || !key_path.exists() {
+            return Ok(None);
+        }
+        let (certified, expiry) = certified_key(&fs::read(cert_path)?, &fs::read(key_path)?)?;
+        self.serve(certified, expiry);
+        Ok(Some(expiry))
+    }
+
+    fn serve(&mut self, certified: CertifiedKey, expiry: DateTime<Utc>) {
+        self.resolver.swap(certified);
+        self.expiry = Some(expiry);
+        self.report_expiry();
+    }
+
+    fn report_expiry(&self) {
+        if let Some(expiry) = self.expiry {
+            let seconds = (expiry - Utc::now()).num_seconds();
+            gauge(PROXY_TLS_CERT_EXPIRY, seconds as f64, Some(common_labels()));
+        }
+    }
+
+    /// Whether the certificate served has to be renewed.
+    pub fn renewal_due(&self, now: DateTime<Utc>) -> bool {
+        let (Some(expiry), Ok(renew_before)) = (
+            self.expiry,
+            chrono::Duration::from_std(self.options.renew_before),
+        ) else {
+            return true;
+        };
+        expiry - now <= renew_before
+    }
+
+    fn account_key(&self) -> Result<Vec<u8>, Error> {
+        let path = self.secret_path(ACCOUNT_KEY_FILE);
+        if path.exists() {
+            let key = PrivatePkcs8KeyDer::from_pem_file(&path)
+                .map_err(|e| Error::new(ErrorKind::InvalidData, format!("{e:?}")))?;
+            return Ok(key.secret_pkcs8_der().to_vec());
+        }
+        let key = new_pkcs8_key()?;
+        write_secret(&path, pem("PRIVATE KEY", &key).as_bytes())?;
+        Ok(key)
+    }
+
+    /// Orders a new certificate, keeps it in the secrets directory and serves it.
+    pub async fn provision(&mut self) -> Result<(), Error> {
+        fs::create_dir_all(&self.options.secrets_dir)?;
+        let account_key = self.account_key()?;
+        let mut client = AcmeClient::connect(
+            &self.options.directory_url,
+            &account_key,
+            self.options.contact.as_deref(),
+        )
+        .await?;
+        let cert_key = new_pkcs8_key()?;
+        let csr = new_csr(&self.options.domains, &cert_key)?;
+        let chain = client
+            .order(&self.options.domains, &csr, self.solver.as_ref())
+            .await?;
+        let key_pem = pem("PRIVATE KEY", &cert_key);
+        let (certified, expiry) = certified_key(chain.as_bytes(), key_pem.as_bytes())?;
+        // A crash between the writes keeps a mismatched pair, it fails to load and is ordered
+        // again.
+        write_secret(&self.secret_path(KEY_FILE), key_pem.as_bytes())?;
+        write_secret(&self.secret_path(CERT_FILE), chain.as_bytes())?;
+        self.serve(certified, expiry);
+        info!(
+            "ProxySrv ACME certificate of {:?} provisioned, expires {expiry}",
+            self.options.domains
+        );
+        Ok(())
+    }
+
+    /// Renews the certificate if it is due. Returns whether it was renewed.
+    pub async fn renew_if_due(&mut self) -> Result<bool, Error> {
+        if !self.renewal_due(Utc::now()) {
+            return Ok(false);
+        }
+        let rs = self.provision().await;
+        let mut labels = common_labels().clone();
+        let result = if rs.is_ok() { "ok" } else { "failed" };
+        labels.push(("result", result.to_string()));
+        counter_inc(PROXY_ACME_ORDERS, 1, Some(&labels));
+        rs.map(|_| true)
+    }
+
+    /// Serves the kept certificate, then renews it until shutdown.
+    pub async fn run(mut self, mut shutdown_rx: watch::Receiver<ShutdownMessage>) {
+        match self.load() {
+            Ok(Some(expiry)) => info!("ProxySrv ACME certificate loaded, expires {expiry}"),
+            Ok(None) => info!(
+                "ProxySrv ACME no certificate in {:?}",
+                self.options.secrets_dir
+            ),
+            Err(e) => warn!("ProxySrv ACME kept certificate unusable {e:?}"),
+        }
+        loop {
+            let next_check = match self.renew_if_due().await {
+                Ok(_) => self.options.check_interval,
+                Err(e) => {
+                    warn!(
+                        "ProxySrv ACME order of {:?} failed {e:?}",
+                        self.options.domains
+                    );
+                    RETRY_INTERVAL.min(self.options.check_interval)
+                }
+            };
+            self.report_expiry();
+            tokio::select! {
+                _ = shutdown_rx.changed() => {
+                    info!("ProxySrv ACME manager shutdown");
+                    return;
+                }
+                _ = tokio::time::sleep(next_check) => {}
+            }
+        }
+    }
+}
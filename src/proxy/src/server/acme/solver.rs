@@ -0,0 +1,187 @@
+use async_trait::async_trait;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use dashmap::DashMap;
+use sha2::{Digest, Sha256};
+use std::io::Error;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+use tokio::process::Command;
+use tracing::info;
+
+pub const HTTP_01: &str = "http-01";
+pub const DNS_01: &str = "dns-01";
+
+/// `ChallengeSolver` proves to the ACME server that the proxy controls a domain. [`Http01Solver`]
+/// and [`DnsHookSolver`] come with the proxy, embedders may plug in a solver of their DNS
+/// provider.
+#[async_trait]
+pub trait ChallengeSolver: Send + Sync {
+    /// The ACME challenge type solved, [`HTTP_01`] or [`DNS_01`].
+    fn challenge_type(&self) -> &'static str;
+
+    /// Publishes the proof for `domain` before the ACME server is asked to validate it.
+    async fn present(
+        &self,
+        domain: &str,
+        token: &str,
+        key_authorization: &str,
+    ) -> Result<(), Error>;
+
+    /// Removes the proof once the challenge is validated or failed.
+    async fn cleanup(
+        &self,
+        domain: &str,
+        token: &str,
+        key_authorization: &str,
+    ) -> Result<(), Error>;
+}
+
+static HTTP01_TOKENS_ONCE: OnceLock<DashMap<String, String>> = OnceLock::new();
+
+fn http01_tokens() -> &'static DashMap<String, String> {
+    HTTP01_TOKENS_ONCE.get_or_init(DashMap::new)
+}
+
+/// The key authorization served at `/.well-known/acme-challenge/{token}`, `None` if no HTTP-01
+/// challenge is pending for `token`.
+pub fn http01_key_authorization(token: &str) -> Option<String> {
+    http01_tokens().get(token).map(|e| e.value().clone())
+}
+
+/// Answers HTTP-01 challenges from the REST API, which has to be reachable on port 80 of every
+/// domain, e.g. through the port mapping of the load balancer.
+#[derive(Debug, Default)]
+pub struct Http01Solver;
+
+#[async_trait]
+impl ChallengeSolver for Http01Solver {
+    fn challenge_type(&self) -> &'static str {
+        HTTP_01
+    }
+
+    async fn present(
+        &self,
+        _domain: &str,
+        token: &str,
+        key_authorization: &str,
+    ) -> Result<(), Error> {
+        http01_tokens().insert(token.to_string(), key_authorization.to_string());
+        Ok(())
+    }
+
+    async fn cleanup(
+        &self,
+        _domain: &str,
+        token: &str,
+        _key_authorization: &str,
+    ) -> Result<(), Error> {
+        http01_tokens().remove(token);
+        Ok(())
+    }
+}
+
+/// The TXT record name of the DNS-01 challenge of `domain`, a wildcard is validated on its base
+/// domain.
+pub fn dns01_record_name(domain: &str) -> String {
+    format!("_acme-challenge.{}", domain.trim_start_matches("*."))
+}
+
+/// The TXT record value of the DNS-01 challenge with `key_authorization`.
+pub fn dns01_record_value(key_authorization: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(key_authorization.as_bytes()))
+}
+
+/// Answers DNS-01 challenges with an executable, run as `hook present <name> <value>` to create
+/// the TXT record and `hook cleanup <name> <value>` to remove it. The hook returns once the
+/// record is visible to the ACME server, a non-zero exit fails the order.
+#[derive(Debug, Clone)]
+pub struct DnsHookSolver {
+    hook: PathBuf,
+}
+
+impl DnsHookSolver {
+    pub fn new(hook: PathBuf) -> Self {
+        Self { hook }
+    }
+
+    async fn run(&self, action: &str, domain: &str, key_authorization: &str) -> Result<(), Error> {
+        let name = dns01_record_name(domain);
+        let status = Command::new(&self.hook)
+            .arg(action)
+            .arg(&name)
+            .arg(dns01_record_value(key_authorization))
+            .status()
+            .await?;
+        info!("ProxySrv ACME dns hook {action} {name} exited with {status}");
+        if status.success() {
+            Ok(())
+        } else {
+            Err(Error::other(format!(
+                "dns hook {action} {name} exited with {status}"
+            )))
+        }
+    }
+}
+
+#[async_trait]
+impl ChallengeSolver for DnsHookSolver {
+    fn challenge_type(&self) -> &'static str {
+        DNS_01
+    }
+
+    async fn present(
+        &self,
+        domain: &str,
+        _token: &str,
+        key_authorization: &str,
+    ) -> Result<(), Error> {
+        self.run("present", domain, key_authorization).await
+    }
+
+    async fn cleanup(
+        &self,
+        domain: &str,
+        _token: &str,
+        key_authorization: &str,
+    ) -> Result<(), Error> {
+        self.run("cleanup", domain, key_authorization).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::server::acme::solver::*;
+
+    #[tokio::test]
+    pub async fn test_challenge_solvers() {
+        let solver = Http01Solver;
+        solver
+            .present("db.example.com", "token-a", "token-a.thumbprint")
+            .await
+            .unwrap();
+        assert_eq!(
+            http01_key_authorization("token-a").as_deref(),
+            Some("token-a.thumbprint")
+        );
+        solver
+            .cleanup("db.example.com", "token-a", "token-a.thumbprint")
+            .await
+            .unwrap();
+        assert_eq!(http01_key_authorization("token-a"), None);
+
+        assert_eq!(
+            dns01_record_name("*.db.example.com"),
+            "_acme-challenge.db.example.com"
+        );
+        assert_eq!(
+            dns01_record_value("token-b.thumbprint"),
+            "yORgftQMWXmBLxpVYSfipNFtR-YjqgJa7eada9_mpTs"
+        );
+        let hook = DnsHookSolver::new("false".into());
+        assert!(hook
+            .present("db.example.com", "token-b", "token-b.thumbprint")
+            .await
+            .is_err());
+    }
+}
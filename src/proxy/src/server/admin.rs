@@ -0,0 +1,581 @@
+use crate::backend::backend_mgr::BackendMgr;
+use crate::backend::quarantine::quarantine_registry;
+use crate::backend::replica::{replica_registry, BackendRole};
+use crate::backend::router::p2c::backend_conns;
+use crate::prost::common_proto::TenantKey;
+use crate::protocol::mysql::basic::Column;
+use crate::protocol::mysql::constants::CommandCode;
+use crate::protocol::mysql::error_codes::ErrorKind;
+use crate::protocol::mysql::packet::packet_writer::PacketWriter;
+use crate::protocol::mysql::packet::writers;
+use crate::server::command_policy::command_policy;
+use crate::server::session::session_registry;
+use crate::server::slow_log::{slow_query_log, tenant_label};
+
+use hashbrown::HashMap;
+use mysql_common::constants::{CapabilityFlags, ColumnFlags, ColumnType, StatusFlags};
+use std::io::Error;
+use tokio::io::AsyncWrite;
+
+/// Admin statements are short, longer queries skip parsing entirely.
+const ADMIN_STMT_MAX_LEN: usize = 128;
+const ADMIN_TABLE: &str = "proxy_admin";
+
+/// Statements answered by the proxy itself instead of being forwarded to the backend.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AdminStmt {
+    /// `SHOW PROXY SLOWLOG [LIMIT n]`, the slow queries of the scope.
+    ShowSlowLog { limit: Option<usize> },
+    /// `RESET PROXY SLOWLOG`, admin only.
+    ResetSlowLog,
+    /// `SHOW PROXY SESSIONS [LIMIT n]`, the heaviest sessions of the scope by memory first.
+    ShowSessions { limit: Option<usize> },
+    /// `KILL PROXY SESSION id`, of a session of the scope.
+    KillSession { id: u64 },
+    /// `SHOW PROXY BACKENDS [LIMIT n]`, every backend the router knows by address, admin only.
+    ShowBackends { limit: Option<usize> },
+    /// `SHOW PROXY POOLS [LIMIT n]`, the connection pools by backend address, admin only.
+    ShowPools { limit: Option<usize> },
+}
+
+pub fn parse_admin_stmt(sql: &[u8]) -> Option<AdminStmt> {
+    if sql.len() > ADMIN_STMT_MAX_LEN {
+        return None;
+    }
+    let sql = std::str::from_utf8(sql).ok()?.trim().trim_end_matches(';');
+    let tokens = sql.split_ascii_whitespace().collect::<Vec<_>>();
+    let keyword = |idx: usize, expected: &str| {
+        tokens
+            .get(idx)
+            .is_some_and(|token| token.eq_ignore_ascii_case(expected))
+    };
+    if !keyword(1, "PROXY") {
+        return None;
+    }
+    // `[LIMIT n]` after the three leading keywords.
+    let limit = || match tokens.len() {
+        3 => Some(None),
+        5 if keyword(3, "LIMIT") => tokens[4].parse::<usize>().ok().map(Some),
+        _ => None,
+    };
+    if keyword(0, "SHOW") && keyword(2, "SLOWLOG") {
+        limit().map(|limit| AdminStmt::ShowSlowLog { limit })
+    } else if keyword(0, "RESET") && keyword(2, "SLOWLOG") && tokens.len() == 3 {
+        Some(AdminStmt::ResetSlowLog)
+    } else if keyword(0, "SHOW") && keyword(2, "SESSIONS") {
+        limit().map(|limit| AdminStmt::ShowSessions { limit })
+    } else if keyword(0, "KILL") && keyword(2, "SESSION") && tokens.len() == 4 {
+        tokens[3]
+            .parse::<u64>()
+            .ok()
+            .map(|id| AdminStmt::KillSession { id })
+    } else if keyword(0, "SHOW") && keyword(2, "BACKENDS") {
+        limit().map(|limit| AdminStmt::ShowBackends { limit })
+    } else if keyword(0, "SHOW") && keyword(2, "POOLS") {
+        limit().map(|limit| AdminStmt::ShowPools { limit })
+    } else {
+        None
+    }
+}
+
+/// The tenants the admin statements of a tenant reach. Only the tenants a command policy makes
+/// admin, see [`crate::server::command_policy::CommandPolicy::is_admin`], reach every tenant.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AdminScope {
+    All,
+    /// The tenant of the session, by its label.
+    Tenant(String),
+}
+
+impl AdminScope {
+    pub fn of(tenant: &TenantKey) -> Self {
+        if command_policy().is_admin(tenant) {
+            AdminScope::All
+        } else {
+            AdminScope::Tenant(tenant_label(tenant))
+        }
+    }
+
+    /// The label of the only tenant in the scope, `None` for every tenant.
+    pub fn tenant(&self) -> Option<&str> {
+        match self {
+            AdminScope::All => None,
+            AdminScope::Tenant(label) => Some(label),
+        }
+    }
+
+    /// Whether the tenant labeled `tenant` is in the scope.
+    pub fn contains(&self, tenant: &str) -> bool {
+        match self {
+            AdminScope::All => true,
+            AdminScope::Tenant(label) => label == tenant,
+        }
+    }
+}
+
+fn admin_column(name: &str, column_type: ColumnType) -> Column {
+    Column {
+        table: ADMIN_TABLE.to_string(),
+        column: name.to_string(),
+        column_type,
+        column_flags: ColumnFlags::empty(),
+    }
+}
+
+/// Answers an admin statement over every tenant sent by a tenant that is not admin.
+async fn write_admin_denied<W>(
+    stmt: &str,
+    client_writer: &mut PacketWriter<W>,
+    client_capabilities: CapabilityFlags,
+) -> Result<(), Error>
+where
+    W: AsyncWrite + Send + Unpin,
+{
+    let message = format!("Access denied; {stmt} requires an admin command policy");
+    writers::write_err_packet(
+        ErrorKind::ER_SPECIFIC_ACCESS_DENIED_ERROR,
+        message.as_bytes(),
+        client_writer,
+        client_capabilities,
+    )
+    .await
+}
+
+/// Answers the command `com_code` of a session of `tenant` if it is an admin statement, which is
+/// then not forwarded to the backend. Returns whether the command was answered.
+pub async fn answer_admin_command<W>(
+    com_code: CommandCode,
+    payload: &[u8],
+    backend_mgr: &BackendMgr,
+    tenant: &TenantKey,
+    seq: u8,
+    client_writer: &mut PacketWriter<W>,
+    client_capabilities: CapabilityFlags,
+) -> Result<bool, Error>
+where
+    W: AsyncWrite + Send + Unpin,
+{
+    let Some(stmt) = (com_code == CommandCode::ComQuery)
+        .then(|| parse_admin_stmt(payload))
+        .flatten()
+    else {
+        return Ok(false);
+    };
+    handle_admin_stmt(
+        stmt,
+        backend_mgr,
+        tenant,
+        seq,
+        client_writer,
+        client_capabilities,
+    )
+    .await?;
+    Ok(true)
+}
+
+/// Writes the response of an admin statement of a session of `tenant`. `seq` is the sequence id
+/// of the client command.
+pub async fn handle_admin_stmt<W>(
+    stmt: AdminStmt,
+    backend_mgr: &BackendMgr,
+    tenant: &TenantKey,
+    seq: u8,
+    client_writer: &mut PacketWriter<W>,
+    client_capabilities: CapabilityFlags,
+) -> Result<(), Error>
+where
+    W: AsyncWrite + Send + Unpin,
+{
+    client_writer.set_seq(seq.wrapping_add(1));
+    let scope = AdminScope::of(tenant);
+    match stmt {
+        AdminStmt::ShowSlowLog { limit } => {
+            let columns = [
+                admin_column("id", ColumnType::MYSQL_TYPE_LONGLONG),
+                admin_column("time", ColumnType::MYSQL_TYPE_VAR_STRING),
+                admin_column("tenant", ColumnType::MYSQL_TYPE_VAR_STRING),
+                admin_column("user", ColumnType::MYSQL_TYPE_VAR_STRING),
+                admin_column("duration_ms", ColumnType::MYSQL_TYPE_LONGLONG),
+                admin_column("bytes", ColumnType::MYSQL_TYPE_LONGLONG),
+                admin_column("sql", ColumnType::MYSQL_TYPE_VAR_STRING),
+                admin_column("fingerprint", ColumnType::MYSQL_TYPE_VAR_STRING),
+            ];
+            let rows = slow_query_log()
+                .entries(limit, scope.tenant())
+                .into_iter()
+                .map(|entry| {
+                    vec![
+                        Some(entry.id.to_string()),
+                        Some(entry.time.format("%Y-%m-%d %H:%M:%S%.3f").to_string()),
+                        Some(entry.tenant),
+                        Some(entry.user),
+                        Some(entry.duration.as_millis().to_string()),
+                        Some(entry.bytes.to_string()),
+                        entry.sql,
+                        entry.fingerprint.map(|fingerprint| fingerprint.to_string()),
+                    ]
+                })
+                .collect::<Vec<_>>();
+            writers::write_text_result_set(&columns, &rows, client_writer, client_capabilities)
+                .await?;
+        }
+        AdminStmt::ResetSlowLog if scope != AdminScope::All => {
+            write_admin_denied("RESET PROXY SLOWLOG", client_writer, client_capabilities).await?;
+        }
+        AdminStmt::ResetSlowLog => {
+            slow_query_log().reset();
+            writers::write_ok_packet(client_writer, 0, 0, StatusFlags::SERVER_STATUS_AUTOCOMMIT)
+                .await?;
+        }
+        AdminStmt::ShowSessions { limit } => {
+            let columns = [
+                admin_column("id", ColumnType::MYSQL_TYPE_LONGLONG),
+                admin_column("tenant", ColumnType::MYSQL_TYPE_VAR_STRING),
+                admin_column("user", ColumnType::MYSQL_TYPE_VAR_STRING),
+                admin_column("connected_at", ColumnType::MYSQL_TYPE_VAR_STRING),
+                admin_column("memory_bytes", ColumnType::MYSQL_TYPE_LONGLONG),
+                admin_column("client_buffer_bytes", ColumnType::MYSQL_TYPE_LONGLONG),
+                admin_column("backend_buffer_bytes", ColumnType::MYSQL_TYPE_LONGLONG),
+                admin_column("stmt_cache_bytes", ColumnType::MYSQL_TYPE_LONGLONG),
+                admin_column("buffered_bytes", ColumnType::MYSQL_TYPE_LONGLONG),
+            ];
+            let rows = session_registry()
+                .top_by_memory(None)
+                .into_iter()
+                .filter(|session| scope.contains(&session.tenant))
+                .take(limit.unwrap_or(usize::MAX))
+                .map(|session| {
+                    vec![
+                        Some(session.id.to_string()),
+                        Some(session.tenant),
+                        Some(session.user),
+                        Some(session.connected_at),
+                        Some(session.memory_bytes.to_string()),
+                        Some(session.memory.client_buffer_bytes.to_string()),
+                        Some(session.memory.backend_buffer_bytes.to_string()),
+                        Some(session.memory.stmt_cache_bytes.to_string()),
+                        Some(session.buffered_bytes.to_string()),
+                    ]
+                })
+                .collect::<Vec<_>>();
+            writers::write_text_result_set(&columns, &rows, client_writer, client_capabilities)
+                .await?;
+        }
+        AdminStmt::KillSession { id } => {
+            let killed = match scope {
+                AdminScope::All => session_registry().kill(id),
+                AdminScope::Tenant(_) => session_registry().kill_of_tenant(id, tenant),
+            };
+            // The sessions of other tenants are unknown to the tenant, not denied to it.
+            if killed {
+                writers::write_ok_packet(
+                    client_writer,
+                    1,
+                    0,
+                    StatusFlags::SERVER_STATUS_AUTOCOMMIT,
+                )
+                .await?;
+            } else {
+                let message = format!("Unknown proxy session id: {id}");
+                writers::write_err_packet(
+                    ErrorKind::ER_NO_SUCH_THREAD,
+                    message.as_bytes(),
+                    client_writer,
+                    client_capabilities,
+                )
+                .await?;
+            }
+        }
+        AdminStmt::ShowBackends { .. } | AdminStmt::ShowPools { .. }
+            if scope != AdminScope::All =>
+        {
+            let stmt = match stmt {
+                AdminStmt::ShowBackends { .. } => "SHOW PROXY BACKENDS",
+                _ => "SHOW PROXY POOLS",
+            };
+            write_admin_denied(stmt, client_writer, client_capabilities).await?;
+        }
+        AdminStmt::ShowBackends { limit } => {
+            let columns = [
+                admin_column("addr", ColumnType::MYSQL_TYPE_VAR_STRING),
+                admin_column("cluster", ColumnType::MYSQL_TYPE_VAR_STRING),
+                admin_column("status", ColumnType::MYSQL_TYPE_VAR_STRING),
+                admin_column("role", ColumnType::MYSQL_TYPE_VAR_STRING),
+                admin_column("lag_ms", ColumnType::MYSQL_TYPE_LONGLONG),
+                admin_column("in_flight", ColumnType::MYSQL_TYPE_LONGLONG),
+                admin_column("quarantined", ColumnType::MYSQL_TYPE_TINY),
+                admin_column("consecutive_failures", ColumnType::MYSQL_TYPE_LONGLONG),
+            ];
+            let replicas = replica_registry()
+                .list()
+                .into_iter()
+                .map(|replica| (replica.addr.clone(), replica))
+                .collect::<HashMap<_, _>>();
+            let mut backends = backend_mgr.discovered_backends().await;
+            backends.sort_by(|a, b| a.addr.cmp(&b.addr));
+            let rows = backends
+                .into_iter()
+                .take(limit.unwrap_or(usize::MAX))
+                .map(|backend| {
+                    let replica = replicas.get(&backend.addr);
+                    let role = replica.map_or(BackendRole::Primary, |replica| replica.role);
+                    let lag_ms = replica.and_then(|replica| replica.lag_ms);
+                    let in_flight = backend_conns().in_flight(&backend.addr);
+                    let diagnostics = quarantine_registry().diagnostics(&backend.addr);
+                    let quarantined = diagnostics.as_ref().is_some_and(|d| d.quarantined);
+                    let failures = diagnostics.map_or(0, |d| d.consecutive_failures);
+                    vec![
+                        Some(backend.addr.clone()),
+                        Some(format!(
+                            "{}/{}",
+                            backend.cluster.namespace, backend.cluster.cluster_name
+                        )),
+                        Some(backend.status.as_str_name().to_string()),
+                        Some(format!("{role:?}").to_ascii_lowercase()),
+                        lag_ms.map(|lag_ms| lag_ms.to_string()),
+                        Some(in_flight.to_string()),
+                        Some((quarantined as u8).to_string()),
+                        Some(failures.to_string()),
+                    ]
+                })
+                .collect::<Vec<_>>();
+            writers::write_text_result_set(&columns, &rows, client_writer, client_capabilities)
+                .await?;
+        }
+        AdminStmt::ShowPools { limit } => {
+            let columns = [
+                admin_column("addr", ColumnType::MYSQL_TYPE_VAR_STRING),
+                admin_column("cluster", ColumnType::MYSQL_TYPE_VAR_STRING),
+                admin_column("status", ColumnType::MYSQL_TYPE_VAR_STRING),
+                admin_column("max_size", ColumnType::MYSQL_TYPE_LONGLONG),
+                admin_column("size", ColumnType::MYSQL_TYPE_LONGLONG),
+                admin_column("available", ColumnType::MYSQL_TYPE_LONGLONG),
+                admin_column("in_use", ColumnType::MYSQL_TYPE_LONGLONG),
+                admin_column("waiting", ColumnType::MYSQL_TYPE_LONGLONG),
+                admin_column("in_flight", ColumnType::MYSQL_TYPE_LONGLONG),
+            ];
+            let rows = backend_mgr
+                .pool_stats()
+                .into_iter()
+                .take(limit.unwrap_or(usize::MAX))
+                .map(|stats| {
+                    let pool = stats.pool;
+                    vec![
+                        Some(pool.addr),
+                        Some(pool.cluster),
+                        Some(pool.status),
+                        Some(pool.max_size.to_string()),
+                        Some(pool.size.to_string()),
+                        Some(pool.available.to_string()),
+                        Some(stats.in_use.to_string()),
+                        Some(pool.waiting.to_string()),
+                        Some(pool.in_flight.to_string()),
+                    ]
+                })
+                .collect::<Vec<_>>();
+            writers::write_text_result_set(&columns, &rows, client_writer, client_capabilities)
+                .await?;
+        }
+    }
+    client_writer.flush_all().await
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::backend_mgr::{BackendManagerOptions, BackendMgr};
+    use crate::backend::router::new_backend_router;
+    use crate::backend::test_tenant_key;
+    use crate::prost::common_proto::TenantKey;
+    use crate::protocol::mysql::constants::CommandCode;
+    use crate::protocol::mysql::error_codes::ErrorKind;
+    use crate::protocol::mysql::packet::packet_reader::PacketReader;
+    use crate::protocol::mysql::packet::packet_writer::PacketWriter;
+    use crate::protocol::mysql::packet::Packet;
+    use crate::server::admin::{answer_admin_command, parse_admin_stmt, AdminStmt};
+    use crate::server::command_policy::{command_policy, TenantCommandPolicy};
+    use crate::server::proxy_cli_args::{BackendConfigArgs, ProxyServerArgs};
+    use crate::server::session::session_registry;
+    use common::ShutdownMessage;
+    use mysql_common::constants::CapabilityFlags;
+    use tokio::sync::watch;
+
+    async fn static_backend_mgr() -> BackendMgr {
+        let (_shutdown_tx, shutdown_rx) = watch::channel(ShutdownMessage::Init);
+        let args = ProxyServerArgs {
+            backend: Some(BackendConfigArgs::Backend {
+                backend_addr: "127.0.0.1:3306".to_string(),
+            }),
+            ..Default::default()
+        };
+        let router = new_backend_router(&args, &shutdown_rx).await;
+        BackendMgr::new(router, BackendManagerOptions::default())
+    }
+
+    /// Runs the admin statement `sql` in a session of `tenant`, returns the response packets.
+    async fn run_admin(sql: &[u8], tenant: &TenantKey) -> Vec<Packet> {
+        let backend_mgr = static_backend_mgr().await;
+        let mut writer = PacketWriter::new(vec![]);
+        let capabilities = CapabilityFlags::CLIENT_PROTOCOL_41;
+        let answered = answer_admin_command(
+            CommandCode::ComQuery,
+            sql,
+            &backend_mgr,
+            tenant,
+            0,
+            &mut writer,
+            capabilities,
+        )
+        .await
+        .unwrap();
+        assert!(answered);
+        let mut reader = PacketReader::new(&writer.inner_writer[..]);
+        let mut packets = vec![];
+        while let Some((seq, packet)) = reader.next_async().await.unwrap() {
+            assert_eq!(usize::from(seq), packets.len() + 1);
+            packets.push(packet);
+        }
+        packets
+    }
+
+    fn err_code(packet: &Packet) -> Option<u16> {
+        packet
+            .is_err_packet()
+            .then(|| u16::from_le_bytes([packet[1], packet[2]]))
+    }
+
+    #[test]
+    pub fn test_parse_admin_stmt() {
+        assert_eq!(
+            parse_admin_stmt(b"show proxy slowlog"),
+            Some(AdminStmt::ShowSlowLog { limit: None })
+        );
+        assert_eq!(
+            parse_admin_stmt(b" SHOW PROXY SLOWLOG LIMIT 10;"),
+            Some(AdminStmt::ShowSlowLog { limit: Some(10) })
+        );
+        assert_eq!(
+            parse_admin_stmt(b"RESET PROXY SLOWLOG"),
+            Some(AdminStmt::ResetSlowLog)
+        );
+        assert_eq!(
+            parse_admin_stmt(b"show proxy sessions limit 5"),
+            Some(AdminStmt::ShowSessions { limit: Some(5) })
+        );
+        assert_eq!(
+            parse_admin_stmt(b"KILL PROXY SESSION 42"),
+            Some(AdminStmt::KillSession { id: 42 })
+        );
+        assert_eq!(
+            parse_admin_stmt(b"show proxy backends"),
+            Some(AdminStmt::ShowBackends { limit: None })
+        );
+        assert_eq!(
+            parse_admin_stmt(b"SHOW PROXY POOLS LIMIT 3"),
+            Some(AdminStmt::ShowPools { limit: Some(3) })
+        );
+        assert_eq!(parse_admin_stmt(b"SHOW PROXY POOLS 3"), None);
+        assert_eq!(parse_admin_stmt(b"KILL PROXY SESSION"), None);
+        assert_eq!(parse_admin_stmt(b"KILL 42"), None);
+        assert_eq!(parse_admin_stmt(b"SHOW PROXY SLOWLOG LIMIT x"), None);
+        assert_eq!(parse_admin_stmt(b"SHOW TABLES"), None);
+        assert_eq!(parse_admin_stmt(b"select * from slowlog"), None);
+    }
+
+    #[tokio::test]
+    pub async fn test_answer_admin_command() {
+        let backend_mgr = static_backend_mgr().await;
+        let tenant = test_tenant_key();
+        let mut writer = PacketWriter::new(vec![]);
+        // Other statements and admin statements in other commands go to the backend.
+        for (com_code, payload) in [
+            (CommandCode::ComQuery, &b"SELECT 1"[..]),
+            (CommandCode::ComStmtPrepare, b"SHOW PROXY SLOWLOG"),
+            (CommandCode::ComInitDB, b"SHOW PROXY SLOWLOG"),
+        ] {
+            let answered = answer_admin_command(
+                com_code,
+                payload,
+                &backend_mgr,
+                &tenant,
+                0,
+                &mut writer,
+                CapabilityFlags::CLIENT_PROTOCOL_41,
+            )
+            .await
+            .unwrap();
+            assert!(!answered);
+        }
+        assert!(writer.inner_writer.is_empty());
+    }
+
+    #[tokio::test]
+    pub async fn test_kill_scoped_to_tenant() {
+        let tenant = test_tenant_key();
+        let other = TenantKey {
+            cluster_name: "admin-other".to_string(),
+            ..tenant.clone()
+        };
+        let own_session = session_registry().register(&tenant, "own".to_string());
+        let other_session = session_registry().register(&other, "other".to_string());
+
+        // A tenant without an admin command policy cannot kill the sessions of others.
+        let kill_other = format!("KILL PROXY SESSION {}", other_session.id());
+        let packet = &run_admin(kill_other.as_bytes(), &tenant).await[0];
+        assert_eq!(err_code(packet), Some(ErrorKind::ER_NO_SUCH_THREAD as u16));
+        assert!(!other_session.is_killed());
+
+        let kill_own = format!("KILL PROXY SESSION {}", own_session.id());
+        let packet = &run_admin(kill_own.as_bytes(), &tenant).await[0];
+        assert!(packet.is_ok_packet());
+        assert!(own_session.is_killed());
+    }
+
+    #[tokio::test]
+    pub async fn test_reset_slow_log_denied() {
+        let tenant = TenantKey {
+            cluster_name: "admin-slowlog".to_string(),
+            ..test_tenant_key()
+        };
+        let packet = &run_admin(b"RESET PROXY SLOWLOG", &tenant).await[0];
+        assert_eq!(
+            err_code(packet),
+            Some(ErrorKind::ER_SPECIFIC_ACCESS_DENIED_ERROR as u16)
+        );
+    }
+
+    #[tokio::test]
+    pub async fn test_show_backends_and_pools() {
+        let tenant = TenantKey {
+            cluster_name: "admin-backends".to_string(),
+            ..test_tenant_key()
+        };
+        for sql in [&b"SHOW PROXY BACKENDS"[..], b"SHOW PROXY POOLS"] {
+            let packets = run_admin(sql, &tenant).await;
+            assert_eq!(packets.len(), 1);
+            assert_eq!(
+                err_code(&packets[0]),
+                Some(ErrorKind::ER_SPECIFIC_ACCESS_DENIED_ERROR as u16)
+            );
+        }
+
+        command_policy().set_tenant_policy(TenantCommandPolicy {
+            tenant: tenant.clone(),
+            allow: vec![],
+            deny: vec![],
+            replication: false,
+            admin: true,
+        });
+        // The column count, 8 columns and an EOF, the row of the static backend and an EOF.
+        let packets = run_admin(b"SHOW PROXY BACKENDS", &tenant).await;
+        assert_eq!(packets.len(), 12);
+        let row = &packets[10];
+        let row = String::from_utf8_lossy(row);
+        assert!(row.contains("127.0.0.1:3306"));
+        assert!(row.contains("primary"));
+        // No pool is open before the first session, only the 9 columns are sent.
+        let packets = run_admin(b"SHOW PROXY POOLS", &tenant).await;
+        assert_eq!(packets.len(), 12);
+        assert!(packets[11].is_eof_packet());
+        command_policy().remove_tenant_policy(&tenant);
+    }
+}
@@ -1,25 +1,35 @@
 use crate::async_packet_read;
-use crate::protocol::mysql::basic::{client_handshake_response, HandshakeResponse};
+use crate::backend::capability::{capability_cache, BackendCapabilities};
+use crate::backend::stream::{BackendReadHalf, BackendWriteHalf};
+use crate::protocol::mysql::basic::{
+    client_handshake_response, err_packet_message, ok_packet, HandshakeResponse, OkPacket,
+};
 use crate::protocol::mysql::charset::UTF8_MB4_GENERAL_CI;
 use crate::protocol::mysql::constants::AuthPluginName::UnKnowPluginName;
 use crate::protocol::mysql::constants::HeaderInfo;
 use crate::protocol::mysql::error_codes::ErrorKind;
+use crate::protocol::mysql::packet::compress::CompressCodec;
 use crate::protocol::mysql::packet::packet_reader::PacketReader;
 use crate::protocol::mysql::packet::packet_writer::PacketWriter;
 use crate::protocol::mysql::packet::{writers, Packet};
+use crate::server::auth::caching_sha2::{
+    encrypt_password, is_public_key_request, AuthMoreData, AUTH_MORE_DATA, REQUEST_PUBLIC_KEY,
+};
+use crate::server::auth::identity::MappedIdentity;
 use crate::server::auth::Authenticator;
-use crate::server::{default_capabilities, DEFAULT_BACKEND_VERSION};
+use crate::server::handshake_profile::HandshakeProfile;
+use crate::server::wrong_protocol::{reject_wrong_protocol, WrongProtocol, SNIFF_LEN};
 
 use async_trait::async_trait;
+use mysql_common::constants::{CapabilityFlags, StatusFlags};
 use mysql_common::io::ParseBuf;
-use mysql_common::packets::{AuthPlugin, ComChangeUserMoreData, ErrPacket};
+use mysql_common::packets::{AuthPlugin, AuthSwitchRequest, ComChangeUserMoreData};
 use mysql_common::proto::{MyDeserialize, MySerialize};
 use rustls::server::ServerConfig;
 use std::borrow::Cow;
 use std::io::{Error, Write};
 
 use tokio::io::{AsyncRead, AsyncWrite};
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio_rustls::rustls;
 use tracing::{debug, warn};
 
@@ -31,10 +41,11 @@ impl ProxyAuthenticator {
     async fn process_auth_switch_plugin<R, W>(
         &self,
         client_seq: u8,
-        backend_writer: &mut PacketWriter<OwnedWriteHalf>,
-        backend_reader: &mut PacketReader<OwnedReadHalf>,
+        backend_writer: &mut PacketWriter<BackendWriteHalf>,
+        backend_reader: &mut PacketReader<BackendReadHalf>,
         client_writer: &mut PacketWriter<W>,
         client_reader: &mut PacketReader<R>,
+        handshake_resp: &HandshakeResponse,
     ) -> Result<(), Error>
     where
         R: AsyncRead + Send + Unpin,
@@ -42,45 +53,367 @@ impl ProxyAuthenticator {
     {
         let (be_seq, pkt) = async_packet_read!(backend_reader);
         assert_eq!(AUTH_SWITCH_REQUEST, pkt[0]);
-        client_writer.set_seq(client_seq + 1);
+        let capabilities = handshake_resp.client_flag;
+        if let Some(identity) = handshake_resp.identity.as_ref() {
+            return self
+                .answer_auth_switch(
+                    identity,
+                    capabilities,
+                    (client_seq, be_seq),
+                    &pkt,
+                    backend_writer,
+                    backend_reader,
+                    client_writer,
+                )
+                .await;
+        }
+        // After a COM_CHANGE_USER the client leg is ahead of the backend leg, whose sequence
+        // restarted with the command.
+        let seq_offset = client_seq.wrapping_add(1).wrapping_sub(be_seq);
+        let auth_switch = auth_switch_request(&pkt)?;
+        client_writer.set_seq(client_seq.wrapping_add(1));
         client_writer.write_all(&pkt)?;
         client_writer.end_packet().await?;
         client_writer.flush_all().await?;
 
         // read auth_response from client (password_len + hash(password, salt))
-        let (_c_seq, auth_response) = async_packet_read!(client_reader);
-        backend_writer.set_seq(be_seq + 1);
+        let (c_seq, auth_response) = async_packet_read!(client_reader);
+        backend_writer.set_seq(c_seq.wrapping_sub(seq_offset));
         backend_writer.write_all(&auth_response)?;
         backend_writer.end_packet().await?;
         backend_writer.flush_all().await?;
 
-        let (l_seq, be_auth_pkt) = async_packet_read!(backend_reader);
-        client_writer.set_seq(l_seq);
+        let encrypt_cleartext =
+            capabilities.contains(CapabilityFlags::CLIENT_SSL) && !backend_reader.r.is_secure();
+        let (l_seq, be_auth_pkt) = self
+            .relay_auth_more_data(
+                seq_offset,
+                (scramble(auth_switch.plugin_data()), encrypt_cleartext),
+                backend_writer,
+                backend_reader,
+                client_writer,
+                client_reader,
+            )
+            .await?;
+        client_writer.set_seq(l_seq.wrapping_add(seq_offset));
+        match token_ok_packet(&be_auth_pkt, handshake_resp) {
+            Some(ok_packet) => {
+                writers::write_ok_packet_with_client_flags(client_writer, capabilities, ok_packet)
+                    .await?
+            }
+            None => {
+                client_writer.write_all(&be_auth_pkt)?;
+                client_writer.end_packet().await?;
+            }
+        }
+        client_writer.flush_all().await?;
+        auth_result(&be_auth_pkt, capabilities)
+    }
+
+    /// Relays the AuthMoreData exchanges of caching_sha2_password between the backend and the
+    /// client, until the final OK or ERR packet of the backend, returned with its sequence.
+    ///
+    /// The fast auth success is followed by the OK packet. A full authentication is answered by
+    /// the client with its password in clear text over TLS, or with a public key request and then
+    /// the password encrypted with the key the backend sent. The TLS of the client ends at the
+    /// proxy, so with `encrypt_cleartext` the proxy encrypts a clear text password with the key of
+    /// the backend itself, as the backend refuses it over an insecure connection.
+    async fn relay_auth_more_data<R, W>(
+        &self,
+        seq_offset: u8,
+        (scramble, encrypt_cleartext): (&[u8], bool),
+        backend_writer: &mut PacketWriter<BackendWriteHalf>,
+        backend_reader: &mut PacketReader<BackendReadHalf>,
+        client_writer: &mut PacketWriter<W>,
+        client_reader: &mut PacketReader<R>,
+    ) -> Result<(u8, Packet), Error>
+    where
+        R: AsyncRead + Send + Unpin,
+        W: AsyncWrite + Send + Unpin,
+    {
+        let (mut l_seq, mut be_auth_pkt) = async_packet_read!(backend_reader);
+        while be_auth_pkt[0] == AUTH_MORE_DATA {
+            let more_data = AuthMoreData::parse(&be_auth_pkt);
+            client_writer.set_seq(l_seq.wrapping_add(seq_offset));
+            client_writer.write_all(&be_auth_pkt)?;
+            client_writer.end_packet().await?;
+            client_writer.flush_all().await?;
+            if more_data != Some(AuthMoreData::FastAuthSuccess) {
+                let (c_seq, client_auth_pkt) = async_packet_read!(client_reader);
+                let be_seq = c_seq.wrapping_sub(seq_offset);
+                let cleartext = more_data == Some(AuthMoreData::PerformFullAuthentication)
+                    && !is_public_key_request(&client_auth_pkt);
+                let (be_seq, auth_data) = if cleartext && encrypt_cleartext {
+                    debug!("ProxySrv Auth encrypting the password with the backend public key");
+                    encrypt_with_backend_key(
+                        be_seq,
+                        &client_auth_pkt,
+                        scramble,
+                        backend_writer,
+                        backend_reader,
+                    )
+                    .await?
+                } else {
+                    (be_seq, client_auth_pkt.to_vec())
+                };
+                backend_writer.set_seq(be_seq);
+                backend_writer.write_all(&auth_data)?;
+                backend_writer.end_packet().await?;
+                backend_writer.flush_all().await?;
+            }
+            (l_seq, be_auth_pkt) = async_packet_read!(backend_reader);
+        }
+        Ok((l_seq, be_auth_pkt))
+    }
+
+    /// Answers the AuthSwitchRequest of the backend with the credential of a mapped identity. The
+    /// client already authenticated against the proxy, so it only receives the final OK or ERR.
+    #[allow(clippy::too_many_arguments)]
+    async fn answer_auth_switch<W>(
+        &self,
+        identity: &MappedIdentity,
+        capabilities: CapabilityFlags,
+        (client_seq, be_seq): (u8, u8),
+        auth_switch_pkt: &[u8],
+        backend_writer: &mut PacketWriter<BackendWriteHalf>,
+        backend_reader: &mut PacketReader<BackendReadHalf>,
+        client_writer: &mut PacketWriter<W>,
+    ) -> Result<(), Error>
+    where
+        W: AsyncWrite + Send + Unpin,
+    {
+        let be_auth_pkt = match answer_identity_auth_switch(
+            identity,
+            be_seq,
+            auth_switch_pkt,
+            backend_writer,
+            backend_reader,
+        )
+        .await
+        {
+            Ok(be_auth_pkt) => be_auth_pkt,
+            Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+                warn!(
+                    "ProxySrv identity {} backend user {} unexpected auth data",
+                    identity.client_user, identity.backend_user
+                );
+                client_writer.set_seq(client_seq + 1);
+                writers::write_err_packet(
+                    ErrorKind::ER_ACCESS_DENIED_ERROR,
+                    e.to_string().as_bytes(),
+                    client_writer,
+                    capabilities,
+                )
+                .await?;
+                client_writer.flush_all().await?;
+                return Err(e);
+            }
+            Err(e) => return Err(e),
+        };
+        client_writer.set_seq(client_seq + 1);
         client_writer.write_all(&be_auth_pkt)?;
         client_writer.end_packet().await?;
         client_writer.flush_all().await?;
-        if be_auth_pkt[0] == HeaderInfo::ErrHeader as u8 {
-            let err_packet =
-                ErrPacket::deserialize(default_capabilities(), &mut ParseBuf(&be_auth_pkt))
-                    .unwrap();
-            let srv_error = err_packet.server_error();
-            warn!(" {:?}", srv_error.message_str());
-            let msg_err = srv_error.message_ref();
-            let err_msg_str = String::from_utf8(msg_err.to_vec()).unwrap();
-            Err(Error::new(
-                std::io::ErrorKind::PermissionDenied,
-                err_msg_str,
-            ))
-        } else {
-            debug!(
-                "ProxySrv Auth continue_auth auth success={:?}",
-                be_auth_pkt[0]
-            );
-            Ok(())
+        auth_result(&be_auth_pkt, capabilities)
+    }
+}
+
+/// Answers the AuthSwitchRequest `auth_switch_pkt` of the backend, read with `be_seq`, with the
+/// credential of a mapped identity, and returns the final OK or ERR packet of the backend.
+async fn answer_identity_auth_switch(
+    identity: &MappedIdentity,
+    be_seq: u8,
+    auth_switch_pkt: &[u8],
+    backend_writer: &mut PacketWriter<BackendWriteHalf>,
+    backend_reader: &mut PacketReader<BackendReadHalf>,
+) -> Result<Packet, Error> {
+    let auth_switch = auth_switch_request(auth_switch_pkt)?;
+    let auth_data = identity
+        .auth_data(&auth_switch.auth_plugin(), auth_switch.plugin_data())
+        .unwrap_or_default();
+    backend_writer.set_seq(be_seq.wrapping_add(1));
+    backend_writer.write_all(&auth_data)?;
+    backend_writer.end_packet().await?;
+    backend_writer.flush_all().await?;
+
+    let (mut l_seq, mut be_auth_pkt) = async_packet_read!(backend_reader);
+    while be_auth_pkt[0] == AUTH_MORE_DATA {
+        match AuthMoreData::parse(&be_auth_pkt) {
+            Some(AuthMoreData::FastAuthSuccess) => {}
+            Some(AuthMoreData::PerformFullAuthentication) => {
+                let (be_seq, encrypted) = encrypt_with_backend_key(
+                    l_seq.wrapping_add(1),
+                    &identity.full_auth_password(),
+                    scramble(auth_switch.plugin_data()),
+                    backend_writer,
+                    backend_reader,
+                )
+                .await?;
+                backend_writer.set_seq(be_seq);
+                backend_writer.write_all(&encrypted)?;
+                backend_writer.end_packet().await?;
+                backend_writer.flush_all().await?;
+            }
+            _ => {
+                return Err(Error::new(
+                    std::io::ErrorKind::PermissionDenied,
+                    "unexpected backend authentication of the mapped user",
+                ))
+            }
         }
+        (l_seq, be_auth_pkt) = async_packet_read!(backend_reader);
+    }
+    Ok(be_auth_pkt)
+}
+
+/// Authenticates a backend connection as the mapped identity of a session without the client,
+/// e.g. the read leg of a session the read/write router splits. A connection in the command phase
+/// changes its user, a new one answers the initial handshake of the backend; both then answer
+/// the AuthSwitchRequest the unknown plugin triggers, as when relaying a client. `compress`
+/// requests the compressed protocol the way the session connection does.
+pub async fn authenticate_identity(
+    handshake_resp: &HandshakeResponse,
+    command_phase: bool,
+    compress: bool,
+    backend_writer: &mut PacketWriter<BackendWriteHalf>,
+    backend_reader: &mut PacketReader<BackendReadHalf>,
+) -> Result<(), Error> {
+    let Some(identity) = handshake_resp.identity.as_ref() else {
+        return Err(Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            "only mapped identities are authenticated by the proxy",
+        ));
+    };
+    let un_know_plugin_data = AuthPlugin::Other(Cow::from(UnKnowPluginName.as_ref().as_bytes()));
+    let mut capabilities = handshake_resp.client_flag;
+    let mut backend_compress = false;
+    let mut request = Vec::new();
+    if command_phase {
+        mysql_common::packets::ComChangeUser::new()
+            .with_user(handshake_resp.username.as_deref())
+            .with_database(handshake_resp.database.as_deref())
+            .with_more_data(Some(
+                ComChangeUserMoreData::new(UTF8_MB4_GENERAL_CI as u16)
+                    .with_auth_plugin(Some(un_know_plugin_data)),
+            ))
+            .serialize(&mut request);
+        backend_writer.reset_seq();
+    } else {
+        let (seq, handshake_init) = async_packet_read!(backend_reader);
+        let backend_caps = BackendCapabilities::parse(&handshake_init)?;
+        capability_cache().record(&backend_reader.r.peer_addr()?, backend_caps.clone());
+        backend_compress = compress
+            && backend_caps
+                .capabilities
+                .contains(CapabilityFlags::CLIENT_COMPRESS);
+        capabilities &= backend_caps.capabilities;
+        capabilities.remove(CapabilityFlags::CLIENT_SSL);
+        capabilities.set(CapabilityFlags::CLIENT_COMPRESS, backend_compress);
+        mysql_common::packets::HandshakeResponse::new(
+            None::<&[u8]>,
+            (8, 0, 36),
+            handshake_resp.username.as_deref(),
+            handshake_resp.database.as_deref(),
+            Some(un_know_plugin_data),
+            capabilities,
+            None,
+            handshake_resp.max_packet_len,
+        )
+        .serialize(&mut request);
+        backend_writer.set_seq(seq.wrapping_add(1));
+    }
+    backend_writer.write_all(&request)?;
+    backend_writer.end_packet().await?;
+    backend_writer.flush_all().await?;
+
+    let (be_seq, pkt) = async_packet_read!(backend_reader);
+    let be_auth_pkt = if pkt[0] == AUTH_SWITCH_REQUEST {
+        answer_identity_auth_switch(identity, be_seq, &pkt, backend_writer, backend_reader).await?
+    } else {
+        pkt
+    };
+    auth_result(&be_auth_pkt, capabilities)?;
+    if backend_compress {
+        let codec = CompressCodec::new(backend_reader.r.peer_addr()?);
+        backend_reader.enable_compression(codec.clone());
+        backend_writer.enable_compression(codec);
+    }
+    Ok(())
+}
+
+fn auth_switch_request(pkt: &[u8]) -> Result<AuthSwitchRequest<'_>, Error> {
+    AuthSwitchRequest::deserialize((), &mut ParseBuf(pkt))
+        .map_err(|e| Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+/// The scramble of an AuthSwitchRequest, whose plugin data is NUL terminated.
+fn scramble(plugin_data: &[u8]) -> &[u8] {
+    plugin_data.strip_suffix(&[0]).unwrap_or(plugin_data)
+}
+
+/// Asks the backend for its RSA public key, with sequence `be_seq`, and encrypts the NUL
+/// terminated `password` with it. Returns the encrypted password and the sequence to send it with.
+async fn encrypt_with_backend_key(
+    be_seq: u8,
+    password: &[u8],
+    scramble: &[u8],
+    backend_writer: &mut PacketWriter<BackendWriteHalf>,
+    backend_reader: &mut PacketReader<BackendReadHalf>,
+) -> Result<(u8, Vec<u8>), Error> {
+    backend_writer.set_seq(be_seq);
+    backend_writer.write_all(&[REQUEST_PUBLIC_KEY])?;
+    backend_writer.end_packet().await?;
+    backend_writer.flush_all().await?;
+    let (key_seq, key_pkt) = async_packet_read!(backend_reader);
+    let Some(AuthMoreData::PublicKey(pem)) = AuthMoreData::parse(&key_pkt) else {
+        return Err(Error::new(
+            std::io::ErrorKind::InvalidData,
+            "backend did not send its RSA public key",
+        ));
+    };
+    let encrypted = encrypt_password(password, scramble, pem)?;
+    Ok((key_seq.wrapping_add(1), encrypted))
+}
+
+/// The outcome of the final packet of the backend authentication, `capabilities` are the ones
+/// negotiated by the session.
+fn auth_result(be_auth_pkt: &[u8], capabilities: CapabilityFlags) -> Result<(), Error> {
+    if be_auth_pkt[0] == HeaderInfo::ErrHeader as u8 {
+        let err_msg_str = err_packet_message(be_auth_pkt, capabilities)
+            .map_err(|e| Error::new(std::io::ErrorKind::InvalidData, e))?;
+        warn!(" {:?}", err_msg_str);
+        Err(Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            err_msg_str,
+        ))
+    } else {
+        debug!(
+            "ProxySrv Auth continue_auth auth success={:?}",
+            be_auth_pkt[0]
+        );
+        Ok(())
     }
 }
 
+/// The final OK packet of an authentication with the reconnect token of the client as its info,
+/// `None` if there is no token to hand out or the backend filled the info already.
+fn token_ok_packet(be_auth_pkt: &[u8], handshake_resp: &HandshakeResponse) -> Option<OkPacket> {
+    let token = handshake_resp.reconnect_token.as_ref()?;
+    if be_auth_pkt[0] != HeaderInfo::OKHeader as u8 {
+        return None;
+    }
+    let (rest, ok_packet) = ok_packet(be_auth_pkt, handshake_resp.client_flag).ok()?;
+    let untouched = rest.is_empty()
+        && ok_packet.info.is_empty()
+        && !ok_packet
+            .status_flags
+            .contains(StatusFlags::SERVER_SESSION_STATE_CHANGED);
+    untouched.then(|| OkPacket {
+        info: token.clone(),
+        ..ok_packet
+    })
+}
+
 /// `reset_handshake_plugin` Reset the plugin_name of HandshakeResponse.
 ///
 /// The authentication phase involves  - client, proxy, and backend. The proxy has to route to the
@@ -90,14 +423,23 @@ impl ProxyAuthenticator {
 /// the backend for authentication. The authentication fails because the scramble (random string)
 /// generated during the two handshakes is different. So, we need to trigger an AuthSwitchRequest to
 /// get the correct auth response from the client.
+///
+/// `backend_compress` requests the compressed protocol from the backend, regardless of what the
+/// client asked for. Capabilities the backend did not advertise, or the listener of the client
+/// disabled, are not requested.
 fn reset_handshake_plugin(
     packet: &[u8],
     handshake_response: &HandshakeResponse,
+    backend_caps: &BackendCapabilities,
+    backend_compress: bool,
 ) -> Result<Vec<u8>, Error> {
     let curr_user = handshake_response.username.clone();
     let max_pkt_len = handshake_response.max_packet_len;
     mysql_common::packets::HandshakeResponse::deserialize((), &mut ParseBuf(packet))
         .map(|pkt| {
+            let mut capabilities =
+                pkt.capabilities() & handshake_response.client_flag & backend_caps.capabilities;
+            capabilities.set(CapabilityFlags::CLIENT_COMPRESS, backend_compress);
             let un_know_plugin_rsp = mysql_common::packets::HandshakeResponse::new(
                 Some(pkt.scramble_buf()),
                 (8, 0, 36),
@@ -106,7 +448,7 @@ fn reset_handshake_plugin(
                 Some(AuthPlugin::Other(Cow::from(
                     UnKnowPluginName.as_ref().as_bytes(),
                 ))),
-                pkt.capabilities(),
+                capabilities,
                 pkt.connect_attributes(),
                 max_pkt_len,
             );
@@ -121,8 +463,8 @@ fn reset_handshake_plugin(
 impl Authenticator for ProxyAuthenticator {
     async fn continue_auth<R, W>(
         &self,
-        backend_writer: &mut PacketWriter<OwnedWriteHalf>,
-        backend_reader: &mut PacketReader<OwnedReadHalf>,
+        backend_writer: &mut PacketWriter<BackendWriteHalf>,
+        backend_reader: &mut PacketReader<BackendReadHalf>,
         client_writer: &mut PacketWriter<W>,
         client_reader: &mut PacketReader<R>,
         client_seq: u8,
@@ -155,41 +497,82 @@ impl Authenticator for ProxyAuthenticator {
             backend_reader,
             client_writer,
             client_reader,
+            handshake_resp,
         )
         .await
     }
 
+    async fn resume_auth<W>(
+        &self,
+        backend_writer: &mut PacketWriter<BackendWriteHalf>,
+        backend_reader: &mut PacketReader<BackendReadHalf>,
+        client_writer: &mut PacketWriter<W>,
+        client_seq: u8,
+        handshake_resp: &HandshakeResponse,
+    ) -> Result<bool, Error>
+    where
+        W: AsyncWrite + Send + Unpin,
+    {
+        writers::write_reset_connection(backend_writer).await?;
+        let (_be_seq, mut be_rsp_pkt) = async_packet_read!(backend_reader);
+        backend_writer.reset_seq();
+        if let Some(database) = handshake_resp.database.as_deref() {
+            if be_rsp_pkt[0] == HeaderInfo::OKHeader as u8 && !database.is_empty() {
+                writers::write_init_db(backend_writer, database).await?;
+                (_, be_rsp_pkt) = async_packet_read!(backend_reader);
+                backend_writer.reset_seq();
+            }
+        }
+        if be_rsp_pkt[0] != HeaderInfo::OKHeader as u8 {
+            // e.g. the database is gone, the client is authenticated as usual and gets the error.
+            debug!("ProxySrv Auth resume_auth refused by the backend, authenticating");
+            return Ok(false);
+        }
+        let capabilities = handshake_resp.client_flag;
+        let status_flags = ok_packet(&be_rsp_pkt, capabilities)
+            .map(|(_, ok_packet)| ok_packet.status_flags)
+            .unwrap_or(StatusFlags::SERVER_STATUS_AUTOCOMMIT);
+        let ok_packet = OkPacket {
+            status_flags: status_flags - StatusFlags::SERVER_SESSION_STATE_CHANGED,
+            info: handshake_resp.reconnect_token.clone().unwrap_or_default(),
+            ..Default::default()
+        };
+        client_writer.set_seq(client_seq.wrapping_add(1));
+        writers::write_ok_packet_with_client_flags(client_writer, capabilities, ok_packet).await?;
+        client_writer.flush_all().await?;
+        Ok(true)
+    }
+
     async fn initial_handshake<R, W>(
         &self,
         conn_id: u64,
         scramble: [u8; 20],
         client_reader: &mut PacketReader<R>,
         client_writer: &mut PacketWriter<W>,
+        profile: &HandshakeProfile,
         #[cfg(feature = "tls")] tls_conf: &Option<std::sync::Arc<ServerConfig>>,
     ) -> Result<(u8, HandshakeResponse, Packet), Error>
     where
         R: AsyncRead + Send + Unpin,
         W: AsyncWrite + Send + Unpin,
     {
-        let server_version_bytes = DEFAULT_BACKEND_VERSION;
         // 1. The ProxyServer sends an initial handshake packet to the client.
         #[cfg(feature = "tls")]
-        writers::write_initial_handshake(
-            client_writer,
-            conn_id,
-            scramble,
-            server_version_bytes,
-            tls_conf,
-        )
-        .await?;
-        #[cfg(not(feature = "tls"))]
-        writers::write_initial_handshake(client_writer, conn_id, scramble, server_version_bytes)
+        writers::write_initial_handshake(client_writer, conn_id, scramble, profile, tls_conf)
             .await?;
-        // 2. The ProxyServer reads the client's HandshakeResponse.
+        #[cfg(not(feature = "tls"))]
+        writers::write_initial_handshake(client_writer, conn_id, scramble, profile).await?;
+        // 2. The ProxyServer reads the client's HandshakeResponse, unless it speaks another
+        // protocol.
+        if let Some(protocol) = WrongProtocol::sniff(client_reader.peek(SNIFF_LEN).await?) {
+            return Err(reject_wrong_protocol(profile.listener, protocol));
+        }
         if let Some((seq, client_handshake_rsp_pkt)) = client_reader.next_async().await? {
             let (_, mut handshake_resp) =
                 client_handshake_response(&client_handshake_rsp_pkt, false).unwrap();
             handshake_resp.change_tenant_if_need();
+            // Clients must not use what the listener did not offer.
+            handshake_resp.client_flag &= profile.capabilities() | CapabilityFlags::CLIENT_SSL;
             Ok((seq, handshake_resp, client_handshake_rsp_pkt))
         } else {
             warn!("ProxySrv Failed to read client HandshakeResponse");
@@ -197,6 +580,7 @@ impl Authenticator for ProxyAuthenticator {
                 ErrorKind::ER_ACCESS_DENIED_ERROR,
                 "peer terminated connection".as_bytes(),
                 client_writer,
+                profile.capabilities(),
             )
             .await?;
             Err(Error::new(
@@ -208,21 +592,34 @@ impl Authenticator for ProxyAuthenticator {
 
     async fn reply_handshake_response<R, W>(
         &self,
-        backend_writer: &mut PacketWriter<OwnedWriteHalf>,
-        backend_reader: &mut PacketReader<OwnedReadHalf>,
+        backend_writer: &mut PacketWriter<BackendWriteHalf>,
+        backend_reader: &mut PacketReader<BackendReadHalf>,
         client_writer: &mut PacketWriter<W>,
         client_reader: &mut PacketReader<R>,
         client_seq: u8,
         handshake_resp_pair: (&[u8], &HandshakeResponse),
+        backend_compress: bool,
     ) -> Result<(), Error>
     where
         R: AsyncRead + Send + Unpin,
         W: AsyncWrite + Send + Unpin,
     {
         // 1. ProxyServer reads initial handshake packets from the backend.
-        let (_seq_val, _handshake_init) = async_packet_read!(backend_reader);
+        let (_seq_val, handshake_init) = async_packet_read!(backend_reader);
+        let backend_addr = backend_reader.r.peer_addr()?;
+        let backend_caps = BackendCapabilities::parse(&handshake_init)?;
+        capability_cache().record(&backend_addr, backend_caps.clone());
+        let backend_compress = backend_compress
+            && backend_caps
+                .capabilities
+                .contains(CapabilityFlags::CLIENT_COMPRESS);
         let (packet_bytes, client_handshake_rsp) = handshake_resp_pair;
-        let new_packet = reset_handshake_plugin(packet_bytes, client_handshake_rsp)?;
+        let new_packet = reset_handshake_plugin(
+            packet_bytes,
+            client_handshake_rsp,
+            &backend_caps,
+            backend_compress,
+        )?;
 
         backend_writer.set_seq(client_seq);
         backend_writer.write_all(&new_packet)?;
@@ -234,7 +631,16 @@ impl Authenticator for ProxyAuthenticator {
             backend_reader,
             client_writer,
             client_reader,
+            client_handshake_rsp,
         )
-        .await
+        .await?;
+        // The compressed protocol starts right after the OK packet of the authentication.
+        if backend_compress {
+            debug!("ProxySrv backend compression enabled {backend_addr}");
+            let codec = CompressCodec::new(backend_addr);
+            backend_reader.enable_compression(codec.clone());
+            backend_writer.enable_compression(codec);
+        }
+        Ok(())
     }
 }
@@ -0,0 +1,138 @@
+use aws_lc_rs::rsa::{OaepPublicEncryptingKey, PublicEncryptingKey, OAEP_SHA1_MGF1SHA1};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use std::io::{Error, ErrorKind};
+
+/// The header of the AuthMoreData packets of caching_sha2_password.
+pub const AUTH_MORE_DATA: u8 = 0x01;
+/// Sent by the client to ask the server for its RSA public key.
+pub const REQUEST_PUBLIC_KEY: u8 = 0x02;
+const FAST_AUTH_SUCCESS: u8 = 0x03;
+const PERFORM_FULL_AUTHENTICATION: u8 = 0x04;
+
+/// An AuthMoreData packet of the server during a caching_sha2_password authentication.
+/// see: https://dev.mysql.com/doc/dev/mysql-server/latest/page_caching_sha2_authentication_exchanges.html
+#[derive(Debug, PartialEq, Eq)]
+pub enum AuthMoreData<'a> {
+    /// The scramble matched the cache of the server, the OK packet follows.
+    FastAuthSuccess,
+    /// The server needs the password, in clear text over a secure connection or encrypted with
+    /// its RSA public key.
+    PerformFullAuthentication,
+    /// The PEM of the RSA public key, answering a key request.
+    PublicKey(&'a [u8]),
+}
+
+impl<'a> AuthMoreData<'a> {
+    pub fn parse(pkt: &'a [u8]) -> Option<Self> {
+        match pkt {
+            [AUTH_MORE_DATA, FAST_AUTH_SUCCESS] => Some(AuthMoreData::FastAuthSuccess),
+            [AUTH_MORE_DATA, PERFORM_FULL_AUTHENTICATION] => {
+                Some(AuthMoreData::PerformFullAuthentication)
+            }
+            [AUTH_MORE_DATA, key @ ..] if key.starts_with(b"-----BEGIN") => {
+                Some(AuthMoreData::PublicKey(key))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Whether the client answered a full authentication request with a key request rather than its
+/// password.
+pub fn is_public_key_request(pkt: &[u8]) -> bool {
+    pkt == [REQUEST_PUBLIC_KEY]
+}
+
+/// The DER of a PEM encoded public key.
+fn pem_to_der(pem: &[u8]) -> Result<Vec<u8>, Error> {
+    let pem = std::str::from_utf8(pem).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+    let body: String = pem
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with("-----"))
+        .collect();
+    STANDARD
+        .decode(body)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, e))
+}
+
+/// Encrypts the NUL terminated `password` for a full authentication the way the server expects
+/// it: XOR-ed with `scramble`, then with the RSA public key `pem` of the server, RSA-OAEP.
+pub fn encrypt_password(password: &[u8], scramble: &[u8], pem: &[u8]) -> Result<Vec<u8>, Error> {
+    if scramble.is_empty() {
+        return Err(Error::new(ErrorKind::InvalidData, "empty scramble"));
+    }
+    let public_key = PublicEncryptingKey::from_der(&pem_to_der(pem)?)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, format!("RSA public key: {e}")))?;
+    let key = OaepPublicEncryptingKey::new(public_key)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, format!("RSA public key: {e}")))?;
+    let plaintext: Vec<u8> = password
+        .iter()
+        .zip(scramble.iter().cycle())
+        .map(|(byte, salt)| byte ^ salt)
+        .collect();
+    let mut ciphertext = vec![0; key.ciphertext_size()];
+    let len = key
+        .encrypt(&OAEP_SHA1_MGF1SHA1, &plaintext, &mut ciphertext, None)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, format!("RSA encrypt: {e}")))?
+        .len();
+    ciphertext.truncate(len);
+    Ok(ciphertext)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::server::auth::caching_sha2::{
+        encrypt_password, is_public_key_request, AuthMoreData,
+    };
+    use aws_lc_rs::encoding::AsDer;
+    use aws_lc_rs::rsa::{
+        KeySize, OaepPrivateDecryptingKey, PrivateDecryptingKey, OAEP_SHA1_MGF1SHA1,
+    };
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine;
+
+    #[test]
+    pub fn test_caching_sha2() {
+        assert_eq!(
+            AuthMoreData::parse(&[0x01, 0x03]),
+            Some(AuthMoreData::FastAuthSuccess)
+        );
+        assert_eq!(
+            AuthMoreData::parse(&[0x01, 0x04]),
+            Some(AuthMoreData::PerformFullAuthentication)
+        );
+        assert_eq!(AuthMoreData::parse(&[0x00, 0x03]), None);
+        assert!(is_public_key_request(&[0x02]));
+        assert!(!is_public_key_request(b"secret\0"));
+
+        let private_key = PrivateDecryptingKey::generate(KeySize::Rsa2048).unwrap();
+        let der = private_key.public_key().as_der().unwrap();
+        let pem = format!(
+            "-----BEGIN PUBLIC KEY-----\n{}\n-----END PUBLIC KEY-----\n",
+            STANDARD.encode(der.as_ref())
+        );
+        let mut key_pkt = vec![0x01];
+        key_pkt.extend_from_slice(pem.as_bytes());
+        assert_eq!(
+            AuthMoreData::parse(&key_pkt),
+            Some(AuthMoreData::PublicKey(pem.as_bytes()))
+        );
+
+        let scramble = b"0123456789abcdefghij";
+        let encrypted = encrypt_password(b"secret\0", scramble, pem.as_bytes()).unwrap();
+        let private_key = OaepPrivateDecryptingKey::new(private_key).unwrap();
+        let mut plaintext = vec![0; private_key.min_output_size()];
+        let decrypted = private_key
+            .decrypt(&OAEP_SHA1_MGF1SHA1, &encrypted, &mut plaintext, None)
+            .unwrap();
+        let password: Vec<u8> = decrypted
+            .iter()
+            .zip(scramble.iter().cycle())
+            .map(|(byte, salt)| byte ^ salt)
+            .collect();
+        assert_eq!(password, b"secret\0");
+        assert!(encrypt_password(b"secret\0", scramble, b"not a key").is_err());
+    }
+}
@@ -0,0 +1,268 @@
+use crate::prost::common_proto::TenantKey;
+use crate::protocol::mysql::error_codes::ErrorKind;
+use crate::protocol::mysql::packet::packet_writer::PacketWriter;
+use crate::protocol::mysql::packet::writers;
+use crate::server::recent_errors::recent_errors;
+
+use common::metrics::metric_def::PROXY_CLIENT_ACL_REJECTED;
+use common::metrics::{common_labels, counter_inc};
+use dashmap::DashMap;
+use ipnet::IpNet;
+use mysql_common::constants::CapabilityFlags;
+use serde::{Deserialize, Serialize};
+use std::io::Error;
+use std::net::IpAddr;
+use std::sync::{OnceLock, RwLock};
+use tokio::io::AsyncWrite;
+use tracing::{info, warn};
+
+/// Client networks allowed and denied, as CIDRs or IP addresses. A client is denied if a deny
+/// entry matches it, or if there are allow entries and none matches it.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClientAclRules {
+    #[serde(default)]
+    pub allow: Vec<String>,
+    #[serde(default)]
+    pub deny: Vec<String>,
+}
+
+/// The client networks of one tenant, checked in addition to the global ones.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TenantClientAcl {
+    pub tenant: TenantKey,
+    #[serde(flatten)]
+    pub rules: ClientAclRules,
+}
+
+fn parse_nets(entries: &[String]) -> Result<Vec<IpNet>, Error> {
+    entries
+        .iter()
+        .map(|entry| entry.trim())
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            entry
+                .parse::<IpNet>()
+                .or_else(|_| entry.parse::<IpAddr>().map(IpNet::from))
+                .map_err(|_| {
+                    Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        format!("invalid client network {entry}"),
+                    )
+                })
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, Default)]
+struct CompiledRules {
+    rules: ClientAclRules,
+    allow: Vec<IpNet>,
+    deny: Vec<IpNet>,
+}
+
+impl CompiledRules {
+    fn new(rules: ClientAclRules) -> Result<Self, Error> {
+        Ok(Self {
+            allow: parse_nets(&rules.allow)?,
+            deny: parse_nets(&rules.deny)?,
+            rules,
+        })
+    }
+
+    fn is_allowed(&self, ip: IpAddr) -> bool {
+        // An IPv4 client of a dual-stack listener shows up as an IPv4-mapped IPv6 address.
+        let ip = ip.to_canonical();
+        !self.deny.iter().any(|net| net.contains(&ip))
+            && (self.allow.is_empty() || self.allow.iter().any(|net| net.contains(&ip)))
+    }
+}
+
+impl ClientAclRules {
+    pub fn validate(&self) -> Result<(), Error> {
+        CompiledRules::new(self.clone()).map(|_| ())
+    }
+}
+
+/// `ClientAcl` restricts the client addresses that may reach the tenants through the proxy. The
+/// global lists are checked before the handshake, the lists of the tenant once the handshake
+/// response tells the tenant. Tenant lists are managed by the control plane through the REST
+/// API.
+pub struct ClientAcl {
+    global: RwLock<CompiledRules>,
+    tenants: DashMap<TenantKey, CompiledRules>,
+}
+
+static CLIENT_ACL_ONCE: OnceLock<ClientAcl> = OnceLock::new();
+
+/// Initializes the global client ACL, must be called before the first client is accepted.
+pub fn init_client_acl(rules: ClientAclRules) -> Result<&'static ClientAcl, Error> {
+    let global = CompiledRules::new(rules)?;
+    Ok(CLIENT_ACL_ONCE.get_or_init(|| ClientAcl::with_rules(global)))
+}
+
+pub fn client_acl() -> &'static ClientAcl {
+    CLIENT_ACL_ONCE.get_or_init(|| ClientAcl::with_rules(CompiledRules::default()))
+}
+
+fn record_rejected(scope: &str, message: &str) {
+    warn!("ProxySrv session rejected: {message}");
+    recent_errors().record("client_acl", message.to_string());
+    let mut labels = common_labels().clone();
+    labels.push(("scope", scope.to_string()));
+    counter_inc(PROXY_CLIENT_ACL_REJECTED, 1, Some(&labels));
+}
+
+impl ClientAcl {
+    pub fn new(rules: ClientAclRules) -> Result<Self, Error> {
+        Ok(Self::with_rules(CompiledRules::new(rules)?))
+    }
+
+    fn with_rules(global: CompiledRules) -> Self {
+        Self {
+            global: RwLock::new(global),
+            tenants: DashMap::new(),
+        }
+    }
+
+    pub fn global_rules(&self) -> ClientAclRules {
+        self.global.read().unwrap().rules.clone()
+    }
+
+    pub fn set_global_rules(&self, rules: ClientAclRules) -> Result<(), Error> {
+        let compiled = CompiledRules::new(rules)?;
+        info!("ProxySrv client acl global {:?}", compiled.rules);
+        *self.global.write().unwrap() = compiled;
+        Ok(())
+    }
+
+    pub fn set_tenant_acl(&self, acl: TenantClientAcl) -> Result<(), Error> {
+        let compiled = CompiledRules::new(acl.rules)?;
+        info!(
+            "ProxySrv client acl set {:?} {:?}",
+            acl.tenant, compiled.rules
+        );
+        self.tenants.insert(acl.tenant, compiled);
+        Ok(())
+    }
+
+    pub fn remove_tenant_acl(&self, tenant: &TenantKey) -> Option<TenantClientAcl> {
+        info!("ProxySrv client acl removed {:?}", tenant);
+        self.tenants
+            .remove(tenant)
+            .map(|(tenant, compiled)| TenantClientAcl {
+                tenant,
+                rules: compiled.rules,
+            })
+    }
+
+    pub fn list(&self) -> Vec<TenantClientAcl> {
+        self.tenants
+            .iter()
+            .map(|e| TenantClientAcl {
+                tenant: e.key().clone(),
+                rules: e.value().rules.clone(),
+            })
+            .collect()
+    }
+
+    /// Checks a client against the global lists, returns the message it is rejected with.
+    pub fn check_client(&self, ip: IpAddr) -> Result<(), String> {
+        if self.global.read().unwrap().is_allowed(ip) {
+            return Ok(());
+        }
+        let message = format!("Host '{ip}' is not allowed to connect to this proxy");
+        record_rejected("global", &message);
+        Err(message)
+    }
+
+    /// Checks a client against the lists of `tenant`, returns the message it is rejected with.
+    pub fn check_tenant(&self, tenant: &TenantKey, ip: IpAddr) -> Result<(), String> {
+        if self
+            .tenants
+            .get(tenant)
+            .map_or(true, |compiled| compiled.is_allowed(ip))
+        {
+            return Ok(());
+        }
+        let message = format!(
+            "Host '{ip}' is not allowed to connect to cluster {}/{}",
+            tenant.namespace, tenant.cluster_name
+        );
+        record_rejected("tenant", &message);
+        Err(message)
+    }
+}
+
+/// Answers a client the ACL rejected with ER_HOST_NOT_PRIVILEGED, in place of the initial
+/// handshake or of the OK of its handshake response.
+pub async fn write_host_denied_err<W>(
+    message: &str,
+    client_writer: &mut PacketWriter<W>,
+    client_capabilities: CapabilityFlags,
+) -> Result<(), Error>
+where
+    W: AsyncWrite + Send + Unpin,
+{
+    writers::write_err_packet(
+        ErrorKind::ER_HOST_NOT_PRIVILEGED,
+        message.as_bytes(),
+        client_writer,
+        client_capabilities,
+    )
+    .await?;
+    client_writer.flush_all().await
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::prost::common_proto::TenantKey;
+    use crate::server::auth::client_acl::{ClientAcl, ClientAclRules, TenantClientAcl};
+    use std::net::IpAddr;
+
+    fn ip(addr: &str) -> IpAddr {
+        addr.parse().unwrap()
+    }
+
+    #[test]
+    pub fn test_client_acl() {
+        let acl = ClientAcl::new(ClientAclRules {
+            allow: vec![],
+            deny: vec!["203.0.113.0/24".to_string()],
+        })
+        .unwrap();
+        assert!(acl.check_client(ip("198.51.100.7")).is_ok());
+        assert!(acl.check_client(ip("203.0.113.9")).is_err());
+        assert!(acl.check_client(ip("::ffff:203.0.113.9")).is_err());
+
+        let tenant = TenantKey {
+            namespace: "client-acl".to_string(),
+            cluster_name: "c1".to_string(),
+            ..Default::default()
+        };
+        assert!(acl.check_tenant(&tenant, ip("198.51.100.7")).is_ok());
+        acl.set_tenant_acl(TenantClientAcl {
+            tenant: tenant.clone(),
+            rules: ClientAclRules {
+                allow: vec!["10.0.0.0/8".to_string(), "192.0.2.1".to_string()],
+                deny: vec!["10.9.0.0/16".to_string()],
+            },
+        })
+        .unwrap();
+        assert!(acl.check_tenant(&tenant, ip("10.1.2.3")).is_ok());
+        assert!(acl.check_tenant(&tenant, ip("192.0.2.1")).is_ok());
+        assert!(acl.check_tenant(&tenant, ip("10.9.2.3")).is_err());
+        let e = acl.check_tenant(&tenant, ip("198.51.100.7")).unwrap_err();
+        assert!(
+            e.contains("198.51.100.7") && e.contains("client-acl/c1"),
+            "{e}"
+        );
+        assert!(acl.remove_tenant_acl(&tenant).is_some());
+        assert!(acl.check_tenant(&tenant, ip("198.51.100.7")).is_ok());
+
+        let invalid = ClientAclRules {
+            allow: vec!["10.0.0.0/33".to_string()],
+            deny: vec![],
+        };
+        assert!(acl.set_global_rules(invalid).is_err());
+    }
+}
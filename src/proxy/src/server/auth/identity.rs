@@ -0,0 +1,322 @@
+use crate::backend::handshake_tenant_key;
+use crate::prost::common_proto::TenantKey;
+use crate::protocol::mysql::basic::HandshakeResponse;
+use crate::protocol::mysql::constants::{AuthPluginName, CommandCode};
+use crate::protocol::mysql::error_codes;
+use crate::protocol::mysql::packet::packet_writer::PacketWriter;
+use crate::protocol::mysql::packet::writers;
+use crate::server::auth::{hex_string_decode, sha1_1, sha1_2, xor};
+
+use dashmap::DashMap;
+use hashbrown::HashMap;
+use mysql_common::constants::CapabilityFlags;
+use mysql_common::packets::AuthPlugin;
+use serde::{Deserialize, Serialize};
+use std::fmt::{Debug, Formatter};
+use std::io::{Error, ErrorKind};
+use std::path::PathBuf;
+use std::sync::OnceLock;
+use tokio::io::AsyncWrite;
+use tracing::{info, warn};
+
+/// Where the password of a backend user is read from, the proxy never stores it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CredentialRef {
+    /// An environment variable of the proxy process.
+    Env(String),
+    /// A file holding the password, e.g. a mounted K8s secret. Trailing newlines are ignored.
+    File(PathBuf),
+}
+
+impl CredentialRef {
+    pub fn resolve(&self) -> Result<String, Error> {
+        match self {
+            CredentialRef::Env(name) => std::env::var(name).map_err(|e| {
+                Error::new(ErrorKind::NotFound, format!("credential env {name}: {e}"))
+            }),
+            CredentialRef::File(path) => std::fs::read_to_string(path)
+                .map(|password| password.trim_end_matches(['\r', '\n']).to_string())
+                .map_err(|e| Error::new(e.kind(), format!("credential file {path:?}: {e}"))),
+        }
+    }
+}
+
+/// Maps a platform user of a tenant to the backend user the proxy authenticates as.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IdentityMapping {
+    pub tenant: TenantKey,
+    /// The user the client connects as.
+    pub client_user: String,
+    /// `mysql_native_password` hash of the client password, `*` and 40 hex digits as stored in
+    /// `mysql.user`.
+    pub client_auth_hash: String,
+    pub backend_user: String,
+    pub backend_credential: CredentialRef,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IdentityKey {
+    pub tenant: TenantKey,
+    pub client_user: String,
+}
+
+/// The backend identity of a client session whose user is mapped.
+#[derive(Clone, PartialEq, Eq)]
+pub struct MappedIdentity {
+    pub client_user: String,
+    pub backend_user: String,
+    password: String,
+}
+
+impl Debug for MappedIdentity {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MappedIdentity")
+            .field("client_user", &self.client_user)
+            .field("backend_user", &self.backend_user)
+            .finish_non_exhaustive()
+    }
+}
+
+impl MappedIdentity {
+    /// The auth response to a backend AuthSwitchRequest for `plugin` with `nonce`.
+    pub fn auth_data(&self, plugin: &AuthPlugin<'_>, nonce: &[u8]) -> Option<Vec<u8>> {
+        plugin
+            .gen_data(Some(self.password.as_str()), nonce)
+            .map(|data| data.to_vec())
+    }
+
+    /// The password to encrypt when the backend asks for a full authentication, NUL terminated.
+    pub fn full_auth_password(&self) -> Vec<u8> {
+        let mut password = self.password.as_bytes().to_vec();
+        password.push(0);
+        password
+    }
+}
+
+/// Checks a `mysql_native_password` auth response computed with `salt` against the stored hash
+/// SHA1(SHA1(password)), the same way the server does.
+pub fn verify_native_password(salt: &[u8], auth_response: &[u8], auth_hash: &str) -> bool {
+    let Ok(stored) = hex_string_decode(auth_hash.trim_start_matches('*')) else {
+        return false;
+    };
+    if stored.len() != 20 || auth_response.len() != 20 {
+        return false;
+    }
+    let password_sha1 = xor(auth_response.to_vec(), sha1_2(salt, &stored));
+    sha1_1(password_sha1) == stored[..]
+}
+
+fn access_denied(client_user: &str, reason: &str) -> Error {
+    Error::new(
+        ErrorKind::PermissionDenied,
+        format!("Access denied for user '{client_user}': {reason}"),
+    )
+}
+
+/// `IdentityRegistry` keeps the identity mappings of the tenants, managed by the control plane
+/// through the REST API. Clients of a mapped user authenticate against the proxy with their
+/// platform password, the proxy then authenticates to the backend with the backend credential,
+/// so clients never need to know it.
+#[derive(Default)]
+pub struct IdentityRegistry {
+    tenants: DashMap<TenantKey, HashMap<String, IdentityMapping>>,
+}
+
+static IDENTITY_REGISTRY_ONCE: OnceLock<IdentityRegistry> = OnceLock::new();
+
+pub fn identity_registry() -> &'static IdentityRegistry {
+    IDENTITY_REGISTRY_ONCE.get_or_init(IdentityRegistry::default)
+}
+
+impl IdentityRegistry {
+    pub fn set_mapping(&self, mapping: IdentityMapping) {
+        info!(
+            "ProxySrv identity mapping set {:?} {} -> {}",
+            mapping.tenant, mapping.client_user, mapping.backend_user
+        );
+        self.tenants
+            .entry(mapping.tenant.clone())
+            .or_default()
+            .insert(mapping.client_user.clone(), mapping);
+    }
+
+    pub fn remove_mapping(&self, key: &IdentityKey) -> Option<IdentityMapping> {
+        info!("ProxySrv identity mapping removed {:?}", key);
+        let mut mappings = self.tenants.get_mut(&key.tenant)?;
+        let removed = mappings.remove(&key.client_user);
+        let is_empty = mappings.is_empty();
+        drop(mappings);
+        if is_empty {
+            self.tenants
+                .remove_if(&key.tenant, |_, mappings| mappings.is_empty());
+        }
+        removed
+    }
+
+    pub fn list(&self) -> Vec<IdentityMapping> {
+        self.tenants
+            .iter()
+            .flat_map(|e| e.value().values().cloned().collect::<Vec<_>>())
+            .collect()
+    }
+
+    pub fn has_mappings(&self, tenant: &TenantKey) -> bool {
+        self.tenants.contains_key(tenant)
+    }
+
+    /// Authenticates the client of a mapped user with the proxy `salt` and switches the handshake
+    /// to the backend identity. Handshakes of users without a mapping are left untouched.
+    pub fn map_identity(
+        &self,
+        handshake: &mut HandshakeResponse,
+        salt: &[u8],
+    ) -> Result<(), Error> {
+        let tenant = handshake_tenant_key(handshake);
+        let client_user = handshake.db_user_string();
+        let Some(mapping) = self
+            .tenants
+            .get(&tenant)
+            .and_then(|mappings| mappings.get(&client_user).cloned())
+        else {
+            return Ok(());
+        };
+        let auth_plugin = handshake
+            .auth_plugin
+            .strip_suffix(b"\0")
+            .unwrap_or(&handshake.auth_plugin);
+        if !auth_plugin.is_empty()
+            && auth_plugin != AuthPluginName::AuthNativePassword.as_ref().as_bytes()
+        {
+            return Err(access_denied(
+                &client_user,
+                "mapped users authenticate with mysql_native_password",
+            ));
+        }
+        if !verify_native_password(salt, &handshake.auth_response, &mapping.client_auth_hash) {
+            warn!("ProxySrv identity {client_user} of {tenant:?} wrong password");
+            return Err(access_denied(&client_user, "wrong password"));
+        }
+        let password = mapping.backend_credential.resolve().map_err(|e| {
+            warn!("ProxySrv identity {client_user} of {tenant:?} credential err {e:?}");
+            access_denied(&client_user, "backend credential unavailable")
+        })?;
+        handshake.username = Some(mapping.backend_user.as_bytes().to_vec());
+        handshake.identity = Some(MappedIdentity {
+            client_user,
+            backend_user: mapping.backend_user,
+            password,
+        });
+        Ok(())
+    }
+}
+
+/// Refuses COM_CHANGE_USER in a session of a tenant with mapped identities, the new user would
+/// skip the mapping. Returns true if the command was refused. `seq` is the sequence id of the
+/// client packet answered.
+pub async fn reject_change_user<W>(
+    tenant: &TenantKey,
+    com_code: CommandCode,
+    seq: u8,
+    client_writer: &mut PacketWriter<W>,
+    client_capabilities: CapabilityFlags,
+) -> Result<bool, Error>
+where
+    W: AsyncWrite + Send + Unpin,
+{
+    if com_code != CommandCode::ComChangeUser || !identity_registry().has_mappings(tenant) {
+        return Ok(false);
+    }
+    client_writer.set_seq(seq.wrapping_add(1));
+    writers::write_err_packet(
+        error_codes::ErrorKind::ER_NOT_SUPPORTED_AUTH_MODE,
+        b"COM_CHANGE_USER is not supported for tenants with mapped identities",
+        client_writer,
+        client_capabilities,
+    )
+    .await?;
+    client_writer.flush_all().await?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::{encode_tenant_key, test_tenant_key};
+    use crate::protocol::mysql::basic::HandshakeResponse;
+    use crate::server::auth::identity::{
+        verify_native_password, CredentialRef, IdentityKey, IdentityMapping, IdentityRegistry,
+    };
+    use crate::server::auth::{default_salt, sha1_1};
+    use mysql_common::constants::CapabilityFlags;
+    use mysql_common::scramble::scramble_native;
+
+    fn auth_hash(password: &str) -> String {
+        format!("*{}", hex::encode_upper(sha1_1(sha1_1(password))))
+    }
+
+    fn handshake(username: &str, auth_response: Vec<u8>) -> HandshakeResponse {
+        HandshakeResponse {
+            client_flag: CapabilityFlags::empty(),
+            max_packet_len: 0,
+            collation: 0,
+            tenant_key: Some(encode_tenant_key(&test_tenant_key()).into_bytes()),
+            username: Some(username.as_bytes().to_vec()),
+            auth_response,
+            auth_plugin: b"mysql_native_password".to_vec(),
+            database: None,
+            connect_attributes: None,
+            shard: None,
+            identity: None,
+            reconnect: None,
+            reconnect_token: None,
+            server_name: None,
+        }
+    }
+
+    #[test]
+    pub fn test_identity_mapping() {
+        let salt = default_salt();
+        let response = scramble_native(&salt, b"platform-secret").unwrap().to_vec();
+        assert!(verify_native_password(
+            &salt,
+            &response,
+            &auth_hash("platform-secret")
+        ));
+        assert!(!verify_native_password(
+            &salt,
+            &response,
+            &auth_hash("other")
+        ));
+
+        std::env::set_var("HAENTGL_TEST_BACKEND_PASSWORD", "backend-secret");
+        let registry = IdentityRegistry::default();
+        registry.set_mapping(IdentityMapping {
+            tenant: test_tenant_key(),
+            client_user: "alice".to_string(),
+            client_auth_hash: auth_hash("platform-secret"),
+            backend_user: "app_rw".to_string(),
+            backend_credential: CredentialRef::Env("HAENTGL_TEST_BACKEND_PASSWORD".to_string()),
+        });
+
+        let mut unmapped = handshake("bob", response.clone());
+        registry.map_identity(&mut unmapped, &salt).unwrap();
+        assert_eq!(unmapped.identity, None);
+
+        let mut mapped = handshake("alice", response);
+        registry.map_identity(&mut mapped, &salt).unwrap();
+        assert_eq!(mapped.db_user_string(), "app_rw");
+        let identity = mapped.identity.unwrap();
+        assert_eq!(identity.client_user, "alice");
+        assert!(!format!("{identity:?}").contains("backend-secret"));
+
+        let mut wrong = handshake("alice", vec![0; 20]);
+        assert!(registry.map_identity(&mut wrong, &salt).is_err());
+
+        let key = IdentityKey {
+            tenant: test_tenant_key(),
+            client_user: "alice".to_string(),
+        };
+        assert!(registry.remove_mapping(&key).is_some());
+        assert!(!registry.has_mappings(&test_tenant_key()));
+    }
+}
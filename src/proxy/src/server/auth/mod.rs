@@ -1,23 +1,28 @@
+use crate::backend::stream::{BackendReadHalf, BackendWriteHalf};
 use crate::protocol::mysql::basic::HandshakeResponse;
 use crate::protocol::mysql::constants::SCRAMBLE_SIZE;
 use crate::protocol::mysql::packet::packet_reader::PacketReader;
 use crate::protocol::mysql::packet::packet_writer::PacketWriter;
 use crate::protocol::mysql::packet::Packet;
+use crate::server::handshake_profile::HandshakeProfile;
 use std::io::ErrorKind;
 
 use async_trait::async_trait;
 use itertools::Itertools;
-use rand::rngs::StdRng;
-use rand::{Rng, SeedableRng};
+use rand::rngs::OsRng;
+use rand::{Rng, RngCore};
 use sha1::Digest;
 use sha2::Sha256;
 
 use rustls::server::ServerConfig;
 use tokio::io::{AsyncRead, AsyncWrite};
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio_rustls::rustls;
 
 pub mod authenticator;
+pub mod caching_sha2;
+pub mod client_acl;
+pub mod identity;
+pub mod reconnect_token;
 
 // Only for test purpose.
 pub fn default_salt() -> [u8; SCRAMBLE_SIZE] {
@@ -64,10 +69,13 @@ pub fn hex_string_decode<T: AsRef<[u8]>>(data: T) -> Result<Vec<u8>, std::io::Er
 
 /// Generate a random string user ASCII but avoid separator character.
 /// https://github.com/mysql/mysql-server/blob/8.0/mysys/crypt_genhash_impl.cc#L427
+///
+/// Every byte is drawn from the OS CSPRNG, no generator state is shared between connections, so
+/// the scramble of one connection tells nothing about those of others.
 #[inline]
 pub fn gen_user_salt() -> [u8; SCRAMBLE_SIZE] {
     let mut salt: [u8; SCRAMBLE_SIZE] = [0; SCRAMBLE_SIZE];
-    let mut r = StdRng::from_entropy();
+    let mut r = OsRng;
     for salt_item in salt.iter_mut() {
         let salt_rand = r.gen_range(0..127) as u8;
         *salt_item = salt_rand;
@@ -78,6 +86,13 @@ pub fn gen_user_salt() -> [u8; SCRAMBLE_SIZE] {
     salt
 }
 
+/// The connection id of the initial handshake, drawn from the OS CSPRNG. The tenant is not
+/// known before the handshake response, the id must neither collide predictably nor order the
+/// connections of different tenants.
+pub fn gen_conn_id() -> u32 {
+    OsRng.next_u32()
+}
+
 fn to_u8_32(bytes: impl AsRef<[u8]>) -> [u8; 32] {
     let mut out = [0; 32];
     (out[..]).copy_from_slice(bytes.as_ref());
@@ -132,8 +147,8 @@ pub fn sha1_2(bytes1: impl AsRef<[u8]>, bytes2: impl AsRef<[u8]>) -> [u8; 20] {
 pub trait Authenticator: Send + Sync {
     async fn continue_auth<R, W>(
         &self,
-        backend_writer: &mut PacketWriter<OwnedWriteHalf>,
-        backend_reader: &mut PacketReader<OwnedReadHalf>,
+        backend_writer: &mut PacketWriter<BackendWriteHalf>,
+        backend_reader: &mut PacketReader<BackendReadHalf>,
         client_writer: &mut PacketWriter<W>,
         client_reader: &mut PacketReader<R>,
         client_seq: u8,
@@ -143,6 +158,21 @@ pub trait Authenticator: Send + Sync {
         R: AsyncRead + Send + Unpin,
         W: AsyncWrite + Send + Unpin;
 
+    /// Resumes the session of a client that redeemed a reconnect token, on a pooled connection in
+    /// the command phase already authenticated as its user: the connection is reset and switched
+    /// to the database of the client instead of authenticated again. Returns false, with nothing
+    /// sent to the client, if the backend refused.
+    async fn resume_auth<W>(
+        &self,
+        backend_writer: &mut PacketWriter<BackendWriteHalf>,
+        backend_reader: &mut PacketReader<BackendReadHalf>,
+        client_writer: &mut PacketWriter<W>,
+        client_seq: u8,
+        handshake_resp: &HandshakeResponse,
+    ) -> Result<bool, std::io::Error>
+    where
+        W: AsyncWrite + Send + Unpin;
+
     /// Reads Backend's HandshakePacket and forwards it to the client
     async fn initial_handshake<R, W>(
         &self,
@@ -150,6 +180,7 @@ pub trait Authenticator: Send + Sync {
         scramble: [u8; 20],
         client_reader: &mut PacketReader<R>,
         client_writer: &mut PacketWriter<W>,
+        profile: &HandshakeProfile,
         #[cfg(feature = "tls")] tls_conf: &Option<std::sync::Arc<ServerConfig>>,
     ) -> Result<(u8, HandshakeResponse, Packet), std::io::Error>
     where
@@ -157,14 +188,19 @@ pub trait Authenticator: Send + Sync {
         W: AsyncWrite + Send + Unpin;
 
     /// Responds to the client's HandshakePacket, which is forwarded to the backend.
+    ///
+    /// With `backend_compress` the compressed protocol is negotiated on the backend leg only, if
+    /// the backend supports it. The client leg always stays uncompressed.
+    #[allow(clippy::too_many_arguments)]
     async fn reply_handshake_response<R, W>(
         &self,
-        backend_writer: &mut PacketWriter<OwnedWriteHalf>,
-        backend_reader: &mut PacketReader<OwnedReadHalf>,
+        backend_writer: &mut PacketWriter<BackendWriteHalf>,
+        backend_reader: &mut PacketReader<BackendReadHalf>,
         client_writer: &mut PacketWriter<W>,
         client_reader: &mut PacketReader<R>,
         seq: u8,
         client_handshake_rsp_pkt: (&[u8], &HandshakeResponse),
+        backend_compress: bool,
     ) -> Result<(), std::io::Error>
     where
         R: AsyncRead + Send + Unpin,
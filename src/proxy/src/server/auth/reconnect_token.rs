@@ -0,0 +1,271 @@
+use crate::backend::backend_tenant_key;
+use crate::prost::common_proto::TenantKey;
+use crate::protocol::mysql::basic::HandshakeResponse;
+use crate::server::auth::hex_string_decode;
+
+use aws_lc_rs::hmac;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::Duration;
+use tracing::{debug, warn};
+
+/// The connection attribute of a client asking for a reconnect token, or redeeming one. An empty
+/// value only asks for a token.
+pub const RECONNECT_TOKEN_ATTR: &str = "proxy_reconnect_token";
+/// Shorter signing keys are refused.
+const MIN_KEY_LEN: usize = 32;
+
+#[derive(Debug, Clone)]
+pub struct ReconnectTokenConfig {
+    /// One signing key per line, `<key id> <hex secret>`. The first key signs the new tokens, all
+    /// of them verify, so a key is rotated by prepending its successor. Tokens are off if not set.
+    pub keys_file: Option<PathBuf>,
+    pub ttl: Duration,
+}
+
+/// What a valid reconnect token grants the client presenting it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReconnectGrant {
+    /// The backend the client was connected to, its session lands there again if the backend
+    /// still serves the tenant.
+    pub backend_addr: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct TokenClaims {
+    tenant: TenantKey,
+    user: String,
+    backend: String,
+    /// Unix seconds, the tokens are verified by every proxy sharing the keys.
+    exp: i64,
+}
+
+/// `ReconnectTokens` signs the reconnect tokens handed to authenticated clients in the info of the
+/// final OK packet. A client reconnecting with its token, e.g. a serverless function on a cold
+/// start, lands on the same backend, where a pooled connection already authenticated as its user
+/// is reset instead of authenticated again.
+///
+/// A token is `<key id>.<claims>.<mac>`, both base64url, the HMAC-SHA256 covering the key id and
+/// the claims. It binds the tenant, the backend user and the backend, and may be redeemed until it
+/// expires.
+pub struct ReconnectTokens {
+    keys: Vec<(String, hmac::Key)>,
+    ttl: Duration,
+}
+
+static RECONNECT_TOKENS_ONCE: OnceLock<ReconnectTokens> = OnceLock::new();
+
+/// Initializes the global reconnect tokens, must be called before the first client connects.
+pub fn init_reconnect_tokens(
+    config: &ReconnectTokenConfig,
+) -> Result<&'static ReconnectTokens, Error> {
+    let tokens = match &config.keys_file {
+        Some(keys_file) => ReconnectTokens::new(read_keys(keys_file)?, config.ttl)?,
+        None => ReconnectTokens::disabled(),
+    };
+    Ok(RECONNECT_TOKENS_ONCE.get_or_init(|| tokens))
+}
+
+pub fn reconnect_tokens() -> &'static ReconnectTokens {
+    RECONNECT_TOKENS_ONCE.get_or_init(ReconnectTokens::disabled)
+}
+
+fn read_keys(keys_file: &Path) -> Result<Vec<(String, Vec<u8>)>, Error> {
+    let keys = std::fs::read_to_string(keys_file).map_err(|e| {
+        Error::new(
+            e.kind(),
+            format!("reconnect token keys {}: {e}", keys_file.display()),
+        )
+    })?;
+    keys.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| match line.split_once(char::is_whitespace) {
+            Some((id, secret)) => Ok((id.to_string(), hex_string_decode(secret.trim())?)),
+            None => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("reconnect token key {line:?} is not <key id> <hex secret>"),
+            )),
+        })
+        .collect()
+}
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::PermissionDenied, message.to_string())
+}
+
+impl ReconnectTokens {
+    pub fn new(keys: Vec<(String, Vec<u8>)>, ttl: Duration) -> Result<Self, Error> {
+        if keys.is_empty() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "no reconnect token signing key",
+            ));
+        }
+        let keys = keys
+            .into_iter()
+            .map(|(id, secret)| {
+                if id.is_empty() || id.contains('.') || secret.len() < MIN_KEY_LEN {
+                    return Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        format!(
+                            "reconnect token key {id:?} needs an id without '.' and a secret of \
+                             at least {MIN_KEY_LEN} bytes"
+                        ),
+                    ));
+                }
+                Ok((id, hmac::Key::new(hmac::HMAC_SHA256, &secret)))
+            })
+            .collect::<Result<_, Error>>()?;
+        Ok(Self { keys, ttl })
+    }
+
+    fn disabled() -> Self {
+        Self {
+            keys: vec![],
+            ttl: Duration::ZERO,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.keys.is_empty()
+    }
+
+    /// A token for `user` of `tenant` connected to `backend_addr`, `None` if tokens are off.
+    pub fn issue(&self, tenant: &TenantKey, user: &str, backend_addr: &str) -> Option<String> {
+        let (key_id, key) = self.keys.first()?;
+        let claims = TokenClaims {
+            tenant: tenant.clone(),
+            user: user.to_string(),
+            backend: backend_addr.to_string(),
+            exp: Utc::now().timestamp() + self.ttl.as_secs() as i64,
+        };
+        let claims = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&claims).ok()?);
+        let signed = format!("{key_id}.{claims}");
+        let mac = URL_SAFE_NO_PAD.encode(hmac::sign(key, signed.as_bytes()));
+        Some(format!("{signed}.{mac}"))
+    }
+
+    /// Verifies a token presented by `user` of `tenant`.
+    pub fn verify(
+        &self,
+        token: &str,
+        tenant: &TenantKey,
+        user: &str,
+    ) -> Result<ReconnectGrant, Error> {
+        let (signed, mac) = token
+            .rsplit_once('.')
+            .ok_or_else(|| invalid("malformed reconnect token"))?;
+        let (key_id, claims) = signed
+            .split_once('.')
+            .ok_or_else(|| invalid("malformed reconnect token"))?;
+        let (_, key) = self
+            .keys
+            .iter()
+            .find(|(id, _)| id == key_id)
+            .ok_or_else(|| invalid("reconnect token signed by an unknown key"))?;
+        let mac = URL_SAFE_NO_PAD
+            .decode(mac)
+            .map_err(|_| invalid("malformed reconnect token"))?;
+        // Constant time, the signature is checked before anything is read from the claims.
+        hmac::verify(key, signed.as_bytes(), &mac)
+            .map_err(|_| invalid("reconnect token signature mismatch"))?;
+        let claims = URL_SAFE_NO_PAD
+            .decode(claims)
+            .ok()
+            .and_then(|claims| serde_json::from_slice::<TokenClaims>(&claims).ok())
+            .ok_or_else(|| invalid("malformed reconnect token"))?;
+        if claims.exp <= Utc::now().timestamp() {
+            return Err(Error::new(ErrorKind::TimedOut, "reconnect token expired"));
+        }
+        if &claims.tenant != tenant || claims.user != user {
+            return Err(invalid("reconnect token of another tenant or user"));
+        }
+        Ok(ReconnectGrant {
+            backend_addr: claims.backend,
+        })
+    }
+
+    /// The reconnect token attribute of a client, `None` if it did not ask for tokens. Mapped
+    /// identities are authenticated by the proxy and get none.
+    fn attribute<'a>(&self, handshake: &'a HandshakeResponse) -> Option<&'a str> {
+        if !self.is_enabled() || handshake.identity.is_some() {
+            return None;
+        }
+        handshake
+            .connect_attributes
+            .as_ref()?
+            .get(RECONNECT_TOKEN_ATTR)
+            .map(String::as_str)
+    }
+
+    /// Redeems the token of a routed client, setting [`HandshakeResponse::reconnect`] if it is
+    /// valid. A client with an invalid token is authenticated as usual.
+    pub fn redeem(&self, handshake: &mut HandshakeResponse) {
+        let Some(token) = self.attribute(handshake).filter(|token| !token.is_empty()) else {
+            return;
+        };
+        let tenant = backend_tenant_key(handshake);
+        match self.verify(token, &tenant, &handshake.db_user_string()) {
+            Ok(grant) => handshake.reconnect = Some(grant),
+            Err(e) if e.kind() == ErrorKind::TimedOut => {
+                debug!("ProxySrv reconnect token of {tenant:?} rejected: {e}")
+            }
+            Err(e) => warn!("ProxySrv reconnect token of {tenant:?} rejected: {e}"),
+        }
+    }
+
+    /// Sets the token the client gets once authenticated on `backend_addr`, if it asked for one.
+    pub fn grant(&self, handshake: &mut HandshakeResponse, backend_addr: &str) {
+        if self.attribute(handshake).is_none() {
+            return;
+        }
+        let tenant = backend_tenant_key(handshake);
+        handshake.reconnect_token = self.issue(&tenant, &handshake.db_user_string(), backend_addr);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::test_tenant_key;
+    use crate::server::auth::reconnect_token::ReconnectTokens;
+    use std::time::Duration;
+
+    #[test]
+    pub fn test_reconnect_token() {
+        let key = |id: &str, byte: u8| (id.to_string(), vec![byte; 32]);
+        assert!(ReconnectTokens::new(vec![], Duration::from_secs(60)).is_err());
+        assert!(ReconnectTokens::new(vec![("k".into(), vec![0; 8])], Duration::ZERO).is_err());
+
+        let old = ReconnectTokens::new(vec![key("k1", 1)], Duration::from_secs(60)).unwrap();
+        let tenant = test_tenant_key();
+        let token = old.issue(&tenant, "app", "10.0.0.1:3306").unwrap();
+        let grant = old.verify(&token, &tenant, "app").unwrap();
+        assert_eq!(grant.backend_addr, "10.0.0.1:3306");
+        assert!(old.verify(&token, &tenant, "admin").is_err());
+
+        // After a rotation the tokens of the old key stay valid, the new ones use the new key.
+        let rotated =
+            ReconnectTokens::new(vec![key("k2", 2), key("k1", 1)], Duration::from_secs(60))
+                .unwrap();
+        assert!(rotated.verify(&token, &tenant, "app").is_ok());
+        assert!(rotated
+            .issue(&tenant, "app", "10.0.0.1:3306")
+            .unwrap()
+            .starts_with("k2."));
+        let forged = ReconnectTokens::new(vec![key("k1", 3)], Duration::from_secs(60)).unwrap();
+        let forged_token = forged.issue(&tenant, "app", "10.0.0.2:3306").unwrap();
+        assert!(old.verify(&forged_token, &tenant, "app").is_err());
+        assert!(old.verify("k1.e30", &tenant, "app").is_err());
+
+        let expired = ReconnectTokens::new(vec![key("k1", 1)], Duration::ZERO).unwrap();
+        let token = expired.issue(&tenant, "app", "10.0.0.1:3306").unwrap();
+        let err = expired.verify(&token, &tenant, "app").err().unwrap();
+        assert_eq!(err.to_string(), "reconnect token expired");
+    }
+}
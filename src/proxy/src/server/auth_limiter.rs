@@ -0,0 +1,224 @@
+use crate::protocol::mysql::error_codes::ErrorKind;
+use crate::protocol::mysql::packet::packet_writer::PacketWriter;
+use crate::protocol::mysql::packet::writers;
+use crate::server::recent_errors::recent_errors;
+
+use common::metrics::metric_def::PROXY_AUTH_REJECTED;
+use common::metrics::{common_labels, counter_inc};
+use mysql_common::constants::CapabilityFlags;
+use serde::{Deserialize, Serialize};
+use std::io::Error;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+use tokio::io::AsyncWrite;
+use tokio::sync::{Semaphore, SemaphorePermit};
+use tracing::{info, warn};
+
+/// Bounds the sessions in the auth phase, from the greeting until the backend authenticated the
+/// client.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuthLimits {
+    /// Concurrent handshakes, 0 means unlimited.
+    #[serde(default)]
+    pub max_handshakes: usize,
+    /// How long a client waits for a handshake slot before it is rejected.
+    #[serde(default)]
+    pub queue_timeout_ms: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct AuthLimiterStatus {
+    #[serde(flatten)]
+    pub limits: AuthLimits,
+    pub in_flight: usize,
+    pub rejected: u64,
+}
+
+#[derive(Debug, Default)]
+struct LimiterState {
+    limits: AuthLimits,
+    /// Permits to forget once released, the limit was lowered below the handshakes in flight.
+    excess: usize,
+    rejected: u64,
+}
+
+/// `AuthLimiter` is the semaphore of the auth phase. The auth phase takes several round trips and
+/// a backend checkout, so a reconnection storm after a proxy or backend restart would otherwise
+/// stack thousands of handshakes; the clients beyond the limit queue briefly, then get
+/// ER_CON_COUNT_ERROR, which they retry. It is separate from the limits on authenticated sessions,
+/// and its limits are changed at runtime.
+pub struct AuthLimiter {
+    semaphore: Semaphore,
+    state: Mutex<LimiterState>,
+}
+
+/// A handshake slot, released once the session is authenticated or fails.
+pub struct AuthPermit<'a> {
+    limiter: &'a AuthLimiter,
+    permit: Option<SemaphorePermit<'a>>,
+}
+
+impl Drop for AuthPermit<'_> {
+    fn drop(&mut self) {
+        let Some(permit) = self.permit.take() else {
+            return;
+        };
+        let mut state = self.limiter.state.lock().unwrap();
+        if state.excess > 0 {
+            state.excess -= 1;
+            permit.forget();
+        }
+    }
+}
+
+static AUTH_LIMITER_ONCE: OnceLock<AuthLimiter> = OnceLock::new();
+
+/// Initializes the global auth limiter, must be called before the first client connects.
+pub fn init_auth_limiter(limits: AuthLimits) -> &'static AuthLimiter {
+    AUTH_LIMITER_ONCE.get_or_init(|| AuthLimiter::new(limits))
+}
+
+pub fn auth_limiter() -> &'static AuthLimiter {
+    AUTH_LIMITER_ONCE.get_or_init(|| AuthLimiter::new(AuthLimits::default()))
+}
+
+impl AuthLimiter {
+    pub fn new(limits: AuthLimits) -> Self {
+        Self {
+            semaphore: Semaphore::new(limits.max_handshakes),
+            state: Mutex::new(LimiterState {
+                limits,
+                ..Default::default()
+            }),
+        }
+    }
+
+    pub fn limits(&self) -> AuthLimits {
+        self.state.lock().unwrap().limits
+    }
+
+    /// Resizes the semaphore. Lowering the limit below the handshakes in flight lets them finish,
+    /// their permits are forgotten as they are released.
+    pub fn set_limits(&self, limits: AuthLimits) {
+        info!("ProxySrv auth limits {:?}", limits);
+        let mut state = self.state.lock().unwrap();
+        let current = state.limits.max_handshakes;
+        if limits.max_handshakes >= current {
+            let added = limits.max_handshakes - current;
+            let repaid = added.min(state.excess);
+            state.excess -= repaid;
+            self.semaphore.add_permits(added - repaid);
+        } else {
+            let removed = current - limits.max_handshakes;
+            let forgotten = self.semaphore.forget_permits(removed);
+            state.excess += removed - forgotten;
+        }
+        state.limits = limits;
+    }
+
+    pub fn status(&self) -> AuthLimiterStatus {
+        let state = self.state.lock().unwrap();
+        let in_flight = if state.limits.max_handshakes == 0 {
+            0
+        } else {
+            (state.limits.max_handshakes + state.excess)
+                .saturating_sub(self.semaphore.available_permits())
+        };
+        AuthLimiterStatus {
+            limits: state.limits,
+            in_flight,
+            rejected: state.rejected,
+        }
+    }
+
+    /// Waits up to the queue timeout for a handshake slot, `None` if the auth phase stays full.
+    pub async fn acquire(&self) -> Option<AuthPermit<'_>> {
+        let limits = self.limits();
+        if limits.max_handshakes == 0 {
+            return Some(AuthPermit {
+                limiter: self,
+                permit: None,
+            });
+        }
+        let queue_timeout = Duration::from_millis(limits.queue_timeout_ms);
+        match tokio::time::timeout(queue_timeout, self.semaphore.acquire()).await {
+            Ok(Ok(permit)) => Some(AuthPermit {
+                limiter: self,
+                permit: Some(permit),
+            }),
+            _ => {
+                self.state.lock().unwrap().rejected += 1;
+                counter_inc(PROXY_AUTH_REJECTED, 1, Some(common_labels()));
+                None
+            }
+        }
+    }
+}
+
+/// Rejects a client the auth phase has no slot for. No greeting was sent yet, so the error is
+/// the first packet, the way the server refuses connections beyond its max_connections.
+pub async fn write_auth_full_err<W>(client_writer: &mut PacketWriter<W>) -> Result<(), Error>
+where
+    W: AsyncWrite + Send + Unpin,
+{
+    let message = "Too many connections authenticating, retry later";
+    warn!("ProxySrv session rejected: {message}");
+    recent_errors().record("auth_limit", message.to_string());
+    writers::write_err_packet(
+        ErrorKind::ER_CON_COUNT_ERROR,
+        message.as_bytes(),
+        client_writer,
+        CapabilityFlags::empty(),
+    )
+    .await?;
+    client_writer.flush_all().await
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::server::auth_limiter::{AuthLimiter, AuthLimits};
+    use std::time::Duration;
+
+    #[tokio::test]
+    pub async fn test_auth_limiter() {
+        let unlimited = AuthLimiter::new(AuthLimits::default());
+        let _permits = [
+            unlimited.acquire().await.unwrap(),
+            unlimited.acquire().await.unwrap(),
+        ];
+        assert_eq!(unlimited.status().in_flight, 0);
+
+        let limiter = AuthLimiter::new(AuthLimits {
+            max_handshakes: 2,
+            queue_timeout_ms: 10,
+        });
+        let first = limiter.acquire().await.unwrap();
+        let second = limiter.acquire().await.unwrap();
+        assert!(limiter.acquire().await.is_none());
+        assert_eq!(limiter.status().in_flight, 2);
+        assert_eq!(limiter.status().rejected, 1);
+
+        // Lowered below the handshakes in flight, the released permits are forgotten.
+        limiter.set_limits(AuthLimits {
+            max_handshakes: 1,
+            queue_timeout_ms: 10,
+        });
+        drop(first);
+        assert!(limiter.acquire().await.is_none());
+        drop(second);
+        let third = limiter.acquire().await.unwrap();
+        assert!(limiter.acquire().await.is_none());
+        assert_eq!(limiter.status().in_flight, 1);
+
+        // A queued client gets the slot released while it waits.
+        limiter.set_limits(AuthLimits {
+            max_handshakes: 1,
+            queue_timeout_ms: 1000,
+        });
+        let (queued, _) = tokio::join!(limiter.acquire(), async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            drop(third);
+        });
+        assert!(queued.is_some());
+    }
+}
@@ -0,0 +1,227 @@
+use crate::prost::common_proto::TenantKey;
+use crate::prost::control_plane::BillingRecord;
+use crate::protocol::mysql::constants::CommandCode;
+
+use chrono::Local;
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+
+/// Records kept for the control plane until it pulls them, the oldest are dropped first.
+pub const BILLING_PENDING_CAPACITY: usize = 65536;
+
+#[derive(Debug, Clone, Default)]
+pub struct BillingConfig {
+    /// Appends one JSON line per billing record to this file.
+    pub jsonl_path: Option<PathBuf>,
+    /// Keeps billing records until the control plane pulls them on the control plane stream.
+    pub control_plane: bool,
+}
+
+impl BillingConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.jsonl_path.is_some() || self.control_plane
+    }
+}
+
+/// `BillingExporter` receives a [`BillingRecord`] for every client session that ended and hands
+/// it to the configured sinks, so usage based billing needs no backend instrumentation.
+pub struct BillingExporter {
+    jsonl_tx: Option<mpsc::UnboundedSender<BillingRecord>>,
+    pending: Option<Mutex<VecDeque<BillingRecord>>>,
+}
+
+static BILLING_ONCE: OnceLock<BillingExporter> = OnceLock::new();
+
+/// Starts the billing export if a sink is configured, must be called within the tokio runtime.
+pub fn init_billing(config: BillingConfig) {
+    if !config.is_enabled() {
+        return;
+    }
+    let jsonl_tx = config.jsonl_path.map(|path| {
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(write_jsonl(path, rx));
+        tx
+    });
+    let exporter = BillingExporter::new(jsonl_tx, config.control_plane);
+    if BILLING_ONCE.set(exporter).is_err() {
+        warn!("ProxySrv billing export already initialized");
+    }
+}
+
+pub fn billing() -> Option<&'static BillingExporter> {
+    BILLING_ONCE.get()
+}
+
+async fn write_jsonl(path: PathBuf, mut rx: mpsc::UnboundedReceiver<BillingRecord>) {
+    let mut file = match tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .await
+    {
+        Ok(file) => file,
+        Err(e) => {
+            warn!("ProxySrv billing failed to open {:?}. cause by {e:?}", path);
+            return;
+        }
+    };
+    info!("ProxySrv billing records are appended to {:?}", path);
+    while let Some(record) = rx.recv().await {
+        let mut line = match serde_json::to_vec(&record) {
+            Ok(line) => line,
+            Err(e) => {
+                warn!("ProxySrv billing record serialize err. cause by {e:?}");
+                continue;
+            }
+        };
+        line.push(b'\n');
+        if let Err(e) = file.write_all(&line).await {
+            warn!(
+                "ProxySrv billing record write err {:?}. cause by {e:?}",
+                path
+            );
+        }
+    }
+}
+
+impl BillingExporter {
+    pub fn new(
+        jsonl_tx: Option<mpsc::UnboundedSender<BillingRecord>>,
+        control_plane: bool,
+    ) -> Self {
+        Self {
+            jsonl_tx,
+            pending: control_plane.then(|| Mutex::new(VecDeque::new())),
+        }
+    }
+
+    pub fn export(&self, record: BillingRecord) {
+        if let Some(pending) = &self.pending {
+            let mut pending = pending.lock().unwrap();
+            if pending.len() >= BILLING_PENDING_CAPACITY {
+                let dropped = pending.pop_front();
+                warn!("ProxySrv billing record dropped, control plane lagging {dropped:?}");
+            }
+            pending.push_back(record.clone());
+        }
+        if let Some(jsonl_tx) = &self.jsonl_tx {
+            if let Err(e) = jsonl_tx.send(record) {
+                warn!("ProxySrv billing record dropped, writer stopped {:?}", e.0);
+            }
+        }
+    }
+
+    /// Takes the records waiting for the control plane.
+    pub fn drain(&self) -> Vec<BillingRecord> {
+        match &self.pending {
+            Some(pending) => pending.lock().unwrap().drain(..).collect(),
+            None => Vec::new(),
+        }
+    }
+}
+
+/// `SessionUsage` accumulates what one client session consumed. Its [`BillingRecord`] is
+/// exported when it is dropped, so a session is billed however it ends.
+pub struct SessionUsage {
+    tenant: TenantKey,
+    user: String,
+    session_id: u64,
+    start_ts: u64,
+    started: Instant,
+    queries: u64,
+    bytes_in: u64,
+    bytes_out: u64,
+    backend_time: Duration,
+}
+
+impl SessionUsage {
+    pub fn new(tenant: TenantKey, user: String, session_id: u64) -> Self {
+        Self {
+            tenant,
+            user,
+            session_id,
+            start_ts: Local::now().timestamp_millis() as u64,
+            started: Instant::now(),
+            queries: 0,
+            bytes_in: 0,
+            bytes_out: 0,
+            backend_time: Duration::ZERO,
+        }
+    }
+
+    /// Called for every command forwarded to the backend.
+    pub fn record_command(&mut self, com_code: CommandCode, backend_time: Duration) {
+        if let CommandCode::ComQuery | CommandCode::ComStmtExecute = com_code {
+            self.queries += 1;
+        }
+        self.backend_time += backend_time;
+    }
+
+    /// Sets the bytes exchanged with the client since the session entered the command phase.
+    pub fn set_bytes(&mut self, bytes_in: u64, bytes_out: u64) {
+        self.bytes_in = bytes_in;
+        self.bytes_out = bytes_out;
+    }
+
+    pub fn to_record(&self) -> BillingRecord {
+        BillingRecord {
+            cluster: Some(self.tenant.clone()),
+            user: self.user.clone(),
+            session_id: self.session_id,
+            start_ts: self.start_ts,
+            end_ts: Local::now().timestamp_millis() as u64,
+            queries: self.queries,
+            bytes_in: self.bytes_in,
+            bytes_out: self.bytes_out,
+            wall_time_us: self.started.elapsed().as_micros() as u64,
+            backend_time_us: self.backend_time.as_micros() as u64,
+        }
+    }
+}
+
+impl Drop for SessionUsage {
+    fn drop(&mut self) {
+        if let Some(exporter) = billing() {
+            exporter.export(self.to_record());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::test_tenant_key;
+    use crate::protocol::mysql::constants::CommandCode;
+    use crate::server::billing::{BillingExporter, SessionUsage};
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+
+    #[test]
+    pub fn test_session_billing_record() {
+        let mut usage = SessionUsage::new(test_tenant_key(), "root".to_string(), 7);
+        usage.record_command(CommandCode::ComQuery, Duration::from_millis(3));
+        usage.record_command(CommandCode::ComStmtPrepare, Duration::from_millis(1));
+        usage.record_command(CommandCode::ComStmtExecute, Duration::from_millis(2));
+        usage.set_bytes(120, 4096);
+        let record = usage.to_record();
+        assert_eq!(record.session_id, 7);
+        assert_eq!(record.queries, 2);
+        assert_eq!(record.bytes_in, 120);
+        assert_eq!(record.bytes_out, 4096);
+        assert_eq!(record.backend_time_us, 6000);
+        assert!(record.end_ts >= record.start_ts);
+
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let exporter = BillingExporter::new(Some(tx), true);
+        exporter.export(record.clone());
+        assert_eq!(exporter.drain(), vec![record.clone()]);
+        assert!(exporter.drain().is_empty());
+        assert_eq!(rx.try_recv().unwrap(), record);
+        let line = serde_json::to_string(&record).unwrap();
+        assert!(line.contains("\"bytes_out\":4096"));
+    }
+}
@@ -0,0 +1,170 @@
+//! Client connections of the proxy listener. A client asks for TLS within the MySQL handshake,
+//! after the initial handshake was sent in plain text, so the halves of its connection are
+//! upgraded in place and the session reads and writes them alike.
+
+use crate::protocol::mysql::basic::{client_handshake_response, HandshakeResponse};
+use crate::protocol::mysql::packet::packet_reader::PacketReader;
+use crate::protocol::mysql::packet::Packet;
+use crate::server::client_tls::server_name;
+use crate::server::handshake_profile::HandshakeProfile;
+
+use mysql_common::constants::CapabilityFlags;
+use std::io::{Error, ErrorKind, IoSlice};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, Join, ReadBuf, ReadHalf, WriteHalf};
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+
+/// The TLS stream of a client over its plain halves. The plain reader hands over the bytes it
+/// buffered after the TLS request, the start of the TLS handshake.
+type ClientTlsStream<R, W> = TlsStream<Join<PacketReader<R>, W>>;
+
+pub enum ClientReadHalf<R, W> {
+    Plain(R),
+    Tls(ReadHalf<ClientTlsStream<R, W>>),
+    /// Left while the connection is upgraded, or once the upgrade failed.
+    Upgrading,
+}
+
+pub enum ClientWriteHalf<R, W> {
+    Plain(W),
+    Tls(WriteHalf<ClientTlsStream<R, W>>),
+    Upgrading,
+}
+
+fn upgrading() -> Error {
+    Error::new(
+        ErrorKind::NotConnected,
+        "the client connection is upgrading",
+    )
+}
+
+/// Whether `handshake` is the request of a client to continue over TLS, the short handshake
+/// response sent before the TLS handshake.
+pub fn is_tls_request(handshake: &HandshakeResponse) -> bool {
+    handshake.client_flag.contains(CapabilityFlags::CLIENT_SSL) && handshake.username.is_none()
+}
+
+/// Upgrades the client connection to TLS once the client asked for it, and reads the handshake
+/// response it then sends over TLS, with the SNI hostname it connected to.
+pub async fn accept_client_tls<R, W>(
+    reader: &mut PacketReader<ClientReadHalf<R, W>>,
+    writer: &mut ClientWriteHalf<R, W>,
+    tls_conf: Arc<ServerConfig>,
+    profile: &HandshakeProfile,
+) -> Result<(u8, HandshakeResponse, Packet), Error>
+where
+    R: AsyncRead + Send + Unpin,
+    W: AsyncWrite + Send + Unpin,
+{
+    let plain = (
+        std::mem::replace(&mut reader.r, ClientReadHalf::Upgrading),
+        std::mem::replace(writer, ClientWriteHalf::Upgrading),
+    );
+    let (ClientReadHalf::Plain(r), ClientWriteHalf::Plain(w)) = plain else {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "the client asked for TLS twice",
+        ));
+    };
+    let plain_reader = PacketReader::new(r).with_buffered(reader.take_buffered());
+    let tls_stream = TlsAcceptor::from(tls_conf)
+        .accept(tokio::io::join(plain_reader, w))
+        .await?;
+    let server_name = server_name(&tls_stream);
+    let (r, w) = tokio::io::split(tls_stream);
+    reader.r = ClientReadHalf::Tls(r);
+    *writer = ClientWriteHalf::Tls(w);
+
+    let Some((seq, packet)) = reader.next_async().await? else {
+        return Err(Error::new(
+            ErrorKind::ConnectionAborted,
+            "peer terminated connection after the TLS handshake",
+        ));
+    };
+    let (_, mut handshake) = client_handshake_response(&packet, true).map_err(|e| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("bad client handshake response {e:?}"),
+        )
+    })?;
+    handshake.change_tenant_if_need();
+    handshake.client_flag &= profile.capabilities() | CapabilityFlags::CLIENT_SSL;
+    handshake.server_name = server_name;
+    Ok((seq, handshake, packet))
+}
+
+impl<R, W> AsyncRead for ClientReadHalf<R, W>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            ClientReadHalf::Plain(r) => Pin::new(r).poll_read(cx, buf),
+            ClientReadHalf::Tls(r) => Pin::new(r).poll_read(cx, buf),
+            ClientReadHalf::Upgrading => Poll::Ready(Err(upgrading())),
+        }
+    }
+}
+
+impl<R, W> AsyncWrite for ClientWriteHalf<R, W>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, Error>> {
+        match self.get_mut() {
+            ClientWriteHalf::Plain(w) => Pin::new(w).poll_write(cx, buf),
+            ClientWriteHalf::Tls(w) => Pin::new(w).poll_write(cx, buf),
+            ClientWriteHalf::Upgrading => Poll::Ready(Err(upgrading())),
+        }
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<Result<usize, Error>> {
+        match self.get_mut() {
+            ClientWriteHalf::Plain(w) => Pin::new(w).poll_write_vectored(cx, bufs),
+            ClientWriteHalf::Tls(w) => Pin::new(w).poll_write_vectored(cx, bufs),
+            ClientWriteHalf::Upgrading => Poll::Ready(Err(upgrading())),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match self {
+            ClientWriteHalf::Plain(w) => w.is_write_vectored(),
+            ClientWriteHalf::Tls(w) => w.is_write_vectored(),
+            ClientWriteHalf::Upgrading => false,
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        match self.get_mut() {
+            ClientWriteHalf::Plain(w) => Pin::new(w).poll_flush(cx),
+            ClientWriteHalf::Tls(w) => Pin::new(w).poll_flush(cx),
+            ClientWriteHalf::Upgrading => Poll::Ready(Err(upgrading())),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        match self.get_mut() {
+            ClientWriteHalf::Plain(w) => Pin::new(w).poll_shutdown(cx),
+            ClientWriteHalf::Tls(w) => Pin::new(w).poll_shutdown(cx),
+            ClientWriteHalf::Upgrading => Poll::Ready(Err(upgrading())),
+        }
+    }
+}
@@ -0,0 +1,260 @@
+use common::metrics::metric_def::{PROXY_TLS_HANDSHAKE_FAILED, PROXY_TLS_HANDSHAKE_LATENCY};
+use common::metrics::{common_labels, counter_handle, histogram_handle, Counter, Histogram};
+use std::io::{Error, ErrorKind};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::runtime::Runtime;
+use tokio_rustls::rustls;
+use tokio_rustls::rustls::crypto::{aws_lc_rs, CryptoProvider};
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::{ResolvesServerCert, ServerConfig, ServerSessionMemoryCache};
+use tokio_rustls::rustls::{HandshakeKind, SupportedProtocolVersion};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, info};
+
+/// Sessions kept for session ID resumption by default.
+pub const DEFAULT_TLS_SESSION_CACHE_SIZE: usize = 10240;
+
+/// `ClientTlsOptions` configures TLS termination of client connections. Serverless clients
+/// reconnect constantly, so resumption is enabled by default to keep full handshakes rare.
+#[derive(Debug, Clone)]
+pub struct ClientTlsOptions {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+    /// Sessions kept for session ID resumption, 0 disables it.
+    pub session_cache_size: usize,
+    /// Issues stateless session tickets.
+    pub session_tickets: bool,
+    /// Allowed cipher suites, e.g. `TLS13_AES_128_GCM_SHA256`. Empty keeps the defaults.
+    pub cipher_suites: Vec<String>,
+    /// Allowed protocol versions, `1.2` or `1.3`. Empty allows both.
+    pub protocol_versions: Vec<String>,
+    /// Threads dedicated to TLS handshakes, 0 runs them on the connection task.
+    pub handshake_threads: usize,
+    /// Picks the certificate of every handshake instead of `cert_path` and `key_path`, e.g. the
+    /// [`AcmeCertResolver`] renewing it.
+    ///
+    /// [`AcmeCertResolver`]: crate::server::acme::AcmeCertResolver
+    pub cert_resolver: Option<Arc<dyn ResolvesServerCert>>,
+}
+
+impl ClientTlsOptions {
+    pub fn new(cert_path: PathBuf, key_path: PathBuf) -> Self {
+        Self {
+            cert_path,
+            key_path,
+            session_cache_size: DEFAULT_TLS_SESSION_CACHE_SIZE,
+            session_tickets: true,
+            cipher_suites: vec![],
+            protocol_versions: vec![],
+            handshake_threads: 0,
+            cert_resolver: None,
+        }
+    }
+
+    /// Options serving the certificate picked by `cert_resolver`.
+    pub fn with_cert_resolver(cert_resolver: Arc<dyn ResolvesServerCert>) -> Self {
+        Self {
+            cert_resolver: Some(cert_resolver),
+            ..Self::new(PathBuf::new(), PathBuf::new())
+        }
+    }
+
+    fn crypto_provider(&self) -> Result<CryptoProvider, Error> {
+        let mut provider = CryptoProvider::get_default()
+            .map(|provider| provider.as_ref().clone())
+            .unwrap_or_else(aws_lc_rs::default_provider);
+        if self.cipher_suites.is_empty() {
+            return Ok(provider);
+        }
+        let mut cipher_suites = Vec::with_capacity(self.cipher_suites.len());
+        for name in &self.cipher_suites {
+            let suite = provider
+                .cipher_suites
+                .iter()
+                .find(|suite| format!("{:?}", suite.suite()).eq_ignore_ascii_case(name.trim()))
+                .ok_or_else(|| {
+                    Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("unknown cipher suite {name}"),
+                    )
+                })?;
+            cipher_suites.push(*suite);
+        }
+        provider.cipher_suites = cipher_suites;
+        Ok(provider)
+    }
+
+    fn protocol_versions(&self) -> Result<Vec<&'static SupportedProtocolVersion>, Error> {
+        if self.protocol_versions.is_empty() {
+            return Ok(rustls::ALL_VERSIONS.to_vec());
+        }
+        self.protocol_versions
+            .iter()
+            .map(|version| match version.trim() {
+                "1.2" => Ok(&rustls::version::TLS12),
+                "1.3" => Ok(&rustls::version::TLS13),
+                _ => Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("unsupported TLS version {version}"),
+                )),
+            })
+            .collect()
+    }
+
+    pub fn server_config(&self) -> Result<Arc<ServerConfig>, Error> {
+        let invalid = |e: rustls::Error| Error::new(ErrorKind::InvalidInput, e);
+        let pem_err = |e| Error::new(ErrorKind::InvalidData, format!("{e:?}"));
+        let builder = ServerConfig::builder_with_provider(Arc::new(self.crypto_provider()?))
+            .with_protocol_versions(&self.protocol_versions()?)
+            .map_err(invalid)?
+            .with_no_client_auth();
+        let mut server_config = match &self.cert_resolver {
+            Some(cert_resolver) => builder.with_cert_resolver(Arc::clone(cert_resolver)),
+            None => {
+                let certs = CertificateDer::pem_file_iter(&self.cert_path)
+                    .map_err(pem_err)?
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(pem_err)?;
+                let key = PrivateKeyDer::from_pem_file(&self.key_path).map_err(pem_err)?;
+                builder.with_single_cert(certs, key).map_err(invalid)?
+            }
+        };
+        server_config.session_storage = if self.session_cache_size > 0 {
+            ServerSessionMemoryCache::new(self.session_cache_size)
+        } else {
+            Arc::new(rustls::server::NoServerSessionStorage {})
+        };
+        if self.session_tickets {
+            server_config.ticketer = aws_lc_rs::Ticketer::new().map_err(invalid)?;
+        } else {
+            server_config.send_tls13_tickets = 0;
+        }
+        Ok(Arc::new(server_config))
+    }
+}
+
+/// `TlsHandshaker` upgrades client connections to TLS and measures the handshakes. Handshakes
+/// may run on a dedicated runtime, so a reconnect storm does not starve established sessions.
+pub struct TlsHandshaker {
+    acceptor: TlsAcceptor,
+    offload: Option<Runtime>,
+    full_latency: Histogram,
+    resumed_latency: Histogram,
+    failed: Counter,
+}
+
+impl TlsHandshaker {
+    pub fn new(server_config: Arc<ServerConfig>) -> Self {
+        let labels = common_labels();
+        let with_kind = |kind: &str| {
+            let mut labels = labels.clone();
+            labels.push(("kind", kind.to_string()));
+            labels
+        };
+        Self {
+            acceptor: TlsAcceptor::from(server_config),
+            offload: None,
+            full_latency: histogram_handle(PROXY_TLS_HANDSHAKE_LATENCY, &with_kind("full")),
+            resumed_latency: histogram_handle(PROXY_TLS_HANDSHAKE_LATENCY, &with_kind("resumed")),
+            failed: counter_handle(PROXY_TLS_HANDSHAKE_FAILED, labels),
+        }
+    }
+
+    pub fn with_options(options: &ClientTlsOptions) -> Result<Self, Error> {
+        let mut handshaker = Self::new(options.server_config()?);
+        if options.handshake_threads > 0 {
+            let runtime = tokio::runtime::Builder::new_multi_thread()
+                .worker_threads(options.handshake_threads)
+                .thread_name("tls-handshake")
+                .enable_all()
+                .build()?;
+            info!(
+                "ProxySrv TLS handshakes offloaded to {} threads",
+                options.handshake_threads
+            );
+            handshaker.offload = Some(runtime);
+        }
+        Ok(handshaker)
+    }
+
+    pub fn server_config(&self) -> Arc<ServerConfig> {
+        Arc::clone(self.acceptor.config())
+    }
+
+    pub async fn accept<S>(&self, stream: S) -> Result<TlsStream<S>, Error>
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        let started = Instant::now();
+        let accept = self.acceptor.accept(stream);
+        let rs = match &self.offload {
+            Some(runtime) => runtime.spawn(accept).await.map_err(Error::other)?,
+            None => accept.await,
+        };
+        match rs {
+            Ok(tls_stream) => {
+                let latency = started.elapsed().as_secs_f64() * 1000.0;
+                if tls_stream.get_ref().1.handshake_kind() == Some(HandshakeKind::Resumed) {
+                    self.resumed_latency.record(latency);
+                } else {
+                    self.full_latency.record(latency);
+                }
+                Ok(tls_stream)
+            }
+            Err(e) => {
+                debug!("ProxySrv TLS handshake failed {e:?}");
+                self.failed.increment(1);
+                Err(e)
+            }
+        }
+    }
+}
+
+/// The SNI hostname the client asked for in its TLS handshake, lowercased. `None` if it connected
+/// by IP address.
+pub fn server_name<S>(tls_stream: &TlsStream<S>) -> Option<String> {
+    tls_stream
+        .get_ref()
+        .1
+        .server_name()
+        .map(str::to_ascii_lowercase)
+}
+
+impl Drop for TlsHandshaker {
+    fn drop(&mut self) {
+        // Dropping a runtime blocks, which is not allowed inside the runtime of the server.
+        if let Some(runtime) = self.offload.take() {
+            runtime.shutdown_background();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::server::acme::acme_cert_resolver;
+    use crate::server::client_tls::ClientTlsOptions;
+
+    #[test]
+    pub fn test_client_tls_options() {
+        let mut options = ClientTlsOptions::new("server.crt".into(), "server.key".into());
+        options.cipher_suites = vec!["TLS13_AES_128_GCM_SHA256".to_string()];
+        options.protocol_versions = vec!["1.3".to_string()];
+        assert_eq!(options.crypto_provider().unwrap().cipher_suites.len(), 1);
+        assert_eq!(options.protocol_versions().unwrap().len(), 1);
+
+        options.cipher_suites = vec!["TLS_NULL_WITH_NULL_NULL".to_string()];
+        assert!(options.crypto_provider().is_err());
+        options.protocol_versions = vec!["1.1".to_string()];
+        assert!(options.protocol_versions().is_err());
+        assert!(options.server_config().is_err());
+
+        // The certificate is picked per handshake, the config does not need one yet.
+        let options = ClientTlsOptions::with_cert_resolver(acme_cert_resolver());
+        assert!(options.server_config().is_ok());
+    }
+}
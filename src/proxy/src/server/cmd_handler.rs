@@ -1,9 +1,40 @@
+use crate::protocol::mysql::error_codes::ErrorKind;
 use crate::protocol::mysql::packet::packet_writer::PacketWriter;
+use mysql_common::constants::CapabilityFlags;
+use thiserror::Error;
 use tokio::io::AsyncWrite;
 
+/// A MySQL error a [`CmdHandler`] wants to report to the client. Wrap it with [`cmd_error`]
+/// and return it from any callback; [`StaticProxyServer`](crate::server::static_proxy::StaticProxyServer)
+/// turns it into an ERR packet and keeps the connection open.
+#[derive(Error, Debug, Clone)]
+#[error("{kind:?}: {message}")]
+pub struct CmdError {
+    pub kind: ErrorKind,
+    pub message: String,
+}
+
+pub fn cmd_error(kind: ErrorKind, message: impl Into<String>) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::Other,
+        CmdError {
+            kind,
+            message: message.into(),
+        },
+    )
+}
+
 /// [`CmdHandler`] implements a storage backend compatible with the MySQL wire protocol.
+///
+/// Every callback that receives a `pkt_writer` must write a complete response (OK, ERR or a
+/// result set); the server flushes it after the callback returns. Callbacks without a writer
+/// correspond to commands that have no response on the wire.
 #[async_trait::async_trait]
 pub trait CmdHandler: Send + Sync {
+    /// Called after the initial handshake with the connection id and the capabilities negotiated
+    /// with the client, e.g. to decide between EOF and OK terminated result sets.
+    fn on_connect(&mut self, _conn_id: u64, _capabilities: CapabilityFlags) {}
+
     async fn auth(
         &mut self,
         auth_plugin: &str,
@@ -38,9 +69,28 @@ pub trait CmdHandler: Send + Sync {
 
     async fn on_execute<'a, W>(
         &mut self,
-        packet: &[u8],
+        stmt_id: u32,
+        params: &[u8],
         pkt_writer: &mut PacketWriter<W>,
     ) -> Result<(), std::io::Error>
     where
         W: AsyncWrite + Send + Unpin;
+
+    /// COM_STMT_SEND_LONG_DATA has no response.
+    async fn on_send_long_data(
+        &mut self,
+        _stmt_id: u32,
+        _param: u16,
+        _data: &[u8],
+    ) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+
+    /// COM_STMT_CLOSE has no response.
+    async fn on_close(&mut self, _stmt_id: u32) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+
+    /// Called when the client quits, the connection drops or the server shuts down.
+    async fn on_quit(&mut self) {}
 }
@@ -0,0 +1,370 @@
+use crate::prost::common_proto::TenantKey;
+use crate::protocol::mysql::constants::{CommandCode, SqlComInfo};
+use crate::protocol::mysql::error_codes::ErrorKind;
+use crate::protocol::mysql::packet::packet_writer::PacketWriter;
+use crate::protocol::mysql::packet::writers;
+use crate::server::protocol_features::{protocol_features, BINLOG_PASSTHROUGH};
+use crate::server::slow_log::tenant_label;
+
+use common::metrics::metric_def::PROXY_REPLICATION_REJECTED;
+use common::metrics::{common_labels, counter_inc};
+use dashmap::DashMap;
+use itertools::Itertools;
+use mysql_common::constants::CapabilityFlags;
+use num_traits::FromPrimitive;
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::io::Error;
+use std::sync::{OnceLock, RwLock};
+use tokio::io::AsyncWrite;
+use tracing::{debug, info, warn};
+
+/// Commands ordinary tenants must not send to a shared backend.
+pub const DEFAULT_DENIED_COMMANDS: [CommandCode; 4] = [
+    CommandCode::ComCreateDB,
+    CommandCode::ComDropDB,
+    CommandCode::ComShutdown,
+    CommandCode::ComBinlogDump,
+];
+
+/// User variables a replica sets before its binlog dump to negotiate semi-sync replication.
+const SEMI_SYNC_VARIABLES: [&str; 2] = ["@rpl_semi_sync_slave", "@rpl_semi_sync_replica"];
+
+/// Overrides the global deny list for one tenant.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TenantCommandPolicy {
+    pub tenant: TenantKey,
+    /// Commands allowed even if they are on the global deny list, e.g. for admin tenants.
+    #[serde(default)]
+    pub allow: Vec<CommandCode>,
+    /// Commands denied in addition to the global deny list.
+    #[serde(default)]
+    pub deny: Vec<CommandCode>,
+    /// Lets replicas register and dump the binlog through the proxy.
+    #[serde(default)]
+    pub replication: bool,
+    /// Lets the tenant run the proxy admin statements over every tenant, e.g. `SHOW PROXY
+    /// POOLS` or `KILL PROXY SESSION` of a session of another tenant.
+    #[serde(default)]
+    pub admin: bool,
+}
+
+/// What a replica sends that an ordinary client does not. A replica registered on a pooled
+/// backend connection would leave it streaming binlog events to the next session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplicationAttempt {
+    /// COM_REGISTER_SLAVE.
+    Register,
+    /// COM_BINLOG_DUMP or COM_BINLOG_DUMP_GTID.
+    BinlogDump,
+    /// `SET @rpl_semi_sync_slave = 1` and its replica spelling.
+    SemiSync,
+}
+
+/// Rejected replication attempts of one tenant.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TenantReplicationAttempts {
+    pub tenant: TenantKey,
+    pub attempts: u64,
+}
+
+impl ReplicationAttempt {
+    /// The replication attempt of the command `com_code` with `payload`, if it is one.
+    pub fn detect(com_code: CommandCode, payload: &[u8]) -> Option<Self> {
+        match com_code {
+            CommandCode::ComRegisterSlave => Some(ReplicationAttempt::Register),
+            CommandCode::ComBinlogDump | CommandCode::ComBinlogDumpGtid => {
+                Some(ReplicationAttempt::BinlogDump)
+            }
+            CommandCode::ComQuery => {
+                let sql = String::from_utf8_lossy(payload).to_ascii_lowercase();
+                let sql = sql.trim_start();
+                (sql.starts_with("set ")
+                    && SEMI_SYNC_VARIABLES
+                        .iter()
+                        .any(|variable| sql.contains(variable)))
+                .then_some(ReplicationAttempt::SemiSync)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            ReplicationAttempt::Register => "register",
+            ReplicationAttempt::BinlogDump => "binlog_dump",
+            ReplicationAttempt::SemiSync => "semi_sync",
+        }
+    }
+}
+
+impl TenantCommandPolicy {
+    fn is_allowed(&self, com_code: CommandCode, default_denied: bool) -> bool {
+        if self.deny.contains(&com_code) {
+            false
+        } else {
+            !default_denied || self.allow.contains(&com_code)
+        }
+    }
+}
+
+/// `CommandPolicy` decides which commands a tenant may forward to the backend. A command is
+/// rejected if it is on the global deny list, unless the tenant policy allows it, or if the tenant
+/// policy denies it. Tenant policies are managed by the control plane through the REST API.
+pub struct CommandPolicy {
+    default_deny: RwLock<Vec<CommandCode>>,
+    tenants: DashMap<TenantKey, TenantCommandPolicy>,
+    /// Replication attempts rejected per tenant.
+    replication_rejected: DashMap<TenantKey, u64>,
+}
+
+static COMMAND_POLICY_ONCE: OnceLock<CommandPolicy> = OnceLock::new();
+
+/// Initializes the global command policy, must be called before the first command is served.
+pub fn init_command_policy(default_deny: Vec<CommandCode>) -> &'static CommandPolicy {
+    COMMAND_POLICY_ONCE.get_or_init(|| CommandPolicy::new(default_deny))
+}
+
+pub fn command_policy() -> &'static CommandPolicy {
+    COMMAND_POLICY_ONCE.get_or_init(|| CommandPolicy::new(DEFAULT_DENIED_COMMANDS.to_vec()))
+}
+
+/// Parses a command name such as `ComDropDB`, case-insensitive.
+pub fn parse_command_code(name: &str) -> Option<CommandCode> {
+    SqlComInfo::all_sql_com()
+        .iter()
+        .find(|(_, com_name)| com_name.eq_ignore_ascii_case(name.trim()))
+        .and_then(|(code, _)| CommandCode::from_u8(*code))
+}
+
+impl CommandPolicy {
+    pub fn new(default_deny: Vec<CommandCode>) -> Self {
+        Self {
+            default_deny: RwLock::new(default_deny),
+            tenants: DashMap::new(),
+            replication_rejected: DashMap::new(),
+        }
+    }
+
+    pub fn default_deny(&self) -> Vec<CommandCode> {
+        self.default_deny.read().unwrap().clone()
+    }
+
+    pub fn set_default_deny(&self, default_deny: Vec<CommandCode>) {
+        info!("ProxySrv command policy default deny {:?}", default_deny);
+        *self.default_deny.write().unwrap() = default_deny;
+    }
+
+    pub fn set_tenant_policy(&self, policy: TenantCommandPolicy) {
+        info!("ProxySrv command policy set {:?}", policy);
+        self.tenants.insert(policy.tenant.clone(), policy);
+        self.register_replication();
+    }
+
+    pub fn remove_tenant_policy(&self, tenant: &TenantKey) -> Option<TenantCommandPolicy> {
+        info!("ProxySrv command policy removed {:?}", tenant);
+        let removed = self.tenants.remove(tenant).map(|(_, policy)| policy);
+        self.register_replication();
+        removed
+    }
+
+    /// Binlog passthrough is enabled while a tenant policy enables replication.
+    fn register_replication(&self) {
+        let enabled = self.tenants.iter().any(|e| e.value().replication);
+        protocol_features().set_enabled(BINLOG_PASSTHROUGH, enabled);
+    }
+
+    pub fn list(&self) -> Vec<TenantCommandPolicy> {
+        self.tenants.iter().map(|e| e.value().clone()).collect()
+    }
+
+    /// Whether `tenant` may replicate through the proxy. Replicas are rejected unless their
+    /// tenant policy enables replication, whatever the deny lists say.
+    pub fn replication_allowed(&self, tenant: &TenantKey) -> bool {
+        self.tenants
+            .get(tenant)
+            .is_some_and(|policy| policy.replication)
+    }
+
+    /// Whether `tenant` may run the proxy admin statements over every tenant. The others only
+    /// see and kill their own sessions.
+    pub fn is_admin(&self, tenant: &TenantKey) -> bool {
+        self.tenants.get(tenant).is_some_and(|policy| policy.admin)
+    }
+
+    /// Counts a rejected replication attempt of `tenant`.
+    pub fn replication_rejected(&self, tenant: &TenantKey, attempt: ReplicationAttempt) {
+        *self.replication_rejected.entry(tenant.clone()).or_default() += 1;
+        let mut labels = common_labels().clone();
+        labels.push(("tenant", tenant_label(tenant)));
+        labels.push(("kind", attempt.label().to_string()));
+        counter_inc(PROXY_REPLICATION_REJECTED, 1, Some(&labels));
+    }
+
+    /// Replication attempts rejected per tenant since the start, the most first.
+    pub fn replication_attempts(&self) -> Vec<TenantReplicationAttempts> {
+        self.replication_rejected
+            .iter()
+            .map(|e| TenantReplicationAttempts {
+                tenant: e.key().clone(),
+                attempts: *e.value(),
+            })
+            .sorted_by_key(|e| Reverse(e.attempts))
+            .collect()
+    }
+
+    pub fn is_allowed(&self, tenant: &TenantKey, com_code: CommandCode) -> bool {
+        // COM_QUIT always goes through, otherwise the connection could never be released.
+        if com_code == CommandCode::ComQuit {
+            return true;
+        }
+        let default_denied = self.default_deny.read().unwrap().contains(&com_code);
+        match self.tenants.get(tenant) {
+            Some(policy) => policy.is_allowed(com_code, default_denied),
+            None => !default_denied,
+        }
+    }
+}
+
+/// Rejects a denied command with an ERR packet. `seq` is the sequence id of the client command.
+pub async fn reject_command<W>(
+    com_code: CommandCode,
+    seq: u8,
+    client_writer: &mut PacketWriter<W>,
+    client_capabilities: CapabilityFlags,
+) -> Result<(), Error>
+where
+    W: AsyncWrite + Send + Unpin,
+{
+    debug!("ProxySrv command policy rejected {:?}", com_code);
+    let message = format!("Access denied; {com_code:?} is not allowed for this tenant");
+    client_writer.set_seq(seq.wrapping_add(1));
+    writers::write_err_packet(
+        ErrorKind::ER_SPECIFIC_ACCESS_DENIED_ERROR,
+        message.as_bytes(),
+        client_writer,
+        client_capabilities,
+    )
+    .await?;
+    client_writer.flush_all().await
+}
+
+/// Rejects a replication attempt with an ERR packet. `seq` is the sequence id of the client
+/// command.
+pub async fn reject_replication<W>(
+    attempt: ReplicationAttempt,
+    seq: u8,
+    client_writer: &mut PacketWriter<W>,
+    client_capabilities: CapabilityFlags,
+) -> Result<(), Error>
+where
+    W: AsyncWrite + Send + Unpin,
+{
+    warn!("ProxySrv command policy rejected replication attempt {attempt:?}");
+    let message = "Replication through the proxy is not enabled for this tenant; connect the \
+                   replica to the backend directly";
+    client_writer.set_seq(seq.wrapping_add(1));
+    writers::write_err_packet(
+        ErrorKind::ER_SPECIFIC_ACCESS_DENIED_ERROR,
+        message.as_bytes(),
+        client_writer,
+        client_capabilities,
+    )
+    .await?;
+    client_writer.flush_all().await
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::test_tenant_key;
+    use crate::prost::common_proto::TenantKey;
+    use crate::protocol::mysql::constants::CommandCode;
+    use crate::server::command_policy::{
+        parse_command_code, CommandPolicy, ReplicationAttempt, TenantCommandPolicy,
+        DEFAULT_DENIED_COMMANDS,
+    };
+
+    #[test]
+    pub fn test_command_policy() {
+        let policy = CommandPolicy::new(DEFAULT_DENIED_COMMANDS.to_vec());
+        let tenant = test_tenant_key();
+        let admin_tenant = TenantKey {
+            cluster_name: "admin".to_string(),
+            ..tenant.clone()
+        };
+        assert!(!policy.is_allowed(&tenant, CommandCode::ComDropDB));
+        assert!(policy.is_allowed(&tenant, CommandCode::ComQuery));
+
+        policy.set_tenant_policy(TenantCommandPolicy {
+            tenant: admin_tenant.clone(),
+            allow: DEFAULT_DENIED_COMMANDS.to_vec(),
+            deny: vec![CommandCode::ComProcessKill],
+            replication: true,
+            admin: true,
+        });
+        assert!(policy.is_allowed(&admin_tenant, CommandCode::ComDropDB));
+        assert!(!policy.is_allowed(&admin_tenant, CommandCode::ComProcessKill));
+        assert!(!policy.is_allowed(&tenant, CommandCode::ComDropDB));
+        assert!(policy.is_admin(&admin_tenant));
+        assert!(!policy.is_admin(&tenant));
+
+        policy.set_default_deny(vec![]);
+        assert!(policy.is_allowed(&tenant, CommandCode::ComDropDB));
+        assert!(policy.remove_tenant_policy(&admin_tenant).is_some());
+        assert!(policy.is_allowed(&admin_tenant, CommandCode::ComProcessKill));
+
+        assert_eq!(
+            parse_command_code("comdropdb"),
+            Some(CommandCode::ComDropDB)
+        );
+        assert_eq!(parse_command_code("ComNothing"), None);
+    }
+
+    #[test]
+    pub fn test_replication_attempts() {
+        let detect = ReplicationAttempt::detect;
+        assert_eq!(
+            detect(CommandCode::ComRegisterSlave, &[]),
+            Some(ReplicationAttempt::Register)
+        );
+        assert_eq!(
+            detect(CommandCode::ComBinlogDumpGtid, &[]),
+            Some(ReplicationAttempt::BinlogDump)
+        );
+        assert_eq!(
+            detect(CommandCode::ComQuery, b" SET @rpl_semi_sync_slave= 1"),
+            Some(ReplicationAttempt::SemiSync)
+        );
+        assert_eq!(
+            detect(CommandCode::ComQuery, b"SELECT @rpl_semi_sync_replica"),
+            None
+        );
+        assert_eq!(detect(CommandCode::ComQuery, b"SET autocommit = 1"), None);
+
+        let policy = CommandPolicy::new(DEFAULT_DENIED_COMMANDS.to_vec());
+        let tenant = test_tenant_key();
+        let replica_tenant = TenantKey {
+            cluster_name: "replica".to_string(),
+            ..tenant.clone()
+        };
+        policy.set_tenant_policy(TenantCommandPolicy {
+            tenant: replica_tenant.clone(),
+            allow: vec![],
+            deny: vec![],
+            replication: true,
+            admin: false,
+        });
+        assert!(policy.replication_allowed(&replica_tenant));
+        assert!(!policy.replication_allowed(&tenant));
+
+        policy.replication_rejected(&tenant, ReplicationAttempt::Register);
+        policy.replication_rejected(&tenant, ReplicationAttempt::SemiSync);
+        policy.replication_rejected(&replica_tenant, ReplicationAttempt::Register);
+        let attempts = policy
+            .replication_attempts()
+            .into_iter()
+            .map(|e| (e.tenant, e.attempts))
+            .collect::<Vec<_>>();
+        assert_eq!(attempts, vec![(tenant, 2), (replica_tenant, 1)]);
+    }
+}
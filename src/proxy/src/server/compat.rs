@@ -0,0 +1,319 @@
+use crate::protocol::mysql::constants::AuthPluginName;
+use crate::server::handshake_profile::HandshakeProfile;
+
+use mysql_common::constants::CapabilityFlags;
+use serde::Serialize;
+use std::sync::OnceLock;
+
+/// How a feature of a client library works through the proxy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FeatureSupport {
+    Supported,
+    /// The client works, without the feature or with a fallback.
+    Degraded,
+    Unsupported,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FeatureReport {
+    pub feature: &'static str,
+    pub support: FeatureSupport,
+    pub note: &'static str,
+}
+
+/// The handshake of a known client library, identified by its `_client_name` and
+/// `_client_version` connect attributes.
+#[derive(Debug)]
+pub struct ClientFingerprint {
+    pub client_name: &'static str,
+    pub client_version: &'static str,
+    /// Capabilities the library requests by default.
+    pub capabilities: CapabilityFlags,
+    /// The auth plugin the library starts with.
+    pub auth_plugin: AuthPluginName,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ClientCompatibility {
+    pub client_name: &'static str,
+    pub client_version: &'static str,
+    /// The worst support among the features of the client.
+    pub support: FeatureSupport,
+    pub features: Vec<FeatureReport>,
+}
+
+/// Capabilities clients may request that the proxy does not always offer, with what the client
+/// loses without them.
+const NEGOTIATED_FEATURES: [(&str, CapabilityFlags, &str); 7] = [
+    (
+        "deprecate_eof",
+        CapabilityFlags::CLIENT_DEPRECATE_EOF,
+        "result sets end with EOF packets",
+    ),
+    (
+        "session_track",
+        CapabilityFlags::CLIENT_SESSION_TRACK,
+        "session state changes are not reported",
+    ),
+    (
+        "query_attributes",
+        CapabilityFlags::CLIENT_QUERY_ATTRIBUTES,
+        "query attributes are not sent to the backend",
+    ),
+    (
+        "compression",
+        CapabilityFlags::CLIENT_COMPRESS,
+        "the client leg is uncompressed",
+    ),
+    (
+        "multi_statements",
+        CapabilityFlags::CLIENT_MULTI_STATEMENTS,
+        "statements must be sent one at a time",
+    ),
+    (
+        "ps_multi_results",
+        CapabilityFlags::CLIENT_PS_MULTI_RESULTS,
+        "procedures returning several results cannot be executed as prepared statements",
+    ),
+    (
+        "optional_resultset_metadata",
+        CapabilityFlags::CLIENT_OPTIONAL_RESULTSET_METADATA,
+        "result sets always carry their metadata",
+    ),
+];
+
+const BASE_CAPABILITIES: CapabilityFlags = CapabilityFlags::CLIENT_PROTOCOL_41
+    .union(CapabilityFlags::CLIENT_SECURE_CONNECTION)
+    .union(CapabilityFlags::CLIENT_PLUGIN_AUTH)
+    .union(CapabilityFlags::CLIENT_LONG_PASSWORD)
+    .union(CapabilityFlags::CLIENT_TRANSACTIONS)
+    .union(CapabilityFlags::CLIENT_MULTI_RESULTS)
+    .union(CapabilityFlags::CLIENT_CONNECT_ATTRS)
+    .union(CapabilityFlags::CLIENT_CONNECT_WITH_DB);
+
+/// Default handshakes of common client libraries.
+pub fn known_clients() -> Vec<ClientFingerprint> {
+    vec![
+        ClientFingerprint {
+            client_name: "libmysql",
+            client_version: "8.0.36",
+            capabilities: BASE_CAPABILITIES
+                | CapabilityFlags::CLIENT_PLUGIN_AUTH_LENENC_CLIENT_DATA
+                | CapabilityFlags::CLIENT_DEPRECATE_EOF
+                | CapabilityFlags::CLIENT_SESSION_TRACK
+                | CapabilityFlags::CLIENT_QUERY_ATTRIBUTES
+                | CapabilityFlags::CLIENT_MULTI_STATEMENTS
+                | CapabilityFlags::CLIENT_PS_MULTI_RESULTS,
+            auth_plugin: AuthPluginName::AuthCachingSha2Password,
+        },
+        ClientFingerprint {
+            client_name: "libmariadb",
+            client_version: "3.3.8",
+            capabilities: BASE_CAPABILITIES
+                | CapabilityFlags::CLIENT_PLUGIN_AUTH_LENENC_CLIENT_DATA
+                | CapabilityFlags::CLIENT_SESSION_TRACK
+                | CapabilityFlags::CLIENT_MULTI_STATEMENTS
+                | CapabilityFlags::CLIENT_PS_MULTI_RESULTS,
+            auth_plugin: AuthPluginName::AuthNativePassword,
+        },
+        ClientFingerprint {
+            client_name: "MySQL Connector/J",
+            client_version: "8.3.0",
+            capabilities: BASE_CAPABILITIES
+                | CapabilityFlags::CLIENT_PLUGIN_AUTH_LENENC_CLIENT_DATA
+                | CapabilityFlags::CLIENT_DEPRECATE_EOF
+                | CapabilityFlags::CLIENT_SESSION_TRACK
+                | CapabilityFlags::CLIENT_QUERY_ATTRIBUTES
+                | CapabilityFlags::CLIENT_PS_MULTI_RESULTS,
+            auth_plugin: AuthPluginName::AuthCachingSha2Password,
+        },
+        ClientFingerprint {
+            client_name: "MariaDB Connector/J",
+            client_version: "3.3.3",
+            capabilities: BASE_CAPABILITIES
+                | CapabilityFlags::CLIENT_PLUGIN_AUTH_LENENC_CLIENT_DATA
+                | CapabilityFlags::CLIENT_DEPRECATE_EOF
+                | CapabilityFlags::CLIENT_SESSION_TRACK
+                | CapabilityFlags::CLIENT_PS_MULTI_RESULTS,
+            auth_plugin: AuthPluginName::AuthNativePassword,
+        },
+        ClientFingerprint {
+            client_name: "Go-MySQL-Driver",
+            client_version: "1.8.1",
+            capabilities: BASE_CAPABILITIES | CapabilityFlags::CLIENT_LOCAL_FILES,
+            auth_plugin: AuthPluginName::AuthCachingSha2Password,
+        },
+        ClientFingerprint {
+            client_name: "pymysql",
+            client_version: "1.1.1",
+            capabilities: BASE_CAPABILITIES,
+            auth_plugin: AuthPluginName::AuthNativePassword,
+        },
+    ]
+}
+
+/// Evaluates the features of a client against the capabilities the listener offers.
+pub fn client_compatibility(
+    client: &ClientFingerprint,
+    offered: CapabilityFlags,
+) -> ClientCompatibility {
+    let mut features = vec![];
+    let missing_base = BASE_CAPABILITIES - CapabilityFlags::CLIENT_CONNECT_ATTRS - offered;
+    features.push(if missing_base.is_empty() {
+        FeatureReport {
+            feature: "protocol_41",
+            support: FeatureSupport::Supported,
+            note: "",
+        }
+    } else {
+        FeatureReport {
+            feature: "protocol_41",
+            support: FeatureSupport::Unsupported,
+            note: "the listener disabled a capability the handshake requires",
+        }
+    });
+    for (feature, capability, note) in NEGOTIATED_FEATURES {
+        if !client.capabilities.contains(capability) {
+            continue;
+        }
+        // CLIENT_COMPRESS is only ever negotiated on the backend leg.
+        let supported =
+            offered.contains(capability) && capability != CapabilityFlags::CLIENT_COMPRESS;
+        features.push(FeatureReport {
+            feature,
+            support: if supported {
+                FeatureSupport::Supported
+            } else {
+                FeatureSupport::Degraded
+            },
+            note: if supported { "" } else { note },
+        });
+    }
+    if client
+        .capabilities
+        .contains(CapabilityFlags::CLIENT_LOCAL_FILES)
+    {
+        features.push(FeatureReport {
+            feature: "local_infile",
+            support: FeatureSupport::Unsupported,
+            note: "LOAD DATA LOCAL INFILE requests are not forwarded",
+        });
+    }
+    // The proxy starts with mysql_native_password and relays the auth switch of the backend.
+    features.push(match client.auth_plugin {
+        AuthPluginName::AuthNativePassword => FeatureReport {
+            feature: "auth_plugin",
+            support: FeatureSupport::Supported,
+            note: "",
+        },
+        _ => FeatureReport {
+            feature: "auth_plugin",
+            support: FeatureSupport::Degraded,
+            note: "authenticates after an auth switch, mapped identities require mysql_native_password",
+        },
+    });
+    features.push(if cfg!(feature = "tls") {
+        FeatureReport {
+            feature: "tls",
+            support: FeatureSupport::Supported,
+            note: "",
+        }
+    } else {
+        FeatureReport {
+            feature: "tls",
+            support: FeatureSupport::Unsupported,
+            note: "the proxy is built without the tls feature",
+        }
+    });
+    let support = features
+        .iter()
+        .map(|feature| feature.support)
+        .max_by_key(|support| *support as u8)
+        .unwrap_or(FeatureSupport::Supported);
+    ClientCompatibility {
+        client_name: client.client_name,
+        client_version: client.client_version,
+        support,
+        features,
+    }
+}
+
+static COMPAT_PROFILE_ONCE: OnceLock<HandshakeProfile> = OnceLock::new();
+
+/// Sets the profile of the client listener the compatibility report is evaluated for.
+pub fn init_client_compat(profile: HandshakeProfile) -> &'static HandshakeProfile {
+    COMPAT_PROFILE_ONCE.get_or_init(|| profile)
+}
+
+/// The compatibility of the known client libraries with the client listener. The offered
+/// capabilities depend on the known backends, so the report follows them.
+pub fn client_compat_report() -> Vec<ClientCompatibility> {
+    let offered = COMPAT_PROFILE_ONCE
+        .get_or_init(HandshakeProfile::default)
+        .capabilities();
+    known_clients()
+        .iter()
+        .map(|client| client_compatibility(client, offered))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::server::compat::{client_compatibility, known_clients, FeatureSupport};
+    use crate::server::default_capabilities;
+    use mysql_common::constants::CapabilityFlags;
+
+    #[test]
+    pub fn test_client_compatibility() {
+        let clients = known_clients();
+        let support = |name: &str, offered: CapabilityFlags, feature: &str| {
+            let client = clients
+                .iter()
+                .find(|client| client.client_name == name)
+                .unwrap();
+            client_compatibility(client, offered)
+                .features
+                .into_iter()
+                .find(|report| report.feature == feature)
+                .map(|report| report.support)
+        };
+        let offered = default_capabilities();
+        assert_eq!(
+            support("libmysql", offered, "query_attributes"),
+            Some(FeatureSupport::Degraded)
+        );
+        assert_eq!(
+            support("libmysql", offered, "deprecate_eof"),
+            Some(FeatureSupport::Supported)
+        );
+        assert_eq!(
+            support(
+                "libmysql",
+                offered - CapabilityFlags::CLIENT_DEPRECATE_EOF,
+                "deprecate_eof"
+            ),
+            Some(FeatureSupport::Degraded)
+        );
+        assert_eq!(support("pymysql", offered, "deprecate_eof"), None);
+        assert_eq!(
+            support("pymysql", offered, "auth_plugin"),
+            Some(FeatureSupport::Supported)
+        );
+        assert_eq!(
+            support("Go-MySQL-Driver", offered, "local_infile"),
+            Some(FeatureSupport::Unsupported)
+        );
+        assert_eq!(
+            support(
+                "pymysql",
+                offered - CapabilityFlags::CLIENT_PROTOCOL_41,
+                "protocol_41"
+            ),
+            Some(FeatureSupport::Unsupported)
+        );
+
+        let pymysql = client_compatibility(clients.last().unwrap(), offered);
+        assert_eq!(pymysql.support, FeatureSupport::Supported);
+    }
+}
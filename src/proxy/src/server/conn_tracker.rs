@@ -0,0 +1,124 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::sync::{watch, Notify};
+
+/// Sent to the idle clients closed by a shutdown.
+pub const SHUTDOWN_NOTICE: &str = "proxy shutting down, please reconnect";
+
+/// `ConnTracker` counts the client sessions of a server and hands each of them a shutdown
+/// token. On shutdown the server stops accepting, the sessions end at their next idle point and
+/// the server waits for them to drain before it closes the backend pools.
+pub struct ConnTracker {
+    active: AtomicUsize,
+    drained: Notify,
+    shutdown: watch::Sender<bool>,
+}
+
+impl Default for ConnTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ConnTracker {
+    pub fn new() -> Self {
+        Self {
+            active: AtomicUsize::new(0),
+            drained: Notify::new(),
+            shutdown: watch::channel(false).0,
+        }
+    }
+
+    /// Tracks a session until the token is dropped.
+    pub fn track(&self) -> ConnToken<'_> {
+        self.active.fetch_add(1, Ordering::AcqRel);
+        ConnToken {
+            tracker: self,
+            shutdown: self.shutdown.subscribe(),
+        }
+    }
+
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::Acquire)
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        *self.shutdown.borrow()
+    }
+
+    /// Cancels the tokens of every session, then waits up to `timeout` for the sessions to end.
+    /// Returns the sessions still active.
+    pub async fn drain(&self, timeout: Duration) -> usize {
+        self.shutdown.send_replace(true);
+        let drained = async {
+            loop {
+                // Registered before the check, so a session ending in between still wakes it.
+                let notified = self.drained.notified();
+                if self.active() == 0 {
+                    return;
+                }
+                notified.await;
+            }
+        };
+        let _ = tokio::time::timeout(timeout, drained).await;
+        self.active()
+    }
+}
+
+/// Held by a session while it runs.
+pub struct ConnToken<'a> {
+    tracker: &'a ConnTracker,
+    shutdown: watch::Receiver<bool>,
+}
+
+impl ConnToken<'_> {
+    /// Resolves once the server shuts down.
+    pub async fn cancelled(&mut self) {
+        // The tracker outlives its tokens, the sender is never dropped while waiting.
+        let _ = self.shutdown.wait_for(|shutdown| *shutdown).await;
+    }
+}
+
+impl Drop for ConnToken<'_> {
+    fn drop(&mut self) {
+        if self.tracker.active.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.tracker.drained.notify_waiters();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::server::conn_tracker::ConnTracker;
+    use std::time::Duration;
+
+    #[tokio::test]
+    pub async fn test_conn_tracker() {
+        let tracker = ConnTracker::new();
+        assert_eq!(tracker.drain(Duration::from_secs(1)).await, 0);
+        assert!(tracker.is_shutdown());
+
+        let tracker = ConnTracker::new();
+        let busy = tracker.track();
+        let mut idle = tracker.track();
+        assert_eq!(tracker.active(), 2);
+        let drain = tracker.drain(Duration::from_millis(200));
+        let session = async {
+            // The idle session ends once cancelled, the busy one keeps going past the timeout.
+            idle.cancelled().await;
+            drop(idle);
+        };
+        let (remaining, ()) = tokio::join!(drain, session);
+        assert_eq!(remaining, 1);
+
+        let drain = tracker.drain(Duration::from_secs(5));
+        let session = async {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            drop(busy);
+        };
+        let (remaining, ()) = tokio::join!(drain, session);
+        assert_eq!(remaining, 0);
+        // A session tracked after the shutdown is cancelled right away.
+        tracker.track().cancelled().await;
+    }
+}
@@ -0,0 +1,479 @@
+use crate::backend::backend_mgr::BackendMgr;
+use crate::backend::BackendInstance;
+use crate::prost::common_proto::TenantKey;
+use crate::protocol::mysql::error_codes::ErrorKind;
+use crate::protocol::mysql::packet::packet_writer::PacketWriter;
+use crate::protocol::mysql::packet::writers;
+use crate::server::request_id::{current_request_id, with_deferred_request_id};
+use crate::server::session::session_registry;
+
+use chrono::{Local, SecondsFormat};
+use dashmap::DashMap;
+use mysql_common::constants::CapabilityFlags;
+use serde::{Deserialize, Serialize};
+use std::io::Error;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWrite;
+use tracing::info;
+
+pub const DEFAULT_DRAIN_DEADLINE_MS: u64 = 30_000;
+const DEFAULT_DRAIN_MESSAGE: &str = "The database is being moved, reconnect later";
+/// How often a drain checks the sessions left.
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+fn default_drain_deadline_ms() -> u64 {
+    DEFAULT_DRAIN_DEADLINE_MS
+}
+
+/// Drains a tenant, e.g. before it is migrated to another cluster or decommissioned.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DrainRequest {
+    pub tenant: TenantKey,
+    /// The error message of the sessions refused or closed by the drain.
+    #[serde(default)]
+    pub message: String,
+    /// Closes the sessions at their next transaction boundary instead of waiting for the clients
+    /// to disconnect.
+    #[serde(default)]
+    pub terminate_sessions: bool,
+    /// Sessions still in a transaction this long after the drain started are killed.
+    #[serde(default = "default_drain_deadline_ms")]
+    pub deadline_ms: u64,
+    /// Seconds after which the clients had better reconnect, added to the message as a hint.
+    #[serde(default)]
+    pub retry_after_secs: Option<u64>,
+    /// Endpoint the clients may reconnect to meanwhile, e.g. another proxy the control plane
+    /// picked, added to the message.
+    #[serde(default)]
+    pub alternative_endpoint: Option<String>,
+}
+
+impl DrainRequest {
+    /// The message with the reconnection hints, e.g. `moving, retry after 30s or connect to
+    /// proxy-b:3310`.
+    fn message(&self) -> String {
+        let mut message = if self.message.is_empty() {
+            DEFAULT_DRAIN_MESSAGE.to_string()
+        } else {
+            self.message.clone()
+        };
+        let hints = [
+            self.retry_after_secs
+                .map(|secs| format!("retry after {secs}s")),
+            self.alternative_endpoint
+                .as_ref()
+                .map(|endpoint| format!("connect to {endpoint}")),
+        ];
+        let hints = hints.into_iter().flatten().collect::<Vec<_>>();
+        if !hints.is_empty() {
+            message.push_str(", ");
+            message.push_str(&hints.join(" or "));
+        }
+        message
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DrainStatus {
+    pub request: DrainRequest,
+    /// RFC 3339 time the drain started.
+    pub started_at: String,
+    pub sessions: usize,
+    /// Set once the sessions are gone and the pools of the tenant were closed.
+    pub pools_released: bool,
+    /// The admin request that started the drain, an update keeps it.
+    pub request_id: Option<String>,
+}
+
+struct TenantDrain {
+    request: DrainRequest,
+    request_id: Option<String>,
+    started_at: String,
+    started: Instant,
+    pools_released: bool,
+}
+
+impl TenantDrain {
+    fn deadline_passed(&self) -> bool {
+        self.started.elapsed() >= Duration::from_millis(self.request.deadline_ms)
+    }
+}
+
+/// `DrainRegistry` keeps the tenants drained through the REST API. A drained tenant gets no new
+/// sessions, and its backends get no pools until the drain is removed, whatever the backend
+/// discovery reports meanwhile.
+#[derive(Default)]
+pub struct DrainRegistry {
+    tenants: DashMap<TenantKey, TenantDrain>,
+}
+
+static DRAIN_REGISTRY_ONCE: OnceLock<DrainRegistry> = OnceLock::new();
+
+pub fn drain_registry() -> &'static DrainRegistry {
+    DRAIN_REGISTRY_ONCE.get_or_init(DrainRegistry::default)
+}
+
+/// Whether `backend` belongs to the cluster of `tenant`.
+pub fn serves_tenant(backend: &BackendInstance, tenant: &TenantKey) -> bool {
+    backend.cluster.namespace == tenant.namespace
+        && backend.cluster.cluster_name == tenant.cluster_name
+}
+
+impl DrainRegistry {
+    /// Starts draining a tenant, or updates the drain already running. Returns true if the drain
+    /// is new and [`drain_tenant`] has to be started.
+    pub fn start(&self, request: DrainRequest) -> bool {
+        let request_id = current_request_id();
+        info!("ProxySrv drain started {request:?} request_id={request_id:?}");
+        let tenant = request.tenant.clone();
+        match self.tenants.get_mut(&tenant) {
+            Some(mut drain) => {
+                drain.request = request;
+                false
+            }
+            None => {
+                self.tenants.insert(
+                    tenant,
+                    TenantDrain {
+                        request,
+                        request_id,
+                        started_at: Local::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+                        started: Instant::now(),
+                        pools_released: false,
+                    },
+                );
+                true
+            }
+        }
+    }
+
+    /// Ends the drain of a tenant, its sessions are accepted again.
+    pub fn remove(&self, tenant: &TenantKey) -> Option<DrainRequest> {
+        info!(
+            "ProxySrv drain removed {tenant:?} request_id={:?}",
+            current_request_id()
+        );
+        self.tenants.remove(tenant).map(|(_, drain)| drain.request)
+    }
+
+    pub fn list(&self) -> Vec<DrainStatus> {
+        let sessions = session_registry();
+        self.tenants
+            .iter()
+            .map(|e| DrainStatus {
+                request: e.request.clone(),
+                started_at: e.started_at.clone(),
+                sessions: sessions.tenant_sessions(e.key()).len(),
+                pools_released: e.pools_released,
+                request_id: e.request_id.clone(),
+            })
+            .collect()
+    }
+
+    /// The error message refusing the new sessions of a drained tenant.
+    pub fn refusal(&self, tenant: &TenantKey) -> Option<String> {
+        if self.tenants.is_empty() {
+            return None;
+        }
+        self.tenants
+            .get(tenant)
+            .map(|drain| drain.request.message())
+    }
+
+    /// The error message closing a session of a drained tenant before its next command. A
+    /// session in a transaction is only closed once the deadline passed.
+    pub fn closes_session(&self, tenant: &TenantKey, in_transaction: bool) -> Option<String> {
+        if self.tenants.is_empty() {
+            return None;
+        }
+        let drain = self.tenants.get(tenant)?;
+        (drain.request.terminate_sessions && (!in_transaction || drain.deadline_passed()))
+            .then(|| drain.request.message())
+    }
+
+    /// Whether the discovery must not create a pool for `backend`.
+    pub fn drains_backend(&self, backend: &BackendInstance) -> bool {
+        !self.tenants.is_empty()
+            && self
+                .tenants
+                .iter()
+                .any(|drain| serves_tenant(backend, drain.key()))
+    }
+}
+
+/// Runs the drain of a tenant until its sessions are gone, then releases its pools. Sessions
+/// idle outside a transaction are closed right away if the drain terminates sessions, their
+/// clients told with an ER_SERVER_SHUTDOWN and the reconnection hints; the other sessions are
+/// killed once the deadline passed. Stops early if the drain is removed. The sessions closed and
+/// the log lines carry the id of the admin request that started the drain.
+pub async fn drain_tenant(backend_mgr: Arc<BackendMgr>, tenant: TenantKey) {
+    let request_id = drain_registry()
+        .tenants
+        .get(&tenant)
+        .and_then(|drain| drain.request_id.clone());
+    with_deferred_request_id(request_id, run_drain(backend_mgr, tenant)).await
+}
+
+async fn run_drain(backend_mgr: Arc<BackendMgr>, tenant: TenantKey) {
+    let registry = drain_registry();
+    let mut ticker = tokio::time::interval(DRAIN_POLL_INTERVAL);
+    loop {
+        ticker.tick().await;
+        let Some((terminate, deadline_passed, message)) =
+            registry.tenants.get(&tenant).map(|drain| {
+                (
+                    drain.request.terminate_sessions,
+                    drain.deadline_passed(),
+                    drain.request.message(),
+                )
+            })
+        else {
+            return;
+        };
+        let sessions = session_registry().tenant_sessions(&tenant);
+        if sessions.is_empty() {
+            break;
+        }
+        if terminate {
+            for session in sessions.iter().filter(|session| !session.is_killed()) {
+                if !session.in_transaction() {
+                    session.kill_with_notice(message.clone());
+                } else if deadline_passed {
+                    session.kill();
+                }
+            }
+        }
+    }
+    let released = backend_mgr.release_tenant_pools(&tenant);
+    if let Some(mut drain) = registry.tenants.get_mut(&tenant) {
+        drain.pools_released = true;
+    }
+    info!(
+        "ProxySrv drain of {tenant:?} finished, released {released} pools request_id={:?}",
+        current_request_id()
+    );
+}
+
+/// Refuses a new session or closes one of a drained tenant with `message`. `seq` is the sequence
+/// id of the client packet answered.
+pub async fn write_drain_err<W>(
+    message: &str,
+    seq: u8,
+    client_writer: &mut PacketWriter<W>,
+    client_capabilities: CapabilityFlags,
+) -> Result<(), Error>
+where
+    W: AsyncWrite + Send + Unpin,
+{
+    client_writer.set_seq(seq.wrapping_add(1));
+    writers::write_err_packet(
+        ErrorKind::ER_SERVER_SHUTDOWN,
+        message.as_bytes(),
+        client_writer,
+        client_capabilities,
+    )
+    .await?;
+    client_writer.flush_all().await
+}
+
+/// Tells an idle client its session is closed, before the connection is. The client sent no
+/// command, so the notice is an unsolicited packet of sequence id 0, the way the server reports
+/// its shutdown; the client reads it with the response of its next command.
+pub async fn write_shutdown_notice<W>(
+    message: &str,
+    client_writer: &mut PacketWriter<W>,
+    client_capabilities: CapabilityFlags,
+) -> Result<(), Error>
+where
+    W: AsyncWrite + Send + Unpin,
+{
+    client_writer.reset_seq();
+    writers::write_err_packet(
+        ErrorKind::ER_SERVER_SHUTDOWN,
+        message.as_bytes(),
+        client_writer,
+        client_capabilities,
+    )
+    .await?;
+    client_writer.flush_all().await
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::backend_mgr::{BackendManagerOptions, BackendMgr};
+    use crate::backend::router::new_backend_router;
+    use crate::backend::{test_tenant_key, BackendInstance};
+    use crate::prost::common_proto::TenantKey;
+    use crate::protocol::mysql::error_codes::ErrorKind;
+    use crate::protocol::mysql::packet::packet_reader::PacketReader;
+    use crate::protocol::mysql::packet::packet_writer::PacketWriter;
+    use crate::server::drain::{
+        drain_registry, drain_tenant, serves_tenant, write_shutdown_notice, DrainRequest,
+    };
+    use crate::server::proxy_cli_args::{BackendConfigArgs, ProxyServerArgs};
+    use crate::server::request_id::with_request_id;
+    use crate::server::session::session_registry;
+
+    use common::ShutdownMessage;
+    use mysql_common::constants::CapabilityFlags;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::sync::watch;
+
+    #[test]
+    pub fn test_drain_registry() {
+        let registry = drain_registry();
+        let tenant = TenantKey {
+            cluster_name: "drained".to_string(),
+            ..test_tenant_key()
+        };
+        assert_eq!(registry.refusal(&tenant), None);
+        let session = session_registry().register(&tenant, "app".to_string());
+
+        let request = DrainRequest {
+            tenant: tenant.clone(),
+            message: String::new(),
+            terminate_sessions: false,
+            deadline_ms: 60_000,
+            retry_after_secs: None,
+            alternative_endpoint: None,
+        };
+        assert!(registry.start(request.clone()));
+        assert!(registry.refusal(&tenant).is_some());
+        assert_eq!(registry.closes_session(&tenant, false), None);
+        let terminate = DrainRequest {
+            terminate_sessions: true,
+            message: "moving".to_string(),
+            ..request
+        };
+        assert!(!registry.start(terminate.clone()));
+        assert_eq!(
+            registry.closes_session(&tenant, false),
+            Some("moving".to_string())
+        );
+        assert_eq!(registry.closes_session(&tenant, true), None);
+        registry.start(DrainRequest {
+            retry_after_secs: Some(30),
+            alternative_endpoint: Some("proxy-b:3310".to_string()),
+            ..terminate.clone()
+        });
+        assert_eq!(
+            registry.closes_session(&tenant, false).as_deref(),
+            Some("moving, retry after 30s or connect to proxy-b:3310")
+        );
+        // Only idle sessions are told why they are closed.
+        session.kill_with_notice(registry.refusal(&tenant).unwrap());
+        assert!(session.is_killed());
+        assert!(session.shutdown_notice().is_some());
+        registry.start(DrainRequest {
+            deadline_ms: 0,
+            ..terminate
+        });
+        assert!(registry.closes_session(&tenant, true).is_some());
+
+        let mut backend = BackendInstance::default();
+        backend.cluster.namespace = tenant.namespace.clone();
+        backend.cluster.cluster_name = tenant.cluster_name.clone();
+        assert!(serves_tenant(&backend, &tenant));
+        assert!(registry.drains_backend(&backend));
+        let status = registry
+            .list()
+            .into_iter()
+            .filter(|status| status.request.tenant == tenant)
+            .collect::<Vec<_>>();
+        assert_eq!(status[0].sessions, 1);
+        assert!(!status[0].pools_released);
+
+        drop(session);
+        assert!(registry
+            .remove(&tenant)
+            .is_some_and(|request| request.deadline_ms == 0));
+        assert_eq!(registry.refusal(&tenant), None);
+        assert!(!registry.drains_backend(&backend));
+    }
+
+    #[tokio::test]
+    pub async fn test_drain_tenant_notifies_idle_sessions() {
+        let tenant = TenantKey {
+            cluster_name: "drained-idle".to_string(),
+            ..test_tenant_key()
+        };
+        let idle = session_registry().register(&tenant, "idle".to_string());
+        let busy = session_registry().register(&tenant, "busy".to_string());
+        busy.transaction_flag().store(true, Ordering::Relaxed);
+        let request = DrainRequest {
+            tenant: tenant.clone(),
+            message: "moving".to_string(),
+            terminate_sessions: true,
+            deadline_ms: 60_000,
+            retry_after_secs: Some(30),
+            alternative_endpoint: None,
+        };
+        let registry = drain_registry();
+        with_request_id("req-drain".to_string(), async {
+            registry.start(request.clone());
+        })
+        .await;
+
+        let (_shutdown_tx, shutdown_rx) = watch::channel(ShutdownMessage::Init);
+        let args = ProxyServerArgs {
+            backend: Some(BackendConfigArgs::Backend {
+                backend_addr: "127.0.0.1:3306".to_string(),
+            }),
+            ..Default::default()
+        };
+        let router = new_backend_router(&args, &shutdown_rx).await;
+        let backend_mgr = Arc::new(BackendMgr::new(router, BackendManagerOptions::default()));
+        let drain = tokio::spawn(drain_tenant(backend_mgr, tenant.clone()));
+
+        idle.killed().await;
+        assert_eq!(
+            idle.shutdown_notice().as_deref(),
+            Some("moving, retry after 30s")
+        );
+        assert_eq!(idle.killed_by().as_deref(), Some("req-drain"));
+        // The session in a transaction is left alone until the deadline.
+        assert!(!busy.is_killed());
+        registry.start(DrainRequest {
+            deadline_ms: 0,
+            ..request
+        });
+        busy.killed().await;
+        assert_eq!(busy.shutdown_notice(), None);
+
+        drop((idle, busy));
+        tokio::time::timeout(Duration::from_secs(5), drain)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(registry
+            .list()
+            .iter()
+            .any(|status| status.request.tenant == tenant
+                && status.pools_released
+                && status.request_id.as_deref() == Some("req-drain")));
+        registry.remove(&tenant);
+    }
+
+    #[tokio::test]
+    pub async fn test_write_shutdown_notice() {
+        let mut writer = PacketWriter::new(vec![]);
+        writer.set_seq(5);
+        write_shutdown_notice("moving", &mut writer, CapabilityFlags::CLIENT_PROTOCOL_41)
+            .await
+            .unwrap();
+        let mut reader = PacketReader::new(&writer.inner_writer[..]);
+        let (seq, packet) = reader.next_async().await.unwrap().unwrap();
+        // Unsolicited, the client sent no command.
+        assert_eq!(seq, 0);
+        assert!(packet.is_err_packet());
+        assert_eq!(
+            u16::from_le_bytes([packet[1], packet[2]]),
+            ErrorKind::ER_SERVER_SHUTDOWN as u16
+        );
+        assert!(packet.ends_with(b"moving"));
+        assert!(reader.next_async().await.unwrap().is_none());
+    }
+}
@@ -0,0 +1,252 @@
+use crate::backend::pool::PooledConn;
+use crate::prost::common_proto::TenantKey;
+use crate::protocol::mysql::error_codes::ErrorKind;
+use crate::protocol::mysql::packet::packet_reader::ErrCodeHook;
+use crate::server::slow_log::tenant_label;
+
+use common::metrics::metric_def::PROXY_BACKEND_ERRORS;
+use common::metrics::{common_labels, counter_inc};
+use dashmap::DashMap;
+use serde::Serialize;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, OnceLock};
+
+/// Groups error codes by what an operator would look into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorClass {
+    Deadlock,
+    LockWaitTimeout,
+    AccessDenied,
+    DiskFull,
+    OutOfResources,
+    TooManyConnections,
+    DuplicateKey,
+    Syntax,
+    NotFound,
+    Interrupted,
+    /// The backend closed or is closing the connection, it must not serve another session.
+    ConnectionLost,
+    Other,
+}
+
+/// Codes [`ErrorKind`] does not know: client errors relayed by backends that are proxies
+/// themselves, and server errors newer than it.
+const CR_SERVER_GONE_ERROR: u16 = 2006;
+const CR_SERVER_LOST: u16 = 2013;
+const ER_CONNECTION_KILLED: u16 = 1927;
+const ER_CLIENT_INTERACTION_TIMEOUT: u16 = 4031;
+
+impl ErrorClass {
+    pub fn of(code: u16) -> Self {
+        let is = |kind: ErrorKind| code == kind as u16;
+        if is(ErrorKind::ER_LOCK_DEADLOCK) {
+            ErrorClass::Deadlock
+        } else if is(ErrorKind::ER_LOCK_WAIT_TIMEOUT) {
+            ErrorClass::LockWaitTimeout
+        } else if is(ErrorKind::ER_ACCESS_DENIED_ERROR)
+            || is(ErrorKind::ER_DBACCESS_DENIED_ERROR)
+            || is(ErrorKind::ER_TABLEACCESS_DENIED_ERROR)
+            || is(ErrorKind::ER_COLUMNACCESS_DENIED_ERROR)
+            || is(ErrorKind::ER_SPECIFIC_ACCESS_DENIED_ERROR)
+        {
+            ErrorClass::AccessDenied
+        } else if is(ErrorKind::ER_DISK_FULL)
+            || is(ErrorKind::ER_RECORD_FILE_FULL)
+            || is(ErrorKind::ER_ERROR_ON_WRITE)
+        {
+            ErrorClass::DiskFull
+        } else if is(ErrorKind::ER_OUTOFMEMORY)
+            || is(ErrorKind::ER_OUT_OF_RESOURCES)
+            || is(ErrorKind::ER_LOCK_TABLE_FULL)
+        {
+            ErrorClass::OutOfResources
+        } else if is(ErrorKind::ER_CON_COUNT_ERROR)
+            || is(ErrorKind::ER_TOO_MANY_USER_CONNECTIONS)
+            || is(ErrorKind::ER_USER_LIMIT_REACHED)
+        {
+            ErrorClass::TooManyConnections
+        } else if is(ErrorKind::ER_DUP_ENTRY) {
+            ErrorClass::DuplicateKey
+        } else if is(ErrorKind::ER_PARSE_ERROR) {
+            ErrorClass::Syntax
+        } else if is(ErrorKind::ER_NO_SUCH_TABLE)
+            || is(ErrorKind::ER_BAD_FIELD_ERROR)
+            || is(ErrorKind::ER_BAD_DB_ERROR)
+        {
+            ErrorClass::NotFound
+        } else if is(ErrorKind::ER_QUERY_INTERRUPTED) {
+            ErrorClass::Interrupted
+        } else if is(ErrorKind::ER_SERVER_SHUTDOWN)
+            || is(ErrorKind::ER_FORCING_CLOSE)
+            || is(ErrorKind::ER_ABORTING_CONNECTION)
+            || is(ErrorKind::ER_NEW_ABORTING_CONNECTION)
+            || is(ErrorKind::ER_NET_PACKET_TOO_LARGE)
+            || is(ErrorKind::ER_NET_READ_ERROR)
+            || is(ErrorKind::ER_NET_READ_INTERRUPTED)
+            || is(ErrorKind::ER_NET_ERROR_ON_WRITE)
+            || is(ErrorKind::ER_NET_WRITE_INTERRUPTED)
+            || [
+                CR_SERVER_GONE_ERROR,
+                CR_SERVER_LOST,
+                ER_CONNECTION_KILLED,
+                ER_CLIENT_INTERACTION_TIMEOUT,
+            ]
+            .contains(&code)
+        {
+            ErrorClass::ConnectionLost
+        } else {
+            ErrorClass::Other
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            ErrorClass::Deadlock => "deadlock",
+            ErrorClass::LockWaitTimeout => "lock_wait_timeout",
+            ErrorClass::AccessDenied => "access_denied",
+            ErrorClass::DiskFull => "disk_full",
+            ErrorClass::OutOfResources => "out_of_resources",
+            ErrorClass::TooManyConnections => "too_many_connections",
+            ErrorClass::DuplicateKey => "duplicate_key",
+            ErrorClass::Syntax => "syntax",
+            ErrorClass::NotFound => "not_found",
+            ErrorClass::Interrupted => "interrupted",
+            ErrorClass::ConnectionLost => "connection_lost",
+            ErrorClass::Other => "other",
+        }
+    }
+
+    /// Whether the backend connection that returned the error has to be closed instead of
+    /// going back to the pool.
+    pub fn invalidates_connection(&self) -> bool {
+        *self == ErrorClass::ConnectionLost
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ErrorCodeCount {
+    pub tenant: String,
+    pub backend: String,
+    pub code: u16,
+    pub class: ErrorClass,
+    pub count: u64,
+}
+
+/// `ErrorStats` counts the error codes backends return, by tenant and backend, so spikes of
+/// deadlocks, denied accesses or full disks show up at the proxy without the backend logs.
+/// Prometheus gets the error classes, the REST summary the individual codes.
+#[derive(Default)]
+pub struct ErrorStats {
+    counts: DashMap<(String, String, u16), u64>,
+}
+
+static ERROR_STATS_ONCE: OnceLock<ErrorStats> = OnceLock::new();
+
+pub fn error_stats() -> &'static ErrorStats {
+    ERROR_STATS_ONCE.get_or_init(ErrorStats::default)
+}
+
+impl ErrorStats {
+    pub fn record(&self, tenant: &str, backend: &str, code: u16) {
+        *self
+            .counts
+            .entry((tenant.to_string(), backend.to_string(), code))
+            .or_default() += 1;
+    }
+
+    /// The error codes recorded so far, the most frequent first.
+    pub fn summary(&self) -> Vec<ErrorCodeCount> {
+        let mut summary: Vec<_> = self
+            .counts
+            .iter()
+            .map(|entry| {
+                let (tenant, backend, code) = entry.key();
+                ErrorCodeCount {
+                    tenant: tenant.clone(),
+                    backend: backend.clone(),
+                    code: *code,
+                    class: ErrorClass::of(*code),
+                    count: *entry.value(),
+                }
+            })
+            .collect();
+        summary.sort_by(|a, b| {
+            b.count
+                .cmp(&a.count)
+                .then(a.tenant.cmp(&b.tenant))
+                .then(a.backend.cmp(&b.backend))
+                .then(a.code.cmp(&b.code))
+        });
+        summary
+    }
+}
+
+/// The hook recording the ERR packets a session of `tenant` reads from `backend` on `conn`. An
+/// error the connection does not survive invalidates it.
+pub fn err_code_hook(tenant: &TenantKey, backend: &str, conn: &PooledConn) -> ErrCodeHook {
+    let tenant = tenant_label(tenant);
+    let backend = backend.to_string();
+    let invalidated = Arc::clone(&conn.invalidated);
+    Arc::new(move |code| {
+        error_stats().record(&tenant, &backend, code);
+        let class = ErrorClass::of(code);
+        if class.invalidates_connection() {
+            invalidated.store(true, Ordering::Release);
+        }
+        let mut labels = common_labels().clone();
+        labels.push(("tenant", tenant.clone()));
+        labels.push(("backend", backend.clone()));
+        labels.push(("class", class.label().to_string()));
+        counter_inc(PROXY_BACKEND_ERRORS, 1, Some(&labels));
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::protocol::mysql::error_codes::ErrorKind;
+    use crate::protocol::mysql::packet::packet_reader::PacketReader;
+    use crate::server::error_stats::{ErrorClass, ErrorStats};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    pub async fn test_error_stats() {
+        assert_eq!(
+            ErrorClass::of(ErrorKind::ER_LOCK_DEADLOCK as u16),
+            ErrorClass::Deadlock
+        );
+        assert_eq!(
+            ErrorClass::of(ErrorKind::ER_TABLEACCESS_DENIED_ERROR as u16),
+            ErrorClass::AccessDenied
+        );
+        assert_eq!(ErrorClass::of(3024), ErrorClass::Other);
+        for code in [ErrorKind::ER_SERVER_SHUTDOWN as u16, 2013, 4031] {
+            assert!(ErrorClass::of(code).invalidates_connection());
+        }
+        assert!(!ErrorClass::Deadlock.invalidates_connection());
+
+        let stats = Arc::new(ErrorStats::default());
+        let hook_stats = Arc::clone(&stats);
+        // An ERR packet 1213 between an OK packet and a row starting with a NULL column.
+        let mut wire = vec![7, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0];
+        let err = [&[0xff, 0xbd, 0x04][..], b"#40001Deadlock"].concat();
+        wire.extend([err.len() as u8, 0, 0, 1]);
+        wire.extend(err);
+        wire.extend([1, 0, 0, 2, 0xfb]);
+        let mut reader = PacketReader::new(&wire[..]);
+        reader.set_err_hook(Some(Arc::new(move |code| {
+            hook_stats.record("tenant", "10.0.0.1:3306", code)
+        })));
+        while reader.next_async().await.unwrap().is_some() {}
+        stats.record("tenant", "10.0.0.2:3306", 1045);
+
+        let summary = stats.summary();
+        assert_eq!(summary.len(), 2);
+        assert_eq!(summary[0].backend, "10.0.0.1:3306");
+        assert_eq!(summary[0].code, 1213);
+        assert_eq!(summary[0].class, ErrorClass::Deadlock);
+        assert_eq!(summary[1].class, ErrorClass::AccessDenied);
+        stats.record("tenant", "10.0.0.2:3306", 1045);
+        assert_eq!(stats.summary()[0].count, 2);
+    }
+}
@@ -0,0 +1,272 @@
+use crate::async_packet_read;
+use crate::backend::pool::BackendConn;
+use crate::protocol::mysql::constants::{CommandCode, HeaderInfo};
+use crate::protocol::mysql::error_codes::ErrorKind;
+use crate::protocol::mysql::packet::packet_writer::PacketWriter;
+use crate::protocol::mysql::packet::writers;
+use crate::server::forwarder::session_state::SessionState;
+use crate::server::forwarder::set_option_forward::{
+    handshake_multi_statements, multi_statements_option,
+};
+use crate::server::forwarder::stmt_prepare_forward::SharedSessionStmts;
+use crate::server::mirror::{first_keyword, is_read_only, is_use_stmt, strip_leading_comments};
+
+use mysql_common::constants::CapabilityFlags;
+use std::io::{Error, Write};
+use tokio::io::AsyncWrite;
+
+/// Answers a command whose session lost its backend connection and could not fail over.
+pub const FAILOVER_FAILED: &str = "Lost the connection to the backend, reconnect and retry";
+/// Answers a command the lost connection may have executed, the session failed over.
+pub const FAILOVER_RECONNECTED: &str =
+    "Lost the connection to the backend during the command, its outcome is unknown; \
+     the session reconnected to another backend";
+
+/// Whether a statement changes the character set of the session.
+fn is_charset_stmt(sql: &str) -> bool {
+    let upper = strip_leading_comments(sql).to_ascii_uppercase();
+    let Some(rest) = upper.strip_prefix("SET") else {
+        return false;
+    };
+    let rest = rest.trim_start();
+    ["NAMES", "CHARACTER SET", "CHARSET"]
+        .iter()
+        .any(|charset| rest.starts_with(charset))
+}
+
+/// Whether a statement leaves state on the connection, other than its database and character
+/// set.
+fn holds_session_state(sql: &str) -> bool {
+    let keyword = first_keyword(sql);
+    ["SET", "LOCK", "PREPARE", "HANDLER"]
+        .iter()
+        .any(|stateful| keyword.eq_ignore_ascii_case(stateful))
+        || sql.to_ascii_uppercase().contains("GET_LOCK")
+}
+
+/// The state of a session replayed on the backend connections it checks out after the one it
+/// was authenticated on: its database, character set, multi-statements option and, with a
+/// statement registry, its prepared statements. A session with state the proxy cannot replay, e.g. another session
+/// variable or a lock, does not fail over and keeps its connection when multiplexed.
+#[derive(Debug, Default)]
+pub struct SessionReplay {
+    database: Option<(CommandCode, Vec<u8>)>,
+    charset: Option<Vec<u8>>,
+    /// Set by COM_SET_OPTION, the handshake capabilities tell it otherwise.
+    multi_statements: Option<bool>,
+    stmts: Option<SharedSessionStmts>,
+    /// Long data sent or a cursor opened, both live on the backend connection only.
+    pending_stmt: bool,
+    unreplayable: bool,
+}
+
+impl SessionReplay {
+    /// Replays the prepared statements recorded in `stmts`, without a registry a prepared
+    /// statement makes the session unreplayable.
+    pub fn new(stmts: Option<SharedSessionStmts>) -> Self {
+        Self {
+            stmts,
+            ..Default::default()
+        }
+    }
+
+    /// Tracks a command of the client before it is forwarded.
+    pub fn observe(&mut self, com_code: CommandCode, payload: &[u8]) {
+        match com_code {
+            CommandCode::ComInitDB => self.database = Some((com_code, payload.to_vec())),
+            CommandCode::ComQuery => {
+                let sql = String::from_utf8_lossy(payload);
+                if is_use_stmt(&sql) {
+                    self.database = Some((com_code, payload.to_vec()));
+                } else if is_charset_stmt(&sql) {
+                    self.charset = Some(payload.to_vec());
+                } else if holds_session_state(&sql) {
+                    self.unreplayable = true;
+                }
+            }
+            CommandCode::ComStmtPrepare => self.unreplayable |= self.stmts.is_none(),
+            CommandCode::ComRegisterSlave
+            | CommandCode::ComBinlogDump
+            | CommandCode::ComBinlogDumpGtid => self.unreplayable = true,
+            CommandCode::ComSetOption => {
+                if let Some(multi_statements) = multi_statements_option(payload) {
+                    self.multi_statements = Some(multi_statements);
+                }
+            }
+            CommandCode::ComStmtSendLongData => self.pending_stmt = true,
+            // The flags byte follows the statement id, any cursor type opens a cursor.
+            CommandCode::ComStmtExecute => {
+                self.pending_stmt = payload.get(4).is_some_and(|flags| *flags != 0)
+            }
+            CommandCode::ComStmtReset | CommandCode::ComStmtClose => self.pending_stmt = false,
+            CommandCode::ComResetConnection => {
+                self.charset = None;
+                self.pending_stmt = false;
+                self.unreplayable = false;
+            }
+            _ => {}
+        }
+    }
+
+    /// Whether the session survives the loss of a connection that held `lost`. Sticky-dirty
+    /// state, see [`SessionState::sticky_reason`], is never replayed.
+    pub fn is_replayable(&self, lost: SessionState) -> bool {
+        !self.unreplayable
+            && !self.pending_stmt
+            && !lost.in_transaction
+            && !lost.is_sticky()
+            && !lost.user_variables
+    }
+
+    /// The multi-statements option of the session, which the connections it checks out are
+    /// switched to.
+    pub fn multi_statements(&self, client_flag: CapabilityFlags) -> bool {
+        self.multi_statements
+            .unwrap_or_else(|| handshake_multi_statements(client_flag))
+    }
+
+    pub async fn replay(&self, conn: &mut BackendConn) -> Result<(), Error> {
+        if let Some((com_code, payload)) = &self.database {
+            replay_command(conn, *com_code, payload).await?;
+        }
+        if let Some(charset) = &self.charset {
+            replay_command(conn, CommandCode::ComQuery, charset).await?;
+        }
+        if let Some(stmts) = &self.stmts {
+            stmts.lock().await.reprepare(conn).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether a command the lost connection may have executed can be sent again: the backend did
+/// not receive it, or it has no side effects.
+pub fn is_retryable(com_code: CommandCode, payload: &[u8], received: bool) -> bool {
+    match com_code {
+        CommandCode::ComPing | CommandCode::ComInitDB => true,
+        CommandCode::ComQuery => !received || std::str::from_utf8(payload).is_ok_and(is_read_only),
+        _ => false,
+    }
+}
+
+/// Sends a command of the session to `conn` and expects an OK packet.
+pub async fn replay_command(
+    conn: &mut BackendConn,
+    com_code: CommandCode,
+    payload: &[u8],
+) -> Result<(), Error> {
+    let (reader, writer) = conn;
+    writer.reset_seq();
+    writer.write_all(&[com_code as u8])?;
+    writer.write_all(payload)?;
+    writer.end_packet().await?;
+    writer.flush_all().await?;
+    let (_, pkt) = async_packet_read!(reader);
+    if pkt[0] == HeaderInfo::OKHeader as u8 {
+        Ok(())
+    } else {
+        Err(Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("{com_code:?} refused by the backend"),
+        ))
+    }
+}
+
+/// Answers the command a session lost its backend connection in with a communication error,
+/// which clients retry. `seq` is the sequence id of the client packet answered.
+pub async fn write_failover_err<W>(
+    message: &str,
+    seq: u8,
+    client_writer: &mut PacketWriter<W>,
+    client_capabilities: CapabilityFlags,
+) -> Result<(), Error>
+where
+    W: AsyncWrite + Send + Unpin,
+{
+    client_writer.set_seq(seq.wrapping_add(1));
+    writers::write_err_packet(
+        ErrorKind::ER_NET_READ_ERROR,
+        message.as_bytes(),
+        client_writer,
+        client_capabilities,
+    )
+    .await?;
+    client_writer.flush_all().await
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::protocol::mysql::constants::CommandCode;
+    use crate::server::failover::{is_retryable, SessionReplay};
+    use crate::server::forwarder::session_state::SessionState;
+    use crate::server::forwarder::stmt_prepare_forward::SessionStmts;
+    use mysql_common::constants::CapabilityFlags;
+    use std::sync::Arc;
+    use tokio::sync::Mutex;
+
+    #[test]
+    pub fn test_session_replay() {
+        let mut replay = SessionReplay::default();
+        replay.observe(CommandCode::ComQuery, b"USE sbtest");
+        replay.observe(CommandCode::ComQuery, b"/* driver */ SET NAMES utf8mb4");
+        replay.observe(CommandCode::ComQuery, b"SELECT 1");
+        assert_eq!(
+            replay.database,
+            Some((CommandCode::ComQuery, b"USE sbtest".to_vec()))
+        );
+        assert_eq!(
+            replay.charset.as_deref(),
+            Some(&b"/* driver */ SET NAMES utf8mb4"[..])
+        );
+        assert!(replay.is_replayable(SessionState::default()));
+        let in_transaction = SessionState {
+            in_transaction: true,
+            ..Default::default()
+        };
+        assert!(!replay.is_replayable(in_transaction));
+        let user_locks = SessionState {
+            user_locks: true,
+            ..Default::default()
+        };
+        assert!(!replay.is_replayable(user_locks));
+
+        replay.observe(CommandCode::ComQuery, b"SET sql_mode = ''");
+        assert!(!replay.is_replayable(SessionState::default()));
+        replay.observe(CommandCode::ComResetConnection, &[]);
+        assert!(replay.is_replayable(SessionState::default()));
+        assert!(replay.charset.is_none());
+        replay.observe(CommandCode::ComStmtPrepare, b"SELECT ?");
+        assert!(!replay.is_replayable(SessionState::default()));
+        replay.observe(CommandCode::ComResetConnection, &[]);
+        let stmts = SessionStmts::new(CapabilityFlags::empty());
+        let mut registered = SessionReplay::new(Some(Arc::new(Mutex::new(stmts))));
+        registered.observe(CommandCode::ComStmtPrepare, b"SELECT ?");
+        assert!(registered.is_replayable(SessionState::default()));
+        registered.observe(CommandCode::ComStmtSendLongData, &[1, 0, 0, 0, 0, 0]);
+        assert!(!registered.is_replayable(SessionState::default()));
+        registered.observe(CommandCode::ComStmtExecute, &[1, 0, 0, 0, 0, 1, 0, 0, 0]);
+        assert!(registered.is_replayable(SessionState::default()));
+        // A cursor stays open on the connection until the statement is reset.
+        registered.observe(CommandCode::ComStmtExecute, &[1, 0, 0, 0, 1, 1, 0, 0, 0]);
+        assert!(!registered.is_replayable(SessionState::default()));
+        registered.observe(CommandCode::ComStmtReset, &[1, 0, 0, 0]);
+        assert!(registered.is_replayable(SessionState::default()));
+        replay.observe(CommandCode::ComQuery, b"LOCK TABLES t READ");
+        assert!(!replay.is_replayable(SessionState::default()));
+
+        let multi_statements = CapabilityFlags::CLIENT_MULTI_STATEMENTS;
+        assert!(replay.multi_statements(multi_statements));
+        replay.observe(CommandCode::ComSetOption, &[1, 0]);
+        assert!(!replay.multi_statements(multi_statements));
+
+        assert!(is_retryable(
+            CommandCode::ComQuery,
+            b"SELECT c FROM t",
+            true
+        ));
+        assert!(!is_retryable(CommandCode::ComQuery, b"DELETE FROM t", true));
+        assert!(is_retryable(CommandCode::ComQuery, b"DELETE FROM t", false));
+        assert!(is_retryable(CommandCode::ComPing, &[], true));
+        assert!(!is_retryable(CommandCode::ComStmtExecute, &[], false));
+    }
+}
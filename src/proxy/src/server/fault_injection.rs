@@ -0,0 +1,225 @@
+use crate::prost::common_proto::TenantKey;
+use crate::protocol::mysql::error_codes::ErrorKind;
+use crate::protocol::mysql::packet::packet_writer::PacketWriter;
+use crate::protocol::mysql::packet::writers;
+use crate::server::request_id::current_request_id;
+
+use dashmap::DashMap;
+use mysql_common::constants::CapabilityFlags;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::io::Error;
+use std::sync::OnceLock;
+use std::time::Duration;
+use tokio::io::AsyncWrite;
+use tracing::{debug, info};
+
+/// The scope a fault rule applies to. Tenant faults are evaluated on the forwarder layer
+/// (per command), backend faults are evaluated on the pool layer (per new backend connection)
+/// and on the forwarder layer (per command served by a connection to the backend).
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FaultTarget {
+    Tenant(TenantKey),
+    Backend(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum FaultKind {
+    /// Delay the command (or the backend connect) by `delay_ms`.
+    Latency { delay_ms: u64 },
+    /// Swallow the client packet; nothing is forwarded to the backend.
+    DropPacket,
+    /// Abort the connection as if the peer had reset it.
+    ResetConn,
+    /// Reply with an ERR packet instead of forwarding the command.
+    ErrResponse { code: u16, message: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FaultRule {
+    pub target: FaultTarget,
+    pub fault: FaultKind,
+    /// Probability in `[0, 1]` that the fault fires on a single evaluation.
+    #[serde(default = "default_probability")]
+    pub probability: f64,
+    /// The admin request that injected the fault, logged when the fault fires.
+    #[serde(default)]
+    pub request_id: Option<String>,
+}
+
+fn default_probability() -> f64 {
+    1.0
+}
+
+/// What the caller should do with the current command after a fault has been applied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultAction {
+    Continue,
+    Skip,
+}
+
+/// `FaultInjector` keeps the fault rules for chaos testing. It is empty unless an operator
+/// registers rules through the REST API, whose `/fault` endpoints are only served with
+/// `enable_fault_injection`, so the hot path only pays for an `is_empty` check.
+#[derive(Default)]
+pub struct FaultInjector {
+    rules: DashMap<FaultTarget, FaultRule>,
+}
+
+static FAULT_INJECTOR_ONCE: OnceLock<FaultInjector> = OnceLock::new();
+
+pub fn fault_injector() -> &'static FaultInjector {
+    FAULT_INJECTOR_ONCE.get_or_init(FaultInjector::default)
+}
+
+impl FaultInjector {
+    pub fn inject(&self, mut rule: FaultRule) {
+        rule.probability = rule.probability.clamp(0.0, 1.0);
+        rule.request_id = current_request_id().or(rule.request_id);
+        info!("ProxySrv fault injected {:?}", rule);
+        self.rules.insert(rule.target.clone(), rule);
+    }
+
+    pub fn remove(&self, target: &FaultTarget) -> Option<FaultRule> {
+        info!("ProxySrv fault removed {:?}", target);
+        self.rules.remove(target).map(|(_, rule)| rule)
+    }
+
+    pub fn clear(&self) {
+        self.rules.clear();
+    }
+
+    pub fn list(&self) -> Vec<FaultRule> {
+        self.rules.iter().map(|e| e.value().clone()).collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Returns the fault to apply for `target`, if a rule exists and its probability fires.
+    pub fn triggered(&self, target: &FaultTarget) -> Option<FaultKind> {
+        if self.rules.is_empty() {
+            return None;
+        }
+        let rule = self.rules.get(target)?;
+        let probability = rule.probability;
+        if probability >= 1.0 || rand::thread_rng().gen_bool(probability) {
+            debug!("ProxySrv fault triggered {:?}", rule.value());
+            Some(rule.fault.clone())
+        } else {
+            None
+        }
+    }
+
+    pub fn tenant_fault(&self, tenant: &TenantKey) -> Option<FaultKind> {
+        if self.rules.is_empty() {
+            return None;
+        }
+        self.triggered(&FaultTarget::Tenant(tenant.clone()))
+    }
+
+    pub fn backend_fault(&self, backend_addr: &str) -> Option<FaultKind> {
+        if self.rules.is_empty() {
+            return None;
+        }
+        self.triggered(&FaultTarget::Backend(backend_addr.to_string()))
+    }
+}
+
+/// Applies a tenant or a backend fault on the forwarder layer. `seq` is the sequence id of the
+/// client command.
+pub async fn apply_com_fault<W>(
+    fault: FaultKind,
+    seq: u8,
+    client_writer: &mut PacketWriter<W>,
+    client_capabilities: CapabilityFlags,
+) -> Result<FaultAction, Error>
+where
+    W: AsyncWrite + Send + Unpin,
+{
+    match fault {
+        FaultKind::Latency { delay_ms } => {
+            tokio::time::sleep(Duration::from_millis(delay_ms)).await;
+            Ok(FaultAction::Continue)
+        }
+        FaultKind::DropPacket => Ok(FaultAction::Skip),
+        FaultKind::ResetConn => Err(Error::new(
+            std::io::ErrorKind::ConnectionReset,
+            "connection reset by fault injection",
+        )),
+        FaultKind::ErrResponse { code, message } => {
+            client_writer.set_seq(seq.wrapping_add(1));
+            writers::write_err_packet(
+                ErrorKind::from(code),
+                message.as_bytes(),
+                client_writer,
+                client_capabilities,
+            )
+            .await?;
+            client_writer.flush_all().await?;
+            Ok(FaultAction::Skip)
+        }
+    }
+}
+
+/// Applies a backend fault on the pool layer, before a new backend connection is created.
+pub async fn apply_connect_fault(fault: FaultKind) -> Result<(), Error> {
+    match fault {
+        FaultKind::Latency { delay_ms } => {
+            tokio::time::sleep(Duration::from_millis(delay_ms)).await;
+            Ok(())
+        }
+        FaultKind::DropPacket | FaultKind::ResetConn => Err(Error::new(
+            std::io::ErrorKind::ConnectionRefused,
+            "backend connect refused by fault injection",
+        )),
+        FaultKind::ErrResponse { code, message } => Err(Error::new(
+            std::io::ErrorKind::ConnectionRefused,
+            format!("backend connect failed by fault injection {code}: {message}"),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::test_tenant_key;
+    use crate::server::fault_injection::{FaultInjector, FaultKind, FaultRule, FaultTarget};
+
+    #[test]
+    pub fn test_fault_rule_lifecycle() {
+        let injector = FaultInjector::default();
+        let tenant = test_tenant_key();
+        assert!(injector.tenant_fault(&tenant).is_none());
+
+        injector.inject(FaultRule {
+            target: FaultTarget::Tenant(tenant.clone()),
+            fault: FaultKind::Latency { delay_ms: 10 },
+            probability: 2.0,
+            request_id: None,
+        });
+        assert_eq!(
+            injector.tenant_fault(&tenant),
+            Some(FaultKind::Latency { delay_ms: 10 })
+        );
+        assert!(injector.backend_fault("127.0.0.1:3306").is_none());
+        assert_eq!(injector.list()[0].probability, 1.0);
+
+        injector.remove(&FaultTarget::Tenant(tenant.clone()));
+        assert!(injector.is_empty());
+    }
+
+    #[test]
+    pub fn test_fault_rule_zero_probability() {
+        let injector = FaultInjector::default();
+        injector.inject(FaultRule {
+            target: FaultTarget::Backend("127.0.0.1:3306".to_string()),
+            fault: FaultKind::ResetConn,
+            probability: 0.0,
+            request_id: None,
+        });
+        assert!(injector.backend_fault("127.0.0.1:3306").is_none());
+    }
+}
@@ -0,0 +1,140 @@
+use crate::async_packet_read;
+use crate::backend::stream::{BackendReadHalf, BackendWriteHalf};
+use crate::protocol::mysql::basic::HandshakeResponse;
+use crate::protocol::mysql::packet::packet_reader::PacketReader;
+use crate::protocol::mysql::packet::packet_writer::PacketWriter;
+use crate::protocol::mysql::packet::Packet;
+use crate::server::forwarder::{write_one_packet, ComForwarder};
+
+use async_trait::async_trait;
+use std::io::{Error, ErrorKind};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// Each binlog event is sent in a packet starting with the OK marker, the stream ends with an EOF
+/// packet, once a non-blocking dump reached the end of the binlog, or with an ERR packet.
+fn is_stream_end(packet: &[u8]) -> bool {
+    packet.first() != Some(&0x00)
+}
+
+/// Forwards COM_BINLOG_DUMP and COM_BINLOG_DUMP_GTID. The backend answers a dump with a stream of
+/// binlog events that lasts as long as the replica reads it, so the events are relayed as they
+/// arrive until the stream ends or either side disconnects. The replica may send packets within
+/// the stream, the acknowledgements of semi-sync replication, they are relayed to the backend.
+/// The connection is left in replication mode, the session never returns it to the pool.
+pub struct BinlogDumpForwarder;
+
+#[async_trait]
+impl<R, W> ComForwarder<R, W> for BinlogDumpForwarder
+where
+    R: AsyncRead + Send + Unpin,
+    W: AsyncWrite + Send + Unpin,
+{
+    async fn forward(
+        &self,
+        client_reader: &mut PacketReader<R>,
+        client_writer: &mut PacketWriter<W>,
+        backend_writer: &mut PacketWriter<BackendWriteHalf>,
+        backend_reader: &mut PacketReader<BackendReadHalf>,
+        _: &HandshakeResponse,
+    ) -> Result<Option<Packet>, Error> {
+        // Reading a packet is cancel safe, whichever side finishes first the other loses nothing.
+        tokio::select! {
+            end = relay_binlog_events(backend_reader, client_writer) => end.map(Some),
+            e = relay_replica_packets(client_reader, backend_writer) => Err(e),
+        }
+    }
+}
+
+/// Relays the events of the backend to the replica, flushed as soon as no more are buffered, and
+/// returns the packet that ended the stream.
+async fn relay_binlog_events<W>(
+    backend_reader: &mut PacketReader<BackendReadHalf>,
+    client_writer: &mut PacketWriter<W>,
+) -> Result<Packet, Error>
+where
+    W: AsyncWrite + Send + Unpin,
+{
+    loop {
+        let frames = backend_reader.take_frames(is_stream_end);
+        client_writer.relay_frames(frames).await?;
+        client_writer.flush_all().await?;
+        let (seq, packet) = async_packet_read!(backend_reader);
+        if is_stream_end(&packet) {
+            write_one_packet(client_writer, seq, &packet, true).await?;
+            return Ok(packet);
+        }
+        client_writer.relay_packet(seq, packet).await?;
+    }
+}
+
+/// Relays the packets of the replica to the backend until the replica disconnects.
+async fn relay_replica_packets<R>(
+    client_reader: &mut PacketReader<R>,
+    backend_writer: &mut PacketWriter<BackendWriteHalf>,
+) -> Error
+where
+    R: AsyncRead + Send + Unpin,
+{
+    loop {
+        match client_reader.next_async().await {
+            Ok(Some((seq, packet))) => {
+                if let Err(e) = write_one_packet(backend_writer, seq, &packet, true).await {
+                    return e;
+                }
+            }
+            Ok(None) => {
+                return Error::new(ErrorKind::ConnectionAborted, "the replica disconnected")
+            }
+            Err(e) => return e,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::protocol::mysql::constants::CommandCode;
+    use crate::server::forwarder::binlog_dump_forward::BinlogDumpForwarder;
+    use crate::server::forwarder::script::{PacketScript, Response};
+    use std::io::ErrorKind;
+
+    #[tokio::test]
+    pub async fn test_binlog_dump_forward() {
+        // The binlog position, the flags with BINLOG_DUMP_NON_BLOCK, the server id and the file.
+        let dump = [
+            &[CommandCode::ComBinlogDump as u8][..],
+            &4_u32.to_le_bytes(),
+            &1_u16.to_le_bytes(),
+            &2_u32.to_le_bytes(),
+            b"binlog.000001",
+        ]
+        .concat();
+        let events = [vec![0, 1, 2, 3], vec![0; 300], vec![0, 0xfe]];
+        // The semi-sync acknowledgement of the replica.
+        let ack = [&[0xef][..], &4_u64.to_le_bytes(), b"binlog.000001"].concat();
+        let script = events
+            .iter()
+            .fold(
+                PacketScript::new().expect_client_packet(&dump),
+                |script, event| script.backend_responds(Response::Raw(event.clone())),
+            )
+            .client_sends(&ack);
+        let outcome = script
+            .backend_responds(Response::Raw(vec![0xfe, 0, 0, 2, 0]))
+            .run(&BinlogDumpForwarder, CommandCode::ComBinlogDump, &dump)
+            .await;
+        outcome.assert_forwarded();
+        assert_eq!(outcome.client_received.len(), events.len() + 1);
+        assert!(outcome.response().unwrap().is_eof_packet());
+
+        let outcome = PacketScript::new()
+            .expect_client_packet(&dump)
+            .backend_responds(Response::Raw(events[0].clone()))
+            .backend_disconnects()
+            .run(&BinlogDumpForwarder, CommandCode::ComBinlogDump, &dump)
+            .await;
+        let e = outcome.result.as_ref().unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::ConnectionAborted);
+        // The replica is closed with the backend, the event may not reach it first.
+        assert!(outcome.backend_sent.starts_with(&outcome.client_received));
+    }
+}
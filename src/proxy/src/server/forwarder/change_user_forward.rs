@@ -1,3 +1,4 @@
+use crate::backend::stream::{BackendReadHalf, BackendWriteHalf};
 use crate::parse_err_packet;
 use crate::protocol::mysql::basic::HandshakeResponse;
 use crate::protocol::mysql::packet::packet_reader::PacketReader;
@@ -8,7 +9,6 @@ use crate::server::forwarder::ComForwarder;
 use async_trait::async_trait;
 use std::io::Error;
 use tokio::io::{AsyncRead, AsyncWrite};
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 
 pub struct ChangeUserForwarder;
 
@@ -22,8 +22,8 @@ where
         &self,
         client_reader: &mut PacketReader<R>,
         client_writer: &mut PacketWriter<W>,
-        backend_writer: &mut PacketWriter<OwnedWriteHalf>,
-        backend_reader: &mut PacketReader<OwnedReadHalf>,
+        backend_writer: &mut PacketWriter<BackendWriteHalf>,
+        backend_reader: &mut PacketReader<BackendReadHalf>,
         handshake_response: &HandshakeResponse,
     ) -> Result<Option<Packet>, Error> {
         loop {
@@ -64,3 +64,60 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::protocol::mysql::constants::CommandCode;
+    use crate::protocol::mysql::error_codes::ErrorKind;
+    use crate::server::forwarder::change_user_forward::ChangeUserForwarder;
+    use crate::server::forwarder::script::{PacketScript, Response};
+    use mysql_common::packets::{AuthPlugin, ComChangeUser, ComChangeUserMoreData};
+    use mysql_common::proto::MySerialize;
+
+    fn change_user() -> Vec<u8> {
+        let more_data =
+            ComChangeUserMoreData::new(45).with_auth_plugin(Some(AuthPlugin::MysqlNativePassword));
+        let mut packet = vec![];
+        ComChangeUser::new()
+            .with_user(Some(&b"reporting"[..]))
+            .with_auth_plugin_data(Some(&[7_u8; 20][..]))
+            .with_more_data(Some(more_data))
+            .serialize(&mut packet);
+        packet
+    }
+
+    #[tokio::test]
+    pub async fn test_change_user_forward() {
+        let request = change_user();
+        let auth_switch = [&[0xfe][..], b"mysql_native_password\0", &[1_u8; 20], &[0]].concat();
+        let outcome = PacketScript::new()
+            .expect_client_command(CommandCode::ComChangeUser)
+            .backend_responds(Response::Raw(auth_switch))
+            .client_sends(&[9_u8; 20])
+            .backend_responds(Response::ok())
+            .run(&ChangeUserForwarder, CommandCode::ComChangeUser, &request)
+            .await;
+        assert!(outcome
+            .result
+            .as_ref()
+            .unwrap()
+            .as_ref()
+            .unwrap()
+            .is_ok_packet());
+        outcome.assert_forwarded();
+        let seqs = outcome.client_received.iter().map(|(seq, _)| *seq);
+        assert_eq!(seqs.collect::<Vec<_>>(), vec![1, 3]);
+
+        let outcome = PacketScript::new()
+            .expect_client_command(CommandCode::ComChangeUser)
+            .backend_responds(Response::Err(
+                ErrorKind::ER_ACCESS_DENIED_ERROR,
+                "access denied",
+            ))
+            .run(&ChangeUserForwarder, CommandCode::ComChangeUser, &request)
+            .await;
+        let e = outcome.result.as_ref().unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::PermissionDenied);
+        outcome.assert_forwarded();
+    }
+}
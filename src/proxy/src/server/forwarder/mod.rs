@@ -1,9 +1,17 @@
+pub mod binlog_dump_forward;
 pub mod change_user_forward;
 pub mod query_forward;
 pub mod reset_conn_forward;
+#[cfg(test)]
+mod script;
+pub mod session_state;
+pub mod set_option_forward;
+pub mod stmt_long_data_forward;
 pub mod stmt_prepare_forward;
+pub mod stmt_reset_forward;
 
 use crate::async_packet_read;
+use crate::backend::stream::{BackendReadHalf, BackendWriteHalf};
 use crate::protocol::mysql::basic::HandshakeResponse;
 use crate::protocol::mysql::constants::AuthPluginName::UnKnowPluginName;
 use crate::protocol::mysql::constants::CommandCode;
@@ -12,6 +20,7 @@ use crate::protocol::mysql::packet::packet_writer::PacketWriter;
 use crate::protocol::mysql::packet::Packet;
 
 use async_trait::async_trait;
+use bytes::Bytes;
 use my_common::io::ParseBuf;
 use my_common::packets::{AuthPlugin, ComChangeUserMoreData};
 use my_common::proto::{MyDeserialize, MySerialize};
@@ -19,7 +28,55 @@ use mysql_common as my_common;
 use std::borrow::Cow;
 use std::io::{Error, Write};
 use tokio::io::{AsyncRead, AsyncWrite};
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+
+pub(crate) async fn write_one_packet<W>(
+    dest_writer: &mut PacketWriter<W>,
+    seq: u8,
+    pkt: &[u8],
+    is_flush: bool,
+) -> Result<(), Error>
+where
+    W: AsyncWrite + Send + Unpin,
+{
+    dest_writer.set_seq(seq);
+    dest_writer.write_all(pkt)?;
+    dest_writer.end_packet().await?;
+    if is_flush {
+        dest_writer.flush_all().await?
+    }
+    Ok(())
+}
+
+/// Relays the packets of `src_reader` to `dest_writer` unchanged, up to the first one `is_end`
+/// returns true for, which is returned instead, with the number of packets relayed before it.
+/// The complete packets the reader buffered are relayed together straight from its buffer, see
+/// [`PacketWriter::relay_frames`], so a large result set costs neither an allocation nor a
+/// system call per row.
+pub(crate) async fn relay_until<R, W, F>(
+    src_reader: &mut PacketReader<R>,
+    dest_writer: &mut PacketWriter<W>,
+    is_end: F,
+) -> Result<(usize, (u8, Packet)), Error>
+where
+    R: AsyncRead + Send + Unpin,
+    W: AsyncWrite + Send + Unpin,
+    F: Fn(&[u8]) -> bool + Send + Sync,
+{
+    let mut relayed = 0;
+    loop {
+        let frames = src_reader.take_frames(&is_end);
+        relayed += frames.packets;
+        dest_writer.relay_frames(frames).await?;
+        // No complete packet to relay is buffered: the end packet, a payload split into several
+        // packets or a packet still to read follows.
+        let (seq, packet) = async_packet_read!(src_reader);
+        if is_end(&packet) {
+            return Ok((relayed, (seq, packet)));
+        }
+        relayed += 1;
+        dest_writer.relay_packet(seq, packet).await?;
+    }
+}
 
 #[async_trait]
 pub trait ComForwarder<R, W>: Send + Sync
@@ -34,12 +91,7 @@ where
         is_flush: bool,
     ) -> Result<Packet, Error> {
         let (seq, src_rsp) = async_packet_read!(src_reader);
-        dest_writer.set_seq(seq);
-        dest_writer.write_all(&src_rsp)?;
-        dest_writer.end_packet().await?;
-        if is_flush {
-            dest_writer.flush_all().await?
-        }
+        write_one_packet(dest_writer, seq, &src_rsp, is_flush).await?;
         Ok(src_rsp)
     }
 
@@ -51,8 +103,8 @@ where
         seq: u8,
         com_code: CommandCode,
         handshake_response: &HandshakeResponse,
-        client_packet: Packet,
-        backend_writer: &mut PacketWriter<OwnedWriteHalf>,
+        client_packet: Bytes,
+        backend_writer: &mut PacketWriter<BackendWriteHalf>,
     ) -> Result<(), Error> {
         let pkt_option = match com_code {
             CommandCode::ComQuit => None,
@@ -76,15 +128,12 @@ where
                     .with_more_data(Some(change_user_more_data));
                 let mut new_client_packet = vec![];
                 updated_change_user_com.serialize(&mut new_client_packet);
-                Some(Packet::from_vec(new_client_packet))
+                Some(Bytes::from(new_client_packet))
             }
             _ => Some(client_packet),
         };
         if let Some(pkt) = pkt_option {
-            backend_writer.set_seq(seq);
-            backend_writer.write_all(&pkt)?;
-            backend_writer.end_packet().await?;
-            backend_writer.flush_all().await
+            write_one_packet(backend_writer, seq, &pkt, true).await
         } else {
             Ok(())
         }
@@ -95,8 +144,8 @@ where
         &self,
         client_reader: &mut PacketReader<R>,
         client_writer: &mut PacketWriter<W>,
-        backend_writer: &mut PacketWriter<OwnedWriteHalf>,
-        backend_reader: &mut PacketReader<OwnedReadHalf>,
+        backend_writer: &mut PacketWriter<BackendWriteHalf>,
+        backend_reader: &mut PacketReader<BackendReadHalf>,
         handshake_response: &HandshakeResponse,
     ) -> Result<Option<Packet>, Error>;
 }
@@ -113,8 +162,8 @@ where
         &self,
         _: &mut PacketReader<R>,
         client_writer: &mut PacketWriter<W>,
-        _: &mut PacketWriter<OwnedWriteHalf>,
-        backend_reader: &mut PacketReader<OwnedReadHalf>,
+        _: &mut PacketWriter<BackendWriteHalf>,
+        backend_reader: &mut PacketReader<BackendReadHalf>,
         _: &HandshakeResponse,
     ) -> Result<Option<Packet>, Error> {
         Ok(self
@@ -123,3 +172,72 @@ where
             .map(Some)?)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::protocol::mysql::constants::CommandCode;
+    use crate::protocol::mysql::packet::is_eof_payload;
+    use crate::protocol::mysql::packet::packet_reader::PacketReader;
+    use crate::protocol::mysql::packet::packet_writer::PacketWriter;
+    use crate::server::forwarder::script::{PacketScript, Response};
+    use crate::server::forwarder::{relay_until, GenericComForwarder};
+    use std::io::ErrorKind;
+    use std::io::Write;
+
+    #[tokio::test]
+    pub async fn test_generic_forward() {
+        let ping = [CommandCode::ComPing as u8];
+        let outcome = PacketScript::new()
+            .expect_client_packet(&ping)
+            .backend_responds(Response::ok())
+            .run(&GenericComForwarder, CommandCode::ComPing, &ping)
+            .await;
+        assert!(outcome.response().unwrap().is_ok_packet());
+        outcome.assert_forwarded();
+        assert_eq!(outcome.client_received[0].0, 1);
+
+        let outcome = PacketScript::new()
+            .expect_client_packet(&ping)
+            .backend_disconnects()
+            .run(&GenericComForwarder, CommandCode::ComPing, &ping)
+            .await;
+        let e = outcome.result.as_ref().unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::ConnectionAborted);
+        assert!(outcome.client_received.is_empty());
+    }
+
+    #[tokio::test]
+    pub async fn test_relay_until() {
+        let mut wire = PacketWriter::new(vec![]);
+        for (seq, row) in (1..=200_u8).map(|i| (i, vec![i; 50 + i as usize])) {
+            wire.set_seq(seq);
+            wire.write_all(&row).unwrap();
+            wire.end_packet().await.unwrap();
+        }
+        let rows = wire.inner_writer.clone();
+        wire.set_seq(201);
+        wire.write_all(&[0xfe, 0, 0, 2, 0]).unwrap();
+        wire.end_packet().await.unwrap();
+        wire.write_all(b"next").unwrap();
+        wire.end_packet().await.unwrap();
+
+        // Read in small chunks, packets straddle the reads.
+        let (mut backend, peer) = tokio::io::duplex(1000);
+        let mut reader = PacketReader::new(peer);
+        let mut writer = PacketWriter::new(vec![]);
+        let (_, relayed) = tokio::join!(
+            async { tokio::io::AsyncWriteExt::write_all(&mut backend, &wire.inner_writer).await },
+            relay_until(&mut reader, &mut writer, is_eof_payload)
+        );
+        let (relayed, (seq, end)) = relayed.unwrap();
+        assert_eq!(relayed, 200);
+        assert_eq!((seq, &end[..]), (201, &[0xfe, 0, 0, 2, 0][..]));
+        assert_eq!(writer.seq(), 201);
+        writer.flush_all().await.unwrap();
+        assert_eq!(writer.inner_writer, rows);
+        assert_eq!(writer.bytes_written(), rows.len() as u64);
+        // The packets after the end one are left to the reader.
+        let (_, next) = reader.next_async().await.unwrap().unwrap();
+        assert_eq!(&next[..], b"next");
+    }
+}
@@ -1,36 +1,135 @@
+use crate::async_packet_read;
+use crate::backend::pool::stmt_cache::SharedStmtCache;
+use crate::backend::stream::{BackendReadHalf, BackendWriteHalf};
 use crate::parse_err_packet;
-use crate::protocol::mysql::basic::{eof_server_status, ok_packet, HandshakeResponse};
+use crate::protocol::mysql::basic::{
+    eof_server_status, ok_packet, read_length_encoded_number, HandshakeResponse,
+};
 use crate::protocol::mysql::constants::CommandCode;
+use crate::protocol::mysql::error_codes::ErrorKind;
 use crate::protocol::mysql::packet::packet_reader::PacketReader;
 use crate::protocol::mysql::packet::packet_writer::PacketWriter;
-use crate::protocol::mysql::packet::Packet;
-use crate::server::forwarder::ComForwarder;
+use crate::protocol::mysql::packet::{
+    is_eof_payload, is_err_payload, is_result_set_eof_payload, Packet,
+};
+use crate::server::forwarder::session_state::SharedSessionState;
+use crate::server::forwarder::stmt_prepare_forward::reprepare_stmt;
+use crate::server::forwarder::{relay_until, write_one_packet, ComForwarder};
+use crate::server::maintenance::attach_notice_warning;
 
 use async_trait::async_trait;
 use byteorder::ByteOrder;
+use bytes::{Bytes, BytesMut};
 use mysql_common::constants::{CapabilityFlags, StatusFlags};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
 use tokio::io::{AsyncRead, AsyncWrite};
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+
+/// A COM_STMT_EXECUTE whose statement id was translated by the prepared statement cache.
+pub struct CachedExecute {
+    pub stmt_cache: SharedStmtCache,
+    pub client_id: u32,
+    /// The execute packet as sent to the backend.
+    pub request: Bytes,
+}
 
 pub struct QueryForwarder {
     pub com_code: CommandCode,
+    pub cached_execute: Option<CachedExecute>,
+    /// Set once the maintenance notice was attached to the statement as a warning, `None` if
+    /// the statement does not carry it.
+    pub notice_warning: Option<Arc<AtomicBool>>,
+    /// Set from the status flags ending the statement, see [`Session::transaction_flag`].
+    ///
+    /// [`Session::transaction_flag`]: crate::server::session::Session::transaction_flag
+    pub in_transaction: Arc<AtomicBool>,
+    /// The state of the backend connection serving the statement.
+    pub session_state: SharedSessionState,
+    /// Counts the result set rows forwarded to the client.
+    pub rows: Arc<AtomicU64>,
+}
+
+/// The backend no longer knows the statement or asks for it to be prepared again.
+fn is_reprepare_err(packet: &Packet) -> bool {
+    if !packet.is_err_packet() || packet.len() < 3 {
+        return false;
+    }
+    let code = byteorder::LittleEndian::read_u16(&packet[1..3]);
+    code == ErrorKind::ER_UNKNOWN_STMT_HANDLER as u16 || code == ErrorKind::ER_NEED_REPREPARE as u16
 }
 
 impl QueryForwarder {
+    /// Attaches the maintenance notice to `packet` if it ends the statement.
+    fn attach_notice(&self, packet: &mut Packet) {
+        if let Some(notice_warning) = &self.notice_warning {
+            if !notice_warning.load(Ordering::Relaxed) && attach_notice_warning(packet.as_mut()) {
+                notice_warning.store(true, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Executes a cached statement, re-preparing it once if the backend rejects the statement id.
+    async fn forward_cached_execute<W>(
+        &self,
+        cached_execute: &CachedExecute,
+        handshake: &HandshakeResponse,
+        backend_writer: &mut PacketWriter<BackendWriteHalf>,
+        backend_reader: &mut PacketReader<BackendReadHalf>,
+        client_writer: &mut PacketWriter<W>,
+    ) -> Result<(), std::io::Error>
+    where
+        W: AsyncWrite + Send + Unpin,
+    {
+        let first_packet = async_packet_read!(backend_reader);
+        if !is_reprepare_err(&first_packet.1) {
+            return self
+                .forward_query(handshake, backend_reader, client_writer, Some(first_packet))
+                .await;
+        }
+        let backend_id = reprepare_stmt(
+            &cached_execute.stmt_cache,
+            cached_execute.client_id,
+            backend_writer,
+            backend_reader,
+            handshake.client_flag,
+        )
+        .await?;
+        match backend_id {
+            Some(backend_id) => {
+                let mut request = BytesMut::from(&cached_execute.request[..]);
+                request[1..5].copy_from_slice(&backend_id.to_le_bytes());
+                write_one_packet(backend_writer, 0, &request, true).await?;
+                self.forward_query(handshake, backend_reader, client_writer, None)
+                    .await
+            }
+            None => {
+                self.forward_query(handshake, backend_reader, client_writer, Some(first_packet))
+                    .await
+            }
+        }
+    }
+
+    /// `first_packet` is a response packet already read from the backend but not yet forwarded.
     async fn forward_query<W>(
         &self,
         handshake: &HandshakeResponse,
-        backend_reader: &mut PacketReader<OwnedReadHalf>,
+        backend_reader: &mut PacketReader<BackendReadHalf>,
         client_writer: &mut PacketWriter<W>,
+        mut first_packet: Option<(u8, Packet)>,
     ) -> Result<(), std::io::Error>
     where
         W: AsyncWrite + Send + Unpin,
     {
         let capabilities = handshake.client_flag;
         loop {
-            let response_packet = self
-                .forward_one_packet(client_writer, backend_reader, false)
-                .await?;
+            let (seq, mut response_packet) = match first_packet.take() {
+                Some(first_packet) => first_packet,
+                None => async_packet_read!(backend_reader),
+            };
+            if response_packet.is_ok_packet() {
+                self.attach_notice(&mut response_packet);
+            }
+            write_one_packet(client_writer, seq, &response_packet, false).await?;
             // debug!(
             //     "ProxySrv forward_query start header = {:?}",
             //     response_packet[0]
@@ -38,18 +137,28 @@ impl QueryForwarder {
             let status_flag = if response_packet.is_ok_packet() {
                 client_writer.flush_all().await?;
                 let (_, ok_pkt) = ok_packet(&response_packet, capabilities).unwrap();
+                self.session_state
+                    .observe_session_track(&ok_pkt.session_state_info);
                 ok_pkt.status_flags
             } else if response_packet.is_err_packet() {
                 parse_err_packet!(capabilities, response_packet, "forward_query ERR");
+                self.session_state.observe_error();
                 client_writer.flush_all().await?;
                 return Ok(());
             } else if response_packet.is_local_in_file_packet() {
                 //TODO: supported it
                 unimplemented!("not supported LocalInFileHeader");
             } else {
-                self.forward_result(handshake, backend_reader, client_writer)
+                let columns =
+                    read_length_encoded_number(&response_packet).map_or(0, |(_, columns)| columns);
+                self.forward_result(handshake, columns, backend_reader, client_writer)
                     .await?
             };
+            self.in_transaction.store(
+                status_flag.contains(StatusFlags::SERVER_STATUS_IN_TRANS),
+                Ordering::Relaxed,
+            );
+            self.session_state.observe_status(status_flag);
             if !status_flag.contains(StatusFlags::SERVER_MORE_RESULTS_EXISTS) {
                 break;
             }
@@ -57,10 +166,12 @@ impl QueryForwarder {
         Ok(())
     }
 
+    /// Forwards a result set of `columns` columns after its column count packet.
     async fn forward_result<W>(
         &self,
         handshake: &HandshakeResponse,
-        backend_reader: &mut PacketReader<OwnedReadHalf>,
+        columns: u64,
+        backend_reader: &mut PacketReader<BackendReadHalf>,
         client_writer: &mut PacketWriter<W>,
     ) -> Result<StatusFlags, std::io::Error>
     where
@@ -70,14 +181,9 @@ impl QueryForwarder {
         let client_deprecate_eof =
             client_capability.contains(CapabilityFlags::CLIENT_DEPRECATE_EOF);
         if !client_deprecate_eof {
-            let resp_packet = loop {
-                let response_packet = self
-                    .forward_one_packet(client_writer, backend_reader, false)
-                    .await?;
-                if response_packet.is_eof_packet() {
-                    break response_packet;
-                }
-            };
+            let (_, (seq, resp_packet)) =
+                relay_until(backend_reader, client_writer, is_eof_payload).await?;
+            write_one_packet(client_writer, seq, &resp_packet, false).await?;
             let status_code = byteorder::LittleEndian::read_u16(&resp_packet[3..]);
             if let Some(status_flags) = StatusFlags::from_bits(status_code) {
                 if status_flags.contains(StatusFlags::SERVER_STATUS_CURSOR_EXISTS) {
@@ -86,15 +192,22 @@ impl QueryForwarder {
                     return Ok(status_flags);
                 }
             }
+            // The column definitions were forwarded up to their EOF packet.
+            return self
+                .forward_until_result_end(handshake, 0, backend_reader, client_writer)
+                .await;
         }
-        self.forward_until_result_end(handshake, backend_reader, client_writer)
+        self.forward_until_result_end(handshake, columns, backend_reader, client_writer)
             .await
     }
 
+    /// Forwards the packets of a result set up to its end, the first `definitions` ones are
+    /// column definitions and the following ones rows.
     async fn forward_until_result_end<W>(
         &self,
         handshake: &HandshakeResponse,
-        backend_reader: &mut PacketReader<OwnedReadHalf>,
+        definitions: u64,
+        backend_reader: &mut PacketReader<BackendReadHalf>,
         client_writer: &mut PacketWriter<W>,
     ) -> Result<StatusFlags, std::io::Error>
     where
@@ -103,33 +216,37 @@ impl QueryForwarder {
         let client_capability = handshake.client_flag;
         let client_deprecate_eof =
             client_capability.contains(CapabilityFlags::CLIENT_DEPRECATE_EOF);
-        loop {
-            let response_packet = self
-                .forward_one_packet(client_writer, backend_reader, false)
-                .await?;
-
-            if response_packet.is_err_packet() {
-                parse_err_packet!(
-                    client_capability,
-                    response_packet,
-                    "ComQuery forward_until_result_end ERR"
-                );
-                client_writer.flush_all().await?;
-                break;
-            }
-            if !client_deprecate_eof {
-                if response_packet.is_eof_packet() {
-                    client_writer.flush_all().await?;
-                    let (_, status_flag) = eof_server_status(&response_packet).unwrap();
-                    return Ok(status_flag);
-                }
-            } else if response_packet.is_result_set_eof_packet() {
-                let (_, ok_pkt) = ok_packet(&response_packet, client_capability).unwrap();
-                client_writer.flush_all().await?;
-                return Ok(ok_pkt.status_flags);
-            }
+        let is_end = |payload: &[u8]| {
+            is_err_payload(payload)
+                || (client_deprecate_eof && is_result_set_eof_payload(payload))
+                || (!client_deprecate_eof && is_eof_payload(payload))
+        };
+        // The rows are written to the client together, straight from the buffer of the backend.
+        let (relayed, (seq, mut response_packet)) =
+            relay_until(backend_reader, client_writer, is_end).await?;
+        let relayed = relayed as u64;
+        self.rows
+            .fetch_add(relayed - relayed.min(definitions), Ordering::Relaxed);
+        if response_packet.is_err_packet() {
+            write_one_packet(client_writer, seq, &response_packet, false).await?;
+            parse_err_packet!(
+                client_capability,
+                response_packet,
+                "ComQuery forward_until_result_end ERR"
+            );
+            client_writer.flush_all().await?;
+            return Ok(StatusFlags::default());
+        }
+        self.attach_notice(&mut response_packet);
+        write_one_packet(client_writer, seq, &response_packet, false).await?;
+        client_writer.flush_all().await?;
+        if client_deprecate_eof {
+            let (_, ok_pkt) = ok_packet(&response_packet, client_capability).unwrap();
+            Ok(ok_pkt.status_flags)
+        } else {
+            let (_, status_flag) = eof_server_status(&response_packet).unwrap();
+            Ok(status_flag)
         }
-        Ok(StatusFlags::default())
     }
 }
 
@@ -143,17 +260,35 @@ where
         &self,
         _: &mut PacketReader<R>,
         client_writer: &mut PacketWriter<W>,
-        _: &mut PacketWriter<OwnedWriteHalf>,
-        backend_reader: &mut PacketReader<OwnedReadHalf>,
+        backend_writer: &mut PacketWriter<BackendWriteHalf>,
+        backend_reader: &mut PacketReader<BackendReadHalf>,
         handshake: &HandshakeResponse,
     ) -> Result<Option<Packet>, std::io::Error> {
-        let query_rs = match self.com_code {
-            CommandCode::ComQuery | CommandCode::ComStmtExecute | CommandCode::ComProcessInfo => {
-                self.forward_query(handshake, backend_reader, client_writer)
+        let query_rs = match (self.com_code, &self.cached_execute) {
+            (CommandCode::ComStmtExecute, Some(cached_execute)) => {
+                self.forward_cached_execute(
+                    cached_execute,
+                    handshake,
+                    backend_writer,
+                    backend_reader,
+                    client_writer,
+                )
+                .await
+            }
+            (
+                CommandCode::ComQuery | CommandCode::ComStmtExecute | CommandCode::ComProcessInfo,
+                _,
+            ) => {
+                self.forward_query(handshake, backend_reader, client_writer, None)
                     .await
             }
-            CommandCode::ComFieldList | CommandCode::ComStmtFetch => self
-                .forward_until_result_end(handshake, backend_reader, client_writer)
+            // COM_FIELD_LIST answers column definitions only.
+            (CommandCode::ComFieldList, _) => self
+                .forward_until_result_end(handshake, u64::MAX, backend_reader, client_writer)
+                .await
+                .map(|_| ()),
+            (CommandCode::ComStmtFetch, _) => self
+                .forward_until_result_end(handshake, 0, backend_reader, client_writer)
                 .await
                 .map(|_| ()),
             _ => {
@@ -166,3 +301,146 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::protocol::mysql::constants::CommandCode;
+    use crate::protocol::mysql::error_codes::ErrorKind;
+    use crate::server::default_capabilities;
+    use crate::server::forwarder::query_forward::QueryForwarder;
+    use crate::server::forwarder::script::{PacketScript, Response};
+    use crate::server::forwarder::session_state::SessionStateTracker;
+    use mysql_common::constants::{CapabilityFlags, StatusFlags};
+    use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+    use std::sync::Arc;
+
+    fn query_forwarder() -> QueryForwarder {
+        QueryForwarder {
+            com_code: CommandCode::ComQuery,
+            cached_execute: None,
+            notice_warning: None,
+            in_transaction: Arc::new(AtomicBool::new(false)),
+            session_state: Arc::new(SessionStateTracker::default()),
+            rows: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    fn query(sql: &str) -> Vec<u8> {
+        [&[CommandCode::ComQuery as u8], sql.as_bytes()].concat()
+    }
+
+    #[tokio::test]
+    pub async fn test_query_forward() {
+        let begin = query("BEGIN");
+        let forwarder = query_forwarder();
+        let outcome = PacketScript::new()
+            .expect_client_packet(&begin)
+            .backend_responds(Response::Ok(StatusFlags::SERVER_STATUS_IN_TRANS))
+            .run(&forwarder, CommandCode::ComQuery, &begin)
+            .await;
+        assert!(outcome.response().is_none());
+        outcome.assert_forwarded();
+        assert!(forwarder.in_transaction.load(Ordering::Relaxed));
+        assert!(forwarder.session_state.state().in_transaction);
+
+        // Result sets end with an OK or an EOF packet, as the client asked.
+        let select = query("SELECT a, b FROM t");
+        let rows = vec![vec![Some("1"), None], vec![Some("2"), Some("x")]];
+        let without_eof = default_capabilities() - CapabilityFlags::CLIENT_DEPRECATE_EOF;
+        for (capabilities, packets) in [(default_capabilities(), 6), (without_eof, 7)] {
+            let outcome = PacketScript::with_capabilities(capabilities)
+                .expect_client_packet(&select)
+                .backend_responds(Response::Rows(vec!["a", "b"], rows.clone()))
+                .run(&forwarder, CommandCode::ComQuery, &select)
+                .await;
+            assert!(outcome.response().is_none());
+            outcome.assert_forwarded();
+            assert_eq!(outcome.client_received.len(), packets);
+            assert_eq!(forwarder.rows.swap(0, Ordering::Relaxed), 2);
+        }
+        assert!(!forwarder.in_transaction.load(Ordering::Relaxed));
+        assert!(forwarder.session_state.is_clean());
+
+        // Rows are relayed in batches, a result larger than a batch arrives whole and in order.
+        let many_rows = vec![vec![Some("row")]; 150];
+        let outcome = PacketScript::new()
+            .expect_client_packet(&select)
+            .backend_responds(Response::Rows(vec!["a"], many_rows))
+            .run(&forwarder, CommandCode::ComQuery, &select)
+            .await;
+        outcome.assert_forwarded();
+        assert_eq!(outcome.client_received.len(), 153);
+        assert_eq!(forwarder.rows.swap(0, Ordering::Relaxed), 150);
+
+        // Every result of a multi statement is forwarded.
+        let multi = query("DELETE FROM t; SELECT a FROM t");
+        let more_results =
+            StatusFlags::SERVER_STATUS_AUTOCOMMIT | StatusFlags::SERVER_MORE_RESULTS_EXISTS;
+        let outcome = PacketScript::new()
+            .expect_client_packet(&multi)
+            .backend_responds(Response::Ok(more_results))
+            .backend_responds(Response::Rows(vec!["a"], vec![vec![Some("1")]]))
+            .run(&forwarder, CommandCode::ComQuery, &multi)
+            .await;
+        assert!(outcome.response().is_none());
+        outcome.assert_forwarded();
+        assert_eq!(outcome.client_received.len(), 5);
+
+        let missing = query("SELECT a FROM missing");
+        let outcome = PacketScript::new()
+            .expect_client_packet(&missing)
+            .backend_responds(Response::Err(ErrorKind::ER_NO_SUCH_TABLE, "no table"))
+            .run(&forwarder, CommandCode::ComQuery, &missing)
+            .await;
+        assert!(outcome.response().is_none());
+        outcome.assert_forwarded();
+
+        // A backend lost mid result set fails the session after what was already forwarded.
+        let outcome = PacketScript::new()
+            .expect_client_packet(&select)
+            .backend_responds(Response::Raw(vec![2]))
+            .backend_disconnects()
+            .run(&forwarder, CommandCode::ComQuery, &select)
+            .await;
+        let e = outcome.result.as_ref().unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::ConnectionAborted);
+        assert_eq!(outcome.client_received, vec![(1, vec![2])]);
+    }
+
+    #[tokio::test]
+    pub async fn test_query_forward_rows() {
+        let result_end = vec![0xfe, 0, 0, 2, 0, 0, 0];
+        // COM_FIELD_LIST answers column definitions, they are not rows.
+        let mut forwarder = query_forwarder();
+        forwarder.com_code = CommandCode::ComFieldList;
+        let field_list = [&[CommandCode::ComFieldList as u8][..], b"t\0"].concat();
+        let outcome = PacketScript::new()
+            .expect_client_packet(&field_list)
+            .backend_responds(Response::Raw(b"\x03deftt_a".to_vec()))
+            .backend_responds(Response::Raw(b"\x03deftt_b".to_vec()))
+            .backend_responds(Response::Raw(result_end.clone()))
+            .run(&forwarder, CommandCode::ComFieldList, &field_list)
+            .await;
+        outcome.assert_forwarded();
+        assert_eq!(outcome.client_received.len(), 3);
+        assert_eq!(forwarder.rows.swap(0, Ordering::Relaxed), 0);
+
+        // COM_STMT_FETCH answers rows of the open cursor only.
+        forwarder.com_code = CommandCode::ComStmtFetch;
+        let fetch = [
+            &[CommandCode::ComStmtFetch as u8][..],
+            &1_u32.to_le_bytes(),
+            &2_u32.to_le_bytes(),
+        ]
+        .concat();
+        let outcome = PacketScript::new()
+            .expect_client_packet(&fetch)
+            .backend_responds(Response::Raw(vec![0, 0, 1, b'1']))
+            .backend_responds(Response::Raw(vec![0, 0, 1, b'2']))
+            .backend_responds(Response::Raw(result_end))
+            .run(&forwarder, CommandCode::ComStmtFetch, &fetch)
+            .await;
+        outcome.assert_forwarded();
+        assert_eq!(forwarder.rows.swap(0, Ordering::Relaxed), 2);
+    }
+}
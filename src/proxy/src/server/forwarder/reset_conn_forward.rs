@@ -1,4 +1,5 @@
 use crate::async_packet_read;
+use crate::backend::stream::{BackendReadHalf, BackendWriteHalf};
 use crate::protocol::mysql::basic::HandshakeResponse;
 use crate::protocol::mysql::packet::packet_writer::PacketWriter;
 use crate::protocol::mysql::packet::writers::write_reset_connection;
@@ -8,8 +9,9 @@ use crate::server::forwarder::ComForwarder;
 use async_trait::async_trait;
 use packet_reader::PacketReader;
 use tokio::io::{AsyncRead, AsyncWrite};
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 
+/// Resets the backend connection on COM_QUIT so it goes back to the pool without the session
+/// state of the client, returns the response of the reset.
 pub struct ResetConnForwarder;
 
 #[async_trait]
@@ -22,13 +24,60 @@ where
         &self,
         _: &mut PacketReader<R>,
         _: &mut PacketWriter<W>,
-        backend_writer: &mut PacketWriter<OwnedWriteHalf>,
-        backend_reader: &mut PacketReader<OwnedReadHalf>,
+        backend_writer: &mut PacketWriter<BackendWriteHalf>,
+        backend_reader: &mut PacketReader<BackendReadHalf>,
         _: &HandshakeResponse,
     ) -> Result<Option<Packet>, std::io::Error> {
+        backend_writer.reset_seq();
         write_reset_connection(backend_writer).await?;
-        let (_be_seq, _be_rsp_pkt) = async_packet_read!(backend_reader);
+        let (_be_seq, be_rsp_pkt) = async_packet_read!(backend_reader);
+        Ok(Some(be_rsp_pkt))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::protocol::mysql::constants::CommandCode;
+    use crate::protocol::mysql::error_codes::ErrorKind;
+    use crate::server::forwarder::reset_conn_forward::ResetConnForwarder;
+    use crate::server::forwarder::script::{PacketScript, Response};
+    use crate::server::session::SessionCloseReason;
+
+    #[tokio::test]
+    pub async fn test_reset_conn_forward() {
+        let quit = [CommandCode::ComQuit as u8];
+        let outcome = PacketScript::new()
+            .expect_client_command(CommandCode::ComResetConnection)
+            .backend_responds(Response::ok())
+            .run(&ResetConnForwarder, CommandCode::ComQuit, &quit)
+            .await;
+        assert!(outcome.client_received.is_empty());
+        let reason = SessionCloseReason::on_quit(outcome.response());
+        assert_eq!(reason, SessionCloseReason::Quit);
+        assert!(reason.is_backend_reusable());
+
+        let outcome = PacketScript::new()
+            .expect_client_command(CommandCode::ComResetConnection)
+            .backend_responds(Response::Err(
+                ErrorKind::ER_UNKNOWN_COM_ERROR,
+                "Unknown command",
+            ))
+            .run(&ResetConnForwarder, CommandCode::ComQuit, &quit)
+            .await;
+        assert!(outcome.client_received.is_empty());
+        let reason = SessionCloseReason::on_quit(outcome.response());
+        assert_eq!(reason, SessionCloseReason::QuitResetFailed);
+        assert!(!reason.is_backend_reusable());
+        assert_eq!(reason.label(), "quit_reset_failed");
 
-        Ok(None)
+        let outcome = PacketScript::new()
+            .backend_disconnects()
+            .run(&ResetConnForwarder, CommandCode::ComQuit, &quit)
+            .await;
+        assert!(outcome.result.is_err());
+        assert_eq!(
+            SessionCloseReason::on_quit(None),
+            SessionCloseReason::QuitResetFailed
+        );
     }
 }
@@ -0,0 +1,332 @@
+//! A scripted packet exchange to unit test the forwarders. A [`PacketScript`] lists what the
+//! backend expects from the proxy and what it responds, in order; [`PacketScript::run`] sends the
+//! client command through a forwarder and returns what the client received. Both sides are
+//! in-memory duplex streams, the backend is an in-process `mem://` backend.
+
+use crate::backend::stream::{connect_backend, mem_backends, MemListener, MEM_SCHEME};
+use crate::protocol::mysql::basic::{Column, HandshakeResponse};
+use crate::protocol::mysql::constants::CommandCode;
+use crate::protocol::mysql::error_codes::ErrorKind;
+use crate::protocol::mysql::packet::packet_reader::PacketReader;
+use crate::protocol::mysql::packet::packet_writer::PacketWriter;
+use crate::protocol::mysql::packet::{writers, Packet};
+use crate::server::default_capabilities;
+use crate::server::forwarder::{write_one_packet, ComForwarder};
+
+use bytes::Bytes;
+use mysql_common::constants::{CapabilityFlags, ColumnFlags, ColumnType, StatusFlags};
+use nanoid::nanoid;
+use std::io::Error;
+use std::time::Duration;
+use tokio::io::{AsyncWriteExt, DuplexStream, ReadHalf, WriteHalf};
+use tokio::sync::mpsc;
+
+/// Bytes the client side buffers, the client only reads once the forwarder returned.
+const CLIENT_BUFFER_SIZE: usize = 1024 * 1024;
+/// A forwarder still running after this waits for a packet the script never sends.
+const SCRIPT_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub(crate) type ClientReader = ReadHalf<DuplexStream>;
+pub(crate) type ClientWriter = WriteHalf<DuplexStream>;
+
+/// What the backend responds.
+#[derive(Debug, Clone)]
+pub(crate) enum Response {
+    Ok(StatusFlags),
+    Err(ErrorKind, &'static str),
+    /// A text result set of the `columns` and rows, ended the way the client capabilities ask.
+    Rows(Vec<&'static str>, Vec<Vec<Option<&'static str>>>),
+    /// A COM_STMT_PREPARE_OK of statement `id` followed by its definitions.
+    PrepareOk {
+        id: u32,
+        params: usize,
+        columns: usize,
+    },
+    /// One packet of any payload, e.g. an auth switch request.
+    Raw(Vec<u8>),
+}
+
+impl Response {
+    pub fn ok() -> Self {
+        Response::Ok(StatusFlags::SERVER_STATUS_AUTOCOMMIT)
+    }
+
+    fn columns(names: &[&str]) -> Vec<Column> {
+        names
+            .iter()
+            .map(|name| Column {
+                table: "t".to_string(),
+                column: name.to_string(),
+                column_type: ColumnType::MYSQL_TYPE_VAR_STRING,
+                column_flags: ColumnFlags::empty(),
+            })
+            .collect()
+    }
+
+    /// The packets of the response with their sequence ids, the first one is `seq`.
+    async fn packets(&self, seq: u8, capabilities: CapabilityFlags) -> Vec<(u8, Vec<u8>)> {
+        let mut w = PacketWriter::new(Vec::new());
+        w.set_seq(seq);
+        match self {
+            Response::Ok(status) => writers::write_ok_packet(&mut w, 0, 0, *status).await,
+            Response::Err(kind, message) => {
+                writers::write_err_packet(*kind, message.as_bytes(), &mut w, capabilities).await
+            }
+            Response::Rows(names, rows) => {
+                let rows = rows
+                    .iter()
+                    .map(|row| row.iter().map(|v| v.map(str::to_string)).collect())
+                    .collect::<Vec<_>>();
+                let columns = Response::columns(names);
+                writers::write_text_result_set(&columns, &rows, &mut w, capabilities).await
+            }
+            Response::PrepareOk {
+                id,
+                params,
+                columns,
+            } => {
+                let params = Response::columns(&vec!["?"; *params]);
+                let columns = Response::columns(&vec!["c"; *columns]);
+                writers::write_prepare_ok(*id, &params, &columns, &mut w, capabilities).await
+            }
+            Response::Raw(payload) => {
+                std::io::Write::write_all(&mut w, payload).unwrap();
+                w.end_packet().await
+            }
+        }
+        .unwrap();
+        w.flush_all().await.unwrap();
+        read_all(&w.inner_writer[..]).await
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Step {
+    ExpectClientPacket(Vec<u8>),
+    ExpectClientCommand(CommandCode),
+    ClientSends(Vec<u8>),
+    BackendResponds(Response),
+    BackendDisconnects,
+}
+
+/// Reads packets until the peer closes.
+async fn read_all<R>(r: R) -> Vec<(u8, Vec<u8>)>
+where
+    R: tokio::io::AsyncRead + Send + Unpin,
+{
+    let mut reader = PacketReader::new(r);
+    let mut packets = vec![];
+    while let Ok(Some((seq, packet))) = reader.next_async().await {
+        packets.push((seq, packet.to_vec()));
+    }
+    packets
+}
+
+/// What a script run saw.
+pub(crate) struct ScriptOutcome {
+    pub result: Result<Option<Packet>, Error>,
+    /// Packets the backend sent, with their sequence ids.
+    pub backend_sent: Vec<(u8, Vec<u8>)>,
+    /// Packets the client received, with their sequence ids.
+    pub client_received: Vec<(u8, Vec<u8>)>,
+}
+
+impl ScriptOutcome {
+    /// The packet the forwarder returned, panics if it failed.
+    pub fn response(&self) -> Option<&Packet> {
+        self.result.as_ref().unwrap().as_ref()
+    }
+
+    /// Asserts that every packet of the backend reached the client unchanged.
+    pub fn assert_forwarded(&self) {
+        assert_eq!(
+            self.client_received, self.backend_sent,
+            "the client did not receive what the backend sent"
+        );
+    }
+}
+
+pub(crate) struct PacketScript {
+    handshake: HandshakeResponse,
+    steps: Vec<Step>,
+}
+
+impl PacketScript {
+    pub fn new() -> Self {
+        Self::with_capabilities(default_capabilities())
+    }
+
+    pub fn with_capabilities(capabilities: CapabilityFlags) -> Self {
+        Self {
+            handshake: HandshakeResponse {
+                client_flag: capabilities,
+                max_packet_len: 0,
+                collation: 45,
+                tenant_key: None,
+                username: Some(b"app".to_vec()),
+                auth_response: vec![],
+                auth_plugin: vec![],
+                database: None,
+                connect_attributes: None,
+                shard: None,
+                identity: None,
+                reconnect: None,
+                reconnect_token: None,
+                server_name: None,
+            },
+            steps: vec![],
+        }
+    }
+
+    /// The backend expects the next packet from the proxy to be `payload`.
+    pub fn expect_client_packet(mut self, payload: &[u8]) -> Self {
+        self.steps.push(Step::ExpectClientPacket(payload.to_vec()));
+        self
+    }
+
+    /// The backend expects the next packet from the proxy to be a `com_code` command, for
+    /// commands the proxy rewrites.
+    pub fn expect_client_command(mut self, com_code: CommandCode) -> Self {
+        self.steps.push(Step::ExpectClientCommand(com_code));
+        self
+    }
+
+    /// The client sends `payload` within the command, e.g. its auth switch response, and the
+    /// backend expects it forwarded.
+    pub fn client_sends(mut self, payload: &[u8]) -> Self {
+        self.steps.push(Step::ClientSends(payload.to_vec()));
+        self
+    }
+
+    pub fn backend_responds(mut self, response: Response) -> Self {
+        self.steps.push(Step::BackendResponds(response));
+        self
+    }
+
+    /// The backend closes the connection, whatever the script says next.
+    pub fn backend_disconnects(mut self) -> Self {
+        self.steps.push(Step::BackendDisconnects);
+        self
+    }
+
+    /// Sends the client `request` through `forwarder` the way the session does: written to the
+    /// backend, then the response forwarded.
+    pub async fn run<F>(self, forwarder: &F, com_code: CommandCode, request: &[u8]) -> ScriptOutcome
+    where
+        F: ComForwarder<ClientReader, ClientWriter>,
+    {
+        let name = format!("packet-script-{}", nanoid!());
+        let listener = mem_backends().listen(&name);
+        let (client_tx, client_rx) = mpsc::unbounded_channel();
+        let capabilities = self.handshake.client_flag;
+        let backend = tokio::spawn(run_backend(listener, self.steps, capabilities, client_tx));
+        let (backend_read, backend_write) = connect_backend(&format!("{MEM_SCHEME}{name}"))
+            .await
+            .unwrap();
+        mem_backends().remove(&name);
+        let mut backend_reader = PacketReader::new(backend_read);
+        let mut backend_writer = PacketWriter::new(backend_write);
+
+        let (proxy_end, client_end) = tokio::io::duplex(CLIENT_BUFFER_SIZE);
+        let (proxy_read, proxy_write) = tokio::io::split(proxy_end);
+        let mut client_reader = PacketReader::new(proxy_read);
+        let mut client_writer = PacketWriter::new(proxy_write);
+        let (client_read, client_write) = tokio::io::split(client_end);
+        let client = tokio::spawn(run_client(client_write, client_rx));
+
+        let exchange = async {
+            forwarder
+                .write_to_backend(
+                    0,
+                    com_code,
+                    &self.handshake,
+                    Bytes::copy_from_slice(request),
+                    &mut backend_writer,
+                )
+                .await?;
+            forwarder
+                .forward(
+                    &mut client_reader,
+                    &mut client_writer,
+                    &mut backend_writer,
+                    &mut backend_reader,
+                    &self.handshake,
+                )
+                .await
+        };
+        let result = tokio::time::timeout(SCRIPT_TIMEOUT, exchange)
+            .await
+            .expect("the forwarder waits for a packet the script does not send");
+        drop((backend_reader, backend_writer, client_reader, client_writer));
+        let backend_sent = backend.await.unwrap();
+        client.await.unwrap();
+        ScriptOutcome {
+            result,
+            backend_sent,
+            client_received: read_all(client_read).await,
+        }
+    }
+}
+
+/// Plays the backend side of `steps`, returns the packets it sent.
+async fn run_backend(
+    mut listener: MemListener,
+    steps: Vec<Step>,
+    capabilities: CapabilityFlags,
+    client_tx: mpsc::UnboundedSender<(u8, Vec<u8>)>,
+) -> Vec<(u8, Vec<u8>)> {
+    let stream = listener.accept().await.unwrap();
+    let (read, mut write) = tokio::io::split(stream);
+    let mut reader = PacketReader::new(read);
+    let mut sent = vec![];
+    let mut next_seq = 0_u8;
+    for step in steps {
+        let expected = match step {
+            Step::BackendResponds(response) => {
+                let packets = response.packets(next_seq, capabilities).await;
+                let mut w = PacketWriter::new(&mut write);
+                for (seq, payload) in &packets {
+                    write_one_packet(&mut w, *seq, payload, false)
+                        .await
+                        .unwrap();
+                }
+                w.flush_all().await.unwrap();
+                next_seq = next_seq.wrapping_add(packets.len() as u8);
+                sent.extend(packets);
+                continue;
+            }
+            Step::BackendDisconnects => return sent,
+            Step::ClientSends(payload) => {
+                client_tx.send((next_seq, payload.clone())).unwrap();
+                Step::ClientSends(payload)
+            }
+            step => step,
+        };
+        let (seq, packet) = reader
+            .next_async()
+            .await
+            .unwrap()
+            .unwrap_or_else(|| panic!("the proxy closed before {expected:?}"));
+        match expected {
+            Step::ExpectClientCommand(com_code) => assert_eq!(packet[0], com_code as u8),
+            Step::ExpectClientPacket(payload) | Step::ClientSends(payload) => {
+                assert_eq!(&packet[..], &payload[..])
+            }
+            _ => unreachable!(),
+        }
+        next_seq = seq.wrapping_add(1);
+    }
+    if let Ok(Some((_, packet))) = reader.next_async().await {
+        panic!("the proxy sent the backend an unexpected packet {packet:?}");
+    }
+    sent
+}
+
+/// Writes the packets the backend asks the client to send.
+async fn run_client(write: ClientWriter, mut client_rx: mpsc::UnboundedReceiver<(u8, Vec<u8>)>) {
+    let mut w = PacketWriter::new(write);
+    while let Some((seq, payload)) = client_rx.recv().await {
+        write_one_packet(&mut w, seq, &payload, true).await.unwrap();
+    }
+    w.inner_writer.shutdown().await.unwrap();
+}
@@ -0,0 +1,447 @@
+use crate::prost::common_proto::TenantKey;
+use crate::protocol::mysql::basic::read_length_encoded_string;
+use crate::protocol::mysql::constants::CommandCode;
+use crate::server::mirror::first_keyword;
+use crate::server::session::SessionCloseReason;
+use crate::server::slow_log::tenant_label;
+
+use common::metrics::metric_def::PROXY_STICKY_SESSIONS;
+use common::metrics::{common_labels, counter_inc};
+use mysql_common::constants::StatusFlags;
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+use tracing::debug;
+
+/// The session-track entry types of an OK packet.
+/// see: https://dev.mysql.com/doc/dev/mysql-server/latest/page_protocol_basic_ok_packet.html
+const SESSION_TRACK_SYSTEM_VARIABLES: u8 = 0x00;
+const SESSION_TRACK_STATE_CHANGE: u8 = 0x02;
+const SESSION_TRACK_TRANSACTION_STATE: u8 = 0x05;
+
+pub type SharedSessionState = Arc<SessionStateTracker>;
+
+/// The state a backend connection holds for the session using it. A connection is clean once
+/// it holds none, another session could then use it without noticing.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct SessionState {
+    pub in_transaction: bool,
+    /// `LOCK TABLES` is in effect, reported by the transaction state tracker.
+    pub locked_tables: bool,
+    pub temporary_tables: bool,
+    /// A user lock was taken with `GET_LOCK`, it is held until `RELEASE_ALL_LOCKS` or a reset.
+    pub user_locks: bool,
+    /// The last statement was a `SQL_CALC_FOUND_ROWS` query, `FOUND_ROWS()` reads its count
+    /// next.
+    pub found_rows: bool,
+    pub user_variables: bool,
+    /// A session system variable was changed, e.g. `autocommit` or `time_zone`.
+    pub system_variables: bool,
+    /// The server reported a change of the session state it does not detail, e.g. a prepared
+    /// statement.
+    pub state_changed: bool,
+    /// Long data is buffered for a prepared statement or a cursor is open, COM_STMT_RESET
+    /// discards both.
+    pub stmt_buffers: bool,
+}
+
+impl SessionState {
+    pub fn is_clean(&self) -> bool {
+        *self == SessionState::default()
+    }
+
+    /// Why the connection is sticky-dirty: it holds state the session cannot get back on
+    /// another connection, and another session must not see. Such a connection stays with the
+    /// session and goes back to the pool only after a reset, or is discarded.
+    pub fn sticky_reason(&self) -> Option<&'static str> {
+        [
+            (self.temporary_tables, "temporary_tables"),
+            (self.user_locks, "user_locks"),
+            (self.locked_tables, "locked_tables"),
+            (self.found_rows, "found_rows"),
+        ]
+        .into_iter()
+        .find_map(|(sticky, reason)| sticky.then_some(reason))
+    }
+
+    pub fn is_sticky(&self) -> bool {
+        self.sticky_reason().is_some()
+    }
+}
+
+/// The state a statement may leave behind, known from its text, confirmed once the backend
+/// answers it.
+#[derive(Debug, Default, Clone, Copy)]
+struct StatementHint {
+    temporary_tables: bool,
+    user_locks: bool,
+    release_locks: bool,
+    found_rows: bool,
+    user_variables: bool,
+}
+
+#[derive(Debug, Default)]
+struct Tracked {
+    state: SessionState,
+    pending: StatementHint,
+    /// The multi-statements option the last COM_SET_OPTION switched to. The server keeps it
+    /// until the connection closes, neither a reset nor a re-authentication restores it.
+    multi_statements: Option<bool>,
+}
+
+/// `SessionStateTracker` follows the state of a backend connection from the commands sent to
+/// it and the status flags and session-track payloads of its OK packets. The server only
+/// details what its `session_track_*` variables enable, temporary tables, user locks and user
+/// variables are therefore also recognized from the statements.
+#[derive(Debug, Default)]
+pub struct SessionStateTracker {
+    tracked: Mutex<Tracked>,
+}
+
+impl SessionStateTracker {
+    pub fn state(&self) -> SessionState {
+        self.tracked.lock().unwrap().state
+    }
+
+    pub fn is_clean(&self) -> bool {
+        self.state().is_clean()
+    }
+
+    /// Forgets the state, the connection was re-authenticated or reset. The multi-statements
+    /// option outlives both.
+    pub fn reset(&self) {
+        let mut tracked = self.tracked.lock().unwrap();
+        *tracked = Tracked {
+            multi_statements: tracked.multi_statements,
+            ..Default::default()
+        };
+    }
+
+    /// The multi-statements option a COM_SET_OPTION left on the connection, `None` if it still
+    /// has the one it was authenticated with.
+    pub fn multi_statements(&self) -> Option<bool> {
+        self.tracked.lock().unwrap().multi_statements
+    }
+
+    /// Notes the multi-statements option the backend confirmed a COM_SET_OPTION for.
+    pub fn observe_set_option(&self, multi_statements: bool) {
+        self.tracked.lock().unwrap().multi_statements = Some(multi_statements);
+    }
+
+    /// Notes a COM_STMT_RESET the backend confirmed. One statement is reset, the buffers of
+    /// the others are taken for gone too, as COM_STMT_CLOSE does.
+    pub fn observe_stmt_reset(&self) {
+        self.tracked.lock().unwrap().state.stmt_buffers = false;
+    }
+
+    /// Notes a command about to be sent to the backend.
+    pub fn observe_command(&self, com_code: CommandCode, payload: &[u8]) {
+        let hint = match com_code {
+            CommandCode::ComResetConnection | CommandCode::ComChangeUser => {
+                self.reset();
+                return;
+            }
+            CommandCode::ComQuery => statement_hint(&String::from_utf8_lossy(payload)),
+            // The backend buffers long data without a response. An execution consumes it and,
+            // with any cursor type in the flags byte after the statement id, opens a cursor.
+            CommandCode::ComStmtSendLongData
+            | CommandCode::ComStmtExecute
+            | CommandCode::ComStmtClose => {
+                let stmt_buffers = match com_code {
+                    CommandCode::ComStmtSendLongData => true,
+                    CommandCode::ComStmtExecute => payload.get(4).is_some_and(|flags| *flags != 0),
+                    _ => false,
+                };
+                let mut tracked = self.tracked.lock().unwrap();
+                tracked.state.stmt_buffers = stmt_buffers;
+                tracked.pending = StatementHint::default();
+                return;
+            }
+            _ => StatementHint::default(),
+        };
+        self.tracked.lock().unwrap().pending = hint;
+    }
+
+    /// Applies the status flags ending a statement, the state its command left is then known.
+    pub fn observe_status(&self, status_flags: StatusFlags) {
+        let mut tracked = self.tracked.lock().unwrap();
+        let pending = std::mem::take(&mut tracked.pending);
+        let state = &mut tracked.state;
+        state.in_transaction = status_flags.contains(StatusFlags::SERVER_STATUS_IN_TRANS);
+        state.temporary_tables |= pending.temporary_tables;
+        state.user_locks = (state.user_locks && !pending.release_locks) || pending.user_locks;
+        state.found_rows = pending.found_rows;
+        state.user_variables |= pending.user_variables;
+    }
+
+    /// Notes the ERR packet a statement failed with, it left none of the state its text hints
+    /// at. The count of `FOUND_ROWS()` is then gone too.
+    pub fn observe_error(&self) {
+        let mut tracked = self.tracked.lock().unwrap();
+        tracked.pending = StatementHint::default();
+        tracked.state.found_rows = false;
+    }
+
+    /// Applies the session-track payload of an OK packet, a sequence of a type byte and a
+    /// length encoded entry. A malformed payload is applied up to the malformed entry.
+    pub fn observe_session_track(&self, mut payload: &[u8]) {
+        let mut tracked = self.tracked.lock().unwrap();
+        while let Some((&entry_type, rest)) = payload.split_first() {
+            let Ok((rest, entry)) = read_length_encoded_string(rest) else {
+                break;
+            };
+            payload = rest;
+            let state = &mut tracked.state;
+            match entry_type {
+                SESSION_TRACK_SYSTEM_VARIABLES => state.system_variables = true,
+                SESSION_TRACK_STATE_CHANGE => {
+                    if read_length_encoded_string(entry).is_ok_and(|(_, flag)| flag == b"1") {
+                        state.state_changed = true;
+                    }
+                }
+                SESSION_TRACK_TRANSACTION_STATE => {
+                    // Eight characters, the first is the transaction type and the last `L`
+                    // while tables are locked, `_` stands for unset.
+                    if let Ok((_, [trx_type, .., locked])) = read_length_encoded_string(entry) {
+                        state.in_transaction = *trx_type != b'_';
+                        state.locked_tables = *locked == b'L';
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Notes how the session using the connection closed. Only a close that reset the connection
+    /// clears the state, a sticky-dirty connection is otherwise discarded by the pool.
+    pub fn observe_close(&self, close_reason: SessionCloseReason) {
+        if close_reason.is_backend_reusable() {
+            self.reset();
+        }
+    }
+}
+
+/// The state of the connection serving a command when the command started, see
+/// [`watch_command`].
+pub struct CommandWatch {
+    tracker: SharedSessionState,
+    before: SessionState,
+}
+
+impl CommandWatch {
+    /// Counts the connection turning sticky-dirty with the command, see [`record_sticky`].
+    pub fn finish(self, tenant: &TenantKey) {
+        record_sticky(tenant, self.before, self.tracker.state());
+    }
+}
+
+/// Tracks the command `com_code` on the connection of `tracker`, the one it is forwarded to.
+pub fn watch_command(
+    tracker: &SharedSessionState,
+    com_code: CommandCode,
+    payload: &[u8],
+) -> CommandWatch {
+    let before = tracker.state();
+    tracker.observe_command(com_code, payload);
+    CommandWatch {
+        tracker: Arc::clone(tracker),
+        before,
+    }
+}
+
+/// Counts a connection of the session of `tenant` turning sticky-dirty with the command that
+/// moved it from `before` to `after`, by the reason of `after`.
+pub fn record_sticky(tenant: &TenantKey, before: SessionState, after: SessionState) {
+    let Some(reason) = after.sticky_reason().filter(|_| !before.is_sticky()) else {
+        return;
+    };
+    debug!("ProxySrv session of {tenant:?} sticks to its connection for {reason}");
+    let mut labels = common_labels().clone();
+    labels.push(("tenant", tenant_label(tenant)));
+    labels.push(("reason", reason.to_string()));
+    counter_inc(PROXY_STICKY_SESSIONS, 1, Some(&labels));
+}
+
+/// The state a statement may leave behind. A `@` in a string literal is taken for a user
+/// variable, the connection is then wrongly held dirty rather than wrongly shared. So is a
+/// `GET_LOCK` that timed out, and a `RELEASE_LOCK` keeps the other locks of the session held.
+fn statement_hint(sql: &str) -> StatementHint {
+    let keyword = first_keyword(sql);
+    let upper = sql.to_ascii_uppercase();
+    let assigns =
+        keyword.eq_ignore_ascii_case("SET") || upper.contains(":=") || upper.contains("INTO");
+    StatementHint {
+        temporary_tables: keyword.eq_ignore_ascii_case("CREATE") && upper.contains("TEMPORARY"),
+        user_locks: upper.contains("GET_LOCK"),
+        release_locks: upper.contains("RELEASE_ALL_LOCKS"),
+        found_rows: upper.contains("SQL_CALC_FOUND_ROWS"),
+        user_variables: assigns && upper.replace("@@", "").contains('@'),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::test_tenant_key;
+    use crate::protocol::mysql::constants::CommandCode;
+    use crate::server::forwarder::session_state::{watch_command, SessionStateTracker};
+    use crate::server::session::SessionCloseReason;
+    use mysql_common::constants::StatusFlags;
+    use std::sync::Arc;
+
+    fn lenenc(data: &[u8]) -> Vec<u8> {
+        [&[data.len() as u8], data].concat()
+    }
+
+    fn entry(entry_type: u8, data: &[u8]) -> Vec<u8> {
+        [&[entry_type], lenenc(data).as_slice()].concat()
+    }
+
+    fn run(tracker: &SessionStateTracker, sql: &[u8]) {
+        tracker.observe_command(CommandCode::ComQuery, sql);
+        tracker.observe_status(StatusFlags::SERVER_STATUS_AUTOCOMMIT);
+    }
+
+    #[test]
+    pub fn test_session_state_tracker() {
+        let tracker = SessionStateTracker::default();
+        tracker.observe_command(CommandCode::ComQuery, b"BEGIN");
+        tracker.observe_status(StatusFlags::SERVER_STATUS_IN_TRANS);
+        assert!(tracker.state().in_transaction);
+        tracker.observe_command(CommandCode::ComQuery, b"COMMIT");
+        tracker.observe_status(StatusFlags::SERVER_STATUS_AUTOCOMMIT);
+        assert!(tracker.is_clean());
+
+        // Session system variables are not user variables.
+        tracker.observe_command(CommandCode::ComQuery, b"SET @@session.sql_mode = ''");
+        tracker.observe_status(StatusFlags::SERVER_STATUS_AUTOCOMMIT);
+        assert!(tracker.is_clean());
+        tracker.observe_command(CommandCode::ComQuery, b"SET @last = 1");
+        tracker.observe_status(StatusFlags::SERVER_STATUS_AUTOCOMMIT);
+        assert!(tracker.state().user_variables);
+        tracker.observe_command(CommandCode::ComResetConnection, &[]);
+        assert!(tracker.is_clean());
+
+        tracker.observe_command(CommandCode::ComQuery, b"CREATE TEMPORARY TABLE t (id INT)");
+        tracker.observe_status(StatusFlags::SERVER_SESSION_STATE_CHANGED);
+        assert!(tracker.state().temporary_tables);
+        tracker.reset();
+
+        let variable = [lenenc(b"autocommit"), lenenc(b"OFF")].concat();
+        let payload = [
+            entry(0x00, &variable),
+            entry(0x01, &lenenc(b"sbtest")),
+            entry(0x02, &lenenc(b"1")),
+            entry(0x05, &lenenc(b"T______L")),
+        ]
+        .concat();
+        tracker.observe_session_track(&payload);
+        let state = tracker.state();
+        assert!(state.system_variables && state.state_changed);
+        assert!(state.in_transaction && state.locked_tables);
+        assert!(!state.temporary_tables && !state.user_variables);
+
+        // A truncated payload is applied up to the truncated entry.
+        tracker.reset();
+        tracker.observe_session_track(&payload[..payload.len() - 3]);
+        assert!(tracker.state().state_changed);
+        assert!(!tracker.state().locked_tables);
+
+        tracker.reset();
+        let stmt_id = 1_u32.to_le_bytes();
+        tracker.observe_command(CommandCode::ComStmtSendLongData, &stmt_id);
+        assert!(tracker.state().stmt_buffers);
+        tracker.observe_stmt_reset();
+        assert!(tracker.is_clean());
+        let cursor = [&stmt_id[..], &[0x01], &1_u32.to_le_bytes()].concat();
+        tracker.observe_command(CommandCode::ComStmtExecute, &cursor);
+        assert!(tracker.state().stmt_buffers);
+        tracker.observe_command(CommandCode::ComStmtClose, &stmt_id);
+        assert!(tracker.is_clean());
+
+        // The multi-statements option outlives a reset of the connection.
+        tracker.observe_set_option(false);
+        tracker.observe_command(CommandCode::ComResetConnection, &[]);
+        assert_eq!(tracker.multi_statements(), Some(false));
+        assert!(tracker.is_clean());
+    }
+
+    #[test]
+    pub fn test_temporary_tables_sticky() {
+        let tracker = SessionStateTracker::default();
+        run(&tracker, b"CREATE TABLE t (id INT)");
+        assert!(tracker.is_clean());
+        run(&tracker, b"/* app */ CREATE TEMPORARY TABLE t (id INT)");
+        assert_eq!(tracker.state().sticky_reason(), Some("temporary_tables"));
+        // Dropping the table is not trusted to clean the connection, a reset is.
+        run(&tracker, b"DROP TEMPORARY TABLE t");
+        assert!(tracker.state().temporary_tables);
+        tracker.observe_command(CommandCode::ComResetConnection, &[]);
+        assert!(tracker.is_clean());
+    }
+
+    #[test]
+    pub fn test_user_locks_sticky() {
+        let tracker = SessionStateTracker::default();
+        // A failed statement takes no lock.
+        tracker.observe_command(CommandCode::ComQuery, b"SELECT GET_LOCK('job', 10)");
+        tracker.observe_error();
+        tracker.observe_status(StatusFlags::SERVER_STATUS_AUTOCOMMIT);
+        assert!(tracker.is_clean());
+        run(&tracker, b"SELECT get_lock('job', 10)");
+        assert_eq!(tracker.state().sticky_reason(), Some("user_locks"));
+        run(&tracker, b"SELECT 1");
+        run(&tracker, b"SELECT RELEASE_LOCK('job')");
+        assert!(tracker.state().user_locks);
+        run(&tracker, b"DO RELEASE_ALL_LOCKS()");
+        assert!(tracker.is_clean());
+    }
+
+    #[test]
+    pub fn test_found_rows_sticky() {
+        let tracker = SessionStateTracker::default();
+        run(&tracker, b"SELECT SQL_CALC_FOUND_ROWS id FROM t LIMIT 10");
+        assert_eq!(tracker.state().sticky_reason(), Some("found_rows"));
+        run(&tracker, b"SELECT FOUND_ROWS()");
+        assert!(tracker.is_clean());
+        run(&tracker, b"SELECT SQL_CALC_FOUND_ROWS id FROM t LIMIT 10");
+        tracker.observe_command(CommandCode::ComQuery, b"SELECT FOUND_ROWS() FROM missing");
+        tracker.observe_error();
+        assert!(tracker.is_clean());
+    }
+
+    #[test]
+    pub fn test_sticky_cleared_by_reset_only() {
+        let tracker = SessionStateTracker::default();
+        run(&tracker, b"SELECT GET_LOCK('job', 10)");
+        run(&tracker, b"CREATE TEMPORARY TABLE t (id INT)");
+        for close_reason in [
+            SessionCloseReason::QuitResetFailed,
+            SessionCloseReason::Maintenance,
+            SessionCloseReason::Drained,
+            SessionCloseReason::KeepaliveFailed,
+        ] {
+            tracker.observe_close(close_reason);
+            assert!(tracker.state().is_sticky(), "{close_reason:?}");
+        }
+        tracker.observe_close(SessionCloseReason::Quit);
+        assert!(tracker.is_clean());
+    }
+    #[test]
+    pub fn test_watch_command() {
+        let tenant = test_tenant_key();
+        let tracker = Arc::new(SessionStateTracker::default());
+        let watch = watch_command(
+            &tracker,
+            CommandCode::ComQuery,
+            b"SELECT GET_LOCK('job', 10)",
+        );
+        // The statement takes effect with its response.
+        assert!(!tracker.state().is_sticky());
+        tracker.observe_status(StatusFlags::SERVER_STATUS_AUTOCOMMIT);
+        assert!(tracker.state().is_sticky());
+        watch.finish(&tenant);
+        let watch = watch_command(&tracker, CommandCode::ComQuery, b"SELECT 1");
+        tracker.observe_status(StatusFlags::SERVER_STATUS_AUTOCOMMIT);
+        watch.finish(&tenant);
+        assert!(tracker.state().is_sticky());
+    }
+}
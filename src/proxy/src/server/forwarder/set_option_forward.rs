@@ -0,0 +1,150 @@
+use crate::async_packet_read;
+use crate::backend::pool::BackendConn;
+use crate::backend::stream::{BackendReadHalf, BackendWriteHalf};
+use crate::protocol::mysql::basic::HandshakeResponse;
+use crate::protocol::mysql::constants::CommandCode;
+use crate::protocol::mysql::packet::packet_reader::PacketReader;
+use crate::protocol::mysql::packet::packet_writer::PacketWriter;
+use crate::protocol::mysql::packet::Packet;
+use crate::server::forwarder::session_state::{SessionStateTracker, SharedSessionState};
+use crate::server::forwarder::ComForwarder;
+
+use async_trait::async_trait;
+use mysql_common::constants::CapabilityFlags;
+use std::io::{Error, ErrorKind, Write};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// The options of COM_SET_OPTION.
+/// see: https://dev.mysql.com/doc/dev/mysql-server/latest/page_protocol_com_set_option.html
+pub const MYSQL_OPTION_MULTI_STATEMENTS_ON: u16 = 0;
+pub const MYSQL_OPTION_MULTI_STATEMENTS_OFF: u16 = 1;
+
+/// The multi-statements option a COM_SET_OPTION payload switches to, `None` if it is malformed.
+pub fn multi_statements_option(payload: &[u8]) -> Option<bool> {
+    match payload {
+        [lo, hi] => match u16::from_le_bytes([*lo, *hi]) {
+            MYSQL_OPTION_MULTI_STATEMENTS_ON => Some(true),
+            MYSQL_OPTION_MULTI_STATEMENTS_OFF => Some(false),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Forwards COM_SET_OPTION. The option lasts as long as the backend connection, so the one the
+/// backend confirmed is noted for the session that checks the connection out next, see
+/// [`restore_multi_statements`].
+pub struct SetOptionForwarder {
+    /// The option requested, see [`multi_statements_option`].
+    pub multi_statements: Option<bool>,
+    pub session_state: SharedSessionState,
+}
+
+#[async_trait]
+impl<R, W> ComForwarder<R, W> for SetOptionForwarder
+where
+    R: AsyncRead + Send + Unpin,
+    W: AsyncWrite + Send + Unpin,
+{
+    async fn forward(
+        &self,
+        _: &mut PacketReader<R>,
+        client_writer: &mut PacketWriter<W>,
+        _: &mut PacketWriter<BackendWriteHalf>,
+        backend_reader: &mut PacketReader<BackendReadHalf>,
+        _: &HandshakeResponse,
+    ) -> Result<Option<Packet>, Error> {
+        // An EOF packet confirms the option, an OK packet with CLIENT_DEPRECATE_EOF.
+        let response = self
+            .forward_one_packet(client_writer, backend_reader, true)
+            .await?;
+        if let (false, Some(multi_statements)) = (response.is_err_packet(), self.multi_statements) {
+            self.session_state.observe_set_option(multi_statements);
+        }
+        Ok(Some(response))
+    }
+}
+
+/// Switches the multi-statements option of a connection a session checks out to `wanted`, if a
+/// COM_SET_OPTION of a session that used the connection before switched it to the other one.
+pub async fn restore_multi_statements(
+    conn: &mut BackendConn,
+    session_state: &SessionStateTracker,
+    wanted: bool,
+) -> Result<(), Error> {
+    if session_state
+        .multi_statements()
+        .map_or(true, |current| current == wanted)
+    {
+        return Ok(());
+    }
+    let option = if wanted {
+        MYSQL_OPTION_MULTI_STATEMENTS_ON
+    } else {
+        MYSQL_OPTION_MULTI_STATEMENTS_OFF
+    };
+    let (reader, writer) = conn;
+    writer.reset_seq();
+    writer.write_all(&[CommandCode::ComSetOption as u8])?;
+    writer.write_all(&option.to_le_bytes())?;
+    writer.end_packet().await?;
+    writer.flush_all().await?;
+    let (_, response) = async_packet_read!(reader);
+    if response.is_err_packet() {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "ComSetOption refused by the backend",
+        ));
+    }
+    session_state.observe_set_option(wanted);
+    Ok(())
+}
+
+/// The multi-statements option a client gets without a COM_SET_OPTION.
+pub fn handshake_multi_statements(client_flag: CapabilityFlags) -> bool {
+    client_flag.contains(CapabilityFlags::CLIENT_MULTI_STATEMENTS)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::protocol::mysql::constants::CommandCode;
+    use crate::protocol::mysql::error_codes::ErrorKind;
+    use crate::server::forwarder::script::{PacketScript, Response};
+    use crate::server::forwarder::session_state::SessionStateTracker;
+    use crate::server::forwarder::set_option_forward::{
+        multi_statements_option, SetOptionForwarder, MYSQL_OPTION_MULTI_STATEMENTS_OFF,
+    };
+    use std::sync::Arc;
+
+    #[tokio::test]
+    pub async fn test_set_option_forward() {
+        let option = MYSQL_OPTION_MULTI_STATEMENTS_OFF.to_le_bytes();
+        let set_option = [&[CommandCode::ComSetOption as u8][..], &option].concat();
+        assert_eq!(multi_statements_option(&option), Some(false));
+        assert_eq!(multi_statements_option(&[7, 0]), None);
+        let forwarder = SetOptionForwarder {
+            multi_statements: multi_statements_option(&option),
+            session_state: Arc::new(SessionStateTracker::default()),
+        };
+
+        let outcome = PacketScript::new()
+            .expect_client_packet(&set_option)
+            .backend_responds(Response::Err(
+                ErrorKind::ER_UNKNOWN_COM_ERROR,
+                "Unknown command",
+            ))
+            .run(&forwarder, CommandCode::ComSetOption, &set_option)
+            .await;
+        outcome.assert_forwarded();
+        assert_eq!(forwarder.session_state.multi_statements(), None);
+
+        let outcome = PacketScript::new()
+            .expect_client_packet(&set_option)
+            // An EOF packet with SERVER_STATUS_AUTOCOMMIT.
+            .backend_responds(Response::Raw(vec![0xfe, 0, 0, 2, 0]))
+            .run(&forwarder, CommandCode::ComSetOption, &set_option)
+            .await;
+        outcome.assert_forwarded();
+        assert_eq!(forwarder.session_state.multi_statements(), Some(false));
+    }
+}
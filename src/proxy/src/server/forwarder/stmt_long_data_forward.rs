@@ -0,0 +1,60 @@
+use crate::backend::stream::{BackendReadHalf, BackendWriteHalf};
+use crate::protocol::mysql::basic::HandshakeResponse;
+use crate::protocol::mysql::packet::packet_reader::PacketReader;
+use crate::protocol::mysql::packet::packet_writer::PacketWriter;
+use crate::protocol::mysql::packet::Packet;
+use crate::server::forwarder::ComForwarder;
+
+use async_trait::async_trait;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// Forwards COM_STMT_SEND_LONG_DATA. The backend buffers the data until the statement is
+/// executed and sends no response, not even an error, so nothing is read back: waiting for one
+/// would hang the session.
+pub struct StmtLongDataForwarder;
+
+#[async_trait]
+impl<R, W> ComForwarder<R, W> for StmtLongDataForwarder
+where
+    R: AsyncRead + Send + Unpin,
+    W: AsyncWrite + Send + Unpin,
+{
+    async fn forward(
+        &self,
+        _: &mut PacketReader<R>,
+        _: &mut PacketWriter<W>,
+        _: &mut PacketWriter<BackendWriteHalf>,
+        _: &mut PacketReader<BackendReadHalf>,
+        _: &HandshakeResponse,
+    ) -> Result<Option<Packet>, std::io::Error> {
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::protocol::mysql::constants::CommandCode;
+    use crate::server::forwarder::script::PacketScript;
+    use crate::server::forwarder::stmt_long_data_forward::StmtLongDataForwarder;
+
+    #[tokio::test]
+    pub async fn test_stmt_long_data_forward() {
+        let long_data = [
+            &[CommandCode::ComStmtSendLongData as u8][..],
+            &1_u32.to_le_bytes(),
+            &0_u16.to_le_bytes(),
+            &[0xab; 1024],
+        ]
+        .concat();
+        let outcome = PacketScript::new()
+            .expect_client_packet(&long_data)
+            .run(
+                &StmtLongDataForwarder,
+                CommandCode::ComStmtSendLongData,
+                &long_data,
+            )
+            .await;
+        assert!(outcome.response().is_none());
+        assert!(outcome.client_received.is_empty());
+    }
+}
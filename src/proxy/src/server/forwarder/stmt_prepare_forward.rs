@@ -1,28 +1,447 @@
+use crate::async_packet_read;
+use crate::backend::pool::stmt_cache::{normalize_stmt, CachedStmt, SharedStmtCache, StmtRelease};
+use crate::backend::pool::BackendConn;
+use crate::backend::stream::{BackendReadHalf, BackendWriteHalf};
 use crate::parse_err_packet;
 use crate::protocol::mysql::basic::HandshakeResponse;
 use crate::protocol::mysql::packet::packet_reader::PacketReader;
 use crate::protocol::mysql::packet::packet_writer::PacketWriter;
-use crate::protocol::mysql::packet::Packet;
-use crate::server::forwarder::ComForwarder;
+use crate::protocol::mysql::packet::{writers, Packet};
+use crate::server::forwarder::{write_one_packet, ComForwarder};
 
 use crate::protocol::mysql::constants::CommandCode;
 use async_trait::async_trait;
 use byteorder::ByteOrder;
+use bytes::Bytes;
+use hashbrown::HashMap;
 use mysql_common::constants::CapabilityFlags;
 use std::io::Error;
+use std::sync::Arc;
 use tokio::io::{AsyncRead, AsyncWrite};
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::sync::Mutex;
+use tracing::debug;
 
 pub struct StmtPrepareForwarder {
     pub com_code: CommandCode,
-    pub request: Packet,
+    pub request: Bytes,
+    /// Set when the backend connection has the prepared statement cache enabled.
+    pub stmt_cache: Option<SharedStmtCache>,
+    /// Set when the session may switch backend connections, see [`SessionStmts`].
+    pub session_stmts: Option<SharedSessionStmts>,
+}
+
+pub type SharedSessionStmts = Arc<Mutex<SessionStmts>>;
+
+#[derive(Debug)]
+struct SessionStmt {
+    sql: Vec<u8>,
+    backend_id: u32,
+}
+
+/// `SessionStmts` keeps the statements a session prepared, under ids of the proxy, so that they
+/// survive a switch of backend connection: after a failover or a multiplexed checkout every
+/// statement is prepared again on the new connection, see [`SessionStmts::reprepare`], and the
+/// client ids are remapped to the new backend ids.
+#[derive(Debug)]
+pub struct SessionStmts {
+    capabilities: CapabilityFlags,
+    stmts: HashMap<u32, SessionStmt>,
+    next_client_id: u32,
+}
+
+impl SessionStmts {
+    pub fn new(capabilities: CapabilityFlags) -> Self {
+        Self {
+            capabilities,
+            stmts: HashMap::new(),
+            next_client_id: 1,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.stmts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stmts.is_empty()
+    }
+
+    /// Allocates the client id of a statement the backend prepared as `backend_id`.
+    pub fn register(&mut self, sql: Vec<u8>, backend_id: u32) -> u32 {
+        let client_id = self.next_client_id;
+        self.next_client_id = self.next_client_id.wrapping_add(1).max(1);
+        self.stmts
+            .insert(client_id, SessionStmt { sql, backend_id });
+        client_id
+    }
+
+    /// The text of the statement behind `client_id`.
+    pub fn sql(&self, client_id: u32) -> Option<&[u8]> {
+        self.stmts.get(&client_id).map(|stmt| stmt.sql.as_slice())
+    }
+
+    /// Rewrites the client statement id of COM_STMT_EXECUTE, COM_STMT_SEND_LONG_DATA,
+    /// COM_STMT_RESET, COM_STMT_FETCH and COM_STMT_CLOSE into the backend statement id, and
+    /// forgets the statement on COM_STMT_CLOSE. Returns the client statement id if it is known;
+    /// unknown ids are left untouched for the backend to reject.
+    pub fn translate(&mut self, com_code: CommandCode, client_packet: &mut Packet) -> Option<u32> {
+        if client_packet.len() < 5 {
+            return None;
+        }
+        let client_id = byteorder::LittleEndian::read_u32(&client_packet[1..5]);
+        let backend_id = match com_code {
+            CommandCode::ComStmtClose => self.stmts.remove(&client_id)?.backend_id,
+            _ => self.stmts.get(&client_id)?.backend_id,
+        };
+        client_packet.as_mut()[1..5].copy_from_slice(&backend_id.to_le_bytes());
+        Some(client_id)
+    }
+
+    /// COM_CHANGE_USER and COM_RESET_CONNECTION deallocate every statement on the backend.
+    pub fn clear(&mut self) {
+        self.stmts.clear();
+    }
+
+    /// Applies a command of the client to the statements, see [`translate`](Self::translate) and
+    /// [`clear`](Self::clear). Returns the text of the statement the command runs if `with_sql`.
+    pub fn apply(
+        &mut self,
+        com_code: CommandCode,
+        client_packet: &mut Packet,
+        with_sql: bool,
+    ) -> Option<Vec<u8>> {
+        match com_code {
+            CommandCode::ComStmtExecute
+            | CommandCode::ComStmtSendLongData
+            | CommandCode::ComStmtReset
+            | CommandCode::ComStmtFetch
+            | CommandCode::ComStmtClose => {
+                let client_id = self.translate(com_code, client_packet)?;
+                with_sql
+                    .then(|| self.sql(client_id).map(<[u8]>::to_vec))
+                    .flatten()
+            }
+            CommandCode::ComChangeUser | CommandCode::ComResetConnection => {
+                self.clear();
+                None
+            }
+            _ => None,
+        }
+    }
+
+    /// Prepares every statement of the session on `conn`, the connection it switched to. Fails
+    /// if the backend refuses one of them, the session then cannot use the connection.
+    pub async fn reprepare(&mut self, conn: &mut BackendConn) -> Result<(), Error> {
+        let (backend_reader, backend_writer) = conn;
+        for (client_id, stmt) in self.stmts.iter_mut() {
+            let mut prepare_stmt = vec![CommandCode::ComStmtPrepare as u8];
+            prepare_stmt.extend_from_slice(&stmt.sql);
+            write_one_packet(backend_writer, 0, &prepare_stmt, true).await?;
+            let response = read_prepare_response(backend_reader, self.capabilities).await?;
+            if response[0].is_err_packet() {
+                parse_err_packet!(
+                    self.capabilities,
+                    response[0],
+                    "session stmt re-prepare ERR"
+                );
+                return Err(Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("statement {client_id} refused by the backend"),
+                ));
+            }
+            stmt.backend_id = byteorder::LittleEndian::read_u32(&response[0][1..5]);
+            debug!(
+                "ProxySrv session stmt {client_id} re-prepared backend_stmt_id={}",
+                stmt.backend_id
+            );
+        }
+        Ok(())
+    }
+}
+
+/// The number of parameter and column definition packets following a COM_STMT_PREPARE_OK.
+fn expected_definition_packets(prepare_ok: &Packet, capabilities: CapabilityFlags) -> u16 {
+    let is_client_deprecate_eof = capabilities.contains(CapabilityFlags::CLIENT_DEPRECATE_EOF);
+    let column = byteorder::LittleEndian::read_u16(&prepare_ok[5..]);
+    let params = byteorder::LittleEndian::read_u16(&prepare_ok[7..]);
+    let mut expected_packets = column + params;
+    if !is_client_deprecate_eof {
+        if column > 0 {
+            expected_packets += 1
+        }
+        if params > 0 {
+            expected_packets += 1
+        }
+    }
+    expected_packets
+}
+
+/// Reads a complete COM_STMT_PREPARE response from the backend: the COM_STMT_PREPARE_OK (or ERR)
+/// packet followed by the parameter and column definitions.
+async fn read_prepare_response(
+    backend_reader: &mut PacketReader<BackendReadHalf>,
+    capabilities: CapabilityFlags,
+) -> Result<Vec<Packet>, Error> {
+    let (_, packet) = async_packet_read!(backend_reader);
+    if !packet.is_ok_packet() {
+        return Ok(vec![packet]);
+    }
+    let expected_packets = expected_definition_packets(&packet, capabilities);
+    let mut response = vec![packet];
+    for _idx in 0..expected_packets {
+        let (_, packet) = async_packet_read!(backend_reader);
+        response.push(packet);
+    }
+    Ok(response)
+}
+
+/// Writes a COM_STMT_PREPARE response to the client under the statement id `client_id`.
+async fn write_prepare_response<W>(
+    client_writer: &mut PacketWriter<W>,
+    response: Vec<Packet>,
+    client_id: u32,
+) -> Result<(), Error>
+where
+    W: AsyncWrite + Send + Unpin,
+{
+    let mut seq = 1_u8;
+    for (idx, packet) in response.into_iter().enumerate() {
+        let mut packet = packet;
+        if idx == 0 {
+            packet.as_mut()[1..5].copy_from_slice(&client_id.to_le_bytes());
+        }
+        write_one_packet(client_writer, seq, &packet, false).await?;
+        seq = seq.wrapping_add(1);
+    }
+    client_writer.flush_all().await
+}
+
+/// Closes evicted statements on the backend, COM_STMT_CLOSE has no response.
+async fn close_backend_stmts(
+    backend_ids: &[u32],
+    backend_writer: &mut PacketWriter<BackendWriteHalf>,
+) -> Result<(), Error> {
+    for backend_id in backend_ids {
+        debug!("ProxySrv stmt cache close backend_stmt_id={backend_id}");
+        let mut close_stmt = vec![CommandCode::ComStmtClose as u8];
+        close_stmt.extend_from_slice(&backend_id.to_le_bytes());
+        write_one_packet(backend_writer, 0, &close_stmt, false).await?;
+    }
+    if backend_ids.is_empty() {
+        Ok(())
+    } else {
+        backend_writer.flush_all().await
+    }
+}
+
+/// Rewrites the client statement id of COM_STMT_EXECUTE, COM_STMT_SEND_LONG_DATA, COM_STMT_RESET
+/// and COM_STMT_FETCH into the backend statement id. Returns the client statement id if it is
+/// known to the cache; unknown ids are left untouched for the backend to reject.
+///
+/// A statement deallocated by the backend is prepared again first. So is a statement whose
+/// backend statement holds the long data or the cursor of another client statement, as a
+/// private backend statement of the client statement.
+pub(crate) async fn translate_stmt_id(
+    stmt_cache: &SharedStmtCache,
+    com_code: CommandCode,
+    client_packet: &mut Packet,
+    backend_writer: &mut PacketWriter<BackendWriteHalf>,
+    backend_reader: &mut PacketReader<BackendReadHalf>,
+    capabilities: CapabilityFlags,
+) -> Result<Option<u32>, Error> {
+    if client_packet.len() < 5 {
+        return Ok(None);
+    }
+    let client_id = byteorder::LittleEndian::read_u32(&client_packet[1..5]);
+    let (stale, held_by_other) = {
+        let stmt_cache = stmt_cache.lock().await;
+        (
+            stmt_cache.is_stale(client_id),
+            stmt_cache.is_held_by_other(client_id),
+        )
+    };
+    if stale {
+        reprepare_stmt(
+            stmt_cache,
+            client_id,
+            backend_writer,
+            backend_reader,
+            capabilities,
+        )
+        .await?;
+    } else if held_by_other {
+        prepare_private_stmt(
+            stmt_cache,
+            client_id,
+            backend_writer,
+            backend_reader,
+            capabilities,
+        )
+        .await?;
+    }
+    let mut stmt_cache = stmt_cache.lock().await;
+    let Some(backend_id) = stmt_cache.backend_id(client_id) else {
+        return Ok(None);
+    };
+    // The flags following the statement id ask COM_STMT_EXECUTE to open a cursor.
+    let opens_cursor = client_packet.len() > 5 && client_packet[5] != 0;
+    stmt_cache.on_command(client_id, com_code, opens_cursor);
+    client_packet.as_mut()[1..5].copy_from_slice(&backend_id.to_le_bytes());
+    Ok(Some(client_id))
+}
+
+/// Applies a command of the client to the statement cache of its connection: the statement
+/// commands are translated, see [`translate_stmt_id`], COM_QUIT and COM_RESET_CONNECTION
+/// deallocate the statements, COM_CHANGE_USER clears them. Returns the client id of the
+/// statement a COM_STMT_EXECUTE executes, and the text of the statement if `with_sql`.
+pub(crate) async fn apply_stmt_cache(
+    stmt_cache: &SharedStmtCache,
+    com_code: CommandCode,
+    client_packet: &mut Packet,
+    backend_writer: &mut PacketWriter<BackendWriteHalf>,
+    backend_reader: &mut PacketReader<BackendReadHalf>,
+    capabilities: CapabilityFlags,
+    with_sql: bool,
+) -> Result<(Option<u32>, Option<Vec<u8>>), Error> {
+    match com_code {
+        CommandCode::ComStmtExecute
+        | CommandCode::ComStmtSendLongData
+        | CommandCode::ComStmtReset
+        | CommandCode::ComStmtFetch => {
+            let client_id = translate_stmt_id(
+                stmt_cache,
+                com_code,
+                client_packet,
+                backend_writer,
+                backend_reader,
+                capabilities,
+            )
+            .await?;
+            // Only the statement cache knows the text of executed statements.
+            let sql = match client_id.filter(|_| with_sql) {
+                Some(client_id) => {
+                    let stmt_cache = stmt_cache.lock().await;
+                    stmt_cache
+                        .stmt_key(client_id)
+                        .map(|key| key.as_bytes().to_vec())
+                }
+                None => None,
+            };
+            let executed = client_id.filter(|_| com_code == CommandCode::ComStmtExecute);
+            Ok((executed, sql))
+        }
+        CommandCode::ComQuit | CommandCode::ComResetConnection => {
+            stmt_cache.lock().await.deallocated();
+            Ok((None, None))
+        }
+        CommandCode::ComChangeUser => {
+            stmt_cache.lock().await.clear();
+            Ok((None, None))
+        }
+        _ => Ok((None, None)),
+    }
+}
+
+/// Prepares `sql` on the backend, returns the backend statement id and the prepare response,
+/// or `None` if the backend refused the statement.
+async fn prepare_on_backend(
+    sql: &[u8],
+    backend_writer: &mut PacketWriter<BackendWriteHalf>,
+    backend_reader: &mut PacketReader<BackendReadHalf>,
+    capabilities: CapabilityFlags,
+) -> Result<Option<(u32, Vec<Packet>)>, Error> {
+    let mut prepare_stmt = vec![CommandCode::ComStmtPrepare as u8];
+    prepare_stmt.extend_from_slice(sql);
+    write_one_packet(backend_writer, 0, &prepare_stmt, true).await?;
+    let response = read_prepare_response(backend_reader, capabilities).await?;
+    if response[0].is_err_packet() {
+        parse_err_packet!(capabilities, response[0], "stmt_cache re-prepare ERR");
+        return Ok(None);
+    }
+    let backend_id = byteorder::LittleEndian::read_u32(&response[0][1..5]);
+    Ok(Some((backend_id, response)))
+}
+
+/// Prepares a backend statement of its own for `client_id`. The shared backend statement is
+/// used if the backend refuses.
+async fn prepare_private_stmt(
+    stmt_cache: &SharedStmtCache,
+    client_id: u32,
+    backend_writer: &mut PacketWriter<BackendWriteHalf>,
+    backend_reader: &mut PacketReader<BackendReadHalf>,
+    capabilities: CapabilityFlags,
+) -> Result<(), Error> {
+    let sql = { stmt_cache.lock().await.sql(client_id).map(<[u8]>::to_vec) };
+    let Some(sql) = sql else {
+        return Ok(());
+    };
+    if let Some((backend_id, response)) =
+        prepare_on_backend(&sql, backend_writer, backend_reader, capabilities).await?
+    {
+        debug!("ProxySrv stmt cache private stmt {client_id} backend_stmt_id={backend_id}");
+        stmt_cache
+            .lock()
+            .await
+            .set_private(client_id, CachedStmt::new(backend_id, sql, response));
+    }
+    Ok(())
+}
+
+/// Prepares the statement behind `client_id` again after the backend reported it as unknown or
+/// invalidated by a schema change, or deallocated it. Returns the new backend statement id.
+pub(crate) async fn reprepare_stmt(
+    stmt_cache: &SharedStmtCache,
+    client_id: u32,
+    backend_writer: &mut PacketWriter<BackendWriteHalf>,
+    backend_reader: &mut PacketReader<BackendReadHalf>,
+    capabilities: CapabilityFlags,
+) -> Result<Option<u32>, Error> {
+    let invalidated = {
+        let mut stmt_cache = stmt_cache.lock().await;
+        match stmt_cache.take_private(client_id) {
+            Some(private) => Some((None, private)),
+            None => stmt_cache
+                .invalidate(client_id)
+                .map(|(key, stmt)| (Some(key), stmt)),
+        }
+    };
+    let Some((key, stale_stmt)) = invalidated else {
+        return Ok(None);
+    };
+    debug!(
+        "ProxySrv stmt cache re-prepare stale backend_stmt_id={}",
+        stale_stmt.backend_id
+    );
+    // The backend may already reuse the id of a statement it deallocated.
+    if !stale_stmt.stale {
+        close_backend_stmts(&[stale_stmt.backend_id], backend_writer).await?;
+    }
+
+    let Some((backend_id, response)) = prepare_on_backend(
+        &stale_stmt.sql,
+        backend_writer,
+        backend_reader,
+        capabilities,
+    )
+    .await?
+    else {
+        return Ok(None);
+    };
+    let stmt = CachedStmt::new(backend_id, stale_stmt.sql, response);
+    let Some(key) = key else {
+        stmt_cache.lock().await.set_private(client_id, stmt);
+        return Ok(Some(backend_id));
+    };
+    let evicted = { stmt_cache.lock().await.insert(key, stmt) };
+    close_backend_stmts(&evicted, backend_writer).await?;
+    Ok(Some(backend_id))
 }
 
 impl StmtPrepareForwarder {
     async fn forward_prepare_stmt<W>(
         &self,
         client_writer: &mut PacketWriter<W>,
-        backend_reader: &mut PacketReader<OwnedReadHalf>,
+        backend_reader: &mut PacketReader<BackendReadHalf>,
         handshake: &HandshakeResponse,
     ) -> Result<Option<Packet>, Error>
     where
@@ -32,7 +451,6 @@ impl StmtPrepareForwarder {
             .forward_one_packet(client_writer, backend_reader, false)
             .await?;
         let capabilities = handshake.client_flag;
-        let is_client_deprecate_eof = capabilities.contains(CapabilityFlags::CLIENT_DEPRECATE_EOF);
         if packet.is_err_packet() {
             parse_err_packet!(capabilities, packet, "stmt_prepare_forward ERR");
             if let Err(e) = client_writer.flush_all().await {
@@ -41,17 +459,7 @@ impl StmtPrepareForwarder {
                 Ok(None)
             }
         } else if packet.is_ok_packet() {
-            let column = byteorder::LittleEndian::read_u16(&packet[5..]);
-            let params = byteorder::LittleEndian::read_u16(&packet[7..]);
-            let mut expected_packets = column + params;
-            if !is_client_deprecate_eof {
-                if column > 0 {
-                    expected_packets += 1
-                }
-                if params > 0 {
-                    expected_packets += 1
-                }
-            }
+            let expected_packets = expected_definition_packets(&packet, capabilities);
             for _idx in 0..expected_packets {
                 self.forward_one_packet(client_writer, backend_reader, false)
                     .await?;
@@ -65,10 +473,104 @@ impl StmtPrepareForwarder {
         }
     }
 
-    async fn forward_close_stmt(&self) -> Result<Option<Packet>, Error> {
-        let _status = self.request[0];
-        let _stmt_id = byteorder::LittleEndian::read_u32(&self.request[1..5]);
-        // TODO update prepared statement status
+    /// Serves COM_STMT_PREPARE from the statement cache, preparing on the backend only on a miss.
+    /// The client always sees a proxy allocated statement id.
+    async fn forward_cached_prepare_stmt<W>(
+        &self,
+        stmt_cache: &SharedStmtCache,
+        client_writer: &mut PacketWriter<W>,
+        backend_writer: &mut PacketWriter<BackendWriteHalf>,
+        backend_reader: &mut PacketReader<BackendReadHalf>,
+        handshake: &HandshakeResponse,
+    ) -> Result<Option<Packet>, Error>
+    where
+        W: AsyncWrite + Send + Unpin,
+    {
+        let capabilities = handshake.client_flag;
+        let sql = &self.request[1..];
+        let key = normalize_stmt(sql);
+        let mut stmt_cache_guard = stmt_cache.lock().await;
+        let response = match stmt_cache_guard.lookup(&key) {
+            Some(cached_stmt) => {
+                debug!(
+                    "ProxySrv stmt cache hit backend_stmt_id={}",
+                    cached_stmt.backend_id
+                );
+                cached_stmt.response
+            }
+            None => {
+                let response = read_prepare_response(backend_reader, capabilities).await?;
+                if response[0].is_err_packet() {
+                    parse_err_packet!(capabilities, response[0], "stmt_prepare_forward ERR");
+                    write_one_packet(client_writer, 1, &response[0], true).await?;
+                    return Ok(None);
+                }
+                let backend_id = byteorder::LittleEndian::read_u32(&response[0][1..5]);
+                let evicted = stmt_cache_guard.insert(
+                    key.clone(),
+                    CachedStmt::new(backend_id, sql.to_vec(), response.clone()),
+                );
+                close_backend_stmts(&evicted, backend_writer).await?;
+                response
+            }
+        };
+        let client_id = stmt_cache_guard.register_client_stmt(key);
+        drop(stmt_cache_guard);
+        write_prepare_response(client_writer, response, client_id).await?;
+        Ok(None)
+    }
+
+    /// Forwards COM_STMT_PREPARE and records the statement in the registry of the session, the
+    /// client sees the statement id of the registry.
+    async fn forward_session_prepare_stmt<W>(
+        &self,
+        session_stmts: &SharedSessionStmts,
+        client_writer: &mut PacketWriter<W>,
+        backend_reader: &mut PacketReader<BackendReadHalf>,
+        handshake: &HandshakeResponse,
+    ) -> Result<Option<Packet>, Error>
+    where
+        W: AsyncWrite + Send + Unpin,
+    {
+        let capabilities = handshake.client_flag;
+        let response = read_prepare_response(backend_reader, capabilities).await?;
+        if response[0].is_err_packet() {
+            parse_err_packet!(capabilities, response[0], "stmt_prepare_forward ERR");
+            write_one_packet(client_writer, 1, &response[0], true).await?;
+            return Ok(None);
+        }
+        let backend_id = byteorder::LittleEndian::read_u32(&response[0][1..5]);
+        let client_id = session_stmts
+            .lock()
+            .await
+            .register(self.request[1..].to_vec(), backend_id);
+        write_prepare_response(client_writer, response, client_id).await?;
+        Ok(None)
+    }
+
+    /// Closing a cached statement only releases what it left on the backend: its private
+    /// backend statement, or its long data and cursor on the shared one.
+    async fn forward_close_stmt(
+        &self,
+        backend_writer: &mut PacketWriter<BackendWriteHalf>,
+        backend_reader: &mut PacketReader<BackendReadHalf>,
+    ) -> Result<Option<Packet>, Error> {
+        let stmt_id = byteorder::LittleEndian::read_u32(&self.request[1..5]);
+        let Some(stmt_cache) = &self.stmt_cache else {
+            return Ok(None);
+        };
+        let release = { stmt_cache.lock().await.close_client_stmt(stmt_id) };
+        match release {
+            Some(StmtRelease::Close(backend_id)) => {
+                close_backend_stmts(&[backend_id], backend_writer).await?;
+            }
+            Some(StmtRelease::Reset(backend_id)) => {
+                backend_writer.reset_seq();
+                writers::write_stmt_reset(backend_writer, backend_id).await?;
+                let (_be_seq, _be_rsp_pkt) = async_packet_read!(backend_reader);
+            }
+            None => {}
+        }
         Ok(None)
     }
 }
@@ -79,21 +581,272 @@ where
     R: AsyncRead + Send + Unpin,
     W: AsyncWrite + Send + Unpin,
 {
+    /// With the statement cache enabled, cached prepares and all closes stay on the proxy.
+    async fn write_to_backend(
+        &self,
+        seq: u8,
+        com_code: CommandCode,
+        _: &HandshakeResponse,
+        client_packet: Bytes,
+        backend_writer: &mut PacketWriter<BackendWriteHalf>,
+    ) -> Result<(), Error> {
+        if let Some(stmt_cache) = &self.stmt_cache {
+            let is_local = match com_code {
+                CommandCode::ComStmtPrepare => stmt_cache
+                    .lock()
+                    .await
+                    .contains(&normalize_stmt(&client_packet[1..])),
+                _ => true,
+            };
+            if is_local {
+                return Ok(());
+            }
+        }
+        write_one_packet(backend_writer, seq, &client_packet, true).await
+    }
+
     async fn forward(
         &self,
         _: &mut PacketReader<R>,
         client_writer: &mut PacketWriter<W>,
-        _: &mut PacketWriter<OwnedWriteHalf>,
-        backend_reader: &mut PacketReader<OwnedReadHalf>,
+        backend_writer: &mut PacketWriter<BackendWriteHalf>,
+        backend_reader: &mut PacketReader<BackendReadHalf>,
         handshake: &HandshakeResponse,
     ) -> Result<Option<Packet>, Error> {
-        match self.com_code {
-            CommandCode::ComStmtPrepare => {
+        match (self.com_code, &self.stmt_cache, &self.session_stmts) {
+            (CommandCode::ComStmtPrepare, Some(stmt_cache), _) => {
+                self.forward_cached_prepare_stmt(
+                    stmt_cache,
+                    client_writer,
+                    backend_writer,
+                    backend_reader,
+                    handshake,
+                )
+                .await
+            }
+            (CommandCode::ComStmtPrepare, None, Some(session_stmts)) => {
+                self.forward_session_prepare_stmt(
+                    session_stmts,
+                    client_writer,
+                    backend_reader,
+                    handshake,
+                )
+                .await
+            }
+            (CommandCode::ComStmtPrepare, None, None) => {
                 self.forward_prepare_stmt(client_writer, backend_reader, handshake)
                     .await
             }
-            CommandCode::ComStmtClose => self.forward_close_stmt().await,
+            (CommandCode::ComStmtClose, _, _) => {
+                self.forward_close_stmt(backend_writer, backend_reader)
+                    .await
+            }
             _ => unreachable!(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::pool::stmt_cache::PreparedStmtCache;
+    use crate::backend::stream::{connect_backend, mem_backends, MEM_SCHEME};
+    use crate::protocol::mysql::basic::Column;
+    use crate::protocol::mysql::constants::CommandCode;
+    use crate::protocol::mysql::error_codes::ErrorKind;
+    use crate::protocol::mysql::packet::packet_reader::PacketReader;
+    use crate::protocol::mysql::packet::packet_writer::PacketWriter;
+    use crate::protocol::mysql::packet::{writers, Packet};
+    use crate::server::forwarder::script::{PacketScript, Response};
+    use crate::server::forwarder::stmt_prepare_forward::{SessionStmts, StmtPrepareForwarder};
+    use bytes::Bytes;
+    use mysql_common::constants::CapabilityFlags;
+    use nanoid::nanoid;
+    use std::sync::Arc;
+    use tokio::sync::Mutex;
+
+    fn forwarder(com_code: CommandCode, request: &[u8], cached: bool) -> StmtPrepareForwarder {
+        StmtPrepareForwarder {
+            com_code,
+            request: Bytes::copy_from_slice(request),
+            stmt_cache: cached.then(|| Arc::new(Mutex::new(PreparedStmtCache::new(16)))),
+            session_stmts: None,
+        }
+    }
+
+    #[tokio::test]
+    pub async fn test_stmt_prepare_forward() {
+        let prepare = [
+            &[CommandCode::ComStmtPrepare as u8][..],
+            b"SELECT c FROM t WHERE id = ?",
+        ]
+        .concat();
+        let prepare_ok = Response::PrepareOk {
+            id: 7,
+            params: 1,
+            columns: 1,
+        };
+        let uncached = forwarder(CommandCode::ComStmtPrepare, &prepare, false);
+        let outcome = PacketScript::new()
+            .expect_client_packet(&prepare)
+            .backend_responds(prepare_ok.clone())
+            .run(&uncached, CommandCode::ComStmtPrepare, &prepare)
+            .await;
+        assert!(outcome.response().is_none());
+        outcome.assert_forwarded();
+        assert_eq!(outcome.client_received.len(), 3);
+
+        let outcome = PacketScript::new()
+            .expect_client_packet(&prepare)
+            .backend_responds(Response::Err(ErrorKind::ER_NO_SUCH_TABLE, "no table"))
+            .run(&uncached, CommandCode::ComStmtPrepare, &prepare)
+            .await;
+        assert!(outcome.response().is_none());
+        outcome.assert_forwarded();
+
+        // A miss prepares on the backend, the client sees the statement id of the proxy.
+        let cached = forwarder(CommandCode::ComStmtPrepare, &prepare, true);
+        let outcome = PacketScript::new()
+            .expect_client_packet(&prepare)
+            .backend_responds(prepare_ok)
+            .run(&cached, CommandCode::ComStmtPrepare, &prepare)
+            .await;
+        assert!(outcome.response().is_none());
+        let (received, sent) = (&outcome.client_received, &outcome.backend_sent);
+        assert_eq!(received[0].1[1..5], 1_u32.to_le_bytes());
+        assert_eq!(sent[0].1[1..5], 7_u32.to_le_bytes());
+        assert_eq!(received[1..], sent[1..]);
+
+        // A hit is answered without the backend.
+        let outcome = PacketScript::new()
+            .run(&cached, CommandCode::ComStmtPrepare, &prepare)
+            .await;
+        assert!(outcome.response().is_none());
+        assert_eq!(outcome.client_received.len(), 3);
+        assert_eq!(outcome.client_received[0].1[1..5], 2_u32.to_le_bytes());
+
+        let close = [&[CommandCode::ComStmtClose as u8][..], &1_u32.to_le_bytes()].concat();
+        let mut closing = forwarder(CommandCode::ComStmtClose, &close, true);
+        closing.stmt_cache = cached.stmt_cache.clone();
+        let outcome = PacketScript::new()
+            .run(&closing, CommandCode::ComStmtClose, &close)
+            .await;
+        assert!(outcome.response().is_none());
+        assert!(outcome.client_received.is_empty());
+
+        // The registry of the session remaps its own statement ids to those of the backend.
+        let mut registered = forwarder(CommandCode::ComStmtPrepare, &prepare, false);
+        let session_stmts = Arc::new(Mutex::new(SessionStmts::new(CapabilityFlags::empty())));
+        registered.session_stmts = Some(Arc::clone(&session_stmts));
+        let outcome = PacketScript::new()
+            .expect_client_packet(&prepare)
+            .backend_responds(Response::PrepareOk {
+                id: 42,
+                params: 1,
+                columns: 1,
+            })
+            .run(&registered, CommandCode::ComStmtPrepare, &prepare)
+            .await;
+        assert!(outcome.response().is_none());
+        assert_eq!(outcome.client_received[0].1[1..5], 1_u32.to_le_bytes());
+        let mut session_stmts = session_stmts.lock().await;
+        assert_eq!(session_stmts.sql(1), Some(&prepare[1..]));
+        let execute = [
+            &[CommandCode::ComStmtExecute as u8][..],
+            &1_u32.to_le_bytes(),
+        ]
+        .concat();
+        let mut execute = Packet::from_vec(execute);
+        let executed = session_stmts.apply(CommandCode::ComStmtExecute, &mut execute, true);
+        assert_eq!(executed.as_deref(), Some(&prepare[1..]));
+        assert_eq!(execute[1..5], 42_u32.to_le_bytes());
+        let mut close = Packet::from_vec(close);
+        assert_eq!(
+            session_stmts.translate(CommandCode::ComStmtClose, &mut close),
+            Some(1)
+        );
+        assert_eq!(close[1..5], 42_u32.to_le_bytes());
+        assert!(session_stmts.is_empty());
+    }
+
+    #[tokio::test]
+    pub async fn test_session_stmts_reprepare() {
+        let capabilities = CapabilityFlags::CLIENT_PROTOCOL_41;
+        let name = format!("session-stmts-{}", nanoid!());
+        let mut listener = mem_backends().listen(&name);
+        // The backend the session switched to, it numbers the statements from 7.
+        let backend = tokio::spawn(async move {
+            let stream = listener.accept().await.unwrap();
+            let (read, write) = tokio::io::split(stream);
+            let mut reader = PacketReader::new(read);
+            let mut writer = PacketWriter::new(write);
+            let mut next_id = 7;
+            let mut prepared = vec![];
+            while let Ok(Some((_, packet))) = reader.next_async().await {
+                assert_eq!(packet[0], CommandCode::ComStmtPrepare as u8);
+                writer.set_seq(1);
+                if &packet[1..] == b"SELECT bad" {
+                    writers::write_err_packet(
+                        ErrorKind::ER_PARSE_ERROR,
+                        b"syntax error",
+                        &mut writer,
+                        capabilities,
+                    )
+                    .await
+                    .unwrap();
+                } else {
+                    let no_columns: [Column; 0] = [];
+                    writers::write_prepare_ok(
+                        next_id,
+                        &no_columns,
+                        &no_columns,
+                        &mut writer,
+                        capabilities,
+                    )
+                    .await
+                    .unwrap();
+                    next_id += 1;
+                }
+                writer.flush_all().await.unwrap();
+                prepared.push(packet[1..].to_vec());
+            }
+            prepared
+        });
+        let (read, write) = connect_backend(&format!("{MEM_SCHEME}{name}"))
+            .await
+            .unwrap();
+        mem_backends().remove(&name);
+        let mut conn = (PacketReader::new(read), PacketWriter::new(write));
+
+        let mut session_stmts = SessionStmts::new(capabilities);
+        let first = session_stmts.register(b"SELECT ?".to_vec(), 1);
+        let second = session_stmts.register(b"SELECT 2".to_vec(), 2);
+        session_stmts.reprepare(&mut conn).await.unwrap();
+        // The client keeps its ids, they now stand for the statements of the new backend.
+        let backend_id = |session_stmts: &mut SessionStmts, client_id: u32| {
+            let execute = [
+                &[CommandCode::ComStmtExecute as u8][..],
+                &client_id.to_le_bytes(),
+            ]
+            .concat();
+            let mut execute = Packet::from_vec(execute);
+            assert_eq!(
+                session_stmts.translate(CommandCode::ComStmtExecute, &mut execute),
+                Some(client_id)
+            );
+            u32::from_le_bytes(execute[1..5].try_into().unwrap())
+        };
+        let mut backend_ids = [
+            backend_id(&mut session_stmts, first),
+            backend_id(&mut session_stmts, second),
+        ];
+        backend_ids.sort();
+        assert_eq!(backend_ids, [7, 8]);
+
+        // A statement the new backend refuses fails the switch.
+        session_stmts.register(b"SELECT bad".to_vec(), 3);
+        assert!(session_stmts.reprepare(&mut conn).await.is_err());
+        drop(conn);
+        let prepared = backend.await.unwrap();
+        assert!(prepared.len() >= 3 && prepared.contains(&b"SELECT bad".to_vec()));
+    }
+}
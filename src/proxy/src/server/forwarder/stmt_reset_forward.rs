@@ -0,0 +1,83 @@
+use crate::backend::stream::{BackendReadHalf, BackendWriteHalf};
+use crate::protocol::mysql::basic::HandshakeResponse;
+use crate::protocol::mysql::packet::packet_reader::PacketReader;
+use crate::protocol::mysql::packet::packet_writer::PacketWriter;
+use crate::protocol::mysql::packet::Packet;
+use crate::server::forwarder::session_state::SharedSessionState;
+use crate::server::forwarder::ComForwarder;
+
+use async_trait::async_trait;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// Forwards COM_STMT_RESET, which discards the long data buffered for a statement and closes its
+/// cursor. Once the backend confirmed it the connection no longer holds statement buffers, see
+/// [`SessionState::stmt_buffers`].
+///
+/// [`SessionState::stmt_buffers`]: crate::server::forwarder::session_state::SessionState::stmt_buffers
+pub struct StmtResetForwarder {
+    pub session_state: SharedSessionState,
+}
+
+#[async_trait]
+impl<R, W> ComForwarder<R, W> for StmtResetForwarder
+where
+    R: AsyncRead + Send + Unpin,
+    W: AsyncWrite + Send + Unpin,
+{
+    async fn forward(
+        &self,
+        _: &mut PacketReader<R>,
+        client_writer: &mut PacketWriter<W>,
+        _: &mut PacketWriter<BackendWriteHalf>,
+        backend_reader: &mut PacketReader<BackendReadHalf>,
+        _: &HandshakeResponse,
+    ) -> Result<Option<Packet>, std::io::Error> {
+        let response = self
+            .forward_one_packet(client_writer, backend_reader, true)
+            .await?;
+        if response.is_ok_packet() {
+            self.session_state.observe_stmt_reset();
+        }
+        Ok(Some(response))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::protocol::mysql::constants::CommandCode;
+    use crate::protocol::mysql::error_codes::ErrorKind;
+    use crate::server::forwarder::script::{PacketScript, Response};
+    use crate::server::forwarder::session_state::SessionStateTracker;
+    use crate::server::forwarder::stmt_reset_forward::StmtResetForwarder;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    pub async fn test_stmt_reset_forward() {
+        let stmt_id = 1_u32.to_le_bytes();
+        let reset = [&[CommandCode::ComStmtReset as u8][..], &stmt_id].concat();
+        let forwarder = StmtResetForwarder {
+            session_state: Arc::new(SessionStateTracker::default()),
+        };
+        let session_state = &forwarder.session_state;
+
+        session_state.observe_command(CommandCode::ComStmtSendLongData, &stmt_id);
+        let outcome = PacketScript::new()
+            .expect_client_packet(&reset)
+            .backend_responds(Response::Err(
+                ErrorKind::ER_UNKNOWN_STMT_HANDLER,
+                "Unknown prepared statement handler",
+            ))
+            .run(&forwarder, CommandCode::ComStmtReset, &reset)
+            .await;
+        outcome.assert_forwarded();
+        assert!(session_state.state().stmt_buffers);
+
+        let outcome = PacketScript::new()
+            .expect_client_packet(&reset)
+            .backend_responds(Response::ok())
+            .run(&forwarder, CommandCode::ComStmtReset, &reset)
+            .await;
+        outcome.assert_forwarded();
+        assert!(session_state.is_clean());
+    }
+}
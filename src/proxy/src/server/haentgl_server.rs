@@ -1,88 +1,486 @@
+use crate::audit::AuditConn;
 use crate::backend::backend_mgr::BackendMgr;
-use crate::backend::{DbConnPhase, DbUserConnLifeCycle};
+use crate::backend::quarantine::{
+    is_backend_auth_failure, is_broken_conn, quarantine_registry, BackendFailure,
+};
+use crate::backend::router::p2c::backend_conns;
+use crate::backend::shard::{reject_shard_mismatch, shard_registry};
+use crate::backend::topology_freshness::topology_freshness;
+use crate::backend::{
+    handshake_tenant_key, try_handshake_tenant_key, DbConnPhase, DbUserConnLifeCycle,
+};
+use crate::cp::active_users::{ActivityBatcher, UserActivityWindow};
 use crate::protocol::mysql::basic::HandshakeResponse;
 use crate::protocol::mysql::constants::CommandCode;
-use crate::protocol::mysql::packet::packet_reader::PacketReader;
-use crate::protocol::mysql::packet::packet_writer::PacketWriter;
+use crate::protocol::mysql::error_codes::ErrorKind;
+use crate::protocol::mysql::fingerprint::fingerprint;
+use crate::protocol::mysql::packet::packet_reader::{PacketReader, PacketTooLarge};
+use crate::protocol::mysql::packet::packet_writer::{FlowControl, PacketWriter, Watermarks};
 use crate::protocol::mysql::packet::*;
-use crate::server::auth::{gen_user_salt, Authenticator};
-use crate::server::forwarder::query_forward::QueryForwarder;
+use crate::server::admin::answer_admin_command;
+use crate::server::auth::client_acl::{client_acl, write_host_denied_err};
+use crate::server::auth::identity::{identity_registry, reject_change_user};
+use crate::server::auth::reconnect_token::reconnect_tokens;
+use crate::server::auth::{gen_conn_id, gen_user_salt, Authenticator};
+use crate::server::auth_limiter::{auth_limiter, write_auth_full_err};
+use crate::server::billing::SessionUsage;
+#[cfg(feature = "tls")]
+use crate::server::client_stream::{
+    accept_client_tls, is_tls_request, ClientReadHalf, ClientWriteHalf,
+};
+use crate::server::command_policy::{
+    command_policy, reject_command, reject_replication, ReplicationAttempt,
+};
+use crate::server::conn_tracker::{ConnToken, ConnTracker, SHUTDOWN_NOTICE};
+use crate::server::drain::{drain_registry, write_drain_err, write_shutdown_notice};
+use crate::server::error_stats::err_code_hook;
+use crate::server::failover::{is_retryable, SessionReplay};
+use crate::server::fault_injection::{apply_com_fault, fault_injector, FaultAction};
+use crate::server::forwarder::binlog_dump_forward::BinlogDumpForwarder;
+use crate::server::forwarder::query_forward::{CachedExecute, QueryForwarder};
 use crate::server::forwarder::reset_conn_forward::ResetConnForwarder;
-use crate::server::forwarder::stmt_prepare_forward::StmtPrepareForwarder;
+use crate::server::forwarder::session_state::watch_command;
+use crate::server::forwarder::set_option_forward::{
+    handshake_multi_statements, multi_statements_option, restore_multi_statements,
+    SetOptionForwarder,
+};
+use crate::server::forwarder::stmt_long_data_forward::StmtLongDataForwarder;
+use crate::server::forwarder::stmt_prepare_forward::{
+    apply_stmt_cache, SessionStmts, StmtPrepareForwarder,
+};
+use crate::server::forwarder::stmt_reset_forward::StmtResetForwarder;
 use crate::server::forwarder::{change_user_forward, ComForwarder, GenericComForwarder};
-use crate::server::{init_sql_com_labels, ProxyServer};
+use crate::server::handshake_profile::HandshakeProfile;
+use crate::server::idle_timeout::{close_idle_session, idle_timer};
+use crate::server::keepalive::{keepalive_timer, ping_backend};
+use crate::server::long_data::{apply_long_data_limits, long_data_policy, LongDataTracker};
+use crate::server::maintenance::{
+    write_maintenance_err, write_notice_warnings, NoticeAction, SessionNotice,
+};
+use crate::server::mirror::ShadowMirror;
+use crate::server::notifier::{notify, ProxyEventKind};
+use crate::server::packet_capture::{packet_capture, Direction};
+use crate::server::protocol_limits::{reject_packet_too_large, ProtocolLimits};
+use crate::server::proxy_protocol::{read_proxy_header, TrustedProxies, PROXY_HEADER_TIMEOUT};
+use crate::server::query_digest::query_digests;
+use crate::server::rate_limit::{command_rate_limiter, reject_rate_limited, RateLimitKey};
+use crate::server::read_split::ReadSplit;
+use crate::server::readiness::mark_router_initialized;
+use crate::server::recent_errors::recent_errors;
+use crate::server::route_policy::{route_policy, PolicyInput, RouteDecision, CONNECT_CLASS};
+use crate::server::session::{
+    end_killed_session, killed_session_err, session_registry, SessionCloseReason, SessionMemory,
+};
+use crate::server::session_backend::SessionBackend;
+use crate::server::session_metrics::{ComLatencyRecorder, ComTraffic, SessionMetrics};
+use crate::server::slow_log::{slow_query_log, truncate_sql};
+use crate::server::sni_router::sni_router;
+use crate::server::startup_report::{publish_startup_report, StartupReport};
+use crate::server::transparent::transparent_router;
+use crate::server::ProxyServer;
 
 use async_trait::async_trait;
-use common::metrics::common_labels;
-use common::metrics::metric_def::PROXY_COM_LATENCY;
-use hashbrown::HashMap;
+use byteorder::{ByteOrder, LittleEndian};
+use common::clock::clock;
+use futures::future::OptionFuture;
+use mysql_common::constants::{CapabilityFlags, StatusFlags};
 use num_traits::FromPrimitive;
 use rustls::server::ServerConfig;
 use std::borrow::BorrowMut;
 use std::io::Error;
+use std::net::SocketAddr;
 use std::ops::DerefMut;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use std::thread;
+use std::time::Duration;
 use tokio::io::{AsyncRead, AsyncWrite};
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::sync::Mutex;
+use tokio::time::{timeout, Instant};
 use tokio_rustls::rustls;
-use tracing::{debug, warn};
+use tracing::{debug, info, warn};
 
 pub struct HaentglServer<A> {
-    sql_com_labels: HashMap<u8, Vec<(&'static str, String)>>,
+    com_latency: ComLatencyRecorder,
     backend_mgr: Arc<BackendMgr>,
     authenticator: A,
+    /// Answers COM_QUIT with an OK packet before closing the client connection.
+    quit_reply_ok: bool,
+    /// Receives the commands of every session for the control plane, if it pulls active users.
+    active_users: Option<Arc<UserActivityWindow>>,
+    /// Bounds the results buffered for a client, `None` writes every packet right away.
+    client_watermarks: Option<Watermarks>,
+    /// Pings the backend connection of a session idle this long outside a transaction.
+    backend_keepalive: Option<Duration>,
+    /// Closes the session of a client idle this long, see [`idle_timer`].
+    client_idle_timeout: Option<Duration>,
+    protocol_limits: ProtocolLimits,
+    /// Published with the backends once the pools are initialized.
+    startup_report: Option<StartupReport>,
+    conns: ConnTracker,
+    drain_timeout: Duration,
+    /// Reads a PROXY protocol header ahead of the handshake of the clients accepted from a trusted
+    /// load balancer, its source address stands for the client.
+    proxy_protocol: Option<TrustedProxies>,
 }
 
 impl<A: Authenticator> HaentglServer<A> {
     pub fn new(backend_mgr: Arc<BackendMgr>, authenticator: A) -> Self {
         Self {
-            sql_com_labels: init_sql_com_labels().clone(),
+            com_latency: ComLatencyRecorder::default(),
             backend_mgr,
             authenticator,
+            quit_reply_ok: false,
+            active_users: None,
+            client_watermarks: None,
+            backend_keepalive: None,
+            client_idle_timeout: None,
+            protocol_limits: ProtocolLimits::default(),
+            startup_report: None,
+            conns: ConnTracker::new(),
+            drain_timeout: Duration::ZERO,
+            proxy_protocol: None,
         }
     }
 
+    pub fn with_quit_reply_ok(mut self, quit_reply_ok: bool) -> Self {
+        self.quit_reply_ok = quit_reply_ok;
+        self
+    }
+
+    pub fn with_active_users(mut self, active_users: Option<Arc<UserActivityWindow>>) -> Self {
+        self.active_users = active_users;
+        self
+    }
+
+    pub fn with_client_watermarks(mut self, client_watermarks: Option<Watermarks>) -> Self {
+        self.client_watermarks = client_watermarks;
+        self
+    }
+
+    pub fn with_backend_keepalive(mut self, backend_keepalive: Option<Duration>) -> Self {
+        self.backend_keepalive = backend_keepalive;
+        self
+    }
+
+    pub fn with_client_idle_timeout(mut self, client_idle_timeout: Option<Duration>) -> Self {
+        self.client_idle_timeout = client_idle_timeout;
+        self
+    }
+
+    pub fn with_protocol_limits(mut self, protocol_limits: ProtocolLimits) -> Self {
+        self.protocol_limits = protocol_limits;
+        self
+    }
+
+    /// Queues the command latencies for an aggregator task instead of recording them on the
+    /// command path, see [`ComLatencyRecorder::Queued`]. `None` records them directly.
+    pub fn with_com_latency_queue(mut self, capacity: Option<usize>) -> Self {
+        if let Some(capacity) = capacity {
+            self.com_latency = ComLatencyRecorder::queued(capacity);
+        }
+        self
+    }
+
+    pub fn with_startup_report(mut self, startup_report: StartupReport) -> Self {
+        self.startup_report = Some(startup_report);
+        self
+    }
+
+    /// Time the sessions have to drain on [`ProxyServer::close`].
+    pub fn with_drain_timeout(mut self, drain_timeout: Duration) -> Self {
+        self.drain_timeout = drain_timeout;
+        self
+    }
+
+    /// Expects a PROXY header from the clients accepted from the `trusted` peers, see
+    /// [`Self::connect_to`].
+    pub fn with_proxy_protocol(mut self, trusted: Option<TrustedProxies>) -> Self {
+        self.proxy_protocol = trusted;
+        self
+    }
+
     pub async fn connect<'a, R, W>(
         &'a self,
         reader: R,
-        mut writer: W,
+        writer: W,
+        profile: &HandshakeProfile,
         #[cfg(feature = "tls")] tls_conf: &Option<Arc<ServerConfig>>,
     ) -> Result<(), Error>
     where
         R: AsyncRead + Send + Unpin,
         W: AsyncWrite + Send + Unpin,
     {
+        #[cfg(feature = "tls")]
+        let connected = self
+            .connect_to(reader, writer, profile, tls_conf, None, None)
+            .await;
+        #[cfg(not(feature = "tls"))]
+        let connected = self.connect_to(reader, writer, profile, None, None).await;
+        connected
+    }
+
+    /// [`Self::connect`] for a connection intercepted in transparent mode, routed by the
+    /// `original_dst` it was sent to, see [`TransparentRouter::route`]. The `client_addr` is
+    /// handed to the route policy. With [`Self::with_proxy_protocol`] a connection from a trusted
+    /// load balancer starts with a PROXY header, whose source address replaces the `client_addr`.
+    /// Other connections keep their own address. With a `tls_conf`, clients may upgrade to TLS
+    /// and are routed by the hostname they connected to, see [`SniRouter::route`].
+    ///
+    /// [`TransparentRouter::route`]: crate::server::transparent::TransparentRouter::route
+    /// [`SniRouter::route`]: crate::server::sni_router::SniRouter::route
+    pub async fn connect_to<'a, R, W>(
+        &'a self,
+        mut reader: R,
+        writer: W,
+        profile: &HandshakeProfile,
+        #[cfg(feature = "tls")] tls_conf: &Option<Arc<ServerConfig>>,
+        original_dst: Option<SocketAddr>,
+        mut client_addr: Option<SocketAddr>,
+    ) -> Result<(), Error>
+    where
+        R: AsyncRead + Send + Unpin,
+        W: AsyncWrite + Send + Unpin,
+    {
+        let mut conn = self.conns.track();
+        let from_trusted_proxy = self
+            .proxy_protocol
+            .as_ref()
+            .zip(client_addr)
+            .is_some_and(|(trusted, addr)| trusted.is_trusted(addr.ip()));
+        if from_trusted_proxy {
+            let header = timeout(PROXY_HEADER_TIMEOUT, read_proxy_header(&mut reader))
+                .await
+                .unwrap_or_else(|_| {
+                    Err(Error::new(
+                        std::io::ErrorKind::TimedOut,
+                        "no PROXY header in time",
+                    ))
+                });
+            match header {
+                Ok(Some(addrs)) => client_addr = Some(addrs.source),
+                Ok(None) => {}
+                Err(e) => {
+                    warn!("ProxySrv PROXY header from {client_addr:?} rejected {e}");
+                    recent_errors().record("proxy_protocol", e.to_string());
+                    return Err(e);
+                }
+            }
+        }
+        // Clients may ask for TLS within the handshake, their connection is then upgraded in place.
+        #[cfg(feature = "tls")]
+        let (reader, writer) = (
+            ClientReadHalf::<R, W>::Plain(reader),
+            ClientWriteHalf::<R, W>::Plain(writer),
+        );
+        let mut writer = writer;
+        let mut audit = AuditConn::accepted(client_addr);
+        let client_ip = client_addr.map(|addr| addr.ip());
+        if let Some(Err(message)) = client_ip.map(|ip| client_acl().check_client(ip)) {
+            audit.set_close_reason(&message);
+            let mut client_writer = PacketWriter::new(&mut writer);
+            write_host_denied_err(&message, &mut client_writer, CapabilityFlags::empty()).await?;
+            return Err(Error::new(std::io::ErrorKind::PermissionDenied, message));
+        }
+        let Some(auth_permit) = auth_limiter().acquire().await else {
+            audit.set_close_reason("too many connections authenticating");
+            write_auth_full_err(&mut PacketWriter::new(&mut writer)).await?;
+            return Err(Error::new(
+                std::io::ErrorKind::ConnectionRefused,
+                "too many connections authenticating",
+            ));
+        };
         let salt = gen_user_salt();
         #[cfg(feature = "tls")]
-        let (seq, handshake_response, handshake_pkt, mut reader) =
-            self.on_conn(reader, &mut writer, salt, tls_conf).await?;
+        let (seq, handshake_response, handshake_pkt, mut reader) = self
+            .on_conn(reader, &mut writer, salt, profile, tls_conf)
+            .await?;
         #[cfg(not(feature = "tls"))]
-        let (seq, handshake_response, handshake_pkt, mut reader) =
-            self.on_conn(reader, &mut writer, salt, None).await?;
+        let (seq, mut handshake_response, handshake_pkt, mut reader) = self
+            .on_conn(reader, &mut writer, salt, profile, None)
+            .await?;
+        #[cfg(feature = "tls")]
+        let (seq, mut handshake_response, handshake_pkt) = match tls_conf {
+            Some(tls_conf) if is_tls_request(&handshake_response) => {
+                accept_client_tls(&mut reader, &mut writer, tls_conf.clone(), profile)
+                    .await
+                    .inspect_err(|e| {
+                        warn!("ProxySrv client TLS handshake from {client_addr:?} failed {e}");
+                        audit.set_close_reason(&e.to_string());
+                    })?
+            }
+            None if is_tls_request(&handshake_response) => {
+                audit.set_close_reason("TLS requested but not offered");
+                return Err(Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "client requested TLS but the listener does not offer it",
+                ));
+            }
+            _ => (seq, handshake_response, handshake_pkt),
+        };
 
-        let pool_ref = self
-            .backend_mgr
-            .connect_to_backend(&handshake_response)
+        let routed = transparent_router()
+            .route(original_dst, &mut handshake_response)
+            .and_then(|_| sni_router().route(&mut handshake_response))
+            // The tenant key comes with the user of the client, the steps below expect a valid one.
+            .and_then(|_| try_handshake_tenant_key(&handshake_response))
+            .and_then(|_| identity_registry().map_identity(&mut handshake_response, &salt))
+            .and_then(|_| {
+                shard_registry()
+                    .route(&mut handshake_response)
+                    .inspect_err(|e| warn!("ProxySrv shard routing failed {e:?}"))
+            });
+        if let Ok(tenant) = try_handshake_tenant_key(&handshake_response) {
+            audit.set_user(tenant, handshake_response.client_user_string());
+        }
+        if let Err(e) = routed {
+            recent_errors().record("routing", e.to_string());
+            audit.auth_failure(&e.to_string());
+            let mut client_writer = PacketWriter::new(&mut writer);
+            client_writer.set_seq(seq.wrapping_add(1));
+            writers::write_err_packet(
+                ErrorKind::ER_ACCESS_DENIED_ERROR,
+                e.to_string().as_bytes(),
+                &mut client_writer,
+                handshake_response.client_flag,
+            )
+            .await?;
+            client_writer.flush_all().await?;
+            return Err(e);
+        }
+        let tenant = handshake_tenant_key(&handshake_response);
+        if let Some(Err(message)) = client_ip.map(|ip| client_acl().check_tenant(&tenant, ip)) {
+            audit.auth_failure(&message);
+            let mut client_writer = PacketWriter::new(&mut writer);
+            client_writer.set_seq(seq.wrapping_add(1));
+            write_host_denied_err(&message, &mut client_writer, handshake_response.client_flag)
+                .await?;
+            return Err(Error::new(std::io::ErrorKind::PermissionDenied, message));
+        }
+        if let Some(message) = drain_registry().refusal(&tenant) {
+            warn!("ProxySrv session refused: {message}");
+            audit.set_close_reason(&message);
+            let mut client_writer = PacketWriter::new(&mut writer);
+            write_drain_err(
+                &message,
+                seq,
+                &mut client_writer,
+                handshake_response.client_flag,
+            )
             .await?;
+            return Err(Error::new(std::io::ErrorKind::ConnectionRefused, message));
+        }
+        let policy_input = PolicyInput {
+            tenant: tenant.clone(),
+            user: handshake_response.client_user_string(),
+            client_ip: client_ip.map(|ip| ip.to_string()),
+            statement_class: CONNECT_CLASS,
+        };
+        let policy_backend = match route_policy().decide(policy_input).await {
+            RouteDecision::Allow => None,
+            RouteDecision::Route(backend_addr) => Some(backend_addr),
+            RouteDecision::Deny(reason) => {
+                warn!("ProxySrv session denied by the route policy: {reason}");
+                recent_errors().record("route_policy", reason.clone());
+                audit.auth_failure(&reason);
+                let mut client_writer = PacketWriter::new(&mut writer);
+                client_writer.set_seq(seq.wrapping_add(1));
+                writers::write_err_packet(
+                    ErrorKind::ER_ACCESS_DENIED_ERROR,
+                    reason.as_bytes(),
+                    &mut client_writer,
+                    handshake_response.client_flag,
+                )
+                .await?;
+                client_writer.flush_all().await?;
+                return Err(Error::new(std::io::ErrorKind::PermissionDenied, reason));
+            }
+        };
+        reconnect_tokens().redeem(&mut handshake_response);
+
+        let pool_ref = match &policy_backend {
+            Some(backend_addr) => {
+                self.backend_mgr
+                    .connect_to_backend_at(&handshake_response, backend_addr)
+                    .await
+            }
+            None => {
+                self.backend_mgr
+                    .connect_to_backend(&handshake_response)
+                    .await
+            }
+        };
+        let pool_ref = pool_ref.inspect_err(|e| {
+            recent_errors().record("backend", e.to_string());
+            topology_freshness().record_connect_failure(&tenant);
+        })?;
 
+        let backend_addr = pool_ref.manager().get_addr().await;
+        audit.backend_selected(&backend_addr);
+        reconnect_tokens().grant(&mut handshake_response, &backend_addr);
+        let pool_status = pool_ref.status();
+        if pool_status.available == 0 && pool_status.size >= pool_status.max_size {
+            notify(
+                ProxyEventKind::PoolExhausted,
+                &backend_addr,
+                format!("all {} pooled connections in use", pool_status.max_size),
+            );
+        }
         // FIXME: when pool is full, it will block here.
-        let pooled_conn = pool_ref.get().await.unwrap();
+        let pooled_conn = pool_ref.get().await.map_err(|e| {
+            recent_errors().record("backend", format!("{backend_addr} {e}"));
+            topology_freshness().record_connect_failure(&tenant);
+            Error::new(std::io::ErrorKind::NotConnected, e.to_string())
+        })?;
+        topology_freshness().record_connect_success(&tenant);
+        let in_flight = backend_conns().checkout(&backend_addr);
+        let checked_out_at = clock().coarse_now();
         let conn_uid = &pooled_conn.id;
-        let backend_conn = &pooled_conn.inner_conn;
-        let mut backend_client_guard = backend_conn.lock().await;
+        let mut backend_client_guard = pooled_conn.inner_conn.clone().lock_owned().await;
 
         let conn_life_cycle = { pooled_conn.get_conn_life_cycle().await };
         let (backend_reader, backend_writer) = backend_client_guard.deref_mut();
         backend_writer.reset_seq();
+        backend_reader.set_err_hook(Some(err_code_hook(&tenant, &backend_addr, &pooled_conn)));
 
         let mut mut_writer = PacketWriter::new(writer);
+        // A redeemed token skips the authentication if the connection is already the user's.
+        let resumable = handshake_response.reconnect.is_some()
+            && conn_life_cycle.db_user() == Some(handshake_response.db_user_string());
         let auth_result = if let Some(conn_phase) = conn_life_cycle.conn_phase() {
             match conn_phase {
+                DbConnPhase::Command if resumable => {
+                    debug!("ProxySrv ConnPhase == Command, resuming {conn_uid:?}.");
+                    let resumed = self
+                        .authenticator
+                        .resume_auth(
+                            backend_writer,
+                            backend_reader,
+                            &mut mut_writer,
+                            seq,
+                            &handshake_response,
+                        )
+                        .await;
+                    match resumed {
+                        Ok(true) => Ok(()),
+                        Ok(false) => {
+                            self.authenticator
+                                .continue_auth(
+                                    backend_writer,
+                                    backend_reader,
+                                    &mut mut_writer,
+                                    &mut reader,
+                                    seq,
+                                    &handshake_response,
+                                )
+                                .await
+                        }
+                        Err(e) => Err(e),
+                    }
+                }
                 DbConnPhase::Command => {
                     debug!("ProxySrv  ConnPhase == Command  {conn_uid:?}.");
                     self.authenticator
-                        .continue_auth::<R, W>(
+                        .continue_auth(
                             backend_writer,
                             backend_reader,
                             &mut mut_writer,
@@ -95,13 +493,14 @@ impl<A: Authenticator> HaentglServer<A> {
                 _ => {
                     debug!("ProxySrv ConnPhase == Connection {conn_uid:?}.");
                     self.authenticator
-                        .reply_handshake_response::<R, W>(
+                        .reply_handshake_response(
                             backend_writer,
                             backend_reader,
                             &mut mut_writer,
                             &mut reader,
                             seq,
                             (&handshake_pkt, &handshake_response),
+                            pooled_conn.compression,
                         )
                         .await
                 }
@@ -109,19 +508,23 @@ impl<A: Authenticator> HaentglServer<A> {
         } else {
             debug!("ProxySrv First authentication on current conn {conn_uid:?}.");
             self.authenticator
-                .reply_handshake_response::<R, W>(
+                .reply_handshake_response(
                     backend_writer,
                     backend_reader,
                     &mut mut_writer,
                     &mut reader,
                     seq,
                     (&handshake_pkt, &handshake_response),
+                    pooled_conn.compression,
                 )
                 .await
         };
+        drop(auth_permit);
         let db_user = handshake_response.db_user_string();
         match auth_result {
             Ok(()) => {
+                audit.auth_success();
+                quarantine_registry().record_success(&backend_addr);
                 pooled_conn
                     .update_conn_life_cycle(DbUserConnLifeCycle::new_conn_life_cycle(
                         db_user,
@@ -129,8 +532,32 @@ impl<A: Authenticator> HaentglServer<A> {
                     ))
                     .await;
                 debug!("Authentication success Set ConnPhase=Command");
+                // Authentication on a pooled connection deallocates its prepared statements, the
+                // cached ones are kept for the same user on the same schema.
+                pooled_conn.stmt_cache.lock().await.authenticated(
+                    &handshake_response.db_user_string(),
+                    handshake_response.database.as_deref(),
+                );
+                pooled_conn.session_state.reset();
+                let multi_statements = handshake_multi_statements(handshake_response.client_flag);
+                restore_multi_statements(
+                    &mut backend_client_guard,
+                    &pooled_conn.session_state,
+                    multi_statements,
+                )
+                .await
+                .inspect_err(|_| pooled_conn.invalidated.store(true, Ordering::Release))?;
             }
-            Err(_e) => {
+            Err(e) => {
+                recent_errors().record("auth", e.to_string());
+                audit.auth_failure(&e.to_string());
+                if is_backend_auth_failure(&e, handshake_response.identity.is_some()) {
+                    quarantine_registry().record_failure(
+                        &backend_addr,
+                        BackendFailure::Auth,
+                        e.to_string(),
+                    );
+                }
                 pooled_conn
                     .update_conn_life_cycle(DbUserConnLifeCycle::new_conn_life_cycle(
                         db_user,
@@ -138,23 +565,84 @@ impl<A: Authenticator> HaentglServer<A> {
                     ))
                     .await;
                 debug!("Authentication failure does not execute the command");
+                backend_reader.set_err_hook(None);
                 return Ok(());
             }
         }
 
+        let mut backend = SessionBackend::new(
+            &tenant,
+            backend_addr,
+            pooled_conn,
+            backend_client_guard,
+            checked_out_at,
+            in_flight,
+        );
         let borrow_writer = mut_writer.borrow_mut();
-        self.on_com(
-            &mut reader,
-            borrow_writer,
-            backend_writer,
-            backend_reader,
-            &handshake_response,
-        )
-        .await
+        let close_reason = self
+            .on_com(
+                &mut reader,
+                borrow_writer,
+                &mut backend,
+                &handshake_response,
+                &mut conn,
+                client_addr,
+            )
+            .await;
+        if let Err(e) = &close_reason {
+            recent_errors().record("session", e.to_string());
+        }
+        audit.set_bytes(reader.bytes_read(), mut_writer.bytes_written());
+        match &close_reason {
+            Ok(reason) => audit.set_close_reason(reason.label()),
+            Err(e) => audit.set_close_reason(&e.to_string()),
+        }
+        backend.finish(&close_reason);
+        close_reason?;
+        Ok(())
     }
 
     pub async fn initialize_async(&self) -> Result<(), Error> {
-        self.backend_mgr.prepare_backend_conn_pool().await
+        self.backend_mgr.warm_up_pools().await;
+        if self.backend_mgr.is_static_router() {
+            let prepare_rs = self.backend_mgr.prepare_backend_conn_pool().await;
+            self.publish_startup_report().await;
+            mark_router_initialized();
+            prepare_rs
+        } else {
+            // The sync router opens pools as long as the discovery reports backends, the report
+            // counts those open after the warm-up.
+            self.publish_startup_report().await;
+            mark_router_initialized();
+            self.backend_mgr.prepare_backend_conn_pool().await
+        }
+    }
+
+    async fn publish_startup_report(&self) {
+        if let Some(report) = &self.startup_report {
+            let backends = self.backend_mgr.discovered_backends().await;
+            let pools = self.backend_mgr.pool_statuses().len();
+            publish_startup_report(report.clone().with_backends(&backends, pools));
+        }
+    }
+}
+
+/// COM_QUIT has no response, but some clients log an error when the connection closes without
+/// one. The client may already be gone, so a failed reply is ignored.
+async fn reply_quit<W>(seq: u8, client_writer: &mut PacketWriter<W>)
+where
+    W: AsyncWrite + Send + Unpin,
+{
+    client_writer.set_seq(seq.wrapping_add(1));
+    let reply_rs =
+        match writers::write_ok_packet(client_writer, 0, 0, StatusFlags::SERVER_STATUS_AUTOCOMMIT)
+            .await
+        {
+            Ok(()) => client_writer.flush_all().await,
+            Err(e) => Err(e),
+        };
+    if let Err(e) = reply_rs {
+        debug!("ProxySrv reply COM_QUIT failed {e:?}");
     }
 }
 
@@ -165,6 +653,7 @@ impl<A: Authenticator> ProxyServer for HaentglServer<A> {
         r: R,
         w: &mut W,
         scramble: [u8; 20],
+        profile: &HandshakeProfile,
         #[cfg(feature = "tls")] tls_conf: &Option<Arc<ServerConfig>>,
     ) -> Result<(u8, HandshakeResponse, Packet, PacketReader<R>), Error>
     where
@@ -172,8 +661,9 @@ impl<A: Authenticator> ProxyServer for HaentglServer<A> {
         W: AsyncWrite + Send + Unpin,
     {
         let mut client_reader = PacketReader::new(r);
+        client_reader.set_max_packet_size(self.protocol_limits.max_packet_size);
         let mut client_writer = PacketWriter::new(w);
-        let conn_id = thread::current().id().as_u64().get();
+        let conn_id = u64::from(gen_conn_id());
         #[cfg(feature = "tls")]
         let (seq, handshake_response, pkt) = self
             .authenticator
@@ -182,6 +672,7 @@ impl<A: Authenticator> ProxyServer for HaentglServer<A> {
                 scramble,
                 &mut client_reader,
                 &mut client_writer,
+                profile,
                 tls_conf,
             )
             .await?;
@@ -193,6 +684,7 @@ impl<A: Authenticator> ProxyServer for HaentglServer<A> {
                 scramble,
                 &mut client_reader,
                 &mut client_writer,
+                profile,
                 &None,
             )
             .await?;
@@ -203,17 +695,171 @@ impl<A: Authenticator> ProxyServer for HaentglServer<A> {
         &self,
         client_reader: &mut PacketReader<R>,
         client_writer: &mut PacketWriter<W>,
-        backend_writer: &mut PacketWriter<OwnedWriteHalf>,
-        backend_reader: &mut PacketReader<OwnedReadHalf>,
+        backend: &mut SessionBackend,
         handshake_response: &'a HandshakeResponse,
-    ) -> Result<(), Error>
+        conn: &mut ConnToken<'_>,
+        client_addr: Option<SocketAddr>,
+    ) -> Result<SessionCloseReason, Error>
     where
         R: AsyncRead + Send + Unpin,
         W: AsyncWrite + Send + Unpin,
     {
-        backend_writer.reset_seq();
-        loop {
-            let pkt_opt = client_reader.next_async().await?;
+        if let Some((_, backend_writer, _)) = backend.conn() {
+            backend_writer.reset_seq();
+        }
+        let tenant = handshake_tenant_key(handshake_response);
+        let slow_log = slow_query_log();
+        let digests = query_digests();
+        let policy = command_policy();
+        let rate_limiter = command_rate_limiter();
+        let rate_key = RateLimitKey {
+            tenant: tenant.clone(),
+            user: handshake_response.client_user_string(),
+        };
+        let session = session_registry().register(&tenant, handshake_response.client_user_string());
+        if let Some(client_addr) = client_addr {
+            session.set_host(client_addr);
+        }
+        let capture = packet_capture().start(&tenant, session.id(), handshake_response);
+        if let Some(capture) = &capture {
+            client_reader.set_packet_hook(Some(capture.hook(Direction::Client)));
+        }
+        let in_transaction = session.transaction_flag();
+        let mut usage = SessionUsage::new(
+            tenant.clone(),
+            handshake_response.client_user_string(),
+            session.id(),
+        );
+        let bytes_in_base = client_reader.bytes_read();
+        let bytes_out_base = client_writer.bytes_written();
+        let metrics = SessionMetrics::new(&tenant, self.com_latency.clone());
+        if let Some(watermarks) = self.client_watermarks {
+            client_writer.enable_flow_control(FlowControl {
+                watermarks,
+                buffered: session.buffered_bytes(),
+                paused: metrics.flow_control_paused.clone(),
+            });
+        }
+        let limits = self.protocol_limits;
+        client_writer.set_max_unflushed_packets(limits.max_unflushed_packets);
+        client_reader.set_inflate_budget(limits.inflate_budget(&tenant, "client"));
+        backend.set_hooks(
+            capture
+                .as_ref()
+                .map(|capture| capture.hook(Direction::Backend)),
+            limits.inflate_budget(&tenant, "backend"),
+        );
+        let database = handshake_response
+            .database
+            .as_deref()
+            .map(|database| String::from_utf8_lossy(database).into_owned());
+        let mirror = ShadowMirror::start(&tenant, database);
+        let mut read_split =
+            ReadSplit::start(&self.backend_mgr, handshake_response, backend.addr());
+        let multiplexed =
+            self.backend_mgr.is_multiplexing() && handshake_response.identity.is_some();
+        let rows = Arc::new(AtomicU64::new(0));
+        let mut retry = None;
+        let mut activity = self.active_users.as_ref().map(|window| {
+            ActivityBatcher::new(
+                Arc::clone(window),
+                tenant.clone(),
+                handshake_response.client_user_string(),
+            )
+        });
+        let mut long_data = LongDataTracker::new(long_data_policy().limits(&tenant));
+        let mut notice = SessionNotice::new(tenant.clone());
+        let stmt_cache_enabled = match backend.conn() {
+            Some((_, _, backend_conn)) => backend_conn.stmt_cache.lock().await.is_enabled(),
+            None => false,
+        };
+        // Sessions that may switch backend connections keep their statements in a registry, the
+        // statement cache belongs to the connection.
+        let session_stmts =
+            (handshake_response.identity.is_some() && !stmt_cache_enabled).then(|| {
+                Arc::new(Mutex::new(SessionStmts::new(
+                    handshake_response.client_flag,
+                )))
+            });
+        let mut replay = SessionReplay::new(session_stmts.clone());
+        let mut idle_since = None;
+        let close_reason = loop {
+            client_reader.start_command();
+            // The ticks of the timers of an idle session do not end its idle time.
+            let idle_start = *idle_since.get_or_insert_with(Instant::now);
+            usage.set_bytes(
+                client_reader.bytes_read() - bytes_in_base,
+                client_writer.bytes_written() - bytes_out_base,
+            );
+            let pkt_opt = match retry.take() {
+                Some(retried) => Some(retried),
+                None => tokio::select! {
+                    pkt_opt = client_reader.next_async() => match pkt_opt {
+                        Ok(pkt_opt) => pkt_opt,
+                        Err(e) => {
+                            if let Some(too_large) = PacketTooLarge::of(&e) {
+                                let client_flag = handshake_response.client_flag;
+                                reject_packet_too_large(&tenant, too_large, client_writer, client_flag)
+                                    .await?;
+                            }
+                            return Err(e);
+                        }
+                    },
+                    _ = session.killed() => {
+                        warn!(
+                            "ProxySrv session {} killed request_id={:?}",
+                            session.id(),
+                            session.killed_by()
+                        );
+                        if let Some(notice) = session.shutdown_notice() {
+                            let client_flag = handshake_response.client_flag;
+                            let notified =
+                                write_shutdown_notice(&notice, client_writer, client_flag).await;
+                            if let Err(e) = notified {
+                                debug!("ProxySrv session {} shutdown notice failed {e:?}", session.id());
+                            }
+                        }
+                        return Err(match backend.conn() {
+                            Some((backend_reader, backend_writer, _)) => {
+                                end_killed_session(backend_writer, backend_reader).await
+                            }
+                            None => killed_session_err(),
+                        });
+                    }
+                    _ = conn.cancelled(), if !in_transaction.load(Ordering::Relaxed) => {
+                        warn!("ProxySrv session {} closed: {SHUTDOWN_NOTICE}", session.id());
+                        let client_flag = handshake_response.client_flag;
+                        let notified =
+                            write_shutdown_notice(SHUTDOWN_NOTICE, client_writer, client_flag).await;
+                        if let Err(e) = notified {
+                            debug!("ProxySrv session {} shutdown notice failed {e:?}", session.id());
+                        }
+                        break SessionCloseReason::Shutdown;
+                    }
+                    Some(()) = OptionFuture::from(activity.as_ref().map(ActivityBatcher::flush_due)) => {
+                        activity.as_mut().unwrap().flush();
+                        continue;
+                    }
+                    Some(()) = OptionFuture::from(keepalive_timer(
+                        self.backend_keepalive,
+                        in_transaction.load(Ordering::Relaxed),
+                    )), if backend.is_checked_out() => {
+                        let (backend_reader, backend_writer, _) = backend.conn().unwrap();
+                        if let Err(e) = ping_backend(&tenant, backend_writer, backend_reader).await {
+                            warn!("ProxySrv session {} backend keepalive failed {e:?}", session.id());
+                            break SessionCloseReason::KeepaliveFailed;
+                        }
+                        continue;
+                    }
+                    Some(()) = OptionFuture::from(idle_timer(self.client_idle_timeout, idle_start)) => {
+                        warn!("ProxySrv session {} closed after the idle timeout", session.id());
+                        let client_flag = handshake_response.client_flag;
+                        let backend = backend.conn().map(|(reader, writer, _)| (reader, writer));
+                        break close_idle_session(&tenant, client_writer, client_flag, backend).await;
+                    }
+                },
+            };
+            idle_since = None;
             if pkt_opt.is_none() {
                 warn!("ProxySrv Receive EMPTY PKT: Malform packet error ");
                 return Err(Error::new(
@@ -221,59 +867,427 @@ impl<A: Authenticator> ProxyServer for HaentglServer<A> {
                     "Malform packet error".to_string(),
                 ));
             }
-            let (seq, client_packet) = pkt_opt.unwrap();
+            let (seq, mut client_packet) = pkt_opt.unwrap();
             let recv_com_code = client_packet[0];
             let com_code = CommandCode::from_u8(recv_com_code).unwrap();
+            if let Some(activity) = activity.as_mut() {
+                activity.record(recv_com_code);
+            }
+            match notice.before_command(com_code, &client_packet[1..]) {
+                NoticeAction::Forward => {}
+                NoticeAction::ShowWarnings(message) => {
+                    let client_flag = handshake_response.client_flag;
+                    write_notice_warnings(&message, seq, client_writer, client_flag).await?;
+                    continue;
+                }
+                NoticeAction::Disconnect(message) => {
+                    warn!("ProxySrv session {} closed: {message}", session.id());
+                    let client_flag = handshake_response.client_flag;
+                    write_maintenance_err(&message, seq, client_writer, client_flag).await?;
+                    break SessionCloseReason::Maintenance;
+                }
+            }
+            let drained = (com_code != CommandCode::ComQuit)
+                .then(|| {
+                    drain_registry().closes_session(&tenant, in_transaction.load(Ordering::Relaxed))
+                })
+                .flatten();
+            if let Some(message) = drained {
+                warn!("ProxySrv session {} closed: {message}", session.id());
+                let client_flag = handshake_response.client_flag;
+                write_drain_err(&message, seq, client_writer, client_flag).await?;
+                break SessionCloseReason::Drained;
+            }
+            if let Some(fault) = fault_injector().tenant_fault(&tenant) {
+                if apply_com_fault(fault, seq, client_writer, handshake_response.client_flag)
+                    .await?
+                    == FaultAction::Skip
+                {
+                    continue;
+                }
+            }
+            match ReplicationAttempt::detect(com_code, &client_packet[1..]) {
+                Some(attempt) if !policy.replication_allowed(&tenant) => {
+                    policy.replication_rejected(&tenant, attempt);
+                    let client_flag = handshake_response.client_flag;
+                    reject_replication(attempt, seq, client_writer, client_flag).await?;
+                    continue;
+                }
+                Some(_) => {}
+                None if !policy.is_allowed(&tenant, com_code) => {
+                    reject_command(com_code, seq, client_writer, handshake_response.client_flag)
+                        .await?;
+                    continue;
+                }
+                None => {}
+            }
+            // COM_QUIT is never held back, the client is releasing its connection.
+            if com_code != CommandCode::ComQuit && !rate_limiter.admit(&rate_key).await {
+                let client_flag = handshake_response.client_flag;
+                reject_rate_limited(&rate_key, seq, client_writer, client_flag).await?;
+                continue;
+            }
+            let client_flag = handshake_response.client_flag;
+            if reject_change_user(&tenant, com_code, seq, client_writer, client_flag).await? {
+                continue;
+            }
+            if let (Some(route), CommandCode::ComQuery | CommandCode::ComStmtPrepare) =
+                (&handshake_response.shard, com_code)
+            {
+                let client_flag = handshake_response.client_flag;
+                let sql = &client_packet[1..];
+                if reject_shard_mismatch(&tenant, route, sql, seq, client_writer, client_flag)
+                    .await?
+                {
+                    continue;
+                }
+            }
+            let client_flag = handshake_response.client_flag;
+            let admin_payload = &client_packet[1..];
+            let backend_mgr = &self.backend_mgr;
+            if answer_admin_command(
+                com_code,
+                admin_payload,
+                backend_mgr,
+                &tenant,
+                seq,
+                client_writer,
+                client_flag,
+            )
+            .await?
+            {
+                continue;
+            }
+            if com_code == CommandCode::ComQuit && !backend.is_checked_out() {
+                // The multiplexed session holds no connection, none needs a reset.
+                if self.quit_reply_ok {
+                    reply_quit(seq, client_writer).await;
+                }
+                break SessionCloseReason::Quit;
+            }
+            let backend_mgr = &self.backend_mgr;
+            if !backend
+                .ensure_checked_out(backend_mgr, handshake_response, &replay, seq, client_writer)
+                .await?
+            {
+                continue;
+            }
+            // Backend faults also hit the connections opened before the rule was injected.
+            let backend_fault = backend
+                .addr()
+                .and_then(|addr| fault_injector().backend_fault(addr));
+            if let Some(fault) = backend_fault {
+                if apply_com_fault(fault, seq, client_writer, handshake_response.client_flag)
+                    .await?
+                    == FaultAction::Skip
+                {
+                    continue;
+                }
+            }
+            let (backend_reader, backend_writer, backend_conn) = backend.conn().unwrap();
+            backend_reader.start_command();
+            let stmt_cache = stmt_cache_enabled.then(|| Arc::clone(&backend_conn.stmt_cache));
+            let slow_com = slow_log.is_enabled()
+                && matches!(
+                    com_code,
+                    CommandCode::ComQuery | CommandCode::ComStmtExecute
+                );
+            let mut slow_sql = (slow_com && com_code == CommandCode::ComQuery)
+                .then(|| truncate_sql(&client_packet[1..]));
+            // Executed statements take the fingerprint of their text below, if it is known.
+            let fingerprinted = match com_code {
+                CommandCode::ComQuery => slow_com || digests.is_enabled(),
+                CommandCode::ComStmtPrepare => digests.is_enabled(),
+                _ => false,
+            };
+            let mut sql_shape = fingerprinted.then(|| fingerprint(&client_packet[1..]));
+            let digest = sql_shape
+                .filter(|_| digests.is_enabled())
+                .map(|shape| digests.digest(&tenant, shape, &client_packet[1..]));
+            let mirror_sql = mirror
+                .as_ref()
+                .and_then(|mirror| mirror.sample(com_code, &client_packet[1..]));
+            let mut cached_execute = None;
+            // The long data limits apply per client statement, several of them may share one
+            // backend statement.
+            let client_stmt_id =
+                (client_packet.len() >= 5).then(|| LittleEndian::read_u32(&client_packet[1..5]));
+            if let Some(stmt_cache) = &stmt_cache {
+                let (executed, sql) = apply_stmt_cache(
+                    stmt_cache,
+                    com_code,
+                    &mut client_packet,
+                    backend_writer,
+                    backend_reader,
+                    handshake_response.client_flag,
+                    slow_com,
+                )
+                .await?;
+                if let Some(sql) = sql {
+                    sql_shape = Some(fingerprint(&sql));
+                    slow_sql = Some(truncate_sql(&sql));
+                }
+                cached_execute = executed.map(|client_id| (Arc::clone(stmt_cache), client_id));
+            } else if let Some(session_stmts) = &session_stmts {
+                let mut session_stmts = session_stmts.lock().await;
+                if let Some(sql) = session_stmts.apply(com_code, &mut client_packet, slow_com) {
+                    sql_shape = Some(fingerprint(&sql));
+                    slow_sql = Some(truncate_sql(&sql));
+                }
+            }
+            // The forwarder, the statement cache and the failover share the request from here on.
+            let client_packet = client_packet.freeze();
+            let cached_execute = cached_execute.map(|(stmt_cache, client_id)| CachedExecute {
+                stmt_cache,
+                client_id,
+                request: client_packet.clone(),
+            });
+            if let CommandCode::ComStmtSendLongData
+            | CommandCode::ComStmtExecute
+            | CommandCode::ComStmtReset
+            | CommandCode::ComStmtClose
+            | CommandCode::ComQuit
+            | CommandCode::ComChangeUser
+            | CommandCode::ComResetConnection = com_code
+            {
+                let handled = apply_long_data_limits(
+                    &mut long_data,
+                    &metrics,
+                    com_code,
+                    client_stmt_id,
+                    seq,
+                    &client_packet,
+                    client_writer,
+                    handshake_response.client_flag,
+                    backend_writer,
+                    backend_reader,
+                )
+                .await?;
+                if handled {
+                    continue;
+                }
+            }
+            let mut replica_read = match read_split.as_mut() {
+                Some(read_split) => {
+                    let payload = &client_packet[1..];
+                    let in_transaction = in_transaction.load(Ordering::Relaxed);
+                    let backend_mgr = &self.backend_mgr;
+                    read_split
+                        .route(
+                            backend_mgr,
+                            handshake_response,
+                            com_code,
+                            payload,
+                            in_transaction,
+                        )
+                        .await
+                }
+                None => None,
+            };
+            replay.observe(com_code, &client_packet[1..]);
+            if let CommandCode::ComRegisterSlave
+            | CommandCode::ComBinlogDump
+            | CommandCode::ComBinlogDumpGtid = com_code
+            {
+                // A connection in replication mode cannot serve another session.
+                backend_conn.invalidated.store(true, Ordering::Release);
+            }
+            let fwd_session_state = match replica_read.as_ref() {
+                Some(replica_read) => Arc::clone(replica_read.session_state()),
+                None => Arc::clone(&backend_conn.session_state),
+            };
+            let command_watch = watch_command(&fwd_session_state, com_code, &client_packet[1..]);
+            let (fwd_reader, fwd_writer) = match replica_read.as_mut() {
+                Some(replica_read) => {
+                    let (reader, writer) = replica_read.conn();
+                    (reader, writer)
+                }
+                None => (&mut *backend_reader, &mut *backend_writer),
+            };
             // info!("ProxySrv on_com receive ComCode={:?} from client", com_code);
             let com_forwarder: Box<dyn ComForwarder<R, W>> = match com_code {
                 CommandCode::ComStmtPrepare | CommandCode::ComStmtClose => {
                     Box::new(StmtPrepareForwarder {
                         com_code,
                         request: client_packet.clone(),
+                        stmt_cache: stmt_cache.clone(),
+                        session_stmts: session_stmts.clone(),
                     })
                 }
                 CommandCode::ComQuery
                 | CommandCode::ComStmtExecute
                 | CommandCode::ComProcessInfo
                 | CommandCode::ComFieldList
-                | CommandCode::ComStmtFetch => Box::new(QueryForwarder { com_code }),
+                | CommandCode::ComStmtFetch => Box::new(QueryForwarder {
+                    com_code,
+                    cached_execute,
+                    notice_warning: notice.warning_flag(),
+                    in_transaction: Arc::clone(&in_transaction),
+                    session_state: Arc::clone(&fwd_session_state),
+                    rows: Arc::clone(&rows),
+                }),
+                CommandCode::ComStmtSendLongData => Box::new(StmtLongDataForwarder),
+                CommandCode::ComStmtReset => Box::new(StmtResetForwarder {
+                    session_state: Arc::clone(&fwd_session_state),
+                }),
+                CommandCode::ComSetOption => Box::new(SetOptionForwarder {
+                    multi_statements: multi_statements_option(&client_packet[1..]),
+                    session_state: Arc::clone(&fwd_session_state),
+                }),
                 CommandCode::ComQuit => Box::new(ResetConnForwarder),
                 CommandCode::ComChangeUser => Box::new(change_user_forward::ChangeUserForwarder),
+                CommandCode::ComBinlogDump | CommandCode::ComBinlogDumpGtid => {
+                    Box::new(BinlogDumpForwarder)
+                }
                 _ => Box::new(GenericComForwarder),
             };
-            com_forwarder
-                .write_to_backend(
-                    seq,
-                    com_code,
-                    handshake_response,
-                    client_packet,
-                    backend_writer,
-                )
-                .await?;
+            // Only the commands a failover may send again are kept.
+            let retry_packet = (handshake_response.identity.is_some()
+                && is_retryable(com_code, &client_packet[1..], false))
+            .then(|| (seq, client_packet.clone()));
+            let relayed_base = client_writer.bytes_written();
+            let (sent_base, received_base) = (fwd_writer.bytes_written(), fwd_reader.bytes_read());
+            let started = clock().precise_now();
+            let written = com_forwarder
+                .write_to_backend(seq, com_code, handshake_response, client_packet, fwd_writer)
+                .await;
+            let received = written.is_ok();
 
-            let labels = self.sql_com_labels.get(&recv_com_code).unwrap();
-            let _com_latency =
-                common::metrics::MetricsTimer::new_with_labels(PROXY_COM_LATENCY, labels);
-            let _pkt = com_forwarder
-                .forward(
-                    client_reader,
-                    client_writer,
-                    backend_writer,
-                    backend_reader,
-                    handshake_response,
-                )
-                .await?;
-            if com_code == CommandCode::ComQuit {
-                common::metrics::gauge_dec(
-                    common::metrics::metric_def::PROXY_CURR_CONN,
-                    1_f64,
-                    Some(common_labels()),
+            let _com_latency = metrics.com_timer(recv_com_code);
+            let forwarded = match written {
+                Ok(()) => {
+                    com_forwarder
+                        .forward(
+                            client_reader,
+                            client_writer,
+                            fwd_writer,
+                            fwd_reader,
+                            handshake_response,
+                        )
+                        .await
+                }
+                Err(e) => Err(e),
+            };
+            let traffic = ComTraffic {
+                sent: fwd_writer.bytes_written() - sent_base,
+                received: fwd_reader.bytes_read() - received_base,
+                rows: rows.swap(0, Ordering::Relaxed),
+            };
+            let pkt = match forwarded {
+                Ok(pkt) => pkt,
+                Err(e)
+                    if replica_read.is_none()
+                        && com_code != CommandCode::ComQuit
+                        && is_broken_conn(&e) =>
+                {
+                    warn!(
+                        "ProxySrv session {} lost its backend connection {e:?}",
+                        session.id()
+                    );
+                    if client_writer.bytes_written() != relayed_base {
+                        // The client already reads the response, it cannot be answered anew.
+                        backend.record_failover("failed");
+                        return Err(e);
+                    }
+                    let retry_packet = retry_packet
+                        .filter(|(_, packet)| is_retryable(com_code, &packet[1..], received))
+                        .map(|(seq, packet)| (seq, Packet::from(packet)));
+                    let backend_mgr = &self.backend_mgr;
+                    let lost = fwd_session_state.state();
+                    retry = backend
+                        .recover(
+                            e,
+                            backend_mgr,
+                            handshake_response,
+                            &replay,
+                            lost,
+                            retry_packet,
+                            seq,
+                            client_writer,
+                        )
+                        .await?;
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            command_watch.finish(&tenant);
+            if let Some(replica_read) = replica_read {
+                replica_read.finish();
+            }
+            notice.after_command();
+            if let CommandCode::ComChangeUser | CommandCode::ComResetConnection = com_code {
+                in_transaction.store(false, Ordering::Relaxed);
+            }
+            let elapsed = clock().precise_elapsed(started);
+            usage.record_command(com_code, elapsed);
+            metrics.record_com(recv_com_code, traffic);
+            if let Some(digest) = digest {
+                digest.record(elapsed);
+            }
+            if let (Some(mirror), Some(sql)) = (&mirror, mirror_sql) {
+                mirror.mirror(sql, elapsed);
+            }
+            if slow_com && slow_log.is_slow(elapsed) {
+                slow_log.record(
+                    &tenant,
+                    handshake_response.client_user_string(),
+                    elapsed,
+                    client_writer.bytes_written() - relayed_base,
+                    slow_sql.as_deref(),
+                    sql_shape,
                 );
-                break;
             }
-        }
-        Ok(())
+            let stmt_cache_bytes = match &stmt_cache {
+                Some(stmt_cache) => stmt_cache.lock().await.memory_bytes(),
+                None => 0,
+            };
+            session.update_memory(SessionMemory {
+                client_buffer_bytes: client_reader.buffer_capacity()
+                    + client_writer.buffer_capacity(),
+                backend_buffer_bytes: backend_reader.buffer_capacity()
+                    + backend_writer.buffer_capacity(),
+                stmt_cache_bytes,
+            });
+            if com_code == CommandCode::ComQuit {
+                if self.quit_reply_ok {
+                    reply_quit(seq, client_writer).await;
+                }
+                break SessionCloseReason::on_quit(pkt.as_ref());
+            }
+            if multiplexed
+                && !in_transaction.load(Ordering::Relaxed)
+                && replay.is_replayable(backend_conn.session_state.state())
+            {
+                backend.release();
+            }
+        };
+        usage.set_bytes(
+            client_reader.bytes_read() - bytes_in_base,
+            client_writer.bytes_written() - bytes_out_base,
+        );
+        debug!(
+            "ProxySrv session {} closed {:?}",
+            session.id(),
+            close_reason
+        );
+        metrics.session_closed(close_reason);
+        Ok(close_reason)
     }
 
-    async fn close(&self) {}
+    /// Idle sessions outside a transaction are closed with ER_SERVER_SHUTDOWN, the others at the
+    /// end of their command or transaction, for up to the drain timeout. Then the backend pools
+    /// are closed.
+    async fn close(&self) {
+        let drain_timeout = self.drain_timeout;
+        info!(
+            "ProxySrv draining {} sessions for up to {drain_timeout:?}",
+            self.conns.active()
+        );
+        let remaining = self.conns.drain(drain_timeout).await;
+        if remaining > 0 {
+            warn!("ProxySrv {remaining} sessions still busy after the drain timeout");
+        }
+        let closed = self.backend_mgr.close_pools().await;
+        info!("ProxySrv shutdown closed {closed} backend connections");
+    }
 }
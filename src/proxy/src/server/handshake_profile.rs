@@ -0,0 +1,112 @@
+use crate::backend::capability::capability_cache;
+use crate::protocol::mysql::charset::DEFAULT_COLLATION_ID;
+
+use mysql_common::constants::CapabilityFlags;
+use std::io::{Error, ErrorKind};
+use std::str::FromStr;
+
+/// The listener of the clients, see [`HandshakeProfile::listener`].
+pub const PROXY_LISTENER: &str = "proxy";
+/// The listener of the clients of the tunnel.
+pub const TUNNEL_LISTENER: &str = "tunnel";
+
+/// `HandshakeProfile` is what a listener advertises in the initial handshake. Listeners of the
+/// same proxy may differ, e.g. CLIENT_LOCAL_FILES disabled for external clients but kept for the
+/// internal ones.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HandshakeProfile {
+    /// Names the listener in metrics and logs.
+    pub listener: &'static str,
+    /// The advertised server version, that of the known backends if not set.
+    pub server_version: Option<Vec<u8>>,
+    pub collation: u8,
+    /// Capabilities never offered to clients, nor requested from the backends for them.
+    pub disabled_capabilities: CapabilityFlags,
+}
+
+impl Default for HandshakeProfile {
+    fn default() -> Self {
+        Self {
+            listener: PROXY_LISTENER,
+            server_version: None,
+            collation: DEFAULT_COLLATION_ID,
+            disabled_capabilities: CapabilityFlags::empty(),
+        }
+    }
+}
+
+impl HandshakeProfile {
+    pub fn server_version(&self) -> Vec<u8> {
+        match &self.server_version {
+            Some(server_version) => server_version.clone(),
+            None => capability_cache().server_version(),
+        }
+    }
+
+    /// The capabilities offered to clients, those every known backend supports.
+    pub fn capabilities(&self) -> CapabilityFlags {
+        capability_cache().client_capabilities() - self.disabled_capabilities
+    }
+}
+
+/// Parses `version=8.0.36,collation=45,disable=CLIENT_LOCAL_FILES|CLIENT_COMPRESS`, every key
+/// is optional.
+impl FromStr for HandshakeProfile {
+    type Err = Error;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let invalid = |msg: String| Error::new(ErrorKind::InvalidInput, msg);
+        let mut profile = HandshakeProfile::default();
+        for option in spec.split(',').filter(|option| !option.trim().is_empty()) {
+            let (key, value) = option.split_once('=').ok_or_else(|| {
+                invalid(format!(
+                    "handshake profile option {option} is not key=value"
+                ))
+            })?;
+            match key.trim() {
+                "version" => profile.server_version = Some(value.trim().as_bytes().to_vec()),
+                "collation" => {
+                    profile.collation = value
+                        .trim()
+                        .parse()
+                        .map_err(|e| invalid(format!("collation {value}: {e}")))?
+                }
+                "disable" => {
+                    for name in value.split('|').map(str::trim) {
+                        profile.disabled_capabilities |= CapabilityFlags::from_name(name)
+                            .ok_or_else(|| invalid(format!("unknown capability {name}")))?;
+                    }
+                }
+                _ => return Err(invalid(format!("unknown handshake profile option {key}"))),
+            }
+        }
+        Ok(profile)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::server::handshake_profile::HandshakeProfile;
+    use mysql_common::constants::CapabilityFlags;
+
+    #[test]
+    pub fn test_handshake_profile() {
+        let profile: HandshakeProfile =
+            "version=8.0.36-proxy, collation=45, disable=CLIENT_LOCAL_FILES|CLIENT_COMPRESS"
+                .parse()
+                .unwrap();
+        assert_eq!(profile.server_version(), b"8.0.36-proxy");
+        assert_eq!(profile.collation, 45);
+        assert!(!profile
+            .capabilities()
+            .intersects(CapabilityFlags::CLIENT_LOCAL_FILES | CapabilityFlags::CLIENT_COMPRESS));
+        assert_eq!(
+            "".parse::<HandshakeProfile>().unwrap(),
+            HandshakeProfile::default()
+        );
+        assert!("disable=CLIENT_TELEPATHY"
+            .parse::<HandshakeProfile>()
+            .is_err());
+        assert!("collation".parse::<HandshakeProfile>().is_err());
+    }
+}
@@ -0,0 +1,190 @@
+use crate::async_packet_read;
+use crate::prost::common_proto::TenantKey;
+use crate::protocol::mysql::error_codes::ErrorKind;
+use crate::protocol::mysql::packet::packet_reader::PacketReader;
+use crate::protocol::mysql::packet::packet_writer::PacketWriter;
+use crate::protocol::mysql::packet::writers;
+use crate::server::session::SessionCloseReason;
+use crate::server::slow_log::tenant_label;
+
+use common::metrics::metric_def::PROXY_IDLE_SESSIONS_CLOSED;
+use common::metrics::{common_labels, counter_inc};
+use mysql_common::constants::CapabilityFlags;
+use std::io::Error;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::time::{Instant, Sleep};
+use tracing::debug;
+
+/// Sent to the clients closed for their inactivity.
+pub const IDLE_TIMEOUT_NOTICE: &str =
+    "The client was disconnected by the proxy because of inactivity, see wait_timeout";
+
+/// A backend that does not answer the reset in time keeps the connection out of the pool.
+const IDLE_RESET_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Fires once the client sent no command for `timeout` since `last_command`, the way the
+/// `wait_timeout` of the server does. `None` if the idle timeout is disabled.
+pub fn idle_timer(timeout: Option<Duration>, last_command: Instant) -> Option<Sleep> {
+    timeout.map(|timeout| tokio::time::sleep_until(last_command + timeout))
+}
+
+/// Tells an idle client its session is closed for inactivity. The client sent no command, so the
+/// notice is an unsolicited packet of sequence id 0, like the shutdown notice.
+pub async fn write_idle_notice<W>(
+    client_writer: &mut PacketWriter<W>,
+    client_capabilities: CapabilityFlags,
+) -> Result<(), Error>
+where
+    W: AsyncWrite + Send + Unpin,
+{
+    client_writer.reset_seq();
+    writers::write_err_packet(
+        ErrorKind::ER_NET_READ_INTERRUPTED,
+        IDLE_TIMEOUT_NOTICE.as_bytes(),
+        client_writer,
+        client_capabilities,
+    )
+    .await?;
+    client_writer.flush_all().await
+}
+
+/// Resets the backend connection of a session closed for inactivity, so it goes back to the pool
+/// without the session state of the client, an open transaction is rolled back. Returns whether
+/// the connection is clean.
+pub async fn reset_idle_backend<R, W>(
+    tenant: &TenantKey,
+    backend_writer: &mut PacketWriter<W>,
+    backend_reader: &mut PacketReader<R>,
+) -> bool
+where
+    R: AsyncRead + Send + Unpin,
+    W: AsyncWrite + Send + Unpin,
+{
+    let reset = tokio::time::timeout(IDLE_RESET_TIMEOUT, async {
+        backend_writer.reset_seq();
+        writers::write_reset_connection(backend_writer).await?;
+        let (_be_seq, be_rsp_pkt) = async_packet_read!(backend_reader);
+        Ok::<_, Error>(be_rsp_pkt.is_ok_packet())
+    })
+    .await;
+    let reset = matches!(reset, Ok(Ok(true)));
+    record_idle_closed(tenant, if reset { "reset" } else { "reset_failed" });
+    reset
+}
+
+/// Closes a session of `tenant` idle for the timeout. The client is told why and the backend
+/// connection of the session, `None` for a multiplexed session between transactions, is reset
+/// for the pool.
+pub async fn close_idle_session<CW, R, W>(
+    tenant: &TenantKey,
+    client_writer: &mut PacketWriter<CW>,
+    client_capabilities: CapabilityFlags,
+    backend: Option<(&mut PacketReader<R>, &mut PacketWriter<W>)>,
+) -> SessionCloseReason
+where
+    CW: AsyncWrite + Send + Unpin,
+    R: AsyncRead + Send + Unpin,
+    W: AsyncWrite + Send + Unpin,
+{
+    if let Err(e) = write_idle_notice(client_writer, client_capabilities).await {
+        debug!("ProxySrv idle notice to a session of {tenant:?} failed {e:?}");
+    }
+    let reset = match backend {
+        Some((backend_reader, backend_writer)) => {
+            reset_idle_backend(tenant, backend_writer, backend_reader).await
+        }
+        None => {
+            // A multiplexed session holds no connection between transactions.
+            record_idle_closed(tenant, "released");
+            true
+        }
+    };
+    if reset {
+        SessionCloseReason::IdleTimeout
+    } else {
+        SessionCloseReason::IdleTimeoutResetFailed
+    }
+}
+
+/// Counts a session closed for inactivity, `result` tells what became of its backend connection.
+pub fn record_idle_closed(tenant: &TenantKey, result: &str) {
+    let mut labels = common_labels().clone();
+    labels.push(("tenant", tenant_label(tenant)));
+    labels.push(("result", result.to_string()));
+    counter_inc(PROXY_IDLE_SESSIONS_CLOSED, 1, Some(&labels));
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::test_tenant_key;
+    use crate::protocol::mysql::packet::packet_reader::PacketReader;
+    use crate::protocol::mysql::packet::packet_writer::PacketWriter;
+    use crate::server::idle_timeout::{
+        close_idle_session, idle_timer, reset_idle_backend, write_idle_notice, IDLE_TIMEOUT_NOTICE,
+    };
+    use crate::server::session::SessionCloseReason;
+    use mysql_common::constants::CapabilityFlags;
+    use std::time::Duration;
+    use tokio::time::Instant;
+
+    #[tokio::test]
+    pub async fn test_idle_timeout() {
+        assert!(idle_timer(None, Instant::now()).is_none());
+        let timer = idle_timer(Some(Duration::from_secs(60)), Instant::now()).unwrap();
+        assert!(timer.deadline() > Instant::now() + Duration::from_secs(59));
+
+        let mut notice = PacketWriter::new(vec![]);
+        write_idle_notice(&mut notice, CapabilityFlags::CLIENT_PROTOCOL_41)
+            .await
+            .unwrap();
+        let mut reader = PacketReader::new(&notice.inner_writer[..]);
+        let (seq, packet) = reader.next_async().await.unwrap().unwrap();
+        assert_eq!(seq, 0);
+        assert!(packet.is_err_packet());
+        assert!(packet.ends_with(IDLE_TIMEOUT_NOTICE.as_bytes()));
+
+        let tenant = test_tenant_key();
+        let ok = [7, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0];
+        let mut writer = PacketWriter::new(vec![]);
+        let mut reader = PacketReader::new(&ok[..]);
+        assert!(reset_idle_backend(&tenant, &mut writer, &mut reader).await);
+        assert_eq!(&writer.inner_writer[..5], &[5, 0, 0, 0, 31]);
+
+        let err = [&[9, 0, 0, 1, 0xff, 0x15, 0x04][..], b"denied"].concat();
+        let mut reader = PacketReader::new(&err[..]);
+        assert!(!reset_idle_backend(&tenant, &mut writer, &mut reader).await);
+        let mut reader = PacketReader::new(&[][..]);
+        assert!(!reset_idle_backend(&tenant, &mut writer, &mut reader).await);
+    }
+    #[tokio::test]
+    pub async fn test_close_idle_session() {
+        let tenant = test_tenant_key();
+        let capabilities = CapabilityFlags::CLIENT_PROTOCOL_41;
+        let mut client = PacketWriter::new(vec![]);
+        let released = close_idle_session(
+            &tenant,
+            &mut client,
+            capabilities,
+            None::<(&mut PacketReader<&[u8]>, &mut PacketWriter<Vec<u8>>)>,
+        )
+        .await;
+        assert_eq!(released, SessionCloseReason::IdleTimeout);
+        let mut reader = PacketReader::new(&client.inner_writer[..]);
+        let (_, packet) = reader.next_async().await.unwrap().unwrap();
+        assert!(packet.ends_with(IDLE_TIMEOUT_NOTICE.as_bytes()));
+
+        let ok = [7, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0];
+        let mut writer = PacketWriter::new(vec![]);
+        let mut reader = PacketReader::new(&ok[..]);
+        let mut client = PacketWriter::new(vec![]);
+        let backend = Some((&mut reader, &mut writer));
+        let reset = close_idle_session(&tenant, &mut client, capabilities, backend).await;
+        assert_eq!(reset, SessionCloseReason::IdleTimeout);
+
+        let mut reader = PacketReader::new(&[][..]);
+        let backend = Some((&mut reader, &mut writer));
+        let failed = close_idle_session(&tenant, &mut client, capabilities, backend).await;
+        assert_eq!(failed, SessionCloseReason::IdleTimeoutResetFailed);
+    }
+}
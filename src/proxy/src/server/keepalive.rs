@@ -0,0 +1,115 @@
+use crate::async_packet_read;
+use crate::prost::common_proto::TenantKey;
+use crate::protocol::mysql::packet::packet_reader::PacketReader;
+use crate::protocol::mysql::packet::packet_writer::PacketWriter;
+use crate::protocol::mysql::packet::writers;
+use crate::server::slow_log::tenant_label;
+
+use common::metrics::metric_def::PROXY_BACKEND_KEEPALIVE;
+use common::metrics::{common_labels, counter_inc};
+use std::io::{Error, ErrorKind};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::time::Sleep;
+
+/// A backend that does not answer a keepalive ping in time is considered gone, NATs drop
+/// connections silently.
+const KEEPALIVE_PING_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Fires once a session stayed idle `interval` long. `None` if keepalive pings are disabled or
+/// the session is in a transaction, whose connection is left alone until the client ends it.
+pub fn keepalive_timer(interval: Option<Duration>, in_transaction: bool) -> Option<Sleep> {
+    interval.filter(|_| !in_transaction).map(tokio::time::sleep)
+}
+
+/// Sends COM_PING on the backend connection of an idle session, so intermediate NATs and the
+/// `wait_timeout` of the backend do not drop it under a client coming back hours later. An
+/// error means the connection is gone and the session has to end before its next query fails.
+pub async fn ping_backend<R, W>(
+    tenant: &TenantKey,
+    backend_writer: &mut PacketWriter<W>,
+    backend_reader: &mut PacketReader<R>,
+) -> Result<(), Error>
+where
+    R: AsyncRead + Send + Unpin,
+    W: AsyncWrite + Send + Unpin,
+{
+    let ping_rs = ping_conn(backend_writer, backend_reader).await;
+    let mut labels = common_labels().clone();
+    labels.push(("tenant", tenant_label(tenant)));
+    labels.push((
+        "result",
+        if ping_rs.is_ok() { "ok" } else { "failed" }.to_string(),
+    ));
+    counter_inc(PROXY_BACKEND_KEEPALIVE, 1, Some(&labels));
+    ping_rs
+}
+
+/// Sends COM_PING on an authenticated backend connection and waits for its OK.
+pub async fn ping_conn<R, W>(
+    backend_writer: &mut PacketWriter<W>,
+    backend_reader: &mut PacketReader<R>,
+) -> Result<(), Error>
+where
+    R: AsyncRead + Send + Unpin,
+    W: AsyncWrite + Send + Unpin,
+{
+    tokio::time::timeout(KEEPALIVE_PING_TIMEOUT, async {
+        backend_writer.reset_seq();
+        writers::write_ping(backend_writer).await?;
+        let (_be_seq, be_rsp_pkt) = async_packet_read!(backend_reader);
+        if be_rsp_pkt.is_ok_packet() {
+            Ok(())
+        } else {
+            Err(Error::new(
+                ErrorKind::ConnectionAborted,
+                "backend refused the keepalive ping",
+            ))
+        }
+    })
+    .await
+    .unwrap_or_else(|_| {
+        Err(Error::new(
+            ErrorKind::TimedOut,
+            "backend did not answer the keepalive ping",
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::test_tenant_key;
+    use crate::protocol::mysql::packet::packet_reader::PacketReader;
+    use crate::protocol::mysql::packet::packet_writer::PacketWriter;
+    use crate::server::keepalive::{keepalive_timer, ping_backend};
+    use std::time::Duration;
+
+    #[tokio::test]
+    pub async fn test_ping_backend() {
+        let interval = Some(Duration::from_secs(60));
+        assert!(keepalive_timer(interval, false).is_some());
+        assert!(keepalive_timer(interval, true).is_none());
+        assert!(keepalive_timer(None, false).is_none());
+
+        let tenant = test_tenant_key();
+        let ok = [7, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0];
+        let mut sent = vec![];
+        let mut writer = PacketWriter::new(&mut sent);
+        let mut reader = PacketReader::new(&ok[..]);
+        ping_backend(&tenant, &mut writer, &mut reader)
+            .await
+            .unwrap();
+        assert_eq!(sent, vec![1, 0, 0, 0, 14]);
+
+        let err = [&[9, 0, 0, 1, 0xff, 0x15, 0x04][..], b"denied"].concat();
+        let mut writer = PacketWriter::new(vec![]);
+        let mut reader = PacketReader::new(&err[..]);
+        assert!(ping_backend(&tenant, &mut writer, &mut reader)
+            .await
+            .is_err());
+        let mut reader = PacketReader::new(&[][..]);
+        assert!(ping_backend(&tenant, &mut writer, &mut reader)
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,282 @@
+use crate::async_packet_read;
+use crate::backend::stream::{BackendReadHalf, BackendWriteHalf};
+use crate::prost::common_proto::TenantKey;
+use crate::protocol::mysql::constants::CommandCode;
+use crate::protocol::mysql::error_codes::ErrorKind;
+use crate::protocol::mysql::packet::packet_reader::PacketReader;
+use crate::protocol::mysql::packet::packet_writer::PacketWriter;
+use crate::protocol::mysql::packet::writers;
+use crate::server::session_metrics::SessionMetrics;
+
+use byteorder::{ByteOrder, LittleEndian};
+use dashmap::DashMap;
+use hashbrown::{HashMap, HashSet};
+use mysql_common::constants::CapabilityFlags;
+use serde::{Deserialize, Serialize};
+use std::io::Error;
+use std::sync::{OnceLock, RwLock};
+use tokio::io::AsyncWrite;
+use tracing::{info, warn};
+
+/// COM_STMT_SEND_LONG_DATA header: command, statement id and parameter index.
+const LONG_DATA_HEADER_LEN: usize = 7;
+
+/// Bytes of long data a backend may buffer for a client, 0 means unlimited.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LongDataLimits {
+    /// Long data buffered for one statement until it is executed or reset.
+    #[serde(default)]
+    pub max_stmt_bytes: u64,
+    /// Long data buffered for all statements of a session.
+    #[serde(default)]
+    pub max_session_bytes: u64,
+}
+
+impl LongDataLimits {
+    fn exceeded_by(&self, stmt_bytes: u64, session_bytes: u64) -> bool {
+        let exceeds = |limit: u64, bytes: u64| limit > 0 && bytes > limit;
+        exceeds(self.max_stmt_bytes, stmt_bytes) || exceeds(self.max_session_bytes, session_bytes)
+    }
+}
+
+/// Overrides the default long data limits for one tenant.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TenantLongDataLimits {
+    pub tenant: TenantKey,
+    pub limits: LongDataLimits,
+}
+
+/// `LongDataPolicy` keeps the long data limits, the defaults and those of the tenants managed
+/// by the control plane. Sessions pick their limits when they enter the command phase.
+pub struct LongDataPolicy {
+    default_limits: RwLock<LongDataLimits>,
+    tenants: DashMap<TenantKey, LongDataLimits>,
+}
+
+static LONG_DATA_POLICY_ONCE: OnceLock<LongDataPolicy> = OnceLock::new();
+
+/// Initializes the global long data policy, must be called before the first command is served.
+pub fn init_long_data_policy(default_limits: LongDataLimits) -> &'static LongDataPolicy {
+    LONG_DATA_POLICY_ONCE.get_or_init(|| LongDataPolicy::new(default_limits))
+}
+
+pub fn long_data_policy() -> &'static LongDataPolicy {
+    LONG_DATA_POLICY_ONCE.get_or_init(|| LongDataPolicy::new(LongDataLimits::default()))
+}
+
+impl LongDataPolicy {
+    pub fn new(default_limits: LongDataLimits) -> Self {
+        Self {
+            default_limits: RwLock::new(default_limits),
+            tenants: DashMap::new(),
+        }
+    }
+
+    pub fn default_limits(&self) -> LongDataLimits {
+        *self.default_limits.read().unwrap()
+    }
+
+    pub fn set_default_limits(&self, limits: LongDataLimits) {
+        info!("ProxySrv long data default limits {:?}", limits);
+        *self.default_limits.write().unwrap() = limits;
+    }
+
+    pub fn set_tenant_limits(&self, tenant_limits: TenantLongDataLimits) {
+        info!("ProxySrv long data limits set {:?}", tenant_limits);
+        self.tenants
+            .insert(tenant_limits.tenant, tenant_limits.limits);
+    }
+
+    pub fn remove_tenant_limits(&self, tenant: &TenantKey) -> Option<LongDataLimits> {
+        info!("ProxySrv long data limits removed {:?}", tenant);
+        self.tenants.remove(tenant).map(|(_, limits)| limits)
+    }
+
+    pub fn list(&self) -> Vec<TenantLongDataLimits> {
+        self.tenants
+            .iter()
+            .map(|e| TenantLongDataLimits {
+                tenant: e.key().clone(),
+                limits: *e.value(),
+            })
+            .collect()
+    }
+
+    pub fn limits(&self, tenant: &TenantKey) -> LongDataLimits {
+        self.tenants
+            .get(tenant)
+            .map(|e| *e.value())
+            .unwrap_or_else(|| self.default_limits())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LongDataAction {
+    Forward,
+    /// The chunk exceeds a limit, the long data already buffered by the backend is reset.
+    Reject,
+    /// The long data of the statement has been rejected, further chunks are dropped.
+    Drop,
+}
+
+/// `LongDataTracker` counts the long data a session sent per statement. COM_STMT_SEND_LONG_DATA
+/// has no response, so like the server the proxy reports an exceeded limit when the statement
+/// is executed.
+pub struct LongDataTracker {
+    limits: LongDataLimits,
+    stmt_bytes: HashMap<u32, u64>,
+    rejected: HashSet<u32>,
+}
+
+impl LongDataTracker {
+    pub fn new(limits: LongDataLimits) -> Self {
+        Self {
+            limits,
+            stmt_bytes: HashMap::new(),
+            rejected: HashSet::new(),
+        }
+    }
+
+    fn session_bytes(&self) -> u64 {
+        self.stmt_bytes.values().sum()
+    }
+
+    pub fn on_long_data(&mut self, stmt_id: u32, len: u64) -> LongDataAction {
+        if self.rejected.contains(&stmt_id) {
+            return LongDataAction::Drop;
+        }
+        let stmt_bytes = self.stmt_bytes.get(&stmt_id).copied().unwrap_or(0) + len;
+        if self
+            .limits
+            .exceeded_by(stmt_bytes, self.session_bytes() + len)
+        {
+            self.stmt_bytes.remove(&stmt_id);
+            self.rejected.insert(stmt_id);
+            return LongDataAction::Reject;
+        }
+        self.stmt_bytes.insert(stmt_id, stmt_bytes);
+        LongDataAction::Forward
+    }
+
+    /// The backend releases the long data of a statement once it is executed, reset or closed.
+    /// Returns whether the long data of the statement had been rejected.
+    pub fn release(&mut self, stmt_id: u32) -> bool {
+        self.stmt_bytes.remove(&stmt_id);
+        self.rejected.remove(&stmt_id)
+    }
+
+    pub fn clear(&mut self) {
+        self.stmt_bytes.clear();
+        self.rejected.clear();
+    }
+}
+
+/// Applies the long data limits to a client command. The limits are tracked per client statement
+/// `client_stmt_id`, `client_packet` carries the id of the backend statement. Returns `true` if
+/// the command has been handled by the proxy and must not be forwarded.
+#[allow(clippy::too_many_arguments)]
+pub async fn apply_long_data_limits<W>(
+    tracker: &mut LongDataTracker,
+    metrics: &SessionMetrics,
+    com_code: CommandCode,
+    client_stmt_id: Option<u32>,
+    seq: u8,
+    client_packet: &[u8],
+    client_writer: &mut PacketWriter<W>,
+    client_capabilities: CapabilityFlags,
+    backend_writer: &mut PacketWriter<BackendWriteHalf>,
+    backend_reader: &mut PacketReader<BackendReadHalf>,
+) -> Result<bool, Error>
+where
+    W: AsyncWrite + Send + Unpin,
+{
+    if let CommandCode::ComQuit | CommandCode::ComChangeUser | CommandCode::ComResetConnection =
+        com_code
+    {
+        tracker.clear();
+        return Ok(false);
+    }
+    let Some(client_stmt_id) = client_stmt_id.filter(|_| client_packet.len() >= 5) else {
+        return Ok(false);
+    };
+    let backend_stmt_id = LittleEndian::read_u32(&client_packet[1..5]);
+    match com_code {
+        CommandCode::ComStmtSendLongData => {
+            let len = client_packet.len().saturating_sub(LONG_DATA_HEADER_LEN) as u64;
+            match tracker.on_long_data(client_stmt_id, len) {
+                LongDataAction::Forward => {
+                    metrics.long_data_bytes.increment(len);
+                    Ok(false)
+                }
+                LongDataAction::Reject => {
+                    warn!(
+                        "ProxySrv long data of stmt {} exceeds {:?}",
+                        client_stmt_id, tracker.limits
+                    );
+                    metrics.long_data_rejected.increment(1);
+                    backend_writer.reset_seq();
+                    writers::write_stmt_reset(backend_writer, backend_stmt_id).await?;
+                    let (_be_seq, _be_rsp_pkt) = async_packet_read!(backend_reader);
+                    Ok(true)
+                }
+                LongDataAction::Drop => Ok(true),
+            }
+        }
+        CommandCode::ComStmtExecute => {
+            if !tracker.release(client_stmt_id) {
+                return Ok(false);
+            }
+            let message = format!(
+                "Long data of statement {client_stmt_id} exceeds the limit of {} bytes per statement, {} bytes per session",
+                tracker.limits.max_stmt_bytes, tracker.limits.max_session_bytes
+            );
+            client_writer.set_seq(seq.wrapping_add(1));
+            writers::write_err_packet(
+                ErrorKind::ER_NET_PACKET_TOO_LARGE,
+                message.as_bytes(),
+                client_writer,
+                client_capabilities,
+            )
+            .await?;
+            client_writer.flush_all().await?;
+            Ok(true)
+        }
+        CommandCode::ComStmtReset | CommandCode::ComStmtClose => {
+            tracker.release(client_stmt_id);
+            Ok(false)
+        }
+        _ => Ok(false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::server::long_data::{LongDataAction, LongDataLimits, LongDataTracker};
+
+    #[test]
+    pub fn test_long_data_tracker() {
+        let mut tracker = LongDataTracker::new(LongDataLimits {
+            max_stmt_bytes: 100,
+            max_session_bytes: 150,
+        });
+        assert_eq!(tracker.on_long_data(1, 60), LongDataAction::Forward);
+        assert_eq!(tracker.on_long_data(2, 60), LongDataAction::Forward);
+        // 180 bytes in the session.
+        assert_eq!(tracker.on_long_data(2, 60), LongDataAction::Reject);
+        assert_eq!(tracker.on_long_data(2, 1), LongDataAction::Drop);
+        assert!(tracker.release(2));
+        assert!(!tracker.release(2));
+
+        // 120 bytes for statement 1.
+        assert_eq!(tracker.on_long_data(1, 60), LongDataAction::Reject);
+        assert!(tracker.release(1));
+        assert_eq!(tracker.on_long_data(1, 100), LongDataAction::Forward);
+
+        tracker.clear();
+        let mut unlimited = LongDataTracker::new(LongDataLimits::default());
+        assert_eq!(
+            unlimited.on_long_data(1, u32::MAX as u64),
+            LongDataAction::Forward
+        );
+    }
+}
@@ -0,0 +1,409 @@
+use crate::prost::common_proto::TenantKey;
+use crate::protocol::mysql::basic::Column;
+use crate::protocol::mysql::constants::CommandCode;
+use crate::protocol::mysql::error_codes::ErrorKind;
+use crate::protocol::mysql::packet::packet_writer::PacketWriter;
+use crate::protocol::mysql::packet::writers;
+use crate::server::slow_log::tenant_label;
+
+use byteorder::{ByteOrder, LittleEndian};
+use chrono::{DateTime, FixedOffset, Local};
+use common::metrics::metric_def::PROXY_MAINTENANCE_NOTICES;
+use common::metrics::{common_labels, counter_inc};
+use dashmap::DashMap;
+use mysql_common::constants::{CapabilityFlags, ColumnFlags, ColumnType, StatusFlags};
+use serde::{Deserialize, Serialize};
+use std::io::Error;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use tokio::io::AsyncWrite;
+use tracing::info;
+
+/// A maintenance of the backend of a tenant announced by the control plane.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MaintenanceNotice {
+    pub tenant: TenantKey,
+    /// RFC 3339 time the backend is suspended or upgraded.
+    pub starts_at: String,
+    /// Shown to clients, e.g. `upgrade to 8.0.36`.
+    #[serde(default)]
+    pub reason: String,
+}
+
+impl MaintenanceNotice {
+    fn describe(&self) -> String {
+        if self.reason.is_empty() {
+            format!(
+                "Scheduled maintenance of this database at {}",
+                self.starts_at
+            )
+        } else {
+            format!(
+                "Scheduled maintenance of this database at {}: {}",
+                self.starts_at, self.reason
+            )
+        }
+    }
+}
+
+struct ScheduledMaintenance {
+    id: u64,
+    notice: MaintenanceNotice,
+    starts_at: DateTime<FixedOffset>,
+}
+
+/// Where a tenant stands relative to its scheduled maintenance.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MaintenancePhase {
+    /// Statements of the sessions carry a warning with `message` until the maintenance starts.
+    Announced { id: u64, message: String },
+    /// Sessions are closed with an error on their next command.
+    Started { message: String },
+}
+
+/// `MaintenanceRegistry` keeps the maintenances scheduled by the control plane through the REST
+/// API, so that clients learn about them ahead instead of being disconnected by surprise.
+#[derive(Default)]
+pub struct MaintenanceRegistry {
+    next_id: AtomicU64,
+    tenants: DashMap<TenantKey, ScheduledMaintenance>,
+}
+
+static MAINTENANCE_REGISTRY_ONCE: OnceLock<MaintenanceRegistry> = OnceLock::new();
+
+pub fn maintenance_registry() -> &'static MaintenanceRegistry {
+    MAINTENANCE_REGISTRY_ONCE.get_or_init(MaintenanceRegistry::default)
+}
+
+impl MaintenanceRegistry {
+    /// Schedules the maintenance of a tenant, replacing the one scheduled before.
+    pub fn schedule(&self, notice: MaintenanceNotice) -> Result<(), Error> {
+        let starts_at = DateTime::parse_from_rfc3339(&notice.starts_at).map_err(|e| {
+            Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("invalid starts_at {}: {e}", notice.starts_at),
+            )
+        })?;
+        info!("ProxySrv maintenance scheduled {:?}", notice);
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.tenants.insert(
+            notice.tenant.clone(),
+            ScheduledMaintenance {
+                id,
+                notice,
+                starts_at,
+            },
+        );
+        Ok(())
+    }
+
+    /// Cancels the maintenance of a tenant, or ends it once the backend is back.
+    pub fn cancel(&self, tenant: &TenantKey) -> Option<MaintenanceNotice> {
+        info!("ProxySrv maintenance cancelled {:?}", tenant);
+        self.tenants
+            .remove(tenant)
+            .map(|(_, maintenance)| maintenance.notice)
+    }
+
+    pub fn list(&self) -> Vec<MaintenanceNotice> {
+        self.tenants
+            .iter()
+            .map(|e| e.value().notice.clone())
+            .collect()
+    }
+
+    pub fn phase(&self, tenant: &TenantKey) -> Option<MaintenancePhase> {
+        if self.tenants.is_empty() {
+            return None;
+        }
+        let maintenance = self.tenants.get(tenant)?;
+        let message = maintenance.notice.describe();
+        if maintenance.starts_at <= Local::now() {
+            Some(MaintenancePhase::Started {
+                message: format!("{message} started, reconnect once it ends"),
+            })
+        } else {
+            Some(MaintenancePhase::Announced {
+                id: maintenance.id,
+                message,
+            })
+        }
+    }
+}
+
+/// What a session does with a command of a tenant under maintenance.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NoticeAction {
+    Forward,
+    /// Answer `SHOW WARNINGS` with the notice attached to the previous statement.
+    ShowWarnings(String),
+    /// Close the session with a final error.
+    Disconnect(String),
+}
+
+/// `SessionNotice` attaches the announced maintenance of the tenant to one statement of a session
+/// as a warning, and answers the `SHOW WARNINGS` that follows it.
+pub struct SessionNotice {
+    tenant: TenantKey,
+    /// The maintenance the session was warned about.
+    delivered: Option<u64>,
+    /// Set by the forwarder once it attached the notice to the current statement.
+    pending: Option<(u64, Arc<AtomicBool>)>,
+    /// The maintenance attached to the previous statement.
+    last_stmt: Option<u64>,
+}
+
+impl SessionNotice {
+    pub fn new(tenant: TenantKey) -> Self {
+        Self {
+            tenant,
+            delivered: None,
+            pending: None,
+            last_stmt: None,
+        }
+    }
+
+    pub fn before_command(&mut self, com_code: CommandCode, payload: &[u8]) -> NoticeAction {
+        let last_stmt = self.last_stmt.take();
+        self.pending = None;
+        match maintenance_registry().phase(&self.tenant) {
+            Some(MaintenancePhase::Started { message }) if com_code != CommandCode::ComQuit => {
+                self.count("disconnect");
+                NoticeAction::Disconnect(message)
+            }
+            Some(MaintenancePhase::Announced { id, message }) => {
+                if com_code == CommandCode::ComQuery
+                    && last_stmt == Some(id)
+                    && is_show_warnings(payload)
+                {
+                    return NoticeAction::ShowWarnings(message);
+                }
+                if self.delivered != Some(id)
+                    && matches!(
+                        com_code,
+                        CommandCode::ComQuery | CommandCode::ComStmtExecute
+                    )
+                {
+                    self.pending = Some((id, Arc::new(AtomicBool::new(false))));
+                }
+                NoticeAction::Forward
+            }
+            _ => NoticeAction::Forward,
+        }
+    }
+
+    /// The flag handed to the forwarder of the current statement, if it should carry the notice.
+    pub fn warning_flag(&self) -> Option<Arc<AtomicBool>> {
+        self.pending.as_ref().map(|(_, flag)| Arc::clone(flag))
+    }
+
+    pub fn after_command(&mut self) {
+        if let Some((id, flag)) = self.pending.take() {
+            if flag.load(Ordering::Relaxed) {
+                self.delivered = Some(id);
+                self.last_stmt = Some(id);
+                self.count("warning");
+            }
+        }
+    }
+
+    fn count(&self, kind: &str) {
+        let mut labels = common_labels().clone();
+        labels.push(("tenant", tenant_label(&self.tenant)));
+        labels.push(("kind", kind.to_string()));
+        counter_inc(PROXY_MAINTENANCE_NOTICES, 1, Some(&labels));
+    }
+}
+
+fn is_show_warnings(sql: &[u8]) -> bool {
+    let Ok(sql) = std::str::from_utf8(sql) else {
+        return false;
+    };
+    let mut tokens = sql.trim().trim_end_matches(';').split_ascii_whitespace();
+    tokens
+        .next()
+        .is_some_and(|token| token.eq_ignore_ascii_case("SHOW"))
+        && tokens
+            .next()
+            .is_some_and(|token| token.eq_ignore_ascii_case("WARNINGS"))
+        && tokens.next().is_none()
+}
+
+/// Bytes of a length-encoded integer, by its first byte.
+fn length_encoded_size(first: u8) -> usize {
+    match first {
+        0xfc => 3,
+        0xfd => 4,
+        0xfe => 9,
+        _ => 1,
+    }
+}
+
+/// Sets the warning count of the OK or EOF packet ending a statement to 1, unless the statement
+/// has warnings of its own or more results follow. Returns whether the packet was changed.
+pub fn attach_notice_warning(packet: &mut [u8]) -> bool {
+    let (status_at, warnings_at) = match packet.first() {
+        // EOF packet: header, warnings, status flags.
+        Some(0xfe) if packet.len() <= 5 => (3, 1),
+        // OK packet: header, affected rows, last insert id, status flags, warnings.
+        Some(0x00 | 0xfe) if packet.len() > 1 => {
+            let last_insert_id_at = 1 + length_encoded_size(packet[1]);
+            match packet.get(last_insert_id_at) {
+                Some(first) => {
+                    let status_at = last_insert_id_at + length_encoded_size(*first);
+                    (status_at, status_at + 2)
+                }
+                None => return false,
+            }
+        }
+        _ => return false,
+    };
+    if packet.len() < status_at + 2 || packet.len() < warnings_at + 2 {
+        return false;
+    }
+    let status_flags =
+        StatusFlags::from_bits_truncate(LittleEndian::read_u16(&packet[status_at..]));
+    if status_flags.contains(StatusFlags::SERVER_MORE_RESULTS_EXISTS)
+        || LittleEndian::read_u16(&packet[warnings_at..]) != 0
+    {
+        return false;
+    }
+    LittleEndian::write_u16(&mut packet[warnings_at..], 1);
+    true
+}
+
+fn warning_column(name: &str, column_type: ColumnType) -> Column {
+    Column {
+        table: String::new(),
+        column: name.to_string(),
+        column_type,
+        column_flags: ColumnFlags::empty(),
+    }
+}
+
+/// Answers `SHOW WARNINGS` with the maintenance notice. `seq` is the sequence id of the client
+/// command.
+pub async fn write_notice_warnings<W>(
+    message: &str,
+    seq: u8,
+    client_writer: &mut PacketWriter<W>,
+    client_capabilities: CapabilityFlags,
+) -> Result<(), Error>
+where
+    W: AsyncWrite + Send + Unpin,
+{
+    let columns = [
+        warning_column("Level", ColumnType::MYSQL_TYPE_VAR_STRING),
+        warning_column("Code", ColumnType::MYSQL_TYPE_LONG),
+        warning_column("Message", ColumnType::MYSQL_TYPE_VAR_STRING),
+    ];
+    let rows = [vec![
+        Some("Warning".to_string()),
+        Some((ErrorKind::ER_SERVER_SHUTDOWN as u16).to_string()),
+        Some(message.to_string()),
+    ]];
+    client_writer.set_seq(seq.wrapping_add(1));
+    writers::write_text_result_set(&columns, &rows, client_writer, client_capabilities).await?;
+    client_writer.flush_all().await
+}
+
+/// Ends a session with the final error of a started maintenance.
+pub async fn write_maintenance_err<W>(
+    message: &str,
+    seq: u8,
+    client_writer: &mut PacketWriter<W>,
+    client_capabilities: CapabilityFlags,
+) -> Result<(), Error>
+where
+    W: AsyncWrite + Send + Unpin,
+{
+    client_writer.set_seq(seq.wrapping_add(1));
+    writers::write_err_packet(
+        ErrorKind::ER_SERVER_SHUTDOWN,
+        message.as_bytes(),
+        client_writer,
+        client_capabilities,
+    )
+    .await?;
+    client_writer.flush_all().await
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::test_tenant_key;
+    use crate::prost::common_proto::TenantKey;
+    use crate::protocol::mysql::constants::CommandCode;
+    use crate::server::maintenance::{
+        attach_notice_warning, maintenance_registry, MaintenanceNotice, MaintenancePhase,
+        NoticeAction, SessionNotice,
+    };
+    use chrono::{Duration, Local, SecondsFormat};
+    use std::sync::atomic::Ordering;
+
+    #[test]
+    pub fn test_maintenance_notice() {
+        // OK packet with 2-byte affected rows, autocommit and no warnings.
+        let mut ok = vec![0x00, 0xfc, 0x2c, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00];
+        assert!(attach_notice_warning(&mut ok));
+        assert_eq!(&ok[7..9], &[1, 0]);
+        assert!(!attach_notice_warning(&mut ok));
+        let mut eof = vec![0xfe, 0x00, 0x00, 0x02, 0x00];
+        assert!(attach_notice_warning(&mut eof));
+        assert_eq!(&eof[1..3], &[1, 0]);
+        let mut more_results = vec![0x00, 0x00, 0x00, 0x0a, 0x00, 0x00, 0x00];
+        assert!(!attach_notice_warning(&mut more_results));
+
+        let tenant = TenantKey {
+            cluster_name: "maintenance".to_string(),
+            ..test_tenant_key()
+        };
+        let registry = maintenance_registry();
+        let mut notice = MaintenanceNotice {
+            tenant: tenant.clone(),
+            starts_at: "tomorrow".to_string(),
+            reason: "upgrade".to_string(),
+        };
+        assert!(registry.schedule(notice.clone()).is_err());
+        notice.starts_at =
+            (Local::now() + Duration::hours(1)).to_rfc3339_opts(SecondsFormat::Secs, true);
+        registry.schedule(notice.clone()).unwrap();
+        let Some(MaintenancePhase::Announced { message, .. }) = registry.phase(&tenant) else {
+            panic!("maintenance is not announced");
+        };
+
+        let mut session = SessionNotice::new(tenant.clone());
+        assert_eq!(
+            session.before_command(CommandCode::ComQuery, b"UPDATE t SET a = 1"),
+            NoticeAction::Forward
+        );
+        session
+            .warning_flag()
+            .unwrap()
+            .store(true, Ordering::Relaxed);
+        session.after_command();
+        assert_eq!(
+            session.before_command(CommandCode::ComQuery, b"show warnings;"),
+            NoticeAction::ShowWarnings(message)
+        );
+        // The session was warned once.
+        assert_eq!(
+            session.before_command(CommandCode::ComQuery, b"SELECT 1"),
+            NoticeAction::Forward
+        );
+        assert!(session.warning_flag().is_none());
+
+        notice.starts_at =
+            (Local::now() - Duration::minutes(1)).to_rfc3339_opts(SecondsFormat::Secs, true);
+        registry.schedule(notice).unwrap();
+        assert!(matches!(
+            session.before_command(CommandCode::ComQuery, b"SELECT 1"),
+            NoticeAction::Disconnect(_)
+        ));
+        assert_eq!(
+            session.before_command(CommandCode::ComQuit, b""),
+            NoticeAction::Forward
+        );
+        assert!(registry.cancel(&tenant).is_some());
+        assert_eq!(registry.phase(&tenant), None);
+    }
+}
@@ -0,0 +1,327 @@
+use crate::backend::egress::{egress_policy, EgressTarget};
+use crate::bench::client::BenchConn;
+use crate::prost::common_proto::TenantKey;
+use crate::protocol::mysql::constants::CommandCode;
+use crate::server::auth::identity::CredentialRef;
+use crate::server::slow_log::tenant_label;
+
+use common::metrics::metric_def::{PROXY_MIRROR_LATENCY, PROXY_MIRROR_QUERIES};
+use common::metrics::{common_labels, counter_handle, histogram_handle, Counter, Histogram};
+use dashmap::DashMap;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::io::{Error, ErrorKind};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
+
+/// Queries waiting for the shadow backend of one session, more are dropped.
+const MIRROR_QUEUE_SIZE: usize = 64;
+/// Queries are dropped for this long after the shadow backend refused a connection.
+const MIRROR_RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Mirrors read-only queries of a tenant to a shadow backend, e.g. a new cluster version being
+/// validated. Shadow responses are discarded, only their latency and errors are measured.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TenantMirror {
+    pub tenant: TenantKey,
+    pub shadow_addr: String,
+    /// Percentage of the read-only queries mirrored, 0 to 100.
+    pub percent: u8,
+    pub shadow_user: String,
+    pub shadow_credential: CredentialRef,
+}
+
+/// `MirrorPolicy` keeps the tenants whose traffic is mirrored, managed by the control plane
+/// through the REST API. Sessions pick up the policy of their tenant when they start.
+#[derive(Default)]
+pub struct MirrorPolicy {
+    tenants: DashMap<TenantKey, TenantMirror>,
+}
+
+static MIRROR_POLICY_ONCE: OnceLock<MirrorPolicy> = OnceLock::new();
+
+pub fn mirror_policy() -> &'static MirrorPolicy {
+    MIRROR_POLICY_ONCE.get_or_init(MirrorPolicy::default)
+}
+
+impl MirrorPolicy {
+    pub fn set_mirror(&self, mirror: TenantMirror) -> Result<(), Error> {
+        if mirror.percent > 100 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("mirror percent {} exceeds 100", mirror.percent),
+            ));
+        }
+        info!(
+            "ProxySrv mirror {:?} {}% to {}",
+            mirror.tenant, mirror.percent, mirror.shadow_addr
+        );
+        self.tenants.insert(mirror.tenant.clone(), mirror);
+        Ok(())
+    }
+
+    pub fn remove_mirror(&self, tenant: &TenantKey) -> Option<TenantMirror> {
+        info!("ProxySrv mirror removed {:?}", tenant);
+        self.tenants.remove(tenant).map(|(_, mirror)| mirror)
+    }
+
+    pub fn list(&self) -> Vec<TenantMirror> {
+        self.tenants.iter().map(|e| e.value().clone()).collect()
+    }
+
+    pub fn get(&self, tenant: &TenantKey) -> Option<TenantMirror> {
+        self.tenants.get(tenant).map(|e| e.value().clone())
+    }
+}
+
+/// Skips leading whitespace and comments of a statement.
+pub fn strip_leading_comments(mut sql: &str) -> &str {
+    loop {
+        sql = sql.trim_start();
+        if let Some(rest) = sql.strip_prefix("/*") {
+            match rest.find("*/") {
+                Some(end) => sql = &rest[end + 2..],
+                None => return "",
+            }
+        } else if sql.starts_with("--") || sql.starts_with('#') {
+            match sql.find('\n') {
+                Some(end) => sql = &sql[end + 1..],
+                None => return "",
+            }
+        } else {
+            return sql;
+        }
+    }
+}
+
+pub fn first_keyword(sql: &str) -> &str {
+    let sql = strip_leading_comments(sql);
+    let end = sql
+        .find(|c: char| !c.is_ascii_alphabetic())
+        .unwrap_or(sql.len());
+    &sql[..end]
+}
+
+/// Whether a text query only reads, locking reads and `SELECT ... INTO` excluded.
+pub fn is_read_only(sql: &str) -> bool {
+    let keyword = first_keyword(sql);
+    if !["SELECT", "SHOW", "DESC", "DESCRIBE"]
+        .iter()
+        .any(|read| keyword.eq_ignore_ascii_case(read))
+    {
+        return false;
+    }
+    let upper = sql.to_ascii_uppercase();
+    !["FOR UPDATE", "FOR SHARE", "LOCK IN SHARE MODE", " INTO "]
+        .iter()
+        .any(|clause| upper.contains(clause))
+}
+
+pub fn is_use_stmt(sql: &str) -> bool {
+    first_keyword(sql).eq_ignore_ascii_case("USE")
+}
+
+struct MirroredQuery {
+    sql: String,
+    /// `None` for statements that only keep the shadow session in sync, e.g. `USE`.
+    primary_latency: Option<Duration>,
+}
+
+struct MirrorMetrics {
+    ok: Counter,
+    error: Counter,
+    dropped: Counter,
+    primary_latency: Histogram,
+    shadow_latency: Histogram,
+}
+
+impl MirrorMetrics {
+    fn new(tenant: &TenantKey) -> Self {
+        let mut labels = common_labels().clone();
+        labels.push(("tenant", tenant_label(tenant)));
+        let with = |key: &'static str, value: &str| {
+            let mut labels = labels.clone();
+            labels.push((key, value.to_string()));
+            labels
+        };
+        Self {
+            ok: counter_handle(PROXY_MIRROR_QUERIES, &with("result", "ok")),
+            error: counter_handle(PROXY_MIRROR_QUERIES, &with("result", "error")),
+            dropped: counter_handle(PROXY_MIRROR_QUERIES, &with("result", "dropped")),
+            primary_latency: histogram_handle(PROXY_MIRROR_LATENCY, &with("target", "primary")),
+            shadow_latency: histogram_handle(PROXY_MIRROR_LATENCY, &with("target", "shadow")),
+        }
+    }
+}
+
+/// `ShadowMirror` mirrors the queries of one client session. A background task replays them on
+/// its own shadow connection, so the client never waits for the shadow backend.
+pub struct ShadowMirror {
+    percent: u8,
+    tx: mpsc::Sender<MirroredQuery>,
+    dropped: Counter,
+}
+
+impl ShadowMirror {
+    /// Starts mirroring a session if its tenant has a mirror policy.
+    pub fn start(tenant: &TenantKey, database: Option<String>) -> Option<Self> {
+        let mirror = mirror_policy().get(tenant)?;
+        if mirror.percent == 0 {
+            return None;
+        }
+        let metrics = MirrorMetrics::new(tenant);
+        let dropped = metrics.dropped.clone();
+        let (tx, rx) = mpsc::channel(MIRROR_QUEUE_SIZE);
+        let percent = mirror.percent;
+        tokio::spawn(replay(mirror, database, metrics, rx));
+        Some(Self {
+            percent,
+            tx,
+            dropped,
+        })
+    }
+
+    /// The statement to mirror for a command of the client. `USE` and COM_INIT_DB are always
+    /// mirrored to keep the shadow session on the same database.
+    pub fn sample(&self, com_code: CommandCode, payload: &[u8]) -> Option<String> {
+        match com_code {
+            CommandCode::ComQuery => {
+                let sql = std::str::from_utf8(payload).ok()?;
+                let sampled = is_use_stmt(sql)
+                    || (is_read_only(sql) && rand::thread_rng().gen_range(0..100) < self.percent);
+                sampled.then(|| sql.to_string())
+            }
+            CommandCode::ComInitDB => {
+                let database = String::from_utf8_lossy(payload).replace('`', "``");
+                Some(format!("USE `{database}`"))
+            }
+            _ => None,
+        }
+    }
+
+    /// Mirrors a statement returned by [`sample`](ShadowMirror::sample) once the primary
+    /// answered it in `primary_latency`.
+    pub fn mirror(&self, sql: String, primary_latency: Duration) {
+        let primary_latency = (!is_use_stmt(&sql)).then_some(primary_latency);
+        if self
+            .tx
+            .try_send(MirroredQuery {
+                sql,
+                primary_latency,
+            })
+            .is_err()
+        {
+            self.dropped.increment(1);
+        }
+    }
+}
+
+async fn replay(
+    mirror: TenantMirror,
+    database: Option<String>,
+    metrics: MirrorMetrics,
+    mut rx: mpsc::Receiver<MirroredQuery>,
+) {
+    let mut shadow_conn: Option<BenchConn> = None;
+    let mut connect_failed_at: Option<Instant> = None;
+    while let Some(query) = rx.recv().await {
+        if shadow_conn.is_none() {
+            if connect_failed_at.is_some_and(|at| at.elapsed() < MIRROR_RECONNECT_INTERVAL) {
+                metrics.dropped.increment(1);
+                continue;
+            }
+            let connected = match mirror.shadow_credential.resolve() {
+                Ok(password) => match egress_policy()
+                    .resolve(EgressTarget::Backend, &mirror.shadow_addr)
+                    .await
+                {
+                    Ok(addrs) => {
+                        BenchConn::connect(
+                            &addrs[0].to_string(),
+                            &mirror.shadow_user,
+                            &password,
+                            database.as_deref(),
+                        )
+                        .await
+                    }
+                    Err(e) => Err(e),
+                },
+                Err(e) => Err(e),
+            };
+            match connected {
+                Ok(conn) => shadow_conn = Some(conn),
+                Err(e) => {
+                    connect_failed_at = Some(Instant::now());
+                    warn!(
+                        "ProxySrv mirror connect {} failed {e:?}",
+                        mirror.shadow_addr
+                    );
+                    metrics.error.increment(1);
+                    continue;
+                }
+            }
+        }
+        let conn = shadow_conn.as_mut().unwrap();
+        let started = Instant::now();
+        let shadow_rs = conn.query(&query.sql).await;
+        let shadow_latency = started.elapsed();
+        if let Err(e) = shadow_rs {
+            debug!("ProxySrv mirror query err {e:?}");
+            metrics.error.increment(1);
+            // Server errors keep the connection, a broken one is opened again for the next query.
+            if e.kind() != ErrorKind::Other {
+                shadow_conn = None;
+            }
+            continue;
+        }
+        if let Some(primary_latency) = query.primary_latency {
+            metrics.ok.increment(1);
+            metrics
+                .primary_latency
+                .record(primary_latency.as_secs_f64() * 1000.0);
+            metrics
+                .shadow_latency
+                .record(shadow_latency.as_secs_f64() * 1000.0);
+        }
+    }
+    if let Some(conn) = shadow_conn {
+        let _ = conn.close().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::test_tenant_key;
+    use crate::server::auth::identity::CredentialRef;
+    use crate::server::mirror::{is_read_only, is_use_stmt, MirrorPolicy, TenantMirror};
+
+    #[test]
+    pub fn test_mirror_policy() {
+        assert!(is_read_only("select c from sbtest1 where id = 1"));
+        assert!(is_read_only("/* app */ SHOW TABLES"));
+        assert!(!is_read_only(
+            "SELECT c FROM sbtest1 WHERE id = 1 FOR UPDATE"
+        ));
+        assert!(!is_read_only("SELECT 1 INTO @one"));
+        assert!(!is_read_only("UPDATE sbtest1 SET k = 1"));
+        assert!(!is_read_only("-- select\nDELETE FROM sbtest1"));
+        assert!(is_use_stmt("use sbtest"));
+
+        let policy = MirrorPolicy::default();
+        let mut mirror = TenantMirror {
+            tenant: test_tenant_key(),
+            shadow_addr: "127.0.0.1:3307".to_string(),
+            percent: 101,
+            shadow_user: "shadow".to_string(),
+            shadow_credential: CredentialRef::Env("SHADOW_PASSWORD".to_string()),
+        };
+        assert!(policy.set_mirror(mirror.clone()).is_err());
+        mirror.percent = 10;
+        policy.set_mirror(mirror.clone()).unwrap();
+        assert_eq!(policy.get(&test_tenant_key()), Some(mirror));
+        assert!(policy.remove_mirror(&test_tenant_key()).is_some());
+        assert!(policy.list().is_empty());
+    }
+}
@@ -3,39 +3,86 @@ use crate::protocol::mysql::constants::SqlComInfo;
 use crate::protocol::mysql::packet::packet_reader::PacketReader;
 use crate::protocol::mysql::packet::packet_writer::PacketWriter;
 use crate::protocol::mysql::packet::Packet;
+use crate::server::conn_tracker::ConnToken;
+use crate::server::handshake_profile::HandshakeProfile;
+use crate::server::session::SessionCloseReason;
+use crate::server::session_backend::SessionBackend;
 use async_trait::async_trait;
 use common::metrics::common_labels;
 use mysql_common::constants::CapabilityFlags;
+use std::net::SocketAddr;
 use std::sync::OnceLock;
 
 use hashbrown::HashMap;
 use rustls::server::ServerConfig;
 use std::vec;
 use tokio::io::{AsyncRead, AsyncWrite};
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio_rustls::rustls;
 
+pub mod acme;
+pub mod admin;
 pub mod auth;
+pub mod auth_limiter;
+pub mod billing;
+pub mod client_stream;
+pub mod client_tls;
 pub mod cmd_handler;
-mod forwarder;
+pub mod command_policy;
+pub mod compat;
+pub mod conn_tracker;
+pub mod drain;
+pub mod error_stats;
+pub mod failover;
+pub mod fault_injection;
+pub mod forwarder;
 pub mod haentgl_server;
+pub mod handshake_profile;
+pub mod idle_timeout;
+pub mod keepalive;
+pub mod long_data;
+pub mod maintenance;
+pub mod mirror;
+pub mod notifier;
+pub mod packet_capture;
+pub mod protocol_features;
+pub mod protocol_limits;
 pub mod proxy_cli_args;
+pub mod proxy_config;
+pub mod proxy_protocol;
+pub mod query_digest;
+pub mod rate_limit;
+pub mod read_split;
+pub mod readiness;
+pub mod recent_errors;
+pub mod reload;
+pub mod request_id;
+pub mod route_policy;
+pub mod runtime;
+pub mod session;
+pub mod session_backend;
+pub mod session_metrics;
+pub mod slow_log;
+pub mod sni_router;
+pub mod sql_privacy;
+pub mod startup_report;
 #[allow(unused_variables)]
 pub mod static_proxy;
+pub mod transparent;
+pub mod tunnel;
+pub mod watchdog;
+pub mod wrong_protocol;
+
+pub use runtime::{ProxyRuntime, RuntimeEvent};
 
 #[macro_export]
 macro_rules! parse_err_packet {
     ($capabilities:expr, $packet:expr,$err_msg:expr) => {
-        use mysql_common::io::ParseBuf;
-        use mysql_common::proto::MyDeserialize;
         use tracing::warn;
 
-        let err_packet =
-            mysql_common::packets::ErrPacket::deserialize($capabilities, &mut ParseBuf(&$packet))
-                .unwrap();
-        let server_error = err_packet.server_error();
-        let server_err_msg = server_error.message_str();
-        warn!("{:?} {:?}", $err_msg, server_err_msg);
+        match $crate::protocol::mysql::basic::err_packet_message(&$packet, $capabilities) {
+            Ok(server_err_msg) => warn!("{:?} {:?}", $err_msg, server_err_msg),
+            Err(e) => warn!("{:?} malformed ERR packet {:?}", $err_msg, e),
+        }
     };
 }
 
@@ -84,7 +131,8 @@ pub fn default_capabilities() -> CapabilityFlags {
             | CapabilityFlags::CLIENT_IGNORE_SIGPIPE
             | CapabilityFlags::CLIENT_IGNORE_SPACE
             | CapabilityFlags::CLIENT_INTERACTIVE
-            | CapabilityFlags::CLIENT_LOCAL_FILES
+            // LOAD DATA LOCAL INFILE requests are not relayed.
+            // | CapabilityFlags::CLIENT_LOCAL_FILES
             | CapabilityFlags::CLIENT_LONG_FLAG
             | CapabilityFlags::CLIENT_LONG_PASSWORD
             | CapabilityFlags::CLIENT_MULTI_RESULTS
@@ -120,25 +168,30 @@ pub trait ProxyServer {
         client_reader: R,
         client_writer: &mut W,
         scramble: [u8; 20],
+        profile: &HandshakeProfile,
         #[cfg(feature = "tls")] tls_conf: &Option<std::sync::Arc<ServerConfig>>,
     ) -> Result<(u8, HandshakeResponse, Packet, PacketReader<R>), std::io::Error>
     where
         R: AsyncRead + Send + Unpin,
         W: AsyncWrite + Send + Unpin;
 
-    /// Forwards packets between the client and the Backend.
-    /// If the backend connection fails, it redirects to an available backend connection.
+    /// Forwards packets between the client and the Backend until the client quits.
+    /// If the backend connection breaks, the session fails over to another backend, see
+    /// [`SessionBackend`]. The `client_addr` is the one the session list shows.
     async fn on_com<'a, R, W>(
         &self,
         client_reader: &mut PacketReader<R>,
         client_writer: &mut PacketWriter<W>,
-        backend_writer: &mut PacketWriter<OwnedWriteHalf>,
-        backend_reader: &mut PacketReader<OwnedReadHalf>,
+        backend: &mut SessionBackend,
         handshake_response: &'a HandshakeResponse,
-    ) -> Result<(), std::io::Error>
+        conn: &mut ConnToken<'_>,
+        client_addr: Option<SocketAddr>,
+    ) -> Result<SessionCloseReason, std::io::Error>
     where
         R: AsyncRead + Send + Unpin,
         W: AsyncWrite + Send + Unpin;
 
+    /// Stops the server once it no longer accepts clients: drains the sessions, then closes
+    /// the backend connections.
     async fn close(&self);
 }
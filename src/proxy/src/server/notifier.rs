@@ -0,0 +1,375 @@
+use crate::server::recent_errors::recent_errors;
+
+use chrono::{Local, SecondsFormat};
+use dashmap::DashMap;
+use serde::Serialize;
+use std::io::{Error, ErrorKind};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, mpsc};
+use tracing::{debug, info, warn};
+
+const NOTIFY_QUEUE_SIZE: usize = 256;
+/// Events kept for slow subscribers, the oldest are dropped beyond.
+const SUBSCRIBED_EVENTS_SIZE: usize = 256;
+const NOTIFY_TIMEOUT: Duration = Duration::from_secs(5);
+const K8S_SERVICE_ACCOUNT_DIR: &str = "/var/run/secrets/kubernetes.io/serviceaccount";
+
+/// Significant state changes reported to the platform.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProxyEventKind {
+    /// A backend went offline or could not get a connection pool.
+    BackendUnhealthy,
+    /// A backend circuit breaker opened and stops sending traffic to the backend.
+    CircuitBreakerOpened,
+    /// Every connection of a backend pool is in use, new sessions wait.
+    PoolExhausted,
+    /// The backend discovery stream from the control plane broke.
+    DiscoveryLost,
+    /// An outbound connection target was outside the egress allowlists.
+    EgressRejected,
+    /// The backend list of a tenant was not refreshed for long while its connections fail.
+    TopologyStale,
+}
+
+impl ProxyEventKind {
+    /// The K8s Event `reason`.
+    fn reason(&self) -> &'static str {
+        match self {
+            ProxyEventKind::BackendUnhealthy => "BackendUnhealthy",
+            ProxyEventKind::CircuitBreakerOpened => "CircuitBreakerOpened",
+            ProxyEventKind::PoolExhausted => "PoolExhausted",
+            ProxyEventKind::DiscoveryLost => "DiscoveryLost",
+            ProxyEventKind::EgressRejected => "EgressRejected",
+            ProxyEventKind::TopologyStale => "TopologyStale",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ProxyEvent {
+    pub kind: ProxyEventKind,
+    /// What the event is about, e.g. the backend address.
+    pub subject: String,
+    pub message: String,
+    pub node: String,
+    /// RFC 3339 time the event occurred.
+    pub time: String,
+    /// Events of the same kind and subject dropped by the rate limit since the last one sent.
+    pub suppressed: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct NotifierConfig {
+    pub webhook_url: Option<String>,
+    /// Webhook body with `{{kind}}`, `{{subject}}`, `{{message}}`, `{{node}}`, `{{time}}` and
+    /// `{{suppressed}}` placeholders. The event is posted as JSON if not set.
+    pub webhook_template: Option<String>,
+    /// Also creates K8s Events for the proxy pod when running in a cluster.
+    pub k8s_events: bool,
+    /// At most one event per kind and subject is sent within this interval.
+    pub min_interval: Duration,
+    pub node: String,
+}
+
+impl NotifierConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.webhook_url.is_some() || self.k8s_events
+    }
+}
+
+/// `Notifier` posts [`ProxyEvent`]s to a webhook and/or as K8s Events. Events are sent from a
+/// background task, callers never wait on the network.
+pub struct Notifier {
+    config: NotifierConfig,
+    last_sent: DashMap<(ProxyEventKind, String), (Option<Instant>, u64)>,
+    tx: mpsc::Sender<ProxyEvent>,
+}
+
+static NOTIFIER_ONCE: OnceLock<Notifier> = OnceLock::new();
+static SUBSCRIBED_EVENTS_ONCE: OnceLock<broadcast::Sender<ProxyEvent>> = OnceLock::new();
+
+/// Starts the notifier if a sink is configured, must be called within the tokio runtime.
+pub fn init_notifier(config: NotifierConfig) {
+    if !config.is_enabled() {
+        return;
+    }
+    let (tx, rx) = mpsc::channel(NOTIFY_QUEUE_SIZE);
+    let sinks = NotifySinks::new(&config);
+    if NOTIFIER_ONCE.set(Notifier::new(config, tx)).is_ok() {
+        tokio::spawn(sinks.run(rx));
+    }
+}
+
+pub fn notifier() -> Option<&'static Notifier> {
+    NOTIFIER_ONCE.get()
+}
+
+/// Subscribes to every event reported from now on, whether the notifier is enabled or not, e.g.
+/// for a binary embedding the proxy. The events are not rate limited.
+pub fn subscribe_events() -> broadcast::Receiver<ProxyEvent> {
+    SUBSCRIBED_EVENTS_ONCE
+        .get_or_init(|| broadcast::channel(SUBSCRIBED_EVENTS_SIZE).0)
+        .subscribe()
+}
+
+/// Reports an event if the notifier is enabled. Events are also kept as recent errors.
+pub fn notify(kind: ProxyEventKind, subject: &str, message: String) {
+    recent_errors().record(subject, format!("{}: {message}", kind.reason()));
+    if let Some(subscribed) = SUBSCRIBED_EVENTS_ONCE
+        .get()
+        .filter(|subscribed| subscribed.receiver_count() > 0)
+    {
+        let _ = subscribed.send(ProxyEvent {
+            kind,
+            subject: subject.to_string(),
+            message: message.clone(),
+            node: notifier()
+                .map(|notifier| notifier.config.node.clone())
+                .unwrap_or_default(),
+            time: Local::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            suppressed: 0,
+        });
+    }
+    if let Some(notifier) = notifier() {
+        notifier.notify(kind, subject, message);
+    }
+}
+
+fn json_escape(value: &str) -> String {
+    let quoted = serde_json::to_string(value).unwrap_or_default();
+    quoted[1..quoted.len() - 1].to_string()
+}
+
+/// Fills the placeholders of a webhook template, values are escaped for JSON strings.
+pub fn render_template(template: &str, event: &ProxyEvent) -> String {
+    [
+        ("{{kind}}", event.kind.reason().to_string()),
+        ("{{subject}}", json_escape(&event.subject)),
+        ("{{message}}", json_escape(&event.message)),
+        ("{{node}}", json_escape(&event.node)),
+        ("{{time}}", event.time.clone()),
+        ("{{suppressed}}", event.suppressed.to_string()),
+    ]
+    .iter()
+    .fold(template.to_string(), |body, (placeholder, value)| {
+        body.replace(placeholder, value)
+    })
+}
+
+impl Notifier {
+    fn new(config: NotifierConfig, tx: mpsc::Sender<ProxyEvent>) -> Self {
+        Self {
+            config,
+            last_sent: DashMap::new(),
+            tx,
+        }
+    }
+
+    /// Returns the number of events suppressed since the last one sent, or `None` if the event
+    /// must be suppressed.
+    fn admit(&self, kind: ProxyEventKind, subject: &str) -> Option<u64> {
+        let now = Instant::now();
+        let mut entry = self
+            .last_sent
+            .entry((kind, subject.to_string()))
+            .or_insert((None, 0));
+        let (last_sent, suppressed) = entry.value_mut();
+        if last_sent.is_some_and(|last| now.duration_since(last) < self.config.min_interval) {
+            *suppressed += 1;
+            return None;
+        }
+        *last_sent = Some(now);
+        Some(std::mem::take(suppressed))
+    }
+
+    pub fn notify(&self, kind: ProxyEventKind, subject: &str, message: String) {
+        let Some(suppressed) = self.admit(kind, subject) else {
+            debug!("ProxySrv notify {:?} {} rate limited", kind, subject);
+            return;
+        };
+        let event = ProxyEvent {
+            kind,
+            subject: subject.to_string(),
+            message,
+            node: self.config.node.clone(),
+            time: Local::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            suppressed,
+        };
+        if let Err(e) = self.tx.try_send(event) {
+            warn!("ProxySrv notify queue full, event dropped {:?}", e);
+        }
+    }
+}
+
+/// In-cluster access to the K8s API with the pod service account.
+struct K8sEventSink {
+    client: reqwest::Client,
+    events_url: String,
+    token: String,
+    namespace: String,
+    pod: String,
+}
+
+impl K8sEventSink {
+    fn in_cluster(pod: &str) -> Result<Self, Error> {
+        let host = std::env::var("KUBERNETES_SERVICE_HOST")
+            .map_err(|_| Error::new(ErrorKind::NotFound, "not running in a K8s cluster"))?;
+        let port = std::env::var("KUBERNETES_SERVICE_PORT").unwrap_or("443".to_string());
+        let read = |file: &str| std::fs::read(format!("{K8S_SERVICE_ACCOUNT_DIR}/{file}"));
+        let token = String::from_utf8_lossy(&read("token")?).trim().to_string();
+        let namespace = String::from_utf8_lossy(&read("namespace")?)
+            .trim()
+            .to_string();
+        let ca = reqwest::Certificate::from_pem(&read("ca.crt")?)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        let client = reqwest::ClientBuilder::new()
+            .add_root_certificate(ca)
+            .timeout(NOTIFY_TIMEOUT)
+            .build()
+            .map_err(Error::other)?;
+        Ok(Self {
+            client,
+            events_url: format!("https://{host}:{port}/api/v1/namespaces/{namespace}/events"),
+            token,
+            namespace,
+            pod: pod.to_string(),
+        })
+    }
+
+    async fn send(&self, event: &ProxyEvent) -> Result<(), reqwest::Error> {
+        let time = &event.time;
+        let body = serde_json::json!({
+            "apiVersion": "v1",
+            "kind": "Event",
+            "metadata": { "generateName": "haentgl-proxy-" },
+            "involvedObject": { "kind": "Pod", "name": self.pod, "namespace": self.namespace },
+            "reason": event.kind.reason(),
+            "message": format!("{}: {}", event.subject, event.message),
+            "type": "Warning",
+            "count": event.suppressed + 1,
+            "firstTimestamp": time,
+            "lastTimestamp": time,
+            "source": { "component": "haentgl-proxy", "host": event.node },
+        });
+        self.client
+            .post(&self.events_url)
+            .bearer_auth(&self.token)
+            .json(&body)
+            .send()
+            .await?
+            .error_for_status()
+            .map(|_| ())
+    }
+}
+
+struct NotifySinks {
+    client: reqwest::Client,
+    webhook_url: Option<String>,
+    webhook_template: Option<String>,
+    k8s: Option<K8sEventSink>,
+}
+
+impl NotifySinks {
+    fn new(config: &NotifierConfig) -> Self {
+        let k8s = if config.k8s_events {
+            K8sEventSink::in_cluster(&config.node)
+                .inspect_err(|e| warn!("ProxySrv K8s events disabled, cause by {e:?}"))
+                .ok()
+        } else {
+            None
+        };
+        Self {
+            client: reqwest::ClientBuilder::new()
+                .timeout(NOTIFY_TIMEOUT)
+                .build()
+                .unwrap_or_default(),
+            webhook_url: config.webhook_url.clone(),
+            webhook_template: config.webhook_template.clone(),
+            k8s,
+        }
+    }
+
+    async fn send_webhook(&self, url: &str, event: &ProxyEvent) -> Result<(), reqwest::Error> {
+        let request = match &self.webhook_template {
+            Some(template) => self
+                .client
+                .post(url)
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(render_template(template, event)),
+            None => self.client.post(url).json(event),
+        };
+        request.send().await?.error_for_status().map(|_| ())
+    }
+
+    async fn run(self, mut rx: mpsc::Receiver<ProxyEvent>) {
+        info!("ProxySrv notifier started");
+        while let Some(event) = rx.recv().await {
+            if let Some(url) = &self.webhook_url {
+                if let Err(e) = self.send_webhook(url, &event).await {
+                    warn!("ProxySrv notify webhook failed {:?}", e);
+                }
+            }
+            if let Some(k8s) = &self.k8s {
+                if let Err(e) = k8s.send(&event).await {
+                    warn!("ProxySrv notify K8s event failed {:?}", e);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::server::notifier::{render_template, Notifier, NotifierConfig, ProxyEventKind};
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+
+    #[test]
+    pub fn test_notify_rate_limit() {
+        let (tx, mut rx) = mpsc::channel(8);
+        let notifier = Notifier::new(
+            NotifierConfig {
+                min_interval: Duration::from_secs(60),
+                node: "proxy-0".to_string(),
+                ..Default::default()
+            },
+            tx,
+        );
+        let backend = "10.0.0.1:3306";
+        notifier.notify(
+            ProxyEventKind::BackendUnhealthy,
+            backend,
+            "offline".to_string(),
+        );
+        notifier.notify(
+            ProxyEventKind::BackendUnhealthy,
+            backend,
+            "offline".to_string(),
+        );
+        notifier.notify(
+            ProxyEventKind::PoolExhausted,
+            backend,
+            "10/10 \"busy\"".to_string(),
+        );
+
+        let event = rx.try_recv().unwrap();
+        assert_eq!(
+            (event.kind, event.suppressed),
+            (ProxyEventKind::BackendUnhealthy, 0)
+        );
+        let event = rx.try_recv().unwrap();
+        assert_eq!(event.kind, ProxyEventKind::PoolExhausted);
+        assert!(rx.try_recv().is_err());
+
+        let body = render_template(
+            r#"{"text":"{{kind}} on {{node}}: {{subject}} {{message}}"}"#,
+            &event,
+        );
+        assert_eq!(
+            body,
+            r#"{"text":"PoolExhausted on proxy-0: 10.0.0.1:3306 10/10 \"busy\""}"#
+        );
+    }
+}
@@ -0,0 +1,413 @@
+//! Packet traces of client sessions for support cases. An operator arms a capture of the next
+//! sessions of a tenant through the REST API; each of them then records the packets its client
+//! and its backend send into a trace file of the support directory, until the trace reaches its
+//! size bound. Auth data is never written: the auth response of the handshake and the packets of
+//! a COM_CHANGE_USER exchange are redacted.
+
+use crate::prost::common_proto::TenantKey;
+use crate::protocol::mysql::basic::HandshakeResponse;
+use crate::protocol::mysql::constants::CommandCode;
+use crate::protocol::mysql::packet::packet_reader::PacketHook;
+use crate::server::slow_log::tenant_label;
+
+use chrono::Utc;
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Instant;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+
+pub const TRACE_FILE_EXTENSION: &str = "trace";
+pub const DEFAULT_CAPTURE_MAX_BYTES: u64 = 1024 * 1024;
+/// Sessions one capture may arm, a support case needs a handful.
+pub const CAPTURE_MAX_SESSIONS: u32 = 100;
+
+fn default_max_bytes() -> u64 {
+    DEFAULT_CAPTURE_MAX_BYTES
+}
+
+/// Captures the next `sessions` sessions of `tenant`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CaptureRequest {
+    pub tenant: TenantKey,
+    /// Sessions still to capture.
+    pub sessions: u32,
+    /// Bytes a trace holds at most, the packets past it are left out.
+    #[serde(default = "default_max_bytes")]
+    pub max_bytes: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TraceFile {
+    pub name: String,
+    pub bytes: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CaptureStatus {
+    pub armed: Vec<CaptureRequest>,
+    pub traces: Vec<TraceFile>,
+}
+
+/// `PacketCapture` keeps the armed captures and the traces of the support directory. Without a
+/// support directory nothing can be armed.
+pub struct PacketCapture {
+    dir: Option<PathBuf>,
+    armed: DashMap<TenantKey, CaptureRequest>,
+}
+
+static PACKET_CAPTURE_ONCE: OnceLock<PacketCapture> = OnceLock::new();
+
+/// Initializes the global packet capture, must be called before the first session starts.
+pub fn init_packet_capture(dir: Option<PathBuf>) -> &'static PacketCapture {
+    PACKET_CAPTURE_ONCE.get_or_init(|| PacketCapture::new(dir))
+}
+
+pub fn packet_capture() -> &'static PacketCapture {
+    PACKET_CAPTURE_ONCE.get_or_init(|| PacketCapture::new(None))
+}
+
+impl PacketCapture {
+    pub fn new(dir: Option<PathBuf>) -> Self {
+        Self {
+            dir,
+            armed: DashMap::new(),
+        }
+    }
+
+    fn dir(&self) -> Result<&PathBuf, Error> {
+        self.dir.as_ref().ok_or_else(|| {
+            Error::new(
+                ErrorKind::Unsupported,
+                "packet capture needs a support directory, see --support-dir",
+            )
+        })
+    }
+
+    /// Arms a capture, replacing the capture armed for the same tenant.
+    pub fn arm(&self, request: CaptureRequest) -> Result<(), Error> {
+        let dir = self.dir()?;
+        if request.sessions == 0 || request.sessions > CAPTURE_MAX_SESSIONS {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("sessions must be between 1 and {CAPTURE_MAX_SESSIONS}"),
+            ));
+        }
+        if request.max_bytes == 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "max_bytes must not be 0",
+            ));
+        }
+        std::fs::create_dir_all(dir)?;
+        info!(
+            "ProxySrv packet capture of the next {} sessions of {} armed",
+            request.sessions,
+            tenant_label(&request.tenant)
+        );
+        self.armed.insert(request.tenant.clone(), request);
+        Ok(())
+    }
+
+    pub fn disarm(&self, tenant: &TenantKey) -> Option<CaptureRequest> {
+        self.armed.remove(tenant).map(|(_, request)| request)
+    }
+
+    pub fn status(&self) -> Result<CaptureStatus, Error> {
+        let mut armed = self
+            .armed
+            .iter()
+            .map(|e| e.value().clone())
+            .collect::<Vec<_>>();
+        armed.sort_by_key(|request| tenant_label(&request.tenant));
+        let mut traces = vec![];
+        if let Some(dir) = self.dir.as_ref().filter(|dir| dir.is_dir()) {
+            for entry in std::fs::read_dir(dir)? {
+                let entry = entry?;
+                let path = entry.path();
+                if path
+                    .extension()
+                    .is_some_and(|ext| ext == TRACE_FILE_EXTENSION)
+                {
+                    traces.push(TraceFile {
+                        name: entry.file_name().to_string_lossy().into_owned(),
+                        bytes: entry.metadata()?.len(),
+                    });
+                }
+            }
+        }
+        traces.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(CaptureStatus { armed, traces })
+    }
+
+    /// The content of the trace `name`, only the trace files of the support directory are read.
+    pub fn read_trace(&self, name: &str) -> Result<Vec<u8>, Error> {
+        let dir = self.dir()?;
+        let path = dir.join(name);
+        let is_trace = !name.contains(['/', '\\'])
+            && path
+                .extension()
+                .is_some_and(|ext| ext == TRACE_FILE_EXTENSION);
+        if !is_trace {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("{name:?} is not a trace"),
+            ));
+        }
+        std::fs::read(path)
+    }
+
+    /// Starts capturing a session if a capture of its tenant is armed, it uses up one of the
+    /// sessions of the capture.
+    pub fn start(
+        &self,
+        tenant: &TenantKey,
+        session_id: u64,
+        handshake: &HandshakeResponse,
+    ) -> Option<SessionCapture> {
+        let dir = self.dir.as_ref()?;
+        let max_bytes = {
+            let mut armed = self.armed.get_mut(tenant)?;
+            armed.sessions -= 1;
+            armed.max_bytes
+        };
+        self.armed.remove_if(tenant, |_, armed| armed.sessions == 0);
+        let label = tenant_label(tenant)
+            .chars()
+            .map(|c| {
+                if c.is_alphanumeric() || c == '-' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect::<String>();
+        let name = format!(
+            "{label}-{session_id}-{}.{TRACE_FILE_EXTENSION}",
+            Utc::now().timestamp_millis()
+        );
+        info!("ProxySrv session {session_id} captured into {name}");
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(write_trace(dir.join(name), rx));
+        let mut trace = TraceWriter {
+            started: Instant::now(),
+            max_bytes,
+            written: 0,
+            redacting: false,
+            truncated: false,
+            tx,
+        };
+        trace.write(trace_header(tenant, session_id, handshake));
+        Some(SessionCapture {
+            trace: Arc::new(Mutex::new(trace)),
+        })
+    }
+}
+
+/// The session and handshake of a trace, the auth response left out.
+fn trace_header(tenant: &TenantKey, session_id: u64, handshake: &HandshakeResponse) -> String {
+    let text = |bytes: &Option<Vec<u8>>| {
+        bytes
+            .as_deref()
+            .map(|bytes| String::from_utf8_lossy(bytes).into_owned())
+    };
+    format!(
+        "# tenant={} session={session_id} user={} started={}\n\
+         # client_flag={:#010x} collation={} max_packet_len={} database={:?} auth_plugin={:?} \
+         auth_response=<redacted {} bytes>\n\
+         # connect_attributes={:?}\n",
+        tenant_label(tenant),
+        handshake.client_user_string(),
+        Utc::now().to_rfc3339(),
+        handshake.client_flag.bits(),
+        handshake.collation,
+        handshake.max_packet_len,
+        text(&handshake.database),
+        String::from_utf8_lossy(&handshake.auth_plugin),
+        handshake.auth_response.len(),
+        handshake.connect_attributes,
+    )
+}
+
+async fn write_trace(path: PathBuf, mut rx: mpsc::UnboundedReceiver<String>) {
+    let mut file = match tokio::fs::File::create(&path).await {
+        Ok(file) => file,
+        Err(e) => {
+            warn!("ProxySrv packet capture open {path:?} failed {e:?}");
+            return;
+        }
+    };
+    while let Some(line) = rx.recv().await {
+        if let Err(e) = file.write_all(line.as_bytes()).await {
+            warn!("ProxySrv packet capture write {path:?} failed {e:?}");
+            return;
+        }
+    }
+    let _ = file.flush().await;
+}
+
+/// Who sent a captured packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Client,
+    Backend,
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Direction::Client => write!(f, "client"),
+            Direction::Backend => write!(f, "backend"),
+        }
+    }
+}
+
+struct TraceWriter {
+    started: Instant,
+    max_bytes: u64,
+    written: u64,
+    /// Set from a COM_CHANGE_USER of the client until the backend ends its authentication.
+    redacting: bool,
+    truncated: bool,
+    tx: mpsc::UnboundedSender<String>,
+}
+
+impl TraceWriter {
+    fn write(&mut self, line: String) {
+        if self.truncated {
+            return;
+        }
+        let line = if self.written + line.len() as u64 > self.max_bytes {
+            self.truncated = true;
+            format!("# truncated at {} bytes\n", self.max_bytes)
+        } else {
+            line
+        };
+        self.written += line.len() as u64;
+        let _ = self.tx.send(line);
+    }
+
+    fn record(&mut self, direction: Direction, seq: u8, payload: &[u8]) {
+        let change_user = direction == Direction::Client
+            && payload.first() == Some(&(CommandCode::ComChangeUser as u8));
+        let auth_end = direction == Direction::Backend
+            && self.redacting
+            && matches!(payload.first(), Some(0x00 | 0xff));
+        self.redacting = (self.redacting || change_user) && !auth_end;
+        let data = if change_user {
+            format!("{} <redacted>", hex::encode(&payload[..1]))
+        } else if self.redacting {
+            "<redacted>".to_string()
+        } else {
+            hex::encode(payload)
+        };
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let line = format!(
+            "+{elapsed:.6}s {direction} seq={seq} len={} {data}\n",
+            payload.len()
+        );
+        self.write(line);
+    }
+}
+
+/// The trace of a captured session, its hooks record the packets read from the client and the
+/// backend.
+pub struct SessionCapture {
+    trace: Arc<Mutex<TraceWriter>>,
+}
+
+impl SessionCapture {
+    pub fn hook(&self, direction: Direction) -> PacketHook {
+        let trace = Arc::clone(&self.trace);
+        Arc::new(move |seq, payload| trace.lock().unwrap().record(direction, seq, payload))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::test_tenant_key;
+    use crate::protocol::mysql::basic::HandshakeResponse;
+    use crate::server::packet_capture::{CaptureRequest, Direction, PacketCapture};
+    use mysql_common::constants::CapabilityFlags;
+    use std::time::Duration;
+
+    #[tokio::test]
+    pub async fn test_packet_capture() {
+        let dir = std::env::temp_dir().join(format!("{}-packet-capture", std::process::id()));
+        let tenant = test_tenant_key();
+        let handshake = HandshakeResponse {
+            client_flag: CapabilityFlags::CLIENT_PROTOCOL_41,
+            max_packet_len: 0,
+            collation: 45,
+            tenant_key: None,
+            username: Some(b"app".to_vec()),
+            auth_response: b"scrambled-secret".to_vec(),
+            auth_plugin: b"mysql_native_password".to_vec(),
+            database: None,
+            connect_attributes: None,
+            shard: None,
+            identity: None,
+            reconnect: None,
+            reconnect_token: None,
+            server_name: None,
+        };
+        let request = CaptureRequest {
+            tenant: tenant.clone(),
+            sessions: 1,
+            max_bytes: 4096,
+        };
+        assert!(PacketCapture::new(None).arm(request.clone()).is_err());
+        let capture = PacketCapture::new(Some(dir.clone()));
+        assert!(capture.start(&tenant, 1, &handshake).is_none());
+        capture.arm(request).unwrap();
+        assert_eq!(capture.status().unwrap().armed.len(), 1);
+
+        let session = capture.start(&tenant, 7, &handshake).unwrap();
+        // The capture of a single session is used up.
+        assert!(capture.start(&tenant, 8, &handshake).is_none());
+        assert!(capture.status().unwrap().armed.is_empty());
+        let (client, backend) = (
+            session.hook(Direction::Client),
+            session.hook(Direction::Backend),
+        );
+        client(0, b"\x03select 1");
+        backend(1, b"\x00\x00\x00\x02\x00\x00\x00");
+        client(0, b"\x11app\x00\x14secret-auth-response");
+        backend(1, b"\xfemysql_native_password\x00scramble");
+        client(2, b"auth-switch-response");
+        backend(3, b"\x00\x00\x00\x02\x00\x00\x00");
+        client(0, &[b'x'; 4096]);
+        client(0, b"\x01");
+        drop((session, client, backend));
+
+        // The trace is written in the background, its last line marks the size bound.
+        let mut trace = String::new();
+        for _ in 0..50 {
+            let traces = capture.status().unwrap().traces;
+            if let Some(name) = traces.first().map(|trace| trace.name.clone()) {
+                trace = String::from_utf8(capture.read_trace(&name).unwrap()).unwrap();
+            }
+            if trace.ends_with("bytes\n") {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let lines = trace.lines().collect::<Vec<_>>();
+        assert!(lines[1].contains("auth_response=<redacted 16 bytes>"));
+        assert!(lines[3].ends_with("client seq=0 len=9 0373656c6563742031"));
+        assert!(lines[5].ends_with("client seq=0 len=26 11 <redacted>"));
+        assert!(lines[6].ends_with("backend seq=1 len=31 <redacted>"));
+        assert!(lines[7].ends_with("client seq=2 len=20 <redacted>"));
+        assert!(lines[8].ends_with("backend seq=3 len=7 00000002000000"));
+        assert_eq!(lines[9], "# truncated at 4096 bytes");
+        assert_eq!(lines.len(), 10);
+        assert!(!trace.contains(&hex::encode("secret")));
+        assert!(capture.read_trace("../../etc/passwd").is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
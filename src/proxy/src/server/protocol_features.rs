@@ -0,0 +1,185 @@
+use common::metrics::metric_def::PROXY_PROTOCOL_FEATURE;
+use common::metrics::{common_labels, gauge};
+use mysql_common::constants::CapabilityFlags;
+use serde::Serialize;
+use std::sync::{OnceLock, RwLock};
+use tracing::info;
+
+pub const BINLOG_PASSTHROUGH: &str = "binlog_passthrough";
+
+/// A protocol feature and whether the proxy serves it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ProtocolFeature {
+    pub name: &'static str,
+    /// The capability advertised to clients for the feature, `None` if it is not negotiated in
+    /// the handshake.
+    #[serde(skip)]
+    pub capability: Option<CapabilityFlags>,
+    /// Whether the proxy has a handler for the feature. A listener must not advertise the
+    /// capability of an unhandled feature, nor the configuration enable it.
+    pub handled: bool,
+    pub enabled: bool,
+    /// The configuration key enabling the feature, if any.
+    #[serde(skip)]
+    pub config_key: Option<&'static str>,
+    pub note: &'static str,
+}
+
+impl ProtocolFeature {
+    pub fn new(name: &'static str, handled: bool, enabled: bool, note: &'static str) -> Self {
+        Self {
+            name,
+            capability: None,
+            handled,
+            enabled,
+            config_key: None,
+            note,
+        }
+    }
+
+    pub fn with_capability(mut self, capability: CapabilityFlags) -> Self {
+        self.capability = Some(capability);
+        self
+    }
+
+    pub fn with_config_key(mut self, config_key: &'static str) -> Self {
+        self.config_key = Some(config_key);
+        self
+    }
+
+    /// Enabled without a handler, the proxy would accept traffic it cannot serve.
+    pub fn is_broken(&self) -> bool {
+        self.enabled && !self.handled
+    }
+
+    fn labels(&self) -> Vec<(&'static str, String)> {
+        let mut labels = common_labels().clone();
+        labels.push(("feature", self.name.to_string()));
+        labels.push(("handled", self.handled.to_string()));
+        labels.push(("enabled", self.enabled.to_string()));
+        labels
+    }
+}
+
+/// The features whose capability is in `advertised` but that the proxy does not handle.
+pub fn unhandled_capabilities(
+    features: &[ProtocolFeature],
+    advertised: CapabilityFlags,
+) -> Vec<&ProtocolFeature> {
+    features
+        .iter()
+        .filter(|feature| !feature.handled)
+        .filter(|feature| {
+            feature
+                .capability
+                .is_some_and(|cap| advertised.intersects(cap))
+        })
+        .collect()
+}
+
+/// `ProtocolFeatures` is the support matrix of the proxy: every protocol feature registers
+/// whether it is handled and enabled, and is exported as an info metric, so a configuration that
+/// silently breaks a feature is visible, or refused at startup.
+#[derive(Default)]
+pub struct ProtocolFeatures {
+    features: RwLock<Vec<ProtocolFeature>>,
+}
+
+static PROTOCOL_FEATURES_ONCE: OnceLock<ProtocolFeatures> = OnceLock::new();
+
+/// Registers the features of the configuration, must be called before the first client connects.
+pub fn init_protocol_features(features: Vec<ProtocolFeature>) -> &'static ProtocolFeatures {
+    let registry = protocol_features();
+    for feature in features {
+        registry.register(feature);
+    }
+    registry
+}
+
+pub fn protocol_features() -> &'static ProtocolFeatures {
+    PROTOCOL_FEATURES_ONCE.get_or_init(ProtocolFeatures::default)
+}
+
+impl ProtocolFeatures {
+    /// Registers `feature`, replacing the one of the same name.
+    pub fn register(&self, feature: ProtocolFeature) {
+        info!(
+            "ProxySrv protocol feature {} handled={} enabled={}",
+            feature.name, feature.handled, feature.enabled
+        );
+        let mut features = self.features.write().unwrap();
+        gauge(PROXY_PROTOCOL_FEATURE, 1.0, Some(&feature.labels()));
+        match features.iter_mut().find(|f| f.name == feature.name) {
+            Some(registered) => {
+                if registered.labels() != feature.labels() {
+                    gauge(PROXY_PROTOCOL_FEATURE, 0.0, Some(&registered.labels()));
+                }
+                *registered = feature;
+            }
+            None => features.push(feature),
+        }
+    }
+
+    /// Updates the state of a registered feature, e.g. once the control plane enabled it for a
+    /// tenant.
+    pub fn set_enabled(&self, name: &str, enabled: bool) {
+        let registered = self.get(name);
+        if let Some(feature) = registered.filter(|feature| feature.enabled != enabled) {
+            self.register(ProtocolFeature { enabled, ..feature });
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<ProtocolFeature> {
+        self.features
+            .read()
+            .unwrap()
+            .iter()
+            .find(|feature| feature.name == name)
+            .cloned()
+    }
+
+    pub fn list(&self) -> Vec<ProtocolFeature> {
+        self.features.read().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::server::default_capabilities;
+    use crate::server::protocol_features::{
+        unhandled_capabilities, ProtocolFeature, ProtocolFeatures,
+    };
+    use mysql_common::constants::CapabilityFlags;
+
+    #[test]
+    pub fn test_protocol_features() {
+        let features = vec![
+            ProtocolFeature::new("cursors", true, true, ""),
+            ProtocolFeature::new("local_infile", false, false, "")
+                .with_capability(CapabilityFlags::CLIENT_LOCAL_FILES),
+            ProtocolFeature::new("tls", false, true, "").with_config_key("tls"),
+        ];
+        assert!(unhandled_capabilities(&features, default_capabilities()).is_empty());
+        let unhandled = unhandled_capabilities(
+            &features,
+            default_capabilities() | CapabilityFlags::CLIENT_LOCAL_FILES,
+        );
+        assert_eq!(unhandled[0].name, "local_infile");
+        assert!(features[2].is_broken());
+
+        let registry = ProtocolFeatures::default();
+        for feature in features {
+            registry.register(feature);
+        }
+        registry.register(ProtocolFeature::new("cursors", true, false, "off"));
+        assert_eq!(registry.list().len(), 3);
+        assert!(!registry.get("cursors").unwrap().enabled);
+        registry.set_enabled("cursors", true);
+        assert_eq!(
+            registry.get("cursors").unwrap(),
+            ProtocolFeature::new("cursors", true, true, "off")
+        );
+        registry.set_enabled("compression", true);
+        assert!(registry.get("compression").is_none());
+    }
+}
@@ -0,0 +1,112 @@
+use crate::prost::common_proto::TenantKey;
+use crate::protocol::mysql::error_codes::ErrorKind;
+use crate::protocol::mysql::packet::compress::{InflateBudget, InflateLimits};
+use crate::protocol::mysql::packet::packet_reader::PacketTooLarge;
+use crate::protocol::mysql::packet::packet_writer::PacketWriter;
+use crate::protocol::mysql::packet::writers;
+use crate::server::recent_errors::recent_errors;
+use crate::server::slow_log::tenant_label;
+
+use common::metrics::metric_def::PROXY_PROTOCOL_LIMIT_EXCEEDED;
+use common::metrics::{common_labels, counter_inc};
+use mysql_common::constants::CapabilityFlags;
+use std::io::Error;
+use std::sync::Arc;
+use tokio::io::AsyncWrite;
+use tracing::warn;
+
+/// Caps the work a session makes the proxy do per packet, so compression bombs or endless result
+/// sets cannot monopolize a worker.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ProtocolLimits {
+    pub inflate: InflateLimits,
+    /// Packets relayed to a client before the proxy flushes them and yields, 0 disables the
+    /// budget.
+    pub max_unflushed_packets: usize,
+    /// Bytes of the largest packet a client may send, 0 means unlimited. The packet headers
+    /// announce its length, a larger packet is refused before it is buffered.
+    pub max_packet_size: usize,
+}
+
+impl ProtocolLimits {
+    /// The inflate budget of the `leg` of a session of `tenant`, `None` if inflating is unlimited.
+    /// Exceeding it fails the session, and flags the tenant in the metrics and recent errors.
+    pub fn inflate_budget(&self, tenant: &TenantKey, leg: &'static str) -> Option<InflateBudget> {
+        if self.inflate.is_unlimited() {
+            return None;
+        }
+        let tenant = tenant_label(tenant);
+        let on_exceeded =
+            Arc::new(move |limit: &'static str| record_limit_exceeded(&tenant, leg, limit));
+        Some(InflateBudget::new(self.inflate, Some(on_exceeded)))
+    }
+}
+
+/// Flags the `tenant` whose session exceeded `limit` on the `leg` in the metrics and recent
+/// errors.
+fn record_limit_exceeded(tenant: &str, leg: &'static str, limit: &'static str) {
+    let mut labels = common_labels().clone();
+    labels.push(("tenant", tenant.to_string()));
+    labels.push(("leg", leg.to_string()));
+    labels.push(("limit", limit.to_string()));
+    counter_inc(PROXY_PROTOCOL_LIMIT_EXCEEDED, 1, Some(&labels));
+    recent_errors().record(
+        "protocol_limit",
+        format!("tenant {tenant} exceeded {limit} on the {leg} leg"),
+    );
+}
+
+/// Answers a client whose packet exceeded the max packet size with ER_NET_PACKET_TOO_LARGE, the
+/// way the server does. The rest of the packet is never read, the session is closed after it.
+pub async fn reject_packet_too_large<W>(
+    tenant: &TenantKey,
+    too_large: &PacketTooLarge,
+    client_writer: &mut PacketWriter<W>,
+    client_capabilities: CapabilityFlags,
+) -> Result<(), Error>
+where
+    W: AsyncWrite + Send + Unpin,
+{
+    warn!("ProxySrv client of {:?} sent a {too_large}", tenant);
+    record_limit_exceeded(&tenant_label(tenant), "client", "max_packet_size");
+    client_writer.set_seq(too_large.seq.wrapping_add(1));
+    writers::write_err_packet(
+        ErrorKind::ER_NET_PACKET_TOO_LARGE,
+        b"Got a packet bigger than 'max_allowed_packet' bytes",
+        client_writer,
+        client_capabilities,
+    )
+    .await?;
+    client_writer.flush_all().await
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::test_tenant_key;
+    use crate::protocol::mysql::packet::compress::InflateLimits;
+    use crate::server::protocol_limits::ProtocolLimits;
+    use crate::server::recent_errors::recent_errors;
+
+    #[test]
+    pub fn test_inflate_budget() {
+        let tenant = test_tenant_key();
+        let limits = ProtocolLimits::default();
+        assert!(limits.inflate_budget(&tenant, "client").is_none());
+
+        let limits = ProtocolLimits {
+            inflate: InflateLimits {
+                max_bytes_per_command: 1024,
+                max_bytes_per_sec: 0,
+            },
+            max_unflushed_packets: 0,
+            max_packet_size: 0,
+        };
+        let mut budget = limits.inflate_budget(&tenant, "backend").unwrap();
+        budget.charge(1024).unwrap();
+        assert!(budget.charge(1).is_err());
+        assert!(recent_errors()
+            .list()
+            .iter()
+            .any(|error| error.source == "protocol_limit"));
+    }
+}
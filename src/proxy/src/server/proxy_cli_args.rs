@@ -1,14 +1,48 @@
+use crate::audit::AuditSink;
 use crate::backend::backend_mgr::BackendManagerOptions;
+use crate::backend::egress::EgressConfig;
+use crate::backend::pool::{BackendPoolConfig, PoolHealthCheck, PoolWarmup};
+use crate::backend::quarantine::QuarantineConfig;
 use crate::backend::router::{BackendLoadBalancerType, BackendRouterType};
 use crate::backend::BackendInstance;
+use crate::bench::BenchArgs;
+use crate::client::EncodeTenantArgs;
 use crate::prost::common_proto::{ClusterName, DBLocation, ServiceStatus};
+use crate::protocol::mysql::constants::CommandCode;
+use crate::protocol::mysql::packet::compress::InflateLimits;
+use crate::protocol::mysql::packet::packet_writer::Watermarks;
+use crate::server::acme::solver::{ChallengeSolver, DnsHookSolver, Http01Solver, DNS_01, HTTP_01};
+use crate::server::acme::{acme_cert_resolver, AcmeOptions, LETS_ENCRYPT_DIRECTORY};
+use crate::server::auth::client_acl::ClientAclRules;
+use crate::server::auth::reconnect_token::ReconnectTokenConfig;
+use crate::server::auth_limiter::AuthLimits;
+use crate::server::billing::BillingConfig;
+use crate::server::client_tls::ClientTlsOptions;
+use crate::server::command_policy::parse_command_code;
+use crate::server::handshake_profile::{HandshakeProfile, PROXY_LISTENER, TUNNEL_LISTENER};
+use crate::server::long_data::LongDataLimits;
+use crate::server::notifier::NotifierConfig;
+use crate::server::protocol_features::{ProtocolFeature, BINLOG_PASSTHROUGH};
+use crate::server::protocol_limits::ProtocolLimits;
+use crate::server::proxy_protocol::TrustedProxies;
+use crate::server::rate_limit::RateLimit;
+use crate::server::reload::RuntimeConfig;
+use crate::server::route_policy::{PolicyEngine, RoutePolicyConfig};
+use crate::server::slow_log::SlowLogFile;
+use crate::server::sql_privacy::SqlExport;
+use crate::server::startup_report::{ListenerReport, StartupReport};
+use crate::server::watchdog::WatchdogConfig;
 
 use clap::{Parser, Subcommand};
 use itertools::Itertools;
+use mysql_common::constants::CapabilityFlags;
+use serde::{Deserialize, Serialize};
 use std::collections::VecDeque;
 use std::ops::Deref;
+use std::path::PathBuf;
 use std::str::FromStr;
-use std::sync::LazyLock;
+use std::sync::{Arc, LazyLock};
+use std::time::Duration;
 
 pub static TEST_BACKEND_ADDRS: LazyLock<VecDeque<BackendInstance>> = LazyLock::new(|| {
     VecDeque::from(vec![BackendInstance {
@@ -24,7 +58,27 @@ pub static TEST_BACKEND_ADDRS: LazyLock<VecDeque<BackendInstance>> = LazyLock::n
     }])
 });
 
-#[derive(Parser, Default, Debug, Clone)]
+fn command_code_arg(name: &str) -> Result<CommandCode, String> {
+    parse_command_code(name).ok_or_else(|| format!("unknown command {name:?}"))
+}
+
+/// Checks an argument kept as text, for the configuration keys, parses as a `T`. A bad value is
+/// then an argument error rather than a failure once the configuration is applied.
+fn checked_arg<T>(spec: &str) -> Result<String, String>
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    spec.parse::<T>()
+        .map(|_| spec.to_string())
+        .map_err(|e| e.to_string())
+}
+
+/// The proxy configuration. Every argument is also a key of the configuration file, the
+/// environment and the control plane overrides, see [`load_proxy_config`].
+///
+/// [`load_proxy_config`]: crate::server::proxy_config::load_proxy_config
+#[derive(Parser, Default, Debug, Clone, Serialize, Deserialize)]
 #[clap(
     name = "my-proxy",
     version = "0.1.0",
@@ -39,14 +93,88 @@ pub struct ProxyServerArgs {
     pub http_port: u16,
     #[clap(long, value_name = "TLS", default_value_t = false)]
     pub tls: bool,
+    /// Domains of the client listener certificate ordered from an ACME server, the first is its
+    /// common name. Empty disables ACME.
+    #[clap(long, value_name = "ACME_DOMAIN", value_delimiter = ',')]
+    pub acme_domains: Vec<String>,
+    #[clap(long, value_name = "ACME_DIRECTORY", default_value = LETS_ENCRYPT_DIRECTORY)]
+    pub acme_directory: String,
+    /// Email of the ACME account, for the expiry notices of the ACME server.
+    #[clap(long, value_name = "ACME_CONTACT")]
+    pub acme_contact: Option<String>,
+    /// `http-01`, answered by the REST API, or `dns-01`, answered by `acme_dns_hook`.
+    #[clap(
+        long,
+        value_name = "ACME_CHALLENGE",
+        value_parser = [HTTP_01, DNS_01],
+        default_value = HTTP_01
+    )]
+    pub acme_challenge: String,
+    /// Executable run as `hook present|cleanup <name> <value>` to create and remove the TXT
+    /// records of `dns-01` challenges.
+    #[clap(
+        long,
+        value_name = "ACME_DNS_HOOK",
+        required_if_eq("acme_challenge", DNS_01)
+    )]
+    pub acme_dns_hook: Option<PathBuf>,
+    /// ACME certificates are renewed once they expire within this many days.
+    #[clap(long, value_name = "ACME_RENEW_BEFORE_DAYS", default_value_t = 30)]
+    pub acme_renew_before_days: u64,
+    /// Domain of the hostnames TLS clients connect to, `<cluster>-<namespace>.<domain>` routes
+    /// them to that tenant whatever their user name. Requires `tls`.
+    #[clap(long, value_name = "SNI_TENANT_DOMAIN")]
+    pub sni_tenant_domain: Option<String>,
+    /// Directory of the secrets the proxy keeps, e.g. the ACME account and certificate.
+    #[clap(long, value_name = "SECRETS_DIR", default_value = "secrets")]
+    pub secrets_dir: PathBuf,
+    /// Accepts MySQL tunneled over WebSocket or HTTP CONNECT on this port.
+    #[clap(long, value_name = "TUNNEL_PORT")]
+    pub tunnel_port: Option<u16>,
+    /// Accepts connections redirected to the client port by iptables or eBPF, routed to the
+    /// tenant of their original destination. Linux only.
+    #[clap(long, default_value_t = false)]
+    pub transparent: bool,
+    /// Expects a PROXY protocol v1 or v2 header on the client connections of the load balancers
+    /// in front of the proxy, see `proxy_protocol_trusted_cidrs`. Its source address stands for
+    /// the client, e.g. for the client ACLs and the session list.
+    #[clap(long, default_value_t = false)]
+    pub proxy_protocol: bool,
+    /// CIDRs or IP addresses of the load balancers trusted to send a PROXY header. Other peers
+    /// connect directly, their own address stands for the client.
+    #[clap(
+        long,
+        value_name = "PROXY_PROTOCOL_TRUSTED_CIDR",
+        value_delimiter = ','
+    )]
+    pub proxy_protocol_trusted_cidrs: Vec<String>,
+    /// What the client listener advertises in the handshake, e.g.
+    /// `version=8.0.36,collation=45,disable=CLIENT_LOCAL_FILES|CLIENT_COMPRESS`.
+    #[clap(
+        long,
+        value_name = "HANDSHAKE_PROFILE",
+        value_parser = checked_arg::<HandshakeProfile>
+    )]
+    pub handshake_profile: Option<String>,
+    /// What the tunnel listener advertises in the handshake, same format as `handshake_profile`.
+    #[clap(
+        long,
+        value_name = "TUNNEL_HANDSHAKE_PROFILE",
+        value_parser = checked_arg::<HandshakeProfile>
+    )]
+    pub tunnel_handshake_profile: Option<String>,
     #[clap(long, value_name = "ENABLE METRICS COLLECTOR", default_value_t = false)]
     pub enable_metrics: bool,
     #[clap(long, value_name = "ENABLE REST API", default_value_t = false)]
     pub enable_rest: bool,
+    /// Serves the `/fault` endpoints of the REST API, for chaos testing only.
+    #[clap(long, default_value_t = false)]
+    pub enable_fault_injection: bool,
     #[clap(long, value_name = "ROUTE_NAME")]
     pub router: Option<String>,
     #[clap(long, value_name = "BALANCE")]
     pub balance: Option<String>,
+    /// Reloadable, see `POST /admin/reload`.
     #[clap(long, value_name = "LOG_LEVEL")]
     pub log_level: Option<String>,
     #[clap(long, value_name = "Control Plane Grpc Address")]
@@ -55,18 +183,286 @@ pub struct ProxyServerArgs {
     pub curr_node: Option<String>,
     #[clap(long, value_name = "NAMESPACE")]
     pub namespace: Option<String>,
+    /// Queries slower than this are kept in the slow log, see `SHOW PROXY SLOWLOG`. Reloadable.
+    #[clap(long, value_name = "SLOW_QUERY_MS", default_value_t = 1000)]
+    pub slow_query_ms: u64,
+    /// Number of slow queries kept in memory, 0 disables the slow log.
+    #[clap(long, value_name = "SLOW_LOG_CAPACITY", default_value_t = 128)]
+    pub slow_log_capacity: usize,
+    /// Appends a JSON line per slow query to this file instead of logging it to the
+    /// `slow_query` tracing target.
+    #[clap(long, value_name = "SLOW_LOG_PATH")]
+    pub slow_log_path: Option<PathBuf>,
+    /// The slow log file is rotated once it reaches this size.
+    #[clap(long, value_name = "SLOW_LOG_MAX_MB", default_value_t = 64)]
+    pub slow_log_max_mb: u64,
+    /// How SQL text leaves the proxy, e.g. in the slow log, for the tenants without a setting of
+    /// their own: `raw`, `normalized` with the literals stripped, or `off`.
+    #[clap(
+        long,
+        value_name = "SQL_EXPORT",
+        value_parser = checked_arg::<SqlExport>,
+        default_value = "normalized"
+    )]
+    pub sql_export: String,
+    /// Statement fingerprints tracked over all the tenants, whose statements are counted in
+    /// metrics labelled with the fingerprint; the others count as `other`. 0 disables them.
+    #[clap(long, value_name = "QUERY_DIGEST_CAPACITY", default_value_t = 0)]
+    pub query_digest_capacity: usize,
+    /// Command latency samples queued for the aggregator task recording them into histograms,
+    /// 0 records them on the command path.
+    #[clap(long, value_name = "COM_LATENCY_QUEUE", default_value_t = 0)]
+    pub com_latency_queue: usize,
+    /// Prepared statements cached per backend connection, 0 disables the cache.
+    #[clap(long, value_name = "STMT_CACHE_SIZE", default_value_t = 0)]
+    pub stmt_cache_size: usize,
+    /// Backend addresses whose connections use protocol compression, `*` for every backend.
+    /// Clients stay uncompressed.
+    #[clap(long, value_name = "BACKEND_ADDR", value_delimiter = ',')]
+    pub backend_compress: Vec<String>,
+    /// Backend addresses whose connections start with a PROXY protocol v2 header, `*` for every
+    /// backend. Pooled connections serve many clients, the header names the proxy.
+    #[clap(long, value_name = "BACKEND_ADDR", value_delimiter = ',')]
+    pub backend_proxy_protocol: Vec<String>,
+    /// Backends of the static router, in place of those of the `backend` command. Reloadable,
+    /// the pools of the backends removed are closed.
+    #[clap(long, value_name = "BACKEND_ADDR", value_delimiter = ',')]
+    pub static_backends: Vec<String>,
+    /// Commands rejected for every tenant unless a tenant policy allows them, e.g. `ComDropDB`.
+    #[clap(
+        long,
+        value_name = "COMMAND",
+        value_delimiter = ',',
+        value_parser = command_code_arg,
+        default_value = "ComCreateDB,ComDropDB,ComShutdown,ComBinlogDump"
+    )]
+    pub deny_commands: Vec<CommandCode>,
+    /// Replicas lagging more than this are skipped for reads, unless a tenant threshold is set.
+    #[clap(long, value_name = "MAX_REPLICA_LAG_MS", default_value_t = 5000)]
+    pub max_replica_lag_ms: u64,
+    /// Consecutive connect, authentication or early close failures that quarantine a backend,
+    /// 0 disables quarantine.
+    #[clap(long, value_name = "QUARANTINE_THRESHOLD", default_value_t = 5)]
+    pub quarantine_threshold: u32,
+    #[clap(long, value_name = "QUARANTINE_SECS", default_value_t = 30)]
+    pub quarantine_secs: u64,
+    /// Connections a backend pool holds at most. Reloadable, the pools are resized.
+    #[clap(long, value_name = "POOL_MAX_SIZE", default_value_t = 50)]
+    pub pool_max_size: u32,
+    /// Connections opened in the background when the pool of a Ready backend is created, 0
+    /// creates the pools empty.
+    #[clap(long, value_name = "POOL_INITIAL_SIZE", default_value_t = 0)]
+    pub pool_initial_size: u32,
+    /// Connections opened per Ready backend at startup, 0 disables the warm-up.
+    #[clap(long, value_name = "POOL_WARMUP_CONNS", default_value_t = 0)]
+    pub pool_warmup_conns: u32,
+    /// Backends warmed up at the same time.
+    #[clap(long, value_name = "POOL_WARMUP_PARALLELISM", default_value_t = 8)]
+    pub pool_warmup_parallelism: usize,
+    /// Backends whose status events are applied to the pools at the same time, the events of
+    /// one backend are applied in order.
+    #[clap(long, value_name = "BACKEND_EVENT_PARALLELISM", default_value_t = 8)]
+    pub backend_event_parallelism: usize,
+    /// Time a backend may take to open its warm-up connections.
+    #[clap(long, value_name = "POOL_WARMUP_TIMEOUT_MS", default_value_t = 10000)]
+    pub pool_warmup_timeout_ms: u64,
+    /// Pings the pooled connections idle this long and closes those the backend dropped, 0
+    /// disables the health checks.
+    #[clap(long, value_name = "POOL_HEALTH_CHECK_SECS", default_value_t = 0)]
+    pub pool_health_check_secs: u64,
+    /// Pooled connections idle beyond this are closed by the health checks, 0 keeps them.
+    #[clap(long, value_name = "POOL_MAX_IDLE_SECS", default_value_t = 0)]
+    pub pool_max_idle_secs: u64,
+    /// Time the sessions on a backend marked Offline have to finish before its pool is closed,
+    /// 0 closes it at once.
+    #[clap(long, value_name = "POOL_DRAIN_TIMEOUT_SECS", default_value_t = 30)]
+    pub pool_drain_timeout_secs: u64,
+    /// CIDRs, IP addresses or host names (`*.example.com` for subdomains) the proxy may connect
+    /// to as control plane or backend. Empty allows every target.
+    #[clap(long, value_name = "EGRESS_ALLOW", value_delimiter = ',')]
+    pub egress_allow: Vec<String>,
+    /// CIDRs or IP addresses of the clients that may connect, empty allows every client not
+    /// denied. Tenants restrict their clients further through the web service.
+    #[clap(long, value_name = "CLIENT_ALLOW", value_delimiter = ',')]
+    pub client_allow: Vec<String>,
+    /// CIDRs or IP addresses of the clients rejected before the handshake.
+    #[clap(long, value_name = "CLIENT_DENY", value_delimiter = ',')]
+    pub client_deny: Vec<String>,
+    /// Long data a backend may buffer per prepared statement, 0 means unlimited.
+    #[clap(long, value_name = "MAX_LONG_DATA_STMT_BYTES", default_value_t = 0)]
+    pub max_long_data_stmt_bytes: u64,
+    /// Long data a backend may buffer per session, 0 means unlimited.
+    #[clap(long, value_name = "MAX_LONG_DATA_SESSION_BYTES", default_value_t = 0)]
+    pub max_long_data_session_bytes: u64,
+    /// Idle pooled connections are shed once the process RSS exceeds this, 0 disables the check.
+    #[clap(long, value_name = "WATCHDOG_MAX_RSS_MB", default_value_t = 0)]
+    pub watchdog_max_rss_mb: u64,
+    /// Idle pooled connections are closed once the open FDs exceed this percentage of the FD
+    /// limit, 0 disables the check.
+    #[clap(long, value_name = "WATCHDOG_MAX_FDS_PERCENT", default_value_t = 90)]
+    pub watchdog_max_fds_percent: u64,
+    #[clap(long, value_name = "WATCHDOG_INTERVAL_SECS", default_value_t = 5)]
+    pub watchdog_interval_secs: u64,
+    /// Posts a JSON webhook to this URL on significant proxy state changes.
+    #[clap(long, value_name = "NOTIFY_WEBHOOK")]
+    pub notify_webhook: Option<String>,
+    /// Webhook body template, e.g. `{"text":"{{kind}} {{subject}}: {{message}}"}`.
+    #[clap(long, value_name = "NOTIFY_TEMPLATE")]
+    pub notify_template: Option<String>,
+    /// Creates K8s Events for the proxy pod on significant proxy state changes.
+    #[clap(long, default_value_t = false)]
+    pub notify_k8s_events: bool,
+    /// At most one notification per event kind and subject is sent within this interval.
+    #[clap(long, value_name = "NOTIFY_MIN_INTERVAL_SECS", default_value_t = 60)]
+    pub notify_min_interval_secs: u64,
+    /// Appends a JSON line with the usage of every ended client session to this file.
+    #[clap(long, value_name = "BILLING_JSONL")]
+    pub billing_jsonl: Option<PathBuf>,
+    /// Keeps the usage of ended client sessions for the control plane to pull.
+    #[clap(long, default_value_t = false)]
+    pub billing_control_plane: bool,
+    /// Where the authentication and connection events of the clients go: `stdout`,
+    /// `file:<PATH>` for JSON lines appended to a file, or `control-plane` for the control plane
+    /// to pull. There is no audit log if not set.
+    #[clap(long, value_name = "AUDIT_SINK", value_parser = checked_arg::<AuditSink>)]
+    pub audit_sink: Option<String>,
+    /// Directory of the packet traces captured for support cases, capture is off if not set.
+    #[clap(long, value_name = "SUPPORT_DIR")]
+    pub support_dir: Option<PathBuf>,
+    /// Results buffered for a client before the proxy stops reading from the backend until the
+    /// client drained them, 0 writes every packet right away.
+    #[clap(long, value_name = "CLIENT_HIGH_WATERMARK", default_value_t = 262144)]
+    pub client_high_watermark: usize,
+    /// Buffered bytes at which reading from the backend resumes.
+    #[clap(long, value_name = "CLIENT_LOW_WATERMARK", default_value_t = 65536)]
+    pub client_low_watermark: usize,
+    /// Answers COM_QUIT with an OK packet, for clients that log an error on a silent close.
+    #[clap(long, default_value_t = false)]
+    pub quit_reply_ok: bool,
+    /// Pings the backend connection of a session idle this long outside a transaction, so NATs
+    /// and the backend `wait_timeout` do not drop it, 0 disables keepalive pings.
+    #[clap(long, value_name = "BACKEND_KEEPALIVE_SECS", default_value_t = 0)]
+    pub backend_keepalive_secs: u64,
+    /// Closes the session of a client that sent no command for this long, like the server
+    /// `wait_timeout`, and resets its backend connection back into the pool. 0 keeps idle
+    /// sessions open.
+    #[clap(long, value_name = "CLIENT_IDLE_TIMEOUT_SECS", default_value_t = 0)]
+    pub client_idle_timeout_secs: u64,
+    /// Hands the backend connection of a session back to the pool between transactions, so
+    /// fewer connections serve more clients. Only sessions of mapped identities are multiplexed,
+    /// they keep their connection once they hold state the proxy cannot replay, e.g. a session
+    /// variable or an open cursor. Prepared statements are prepared again on every connection,
+    /// unless the statement cache is enabled. Functions over the previous statement, e.g.
+    /// `LAST_INSERT_ID()`, need a transaction.
+    #[clap(long, default_value_t = false)]
+    pub multiplexing: bool,
+    /// Closes the pools of a tenant without sessions that did not connect for this long, its
+    /// next connection reopens them. 0 keeps the pools of every tenant open.
+    #[clap(long, value_name = "TENANT_IDLE_TTL_SECS", default_value_t = 0)]
+    pub tenant_idle_ttl_secs: u64,
+    /// On shutdown, time the sessions have to finish their command or transaction before the
+    /// backend pools are closed, 0 closes them right away.
+    #[clap(long, value_name = "SHUTDOWN_DRAIN_SECS", default_value_t = 30)]
+    pub shutdown_drain_secs: u64,
+    /// Signing keys of the reconnect tokens handed to the clients asking for one with the
+    /// `proxy_reconnect_token` connection attribute, one `<key id> <hex secret>` per line, the
+    /// signing key first. Proxies sharing the keys accept the tokens of each other. Tokens are off
+    /// if not set.
+    #[clap(long, value_name = "RECONNECT_TOKEN_KEYS")]
+    pub reconnect_token_keys: Option<PathBuf>,
+    /// How long a reconnect token can be redeemed.
+    #[clap(long, value_name = "RECONNECT_TOKEN_TTL_SECS", default_value_t = 300)]
+    pub reconnect_token_ttl_secs: u64,
+    /// Asks this OPA data API or webhook whether a session may connect and to which backend,
+    /// the policy is off if not set.
+    #[clap(long, value_name = "ROUTE_POLICY_URL")]
+    pub route_policy_url: Option<String>,
+    /// The engine behind the route policy url, opa or webhook.
+    #[clap(
+        long,
+        value_name = "ROUTE_POLICY_ENGINE",
+        value_parser = checked_arg::<PolicyEngine>,
+        default_value = "webhook"
+    )]
+    pub route_policy_engine: String,
+    #[clap(long, value_name = "ROUTE_POLICY_TIMEOUT_MS", default_value_t = 200)]
+    pub route_policy_timeout_ms: u64,
+    /// How long a route policy decision is reused for the same tenant, user and client.
+    #[clap(long, value_name = "ROUTE_POLICY_CACHE_SECS", default_value_t = 60)]
+    pub route_policy_cache_secs: u64,
+    /// Allows the sessions while the route policy engine fails, they are denied otherwise.
+    #[clap(long, default_value_t = false)]
+    pub route_policy_fail_open: bool,
+    /// Sessions in the auth phase at once, from the greeting until the backend authenticated the
+    /// client, 0 means unlimited. Adjustable at runtime through the web service.
+    #[clap(long, value_name = "MAX_AUTH_HANDSHAKES", default_value_t = 0)]
+    pub max_auth_handshakes: usize,
+    /// How long a client beyond the concurrent handshakes waits before it is rejected with a
+    /// retryable error.
+    #[clap(long, value_name = "AUTH_QUEUE_TIMEOUT_MS", default_value_t = 200)]
+    pub auth_queue_timeout_ms: u64,
+    /// Commands per second each user of a tenant may send, 0 means unlimited. Per user limits
+    /// are set through the REST API.
+    #[clap(long, value_name = "MAX_USER_QPS", default_value_t = 0)]
+    pub max_user_qps: u32,
+    /// Commands a user may send at once above the rate, 0 allows one second of commands.
+    #[clap(long, value_name = "USER_QPS_BURST", default_value_t = 0)]
+    pub user_qps_burst: u32,
+    /// How long a command above the rate waits for its turn before it is rejected.
+    #[clap(long, value_name = "USER_QPS_DELAY_MS", default_value_t = 100)]
+    pub user_qps_delay_ms: u64,
+    /// Flags a tenant whose backend list got no change event for this long while its
+    /// connections keep failing, 0 disables the check.
+    #[clap(long, value_name = "TOPOLOGY_STALE_SECS", default_value_t = 600)]
+    pub topology_stale_secs: u64,
+    /// Bytes a session may inflate from compressed packets per command, 0 means unlimited.
+    #[clap(long, value_name = "MAX_INFLATE_COMMAND_BYTES", default_value_t = 0)]
+    pub max_inflate_command_bytes: u64,
+    /// Bytes a session may inflate from compressed packets per second, 0 means unlimited.
+    #[clap(long, value_name = "MAX_INFLATE_BYTES_PER_SEC", default_value_t = 0)]
+    pub max_inflate_bytes_per_sec: u64,
+    /// Packets relayed to a client before they are flushed and the worker yields, 0 disables
+    /// the budget.
+    #[clap(long, value_name = "MAX_UNFLUSHED_PACKETS", default_value_t = 4096)]
+    pub max_unflushed_packets: usize,
+    /// Bytes of the largest packet a client may send, like the server `max_allowed_packet`. A
+    /// client sending a larger one gets ER_NET_PACKET_TOO_LARGE and its session is closed, 0
+    /// means unlimited.
+    #[clap(long, value_name = "MAX_PACKET_SIZE", default_value_t = 67108864)]
+    pub max_packet_size: usize,
+    /// Configuration file, overridden by the environment and the command line. TOML for a
+    /// `.toml` extension, YAML for `.yaml` or `.yml`, JSON otherwise.
+    #[clap(long, value_name = "CONFIG")]
+    #[serde(skip)]
+    pub config: Option<PathBuf>,
+    /// Overrides written by the control plane, applied over every other source. In the format
+    /// of the configuration file.
+    #[clap(long, value_name = "CONFIG_OVERRIDES")]
+    #[serde(skip)]
+    pub config_overrides: Option<PathBuf>,
+    /// Prints the JSON Schema of the configuration file and exits.
+    #[clap(long, default_value_t = false)]
+    #[serde(skip)]
+    pub print_config_schema: bool,
     #[clap(subcommand)]
+    #[serde(skip)]
     pub backend: Option<BackendConfigArgs>,
     #[clap(flatten)]
+    #[serde(flatten)]
     pub cp_args: ControlPlaneArgs,
 }
 
-#[derive(clap::Parser, Debug, Default, Clone)]
+#[derive(clap::Parser, Debug, Default, Clone, Serialize, Deserialize)]
 pub struct ControlPlaneArgs {
     #[clap(long)]
     pub enable_cp: bool,
     #[clap(long)]
     pub cp_target_addr: Option<String>,
+    /// Sessions report one of every this many commands as user activity to the control plane,
+    /// each counting for as many, to bound the overhead of the reporting. 1 reports every
+    /// command.
+    #[clap(long, value_name = "CP_ACTIVITY_SAMPLE_EVERY", default_value_t = 1)]
+    pub cp_activity_sample_every: u32,
 }
 
 impl ControlPlaneArgs {
@@ -97,6 +493,14 @@ pub enum BackendConfigArgs {
         #[clap(long)]
         cluster_name: Option<String>,
     },
+    #[command(
+        long_about = "Runs a load generator against the proxy, or a backend for a baseline, and reports throughput and latency percentiles."
+    )]
+    Bench(BenchArgs),
+    #[command(
+        long_about = "Prints the username that logs a user in to a tenant when the proxy routes by tenant."
+    )]
+    EncodeTenant(EncodeTenantArgs),
 }
 
 impl ProxyServerArgs {
@@ -130,6 +534,331 @@ impl ProxyServerArgs {
                 true
             },
             balance_type: BackendLoadBalancerType::from_str(balancer_type_str.as_str()).unwrap(),
+            status_event_parallelism: self.backend_event_parallelism,
+            multiplexing: self.multiplexing,
+            pool_drain_timeout: Duration::from_secs(self.pool_drain_timeout_secs),
+            pool_config: BackendPoolConfig {
+                initial_size: self.pool_initial_size,
+                max_size: self.pool_max_size,
+                stmt_cache_size: self.stmt_cache_size,
+                compress_backends: self.backend_compress.clone(),
+                proxy_protocol_backends: self.backend_proxy_protocol.clone(),
+                warmup: PoolWarmup {
+                    conns_per_backend: self.pool_warmup_conns,
+                    parallelism: self.pool_warmup_parallelism,
+                    backend_timeout: Duration::from_millis(self.pool_warmup_timeout_ms),
+                },
+                health_check: PoolHealthCheck {
+                    interval: Duration::from_secs(self.pool_health_check_secs),
+                    max_idle: Duration::from_secs(self.pool_max_idle_secs),
+                },
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    /// The part of the configuration a reload applies.
+    pub fn runtime_config(&self) -> RuntimeConfig {
+        RuntimeConfig {
+            log_level: self.log_level.clone(),
+            pool_max_size: self.pool_max_size,
+            slow_query_ms: self.slow_query_ms,
+            static_backends: self.static_backend_list(),
+            request_id: None,
+        }
+    }
+
+    pub fn long_data_limits(&self) -> LongDataLimits {
+        LongDataLimits {
+            max_stmt_bytes: self.max_long_data_stmt_bytes,
+            max_session_bytes: self.max_long_data_session_bytes,
+        }
+    }
+
+    /// The support matrix of the protocol features as configured.
+    pub fn protocol_features(&self) -> Vec<ProtocolFeature> {
+        vec![
+            ProtocolFeature::new(
+                "compression",
+                false,
+                false,
+                "the client leg is uncompressed, see backend_compression",
+            )
+            .with_capability(CapabilityFlags::CLIENT_COMPRESS),
+            ProtocolFeature::new(
+                "backend_compression",
+                true,
+                !self.backend_compress.is_empty(),
+                "the compressed protocol with the backend_compress backends",
+            )
+            .with_config_key("backend_compress"),
+            ProtocolFeature::new(
+                "query_attributes",
+                false,
+                false,
+                "query attributes are not relayed to the backend",
+            )
+            .with_capability(CapabilityFlags::CLIENT_QUERY_ATTRIBUTES),
+            ProtocolFeature::new(
+                "cursors",
+                true,
+                true,
+                "COM_STMT_FETCH is relayed to the backend of the session",
+            ),
+            ProtocolFeature::new(
+                "local_infile",
+                false,
+                false,
+                "LOAD DATA LOCAL INFILE requests are not relayed",
+            )
+            .with_capability(CapabilityFlags::CLIENT_LOCAL_FILES),
+            ProtocolFeature::new(
+                BINLOG_PASSTHROUGH,
+                true,
+                false,
+                "binlog streamed to the replicas of the tenants whose command policy enables replication",
+            ),
+            ProtocolFeature::new(
+                "caching_sha2",
+                true,
+                true,
+                "relayed after an auth switch, full authentication through the RSA key of the backend",
+            ),
+            ProtocolFeature::new(
+                "tls",
+                cfg!(feature = "tls"),
+                self.tls,
+                "requires the tls build feature",
+            )
+            .with_capability(CapabilityFlags::CLIENT_SSL)
+            .with_config_key("tls"),
+        ]
+    }
+
+    pub fn auth_limits(&self) -> AuthLimits {
+        AuthLimits {
+            max_handshakes: self.max_auth_handshakes,
+            queue_timeout_ms: self.auth_queue_timeout_ms,
+        }
+    }
+
+    pub fn user_rate_limit(&self) -> RateLimit {
+        RateLimit {
+            qps: self.max_user_qps,
+            burst: self.user_qps_burst,
+            max_delay_ms: self.user_qps_delay_ms,
+        }
+    }
+
+    pub fn watchdog_config(&self) -> WatchdogConfig {
+        WatchdogConfig {
+            interval: Duration::from_secs(self.watchdog_interval_secs.max(1)),
+            max_rss_bytes: self.watchdog_max_rss_mb * 1024 * 1024,
+            max_fds_percent: self.watchdog_max_fds_percent,
+        }
+    }
+
+    pub fn notifier_config(&self) -> NotifierConfig {
+        NotifierConfig {
+            webhook_url: self.notify_webhook.clone(),
+            webhook_template: self.notify_template.clone(),
+            k8s_events: self.notify_k8s_events,
+            min_interval: Duration::from_secs(self.notify_min_interval_secs),
+            node: self.get_node_id(),
+        }
+    }
+
+    pub fn slow_log_file(&self) -> Option<SlowLogFile> {
+        self.slow_log_path.as_ref().map(|path| SlowLogFile {
+            path: path.clone(),
+            max_bytes: self.slow_log_max_mb * 1024 * 1024,
+        })
+    }
+
+    pub fn billing_config(&self) -> BillingConfig {
+        BillingConfig {
+            jsonl_path: self.billing_jsonl.clone(),
+            control_plane: self.billing_control_plane,
+        }
+    }
+
+    pub fn audit_sink(&self) -> Result<Option<AuditSink>, std::io::Error> {
+        self.audit_sink
+            .as_deref()
+            .map(AuditSink::from_str)
+            .transpose()
+    }
+
+    pub fn quarantine_config(&self) -> QuarantineConfig {
+        QuarantineConfig {
+            threshold: self.quarantine_threshold,
+            duration: Duration::from_secs(self.quarantine_secs),
+        }
+    }
+
+    pub fn client_watermarks(&self) -> Option<Watermarks> {
+        (self.client_high_watermark > 0).then(|| Watermarks {
+            high: self.client_high_watermark,
+            low: self.client_low_watermark.min(self.client_high_watermark),
+        })
+    }
+
+    pub fn backend_keepalive(&self) -> Option<Duration> {
+        (self.backend_keepalive_secs > 0).then(|| Duration::from_secs(self.backend_keepalive_secs))
+    }
+
+    pub fn client_idle_timeout(&self) -> Option<Duration> {
+        (self.client_idle_timeout_secs > 0)
+            .then(|| Duration::from_secs(self.client_idle_timeout_secs))
+    }
+
+    pub fn com_latency_queue(&self) -> Option<usize> {
+        (self.com_latency_queue > 0).then_some(self.com_latency_queue)
+    }
+
+    pub fn tenant_idle_ttl(&self) -> Option<Duration> {
+        (self.tenant_idle_ttl_secs > 0).then(|| Duration::from_secs(self.tenant_idle_ttl_secs))
+    }
+
+    pub fn shutdown_drain_timeout(&self) -> Duration {
+        Duration::from_secs(self.shutdown_drain_secs)
+    }
+
+    pub fn reconnect_token_config(&self) -> ReconnectTokenConfig {
+        ReconnectTokenConfig {
+            keys_file: self.reconnect_token_keys.clone(),
+            ttl: Duration::from_secs(self.reconnect_token_ttl_secs),
+        }
+    }
+
+    pub fn route_policy_config(&self) -> Result<RoutePolicyConfig, std::io::Error> {
+        Ok(RoutePolicyConfig {
+            url: self.route_policy_url.clone(),
+            engine: self.route_policy_engine.parse()?,
+            timeout: Duration::from_millis(self.route_policy_timeout_ms),
+            cache_ttl: Duration::from_secs(self.route_policy_cache_secs),
+            fail_open: self.route_policy_fail_open,
+        })
+    }
+
+    pub fn topology_stale_window(&self) -> Option<Duration> {
+        (self.topology_stale_secs > 0).then(|| Duration::from_secs(self.topology_stale_secs))
+    }
+
+    pub fn protocol_limits(&self) -> ProtocolLimits {
+        ProtocolLimits {
+            inflate: InflateLimits {
+                max_bytes_per_command: self.max_inflate_command_bytes,
+                max_bytes_per_sec: self.max_inflate_bytes_per_sec,
+            },
+            max_unflushed_packets: self.max_unflushed_packets,
+            max_packet_size: self.max_packet_size,
+        }
+    }
+
+    pub fn egress_config(&self) -> Result<EgressConfig, std::io::Error> {
+        EgressConfig::from_entries(&self.egress_allow)
+    }
+
+    /// The peers trusted to send a PROXY header, `None` without `proxy_protocol`.
+    pub fn trusted_proxies(&self) -> Result<Option<TrustedProxies>, std::io::Error> {
+        if !self.proxy_protocol {
+            return Ok(None);
+        }
+        TrustedProxies::from_entries(&self.proxy_protocol_trusted_cidrs).map(Some)
+    }
+
+    pub fn client_acl_rules(&self) -> ClientAclRules {
+        ClientAclRules {
+            allow: self.client_allow.clone(),
+            deny: self.client_deny.clone(),
+        }
+    }
+
+    pub fn sql_export(&self) -> Result<SqlExport, std::io::Error> {
+        self.sql_export.parse()
+    }
+
+    pub fn handshake_profile(&self) -> Result<HandshakeProfile, std::io::Error> {
+        let profile = self
+            .handshake_profile
+            .as_deref()
+            .map(HandshakeProfile::from_str);
+        Ok(profile.transpose()?.unwrap_or_default())
+    }
+
+    pub fn tunnel_handshake_profile(&self) -> Result<HandshakeProfile, std::io::Error> {
+        let profile = self
+            .tunnel_handshake_profile
+            .as_deref()
+            .map(HandshakeProfile::from_str);
+        Ok(HandshakeProfile {
+            listener: TUNNEL_LISTENER,
+            ..profile.transpose()?.unwrap_or_default()
+        })
+    }
+
+    pub fn acme_options(&self) -> Option<AcmeOptions> {
+        let domains: Vec<String> = self
+            .acme_domains
+            .iter()
+            .filter(|domain| !domain.is_empty())
+            .cloned()
+            .collect();
+        (!domains.is_empty()).then(|| AcmeOptions {
+            directory_url: self.acme_directory.clone(),
+            contact: self.acme_contact.clone(),
+            renew_before: Duration::from_secs(self.acme_renew_before_days * 24 * 3600),
+            ..AcmeOptions::new(domains, self.secrets_dir.clone())
+        })
+    }
+
+    /// TLS of the client listener, served with the ACME certificate. `None` without `tls` or
+    /// ACME domains.
+    pub fn client_tls_options(&self) -> Option<ClientTlsOptions> {
+        (self.tls && self.acme_options().is_some())
+            .then(|| ClientTlsOptions::with_cert_resolver(acme_cert_resolver()))
+    }
+
+    pub fn acme_solver(&self) -> Result<Arc<dyn ChallengeSolver>, std::io::Error> {
+        let invalid = |msg: String| std::io::Error::new(std::io::ErrorKind::InvalidInput, msg);
+        match (self.acme_challenge.as_str(), &self.acme_dns_hook) {
+            (HTTP_01, _) => Ok(Arc::new(Http01Solver)),
+            (DNS_01, Some(hook)) => Ok(Arc::new(DnsHookSolver::new(hook.clone()))),
+            (DNS_01, None) => Err(invalid(format!(
+                "acme_challenge {DNS_01} requires acme_dns_hook"
+            ))),
+            (challenge, _) => Err(invalid(format!(
+                "unknown acme_challenge {challenge:?}, expected {HTTP_01} or {DNS_01}"
+            ))),
+        }
+    }
+
+    /// The configuration part of the startup report, without the backends.
+    pub fn startup_report(&self) -> StartupReport {
+        let mut listeners = vec![ListenerReport {
+            name: PROXY_LISTENER,
+            addr: format!("0.0.0.0:{}", self.port),
+        }];
+        if let Some(tunnel_port) = self.tunnel_port {
+            listeners.push(ListenerReport {
+                name: TUNNEL_LISTENER,
+                addr: format!("0.0.0.0:{tunnel_port}"),
+            });
+        }
+        let http_endpoint = format!("0.0.0.0:{}", self.http_port);
+        StartupReport {
+            router: self.router.clone().unwrap_or("static".to_string()),
+            balancer: self.balancer_type(),
+            workers: self.works,
+            listeners,
+            tls_enabled: self.tls,
+            tls_supported: cfg!(feature = "tls"),
+            metrics_endpoint: self
+                .enable_metrics
+                .then(|| format!("{http_endpoint}/metrics")),
+            rest_endpoint: self.enable_rest.then_some(http_endpoint),
             ..Default::default()
         }
     }
@@ -153,6 +882,18 @@ impl ProxyServerArgs {
 
     // only for testing purposes.
     pub fn static_backend_list(&self) -> VecDeque<BackendInstance> {
+        if !self.static_backends.is_empty() {
+            return self
+                .static_backends
+                .iter()
+                .map(|addr| BackendInstance {
+                    location: DBLocation::default(),
+                    addr: addr.to_string(),
+                    status: ServiceStatus::Ready,
+                    cluster: ClusterName::default(),
+                })
+                .collect();
+        }
         if let Some(backend_cmd) = &self.backend {
             match backend_cmd {
                 BackendConfigArgs::Backend {
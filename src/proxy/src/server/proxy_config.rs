@@ -0,0 +1,927 @@
+use crate::audit::AuditSink;
+use crate::backend::egress::EgressConfig;
+use crate::backend::router::{BackendLoadBalancerType, BackendRouterType};
+use crate::protocol::mysql::constants::CommandCode;
+use crate::server::acme::solver::{DNS_01, HTTP_01};
+use crate::server::auth::client_acl::ClientAclRules;
+use crate::server::command_policy::parse_command_code;
+use crate::server::handshake_profile::HandshakeProfile;
+use crate::server::protocol_features::unhandled_capabilities;
+use crate::server::proxy_cli_args::ProxyServerArgs;
+use crate::server::proxy_protocol::TrustedProxies;
+use crate::server::route_policy::PolicyEngine;
+use crate::server::sql_privacy::SqlExport;
+
+use clap::parser::ValueSource;
+use clap::{Arg, CommandFactory, FromArgMatches};
+use hashbrown::HashMap;
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use std::any::TypeId;
+use std::ffi::OsString;
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use tracing::Level;
+
+/// Prefix of the environment variables setting a configuration key, e.g.
+/// `HAENTGL_CONFIG_SLOW_QUERY_MS`. Keeps them apart from the `HAENTGL_*` variables Kubernetes
+/// injects for a service named `haentgl`.
+pub const CONFIG_ENV_PREFIX: &str = "HAENTGL_CONFIG_";
+
+/// Where a configuration value comes from, from the lowest precedence to the highest.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigSource {
+    Default,
+    File(PathBuf),
+    Env(String),
+    Cli,
+    ControlPlane(PathBuf),
+}
+
+impl fmt::Display for ConfigSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigSource::Default => write!(f, "default"),
+            ConfigSource::File(path) => write!(f, "config file {}", path.display()),
+            ConfigSource::Env(var) => write!(f, "env {var}"),
+            ConfigSource::Cli => write!(f, "command line"),
+            ConfigSource::ControlPlane(path) => {
+                write!(f, "control plane overrides {}", path.display())
+            }
+        }
+    }
+}
+
+/// A section of the configuration file grouping keys: its `key` stands for `{prefix}{key}`, and
+/// `enabled` for the key of the section itself, if any.
+struct ConfigSection {
+    name: &'static str,
+    prefix: &'static str,
+    enabled: Option<&'static str>,
+}
+
+impl ConfigSection {
+    fn key(&self, key: &str) -> String {
+        match self.enabled {
+            Some(enabled) if key == "enabled" => enabled.to_string(),
+            _ => format!("{}{key}", self.prefix),
+        }
+    }
+
+    /// The keys of the section, by their key within it.
+    fn keys<'a>(
+        &'a self,
+        fields: &'a [ConfigField],
+    ) -> impl Iterator<Item = (String, &'a ConfigField)> {
+        fields.iter().filter_map(|field| {
+            if self.enabled == Some(field.key.as_str()) {
+                Some(("enabled".to_string(), field))
+            } else {
+                let key = field.key.strip_prefix(self.prefix)?;
+                Some((key.to_string(), field))
+            }
+        })
+    }
+}
+
+/// `[pool] warmup_conns = 4` stands for `pool_warmup_conns = 4`, `[tls] enabled = true` for
+/// `tls = true` and `[limits] auth_handshakes = 64` for `max_auth_handshakes = 64`.
+const CONFIG_SECTIONS: [ConfigSection; 3] = [
+    ConfigSection {
+        name: "pool",
+        prefix: "pool_",
+        enabled: None,
+    },
+    ConfigSection {
+        name: "tls",
+        prefix: "acme_",
+        enabled: Some("tls"),
+    },
+    ConfigSection {
+        name: "limits",
+        prefix: "max_",
+        enabled: None,
+    },
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FieldKind {
+    Bool,
+    Integer { max: u64 },
+    String,
+    Command,
+}
+
+impl FieldKind {
+    fn of(arg: &Arg) -> Self {
+        let type_id = arg.get_value_parser().type_id();
+        if type_id == TypeId::of::<bool>() {
+            FieldKind::Bool
+        } else if type_id == TypeId::of::<u16>() {
+            FieldKind::Integer {
+                max: u16::MAX as u64,
+            }
+        } else if type_id == TypeId::of::<u32>() {
+            FieldKind::Integer {
+                max: u32::MAX as u64,
+            }
+        } else if type_id == TypeId::of::<u64>() || type_id == TypeId::of::<usize>() {
+            FieldKind::Integer { max: u64::MAX }
+        } else if type_id == TypeId::of::<CommandCode>() {
+            FieldKind::Command
+        } else {
+            FieldKind::String
+        }
+    }
+
+    fn check(&self, value: &Value) -> Result<(), String> {
+        let valid = match self {
+            FieldKind::Bool => value.is_boolean(),
+            FieldKind::Integer { max } => value.as_u64().is_some_and(|n| n <= *max),
+            FieldKind::String => value.is_string(),
+            FieldKind::Command => serde_json::from_value::<CommandCode>(value.clone()).is_ok(),
+        };
+        if valid {
+            Ok(())
+        } else {
+            Err(format!("expected {self}, got {value}"))
+        }
+    }
+
+    fn parse(&self, raw: &str) -> Result<Value, String> {
+        match self {
+            FieldKind::Bool => raw.parse::<bool>().map(Value::from).ok(),
+            FieldKind::Integer { max } => raw
+                .parse::<u64>()
+                .ok()
+                .filter(|n| n <= max)
+                .map(Value::from),
+            FieldKind::String => Some(Value::from(raw)),
+            FieldKind::Command => {
+                parse_command_code(raw).and_then(|code| serde_json::to_value(code).ok())
+            }
+        }
+        .ok_or_else(|| format!("expected {self}, got {raw:?}"))
+    }
+
+    fn schema(&self) -> Value {
+        match self {
+            FieldKind::Bool => json!({ "type": "boolean" }),
+            FieldKind::Integer { max: u64::MAX } => json!({ "type": "integer", "minimum": 0 }),
+            FieldKind::Integer { max } => {
+                json!({ "type": "integer", "minimum": 0, "maximum": max })
+            }
+            FieldKind::String | FieldKind::Command => json!({ "type": "string" }),
+        }
+    }
+}
+
+impl fmt::Display for FieldKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FieldKind::Bool => write!(f, "true or false"),
+            FieldKind::Integer { max } => write!(f, "an integer between 0 and {max}"),
+            FieldKind::String => write!(f, "a string"),
+            FieldKind::Command => write!(f, "a command such as ComDropDB"),
+        }
+    }
+}
+
+/// A configuration key, described by its command line argument and default value.
+#[derive(Debug, Clone)]
+struct ConfigField {
+    key: String,
+    kind: FieldKind,
+    array: bool,
+    nullable: bool,
+    description: String,
+    default: Value,
+}
+
+impl ConfigField {
+    fn check(&self, value: &Value) -> Result<(), String> {
+        let key = &self.key;
+        match value {
+            Value::Null if self.nullable => Ok(()),
+            Value::Array(items) if self.array => {
+                items.iter().enumerate().try_for_each(|(idx, item)| {
+                    self.kind
+                        .check(item)
+                        .map_err(|e| format!("`{key}[{idx}]` {e}"))
+                })
+            }
+            _ if self.array => Err(format!("`{key}` expected an array, got {value}")),
+            _ => self.kind.check(value).map_err(|e| format!("`{key}` {e}")),
+        }
+    }
+
+    /// Parses an environment variable, arrays are comma separated like on the command line.
+    fn parse(&self, raw: &str) -> Result<Value, String> {
+        let parsed = if self.array {
+            raw.split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .map(|item| self.kind.parse(item))
+                .collect::<Result<Vec<_>, _>>()
+                .map(Value::Array)
+        } else {
+            self.kind.parse(raw)
+        };
+        parsed.map_err(|e| format!("`{}` {e}", self.key))
+    }
+
+    fn schema(&self) -> Value {
+        let mut schema = if self.array {
+            json!({ "type": "array", "items": self.kind.schema() })
+        } else {
+            self.kind.schema()
+        };
+        if self.nullable {
+            schema["type"] = json!([schema["type"], "null"]);
+        }
+        if !self.description.is_empty() {
+            schema["description"] = Value::from(self.description.as_str());
+        }
+        schema["default"] = self.default.clone();
+        schema
+    }
+}
+
+fn to_object<T: Serialize>(value: &T) -> Map<String, Value> {
+    match serde_json::to_value(value) {
+        Ok(Value::Object(object)) => object,
+        _ => Map::new(),
+    }
+}
+
+fn invalid_input(message: impl Into<String>) -> Error {
+    Error::new(ErrorKind::InvalidInput, message.into())
+}
+
+/// The defaults of the configuration keys, the values of the arguments left out.
+fn default_config() -> Result<ProxyServerArgs, Error> {
+    let command = ProxyServerArgs::command();
+    let bin = command.get_name().to_string();
+    let matches = command
+        .try_get_matches_from([bin])
+        .map_err(|e| invalid_input(e.to_string()))?;
+    ProxyServerArgs::from_arg_matches(&matches).map_err(|e| invalid_input(e.to_string()))
+}
+
+/// The configuration keys: the arguments of [`ProxyServerArgs`] that are not command line only.
+fn config_fields(defaults: &Map<String, Value>) -> Vec<ConfigField> {
+    ProxyServerArgs::command()
+        .get_arguments()
+        .filter_map(|arg| {
+            let key = arg.get_id().as_str();
+            let default = defaults.get(key)?;
+            Some(ConfigField {
+                key: key.to_string(),
+                kind: FieldKind::of(arg),
+                array: default.is_array(),
+                nullable: default.is_null(),
+                description: arg
+                    .get_help()
+                    .map(|help| help.to_string())
+                    .unwrap_or_default(),
+                default: default.clone(),
+            })
+        })
+        .collect()
+}
+
+/// The JSON Schema of the configuration file and the control plane overrides, printed by
+/// `--print-config-schema` so configurations can be checked before they are deployed.
+pub fn config_schema() -> Result<Value, Error> {
+    let defaults = to_object(&default_config()?);
+    let fields = config_fields(&defaults);
+    let mut properties: Map<String, Value> = fields
+        .iter()
+        .map(|field| (field.key.clone(), field.schema()))
+        .collect();
+    for section in &CONFIG_SECTIONS {
+        let section_properties: Map<String, Value> = section
+            .keys(&fields)
+            .map(|(key, field)| (key, field.schema()))
+            .collect();
+        properties.insert(
+            section.name.to_string(),
+            json!({
+                "type": "object",
+                "additionalProperties": false,
+                "properties": section_properties,
+            }),
+        );
+    }
+    Ok(json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": "haentgl proxy configuration",
+        "type": "object",
+        "additionalProperties": false,
+        "properties": properties,
+    }))
+}
+
+/// The configuration merged so far, with the source of every key.
+struct ConfigLayers {
+    fields: HashMap<String, ConfigField>,
+    values: Map<String, Value>,
+    sources: HashMap<String, ConfigSource>,
+    errors: Vec<String>,
+}
+
+impl ConfigLayers {
+    fn new(defaults: Map<String, Value>) -> Self {
+        Self {
+            fields: config_fields(&defaults)
+                .into_iter()
+                .map(|field| (field.key.clone(), field))
+                .collect(),
+            values: defaults,
+            sources: HashMap::new(),
+            errors: vec![],
+        }
+    }
+
+    fn source(&self, key: &str) -> &ConfigSource {
+        self.sources.get(key).unwrap_or(&ConfigSource::Default)
+    }
+
+    fn set(&mut self, source: &ConfigSource, key: &str, value: Value) {
+        let Some(field) = self.fields.get(key) else {
+            self.errors.push(format!("{source}: unknown key `{key}`"));
+            return;
+        };
+        match field.check(&value) {
+            Ok(()) => {
+                self.values.insert(key.to_string(), value);
+                self.sources.insert(key.to_string(), source.clone());
+            }
+            Err(e) => self.errors.push(format!("{source}: {e}")),
+        }
+    }
+
+    fn merge_file(&mut self, source: ConfigSource, path: &PathBuf) {
+        let object = std::fs::read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|content| parse_config_file(path, &content));
+        let object = match object {
+            Ok(object) => object,
+            Err(e) => return self.errors.push(format!("{source}: {e}")),
+        };
+        for (key, value) in object {
+            let section = CONFIG_SECTIONS.iter().find(|section| section.name == key);
+            match (section, value) {
+                (Some(section), Value::Object(entries)) => {
+                    for (key, value) in entries {
+                        let flat_key = section.key(&key);
+                        if self.fields.contains_key(&flat_key) {
+                            self.set(&source, &flat_key, value);
+                        } else {
+                            let name = section.name;
+                            self.errors
+                                .push(format!("{source}: unknown key `{name}.{key}`"));
+                        }
+                    }
+                }
+                (_, value) => self.set(&source, &key, value),
+            }
+        }
+    }
+
+    fn merge_env(&mut self, env: impl IntoIterator<Item = (String, String)>) {
+        let mut vars: Vec<_> = env
+            .into_iter()
+            .filter(|(var, _)| var.starts_with(CONFIG_ENV_PREFIX))
+            .collect();
+        vars.sort();
+        for (var, raw) in vars {
+            let key = var[CONFIG_ENV_PREFIX.len()..].to_lowercase();
+            let source = ConfigSource::Env(var);
+            let parsed = match self.fields.get(&key) {
+                Some(field) => field.parse(&raw),
+                None => Err(format!("unknown key `{key}`")),
+            };
+            match parsed {
+                Ok(value) => self.set(&source, &key, value),
+                Err(e) => self.errors.push(format!("{source}: {e}")),
+            }
+        }
+    }
+
+    fn finish(mut self) -> Result<ProxyServerArgs, Error> {
+        let config = if self.errors.is_empty() {
+            serde_json::from_value::<ProxyServerArgs>(Value::Object(self.values.clone()))
+                .map_err(|e| self.errors.push(e.to_string()))
+                .ok()
+        } else {
+            None
+        };
+        if let Some(config) = &config {
+            for (key, e) in semantic_errors(config) {
+                let top_key = key.split('[').next().unwrap_or_default();
+                let source = self.source(top_key).clone();
+                self.errors.push(format!("{source}: `{key}` {e}"));
+            }
+        }
+        match config {
+            Some(config) if self.errors.is_empty() => Ok(config),
+            _ => Err(invalid_input(format!(
+                "invalid proxy configuration:\n  {}",
+                self.errors.join("\n  ")
+            ))),
+        }
+    }
+}
+
+/// Parses a configuration file by its extension: TOML, YAML or else JSON.
+fn parse_config_file(path: &Path, content: &str) -> Result<Map<String, Value>, String> {
+    match path.extension().and_then(|extension| extension.to_str()) {
+        Some("toml") => toml::from_str(content).map_err(|e| e.to_string()),
+        Some("yaml" | "yml") => serde_yaml::from_str(content).map_err(|e| e.to_string()),
+        _ => serde_json::from_str(content).map_err(|e| e.to_string()),
+    }
+}
+
+/// What the types of the keys cannot tell, with the path of the key at fault.
+fn semantic_errors(config: &ProxyServerArgs) -> Vec<(String, String)> {
+    let mut errors = vec![];
+    if config.works == 0 {
+        errors.push(("works".to_string(), "must be at least 1".to_string()));
+    }
+    if let Some(level) = &config.log_level {
+        if Level::from_str(level).is_err() {
+            errors.push(("log_level".to_string(), format!("unknown level {level:?}")));
+        }
+    }
+    if config.pool_max_size == 0 {
+        errors.push((
+            "pool_max_size".to_string(),
+            "must be at least 1".to_string(),
+        ));
+    }
+    if config.cp_args.cp_activity_sample_every == 0 {
+        errors.push((
+            "cp_activity_sample_every".to_string(),
+            "must be at least 1".to_string(),
+        ));
+    }
+    if config.pool_warmup_parallelism == 0 {
+        errors.push((
+            "pool_warmup_parallelism".to_string(),
+            "must be at least 1".to_string(),
+        ));
+    }
+    let features = config.protocol_features();
+    for (key, spec) in [
+        ("handshake_profile", &config.handshake_profile),
+        ("tunnel_handshake_profile", &config.tunnel_handshake_profile),
+    ] {
+        match spec.as_deref().map(HandshakeProfile::from_str) {
+            Some(Err(e)) => errors.push((key.to_string(), e.to_string())),
+            profile => {
+                let advertised = profile
+                    .and_then(Result::ok)
+                    .unwrap_or_default()
+                    .capabilities();
+                for feature in unhandled_capabilities(&features, advertised) {
+                    errors.push((
+                        key.to_string(),
+                        format!("advertises {} the proxy does not handle", feature.name),
+                    ));
+                }
+            }
+        }
+    }
+    for feature in features.iter().filter(|feature| feature.is_broken()) {
+        errors.push((
+            feature.config_key.unwrap_or(feature.name).to_string(),
+            format!(
+                "enables {} the proxy does not handle, {}",
+                feature.name, feature.note
+            ),
+        ));
+    }
+    if let Some(Err(e)) = config.audit_sink.as_deref().map(AuditSink::from_str) {
+        errors.push(("audit_sink".to_string(), e.to_string()));
+    }
+    if config.slow_log_path.is_some() && config.slow_log_max_mb == 0 {
+        errors.push((
+            "slow_log_max_mb".to_string(),
+            "must be at least 1".to_string(),
+        ));
+    }
+    if config.transparent && !cfg!(target_os = "linux") {
+        errors.push((
+            "transparent".to_string(),
+            "transparent mode is only supported on Linux".to_string(),
+        ));
+    }
+    if let Some(router) = &config.router {
+        if BackendRouterType::from_str(router).is_err() {
+            errors.push((
+                "router".to_string(),
+                format!("unknown router {router:?}, expected static, sync-with-cp or read-write"),
+            ));
+        }
+    }
+    if BackendLoadBalancerType::from_str(&config.balancer_type()).is_err() {
+        errors.push((
+            "balance".to_string(),
+            format!(
+                "unknown balancer {:?}, expected random or p2c",
+                config.balancer_type()
+            ),
+        ));
+    }
+    if let Err(e) = EgressConfig::from_entries(&config.egress_allow) {
+        errors.push(("egress_allow".to_string(), e.to_string()));
+    }
+    for (key, entries) in [
+        ("client_allow", &config.client_allow),
+        ("client_deny", &config.client_deny),
+    ] {
+        let rules = ClientAclRules {
+            allow: entries.clone(),
+            deny: vec![],
+        };
+        if let Err(e) = rules.validate() {
+            errors.push((key.to_string(), e.to_string()));
+        }
+    }
+    match TrustedProxies::from_entries(&config.proxy_protocol_trusted_cidrs) {
+        Err(e) => errors.push(("proxy_protocol_trusted_cidrs".to_string(), e.to_string())),
+        Ok(trusted) if config.proxy_protocol && trusted.is_empty() => errors.push((
+            "proxy_protocol_trusted_cidrs".to_string(),
+            "required by proxy_protocol".to_string(),
+        )),
+        Ok(_) => {}
+    }
+    if let Err(e) = config.sql_export.parse::<SqlExport>() {
+        errors.push(("sql_export".to_string(), e.to_string()));
+    }
+    if let Err(e) = config.route_policy_engine.parse::<PolicyEngine>() {
+        errors.push(("route_policy_engine".to_string(), e.to_string()));
+    }
+    match (config.acme_challenge.as_str(), &config.acme_dns_hook) {
+        (HTTP_01, _) | (DNS_01, Some(_)) => {}
+        (DNS_01, None) => errors.push((
+            "acme_dns_hook".to_string(),
+            "required by the dns-01 challenge".to_string(),
+        )),
+        (challenge, _) => errors.push((
+            "acme_challenge".to_string(),
+            format!("unknown challenge {challenge:?}, expected http-01 or dns-01"),
+        )),
+    }
+    if config.tls && config.acme_options().is_none() {
+        errors.push(("acme_domains".to_string(), "required by tls".to_string()));
+    }
+    if config.sni_tenant_domain.is_some() && !config.tls {
+        errors.push(("sni_tenant_domain".to_string(), "requires tls".to_string()));
+    }
+    if config.reconnect_token_keys.is_some() && config.reconnect_token_ttl_secs == 0 {
+        errors.push((
+            "reconnect_token_ttl_secs".to_string(),
+            "must be at least 1 with reconnect_token_keys".to_string(),
+        ));
+    }
+    errors
+}
+
+/// Loads the configuration of the process, see [`load_proxy_config_from`].
+pub fn load_proxy_config() -> Result<ProxyServerArgs, Error> {
+    ProxyServerArgs::from_sources(std::env::args_os(), std::env::vars())
+}
+
+impl ProxyServerArgs {
+    /// The configuration merged from the command line `args`, the `env` variables and the files
+    /// they name, see [`load_proxy_config_from`].
+    pub fn from_sources<I, T>(
+        args: I,
+        env: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<Self, Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString> + Clone,
+    {
+        load_proxy_config_from(args, env)
+    }
+}
+
+/// Merges the configuration sources, each overriding the previous ones: the defaults, the
+/// `--config` file, the `HAENTGL_CONFIG_*` environment variables, the command line and the
+/// `--config-overrides` file of the control plane. Every invalid key is reported with its source.
+pub fn load_proxy_config_from<I, T>(
+    args: I,
+    env: impl IntoIterator<Item = (String, String)>,
+) -> Result<ProxyServerArgs, Error>
+where
+    I: IntoIterator<Item = T>,
+    T: Into<OsString> + Clone,
+{
+    let matches = ProxyServerArgs::command().get_matches_from(args);
+    let cli =
+        ProxyServerArgs::from_arg_matches(&matches).map_err(|e| invalid_input(e.to_string()))?;
+
+    let mut layers = ConfigLayers::new(to_object(&default_config()?));
+    if let Some(path) = &cli.config {
+        layers.merge_file(ConfigSource::File(path.clone()), path);
+    }
+    layers.merge_env(env);
+    let cli_values = to_object(&cli);
+    for (key, value) in cli_values {
+        if matches.value_source(&key) == Some(ValueSource::CommandLine) {
+            layers.set(&ConfigSource::Cli, &key, value);
+        }
+    }
+    if let Some(path) = &cli.config_overrides {
+        layers.merge_file(ConfigSource::ControlPlane(path.clone()), path);
+    }
+
+    let mut config = layers.finish()?;
+    config.config = cli.config;
+    config.config_overrides = cli.config_overrides;
+    config.print_config_schema = cli.print_config_schema;
+    config.backend = cli.backend;
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::protocol::mysql::constants::CommandCode;
+    use crate::server::handshake_profile::TUNNEL_LISTENER;
+    use crate::server::proxy_cli_args::ProxyServerArgs;
+    use crate::server::proxy_config::{config_schema, load_proxy_config_from};
+    use crate::server::route_policy::PolicyEngine;
+    use crate::server::sql_privacy::SqlExport;
+    use clap::Parser;
+    use std::path::PathBuf;
+
+    fn write_config(name: &str, content: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("{}-{name}.json", std::process::id()));
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[test]
+    pub fn test_config_file_formats() {
+        let dir = std::env::temp_dir();
+        let toml = dir.join(format!("{}-config.toml", std::process::id()));
+        std::fs::write(
+            &toml,
+            r#"
+port = 3320
+deny_commands = ["ComDropDB"]
+
+[pool]
+warmup_conns = 4
+
+[tls]
+enabled = true
+domains = ["proxy.example.com"]
+
+[limits]
+auth_handshakes = 64
+"#,
+        )
+        .unwrap();
+        let args = [
+            "haentgl",
+            "--config",
+            toml.to_str().unwrap(),
+            "--port",
+            "3330",
+        ];
+        let config = ProxyServerArgs::from_sources(args, vec![]).unwrap();
+        assert_eq!(config.port, 3330);
+        assert_eq!(config.deny_commands, vec![CommandCode::ComDropDB]);
+        assert_eq!(config.pool_warmup_conns, 4);
+        assert!(config.tls);
+        assert_eq!(config.acme_domains, vec!["proxy.example.com"]);
+        assert_eq!(config.max_auth_handshakes, 64);
+
+        let yaml = dir.join(format!("{}-config.yaml", std::process::id()));
+        std::fs::write(&yaml, "works: 2\npool:\n  max_idle_secs: 30\n  nope: 1\n").unwrap();
+        let e =
+            ProxyServerArgs::from_sources(["haentgl", "--config", yaml.to_str().unwrap()], vec![])
+                .unwrap_err()
+                .to_string();
+        assert!(e.contains("unknown key `pool.nope`"), "{e}");
+        std::fs::write(&yaml, "works: 2\npool:\n  max_idle_secs: 30\n").unwrap();
+        let config =
+            ProxyServerArgs::from_sources(["haentgl", "--config", yaml.to_str().unwrap()], vec![])
+                .unwrap();
+        assert_eq!(config.works, 2);
+        assert_eq!(config.pool_max_idle_secs, 30);
+        for path in [toml, yaml] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    pub fn test_load_proxy_config() {
+        let file = write_config(
+            "config",
+            r#"{"port": 3300, "works": 2, "enable_cp": true, "balance": "p2c"}"#,
+        );
+        let overrides = write_config("overrides", r#"{"works": 3}"#);
+        let env = |vars: &[(&str, &str)]| {
+            vars.iter()
+                .map(|(var, value)| (var.to_string(), value.to_string()))
+                .collect::<Vec<_>>()
+        };
+        let args = [
+            "haentgl",
+            "--config",
+            file.to_str().unwrap(),
+            "--port",
+            "3400",
+            "--deny-commands",
+            "comdropdb,ComShutdown",
+        ];
+        let config = load_proxy_config_from(
+            args,
+            env(&[
+                ("HAENTGL_CONFIG_PORT", "3311"),
+                ("HAENTGL_CONFIG_SLOW_QUERY_MS", "250"),
+                ("HAENTGL_CONFIG_BACKEND_COMPRESS", "a:3306, b:3306"),
+                ("HAENTGL_PORT", "tcp://10.0.0.1:3310"),
+            ]),
+        )
+        .unwrap();
+        assert_eq!(config.port, 3400);
+        assert_eq!(config.works, 2);
+        assert_eq!(config.slow_query_ms, 250);
+        assert_eq!(config.backend_compress, vec!["a:3306", "b:3306"]);
+        assert!(config.cp_args.enable_cp);
+        assert_eq!(config.balancer_type(), "p2c");
+        assert_eq!(config.http_port, 9000);
+        assert_eq!(config.config, Some(file.clone()));
+        assert_eq!(
+            config.deny_commands,
+            vec![CommandCode::ComDropDB, CommandCode::ComShutdown]
+        );
+
+        let config = load_proxy_config_from(
+            [
+                "haentgl",
+                "--works",
+                "8",
+                "--config-overrides",
+                overrides.to_str().unwrap(),
+            ],
+            vec![],
+        )
+        .unwrap();
+        assert_eq!(config.works, 3);
+        let config = load_proxy_config_from(
+            ["haentgl"],
+            env(&[("HAENTGL_CONFIG_DENY_COMMANDS", "comquit, ComBinlogDump")]),
+        )
+        .unwrap();
+        assert_eq!(
+            config.deny_commands,
+            vec![CommandCode::ComQuit, CommandCode::ComBinlogDump]
+        );
+
+        let invalid = write_config("invalid", r#"{"prot": 1, "tunnel_port": null}"#);
+        let e = load_proxy_config_from(
+            ["haentgl", "--config", invalid.to_str().unwrap()],
+            env(&[("HAENTGL_CONFIG_PORT", "abc")]),
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(e.contains("unknown key `prot`"), "{e}");
+        assert!(
+            e.contains("env HAENTGL_CONFIG_PORT: `port` expected an integer between 0 and 65535"),
+            "{e}"
+        );
+        let commands = write_config("commands", r#"{"deny_commands": ["ComDropDB", "ComNope"]}"#);
+        let e = load_proxy_config_from(["haentgl", "--config", commands.to_str().unwrap()], vec![])
+            .unwrap_err()
+            .to_string();
+        assert!(
+            e.contains(r#"`deny_commands[1]` expected a command such as ComDropDB, got "ComNope""#),
+            "{e}"
+        );
+        let e = load_proxy_config_from(["haentgl", "--cp-activity-sample-every", "0"], vec![])
+            .unwrap_err()
+            .to_string();
+        assert!(
+            e.contains("`cp_activity_sample_every` must be at least 1"),
+            "{e}"
+        );
+        let e = load_proxy_config_from(["haentgl", "--proxy-protocol"], vec![])
+            .unwrap_err()
+            .to_string();
+        assert!(
+            e.contains("`proxy_protocol_trusted_cidrs` required by proxy_protocol"),
+            "{e}"
+        );
+        let config = load_proxy_config_from(
+            [
+                "haentgl",
+                "--proxy-protocol",
+                "--proxy-protocol-trusted-cidrs",
+                "10.0.0.0/8,192.0.2.10",
+            ],
+            vec![],
+        )
+        .unwrap();
+        let trusted = config.trusted_proxies().unwrap().unwrap();
+        assert!(trusted.is_trusted("10.1.2.3".parse().unwrap()));
+        let e = load_proxy_config_from(["haentgl", "--tls"], vec![])
+            .unwrap_err()
+            .to_string();
+        assert!(e.contains("`acme_domains` required by tls"), "{e}");
+        for path in [file, overrides, invalid, commands] {
+            std::fs::remove_file(path).unwrap();
+        }
+
+        let schema = config_schema().unwrap();
+        let properties = schema["properties"].as_object().unwrap();
+        assert_eq!(properties["port"]["maximum"], 65535);
+        assert_eq!(properties["port"]["default"], 3310);
+        assert_eq!(properties["deny_commands"]["type"], "array");
+        assert_eq!(
+            properties["tunnel_port"]["type"],
+            serde_json::json!(["integer", "null"])
+        );
+        assert_eq!(properties["enable_cp"]["type"], "boolean");
+        assert!(!properties.contains_key("config"));
+        let pool = &properties["pool"]["properties"];
+        assert_eq!(pool["warmup_conns"]["type"], "integer");
+        assert_eq!(
+            properties["tls"]["properties"]["enabled"]["type"],
+            "boolean"
+        );
+        assert!(!properties.contains_key("help"));
+    }
+    #[test]
+    pub fn test_invalid_args() {
+        let args = |args: &[&str]| ProxyServerArgs::try_parse_from([&["haentgl"], args].concat());
+        let e = args(&["--audit-sink", "syslog"]).unwrap_err().to_string();
+        assert!(e.contains("unknown audit sink syslog"), "{e}");
+        let config = args(&["--audit-sink", "file:/tmp/audit.jsonl"]).unwrap();
+        assert!(config.audit_sink().unwrap().is_some());
+        let e = args(&["--sql-export", "hashed"]).unwrap_err().to_string();
+        assert!(e.contains("unknown sql export \"hashed\""), "{e}");
+        let config = args(&["--sql-export", "raw"]).unwrap();
+        assert_eq!(config.sql_export().unwrap(), SqlExport::Raw);
+        for flag in ["--handshake-profile", "--tunnel-handshake-profile"] {
+            let e = args(&[flag, "version=8.0.36,nope=1"])
+                .unwrap_err()
+                .to_string();
+            assert!(e.contains("nope"), "{e}");
+        }
+        let config = args(&["--tunnel-handshake-profile", "collation=45"]).unwrap();
+        let profile = config.tunnel_handshake_profile().unwrap();
+        assert_eq!((profile.listener, profile.collation), (TUNNEL_LISTENER, 45));
+        let e = args(&["--acme-challenge", "tls-alpn-01"])
+            .unwrap_err()
+            .to_string();
+        assert!(e.contains("tls-alpn-01"), "{e}");
+        let e = args(&["--acme-challenge", "dns-01"])
+            .unwrap_err()
+            .to_string();
+        assert!(e.contains("--acme-dns-hook"), "{e}");
+        let config = args(&["--acme-challenge", "dns-01", "--acme-dns-hook", "/bin/hook"]).unwrap();
+        assert!(config.acme_solver().is_ok());
+        let e = args(&["--route-policy-engine", "opaa"])
+            .unwrap_err()
+            .to_string();
+        assert!(e.contains("unknown policy engine \"opaa\""), "{e}");
+        let config = args(&["--route-policy-engine", "opa"]).unwrap();
+        assert_eq!(
+            config.route_policy_config().unwrap().engine,
+            PolicyEngine::Opa
+        );
+
+        // A configuration not parsed from the command line is checked once it is applied.
+        let config = ProxyServerArgs {
+            audit_sink: Some("file:".to_string()),
+            ..Default::default()
+        };
+        assert!(config.audit_sink().is_err());
+        assert!(config.sql_export().is_err());
+        let config = ProxyServerArgs {
+            handshake_profile: Some("collation=utf8".to_string()),
+            ..Default::default()
+        };
+        assert!(config.handshake_profile().is_err());
+        let config = ProxyServerArgs {
+            acme_challenge: "dns-01".to_string(),
+            ..Default::default()
+        };
+        let e = config.acme_solver().err().unwrap().to_string();
+        assert!(e.contains("requires acme_dns_hook"), "{e}");
+        let config = ProxyServerArgs {
+            route_policy_engine: "rego".to_string(),
+            ..Default::default()
+        };
+        assert!(config.route_policy_config().is_err());
+    }
+}
@@ -0,0 +1,254 @@
+use ipnet::IpNet;
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// How long a load balancer may take to send the PROXY header of a connection.
+pub const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Every PROXY protocol v2 header starts with these bytes.
+/// see: https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+const V1_PREFIX: &[u8] = b"PROXY ";
+/// The longest v1 header, `PROXY TCP6` with the longest addresses and ports.
+const V1_MAX_LEN: usize = 107;
+const V2_LOCAL: u8 = 0x20;
+const V2_PROXY: u8 = 0x21;
+const V2_AF_UNSPEC: u8 = 0x00;
+const V2_TCP4: u8 = 0x11;
+const V2_TCP6: u8 = 0x21;
+
+/// The addresses of the connection a load balancer accepted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProxiedAddrs {
+    pub source: SocketAddr,
+    pub destination: SocketAddr,
+}
+
+/// The peers trusted to send a PROXY header, the load balancers in front of the proxy. Other peers
+/// are clients connecting directly: their own address stands for them and a header they send is
+/// not read, so they cannot pass for another address.
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies {
+    nets: Vec<IpNet>,
+}
+
+impl TrustedProxies {
+    /// Parses CIDRs or IP addresses, e.g. `10.0.0.0/8` or `192.0.2.10`.
+    pub fn from_entries(entries: &[String]) -> Result<Self, Error> {
+        let nets = entries
+            .iter()
+            .map(|entry| entry.trim())
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| {
+                entry
+                    .parse::<IpNet>()
+                    .or_else(|_| entry.parse::<IpAddr>().map(IpNet::from))
+                    .map_err(|_| {
+                        Error::new(
+                            ErrorKind::InvalidInput,
+                            format!("invalid trusted proxy network {entry}"),
+                        )
+                    })
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self { nets })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nets.is_empty()
+    }
+
+    pub fn is_trusted(&self, peer: IpAddr) -> bool {
+        let peer = peer.to_canonical();
+        self.nets.iter().any(|net| net.contains(&peer))
+    }
+}
+
+fn invalid(message: impl Into<String>) -> Error {
+    Error::new(ErrorKind::InvalidData, message.into())
+}
+
+/// Reads the PROXY protocol header, v1 or v2, a connection starts with. `None` if the header
+/// carries no addresses, e.g. a health check of the load balancer. Nothing beyond the header is
+/// read, the client waits for the initial handshake anyway.
+pub async fn read_proxy_header<R>(reader: &mut R) -> Result<Option<ProxiedAddrs>, Error>
+where
+    R: AsyncRead + Unpin,
+{
+    // The shortest v1 header, `PROXY UNKNOWN\r\n`, is longer than the v2 signature.
+    let mut head = [0; V2_SIGNATURE.len()];
+    reader.read_exact(&mut head).await?;
+    if head == V2_SIGNATURE {
+        let mut fixed = [0; 4];
+        reader.read_exact(&mut fixed).await?;
+        let [ver_cmd, family, len_hi, len_lo] = fixed;
+        let mut body = vec![0; u16::from_be_bytes([len_hi, len_lo]) as usize];
+        reader.read_exact(&mut body).await?;
+        parse_v2(ver_cmd, family, &body)
+    } else if head.starts_with(V1_PREFIX) {
+        let mut line = head.to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() >= V1_MAX_LEN {
+                return Err(invalid("PROXY v1 header too long"));
+            }
+            line.push(reader.read_u8().await?);
+        }
+        parse_v1(&line)
+    } else {
+        Err(invalid("the connection does not start with a PROXY header"))
+    }
+}
+
+fn parse_v1(line: &[u8]) -> Result<Option<ProxiedAddrs>, Error> {
+    let line = std::str::from_utf8(line)
+        .map_err(|_| invalid("PROXY v1 header is not ASCII"))?
+        .trim_end_matches("\r\n");
+    let fields = line.split(' ').collect::<Vec<_>>();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", source, destination, source_port, destination_port] => {
+            let addr = |ip: &str, port: &str| {
+                let ip = ip.parse::<IpAddr>();
+                let port = port.parse::<u16>();
+                match (ip, port) {
+                    (Ok(ip), Ok(port)) => Ok(SocketAddr::new(ip, port)),
+                    _ => Err(invalid(format!("malformed PROXY v1 header {line:?}"))),
+                }
+            };
+            Ok(Some(ProxiedAddrs {
+                source: addr(source, source_port)?,
+                destination: addr(destination, destination_port)?,
+            }))
+        }
+        _ => Err(invalid(format!("malformed PROXY v1 header {line:?}"))),
+    }
+}
+
+fn parse_v2(ver_cmd: u8, family: u8, body: &[u8]) -> Result<Option<ProxiedAddrs>, Error> {
+    match ver_cmd {
+        V2_LOCAL => return Ok(None),
+        V2_PROXY => {}
+        _ => {
+            return Err(invalid(format!(
+                "unsupported PROXY v2 command {ver_cmd:#x}"
+            )))
+        }
+    }
+    let port = |at: usize| u16::from_be_bytes([body[at], body[at + 1]]);
+    // The high nibble is the address family, the low one the transport.
+    match family >> 4 {
+        1 if body.len() >= 12 => {
+            let ip = |at: usize| Ipv4Addr::new(body[at], body[at + 1], body[at + 2], body[at + 3]);
+            Ok(Some(ProxiedAddrs {
+                source: SocketAddr::new(ip(0).into(), port(8)),
+                destination: SocketAddr::new(ip(4).into(), port(10)),
+            }))
+        }
+        2 if body.len() >= 36 => {
+            let ip = |at: usize| {
+                let octets: [u8; 16] = body[at..at + 16].try_into().unwrap();
+                Ipv6Addr::from(octets)
+            };
+            Ok(Some(ProxiedAddrs {
+                source: SocketAddr::new(ip(0).into(), port(32)),
+                destination: SocketAddr::new(ip(16).into(), port(34)),
+            }))
+        }
+        1 | 2 => Err(invalid("truncated PROXY v2 addresses")),
+        // Unix sockets or unspecified, the connection keeps its own address.
+        _ => Ok(None),
+    }
+}
+
+/// Encodes a PROXY protocol v2 header for `addrs`, a LOCAL header if there are none.
+pub fn encode_v2_header(addrs: Option<ProxiedAddrs>) -> Vec<u8> {
+    let mut header = V2_SIGNATURE.to_vec();
+    let Some(ProxiedAddrs {
+        source,
+        destination,
+    }) = addrs
+    else {
+        header.extend_from_slice(&[V2_LOCAL, V2_AF_UNSPEC, 0, 0]);
+        return header;
+    };
+    let mut body = vec![];
+    let family = match (source.ip(), destination.ip()) {
+        (IpAddr::V4(source_ip), IpAddr::V4(destination_ip)) => {
+            body.extend_from_slice(&source_ip.octets());
+            body.extend_from_slice(&destination_ip.octets());
+            V2_TCP4
+        }
+        (source_ip, destination_ip) => {
+            let v6 = |ip: IpAddr| match ip {
+                IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                IpAddr::V6(ip) => ip,
+            };
+            body.extend_from_slice(&v6(source_ip).octets());
+            body.extend_from_slice(&v6(destination_ip).octets());
+            V2_TCP6
+        }
+    };
+    body.extend_from_slice(&source.port().to_be_bytes());
+    body.extend_from_slice(&destination.port().to_be_bytes());
+    header.extend_from_slice(&[V2_PROXY, family]);
+    header.extend_from_slice(&(body.len() as u16).to_be_bytes());
+    header.extend_from_slice(&body);
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::server::proxy_protocol::{
+        encode_v2_header, read_proxy_header, ProxiedAddrs, TrustedProxies,
+    };
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    pub async fn test_read_proxy_header() {
+        let mut v1 = &b"PROXY TCP4 198.51.100.7 203.0.113.1 51234 3306\r\n\x4a"[..];
+        let addrs = read_proxy_header(&mut v1).await.unwrap().unwrap();
+        assert_eq!(addrs.source, "198.51.100.7:51234".parse().unwrap());
+        assert_eq!(addrs.destination, "203.0.113.1:3306".parse().unwrap());
+        // The bytes after the header are left to the protocol.
+        assert_eq!(v1.read_u8().await.unwrap(), 0x4a);
+        let mut unknown = &b"PROXY UNKNOWN\r\n"[..];
+        assert_eq!(read_proxy_header(&mut unknown).await.unwrap(), None);
+
+        for (source, destination) in [
+            ("198.51.100.7:51234", "203.0.113.1:3306"),
+            ("[2001:db8::7]:51234", "[2001:db8::1]:3306"),
+        ] {
+            let addrs = ProxiedAddrs {
+                source: source.parse().unwrap(),
+                destination: destination.parse().unwrap(),
+            };
+            let header = encode_v2_header(Some(addrs));
+            let parsed = read_proxy_header(&mut &header[..]).await.unwrap();
+            assert_eq!(parsed, Some(addrs));
+        }
+        let local = encode_v2_header(None);
+        assert_eq!(read_proxy_header(&mut &local[..]).await.unwrap(), None);
+
+        let mut direct = &b"\x4a\0\0\0\x0a8.0.36\0\0\0\0\0\0\0"[..];
+        assert!(read_proxy_header(&mut direct).await.is_err());
+        let mut malformed = &b"PROXY TCP4 198.51.100.7 nope 1 2\r\n"[..];
+        assert!(read_proxy_header(&mut malformed).await.is_err());
+    }
+    #[test]
+    pub fn test_trusted_proxies() {
+        let entries = ["10.0.0.0/8".to_string(), " 192.0.2.10 ".to_string()];
+        let trusted = TrustedProxies::from_entries(&entries).unwrap();
+        assert!(trusted.is_trusted("10.1.2.3".parse().unwrap()));
+        assert!(trusted.is_trusted("192.0.2.10".parse().unwrap()));
+        assert!(trusted.is_trusted("::ffff:10.1.2.3".parse().unwrap()));
+        assert!(!trusted.is_trusted("192.0.2.11".parse().unwrap()));
+        assert!(!TrustedProxies::default().is_trusted("10.1.2.3".parse().unwrap()));
+        assert!(TrustedProxies::from_entries(&["".to_string()])
+            .unwrap()
+            .is_empty());
+        let e = TrustedProxies::from_entries(&["10.0.0.0/33".to_string()]).unwrap_err();
+        assert!(e.to_string().contains("10.0.0.0/33"), "{e}");
+    }
+}
@@ -0,0 +1,231 @@
+use crate::prost::common_proto::TenantKey;
+use crate::protocol::mysql::fingerprint::{strip_literals, SqlFingerprint};
+use crate::server::slow_log::{tenant_label, truncate_sql};
+use crate::server::sql_privacy::{sql_privacy, SqlExport};
+
+use common::metrics::metric_def::{PROXY_QUERY_DIGEST_CALLS, PROXY_QUERY_DIGEST_TIME};
+use common::metrics::{common_labels, counter_handle, Counter};
+use dashmap::DashMap;
+use itertools::Itertools;
+use serde::Serialize;
+use std::cmp::Reverse;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+/// The `fingerprint` label of the statements beyond the digests tracked.
+pub const OTHER_FINGERPRINT: &str = "other";
+
+/// The statements of one shape of a tenant.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct QueryDigestStatus {
+    pub tenant: TenantKey,
+    /// `None` for the statements beyond the digests tracked.
+    pub fingerprint: Option<SqlFingerprint>,
+    /// The first statement of the shape with its literals stripped, `None` if the tenant exports
+    /// no SQL text.
+    pub sql: Option<String>,
+    pub calls: u64,
+    pub total_us: u64,
+    pub max_us: u64,
+}
+
+/// The counters of one statement shape, shared by the sessions of its tenant.
+pub struct QueryDigest {
+    fingerprint: Option<SqlFingerprint>,
+    sql: Option<String>,
+    calls: AtomicU64,
+    total_us: AtomicU64,
+    max_us: AtomicU64,
+    calls_counter: Counter,
+    time_counter: Counter,
+}
+
+impl QueryDigest {
+    fn new(tenant: &TenantKey, fingerprint: Option<SqlFingerprint>, sql: Option<String>) -> Self {
+        let mut labels = common_labels().clone();
+        labels.push(("tenant", tenant_label(tenant)));
+        labels.push((
+            "fingerprint",
+            fingerprint.map_or(OTHER_FINGERPRINT.to_string(), |f| f.to_string()),
+        ));
+        Self {
+            fingerprint,
+            sql,
+            calls: AtomicU64::new(0),
+            total_us: AtomicU64::new(0),
+            max_us: AtomicU64::new(0),
+            calls_counter: counter_handle(PROXY_QUERY_DIGEST_CALLS, &labels),
+            time_counter: counter_handle(PROXY_QUERY_DIGEST_TIME, &labels),
+        }
+    }
+
+    pub fn record(&self, elapsed: Duration) {
+        let elapsed_us = elapsed.as_micros() as u64;
+        self.calls.fetch_add(1, Ordering::Relaxed);
+        self.total_us.fetch_add(elapsed_us, Ordering::Relaxed);
+        self.max_us.fetch_max(elapsed_us, Ordering::Relaxed);
+        self.calls_counter.increment(1);
+        self.time_counter.increment(elapsed_us);
+    }
+
+    fn status(&self, tenant: &TenantKey) -> QueryDigestStatus {
+        QueryDigestStatus {
+            tenant: tenant.clone(),
+            fingerprint: self.fingerprint,
+            sql: self.sql.clone(),
+            calls: self.calls.load(Ordering::Relaxed),
+            total_us: self.total_us.load(Ordering::Relaxed),
+            max_us: self.max_us.load(Ordering::Relaxed),
+        }
+    }
+}
+
+struct TenantDigests {
+    digests: DashMap<SqlFingerprint, Arc<QueryDigest>>,
+    other: Arc<QueryDigest>,
+}
+
+/// `QueryDigests` aggregates the statements of each tenant by their [`SqlFingerprint`], into
+/// metrics labelled with the fingerprint. The digests tracked are bounded, the statements of the
+/// shapes seen once the bound is reached count as [`OTHER_FINGERPRINT`], so a workload of ad hoc
+/// statements cannot explode the cardinality of the metrics.
+pub struct QueryDigests {
+    capacity: usize,
+    len: AtomicUsize,
+    tenants: DashMap<TenantKey, TenantDigests>,
+}
+
+static QUERY_DIGESTS_ONCE: OnceLock<QueryDigests> = OnceLock::new();
+
+/// Initializes the global query digests, must be called before the first query is served.
+pub fn init_query_digests(capacity: usize) -> &'static QueryDigests {
+    QUERY_DIGESTS_ONCE.get_or_init(|| QueryDigests::new(capacity))
+}
+
+pub fn query_digests() -> &'static QueryDigests {
+    QUERY_DIGESTS_ONCE.get_or_init(|| QueryDigests::new(0))
+}
+
+impl QueryDigests {
+    /// Tracks up to `capacity` digests over all the tenants, 0 disables the digests.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            len: AtomicUsize::new(0),
+            tenants: DashMap::new(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    /// The digest of the statement `sql` of `tenant` with the shape `fingerprint`.
+    pub fn digest(
+        &self,
+        tenant: &TenantKey,
+        fingerprint: SqlFingerprint,
+        sql: &[u8],
+    ) -> Arc<QueryDigest> {
+        if let Some(tenant_digests) = self.tenants.get(tenant) {
+            if let Some(digest) = tenant_digests.digests.get(&fingerprint) {
+                return Arc::clone(&digest);
+            }
+        }
+        let tenant_digests = self
+            .tenants
+            .entry(tenant.clone())
+            .or_insert_with(|| TenantDigests {
+                digests: DashMap::new(),
+                other: Arc::new(QueryDigest::new(tenant, None, None)),
+            })
+            .downgrade();
+        if let Some(digest) = tenant_digests.digests.get(&fingerprint) {
+            return Arc::clone(&digest);
+        }
+        if self.len.fetch_add(1, Ordering::Relaxed) >= self.capacity {
+            self.len.fetch_sub(1, Ordering::Relaxed);
+            return Arc::clone(&tenant_digests.other);
+        }
+        let digest = tenant_digests
+            .digests
+            .entry(fingerprint)
+            .or_insert_with(|| {
+                let sql = match sql_privacy().export_of(tenant) {
+                    SqlExport::Off => None,
+                    _ => Some(strip_literals(&String::from_utf8_lossy(&truncate_sql(sql)))),
+                };
+                Arc::new(QueryDigest::new(tenant, Some(fingerprint), sql))
+            });
+        Arc::clone(&digest)
+    }
+
+    /// The digests, the longest total time first.
+    pub fn statuses(&self, limit: Option<usize>) -> Vec<QueryDigestStatus> {
+        self.tenants
+            .iter()
+            .flat_map(|tenant_digests| {
+                let tenant = tenant_digests.key();
+                tenant_digests
+                    .digests
+                    .iter()
+                    .map(|digest| digest.status(tenant))
+                    .chain(Some(tenant_digests.other.status(tenant)))
+                    .filter(|status| status.calls > 0)
+                    .collect::<Vec<_>>()
+            })
+            .sorted_by_key(|status| Reverse(status.total_us))
+            .take(limit.unwrap_or(usize::MAX))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::test_tenant_key;
+    use crate::protocol::mysql::fingerprint::fingerprint;
+    use crate::server::query_digest::QueryDigests;
+    use std::time::Duration;
+
+    #[test]
+    pub fn test_query_digests() {
+        let digests = QueryDigests::new(2);
+        let tenant = test_tenant_key();
+        for (sql, elapsed_ms) in [
+            ("select c from t where id = 1", 3),
+            ("SELECT c FROM t WHERE id = 2", 5),
+            ("update t set c = 'x' where id = 1", 20),
+            ("delete from t where id = 1", 1),
+            ("delete from t where id = 2", 2),
+        ] {
+            let sql = sql.as_bytes();
+            let digest = digests.digest(&tenant, fingerprint(sql), sql);
+            digest.record(Duration::from_millis(elapsed_ms));
+        }
+        let statuses = digests.statuses(None);
+        let summary = statuses
+            .iter()
+            .map(|s| {
+                (
+                    s.sql.as_deref(),
+                    s.calls,
+                    s.total_us / 1000,
+                    s.max_us / 1000,
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            vec![
+                (Some("update t set c = ? where id = ?"), 1, 20, 20),
+                (Some("select c from t where id = ?"), 2, 8, 5),
+                // Beyond the capacity, the deletes count as other statements.
+                (None, 2, 3, 2),
+            ]
+        );
+        assert!(statuses[2].fingerprint.is_none());
+        assert_eq!(digests.statuses(Some(1)).len(), 1);
+        assert!(!QueryDigests::new(0).is_enabled());
+    }
+}
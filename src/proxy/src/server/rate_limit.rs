@@ -0,0 +1,299 @@
+use crate::prost::common_proto::TenantKey;
+use crate::protocol::mysql::error_codes::ErrorKind;
+use crate::protocol::mysql::packet::packet_writer::PacketWriter;
+use crate::protocol::mysql::packet::writers;
+use crate::server::slow_log::tenant_label;
+
+use common::metrics::metric_def::PROXY_COMMAND_RATE_LIMITED;
+use common::metrics::{common_labels, counter_inc};
+use dashmap::DashMap;
+use itertools::Itertools;
+use mysql_common::constants::CapabilityFlags;
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::io::Error;
+use std::sync::{OnceLock, RwLock};
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWrite;
+use tracing::{debug, info};
+
+/// The commands per second a user may send.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RateLimit {
+    /// Commands per second, 0 means unlimited.
+    pub qps: u32,
+    /// Commands sent at once above the rate, 0 allows one second of commands.
+    #[serde(default)]
+    pub burst: u32,
+    /// How long a command above the rate waits for its turn before it is rejected, 0 rejects it
+    /// at once.
+    #[serde(default)]
+    pub max_delay_ms: u64,
+}
+
+impl RateLimit {
+    fn capacity(&self) -> f64 {
+        let burst = if self.burst == 0 {
+            self.qps
+        } else {
+            self.burst
+        };
+        f64::from(burst.max(1))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct RateLimitKey {
+    pub tenant: TenantKey,
+    pub user: String,
+}
+
+/// Overrides the default rate limit for one user of a tenant.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UserRateLimit {
+    #[serde(flatten)]
+    pub key: RateLimitKey,
+    #[serde(flatten)]
+    pub limit: RateLimit,
+}
+
+/// The commands of a user held back since the start.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct UserRateStatus {
+    #[serde(flatten)]
+    pub key: RateLimitKey,
+    pub limit: RateLimit,
+    pub delayed: u64,
+    pub rejected: u64,
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    /// Negative while commands wait for their turn, each holds its token in advance.
+    tokens: f64,
+    updated: Instant,
+    delayed: u64,
+    rejected: u64,
+}
+
+/// `CommandRateLimiter` throttles the commands of each user of a tenant with a token bucket, so a
+/// runaway client cannot saturate the backend it shares with the other tenants. A command above
+/// the rate waits for its turn up to the maximum delay, then it is rejected with
+/// ER_USER_LIMIT_REACHED. The limits of single users are managed through the REST API.
+pub struct CommandRateLimiter {
+    default_limit: RwLock<RateLimit>,
+    users: DashMap<RateLimitKey, RateLimit>,
+    buckets: DashMap<RateLimitKey, TokenBucket>,
+}
+
+static COMMAND_RATE_LIMITER_ONCE: OnceLock<CommandRateLimiter> = OnceLock::new();
+
+/// Initializes the global rate limiter, must be called before the first command is served.
+pub fn init_command_rate_limiter(default_limit: RateLimit) -> &'static CommandRateLimiter {
+    COMMAND_RATE_LIMITER_ONCE.get_or_init(|| CommandRateLimiter::new(default_limit))
+}
+
+pub fn command_rate_limiter() -> &'static CommandRateLimiter {
+    COMMAND_RATE_LIMITER_ONCE.get_or_init(|| CommandRateLimiter::new(RateLimit::default()))
+}
+
+impl CommandRateLimiter {
+    pub fn new(default_limit: RateLimit) -> Self {
+        Self {
+            default_limit: RwLock::new(default_limit),
+            users: DashMap::new(),
+            buckets: DashMap::new(),
+        }
+    }
+
+    pub fn default_limit(&self) -> RateLimit {
+        *self.default_limit.read().unwrap()
+    }
+
+    pub fn set_default_limit(&self, limit: RateLimit) {
+        info!("ProxySrv rate limit default {:?}", limit);
+        *self.default_limit.write().unwrap() = limit;
+    }
+
+    pub fn set_user_limit(&self, user_limit: UserRateLimit) {
+        info!("ProxySrv rate limit set {:?}", user_limit);
+        self.users.insert(user_limit.key, user_limit.limit);
+    }
+
+    pub fn remove_user_limit(&self, key: &RateLimitKey) -> Option<RateLimit> {
+        info!("ProxySrv rate limit removed {:?}", key);
+        self.users.remove(key).map(|(_, limit)| limit)
+    }
+
+    pub fn list(&self) -> Vec<UserRateLimit> {
+        self.users
+            .iter()
+            .map(|e| UserRateLimit {
+                key: e.key().clone(),
+                limit: *e.value(),
+            })
+            .collect()
+    }
+
+    /// The users whose commands were held back, the most rejected first.
+    pub fn statuses(&self) -> Vec<UserRateStatus> {
+        self.buckets
+            .iter()
+            .filter(|e| e.value().delayed > 0 || e.value().rejected > 0)
+            .map(|e| UserRateStatus {
+                key: e.key().clone(),
+                limit: self.limit(e.key()),
+                delayed: e.value().delayed,
+                rejected: e.value().rejected,
+            })
+            .sorted_by_key(|e| (Reverse(e.rejected), Reverse(e.delayed)))
+            .collect()
+    }
+
+    fn limit(&self, key: &RateLimitKey) -> RateLimit {
+        match self.users.get(key) {
+            Some(limit) => *limit,
+            None => self.default_limit(),
+        }
+    }
+
+    /// Takes a token for a command of `key` at `now`: how long the command waits for it, `None`
+    /// if it would wait beyond the maximum delay and is rejected.
+    fn take(&self, key: &RateLimitKey, now: Instant) -> Option<Duration> {
+        let limit = self.limit(key);
+        if limit.qps == 0 {
+            return Some(Duration::ZERO);
+        }
+        let capacity = limit.capacity();
+        let qps = f64::from(limit.qps);
+        let mut bucket = self
+            .buckets
+            .entry(key.clone())
+            .or_insert_with(|| TokenBucket {
+                tokens: capacity,
+                updated: now,
+                delayed: 0,
+                rejected: 0,
+            });
+        let refilled = now.saturating_duration_since(bucket.updated).as_secs_f64() * qps;
+        bucket.tokens = (bucket.tokens + refilled).min(capacity);
+        bucket.updated = now;
+        let wait = if bucket.tokens >= 1.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64((1.0 - bucket.tokens) / qps)
+        };
+        if wait > Duration::from_millis(limit.max_delay_ms) {
+            bucket.rejected += 1;
+            return None;
+        }
+        bucket.tokens -= 1.0;
+        if !wait.is_zero() {
+            bucket.delayed += 1;
+        }
+        Some(wait)
+    }
+
+    /// Waits for the turn of a command of `key`, `false` if it is rejected.
+    pub async fn admit(&self, key: &RateLimitKey) -> bool {
+        let Some(wait) = self.take(key, Instant::now()) else {
+            record_rate_limited(&key.tenant, "rejected");
+            return false;
+        };
+        if !wait.is_zero() {
+            record_rate_limited(&key.tenant, "delayed");
+            tokio::time::sleep(wait).await;
+        }
+        true
+    }
+}
+
+fn record_rate_limited(tenant: &TenantKey, action: &str) {
+    let mut labels = common_labels().clone();
+    labels.push(("tenant", tenant_label(tenant)));
+    labels.push(("action", action.to_string()));
+    counter_inc(PROXY_COMMAND_RATE_LIMITED, 1, Some(&labels));
+}
+
+/// Rejects a command above the rate of its user with an ERR packet. `seq` is the sequence id of
+/// the client command.
+pub async fn reject_rate_limited<W>(
+    key: &RateLimitKey,
+    seq: u8,
+    client_writer: &mut PacketWriter<W>,
+    client_capabilities: CapabilityFlags,
+) -> Result<(), Error>
+where
+    W: AsyncWrite + Send + Unpin,
+{
+    debug!("ProxySrv rate limit rejected a command of {:?}", key);
+    let message = format!(
+        "User '{}' has exceeded the 'max_user_qps' resource (current value: {})",
+        key.user,
+        command_rate_limiter().limit(key).qps
+    );
+    client_writer.set_seq(seq.wrapping_add(1));
+    writers::write_err_packet(
+        ErrorKind::ER_USER_LIMIT_REACHED,
+        message.as_bytes(),
+        client_writer,
+        client_capabilities,
+    )
+    .await?;
+    client_writer.flush_all().await
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::test_tenant_key;
+    use crate::server::rate_limit::{CommandRateLimiter, RateLimit, RateLimitKey, UserRateLimit};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    pub fn test_command_rate_limiter() {
+        let limiter = CommandRateLimiter::new(RateLimit {
+            qps: 10,
+            burst: 2,
+            max_delay_ms: 0,
+        });
+        let key = RateLimitKey {
+            tenant: test_tenant_key(),
+            user: "app".to_string(),
+        };
+        let now = Instant::now();
+        assert_eq!(limiter.take(&key, now), Some(Duration::ZERO));
+        assert_eq!(limiter.take(&key, now), Some(Duration::ZERO));
+        assert_eq!(limiter.take(&key, now), None);
+        // One token back after 100ms at 10 commands per second.
+        let later = now + Duration::from_millis(100);
+        assert_eq!(limiter.take(&key, later), Some(Duration::ZERO));
+
+        // Commands above the rate wait in turn for the next tokens.
+        limiter.set_user_limit(UserRateLimit {
+            key: key.clone(),
+            limit: RateLimit {
+                qps: 10,
+                burst: 1,
+                max_delay_ms: 250,
+            },
+        });
+        let waits = (0..3)
+            .map(|_| limiter.take(&key, later).map(|wait| wait.as_millis()))
+            .collect::<Vec<_>>();
+        assert_eq!(waits, vec![Some(100), Some(200), None]);
+        let statuses = limiter.statuses();
+        assert_eq!(statuses.len(), 1);
+        assert_eq!((statuses[0].delayed, statuses[0].rejected), (2, 2));
+        assert_eq!(statuses[0].limit.max_delay_ms, 250);
+
+        let other = RateLimitKey {
+            user: "admin".to_string(),
+            ..key.clone()
+        };
+        limiter.set_default_limit(RateLimit::default());
+        assert!((0..100).all(|_| limiter.take(&other, now) == Some(Duration::ZERO)));
+        assert!(limiter.remove_user_limit(&key).is_some());
+        assert_eq!(limiter.take(&key, later), Some(Duration::ZERO));
+        assert!(limiter.list().is_empty());
+    }
+}
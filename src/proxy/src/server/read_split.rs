@@ -0,0 +1,343 @@
+use crate::backend::backend_mgr::BackendMgr;
+use crate::backend::pool::pooled_conn_mgr::PooledConnMgr;
+use crate::backend::pool::BackendConn;
+use crate::backend::replica::replica_registry;
+use crate::backend::{handshake_tenant_key, DbConnPhase, DbUserConnLifeCycle};
+use crate::prost::common_proto::TenantKey;
+use crate::protocol::mysql::basic::HandshakeResponse;
+use crate::protocol::mysql::constants::CommandCode;
+use crate::server::auth::authenticator::authenticate_identity;
+use crate::server::failover::replay_command;
+use crate::server::forwarder::session_state::SharedSessionState;
+use crate::server::mirror::{first_keyword, is_read_only, is_use_stmt};
+use crate::server::recent_errors::recent_errors;
+use crate::server::slow_log::tenant_label;
+
+use common::metrics::metric_def::PROXY_READ_SPLIT_QUERIES;
+use common::metrics::{common_labels, counter_handle, Counter};
+use deadpool::managed::Object;
+use std::io::Error;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::OwnedMutexGuard;
+use tracing::{debug, warn};
+
+/// How long a session waits for a replica connection before reading from the primary.
+const READ_LEG_CHECKOUT_TIMEOUT: Duration = Duration::from_secs(1);
+/// Reads stay on the primary for this long after the read leg failed to open.
+const READ_LEG_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Whether a read-only query depends on state of the session the replicas do not share, e.g.
+/// user variables, or it has side effects, e.g. locks.
+fn is_session_dependent(sql: &str) -> bool {
+    let upper = sql.to_ascii_uppercase();
+    upper.contains('@')
+        || [
+            "LAST_INSERT_ID",
+            "FOUND_ROWS",
+            "ROW_COUNT",
+            "GET_LOCK",
+            "RELEASE_LOCK",
+        ]
+        .iter()
+        .any(|function| upper.contains(function))
+}
+
+/// Whether a statement changes state of the session the replicas would not see.
+fn pins_session(sql: &str) -> bool {
+    let keyword = first_keyword(sql);
+    ["SET", "LOCK", "PREPARE", "HANDLER"]
+        .iter()
+        .any(|pinning| keyword.eq_ignore_ascii_case(pinning))
+        || (keyword.eq_ignore_ascii_case("CREATE")
+            && sql.to_ascii_uppercase().contains("TEMPORARY"))
+}
+
+/// The replica connection of a session.
+struct ReadLeg {
+    conn: Object<PooledConnMgr>,
+    addr: String,
+    /// The database change of the session the leg has applied.
+    database_version: u64,
+}
+
+/// A read served by the replica connection. Dropped before it is
+/// [`finished`](ReplicaRead::finish), e.g. as the forwarding failed, it invalidates the
+/// connection, whose replica may still be answering.
+pub struct ReplicaRead {
+    conn: OwnedMutexGuard<BackendConn>,
+    invalidated: Arc<AtomicBool>,
+    session_state: SharedSessionState,
+    finished: bool,
+}
+
+impl ReplicaRead {
+    pub fn conn(&mut self) -> &mut BackendConn {
+        &mut self.conn
+    }
+
+    pub fn session_state(&self) -> &SharedSessionState {
+        &self.session_state
+    }
+
+    pub fn finish(mut self) {
+        self.finished = true;
+    }
+}
+
+impl Drop for ReplicaRead {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.invalidated.store(true, Ordering::Release);
+        }
+    }
+}
+
+/// `ReadSplit` routes the read-only queries of a session the read/write router serves to a
+/// replica, outside transactions, while the session and every other command stay on the
+/// primary. The replica connection is authenticated by the proxy, so only sessions of mapped
+/// identities are split. A session that changes its state, e.g. with `SET` or a temporary table,
+/// reads from the primary from then on; database changes are replayed on the replica.
+pub struct ReadSplit {
+    primary_addr: String,
+    database: Option<(CommandCode, Vec<u8>)>,
+    database_version: u64,
+    pinned: bool,
+    leg: Option<ReadLeg>,
+    retry_at: Option<Instant>,
+    replica_reads: Counter,
+    primary_reads: Counter,
+}
+
+impl ReadSplit {
+    /// Splits a session if the router is read/write and the proxy holds its backend credential.
+    /// `primary_addr` is the backend the session connection belongs to.
+    pub fn start(
+        backend_mgr: &BackendMgr,
+        handshake_response: &HandshakeResponse,
+        primary_addr: Option<&str>,
+    ) -> Option<Self> {
+        if !backend_mgr.is_read_write_router() || handshake_response.identity.is_none() {
+            return None;
+        }
+        let primary_addr = primary_addr?.to_string();
+        let tenant = handshake_tenant_key(handshake_response);
+        let with = |role: &str| {
+            let mut labels = common_labels().clone();
+            labels.push(("tenant", tenant_label(&tenant)));
+            labels.push(("role", role.to_string()));
+            labels
+        };
+        Some(Self {
+            primary_addr,
+            database: None,
+            database_version: 0,
+            pinned: false,
+            leg: None,
+            retry_at: None,
+            replica_reads: counter_handle(PROXY_READ_SPLIT_QUERIES, &with("replica")),
+            primary_reads: counter_handle(PROXY_READ_SPLIT_QUERIES, &with("primary")),
+        })
+    }
+
+    /// Whether a command of the client may read from a replica.
+    pub fn is_replica_read(
+        &self,
+        com_code: CommandCode,
+        payload: &[u8],
+        in_transaction: bool,
+    ) -> bool {
+        if self.pinned || in_transaction || com_code != CommandCode::ComQuery {
+            return false;
+        }
+        std::str::from_utf8(payload)
+            .is_ok_and(|sql| is_read_only(sql) && !is_session_dependent(sql))
+    }
+
+    /// Tracks a command the primary serves, for the state the replica has to follow.
+    pub fn observe(&mut self, com_code: CommandCode, payload: &[u8]) {
+        match com_code {
+            CommandCode::ComInitDB => self.change_database(com_code, payload),
+            CommandCode::ComQuery => {
+                let sql = String::from_utf8_lossy(payload);
+                if is_use_stmt(&sql) {
+                    self.change_database(com_code, payload);
+                } else if pins_session(&sql) {
+                    debug!("ProxySrv read split pinned to the primary");
+                    self.pinned = true;
+                }
+            }
+            CommandCode::ComStmtPrepare => self.pinned = true,
+            CommandCode::ComResetConnection => self.pinned = false,
+            _ => {}
+        }
+    }
+
+    fn change_database(&mut self, com_code: CommandCode, payload: &[u8]) {
+        self.database = Some((com_code, payload.to_vec()));
+        self.database_version += 1;
+    }
+
+    /// The replica connection a command of the client reads from, see
+    /// [`read_leg`](Self::read_leg). `None` if the primary serves it, the command is tracked
+    /// then, see [`observe`](Self::observe).
+    pub async fn route(
+        &mut self,
+        backend_mgr: &BackendMgr,
+        handshake_response: &HandshakeResponse,
+        com_code: CommandCode,
+        payload: &[u8],
+        in_transaction: bool,
+    ) -> Option<ReplicaRead> {
+        if self.is_replica_read(com_code, payload, in_transaction) {
+            return self.read_leg(backend_mgr, handshake_response).await;
+        }
+        self.observe(com_code, payload);
+        None
+    }
+
+    /// The replica connection to read from, opened on first use. `None` if the read goes to the
+    /// primary: no replica is within the lag threshold, or the replica failed.
+    pub async fn read_leg(
+        &mut self,
+        backend_mgr: &BackendMgr,
+        handshake_response: &HandshakeResponse,
+    ) -> Option<ReplicaRead> {
+        let tenant = handshake_tenant_key(handshake_response);
+        if self.leg.as_ref().is_some_and(|leg| {
+            leg.conn.is_invalidated() || replica_registry().is_lagging(&tenant, &leg.addr)
+        }) {
+            debug!("ProxySrv read split leaves the replica");
+            self.leg = None;
+        }
+        if self.leg.is_none() && !self.retry_at.is_some_and(|at| Instant::now() < at) {
+            let opened = self
+                .open_leg(backend_mgr, handshake_response, &tenant)
+                .await;
+            match opened {
+                Ok(leg) => self.leg = leg,
+                Err(e) => {
+                    warn!("ProxySrv read split replica connection failed {e:?}");
+                    recent_errors().record("read_split", e.to_string());
+                    self.retry_at = Some(Instant::now() + READ_LEG_RETRY_INTERVAL);
+                }
+            }
+        }
+        let Some(leg) = self.leg.as_mut() else {
+            self.primary_reads.increment(1);
+            return None;
+        };
+        let mut guard = leg.conn.inner_conn.clone().lock_owned().await;
+        if leg.database_version != self.database_version {
+            if let Err(e) = sync_database(&mut guard, self.database.as_ref()).await {
+                // The replica cannot follow the session, e.g. the database is not replicated.
+                warn!(
+                    "ProxySrv read split database change failed on {}: {e:?}",
+                    leg.addr
+                );
+                drop(guard);
+                self.fail();
+                self.pinned = true;
+                self.primary_reads.increment(1);
+                return None;
+            }
+            leg.database_version = self.database_version;
+        }
+        self.replica_reads.increment(1);
+        Some(ReplicaRead {
+            conn: guard,
+            invalidated: Arc::clone(&leg.conn.invalidated),
+            session_state: Arc::clone(&leg.conn.session_state),
+            finished: false,
+        })
+    }
+
+    /// Closes the replica connection after it failed to serve a read.
+    pub fn fail(&mut self) {
+        if let Some(leg) = self.leg.take() {
+            leg.conn.invalidated.store(true, Ordering::Release);
+        }
+    }
+
+    async fn open_leg(
+        &self,
+        backend_mgr: &BackendMgr,
+        handshake_response: &HandshakeResponse,
+        tenant: &TenantKey,
+    ) -> Result<Option<ReadLeg>, Error> {
+        let pool = backend_mgr
+            .connect_to_read_backend(handshake_response)
+            .await?;
+        let addr = pool.manager().get_addr().await;
+        if addr == self.primary_addr {
+            // Every replica lags behind, the session connection serves the read.
+            return Ok(None);
+        }
+        let conn = tokio::time::timeout(READ_LEG_CHECKOUT_TIMEOUT, pool.get())
+            .await
+            .map_err(|_| Error::new(std::io::ErrorKind::TimedOut, "replica pool exhausted"))?
+            .map_err(|e| Error::new(std::io::ErrorKind::NotConnected, e.to_string()))?;
+        let command_phase =
+            conn.get_conn_life_cycle().await.conn_phase() == Some(DbConnPhase::Command);
+        let authenticated = {
+            let mut guard = conn.inner_conn.lock().await;
+            let (reader, writer) = &mut *guard;
+            authenticate_identity(
+                handshake_response,
+                command_phase,
+                conn.compression,
+                writer,
+                reader,
+            )
+            .await
+        };
+        if let Err(e) = authenticated {
+            conn.invalidated.store(true, Ordering::Release);
+            return Err(e);
+        }
+        conn.update_conn_life_cycle(DbUserConnLifeCycle::new_conn_life_cycle(
+            handshake_response.db_user_string(),
+            DbConnPhase::Command,
+        ))
+        .await;
+        conn.stmt_cache.lock().await.clear();
+        conn.session_state.reset();
+        debug!("ProxySrv read split of {tenant:?} reads from {addr}");
+        Ok(Some(ReadLeg {
+            conn,
+            addr,
+            database_version: 0,
+        }))
+    }
+}
+
+/// Replays the last database change of the session on the replica connection.
+async fn sync_database(
+    conn: &mut BackendConn,
+    database: Option<&(CommandCode, Vec<u8>)>,
+) -> Result<(), Error> {
+    let Some((com_code, payload)) = database else {
+        return Ok(());
+    };
+    replay_command(conn, *com_code, payload).await
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::server::read_split::{is_session_dependent, pins_session};
+
+    #[test]
+    pub fn test_read_split_classification() {
+        assert!(!is_session_dependent("SELECT c FROM sbtest1 WHERE id = 1"));
+        assert!(is_session_dependent("SELECT @last"));
+        assert!(is_session_dependent("select @@session.sql_mode"));
+        assert!(is_session_dependent("SELECT LAST_INSERT_ID()"));
+        assert!(pins_session("SET autocommit = 0"));
+        assert!(pins_session("/* app */ set @x = 1"));
+        assert!(pins_session("CREATE TEMPORARY TABLE t (id INT)"));
+        assert!(pins_session("LOCK TABLES t READ"));
+        assert!(!pins_session("CREATE TABLE t (id INT)"));
+        assert!(!pins_session("INSERT INTO t VALUES (1)"));
+    }
+}
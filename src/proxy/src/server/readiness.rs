@@ -0,0 +1,83 @@
+use crate::backend::backend_mgr::BackendMgr;
+use crate::prost::common_proto::ServiceStatus;
+
+use serde::Serialize;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Set once the proxy prepared the pools of its router, see
+/// [`HaentglServer::initialize_async`](crate::server::haentgl_server::HaentglServer::initialize_async).
+static ROUTER_INITIALIZED: AtomicBool = AtomicBool::new(false);
+
+pub fn mark_router_initialized() {
+    ROUTER_INITIALIZED.store(true, Ordering::Release);
+}
+
+/// Whether the proxy can take traffic, reported by `/readyz` and by the grpc.health.v1 service
+/// of the cp target, so probes and the control plane route clients only to ready proxies.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Readiness {
+    pub router_initialized: bool,
+    /// Backends reported Ready to the router.
+    pub ready_backends: usize,
+    /// Whether the topology stream of the control plane is up, `None` with static backends.
+    pub cp_connected: Option<bool>,
+}
+
+impl Readiness {
+    pub async fn of(backend_mgr: &BackendMgr) -> Self {
+        let ready_backends = backend_mgr
+            .discovered_backends()
+            .await
+            .iter()
+            .filter(|backend| backend.status == ServiceStatus::Ready)
+            .count();
+        Self {
+            router_initialized: ROUTER_INITIALIZED.load(Ordering::Acquire),
+            ready_backends,
+            cp_connected: backend_mgr.cp_connected(),
+        }
+    }
+
+    /// Why the proxy cannot take traffic, `None` if it is ready.
+    pub fn not_ready_reason(&self) -> Option<&'static str> {
+        if !self.router_initialized {
+            Some("the router is initializing")
+        } else if self.cp_connected == Some(false) {
+            Some("the control plane is not connected")
+        } else if self.ready_backends == 0 {
+            Some("no backend is ready")
+        } else {
+            None
+        }
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.not_ready_reason().is_none()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::server::readiness::Readiness;
+
+    #[test]
+    pub fn test_readiness() {
+        let mut readiness = Readiness::default();
+        assert_eq!(
+            readiness.not_ready_reason(),
+            Some("the router is initializing")
+        );
+        readiness.router_initialized = true;
+        assert_eq!(readiness.not_ready_reason(), Some("no backend is ready"));
+        readiness.ready_backends = 2;
+        assert!(readiness.is_ready());
+
+        readiness.cp_connected = Some(false);
+        assert_eq!(
+            readiness.not_ready_reason(),
+            Some("the control plane is not connected")
+        );
+        readiness.cp_connected = Some(true);
+        assert!(readiness.is_ready());
+    }
+}
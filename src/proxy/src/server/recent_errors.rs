@@ -0,0 +1,72 @@
+use chrono::{Local, SecondsFormat};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::{Mutex, OnceLock};
+
+/// Errors kept for the status page, the oldest are dropped first.
+pub const RECENT_ERRORS_CAPACITY: usize = 100;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RecentError {
+    /// RFC 3339 time the error occurred.
+    pub time: String,
+    /// Where the error occurred, e.g. `auth` or the backend address of a proxy event.
+    pub source: String,
+    pub message: String,
+}
+
+/// `RecentErrors` remembers the last errors of client sessions and the proxy events, so the
+/// status page can show them without a log pipeline.
+pub struct RecentErrors {
+    capacity: usize,
+    errors: Mutex<VecDeque<RecentError>>,
+}
+
+static RECENT_ERRORS_ONCE: OnceLock<RecentErrors> = OnceLock::new();
+
+pub fn recent_errors() -> &'static RecentErrors {
+    RECENT_ERRORS_ONCE.get_or_init(|| RecentErrors::new(RECENT_ERRORS_CAPACITY))
+}
+
+impl RecentErrors {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            errors: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    pub fn record(&self, source: &str, message: String) {
+        let mut errors = self.errors.lock().unwrap();
+        if errors.len() >= self.capacity {
+            errors.pop_front();
+        }
+        errors.push_back(RecentError {
+            time: Local::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            source: source.to_string(),
+            message,
+        });
+    }
+
+    /// The recorded errors, newest first.
+    pub fn list(&self) -> Vec<RecentError> {
+        self.errors.lock().unwrap().iter().rev().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::server::recent_errors::RecentErrors;
+
+    #[test]
+    pub fn test_recent_errors() {
+        let errors = RecentErrors::new(2);
+        errors.record("auth", "access denied".to_string());
+        errors.record("10.0.0.1:3306", "backend went offline".to_string());
+        errors.record("session", "broken pipe".to_string());
+        let list = errors.list();
+        assert_eq!(list.len(), 2);
+        assert_eq!(list[0].source, "session");
+        assert_eq!(list[1].message, "backend went offline");
+    }
+}
@@ -0,0 +1,208 @@
+use crate::backend::BackendInstance;
+use crate::server::proxy_cli_args::ProxyServerArgs;
+use crate::server::proxy_config::load_proxy_config;
+use crate::server::recent_errors::recent_errors;
+use crate::server::request_id::{current_request_id, new_request_id, with_request_id};
+
+use common::ShutdownMessage;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::collections::VecDeque;
+use std::io::Error;
+use std::sync::{Mutex, OnceLock};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+/// The configuration keys a reload applies, the others take a restart.
+pub const RUNTIME_KEYS: [&str; 4] = [
+    "log_level",
+    "pool_max_size",
+    "slow_query_ms",
+    "static_backends",
+];
+
+/// The part of the configuration applied without a restart, see [`RUNTIME_KEYS`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuntimeConfig {
+    pub log_level: Option<String>,
+    pub pool_max_size: u32,
+    pub slow_query_ms: u64,
+    pub static_backends: VecDeque<BackendInstance>,
+    /// The admin request that reloaded the configuration, `None` for the one the process
+    /// started with.
+    pub request_id: Option<String>,
+}
+
+/// The keys a reload changed.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ReloadReport {
+    pub applied: Vec<String>,
+    /// Changed in the configuration, still ignored until the proxy restarts.
+    pub restart_required: Vec<String>,
+}
+
+type ConfigLoader = Box<dyn Fn() -> Result<ProxyServerArgs, Error> + Send + Sync>;
+
+/// `ConfigReloader` loads the configuration again, on `SIGHUP` or `POST /admin/reload`, and
+/// publishes its runtime part to the subscribers: the backend manager resizes the pools and
+/// replaces the static backends, the process applies the log level and the slow query
+/// threshold. A configuration that fails to load is not applied.
+pub struct ConfigReloader {
+    load: ConfigLoader,
+    current: Mutex<Map<String, Value>>,
+    runtime_tx: watch::Sender<RuntimeConfig>,
+}
+
+static CONFIG_RELOADER_ONCE: OnceLock<ConfigReloader> = OnceLock::new();
+
+/// Initializes the global reloader with the configuration the process started with, reloads
+/// read the same sources again, see [`load_proxy_config`].
+pub fn init_config_reloader(config: &ProxyServerArgs) -> &'static ConfigReloader {
+    CONFIG_RELOADER_ONCE.get_or_init(|| ConfigReloader::new(config, Box::new(load_proxy_config)))
+}
+
+/// The global reloader, `None` before it is initialized.
+pub fn config_reloader() -> Option<&'static ConfigReloader> {
+    CONFIG_RELOADER_ONCE.get()
+}
+
+fn to_object(config: &ProxyServerArgs) -> Map<String, Value> {
+    match serde_json::to_value(config) {
+        Ok(Value::Object(object)) => object,
+        _ => Map::new(),
+    }
+}
+
+impl ConfigReloader {
+    pub fn new(config: &ProxyServerArgs, load: ConfigLoader) -> Self {
+        Self {
+            load,
+            current: Mutex::new(to_object(config)),
+            runtime_tx: watch::Sender::new(config.runtime_config()),
+        }
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<RuntimeConfig> {
+        self.runtime_tx.subscribe()
+    }
+
+    pub fn reload(&self) -> Result<ReloadReport, Error> {
+        let config = (self.load)()?;
+        let reloaded = to_object(&config);
+        let mut current = self.current.lock().unwrap();
+        let mut report = ReloadReport::default();
+        for (key, value) in &reloaded {
+            if current.get(key) == Some(value) {
+                continue;
+            }
+            if RUNTIME_KEYS.contains(&key.as_str()) {
+                report.applied.push(key.clone());
+            } else {
+                report.restart_required.push(key.clone());
+            }
+        }
+        report.applied.sort();
+        report.restart_required.sort();
+        let request_id = current_request_id();
+        let runtime = RuntimeConfig {
+            request_id: request_id.clone(),
+            ..config.runtime_config()
+        };
+        self.runtime_tx.send_if_modified(|published| {
+            // A reload changing no setting is not published, whatever request it came from.
+            published.request_id.clone_from(&runtime.request_id);
+            let modified = *published != runtime;
+            *published = runtime;
+            modified
+        });
+        *current = reloaded;
+        info!(
+            "ProxySrv configuration reloaded, applied {:?}, restart required {:?} request_id={:?}",
+            report.applied, report.restart_required, request_id
+        );
+        Ok(report)
+    }
+}
+
+/// Reloads the configuration on every `SIGHUP`, each reload under a new request id.
+pub async fn run_reload_on_hangup(mut shutdown_rx: watch::Receiver<ShutdownMessage>) {
+    let Some(reloader) = config_reloader() else {
+        return;
+    };
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            warn!("ProxySrv SIGHUP handler failed to install. cause by {e:?}");
+            return;
+        }
+    };
+    loop {
+        tokio::select! {
+            _ = shutdown_rx.changed() => return,
+            received = hangup.recv() => {
+                if received.is_none() {
+                    return;
+                }
+                let reloaded = with_request_id(new_request_id(), async { reloader.reload() }).await;
+                if let Err(e) = reloaded {
+                    warn!("ProxySrv configuration reload failed {e}");
+                    recent_errors().record("reload", e.to_string());
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::server::proxy_cli_args::ProxyServerArgs;
+    use crate::server::reload::ConfigReloader;
+    use crate::server::request_id::with_request_id;
+
+    #[tokio::test]
+    pub async fn test_config_reload() {
+        let path = std::env::temp_dir().join(format!("{}-reload.toml", std::process::id()));
+        std::fs::write(&path, "slow_query_ms = 500\n").unwrap();
+        let load_path = path.clone();
+        let load = move || {
+            let args = ["haentgl", "--config", load_path.to_str().unwrap()];
+            ProxyServerArgs::from_sources(args, vec![])
+        };
+        let config = load().unwrap();
+        let reloader = ConfigReloader::new(&config, Box::new(load));
+        let mut runtime_rx = reloader.subscribe();
+        assert_eq!(runtime_rx.borrow_and_update().slow_query_ms, 500);
+
+        let report = reloader.reload().unwrap();
+        assert!(report.applied.is_empty() && report.restart_required.is_empty());
+        assert!(!runtime_rx.has_changed().unwrap());
+
+        std::fs::write(
+            &path,
+            "slow_query_ms = 50\nport = 3999\nstatic_backends = [\"127.0.0.1:3307\"]\n\
+             [pool]\nmax_size = 8\n",
+        )
+        .unwrap();
+        let report = with_request_id("req-reload".to_string(), async { reloader.reload() })
+            .await
+            .unwrap();
+        assert_eq!(
+            report.applied,
+            vec!["pool_max_size", "slow_query_ms", "static_backends"]
+        );
+        assert_eq!(report.restart_required, vec!["port"]);
+        let runtime = runtime_rx.borrow_and_update().clone();
+        assert_eq!(runtime.slow_query_ms, 50);
+        assert_eq!(runtime.pool_max_size, 8);
+        assert_eq!(runtime.static_backends[0].addr, "127.0.0.1:3307");
+        assert_eq!(runtime.request_id.as_deref(), Some("req-reload"));
+
+        // A configuration that does not load is not applied.
+        std::fs::write(&path, "log_level = \"LOUD\"\n").unwrap();
+        let e = reloader.reload().unwrap_err().to_string();
+        assert!(e.contains("log_level"), "{e}");
+        assert!(!runtime_rx.has_changed().unwrap());
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,67 @@
+use nanoid::nanoid;
+use std::future::Future;
+use tracing::{info_span, Instrument};
+
+/// Header carrying the id of an admin request, taken from the caller or generated.
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+pub fn new_request_id() -> String {
+    nanoid!()
+}
+
+/// Runs an admin action with `request_id`. Log lines of the action carry the id through the
+/// `admin` span, and subsystems record [`current_request_id`] with the effects they defer to the
+/// data path, e.g. a killed session or an injected fault.
+pub async fn with_request_id<F: Future>(request_id: String, action: F) -> F::Output {
+    let span = info_span!("admin", request_id = %request_id);
+    REQUEST_ID.scope(request_id, action.instrument(span)).await
+}
+
+/// Runs an effect an admin request deferred to another task, e.g. a drain it spawned or a pool
+/// resize it published, with the id of that request if there was one.
+pub async fn with_deferred_request_id<F: Future>(
+    request_id: Option<String>,
+    action: F,
+) -> F::Output {
+    match request_id {
+        Some(request_id) => with_request_id(request_id, action).await,
+        None => action.await,
+    }
+}
+
+/// The id of the admin request being served by the current task.
+pub fn current_request_id() -> Option<String> {
+    REQUEST_ID.try_with(|request_id| request_id.clone()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::server::request_id::{
+        current_request_id, with_deferred_request_id, with_request_id,
+    };
+
+    #[tokio::test]
+    pub async fn test_request_id_scope() {
+        assert_eq!(current_request_id(), None);
+        let request_id = with_request_id("req-1".to_string(), async {
+            tokio::task::yield_now().await;
+            current_request_id()
+        })
+        .await;
+        assert_eq!(request_id.as_deref(), Some("req-1"));
+        assert_eq!(current_request_id(), None);
+
+        let deferred =
+            with_deferred_request_id(Some("req-2".to_string()), async { current_request_id() })
+                .await;
+        assert_eq!(deferred.as_deref(), Some("req-2"));
+        assert_eq!(
+            with_deferred_request_id(None, async { current_request_id() }).await,
+            None
+        );
+    }
+}
@@ -0,0 +1,358 @@
+use crate::prost::common_proto::TenantKey;
+use crate::server::recent_errors::recent_errors;
+use crate::server::slow_log::tenant_label;
+
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use std::str::FromStr;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tracing::{debug, warn};
+
+/// The statement class of the routing decision taken when a session connects, before the client
+/// sent any statement.
+pub const CONNECT_CLASS: &str = "connect";
+/// Cached decisions beyond this evict the expired ones, or all of them.
+const MAX_CACHED_DECISIONS: usize = 65536;
+/// The engine is not asked again for this long after it failed, sessions get the fail mode.
+const FAILURE_BACKOFF: Duration = Duration::from_secs(1);
+
+/// The external engine deciding the routes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PolicyEngine {
+    /// An OPA data API, e.g. `http://127.0.0.1:8181/v1/data/haentgl/route`. The input is posted as
+    /// `{"input": ..}` and the decision read from `result`, an undefined result denies.
+    Opa,
+    /// A webhook answering the posted input with the decision.
+    #[default]
+    Webhook,
+}
+
+impl FromStr for PolicyEngine {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "opa" => Ok(PolicyEngine::Opa),
+            "webhook" => Ok(PolicyEngine::Webhook),
+            _ => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("unknown policy engine {s:?}, expected opa or webhook"),
+            )),
+        }
+    }
+}
+
+impl fmt::Display for PolicyEngine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PolicyEngine::Opa => write!(f, "opa"),
+            PolicyEngine::Webhook => write!(f, "webhook"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RoutePolicyConfig {
+    /// The policy is off if not set.
+    pub url: Option<String>,
+    pub engine: PolicyEngine,
+    pub timeout: Duration,
+    /// How long a decision is reused for the same input.
+    pub cache_ttl: Duration,
+    /// Sessions are allowed, instead of denied, while the engine fails.
+    pub fail_open: bool,
+}
+
+/// What the engine decides on.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
+pub struct PolicyInput {
+    pub tenant: TenantKey,
+    pub user: String,
+    pub client_ip: Option<String>,
+    pub statement_class: &'static str,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RouteDecision {
+    Allow,
+    /// Allowed on this backend instead of the one the balancer picks.
+    Route(String),
+    Deny(String),
+}
+
+/// The decision as the engine answers it.
+#[derive(Debug, Deserialize)]
+struct EngineDecision {
+    #[serde(default)]
+    allow: bool,
+    /// The address of a backend of the tenant to route to.
+    backend: Option<String>,
+    reason: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OpaResponse {
+    result: Option<EngineDecision>,
+}
+
+/// Parses the answer of `engine`.
+pub fn parse_decision(engine: PolicyEngine, body: &[u8]) -> Result<RouteDecision, Error> {
+    let decision = match engine {
+        PolicyEngine::Opa => serde_json::from_slice::<OpaResponse>(body)?.result,
+        PolicyEngine::Webhook => Some(serde_json::from_slice::<EngineDecision>(body)?),
+    };
+    Ok(match decision {
+        None => RouteDecision::Deny("no route policy decision".to_string()),
+        Some(decision) if !decision.allow => RouteDecision::Deny(
+            decision
+                .reason
+                .unwrap_or("denied by the route policy".to_string()),
+        ),
+        Some(EngineDecision {
+            backend: Some(backend),
+            ..
+        }) if !backend.is_empty() => RouteDecision::Route(backend),
+        Some(_) => RouteDecision::Allow,
+    })
+}
+
+/// `RoutePolicy` asks an external policy engine, e.g. an OPA sidecar, whether a session may
+/// connect and where to route it, so organizations keep their access policy in one place. The
+/// decisions are cached per input; while the engine fails, sessions are allowed or denied as
+/// configured.
+pub struct RoutePolicy {
+    config: RoutePolicyConfig,
+    client: reqwest::Client,
+    cache: DashMap<PolicyInput, (RouteDecision, Instant)>,
+    backoff_until: Mutex<Option<Instant>>,
+}
+
+static ROUTE_POLICY_ONCE: OnceLock<RoutePolicy> = OnceLock::new();
+
+/// Initializes the global route policy, must be called before the first client connects.
+pub fn init_route_policy(config: RoutePolicyConfig) -> &'static RoutePolicy {
+    ROUTE_POLICY_ONCE.get_or_init(|| RoutePolicy::new(config))
+}
+
+pub fn route_policy() -> &'static RoutePolicy {
+    ROUTE_POLICY_ONCE.get_or_init(|| RoutePolicy::new(RoutePolicyConfig::default()))
+}
+
+impl RoutePolicy {
+    pub fn new(config: RoutePolicyConfig) -> Self {
+        let client = reqwest::ClientBuilder::new()
+            .timeout(config.timeout)
+            .build()
+            .unwrap_or_default();
+        Self {
+            config,
+            client,
+            cache: DashMap::new(),
+            backoff_until: Mutex::new(None),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.config.url.is_some()
+    }
+
+    pub fn cached(&self) -> usize {
+        self.cache.len()
+    }
+
+    /// Forgets the cached decisions, e.g. once the policy changed.
+    pub fn clear_cache(&self) -> usize {
+        let cached = self.cache.len();
+        self.cache.clear();
+        cached
+    }
+
+    pub async fn decide(&self, input: PolicyInput) -> RouteDecision {
+        let Some(url) = &self.config.url else {
+            return RouteDecision::Allow;
+        };
+        if let Some(cached) = self.cache.get(&input) {
+            let (decision, decided_at) = cached.value();
+            if decided_at.elapsed() < self.config.cache_ttl {
+                return decision.clone();
+            }
+        }
+        let backing_off = self
+            .backoff_until
+            .lock()
+            .unwrap()
+            .is_some_and(|until| Instant::now() < until);
+        let asked = if backing_off {
+            Err(Error::new(ErrorKind::WouldBlock, "backing off"))
+        } else {
+            self.ask(url, &input).await
+        };
+        match asked {
+            Ok(decision) => {
+                debug!(
+                    "ProxySrv route policy of {} user {}: {decision:?}",
+                    tenant_label(&input.tenant),
+                    input.user
+                );
+                self.cache_decision(input, decision.clone());
+                decision
+            }
+            Err(e) => {
+                if !backing_off {
+                    warn!("ProxySrv route policy engine failed, cause by {e:?}");
+                    recent_errors().record("route_policy", e.to_string());
+                    *self.backoff_until.lock().unwrap() = Some(Instant::now() + FAILURE_BACKOFF);
+                }
+                if self.config.fail_open {
+                    RouteDecision::Allow
+                } else {
+                    RouteDecision::Deny("route policy unavailable".to_string())
+                }
+            }
+        }
+    }
+
+    async fn ask(&self, url: &str, input: &PolicyInput) -> Result<RouteDecision, Error> {
+        let request = self.client.post(url);
+        let request = match self.config.engine {
+            PolicyEngine::Opa => request.json(&serde_json::json!({ "input": input })),
+            PolicyEngine::Webhook => request.json(input),
+        };
+        let body = request
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(Error::other)?
+            .bytes()
+            .await
+            .map_err(Error::other)?;
+        parse_decision(self.config.engine, &body)
+    }
+
+    fn cache_decision(&self, input: PolicyInput, decision: RouteDecision) {
+        if self.cache.len() >= MAX_CACHED_DECISIONS {
+            let ttl = self.config.cache_ttl;
+            self.cache
+                .retain(|_, (_, decided_at)| decided_at.elapsed() < ttl);
+            if self.cache.len() >= MAX_CACHED_DECISIONS {
+                self.cache.clear();
+            }
+        }
+        self.cache.insert(input, (decision, Instant::now()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::test_tenant_key;
+    use crate::server::route_policy::{
+        parse_decision, PolicyEngine, PolicyInput, RouteDecision, RoutePolicy, RoutePolicyConfig,
+        CONNECT_CLASS,
+    };
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Answers every request with `body`, counting the requests.
+    async fn engine(body: &'static str) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!(
+            "http://{}/v1/data/haentgl/route",
+            listener.local_addr().unwrap()
+        );
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counted = Arc::clone(&requests);
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                counted.fetch_add(1, Ordering::SeqCst);
+                let mut buf = vec![0; 4096];
+                let _ = stream.read(&mut buf).await;
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\n\
+                     connection: close\r\n\r\n{body}",
+                    body.len()
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+        (url, requests)
+    }
+
+    #[tokio::test]
+    pub async fn test_route_policy() {
+        assert_eq!(
+            parse_decision(PolicyEngine::Webhook, br#"{"allow": true}"#).unwrap(),
+            RouteDecision::Allow
+        );
+        assert_eq!(
+            parse_decision(
+                PolicyEngine::Webhook,
+                br#"{"allow": false, "reason": "off hours"}"#
+            )
+            .unwrap(),
+            RouteDecision::Deny("off hours".to_string())
+        );
+        assert!(matches!(
+            parse_decision(PolicyEngine::Opa, b"{}").unwrap(),
+            RouteDecision::Deny(_)
+        ));
+        assert!(parse_decision(PolicyEngine::Opa, b"<html>").is_err());
+
+        let (url, requests) =
+            engine(r#"{"result": {"allow": true, "backend": "10.0.0.2:3306"}}"#).await;
+        let policy = RoutePolicy::new(RoutePolicyConfig {
+            url: Some(url),
+            engine: PolicyEngine::Opa,
+            timeout: Duration::from_secs(5),
+            cache_ttl: Duration::from_secs(60),
+            fail_open: false,
+        });
+        let input = PolicyInput {
+            tenant: test_tenant_key(),
+            user: "app".to_string(),
+            client_ip: Some("192.0.2.7".to_string()),
+            statement_class: CONNECT_CLASS,
+        };
+        for _ in 0..3 {
+            assert_eq!(
+                policy.decide(input.clone()).await,
+                RouteDecision::Route("10.0.0.2:3306".to_string())
+            );
+        }
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+        assert_eq!(policy.clear_cache(), 1);
+
+        // Nothing listens on the port of a dropped listener.
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", closed.local_addr().unwrap());
+        drop(closed);
+        for (fail_open, expected) in [
+            (
+                false,
+                RouteDecision::Deny("route policy unavailable".to_string()),
+            ),
+            (true, RouteDecision::Allow),
+        ] {
+            let policy = RoutePolicy::new(RoutePolicyConfig {
+                url: Some(url.clone()),
+                timeout: Duration::from_secs(5),
+                cache_ttl: Duration::from_secs(60),
+                fail_open,
+                ..Default::default()
+            });
+            assert_eq!(policy.decide(input.clone()).await, expected);
+            assert_eq!(policy.cached(), 0);
+        }
+        assert_eq!(
+            RoutePolicy::new(RoutePolicyConfig::default())
+                .decide(input)
+                .await,
+            RouteDecision::Allow
+        );
+    }
+}
@@ -0,0 +1,400 @@
+//! Runs the whole proxy from library code, for binaries embedding it and for in-process tests. The
+//! `my-proxy` binary adds the logging, the signals and the REST API around it.
+
+use crate::backend::backend_mgr::{get_or_init_backend_mgr, BackendMgr};
+use crate::backend::router::new_backend_router;
+use crate::backend::tenant_activity::run_tenant_cool_down;
+use crate::backend::topology_freshness::run_topology_freshness_check;
+use crate::cp;
+use crate::cp::active_users::UserActivityWindow;
+use crate::server::acme::AcmeManager;
+use crate::server::auth::authenticator::ProxyAuthenticator;
+use crate::server::haentgl_server::HaentglServer;
+use crate::server::handshake_profile::HandshakeProfile;
+use crate::server::notifier::{subscribe_events, ProxyEvent};
+use crate::server::proxy_cli_args::ProxyServerArgs;
+use crate::server::reload::init_config_reloader;
+use crate::server::transparent::original_dst;
+use crate::server::tunnel::TunnelServer;
+use crate::server::watchdog::ResourceWatchdog;
+use crate::server::{ProxyServer, PROXY_ENV_SYNC_ROUTER};
+
+use common::ShutdownMessage;
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::{broadcast, watch};
+use tokio::task::JoinHandle;
+use tokio_rustls::rustls::ServerConfig;
+use tracing::{info, warn};
+
+/// Events kept for slow subscribers, the oldest are dropped beyond.
+const RUNTIME_EVENTS_SIZE: usize = 256;
+
+/// What happens to a [`ProxyRuntime`], see [`ProxyRuntime::subscribe`].
+#[derive(Debug, Clone)]
+pub enum RuntimeEvent {
+    /// The client listener is bound, clients may connect.
+    Listening(SocketAddr),
+    /// The pools of the router are initialized, see [`HaentglServer::initialize_async`].
+    Initialized,
+    /// An event reported to the platform, see [`notify`](crate::server::notifier::notify).
+    Notified(ProxyEvent),
+    /// The listener is closed and the sessions are drained.
+    Stopped,
+}
+
+/// `ProxyRuntime` wires the proxy of a configuration: its registries, backend router and pools,
+/// background tasks, control plane target and client listener. It must be started within a tokio
+/// runtime.
+///
+/// The registries are process wide, the first runtime of a process initializes them.
+pub struct ProxyRuntime {
+    config: ProxyServerArgs,
+    listen_addr: SocketAddr,
+    shutdown_tx: watch::Sender<ShutdownMessage>,
+    events: broadcast::Sender<RuntimeEvent>,
+    backend_mgr: Option<Arc<BackendMgr>>,
+    accept_loop: Option<JoinHandle<()>>,
+}
+
+impl ProxyRuntime {
+    pub fn new(config: ProxyServerArgs) -> Self {
+        Self {
+            listen_addr: SocketAddr::from(([0, 0, 0, 0], config.port)),
+            config,
+            shutdown_tx: watch::channel(ShutdownMessage::Init).0,
+            events: broadcast::channel(RUNTIME_EVENTS_SIZE).0,
+            backend_mgr: None,
+            accept_loop: None,
+        }
+    }
+
+    /// Listens on `listen_addr` instead of every interface at the configured port, port 0 picks a
+    /// free one.
+    pub fn with_listen_addr(mut self, listen_addr: SocketAddr) -> Self {
+        self.listen_addr = listen_addr;
+        self
+    }
+
+    pub fn config(&self) -> &ProxyServerArgs {
+        &self.config
+    }
+
+    pub fn listen_addr(&self) -> SocketAddr {
+        self.listen_addr
+    }
+
+    /// Subscribes to the events of the runtime from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<RuntimeEvent> {
+        self.events.subscribe()
+    }
+
+    /// Changes once the runtime shuts down, for the tasks run alongside it.
+    pub fn shutdown_receiver(&self) -> watch::Receiver<ShutdownMessage> {
+        self.shutdown_tx.subscribe()
+    }
+
+    /// The backend manager, `None` before the runtime is started.
+    pub fn backend_mgr(&self) -> Option<Arc<BackendMgr>> {
+        self.backend_mgr.clone()
+    }
+
+    fn init_registries(&self) -> Result<(), Error> {
+        let config = &self.config;
+        crate::server::slow_log::init_slow_query_log(
+            Duration::from_millis(config.slow_query_ms),
+            config.slow_log_capacity,
+            config.slow_log_file(),
+        );
+        crate::server::sql_privacy::init_sql_privacy(config.sql_export()?);
+        crate::server::query_digest::init_query_digests(config.query_digest_capacity);
+        crate::server::packet_capture::init_packet_capture(config.support_dir.clone());
+        crate::server::auth::reconnect_token::init_reconnect_tokens(
+            &config.reconnect_token_config(),
+        )?;
+        crate::server::command_policy::init_command_policy(config.deny_commands.clone());
+        crate::server::route_policy::init_route_policy(config.route_policy_config()?);
+        crate::server::long_data::init_long_data_policy(config.long_data_limits());
+        crate::server::auth_limiter::init_auth_limiter(config.auth_limits());
+        crate::server::rate_limit::init_command_rate_limiter(config.user_rate_limit());
+        crate::server::auth::client_acl::init_client_acl(config.client_acl_rules())?;
+        crate::server::sni_router::init_sni_router(config.sni_tenant_domain.clone());
+        crate::server::protocol_features::init_protocol_features(config.protocol_features());
+        crate::backend::quarantine::init_quarantine_registry(config.quarantine_config());
+        crate::backend::egress::init_egress_policy(config.egress_config()?);
+        crate::backend::replica::init_replica_registry(Duration::from_millis(
+            config.max_replica_lag_ms,
+        ));
+        crate::server::notifier::init_notifier(config.notifier_config());
+        crate::server::billing::init_billing(config.billing_config());
+        crate::audit::init_audit_log(config.audit_sink()?);
+        crate::server::compat::init_client_compat(config.handshake_profile()?);
+        Ok(())
+    }
+
+    async fn start_cp_target(
+        &self,
+        backend_mgr: Arc<BackendMgr>,
+    ) -> Option<Arc<UserActivityWindow>> {
+        let cp_args = &self.config.cp_args;
+        let rpc_server_addr = cp_args.cp_listen_addr()?;
+        let active_users = Arc::new(
+            UserActivityWindow::default().with_sample_every(cp_args.cp_activity_sample_every),
+        );
+        cp::start_cp_target_reporter(
+            Arc::clone(&active_users),
+            backend_mgr,
+            rpc_server_addr,
+            Box::new(self.shutdown_receiver()),
+        )
+        .await;
+        Some(active_users)
+    }
+
+    /// Starts the proxy and its client listener, returns the address the listener is bound to.
+    /// The pools of the router are initialized in the background.
+    pub async fn start(&mut self) -> Result<SocketAddr, Error> {
+        if self.accept_loop.is_some() {
+            return Err(Error::new(
+                ErrorKind::AlreadyExists,
+                "the proxy runtime is already started",
+            ));
+        }
+        let config = self.config.clone();
+        info!("ProxySrv running config args={:?}", config);
+        self.init_registries()?;
+        // What the configuration may still be rejected for is checked before anything starts.
+        let handshake_profile = Arc::new(config.handshake_profile()?);
+        let tunnel_handshake_profile = config.tunnel_handshake_profile()?;
+        let acme_solver = config.acme_solver()?;
+        let tls_conf = config
+            .client_tls_options()
+            .map(|options| options.server_config())
+            .transpose()?;
+        if config.tls && tls_conf.is_none() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "tls requires acme_domains, the certificate of the client listener",
+            ));
+        }
+        let shutdown_rx = self.shutdown_receiver();
+
+        let mut notified = subscribe_events();
+        let events = self.events.clone();
+        let mut notified_shutdown_rx = shutdown_rx.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = notified_shutdown_rx.changed() => return,
+                    event = notified.recv() => match event {
+                        Ok(event) => {
+                            let _ = events.send(RuntimeEvent::Notified(event));
+                        }
+                        Err(broadcast::error::RecvError::Lagged(_)) => {}
+                        Err(broadcast::error::RecvError::Closed) => return,
+                    },
+                }
+            }
+        });
+
+        let router = new_backend_router(&config, &shutdown_rx).await;
+        if !router.is_static() {
+            info!("ProxySrv backend router is not static. Waiting to add Tenant.");
+            std::env::set_var(PROXY_ENV_SYNC_ROUTER, "true");
+        }
+        let backend_mgr = get_or_init_backend_mgr(router, config.new_backend_opts());
+        self.backend_mgr = Some(Arc::clone(&backend_mgr));
+        let reloader = init_config_reloader(&config);
+        let backend_mgr_reloaded = Arc::clone(&backend_mgr);
+        let runtime_rx = reloader.subscribe();
+        tokio::spawn(async move { backend_mgr_reloaded.apply_runtime_config(runtime_rx).await });
+
+        let watchdog_config = config.watchdog_config();
+        if watchdog_config.is_enabled() {
+            let watchdog = ResourceWatchdog::new(watchdog_config, Arc::clone(&backend_mgr));
+            tokio::spawn(watchdog.run(shutdown_rx.clone()));
+        }
+        if let Some(ttl) = config.tenant_idle_ttl() {
+            tokio::spawn(run_tenant_cool_down(
+                Arc::clone(&backend_mgr),
+                ttl,
+                shutdown_rx.clone(),
+            ));
+        }
+        if let Some(window) = config.topology_stale_window() {
+            tokio::spawn(run_topology_freshness_check(window, shutdown_rx.clone()));
+        }
+        if let Some(acme_options) = config.acme_options() {
+            let acme = AcmeManager::new(acme_options, acme_solver);
+            tokio::spawn(acme.run(shutdown_rx.clone()));
+        }
+
+        let active_users = self.start_cp_target(Arc::clone(&backend_mgr)).await;
+        let proxy_srv = HaentglServer::new(backend_mgr, ProxyAuthenticator)
+            .with_quit_reply_ok(config.quit_reply_ok)
+            .with_client_watermarks(config.client_watermarks())
+            .with_backend_keepalive(config.backend_keepalive())
+            .with_client_idle_timeout(config.client_idle_timeout())
+            .with_protocol_limits(config.protocol_limits())
+            .with_startup_report(config.startup_report())
+            .with_drain_timeout(config.shutdown_drain_timeout())
+            .with_proxy_protocol(config.trusted_proxies()?)
+            .with_com_latency_queue(config.com_latency_queue())
+            .with_active_users(active_users);
+
+        // Bound before the pools are initialized, so the startup report is published once the
+        // proxy accepts clients.
+        let tcp_listener = TcpListener::bind(self.listen_addr).await?;
+        let local_addr = tcp_listener.local_addr()?;
+        let proxy_srv = Arc::new(proxy_srv);
+        let initialized_srv = Arc::clone(&proxy_srv);
+        let events = self.events.clone();
+        tokio::spawn(async move {
+            match initialized_srv.initialize_async().await {
+                Ok(()) => {
+                    let _ = events.send(RuntimeEvent::Initialized);
+                }
+                Err(e) => warn!("ProxySrv router initialization failed {e:?}"),
+            }
+        });
+
+        if let Some(tunnel_port) = config.tunnel_port {
+            let tunnel_srv = Arc::new(TunnelServer::new(
+                Arc::clone(&proxy_srv),
+                tunnel_handshake_profile,
+            ));
+            let tunnel_shutdown_rx = shutdown_rx.clone();
+            tokio::spawn(async move {
+                tunnel_srv
+                    .start(format!("0.0.0.0:{tunnel_port}"), tunnel_shutdown_rx)
+                    .await
+            });
+        }
+
+        info!("ProxySrv listening on {local_addr}");
+        let _ = self.events.send(RuntimeEvent::Listening(local_addr));
+        let accept_loop = AcceptLoop {
+            proxy_srv,
+            tcp_listener,
+            transparent: config.transparent,
+            handshake_profile,
+            tls_conf,
+        };
+        self.accept_loop = Some(tokio::spawn(
+            accept_loop.run(shutdown_rx, self.events.clone()),
+        ));
+        Ok(local_addr)
+    }
+
+    /// Stops accepting clients, then drains the sessions and closes the backend pools. Returns
+    /// once the proxy is stopped, at once if it was not started.
+    pub async fn shutdown(&mut self, shutdown_msg: ShutdownMessage) {
+        self.shutdown_tx.send_replace(shutdown_msg);
+        if let Some(accept_loop) = self.accept_loop.take() {
+            if let Err(e) = accept_loop.await {
+                warn!("ProxySrv accept loop failed {e:?}");
+            }
+        }
+    }
+}
+
+/// The client listener of a started runtime.
+struct AcceptLoop {
+    proxy_srv: Arc<HaentglServer<ProxyAuthenticator>>,
+    tcp_listener: TcpListener,
+    transparent: bool,
+    handshake_profile: Arc<HandshakeProfile>,
+    tls_conf: Option<Arc<ServerConfig>>,
+}
+
+impl AcceptLoop {
+    async fn run(
+        self,
+        mut shutdown_rx: watch::Receiver<ShutdownMessage>,
+        events: broadcast::Sender<RuntimeEvent>,
+    ) {
+        loop {
+            tokio::select! {
+                _ = shutdown_rx.changed() => break,
+                rs = self.tcp_listener.accept() => {
+                    let (stream, client_addr) = match rs {
+                        Ok(accepted) => accepted,
+                        Err(e) => {
+                            warn!("ProxySrv accept connection err. cause by {e:?}");
+                            continue;
+                        }
+                    };
+                    let original_dst =
+                        match self.transparent.then(|| original_dst(&stream)).transpose() {
+                            Ok(original_dst) => original_dst.flatten(),
+                            Err(e) => {
+                                warn!("ProxySrv original destination unknown, connection dropped. cause by {e:?}");
+                                continue;
+                            }
+                        };
+                    let (client_reader, client_writer) = stream.into_split();
+                    let proxy_srv = Arc::clone(&self.proxy_srv);
+                    let profile = Arc::clone(&self.handshake_profile);
+                    let tls_conf = self.tls_conf.clone();
+                    tokio::spawn(async move {
+                        proxy_srv
+                            .connect_to(
+                                client_reader,
+                                client_writer,
+                                &profile,
+                                &tls_conf,
+                                original_dst,
+                                Some(client_addr),
+                            )
+                            .await
+                    });
+                }
+            }
+        }
+        // Stop accepting before the sessions drain.
+        drop(self.tcp_listener);
+        self.proxy_srv.close().await;
+        let _ = events.send(RuntimeEvent::Stopped);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::server::proxy_cli_args::ProxyServerArgs;
+    use crate::server::runtime::ProxyRuntime;
+    use clap::Parser;
+    use common::ShutdownMessage;
+    use std::io::ErrorKind;
+    use std::net::SocketAddr;
+
+    #[tokio::test]
+    pub async fn test_proxy_runtime_builder() {
+        let config = ProxyServerArgs::parse_from(["haentgl", "--port", "3399"]);
+        let runtime = ProxyRuntime::new(config);
+        assert_eq!(
+            runtime.listen_addr(),
+            SocketAddr::from(([0, 0, 0, 0], 3399))
+        );
+        let listen_addr = SocketAddr::from(([127, 0, 0, 1], 0));
+        let mut runtime = runtime.with_listen_addr(listen_addr);
+        assert_eq!(runtime.listen_addr(), listen_addr);
+        assert!(runtime.backend_mgr().is_none());
+
+        let shutdown_rx = runtime.shutdown_receiver();
+        runtime
+            .shutdown(ShutdownMessage::Cancel("test".to_string()))
+            .await;
+        assert!(shutdown_rx.has_changed().unwrap());
+    }
+
+    #[test]
+    pub fn test_proxy_runtime_invalid_egress() {
+        let config = ProxyServerArgs::parse_from(["haentgl", "--egress-allow", "10.0.0.0/8,a b"]);
+        let e = config.egress_config().unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidInput);
+        assert!(e.to_string().contains("a b"), "{e}");
+    }
+}
@@ -0,0 +1,584 @@
+use crate::async_packet_read;
+use crate::backend::stream::{BackendReadHalf, BackendWriteHalf};
+use crate::prost::common_proto::TenantKey;
+use crate::protocol::mysql::packet::packet_reader::PacketReader;
+use crate::protocol::mysql::packet::packet_writer::PacketWriter;
+use crate::protocol::mysql::packet::{writers, Packet};
+use crate::server::request_id::current_request_id;
+use crate::server::slow_log::tenant_label;
+
+use chrono::{DateTime, Local};
+use dashmap::mapref::entry::Entry;
+use dashmap::DashMap;
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use tokio::sync::Notify;
+use tracing::info;
+
+/// Memory held by one client session, see [`Session::update_memory`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionMemory {
+    /// PacketReader/PacketWriter buffers of the client connection.
+    pub client_buffer_bytes: usize,
+    /// PacketReader/PacketWriter buffers of the backend connection.
+    pub backend_buffer_bytes: usize,
+    /// Prepared statements cached on the backend connection.
+    pub stmt_cache_bytes: usize,
+}
+
+impl SessionMemory {
+    pub fn total(&self) -> usize {
+        self.client_buffer_bytes + self.backend_buffer_bytes + self.stmt_cache_bytes
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionInfo {
+    pub id: u64,
+    pub tenant: String,
+    pub user: String,
+    /// The address of the client, as the PROXY protocol header tells it behind a load balancer.
+    pub host: Option<String>,
+    pub connected_at: String,
+    pub memory_bytes: usize,
+    pub memory: SessionMemory,
+    /// Result bytes waiting for the client to read them, see [`FlowControl`].
+    ///
+    /// [`FlowControl`]: crate::protocol::mysql::packet::packet_writer::FlowControl
+    pub buffered_bytes: usize,
+}
+
+/// Why a client session ended normally.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionCloseReason {
+    /// The client sent COM_QUIT and the backend connection was reset.
+    Quit,
+    /// The client sent COM_QUIT but the backend connection could not be reset.
+    QuitResetFailed,
+    /// The scheduled maintenance of the tenant started.
+    Maintenance,
+    /// The tenant is drained.
+    Drained,
+    /// The backend connection of the idle session failed a keepalive ping.
+    KeepaliveFailed,
+    /// The proxy shut down while the session was idle.
+    Shutdown,
+    /// The client sent no command within the idle timeout and the backend connection was reset.
+    IdleTimeout,
+    /// The client sent no command within the idle timeout but the backend connection could not
+    /// be reset.
+    IdleTimeoutResetFailed,
+}
+
+impl SessionCloseReason {
+    /// How a COM_QUIT session ended, from the response of the backend to the reset, `None` if
+    /// the reset got no response.
+    pub fn on_quit(reset_rsp: Option<&Packet>) -> Self {
+        match reset_rsp {
+            Some(reset_rsp) if reset_rsp.is_ok_packet() => SessionCloseReason::Quit,
+            _ => SessionCloseReason::QuitResetFailed,
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            SessionCloseReason::Quit => "quit",
+            SessionCloseReason::QuitResetFailed => "quit_reset_failed",
+            SessionCloseReason::Maintenance => "maintenance",
+            SessionCloseReason::Drained => "drained",
+            SessionCloseReason::KeepaliveFailed => "keepalive_failed",
+            SessionCloseReason::Shutdown => "shutdown",
+            SessionCloseReason::IdleTimeout => "idle_timeout",
+            SessionCloseReason::IdleTimeoutResetFailed => "idle_timeout_reset_failed",
+        }
+    }
+
+    /// Whether the backend connection is clean and can serve another session.
+    pub fn is_backend_reusable(&self) -> bool {
+        matches!(
+            self,
+            SessionCloseReason::Quit | SessionCloseReason::IdleTimeout
+        )
+    }
+}
+
+/// Sessions to kill, by id and/or the `heaviest` sessions by memory.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KillSessions {
+    #[serde(default)]
+    pub ids: Vec<u64>,
+    #[serde(default)]
+    pub heaviest: Option<usize>,
+}
+
+/// Low bits of a session id, numbering the sessions of its id namespace. The high bits are the
+/// namespace prefix.
+pub const SESSION_SEQ_BITS: u32 = 32;
+
+/// Gives the sessions of `tenant` ids of their own, `prefix` followed by a sequence of the
+/// tenant. The ids of the tenant cannot collide with those of other tenants, and the sequence
+/// only counts the sessions of the tenant, its ids tell nothing about the traffic of others.
+/// Tenants without a namespace share the prefix 0. Session ids end up in the billing records,
+/// the logs and the admin API, the handshake connection id is random, see [`gen_conn_id`].
+///
+/// [`gen_conn_id`]: crate::server::auth::gen_conn_id
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConnIdNamespace {
+    pub tenant: TenantKey,
+    /// Not 0, and unique among the tenants.
+    pub prefix: u32,
+}
+
+/// The session ids of one prefix.
+#[derive(Default)]
+struct IdSpace {
+    prefix: u32,
+    last_seq: AtomicU32,
+}
+
+impl IdSpace {
+    fn new(prefix: u32) -> Self {
+        Self {
+            prefix,
+            last_seq: AtomicU32::new(0),
+        }
+    }
+
+    fn next_id(&self) -> u64 {
+        let seq = self
+            .last_seq
+            .fetch_add(1, Ordering::Relaxed)
+            .wrapping_add(1);
+        (u64::from(self.prefix) << SESSION_SEQ_BITS) | u64::from(seq)
+    }
+}
+
+/// `Session` is the registry entry of one client session in the command phase.
+pub struct Session {
+    id: u64,
+    tenant: String,
+    user: String,
+    host: OnceLock<String>,
+    connected_at: DateTime<Local>,
+    client_buffer_bytes: AtomicUsize,
+    backend_buffer_bytes: AtomicUsize,
+    stmt_cache_bytes: AtomicUsize,
+    buffered_bytes: Arc<AtomicUsize>,
+    /// Whether the backend connection is in a transaction, updated by the forwarder.
+    in_transaction: Arc<AtomicBool>,
+    killed: AtomicBool,
+    /// The admin request that killed the session.
+    killed_by: Mutex<Option<String>>,
+    /// Sent to the client of an idle session before it is closed, see
+    /// [`Session::kill_with_notice`].
+    shutdown_notice: Mutex<Option<String>>,
+    kill_notify: Notify,
+}
+
+impl Session {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Records the memory held by the session, called once per command.
+    pub fn update_memory(&self, memory: SessionMemory) {
+        self.client_buffer_bytes
+            .store(memory.client_buffer_bytes, Ordering::Relaxed);
+        self.backend_buffer_bytes
+            .store(memory.backend_buffer_bytes, Ordering::Relaxed);
+        self.stmt_cache_bytes
+            .store(memory.stmt_cache_bytes, Ordering::Relaxed);
+    }
+
+    /// The gauge of the bytes buffered for the client, updated by its writer.
+    pub fn buffered_bytes(&self) -> Arc<AtomicUsize> {
+        Arc::clone(&self.buffered_bytes)
+    }
+
+    /// The flag the forwarder sets from the status flags ending a statement.
+    pub fn transaction_flag(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.in_transaction)
+    }
+
+    pub fn in_transaction(&self) -> bool {
+        self.in_transaction.load(Ordering::Relaxed)
+    }
+
+    pub fn memory(&self) -> SessionMemory {
+        SessionMemory {
+            client_buffer_bytes: self.client_buffer_bytes.load(Ordering::Relaxed),
+            backend_buffer_bytes: self.backend_buffer_bytes.load(Ordering::Relaxed),
+            stmt_cache_bytes: self.stmt_cache_bytes.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn set_host(&self, client_addr: SocketAddr) {
+        let _ = self.host.set(client_addr.to_string());
+    }
+
+    pub fn info(&self) -> SessionInfo {
+        let memory = self.memory();
+        SessionInfo {
+            id: self.id,
+            tenant: self.tenant.clone(),
+            user: self.user.clone(),
+            host: self.host.get().cloned(),
+            connected_at: self
+                .connected_at
+                .format("%Y-%m-%d %H:%M:%S%.3f")
+                .to_string(),
+            memory_bytes: memory.total(),
+            memory,
+            buffered_bytes: self.buffered_bytes.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Asks the session to terminate. The session stops once its current command finished.
+    pub fn kill(&self) {
+        *self.killed_by.lock().unwrap() = current_request_id();
+        self.killed.store(true, Ordering::Release);
+        // Only the command loop of the session waits, the permit is kept if it is busy.
+        self.kill_notify.notify_one();
+    }
+
+    /// Kills an idle session, its client gets an ER_SERVER_SHUTDOWN of `notice` instead of a
+    /// connection closed without a word, so it knows it may reconnect.
+    pub fn kill_with_notice(&self, notice: String) {
+        *self.shutdown_notice.lock().unwrap() = Some(notice);
+        self.kill();
+    }
+
+    pub fn shutdown_notice(&self) -> Option<String> {
+        self.shutdown_notice.lock().unwrap().clone()
+    }
+
+    pub fn killed_by(&self) -> Option<String> {
+        self.killed_by.lock().unwrap().clone()
+    }
+
+    pub fn is_killed(&self) -> bool {
+        self.killed.load(Ordering::Acquire)
+    }
+
+    /// Completes once the session has been killed.
+    pub async fn killed(&self) {
+        while !self.is_killed() {
+            self.kill_notify.notified().await;
+        }
+    }
+}
+
+/// Removes the session from the registry when the client session ends.
+pub struct SessionGuard {
+    registry: &'static SessionRegistry,
+    session: Arc<Session>,
+}
+
+impl std::ops::Deref for SessionGuard {
+    type Target = Session;
+
+    fn deref(&self) -> &Self::Target {
+        &self.session
+    }
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        self.registry.sessions.remove(&self.session.id);
+    }
+}
+
+/// `SessionRegistry` tracks the live client sessions and the memory each of them holds, so the
+/// heaviest sessions can be found and killed when the proxy memory spikes.
+#[derive(Default)]
+pub struct SessionRegistry {
+    /// The ids of the tenants without a namespace.
+    shared_ids: IdSpace,
+    tenant_ids: DashMap<TenantKey, Arc<IdSpace>>,
+    /// The tenant of every prefix in use.
+    prefixes: DashMap<u32, TenantKey>,
+    sessions: DashMap<u64, Arc<Session>>,
+}
+
+static SESSION_REGISTRY_ONCE: OnceLock<SessionRegistry> = OnceLock::new();
+
+pub fn session_registry() -> &'static SessionRegistry {
+    SESSION_REGISTRY_ONCE.get_or_init(SessionRegistry::default)
+}
+
+impl SessionRegistry {
+    pub fn register(&'static self, tenant: &TenantKey, user: String) -> SessionGuard {
+        let id = match self.tenant_ids.get(tenant) {
+            Some(ids) => ids.next_id(),
+            None => self.shared_ids.next_id(),
+        };
+        let session = Arc::new(Session {
+            id,
+            tenant: tenant_label(tenant),
+            user,
+            host: OnceLock::new(),
+            connected_at: Local::now(),
+            client_buffer_bytes: AtomicUsize::new(0),
+            backend_buffer_bytes: AtomicUsize::new(0),
+            stmt_cache_bytes: AtomicUsize::new(0),
+            buffered_bytes: Arc::new(AtomicUsize::new(0)),
+            in_transaction: Arc::new(AtomicBool::new(false)),
+            killed: AtomicBool::new(false),
+            killed_by: Mutex::new(None),
+            shutdown_notice: Mutex::new(None),
+            kill_notify: Notify::new(),
+        });
+        self.sessions.insert(id, Arc::clone(&session));
+        SessionGuard {
+            registry: self,
+            session,
+        }
+    }
+
+    /// Draws the ids of the next sessions of `namespace.tenant` from `namespace.prefix`. Fails
+    /// if the prefix is 0 or taken by another tenant.
+    pub fn set_conn_id_namespace(&self, namespace: ConnIdNamespace) -> Result<(), Error> {
+        let ConnIdNamespace { tenant, prefix } = namespace;
+        if prefix == 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "the prefix 0 is shared by the tenants without a namespace",
+            ));
+        }
+        match self.prefixes.entry(prefix) {
+            Entry::Occupied(entry) if *entry.get() != tenant => {
+                return Err(Error::new(
+                    ErrorKind::AlreadyExists,
+                    format!("prefix {prefix} is taken by {}", tenant_label(entry.get())),
+                ));
+            }
+            // Setting the same namespace again keeps its sequence.
+            Entry::Occupied(_) => return Ok(()),
+            Entry::Vacant(entry) => {
+                entry.insert(tenant.clone());
+            }
+        }
+        info!(
+            "ProxySrv sessions of {} get ids of prefix {prefix}",
+            tenant_label(&tenant)
+        );
+        if let Some(previous) = self
+            .tenant_ids
+            .insert(tenant, Arc::new(IdSpace::new(prefix)))
+        {
+            self.prefixes.remove(&previous.prefix);
+        }
+        Ok(())
+    }
+
+    /// Moves the next sessions of `tenant` back to the shared ids.
+    pub fn remove_conn_id_namespace(&self, tenant: &TenantKey) -> Option<ConnIdNamespace> {
+        let (tenant, ids) = self.tenant_ids.remove(tenant)?;
+        self.prefixes.remove(&ids.prefix);
+        Some(ConnIdNamespace {
+            tenant,
+            prefix: ids.prefix,
+        })
+    }
+
+    /// The tenant id namespaces, ordered by prefix.
+    pub fn conn_id_namespaces(&self) -> Vec<ConnIdNamespace> {
+        self.prefixes
+            .iter()
+            .map(|e| ConnIdNamespace {
+                tenant: e.value().clone(),
+                prefix: *e.key(),
+            })
+            .sorted_by_key(|namespace| namespace.prefix)
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.sessions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sessions.is_empty()
+    }
+
+    /// Returns the sessions ordered by memory, the heaviest first.
+    pub fn top_by_memory(&self, limit: Option<usize>) -> Vec<SessionInfo> {
+        let mut sessions = self
+            .sessions
+            .iter()
+            .map(|e| e.value().info())
+            .collect::<Vec<_>>();
+        sessions.sort_by(|a, b| b.memory_bytes.cmp(&a.memory_bytes).then(a.id.cmp(&b.id)));
+        sessions.truncate(limit.unwrap_or(sessions.len()));
+        sessions
+    }
+
+    /// The live sessions of a tenant.
+    pub fn tenant_sessions(&self, tenant: &TenantKey) -> Vec<Arc<Session>> {
+        let tenant = tenant_label(tenant);
+        self.sessions
+            .iter()
+            .filter(|e| e.value().tenant == tenant)
+            .map(|e| Arc::clone(e.value()))
+            .collect()
+    }
+
+    pub fn kill(&self, id: u64) -> bool {
+        match self.sessions.get(&id) {
+            Some(session) => {
+                info!("ProxySrv kill session {} memory {:?}", id, session.memory());
+                session.kill();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Kills the session `id` only if it is a session of `tenant`.
+    pub fn kill_of_tenant(&self, id: u64, tenant: &TenantKey) -> bool {
+        let tenant = tenant_label(tenant);
+        let owned = self
+            .sessions
+            .get(&id)
+            .is_some_and(|session| session.tenant == tenant);
+        owned && self.kill(id)
+    }
+
+    /// Kills the `count` heaviest sessions and returns their ids.
+    pub fn kill_heaviest(&self, count: usize) -> Vec<u64> {
+        self.top_by_memory(Some(count))
+            .into_iter()
+            .map(|session| session.id)
+            .filter(|id| self.kill(*id))
+            .collect()
+    }
+}
+
+/// Resets the backend connection of a killed session before it goes back to the pool, then
+/// returns the error failing the session so the client connection is closed.
+pub async fn end_killed_session(
+    backend_writer: &mut PacketWriter<BackendWriteHalf>,
+    backend_reader: &mut PacketReader<BackendReadHalf>,
+) -> Error {
+    let reset_rs: Result<(), Error> = async {
+        backend_writer.reset_seq();
+        writers::write_reset_connection(backend_writer).await?;
+        let (_be_seq, _be_rsp_pkt) = async_packet_read!(backend_reader);
+        Ok(())
+    }
+    .await;
+    match reset_rs {
+        Ok(()) => killed_session_err(),
+        Err(e) => e,
+    }
+}
+
+/// The error ending a killed session.
+pub fn killed_session_err() -> Error {
+    Error::new(
+        ErrorKind::ConnectionAborted,
+        "proxy session killed".to_string(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::test_tenant_key;
+    use crate::prost::common_proto::TenantKey;
+    use crate::server::session::{
+        session_registry, ConnIdNamespace, SessionMemory, SessionRegistry, SESSION_SEQ_BITS,
+    };
+    use std::sync::OnceLock;
+
+    #[tokio::test]
+    pub async fn test_session_registry() {
+        let registry = session_registry();
+        let tenant = test_tenant_key();
+        let light = registry.register(&tenant, "light".to_string());
+        let heavy = registry.register(&tenant, "heavy".to_string());
+        light.update_memory(SessionMemory {
+            client_buffer_bytes: 1024,
+            ..Default::default()
+        });
+        heavy.update_memory(SessionMemory {
+            client_buffer_bytes: 1024,
+            backend_buffer_bytes: 4096,
+            stmt_cache_bytes: 512,
+        });
+        let ids = [light.id(), heavy.id()];
+        let top = registry
+            .top_by_memory(None)
+            .into_iter()
+            .filter(|session| ids.contains(&session.id))
+            .collect::<Vec<_>>();
+        assert_eq!(top.len(), 2);
+        assert_eq!((top[0].id, top[0].memory_bytes), (heavy.id(), 5632));
+        assert_eq!(top[1].user, "light");
+
+        assert!(registry.kill(heavy.id()));
+        heavy.killed().await;
+        assert!(!light.is_killed());
+
+        let other = TenantKey {
+            cluster_name: "other".to_string(),
+            ..tenant.clone()
+        };
+        assert!(!registry.kill_of_tenant(light.id(), &other));
+        assert!(!light.is_killed());
+
+        let heavy_id = heavy.id();
+        drop(heavy);
+        assert!(!registry.kill(heavy_id));
+    }
+
+    #[tokio::test]
+    pub async fn test_conn_id_namespaces() {
+        static REGISTRY: OnceLock<SessionRegistry> = OnceLock::new();
+        let registry = REGISTRY.get_or_init(SessionRegistry::default);
+        let tenant = |cluster_name: &str| TenantKey {
+            namespace: "conn-id".to_string(),
+            cluster_name: cluster_name.to_string(),
+            ..Default::default()
+        };
+        let (a, b) = (tenant("a"), tenant("b"));
+        let namespace = |tenant: &TenantKey, prefix| ConnIdNamespace {
+            tenant: tenant.clone(),
+            prefix,
+        };
+        assert_eq!(registry.register(&a, "u".to_string()).id(), 1);
+        registry.set_conn_id_namespace(namespace(&a, 7)).unwrap();
+        assert!(registry.set_conn_id_namespace(namespace(&b, 7)).is_err());
+        assert!(registry.set_conn_id_namespace(namespace(&b, 0)).is_err());
+        registry.set_conn_id_namespace(namespace(&b, 9)).unwrap();
+
+        let first = registry.register(&a, "u".to_string());
+        assert_eq!(first.id(), 7 << SESSION_SEQ_BITS | 1);
+        // Setting the same namespace again keeps the sequence.
+        registry.set_conn_id_namespace(namespace(&a, 7)).unwrap();
+        assert_eq!(
+            registry.register(&a, "u".to_string()).id(),
+            7 << SESSION_SEQ_BITS | 2
+        );
+        assert_eq!(
+            registry.register(&b, "u".to_string()).id(),
+            9 << SESSION_SEQ_BITS | 1
+        );
+
+        // A new prefix frees the previous one.
+        registry.set_conn_id_namespace(namespace(&a, 8)).unwrap();
+        registry.set_conn_id_namespace(namespace(&b, 7)).unwrap();
+        assert_eq!(
+            registry.conn_id_namespaces(),
+            vec![namespace(&b, 7), namespace(&a, 8)]
+        );
+        assert_eq!(
+            registry.remove_conn_id_namespace(&a),
+            Some(namespace(&a, 8))
+        );
+        assert_eq!(registry.register(&a, "u".to_string()).id(), 2);
+    }
+}
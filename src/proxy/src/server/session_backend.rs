@@ -0,0 +1,474 @@
+use crate::backend::backend_mgr::BackendMgr;
+use crate::backend::pool::pooled_conn_mgr::PooledConnMgr;
+use crate::backend::pool::{BackendConn, PooledConn};
+use crate::backend::quarantine::{
+    is_broken_conn, quarantine_registry, BackendFailure, EARLY_FAILURE_WINDOW,
+};
+use crate::backend::router::p2c::{backend_conns, BackendConnGuard};
+use crate::backend::stream::{BackendReadHalf, BackendWriteHalf};
+use crate::backend::{DbConnPhase, DbUserConnLifeCycle};
+use crate::prost::common_proto::TenantKey;
+use crate::protocol::mysql::basic::HandshakeResponse;
+use crate::protocol::mysql::error_codes::ErrorKind;
+use crate::protocol::mysql::packet::compress::InflateBudget;
+use crate::protocol::mysql::packet::packet_reader::{PacketHook, PacketReader};
+use crate::protocol::mysql::packet::packet_writer::PacketWriter;
+use crate::protocol::mysql::packet::{writers, Packet};
+use crate::server::auth::authenticator::authenticate_identity;
+use crate::server::error_stats::err_code_hook;
+use crate::server::failover::{
+    write_failover_err, SessionReplay, FAILOVER_FAILED, FAILOVER_RECONNECTED,
+};
+use crate::server::forwarder::session_state::SessionState;
+use crate::server::forwarder::set_option_forward::restore_multi_statements;
+use crate::server::recent_errors::recent_errors;
+use crate::server::session::SessionCloseReason;
+use crate::server::slow_log::tenant_label;
+
+use common::clock::{clock, Timestamp};
+use common::metrics::metric_def::PROXY_BACKEND_FAILOVERS;
+use common::metrics::{common_labels, counter_inc};
+use deadpool::managed::Object;
+use std::io::Error;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use tokio::io::AsyncWrite;
+use tokio::sync::OwnedMutexGuard;
+use tracing::{debug, info, warn};
+
+/// How many backends a failover picks before it gives up.
+const FAILOVER_ATTEMPTS: usize = 3;
+/// How long a session waits for a connection of the backend it picked.
+const CHECKOUT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// A backend connection checked out by a session.
+struct CheckedOut {
+    guard: OwnedMutexGuard<BackendConn>,
+    conn: Object<PooledConnMgr>,
+    addr: String,
+    checked_out_at: Timestamp,
+    _in_flight: BackendConnGuard,
+}
+
+impl CheckedOut {
+    fn clear_hooks(&mut self) {
+        let (reader, _) = &mut *self.guard;
+        reader.set_err_hook(None);
+        reader.set_packet_hook(None);
+        reader.set_inflate_budget(None);
+    }
+}
+
+/// `SessionBackend` holds the backend connection of a session. The session keeps the connection
+/// it was authenticated on unless it breaks: it then fails over to another backend the router
+/// picks. With multiplexing, the connection goes back to the pool whenever the session holds no
+/// state on it, and a connection is checked out again for its next command.
+///
+/// The proxy authenticates the session on the connections it checks out itself, so only sessions
+/// of mapped identities fail over or are multiplexed. The database, character set and prepared
+/// statements of the session are replayed on them, see [`SessionReplay`].
+pub struct SessionBackend {
+    tenant: TenantKey,
+    checked_out: Option<CheckedOut>,
+    packet_hook: Option<PacketHook>,
+    inflate_budget: Option<InflateBudget>,
+}
+
+impl SessionBackend {
+    /// The session authenticated on `guard`, the connection of `conn` checked out of the pool of
+    /// the backend `addr` at `checked_out_at`.
+    pub fn new(
+        tenant: &TenantKey,
+        addr: String,
+        conn: Object<PooledConnMgr>,
+        guard: OwnedMutexGuard<BackendConn>,
+        checked_out_at: Timestamp,
+        in_flight: BackendConnGuard,
+    ) -> Self {
+        Self {
+            tenant: tenant.clone(),
+            checked_out: Some(CheckedOut {
+                guard,
+                conn,
+                addr,
+                checked_out_at,
+                _in_flight: in_flight,
+            }),
+            packet_hook: None,
+            inflate_budget: None,
+        }
+    }
+
+    /// Installs the hooks on the connection and on those the session checks out later.
+    pub fn set_hooks(&mut self, packet_hook: Option<PacketHook>, budget: Option<InflateBudget>) {
+        self.packet_hook = packet_hook;
+        self.inflate_budget = budget;
+        if let Some(checked_out) = self.checked_out.as_mut() {
+            let (reader, _) = &mut *checked_out.guard;
+            reader.set_packet_hook(self.packet_hook.clone());
+            reader.set_inflate_budget(self.inflate_budget.clone());
+        }
+    }
+
+    pub fn is_checked_out(&self) -> bool {
+        self.checked_out.is_some()
+    }
+
+    pub fn addr(&self) -> Option<&str> {
+        self.checked_out
+            .as_ref()
+            .map(|checked_out| checked_out.addr.as_str())
+    }
+
+    /// The backend connection of the session, `None` while it is back in the pool.
+    #[allow(clippy::type_complexity)]
+    pub fn conn(
+        &mut self,
+    ) -> Option<(
+        &mut PacketReader<BackendReadHalf>,
+        &mut PacketWriter<BackendWriteHalf>,
+        &PooledConn,
+    )> {
+        let CheckedOut { guard, conn, .. } = self.checked_out.as_mut()?;
+        let (reader, writer) = &mut **guard;
+        Some((reader, writer, conn))
+    }
+
+    /// Hands the connection back to the pool, the session holds no state on it.
+    pub fn release(&mut self) {
+        if let Some(mut checked_out) = self.checked_out.take() {
+            checked_out.clear_hooks();
+            debug!(
+                "ProxySrv session of {:?} released {}",
+                self.tenant, checked_out.addr
+            );
+        }
+    }
+
+    /// Checks out a connection of the backend the router picks for the next command of the
+    /// session.
+    pub async fn check_out(
+        &mut self,
+        backend_mgr: &BackendMgr,
+        handshake_response: &HandshakeResponse,
+        replay: &SessionReplay,
+    ) -> Result<(), Error> {
+        let checked_out = self.open(backend_mgr, handshake_response, replay).await?;
+        self.checked_out = Some(checked_out);
+        Ok(())
+    }
+
+    /// Checks out a connection for the command of `seq` unless the session holds one. Returns
+    /// false if none is available, the client told with an ER_CON_COUNT_ERROR to retry the
+    /// command.
+    pub async fn ensure_checked_out<W>(
+        &mut self,
+        backend_mgr: &BackendMgr,
+        handshake_response: &HandshakeResponse,
+        replay: &SessionReplay,
+        seq: u8,
+        client_writer: &mut PacketWriter<W>,
+    ) -> Result<bool, Error>
+    where
+        W: AsyncWrite + Send + Unpin,
+    {
+        if self.is_checked_out() {
+            return Ok(true);
+        }
+        let Err(e) = self
+            .check_out(backend_mgr, handshake_response, replay)
+            .await
+        else {
+            return Ok(true);
+        };
+        warn!(
+            "ProxySrv session of {:?} found no backend connection {e:?}",
+            self.tenant
+        );
+        recent_errors().record("backend", e.to_string());
+        client_writer.set_seq(seq.wrapping_add(1));
+        writers::write_err_packet(
+            ErrorKind::ER_CON_COUNT_ERROR,
+            b"No backend connection available, retry the command",
+            client_writer,
+            handshake_response.client_flag,
+        )
+        .await?;
+        client_writer.flush_all().await?;
+        Ok(false)
+    }
+
+    /// Counts a failover by its `result`: `retried` the command, `reconnected` without it, or
+    /// `failed`.
+    pub fn record_failover(&self, result: &str) {
+        let mut labels = common_labels().clone();
+        labels.push(("tenant", tenant_label(&self.tenant)));
+        labels.push(("result", result.to_string()));
+        counter_inc(PROXY_BACKEND_FAILOVERS, 1, Some(&labels));
+    }
+
+    /// Closes the broken connection of the session and opens one to the backend the router
+    /// picks.
+    pub async fn fail_over(
+        &mut self,
+        backend_mgr: &BackendMgr,
+        handshake_response: &HandshakeResponse,
+        replay: &SessionReplay,
+    ) -> Result<(), Error> {
+        if let Some(mut lost) = self.checked_out.take() {
+            lost.clear_hooks();
+            lost.conn.invalidated.store(true, Ordering::Release);
+            quarantine_registry().record_failure(
+                &lost.addr,
+                BackendFailure::Lost,
+                "connection lost mid-session".to_string(),
+            );
+        }
+        if handshake_response.identity.is_none() {
+            return Err(Error::new(
+                std::io::ErrorKind::Unsupported,
+                "the proxy does not hold the credential of the session",
+            ));
+        }
+        // The session leaves its backend, a reconnect grant must not lead back to it.
+        let mut handshake_response = handshake_response.clone();
+        handshake_response.reconnect = None;
+        let mut last_err = None;
+        for _ in 0..FAILOVER_ATTEMPTS {
+            match self.open(backend_mgr, &handshake_response, replay).await {
+                Ok(checked_out) => {
+                    info!(
+                        "ProxySrv session of {:?} failed over to {}",
+                        self.tenant, checked_out.addr
+                    );
+                    self.checked_out = Some(checked_out);
+                    return Ok(());
+                }
+                Err(e) => {
+                    warn!("ProxySrv failover of {:?} failed {e:?}", self.tenant);
+                    recent_errors().record("failover", e.to_string());
+                    last_err = Some(e);
+                }
+            }
+        }
+        Err(last_err.unwrap())
+    }
+
+    /// Recovers the session from `e`, its connection broken before the client got a response:
+    /// fails over unless `lost`, the state the session held on the connection, cannot be
+    /// replayed. Returns `retry`, the command to send again on the new connection, if the
+    /// command may be sent again; the client is told to retry otherwise. Returns `e` once the
+    /// client was told the session cannot fail over.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn recover<W>(
+        &mut self,
+        e: Error,
+        backend_mgr: &BackendMgr,
+        handshake_response: &HandshakeResponse,
+        replay: &SessionReplay,
+        lost: SessionState,
+        retry: Option<(u8, Packet)>,
+        seq: u8,
+        client_writer: &mut PacketWriter<W>,
+    ) -> Result<Option<(u8, Packet)>, Error>
+    where
+        W: AsyncWrite + Send + Unpin,
+    {
+        let client_flag = handshake_response.client_flag;
+        let reconnected = if replay.is_replayable(lost) {
+            self.fail_over(backend_mgr, handshake_response, replay)
+                .await
+        } else {
+            Err(Error::new(
+                std::io::ErrorKind::Unsupported,
+                "the session state was lost with the connection",
+            ))
+        };
+        if let Err(failover_err) = reconnected {
+            warn!(
+                "ProxySrv session of {:?} cannot fail over {failover_err:?}",
+                self.tenant
+            );
+            self.record_failover("failed");
+            write_failover_err(FAILOVER_FAILED, seq, client_writer, client_flag).await?;
+            return Err(e);
+        }
+        if retry.is_some() {
+            self.record_failover("retried");
+        } else {
+            self.record_failover("reconnected");
+            write_failover_err(FAILOVER_RECONNECTED, seq, client_writer, client_flag).await?;
+        }
+        Ok(retry)
+    }
+
+    /// Authenticates the session on a connection of the backend the router picks and replays
+    /// its state there.
+    async fn open(
+        &self,
+        backend_mgr: &BackendMgr,
+        handshake_response: &HandshakeResponse,
+        replay: &SessionReplay,
+    ) -> Result<CheckedOut, Error> {
+        let (addr, conn) = backend_mgr
+            .checkout_conn(handshake_response, CHECKOUT_TIMEOUT)
+            .await?;
+        let checked_out_at = clock().coarse_now();
+        let command_phase =
+            conn.get_conn_life_cycle().await.conn_phase() == Some(DbConnPhase::Command);
+        let mut guard = conn.inner_conn.clone().lock_owned().await;
+        let opened = async {
+            let (reader, writer) = &mut *guard;
+            authenticate_identity(
+                handshake_response,
+                command_phase,
+                conn.compression,
+                writer,
+                reader,
+            )
+            .await?;
+            replay.replay(&mut guard).await?;
+            let multi_statements = replay.multi_statements(handshake_response.client_flag);
+            restore_multi_statements(&mut guard, &conn.session_state, multi_statements).await
+        }
+        .await;
+        if let Err(e) = opened {
+            conn.invalidated.store(true, Ordering::Release);
+            if is_broken_conn(&e) {
+                quarantine_registry().record_failure(&addr, BackendFailure::Lost, e.to_string());
+            }
+            return Err(e);
+        }
+        conn.update_conn_life_cycle(DbUserConnLifeCycle::new_conn_life_cycle(
+            handshake_response.db_user_string(),
+            DbConnPhase::Command,
+        ))
+        .await;
+        conn.stmt_cache.lock().await.clear();
+        conn.session_state.reset();
+        let (reader, _) = &mut *guard;
+        reader.set_err_hook(Some(err_code_hook(&self.tenant, &addr, &conn)));
+        reader.set_packet_hook(self.packet_hook.clone());
+        reader.set_inflate_budget(self.inflate_budget.clone());
+        Ok(CheckedOut {
+            guard,
+            conn,
+            _in_flight: backend_conns().checkout(&addr),
+            addr,
+            checked_out_at,
+        })
+    }
+
+    /// Ends the session: the connection goes back to the pool, where other tenants may use it,
+    /// unless the session did not leave it clean. A connection left without a confirmed reset
+    /// keeps its tracked state, the pool discards it if it is sticky-dirty.
+    pub fn finish(mut self, closed: &Result<SessionCloseReason, Error>) {
+        let Some(mut checked_out) = self.checked_out.take() else {
+            return;
+        };
+        checked_out.clear_hooks();
+        match closed {
+            Err(e) => {
+                if is_broken_conn(e)
+                    && clock().coarse_elapsed(checked_out.checked_out_at) < EARLY_FAILURE_WINDOW
+                {
+                    quarantine_registry().record_failure(
+                        &checked_out.addr,
+                        BackendFailure::EarlyClose,
+                        e.to_string(),
+                    );
+                }
+            }
+            Ok(close_reason) if !close_reason.is_backend_reusable() => {
+                let CheckedOut { guard, conn, .. } = checked_out;
+                drop(guard);
+                // Detaching closes the connection in the background.
+                let detached = Object::take(conn);
+                warn!(
+                    "ProxySrv discard backend conn {:?} {:?}",
+                    detached.id, close_reason
+                );
+            }
+            // The session closed with a reset the backend confirmed.
+            Ok(close_reason) => checked_out.conn.session_state.observe_close(*close_reason),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::pool::pooled_conn_mgr::PooledConnMgr;
+    use crate::backend::pool::BackendPoolConfig;
+    use crate::backend::router::p2c::backend_conns;
+    use crate::backend::{test_tenant_key, BackendInstance};
+    use crate::protocol::mysql::constants::CommandCode;
+    use crate::server::session::SessionCloseReason;
+    use crate::server::session_backend::SessionBackend;
+    use common::clock::clock;
+    use deadpool::managed::Pool;
+    use tokio::net::TcpListener;
+
+    async fn check_out(pool: &Pool<PooledConnMgr>, addr: &str) -> SessionBackend {
+        let conn = pool.get().await.unwrap();
+        let guard = conn.inner_conn.clone().lock_owned().await;
+        SessionBackend::new(
+            &test_tenant_key(),
+            addr.to_string(),
+            conn,
+            guard,
+            clock().coarse_now(),
+            backend_conns().checkout(addr),
+        )
+    }
+
+    #[tokio::test]
+    pub async fn test_session_backend_release() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend = BackendInstance {
+            addr: listener.local_addr().unwrap().to_string(),
+            ..Default::default()
+        };
+        let accepted = tokio::spawn(async move {
+            let mut peers = vec![];
+            while let Ok((peer, _)) = listener.accept().await {
+                peers.push(peer);
+            }
+        });
+        let addr = backend.addr.clone();
+        let pool = Pool::builder(PooledConnMgr::new(backend, &BackendPoolConfig::default()))
+            .max_size(1)
+            .build()
+            .unwrap();
+
+        let mut session = check_out(&pool, &addr).await;
+        assert!(session.is_checked_out());
+        assert_eq!(session.addr(), Some(addr.as_str()));
+        assert_eq!(backend_conns().in_flight(&addr), 1);
+        assert_eq!(pool.status().available, 0);
+        // Between transactions the connection goes back to the pool for another session.
+        session.release();
+        assert!(!session.is_checked_out());
+        assert!(session.conn().is_none());
+        assert_eq!(backend_conns().in_flight(&addr), 0);
+        assert_eq!(pool.status().available, 1);
+
+        let mut other = check_out(&pool, &addr).await;
+        assert_eq!(pool.status().size, 1);
+        let (_, _, conn) = other.conn().unwrap();
+        conn.session_state
+            .observe_command(CommandCode::ComStmtSendLongData, &1_u32.to_le_bytes());
+        assert!(!conn.session_state.is_clean());
+        // A confirmed reset leaves the connection clean for the next session.
+        other.finish(&Ok(SessionCloseReason::Quit));
+        assert_eq!(pool.status().available, 1);
+        let mut reused = check_out(&pool, &addr).await;
+        assert!(reused.conn().unwrap().2.session_state.is_clean());
+        reused.finish(&Ok(SessionCloseReason::QuitResetFailed));
+        assert_eq!(pool.status().size, 0);
+
+        // A session ending while released holds no connection to hand back.
+        session.finish(&Ok(SessionCloseReason::Quit));
+        assert_eq!(backend_conns().in_flight(&addr), 0);
+        accepted.abort();
+    }
+}
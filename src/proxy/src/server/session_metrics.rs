@@ -0,0 +1,258 @@
+use crate::prost::common_proto::TenantKey;
+use crate::protocol::mysql::constants::SqlComInfo;
+use crate::server::session::SessionCloseReason;
+use crate::server::slow_log::tenant_label;
+use crate::server::{init_sql_com_labels, PROXY_COM_METRIC_LABEL_KEY};
+
+use common::clock::{clock, Timestamp};
+use common::metrics::metric_def::{
+    PROXY_COM_BYTES, PROXY_COM_LATENCY, PROXY_COM_LATENCY_DROPPED, PROXY_COM_ROWS, PROXY_CURR_CONN,
+    PROXY_FLOW_CONTROL_PAUSED, PROXY_LONG_DATA_BYTES, PROXY_LONG_DATA_REJECTED,
+    PROXY_SESSION_CLOSED,
+};
+use common::metrics::{
+    common_labels, counter_handle, gauge_dec, histogram_handle, Counter, Histogram, MetricsTimer,
+};
+use hashbrown::hash_map::Entry;
+use hashbrown::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+
+/// Latency samples the aggregator records per wake up.
+const COM_LATENCY_BATCH: usize = 256;
+
+/// The latency histogram of every command code, resolved once per server.
+pub fn com_latency_histograms() -> Arc<HashMap<u8, Histogram>> {
+    Arc::new(
+        init_sql_com_labels()
+            .iter()
+            .map(|(com_code, labels)| (*com_code, histogram_handle(PROXY_COM_LATENCY, labels)))
+            .collect(),
+    )
+}
+
+/// The queue of [`ComLatencyRecorder::Queued`], of `(com_code, milliseconds)` samples.
+pub struct ComLatencyQueue {
+    sender: mpsc::Sender<(u8, f64)>,
+    dropped: Counter,
+}
+
+/// How the command latencies of the sessions reach their histograms.
+#[derive(Clone)]
+pub enum ComLatencyRecorder {
+    /// Every command records into its histogram when it completes.
+    Direct(Arc<HashMap<u8, Histogram>>),
+    /// Commands push their latency into a bounded queue, recorded in batches by an aggregator
+    /// task. Sessions no longer contend on the histograms at very high QPS, the histograms lag
+    /// by the samples queued. Samples finding the queue full are dropped.
+    Queued(Arc<ComLatencyQueue>),
+}
+
+impl Default for ComLatencyRecorder {
+    fn default() -> Self {
+        ComLatencyRecorder::Direct(com_latency_histograms())
+    }
+}
+
+impl ComLatencyRecorder {
+    /// A recorder queuing up to `capacity` samples. Spawns the aggregator on the current
+    /// runtime, it stops once the recorder and its clones are dropped.
+    pub fn queued(capacity: usize) -> Self {
+        let (sender, receiver) = mpsc::channel(capacity.max(1));
+        tokio::spawn(aggregate_com_latency(com_latency_histograms(), receiver));
+        ComLatencyRecorder::Queued(Arc::new(ComLatencyQueue {
+            sender,
+            dropped: counter_handle(PROXY_COM_LATENCY_DROPPED, common_labels()),
+        }))
+    }
+
+    /// Samples waiting for the aggregator.
+    pub fn queued_samples(&self) -> usize {
+        match self {
+            ComLatencyRecorder::Direct(_) => 0,
+            ComLatencyRecorder::Queued(queue) => {
+                queue.sender.max_capacity() - queue.sender.capacity()
+            }
+        }
+    }
+}
+
+async fn aggregate_com_latency(
+    histograms: Arc<HashMap<u8, Histogram>>,
+    mut receiver: mpsc::Receiver<(u8, f64)>,
+) {
+    let mut batch = Vec::with_capacity(COM_LATENCY_BATCH);
+    while receiver.recv_many(&mut batch, COM_LATENCY_BATCH).await > 0 {
+        for (com_code, latency) in batch.drain(..) {
+            if let Some(histogram) = histograms.get(&com_code) {
+                histogram.record(latency);
+            }
+        }
+    }
+}
+
+/// Times a command until it is dropped, see [`SessionMetrics::com_timer`].
+pub enum ComTimer<'a> {
+    Direct(MetricsTimer),
+    Queued {
+        com_code: u8,
+        start: Timestamp,
+        queue: &'a ComLatencyQueue,
+    },
+}
+
+impl Drop for ComTimer<'_> {
+    fn drop(&mut self) {
+        if let ComTimer::Queued {
+            com_code,
+            start,
+            queue,
+        } = self
+        {
+            let latency = clock().precise_elapsed(*start).as_secs_f64() * 1e3;
+            if queue.sender.try_send((*com_code, latency)).is_err() {
+                queue.dropped.increment(1);
+            }
+        }
+    }
+}
+
+/// What one command moved, see [`SessionMetrics::record_com`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ComTraffic {
+    /// Bytes sent to the backend.
+    pub sent: u64,
+    /// Bytes received from the backend.
+    pub received: u64,
+    /// Result set rows returned to the client.
+    pub rows: u64,
+}
+
+/// The traffic counters of a command code.
+struct ComCounters {
+    sent: Counter,
+    received: Counter,
+    rows: Counter,
+}
+
+/// `SessionMetrics` holds the metric handles of one client session. The tenant labels are
+/// hashed once when the session starts instead of on every packet.
+pub struct SessionMetrics {
+    com_latency: ComLatencyRecorder,
+    tenant_labels: Vec<(&'static str, String)>,
+    pub long_data_bytes: Counter,
+    pub long_data_rejected: Counter,
+    pub flow_control_paused: Counter,
+    /// Resolved on the first command of each code the session sends.
+    com_counters: Mutex<HashMap<u8, ComCounters>>,
+}
+
+impl SessionMetrics {
+    pub fn new(tenant: &TenantKey, com_latency: ComLatencyRecorder) -> Self {
+        let mut tenant_labels = common_labels().clone();
+        tenant_labels.push(("tenant", tenant_label(tenant)));
+        Self {
+            com_latency,
+            long_data_bytes: counter_handle(PROXY_LONG_DATA_BYTES, &tenant_labels),
+            long_data_rejected: counter_handle(PROXY_LONG_DATA_REJECTED, &tenant_labels),
+            flow_control_paused: counter_handle(PROXY_FLOW_CONTROL_PAUSED, &tenant_labels),
+            com_counters: Mutex::default(),
+            tenant_labels,
+        }
+    }
+
+    /// Times a command until the timer is dropped, most commands take less than a clock tick.
+    pub fn com_timer(&self, com_code: u8) -> Option<ComTimer<'_>> {
+        match &self.com_latency {
+            ComLatencyRecorder::Direct(histograms) => histograms
+                .get(&com_code)
+                .map(|histogram| ComTimer::Direct(MetricsTimer::precise(histogram.clone()))),
+            ComLatencyRecorder::Queued(queue) => Some(ComTimer::Queued {
+                com_code,
+                start: clock().precise_now(),
+                queue,
+            }),
+        }
+    }
+
+    /// Counts the bytes and rows of a command, for capacity planning. Unknown command codes are
+    /// not counted.
+    pub fn record_com(&self, com_code: u8, traffic: ComTraffic) {
+        let mut com_counters = self.com_counters.lock().unwrap();
+        let counters = match com_counters.entry(com_code) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let Some(com_str) = SqlComInfo::all_sql_com().get(&com_code) else {
+                    return;
+                };
+                let mut labels = self.tenant_labels.clone();
+                labels.push((PROXY_COM_METRIC_LABEL_KEY, com_str.to_string()));
+                let with = |direction: &str| {
+                    let mut labels = labels.clone();
+                    labels.push(("direction", direction.to_string()));
+                    labels
+                };
+                entry.insert(ComCounters {
+                    sent: counter_handle(PROXY_COM_BYTES, &with("sent")),
+                    received: counter_handle(PROXY_COM_BYTES, &with("received")),
+                    rows: counter_handle(PROXY_COM_ROWS, &labels),
+                })
+            }
+        };
+        counters.sent.increment(traffic.sent);
+        counters.received.increment(traffic.received);
+        counters.rows.increment(traffic.rows);
+    }
+
+    /// Called once when the session ends, the session no longer counts as a current connection.
+    pub fn session_closed(&self, reason: SessionCloseReason) {
+        gauge_dec(PROXY_CURR_CONN, 1_f64, Some(common_labels()));
+        let mut labels = self.tenant_labels.clone();
+        labels.push(("reason", reason.label().to_string()));
+        counter_handle(PROXY_SESSION_CLOSED, &labels).increment(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::test_tenant_key;
+    use crate::protocol::mysql::constants::CommandCode;
+    use crate::server::session_metrics::{ComLatencyRecorder, ComTraffic, SessionMetrics};
+    use std::time::Duration;
+
+    #[test]
+    pub fn test_session_metrics() {
+        let metrics = SessionMetrics::new(&test_tenant_key(), ComLatencyRecorder::default());
+        assert!(metrics.com_timer(CommandCode::ComQuery as u8).is_some());
+        assert!(metrics.com_timer(0xee).is_none());
+        metrics.long_data_bytes.increment(16);
+        let traffic = ComTraffic {
+            sent: 32,
+            received: 256,
+            rows: 4,
+        };
+        metrics.record_com(CommandCode::ComQuery as u8, traffic);
+        metrics.record_com(CommandCode::ComQuery as u8, traffic);
+        metrics.record_com(0xee, traffic);
+        assert_eq!(metrics.com_counters.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    pub async fn test_queued_com_latency() {
+        let recorder = ComLatencyRecorder::queued(4);
+        let metrics = SessionMetrics::new(&test_tenant_key(), recorder.clone());
+        // The aggregator has not run yet, the samples beyond the capacity are dropped.
+        for _ in 0..6 {
+            drop(metrics.com_timer(CommandCode::ComQuery as u8));
+        }
+        assert_eq!(recorder.queued_samples(), 4);
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while recorder.queued_samples() > 0 {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(ComLatencyRecorder::default().queued_samples(), 0);
+    }
+}
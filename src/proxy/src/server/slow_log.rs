@@ -0,0 +1,379 @@
+use crate::prost::common_proto::TenantKey;
+use crate::protocol::mysql::fingerprint::SqlFingerprint;
+use crate::server::sql_privacy::sql_privacy;
+
+use chrono::{DateTime, Local};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Mutex, OnceLock};
+use std::time::Duration;
+use tracing::{info, warn};
+
+pub const DEFAULT_SLOW_QUERY_THRESHOLD: Duration = Duration::from_secs(1);
+pub const DEFAULT_SLOW_LOG_CAPACITY: usize = 128;
+/// Statements are truncated to this many bytes before they are kept in the ring buffer.
+pub const SLOW_LOG_SQL_MAX_LEN: usize = 256;
+/// The tracing target of the slow queries, unless they are written to a [`SlowLogFile`].
+pub const SLOW_QUERY_TARGET: &str = "slow_query";
+
+#[derive(Debug, Clone)]
+pub struct SlowQueryEntry {
+    pub id: u64,
+    pub time: DateTime<Local>,
+    pub tenant: String,
+    pub user: String,
+    pub duration: Duration,
+    /// Bytes of the response sent to the client.
+    pub bytes: u64,
+    /// The statement as its tenant allows to export it, see [`SqlPrivacy`]. `None` for an
+    /// executed prepared statement whose text the proxy does not know.
+    ///
+    /// [`SqlPrivacy`]: crate::server::sql_privacy::SqlPrivacy
+    pub sql: Option<String>,
+    /// The shape of the statement, exported whatever the SQL privacy of its tenant.
+    pub fingerprint: Option<SqlFingerprint>,
+}
+
+impl SlowQueryEntry {
+    fn to_json_line(&self) -> Vec<u8> {
+        let mut line = serde_json::json!({
+            "id": self.id,
+            "time": self.time.to_rfc3339(),
+            "tenant": self.tenant,
+            "user": self.user,
+            "duration_ms": self.duration.as_millis() as u64,
+            "bytes": self.bytes,
+            "sql": self.sql,
+            "fingerprint": self.fingerprint,
+        })
+        .to_string()
+        .into_bytes();
+        line.push(b'\n');
+        line
+    }
+}
+
+/// A file the slow queries are appended to as JSON lines. Once it would grow beyond `max_bytes`
+/// it is renamed with a `.1` suffix, replacing the previous one, and a new file is started.
+#[derive(Debug, Clone)]
+pub struct SlowLogFile {
+    pub path: PathBuf,
+    pub max_bytes: u64,
+}
+
+/// `SlowQueryLog` keeps the most recent slow queries in a fixed size ring buffer, the oldest
+/// entry is dropped once the buffer is full. Every slow query is also written to the
+/// [`SlowLogFile`] if one is set, otherwise to the [`SLOW_QUERY_TARGET`] tracing target.
+pub struct SlowQueryLog {
+    /// In microseconds, changed by a configuration reload.
+    threshold_us: AtomicU64,
+    capacity: usize,
+    next_id: AtomicU64,
+    entries: Mutex<VecDeque<SlowQueryEntry>>,
+    file_tx: Option<mpsc::Sender<SlowQueryEntry>>,
+}
+
+static SLOW_QUERY_LOG_ONCE: OnceLock<SlowQueryLog> = OnceLock::new();
+
+/// Initializes the global slow query log, must be called before the first query is served.
+pub fn init_slow_query_log(
+    threshold: Duration,
+    capacity: usize,
+    file: Option<SlowLogFile>,
+) -> &'static SlowQueryLog {
+    SLOW_QUERY_LOG_ONCE.get_or_init(|| {
+        let slow_log = SlowQueryLog::new(threshold, capacity);
+        match file {
+            Some(file) => slow_log.with_file(file),
+            None => slow_log,
+        }
+    })
+}
+
+pub fn slow_query_log() -> &'static SlowQueryLog {
+    SLOW_QUERY_LOG_ONCE
+        .get_or_init(|| SlowQueryLog::new(DEFAULT_SLOW_QUERY_THRESHOLD, DEFAULT_SLOW_LOG_CAPACITY))
+}
+
+pub fn tenant_label(tenant: &TenantKey) -> String {
+    format!(
+        "{}/{}/{}/{}",
+        tenant.region, tenant.available_zone, tenant.namespace, tenant.cluster_name
+    )
+}
+
+/// Keeps at most [`SLOW_LOG_SQL_MAX_LEN`] bytes of the statement.
+pub fn truncate_sql(sql: &[u8]) -> Vec<u8> {
+    sql[..sql.len().min(SLOW_LOG_SQL_MAX_LEN)].to_vec()
+}
+
+impl SlowQueryLog {
+    pub fn new(threshold: Duration, capacity: usize) -> Self {
+        Self {
+            threshold_us: AtomicU64::new(threshold.as_micros() as u64),
+            capacity,
+            next_id: AtomicU64::new(0),
+            entries: Mutex::new(VecDeque::with_capacity(capacity)),
+            file_tx: None,
+        }
+    }
+
+    /// Writes the slow queries to `file` on a thread of its own.
+    pub fn with_file(mut self, file: SlowLogFile) -> Self {
+        let (tx, rx) = mpsc::channel();
+        let spawned = std::thread::Builder::new()
+            .name("slow-log".to_string())
+            .spawn(move || write_slow_log(file, rx));
+        match spawned {
+            Ok(_) => self.file_tx = Some(tx),
+            Err(e) => warn!("ProxySrv slow log writer failed to start. cause by {e:?}"),
+        }
+        self
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    pub fn is_slow(&self, duration: Duration) -> bool {
+        self.is_enabled() && duration >= self.threshold()
+    }
+
+    pub fn threshold(&self) -> Duration {
+        Duration::from_micros(self.threshold_us.load(Ordering::Relaxed))
+    }
+
+    pub fn set_threshold(&self, threshold: Duration) {
+        self.threshold_us
+            .store(threshold.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn record(
+        &self,
+        tenant: &TenantKey,
+        user: String,
+        duration: Duration,
+        bytes: u64,
+        sql: Option<&[u8]>,
+        fingerprint: Option<SqlFingerprint>,
+    ) {
+        let entry = SlowQueryEntry {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            time: Local::now(),
+            tenant: tenant_label(tenant),
+            user,
+            duration,
+            bytes,
+            sql: sql.and_then(|sql| sql_privacy().export(tenant, &truncate_sql(sql))),
+            fingerprint,
+        };
+        match &self.file_tx {
+            Some(file_tx) => {
+                if file_tx.send(entry.clone()).is_err() {
+                    warn!("ProxySrv slow query dropped, the slow log writer stopped");
+                }
+            }
+            None => info!(
+                target: SLOW_QUERY_TARGET,
+                tenant = %entry.tenant,
+                user = %entry.user,
+                duration_ms = entry.duration.as_millis() as u64,
+                bytes = entry.bytes,
+                sql = entry.sql.as_deref().unwrap_or_default(),
+                fingerprint = entry.fingerprint.map(|f| f.to_string()).unwrap_or_default(),
+                "slow query"
+            ),
+        }
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+
+    /// Returns up to `limit` entries, newest first. Only the entries of the tenant labeled
+    /// `tenant` if set.
+    pub fn entries(&self, limit: Option<usize>, tenant: Option<&str>) -> Vec<SlowQueryEntry> {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .iter()
+            .rev()
+            .filter(|entry| tenant.map_or(true, |tenant| entry.tenant == tenant))
+            .take(limit.unwrap_or(usize::MAX))
+            .cloned()
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn reset(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+}
+
+/// The path a full slow log file is renamed to.
+fn rotated_path(path: &Path) -> PathBuf {
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(".1");
+    PathBuf::from(rotated)
+}
+
+fn open_slow_log(path: &Path) -> Option<(File, u64)> {
+    let opened = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|file| Ok((file.metadata()?.len(), file)));
+    match opened {
+        Ok((len, file)) => Some((file, len)),
+        Err(e) => {
+            warn!(
+                "ProxySrv slow log failed to open {:?}. cause by {e:?}",
+                path
+            );
+            None
+        }
+    }
+}
+
+fn write_slow_log(file: SlowLogFile, rx: mpsc::Receiver<SlowQueryEntry>) {
+    info!("ProxySrv slow queries are appended to {:?}", file.path);
+    let mut out = None;
+    for entry in rx {
+        let line = entry.to_json_line();
+        if out
+            .as_ref()
+            .is_some_and(|(_, len)| *len > 0 && len + line.len() as u64 > file.max_bytes)
+        {
+            out = None;
+            if let Err(e) = std::fs::rename(&file.path, rotated_path(&file.path)) {
+                warn!("ProxySrv slow log rotation of {:?} failed {e:?}", file.path);
+            }
+        }
+        if out.is_none() {
+            out = open_slow_log(&file.path);
+        }
+        let Some((writer, len)) = out.as_mut() else {
+            continue;
+        };
+        match writer.write_all(&line) {
+            Ok(()) => *len += line.len() as u64,
+            Err(e) => warn!(
+                "ProxySrv slow log write err {:?}. cause by {e:?}",
+                file.path
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::test_tenant_key;
+    use crate::prost::common_proto::TenantKey;
+    use crate::protocol::mysql::fingerprint::fingerprint;
+    use crate::server::slow_log::{rotated_path, tenant_label, SlowLogFile, SlowQueryLog};
+    use std::time::Duration;
+
+    #[test]
+    pub fn test_slow_query_ring_buffer() {
+        let slow_log = SlowQueryLog::new(Duration::from_millis(10), 2);
+        let tenant = test_tenant_key();
+        assert!(!slow_log.is_slow(Duration::from_millis(5)));
+        assert!(slow_log.is_slow(Duration::from_millis(10)));
+        for sql in ["select 1", "select 2", "select 3"] {
+            slow_log.record(
+                &tenant,
+                "root".to_string(),
+                Duration::from_millis(20),
+                64,
+                Some(sql.as_bytes()),
+                Some(fingerprint(sql.as_bytes())),
+            );
+        }
+        let entries = slow_log.entries(None, None);
+        assert_eq!(entries.len(), 2);
+        // Statements are normalized unless the tenant allows raw SQL text.
+        assert_eq!(entries[0].sql.as_deref(), Some("select ?"));
+        assert_eq!(entries[0].fingerprint, entries[1].fingerprint);
+        assert_eq!(entries[1].id, 1);
+        assert_eq!(slow_log.entries(Some(1), None).len(), 1);
+
+        let other = TenantKey {
+            cluster_name: "other".to_string(),
+            ..tenant.clone()
+        };
+        slow_log.record(
+            &other,
+            "root".to_string(),
+            Duration::from_millis(20),
+            64,
+            Some(b"select 4"),
+            None,
+        );
+        let entries = slow_log.entries(None, Some(&tenant_label(&tenant)));
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].tenant, tenant_label(&tenant));
+        assert_eq!(slow_log.entries(None, None).len(), 2);
+
+        slow_log.reset();
+        assert!(slow_log.is_empty());
+    }
+
+    #[test]
+    pub fn test_slow_log_file_rotation() {
+        let dir = std::env::temp_dir().join(format!("slow-log-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("slow.jsonl");
+        let file = SlowLogFile {
+            path: path.clone(),
+            max_bytes: 256,
+        };
+        let slow_log = SlowQueryLog::new(Duration::from_millis(10), 4).with_file(file);
+        let tenant = test_tenant_key();
+        for _ in 0..3 {
+            let duration = Duration::from_millis(20);
+            let sql = b"select 1";
+            let fingerprint = Some(fingerprint(sql));
+            slow_log.record(
+                &tenant,
+                "root".to_string(),
+                duration,
+                64,
+                Some(sql),
+                fingerprint,
+            );
+        }
+        let duration = Duration::from_secs(1);
+        slow_log.record(&tenant, "root".to_string(), duration, 8, None, None);
+        let last_entry = || {
+            let content = std::fs::read_to_string(&path).ok()?;
+            serde_json::from_str::<serde_json::Value>(content.lines().last()?).ok()
+        };
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        let entry = loop {
+            match last_entry() {
+                Some(entry) if entry["id"] == 3 => break entry,
+                _ => {
+                    assert!(std::time::Instant::now() < deadline);
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+            }
+        };
+        assert_eq!(entry["duration_ms"], 1000);
+        assert!(entry["sql"].is_null());
+        assert!(entry["fingerprint"].is_null());
+        // Every entry is larger than half the limit, each one started a new file.
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 1);
+        assert!(rotated_path(&path).exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,228 @@
+//! SNI routing, for clients connecting over TLS to a hostname of their tenant, e.g.
+//! `orders-shop.proxy.example.com` served by a wildcard certificate of the domain. Standard MySQL
+//! clients then connect with their plain user name instead of `tenant.user`.
+
+use crate::backend::encode_tenant_key;
+use crate::prost::common_proto::TenantKey;
+use crate::protocol::mysql::basic::HandshakeResponse;
+use crate::server::slow_log::tenant_label;
+
+use common::metrics::metric_def::PROXY_SNI_ROUTED_CONNS;
+use common::metrics::{common_labels, counter_inc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::sync::{OnceLock, RwLock};
+use tracing::{debug, info, warn};
+
+/// Routes the connections to `hostname` to `tenant`, for hostnames that do not follow the
+/// `<cluster>-<namespace>` naming of the domain.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SniRoute {
+    pub hostname: String,
+    pub tenant: TenantKey,
+}
+
+fn normalize_hostname(hostname: &str) -> String {
+    hostname.trim().trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// `SniRouter` picks the tenant of TLS connections by the hostname the client connected to: a
+/// route of the hostname, managed through the REST API, or else the first label of a hostname of
+/// the domain, `<cluster>-<namespace>`, split at its last dash. Namespaces with a dash need a
+/// route.
+#[derive(Default)]
+pub struct SniRouter {
+    /// The domain whose hostnames name their tenant, `None` routes by the routes only.
+    domain: Option<String>,
+    routes: RwLock<HashMap<String, TenantKey>>,
+}
+
+static SNI_ROUTER_ONCE: OnceLock<SniRouter> = OnceLock::new();
+
+/// Routes the hostnames of `domain`, the first call wins.
+pub fn init_sni_router(domain: Option<String>) {
+    let domain = domain
+        .map(|domain| normalize_hostname(&domain))
+        .filter(|domain| !domain.is_empty());
+    if let Some(domain) = &domain {
+        info!("ProxySrv SNI routing of the hostnames of {domain}");
+    }
+    let _ = SNI_ROUTER_ONCE.set(SniRouter::new(domain));
+}
+
+pub fn sni_router() -> &'static SniRouter {
+    SNI_ROUTER_ONCE.get_or_init(SniRouter::default)
+}
+
+impl SniRouter {
+    pub fn new(domain: Option<String>) -> Self {
+        Self {
+            domain,
+            routes: RwLock::default(),
+        }
+    }
+
+    /// Adds `route`, replacing the route of the same hostname.
+    pub fn set_route(&self, route: SniRoute) -> Result<(), Error> {
+        let hostname = normalize_hostname(&route.hostname);
+        if hostname.is_empty() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "SNI route without a hostname",
+            ));
+        }
+        info!("ProxySrv SNI route {hostname} to {:?}", route.tenant);
+        self.routes.write().unwrap().insert(hostname, route.tenant);
+        Ok(())
+    }
+
+    pub fn remove_route(&self, hostname: &str) -> Option<SniRoute> {
+        let hostname = normalize_hostname(hostname);
+        let tenant = self.routes.write().unwrap().remove(&hostname)?;
+        Some(SniRoute { hostname, tenant })
+    }
+
+    /// The routes, ordered by hostname.
+    pub fn list(&self) -> Vec<SniRoute> {
+        let routes = self.routes.read().unwrap();
+        let mut routes: Vec<_> = routes
+            .iter()
+            .map(|(hostname, tenant)| SniRoute {
+                hostname: hostname.clone(),
+                tenant: tenant.clone(),
+            })
+            .collect();
+        routes.sort_by(|a, b| a.hostname.cmp(&b.hostname));
+        routes
+    }
+
+    /// The first label of `hostname` if it is a hostname of the domain.
+    fn domain_label<'a>(&self, hostname: &'a str) -> Option<&'a str> {
+        let domain = self.domain.as_deref()?;
+        hostname
+            .strip_suffix(domain)?
+            .strip_suffix('.')
+            .filter(|label| !label.is_empty() && !label.contains('.'))
+    }
+
+    /// The tenant of the connections to `hostname`, `None` if it names none.
+    pub fn resolve(&self, hostname: &str) -> Option<TenantKey> {
+        let hostname = normalize_hostname(hostname);
+        if let Some(tenant) = self.routes.read().unwrap().get(&hostname) {
+            return Some(tenant.clone());
+        }
+        let (cluster_name, namespace) = self.domain_label(&hostname)?.rsplit_once('-')?;
+        (!cluster_name.is_empty() && !namespace.is_empty()).then(|| TenantKey {
+            namespace: namespace.to_string(),
+            cluster_name: cluster_name.to_string(),
+            ..Default::default()
+        })
+    }
+
+    /// Sets the tenant of a TLS connection from the hostname it connected to, it replaces any
+    /// tenant of the user name. Connections without a hostname, or to a hostname outside the
+    /// domain without a route, keep the tenant of the user name. Those to a hostname of the domain
+    /// that names no tenant are refused.
+    pub fn route(&self, handshake: &mut HandshakeResponse) -> Result<(), Error> {
+        let Some(hostname) = handshake.server_name.as_deref() else {
+            return Ok(());
+        };
+        let tenant = self.resolve(hostname);
+        let in_domain = self.domain_label(&normalize_hostname(hostname)).is_some();
+        if tenant.is_none() && !in_domain {
+            return Ok(());
+        }
+        let mut labels = common_labels().clone();
+        let result = if tenant.is_some() {
+            "routed"
+        } else {
+            "no_route"
+        };
+        labels.push(("result", result.to_string()));
+        counter_inc(PROXY_SNI_ROUTED_CONNS, 1, Some(&labels));
+        let Some(tenant) = tenant else {
+            warn!("ProxySrv no SNI route for {hostname}");
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                format!("no tenant for the hostname {hostname}"),
+            ));
+        };
+        debug!(
+            "ProxySrv SNI {hostname} routed to {}",
+            tenant_label(&tenant)
+        );
+        handshake.tenant_key = Some(encode_tenant_key(&tenant).into_bytes());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::handshake_tenant_key;
+    use crate::prost::common_proto::TenantKey;
+    use crate::protocol::mysql::basic::HandshakeResponse;
+    use crate::server::sni_router::{SniRoute, SniRouter};
+    use mysql_common::constants::CapabilityFlags;
+
+    #[test]
+    pub fn test_sni_route() {
+        let router = SniRouter::new(Some("proxy.example.com".to_string()));
+        let tenant = |hostname: &str| {
+            router
+                .resolve(hostname)
+                .map(|tenant| (tenant.cluster_name, tenant.namespace))
+        };
+        let expected = Some(("orders-db".to_string(), "shop".to_string()));
+        assert_eq!(tenant("orders-db-shop.proxy.example.com"), expected);
+        assert_eq!(tenant("Orders-DB-Shop.Proxy.Example.com."), expected);
+        assert_eq!(tenant("orders.proxy.example.com"), None);
+        assert_eq!(tenant("a.orders-shop.proxy.example.com"), None);
+        assert_eq!(tenant("orders-shop.example.com"), None);
+        assert_eq!(tenant("proxy.example.com"), None);
+
+        let route = SniRoute {
+            hostname: "Orders.proxy.example.com".to_string(),
+            tenant: TenantKey {
+                namespace: "shop-eu".to_string(),
+                cluster_name: "orders".to_string(),
+                ..Default::default()
+            },
+        };
+        router.set_route(route.clone()).unwrap();
+        assert_eq!(
+            tenant("orders.proxy.example.com"),
+            Some(("orders".to_string(), "shop-eu".to_string()))
+        );
+        assert_eq!(router.list().len(), 1);
+        assert!(router.remove_route("orders.proxy.example.com").is_some());
+        assert!(router.remove_route("orders.proxy.example.com").is_none());
+
+        let mut handshake = HandshakeResponse {
+            client_flag: CapabilityFlags::empty(),
+            max_packet_len: 0,
+            collation: 0,
+            tenant_key: None,
+            username: Some(b"app".to_vec()),
+            auth_response: vec![],
+            auth_plugin: vec![],
+            database: None,
+            connect_attributes: None,
+            shard: None,
+            identity: None,
+            reconnect: None,
+            reconnect_token: None,
+            server_name: None,
+        };
+        router.route(&mut handshake).unwrap();
+        assert_eq!(handshake.tenant_key, None);
+        handshake.server_name = Some("db.internal".to_string());
+        router.route(&mut handshake).unwrap();
+        assert_eq!(handshake.tenant_key, None);
+        handshake.server_name = Some("orders-shop.proxy.example.com".to_string());
+        router.route(&mut handshake).unwrap();
+        assert_eq!(handshake_tenant_key(&handshake).cluster_name, "orders");
+        handshake.server_name = Some("orders.proxy.example.com".to_string());
+        assert!(router.route(&mut handshake).is_err());
+    }
+}
@@ -0,0 +1,181 @@
+use crate::prost::common_proto::TenantKey;
+use crate::protocol::mysql::fingerprint::strip_literals;
+use crate::server::slow_log::tenant_label;
+
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use std::str::FromStr;
+use std::sync::OnceLock;
+use tracing::info;
+
+/// How the SQL text of a tenant leaves the proxy, e.g. in the slow log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SqlExport {
+    /// The statement as the client sent it, literals included.
+    Raw,
+    /// The statement with its literals replaced by `?` and its comments removed.
+    #[default]
+    Normalized,
+    /// No SQL text at all.
+    Off,
+}
+
+impl FromStr for SqlExport {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "raw" => Ok(SqlExport::Raw),
+            "normalized" => Ok(SqlExport::Normalized),
+            "off" => Ok(SqlExport::Off),
+            _ => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("unknown sql export {s:?}, expected raw, normalized or off"),
+            )),
+        }
+    }
+}
+
+impl fmt::Display for SqlExport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SqlExport::Raw => write!(f, "raw"),
+            SqlExport::Normalized => write!(f, "normalized"),
+            SqlExport::Off => write!(f, "off"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TenantSqlPrivacy {
+    pub tenant: TenantKey,
+    pub export: SqlExport,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SqlPrivacySettings {
+    /// Applies to the tenants without a setting of their own.
+    pub default: SqlExport,
+    pub tenants: Vec<TenantSqlPrivacy>,
+}
+
+/// `SqlPrivacy` decides how the SQL text of each tenant is exported. Every export of SQL text
+/// goes through [`SqlPrivacy::export`], so a tenant setting applies to all of them alike.
+pub struct SqlPrivacy {
+    default: SqlExport,
+    tenants: DashMap<TenantKey, SqlExport>,
+}
+
+static SQL_PRIVACY_ONCE: OnceLock<SqlPrivacy> = OnceLock::new();
+
+/// Initializes the global SQL privacy settings, must be called before the first query is served.
+pub fn init_sql_privacy(default: SqlExport) -> &'static SqlPrivacy {
+    SQL_PRIVACY_ONCE.get_or_init(|| SqlPrivacy::new(default))
+}
+
+pub fn sql_privacy() -> &'static SqlPrivacy {
+    SQL_PRIVACY_ONCE.get_or_init(|| SqlPrivacy::new(SqlExport::default()))
+}
+
+impl SqlPrivacy {
+    pub fn new(default: SqlExport) -> Self {
+        Self {
+            default,
+            tenants: DashMap::new(),
+        }
+    }
+
+    pub fn set(&self, setting: TenantSqlPrivacy) {
+        info!(
+            "ProxySrv sql export of {} set to {}",
+            tenant_label(&setting.tenant),
+            setting.export
+        );
+        self.tenants.insert(setting.tenant, setting.export);
+    }
+
+    /// Removes the setting of `tenant`, it falls back to the default.
+    pub fn remove(&self, tenant: &TenantKey) -> Option<SqlExport> {
+        self.tenants.remove(tenant).map(|(_, export)| export)
+    }
+
+    pub fn settings(&self) -> SqlPrivacySettings {
+        let mut tenants = self
+            .tenants
+            .iter()
+            .map(|e| TenantSqlPrivacy {
+                tenant: e.key().clone(),
+                export: *e.value(),
+            })
+            .collect::<Vec<_>>();
+        tenants.sort_by_key(|setting| tenant_label(&setting.tenant));
+        SqlPrivacySettings {
+            default: self.default,
+            tenants,
+        }
+    }
+
+    pub fn export_of(&self, tenant: &TenantKey) -> SqlExport {
+        self.tenants
+            .get(tenant)
+            .map_or(self.default, |e| *e.value())
+    }
+
+    /// The text of `sql` the tenant allows to export, `None` if it allows none.
+    pub fn export(&self, tenant: &TenantKey, sql: &[u8]) -> Option<String> {
+        match self.export_of(tenant) {
+            SqlExport::Raw => Some(String::from_utf8_lossy(sql).into_owned()),
+            SqlExport::Normalized => Some(strip_literals(&String::from_utf8_lossy(sql))),
+            SqlExport::Off => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::test_tenant_key;
+    use crate::protocol::mysql::fingerprint::strip_literals;
+    use crate::server::sql_privacy::{SqlExport, SqlPrivacy, TenantSqlPrivacy};
+
+    #[test]
+    pub fn test_sql_privacy() {
+        assert_eq!(
+            strip_literals("SELECT * FROM t1 WHERE name = 'o''brien' AND id IN (1, 0x1f, 2.5)"),
+            "SELECT * FROM t1 WHERE name = ? AND id IN (?, ?, ?)"
+        );
+        assert_eq!(
+            strip_literals("/* user=alice */ update `t 2`\n  set c = \"a\\\"b\" -- ssn 123\n"),
+            "update `t 2` set c = ?"
+        );
+        assert_eq!(
+            strip_literals("select * from t where k = 'cut sho"),
+            "select * from t where k = ?"
+        );
+
+        let privacy = SqlPrivacy::new(SqlExport::Normalized);
+        let tenant = test_tenant_key();
+        let sql = b"select c from sbtest1 where id = 42";
+        assert_eq!(
+            privacy.export(&tenant, sql).as_deref(),
+            Some("select c from sbtest1 where id = ?")
+        );
+        for (export, exported) in [
+            (SqlExport::Raw, Some("select c from sbtest1 where id = 42")),
+            (SqlExport::Off, None),
+        ] {
+            privacy.set(TenantSqlPrivacy {
+                tenant: tenant.clone(),
+                export,
+            });
+            assert_eq!(privacy.export(&tenant, sql).as_deref(), exported);
+        }
+        assert_eq!(privacy.settings().tenants.len(), 1);
+        assert_eq!(privacy.remove(&tenant), Some(SqlExport::Off));
+        assert_eq!(privacy.export_of(&tenant), SqlExport::Normalized);
+        assert_eq!("RAW".parse::<SqlExport>().unwrap(), SqlExport::Raw);
+        assert!("hashed".parse::<SqlExport>().is_err());
+    }
+}
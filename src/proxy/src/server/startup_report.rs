@@ -0,0 +1,144 @@
+use crate::backend::BackendInstance;
+
+use chrono::{Local, SecondsFormat};
+use itertools::Itertools;
+use serde::Serialize;
+use std::fmt::{Display, Formatter};
+use std::sync::OnceLock;
+use tracing::{debug, info};
+
+/// A listener of the proxy, `name` as in [`HandshakeProfile::listener`].
+///
+/// [`HandshakeProfile::listener`]: crate::server::handshake_profile::HandshakeProfile
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ListenerReport {
+    pub name: &'static str,
+    pub addr: String,
+}
+
+/// The configuration the proxy came up in, published once after its pools are initialized so
+/// deploy pipelines can assert on it through `/startup-report` instead of parsing the logs.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct StartupReport {
+    /// RFC 3339 time the report was published.
+    pub time: String,
+    pub router: String,
+    pub balancer: String,
+    pub workers: usize,
+    pub listeners: Vec<ListenerReport>,
+    /// Whether TLS is configured, and whether the binary is built with the `tls` feature.
+    pub tls_enabled: bool,
+    pub tls_supported: bool,
+    pub metrics_endpoint: Option<String>,
+    pub rest_endpoint: Option<String>,
+    /// Tenants and backends known to the router when the report was published.
+    pub tenants: usize,
+    pub backends: usize,
+    /// Backend pools open when the report was published.
+    pub pools: usize,
+}
+
+static STARTUP_REPORT_ONCE: OnceLock<StartupReport> = OnceLock::new();
+
+/// The published report, `None` while the proxy is starting.
+pub fn startup_report() -> Option<&'static StartupReport> {
+    STARTUP_REPORT_ONCE.get()
+}
+
+/// Logs `report` as a single line and keeps it for `/startup-report`. Only the first report is
+/// published, later ones are dropped.
+pub fn publish_startup_report(mut report: StartupReport) {
+    report.time = Local::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+    let line = report.to_string();
+    match STARTUP_REPORT_ONCE.set(report) {
+        Ok(()) => info!("ProxySrv startup report {line}"),
+        Err(_) => debug!("ProxySrv startup report already published, dropped {line}"),
+    }
+}
+
+impl StartupReport {
+    /// The report with the tenants and backends of `backends` and `pools` open pools.
+    pub fn with_backends(self, backends: &[BackendInstance], pools: usize) -> Self {
+        Self {
+            tenants: backends
+                .iter()
+                .map(|backend| &backend.cluster)
+                .unique()
+                .count(),
+            backends: backends.len(),
+            pools,
+            ..self
+        }
+    }
+}
+
+impl Display for StartupReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let listeners = self
+            .listeners
+            .iter()
+            .map(|listener| format!("{}@{}", listener.name, listener.addr))
+            .join(",");
+        let endpoint = |endpoint: &Option<String>| endpoint.as_deref().unwrap_or("-").to_string();
+        write!(
+            f,
+            "router={} balancer={} workers={} listeners={listeners} tls_enabled={} \
+             tls_supported={} metrics={} rest={} tenants={} backends={} pools={}",
+            self.router,
+            self.balancer,
+            self.workers,
+            self.tls_enabled,
+            self.tls_supported,
+            endpoint(&self.metrics_endpoint),
+            endpoint(&self.rest_endpoint),
+            self.tenants,
+            self.backends,
+            self.pools,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::BackendInstance;
+    use crate::prost::common_proto::ClusterName;
+    use crate::server::startup_report::{ListenerReport, StartupReport};
+
+    #[test]
+    pub fn test_startup_report() {
+        let backend = |addr: &str, cluster_name: &str| BackendInstance {
+            addr: addr.to_string(),
+            cluster: ClusterName {
+                namespace: "startup".to_string(),
+                cluster_name: cluster_name.to_string(),
+            },
+            ..Default::default()
+        };
+        let report = StartupReport {
+            router: "static".to_string(),
+            balancer: "random".to_string(),
+            workers: 4,
+            listeners: vec![ListenerReport {
+                name: "proxy",
+                addr: "0.0.0.0:3310".to_string(),
+            }],
+            rest_endpoint: Some("0.0.0.0:9000".to_string()),
+            ..Default::default()
+        }
+        .with_backends(
+            &[
+                backend("127.0.0.1:3306", "a"),
+                backend("127.0.0.1:3307", "a"),
+                backend("127.0.0.1:3308", "b"),
+            ],
+            2,
+        );
+        assert_eq!((report.tenants, report.backends, report.pools), (2, 3, 2));
+        assert_eq!(
+            report.to_string(),
+            "router=static balancer=random workers=4 listeners=proxy@0.0.0.0:3310 \
+             tls_enabled=false tls_supported=false metrics=- rest=0.0.0.0:9000 tenants=2 \
+             backends=3 pools=2"
+        );
+    }
+}
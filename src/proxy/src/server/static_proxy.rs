@@ -1,23 +1,28 @@
+use crate::protocol::mysql::error_codes::ErrorKind;
 use crate::protocol::mysql::error_codes::ErrorKind::ER_ACCESS_DENIED_NO_PASSWORD_ERROR;
 use crate::protocol::mysql::packet::packet_reader::PacketReader;
 use crate::protocol::mysql::packet::packet_writer::PacketWriter;
 use crate::protocol::mysql::packet::writers;
 use crate::protocol::mysql::packet::writers::write_ok_packet_with_client_flags;
-use crate::server::cmd_handler::CmdHandler;
+use crate::server::client_tls::{ClientTlsOptions, TlsHandshaker};
+use crate::server::cmd_handler::{cmd_error, CmdError, CmdHandler};
 
-use crate::server::auth::default_salt;
+use crate::server::auth::gen_user_salt;
+use common::ShutdownMessage;
 use mysql_common::constants::CapabilityFlags;
 use rustls::ServerConfig;
-use std::borrow::BorrowMut;
 use std::io::Write;
 use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 
 use crate::protocol::mysql::basic::{
     client_handshake_response, from_packet, Command, HandshakeResponse, OkPacket,
 };
 use crate::protocol::mysql::constants::AuthPluginName::AuthNativePassword;
+use crate::server::handshake_profile::HandshakeProfile;
 use crate::server::{default_capabilities, DEFAULT_BACKEND_VERSION};
 use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::watch;
 use tokio_rustls::rustls;
 use tracing::{error, info, warn};
 use winnow::error::ErrMode;
@@ -62,79 +67,215 @@ macro_rules! client_handshake_err {
             .1
     }};
 }
-/// The [`StaticProxyServer`] is a demo to show how to implement a backend service compatible
-/// with the MySQL protocol. Its purpose is to forward commands from the client to the proxy's backend.
-pub struct StaticProxyServer;
+/// The [`StaticProxyServer`] is an embeddable MySQL server framework: it speaks the connection
+/// phase and the command phase of the MySQL protocol and delegates every command to a
+/// [`CmdHandler`]. See `examples/in_memory_table.rs` for a small server built on top of it.
+pub struct StaticProxyServer {
+    next_conn_id: AtomicU64,
+    tls: Option<TlsHandshaker>,
+}
+
+impl Default for StaticProxyServer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+type HandshakeState<R> = (HandshakeResponse, u8, CapabilityFlags, PacketReader<R>);
 
 impl StaticProxyServer {
+    pub fn new() -> Self {
+        Self {
+            next_conn_id: AtomicU64::new(1),
+            tls: None,
+        }
+    }
+
+    /// Advertise `CLIENT_SSL` and upgrade connections served by [`Self::run_tls`].
+    pub fn with_tls(tls_conf: Arc<ServerConfig>) -> Self {
+        Self {
+            next_conn_id: AtomicU64::new(1),
+            tls: Some(TlsHandshaker::new(tls_conf)),
+        }
+    }
+
+    /// Like [`Self::with_tls`], with session resumption, cipher suites, protocol versions and
+    /// handshake threads taken from `options`.
+    pub fn with_tls_options(options: &ClientTlsOptions) -> Result<Self, std::io::Error> {
+        Ok(Self {
+            next_conn_id: AtomicU64::new(1),
+            tls: Some(TlsHandshaker::with_options(options)?),
+        })
+    }
+
+    fn next_conn_id(&self) -> u64 {
+        self.next_conn_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Serves every connection received on `rx` on a task of its own, until `exit_send` changes.
     pub async fn on_recv<R, W, C>(
-        &self,
+        self: Arc<Self>,
         mut rx: tokio::sync::mpsc::Receiver<(R, W, C)>,
-        mut exit_send: tokio::sync::watch::Receiver<()>,
+        mut exit_send: watch::Receiver<ShutdownMessage>,
     ) -> Result<(), std::io::Error>
     where
-        R: AsyncRead + Send + Unpin,
-        W: AsyncWrite + Send + Unpin,
-        C: CmdHandler + Unpin,
+        R: AsyncRead + Send + Unpin + 'static,
+        W: AsyncWrite + Send + Unpin + 'static,
+        C: CmdHandler + Unpin + 'static,
     {
-        let conn_id = AtomicU64::new(0);
         loop {
             tokio::select! {
                 _ = exit_send.changed() => {
                     return Ok(());
                 }
-                Some((inbound, mut outbound, mut cmd_handler)) = rx.recv() => {
+                Some((inbound, outbound, cmd_handler)) = rx.recv() => {
                     info!("StaticProxy recv inbound, outbound");
-                    let new_id = conn_id.fetch_add(1, Ordering::Relaxed);
-                    let (_, (handshake, seq, client_flags, pkt_reader)) = Self::initial_handshake(new_id, inbound,&mut outbound,#[cfg(feature = "tls")]&None,).await?;
-                    let mut reader = PacketReader::new(pkt_reader);
-                    let mut writer = PacketWriter::new(outbound);
-
-                    let borrow_reader = reader.borrow_mut();
-                    let borrow_writer = writer.borrow_mut();
-                    Self::respond_client_handshake_rsp(borrow_reader,borrow_writer,&mut cmd_handler,handshake,seq,).await?;
-                    Self::on_cmd(borrow_reader, borrow_writer, client_flags, &mut cmd_handler).await?;
+                    let shutdown_rx = exit_send.clone();
+                    let server = Arc::clone(&self);
+                    tokio::spawn(async move {
+                        let rs = server
+                            .run_with_shutdown(inbound, outbound, cmd_handler, Some(shutdown_rx))
+                            .await;
+                        if let Err(e) = rs {
+                            warn!("StaticProxy connection closed with error {e:?}");
+                        }
+                    });
                 }
             }
         }
     }
 
     pub async fn run<R, W>(
+        &self,
+        inbound: R,
+        outbound: W,
+        cmd_handler: impl CmdHandler,
+    ) -> Result<(), std::io::Error>
+    where
+        R: AsyncRead + Send + Unpin,
+        W: AsyncWrite + Send + Unpin,
+    {
+        self.run_with_shutdown(inbound, outbound, cmd_handler, None)
+            .await
+    }
+
+    /// Serves one plaintext connection until the client quits or `shutdown_rx` changes.
+    pub async fn run_with_shutdown<R, W>(
+        &self,
         inbound: R,
         mut outbound: W,
         mut cmd_handler: impl CmdHandler,
+        shutdown_rx: Option<watch::Receiver<ShutdownMessage>>,
     ) -> Result<(), std::io::Error>
     where
         R: AsyncRead + Send + Unpin,
         W: AsyncWrite + Send + Unpin,
     {
-        // TODO: conn_id should from CmdHandler
-        let (_, (handshake, seq, client_flags, pkt_reader)) = Self::initial_handshake(
-            0,
-            inbound,
-            &mut outbound,
-            #[cfg(feature = "tls")]
-            &None,
-        )
-        .await?;
-
-        let mut reader = PacketReader::new(pkt_reader);
+        let conn_id = self.next_conn_id();
+        let salt = gen_user_salt();
+        // TLS can't be negotiated on split plaintext halves, see `run_tls`.
+        let (is_tls, handshake_state) =
+            Self::initial_handshake(conn_id, salt, inbound, &mut outbound, &None).await?;
+        if is_tls {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "client requested SSL despite us not advertising support for it",
+            ));
+        }
+        let (handshake, seq, client_flags, mut reader) = handshake_state;
+        cmd_handler.on_connect(conn_id, client_flags);
         let mut writer = PacketWriter::new(outbound);
-
-        let borrow_reader = reader.borrow_mut();
-        let borrow_writer = writer.borrow_mut();
-
         Self::respond_client_handshake_rsp(
-            borrow_reader,
-            borrow_writer,
+            &mut reader,
+            &mut writer,
             &mut cmd_handler,
             handshake,
             seq,
+            salt,
         )
         .await?;
+        let rs = Self::on_cmd(
+            &mut reader,
+            &mut writer,
+            client_flags,
+            &mut cmd_handler,
+            shutdown_rx,
+        )
+        .await;
+        cmd_handler.on_quit().await;
+        rs
+    }
 
-        Self::on_cmd(borrow_reader, borrow_writer, client_flags, &mut cmd_handler).await?;
-        Ok(())
+    /// Serves one connection on a full-duplex stream, upgrading it to TLS if the client asks.
+    pub async fn run_tls<S>(
+        &self,
+        stream: S,
+        mut cmd_handler: impl CmdHandler,
+        shutdown_rx: Option<watch::Receiver<ShutdownMessage>>,
+    ) -> Result<(), std::io::Error>
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        let conn_id = self.next_conn_id();
+        let salt = gen_user_salt();
+        let tls_conf = self.tls.as_ref().map(TlsHandshaker::server_config);
+        let (read_half, mut write_half) = tokio::io::split(stream);
+        let (is_tls, (handshake, seq, client_flags, reader)) =
+            Self::initial_handshake(conn_id, salt, read_half, &mut write_half, &tls_conf).await?;
+        cmd_handler.on_connect(conn_id, client_flags);
+        let rs = match (is_tls, self.tls.as_ref()) {
+            (true, Some(tls)) => {
+                let stream = reader.r.unsplit(write_half);
+                let tls_stream = tls.accept(stream).await?;
+                let (tls_read, tls_write) = tokio::io::split(tls_stream);
+                let mut reader = PacketReader::new(tls_read);
+                let mut writer = PacketWriter::new(tls_write);
+                Self::respond_client_handshake_rsp(
+                    &mut reader,
+                    &mut writer,
+                    &mut cmd_handler,
+                    handshake,
+                    seq,
+                    salt,
+                )
+                .await?;
+                Self::on_cmd(
+                    &mut reader,
+                    &mut writer,
+                    client_flags,
+                    &mut cmd_handler,
+                    shutdown_rx,
+                )
+                .await
+            }
+            (true, None) => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "client requested SSL despite us not advertising support for it",
+            )),
+            (false, _) => {
+                let mut reader = reader;
+                let mut writer = PacketWriter::new(write_half);
+                Self::respond_client_handshake_rsp(
+                    &mut reader,
+                    &mut writer,
+                    &mut cmd_handler,
+                    handshake,
+                    seq,
+                    salt,
+                )
+                .await?;
+                Self::on_cmd(
+                    &mut reader,
+                    &mut writer,
+                    client_flags,
+                    &mut cmd_handler,
+                    shutdown_rx,
+                )
+                .await
+            }
+        };
+        cmd_handler.on_quit().await;
+        rs
     }
 
     async fn on_cmd<R, W>(
@@ -142,72 +283,92 @@ impl StaticProxyServer {
         writer: &mut PacketWriter<W>,
         client_flags: CapabilityFlags,
         cmd_handler: &mut impl CmdHandler,
+        mut shutdown_rx: Option<watch::Receiver<ShutdownMessage>>,
     ) -> Result<(), std::io::Error>
     where
         R: AsyncRead + Send + Unpin,
         W: AsyncWrite + Send + Unpin,
     {
-        while let Some((seq, packet)) = reader.next_async().await? {
-            writer.set_seq(seq + 1);
-            let cmd_pkt_rs = from_packet(&packet);
-            match cmd_pkt_rs {
+        loop {
+            let next_pkt = match shutdown_rx.as_mut() {
+                Some(rx) => tokio::select! {
+                    rs = reader.next_async() => rs?,
+                    _ = rx.changed() => {
+                        info!("StaticProxy shutdown, close the client connection.");
+                        return Ok(());
+                    }
+                },
+                None => reader.next_async().await?,
+            };
+            let Some((seq, packet)) = next_pkt else {
+                return Ok(());
+            };
+            writer.set_seq(seq.wrapping_add(1));
+            let cmd_rs = match from_packet(&packet) {
                 Ok((_, cmd)) => match cmd {
-                    Command::Query(q) => cmd_handler.on_query(q, writer).await?,
-                    Command::Prepare(prepare) => cmd_handler.on_prepare(prepare, writer).await?,
+                    Command::Query(q) => cmd_handler.on_query(q, writer).await,
+                    Command::Prepare(prepare) => cmd_handler.on_prepare(prepare, writer).await,
                     Command::Execute { stmt, params } => {
-                        cmd_handler.on_execute(params, writer).await?
+                        cmd_handler.on_execute(stmt, params, writer).await
                     }
-                    Command::SendLongData { stmt, param, data } => {}
-                    Command::Close(stmt) => {
-                        write_ok_packet_with_client_flags(
-                            writer,
-                            client_flags,
-                            OkPacket::default(),
-                        )
-                        .await?;
+                    Command::SendLongData { stmt, param, data } => {
+                        cmd_handler.on_send_long_data(stmt, param, data).await
                     }
+                    Command::Close(stmt) => cmd_handler.on_close(stmt).await,
                     Command::ListFields(_) => {
                         let ok_packet = OkPacket {
                             header: 0xfe,
                             ..Default::default()
                         };
-                        write_ok_packet_with_client_flags(writer, client_flags, ok_packet).await?;
-                    }
-                    Command::Init(schema) => {
-                        cmd_handler.on_init(schema, writer).await?;
+                        write_ok_packet_with_client_flags(writer, client_flags, ok_packet).await
                     }
+                    Command::Init(schema) => cmd_handler.on_init(schema, writer).await,
                     Command::Ping => {
-                        write_ok_packet_with_client_flags(
-                            writer,
-                            client_flags,
-                            OkPacket::default(),
-                        )
-                        .await?;
+                        write_ok_packet_with_client_flags(writer, client_flags, OkPacket::default())
+                            .await
                     }
                     Command::Quit => {
-                        break;
+                        return Ok(());
                     }
                 },
                 Err(e) => {
-                    write_ok_packet_with_client_flags(writer, client_flags, OkPacket::default())
-                        .await?
+                    warn!("StaticProxy unsupported command {:?} {e:?}", packet.first());
+                    Err(cmd_error(
+                        ErrorKind::ER_UNKNOWN_COM_ERROR,
+                        "Unknown command",
+                    ))
+                }
+            };
+            if let Err(e) = cmd_rs {
+                let cmd_err = e
+                    .get_ref()
+                    .and_then(|inner| inner.downcast_ref::<CmdError>())
+                    .cloned();
+                match cmd_err {
+                    Some(cmd_err) => {
+                        writer.set_seq(seq.wrapping_add(1));
+                        writers::write_err_packet(
+                            cmd_err.kind,
+                            cmd_err.message.as_bytes(),
+                            writer,
+                            client_flags,
+                        )
+                        .await?;
+                    }
+                    None => return Err(e),
                 }
             }
+            writer.flush_all().await?;
         }
-        Ok(())
     }
+
     async fn initial_handshake<R, W>(
         conn_id: u64,
+        salt: [u8; 20],
         reader: R,
         writer: &mut W,
-        #[cfg(feature = "tls")] tls_conf: &Option<std::sync::Arc<ServerConfig>>,
-    ) -> Result<
-        (
-            bool,
-            (HandshakeResponse, u8, CapabilityFlags, PacketReader<R>),
-        ),
-        std::io::Error,
-    >
+        tls_conf: &Option<Arc<ServerConfig>>,
+    ) -> Result<(bool, HandshakeState<R>), std::io::Error>
     where
         R: AsyncRead + Send + Unpin,
         W: AsyncWrite + Send + Unpin,
@@ -215,14 +376,16 @@ impl StaticProxyServer {
         let mut pkt_reader = PacketReader::new(reader);
         let mut pkt_writer = PacketWriter::new(writer);
 
-        let salt = default_salt();
-        let server_version = DEFAULT_BACKEND_VERSION;
+        let profile = HandshakeProfile {
+            server_version: Some(DEFAULT_BACKEND_VERSION.to_vec()),
+            ..Default::default()
+        };
         #[cfg(feature = "tls")]
-        writers::write_initial_handshake(&mut pkt_writer, conn_id, salt, server_version, tls_conf)
+        writers::write_initial_handshake(&mut pkt_writer, conn_id, salt, &profile, tls_conf)
             .await?;
 
         #[cfg(not(feature = "tls"))]
-        writers::write_initial_handshake(&mut pkt_writer, conn_id, salt, server_version).await?;
+        writers::write_initial_handshake(&mut pkt_writer, conn_id, salt, &profile).await?;
         let (seq, handshake_pkt) = pkt_reader.next_async().await?.ok_or_else(|| {
             std::io::Error::new(
                 std::io::ErrorKind::ConnectionAborted,
@@ -233,24 +396,15 @@ impl StaticProxyServer {
         let handshake_rs = client_handshake_response(&handshake_pkt, false);
         let client_handshake_rsp = client_handshake_err!(handshake_rs);
         pkt_writer.set_seq(seq + 1);
-        #[cfg(not(feature = "tls"))]
-        if client_handshake_rsp
-            .client_flag
-            .contains(CapabilityFlags::CLIENT_SSL)
-        {
-            error!("MySQLStaticProxy init_handshake Error.backend requested SSL despite us not advertising support for it. {client_handshake_rsp:?}");
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                "backend requested SSL despite us not advertising support for it",
-            )
-            .into());
-        }
-        let server_capabilities = default_capabilities();
-        #[cfg(feature = "tls")]
+        // the capabilities both sides agree on.
+        let server_capabilities = default_capabilities() & client_handshake_rsp.client_flag;
         if client_handshake_rsp
             .client_flag
             .contains(CapabilityFlags::CLIENT_SSL)
         {
+            if tls_conf.is_none() {
+                error!("MySQLStaticProxy init_handshake Error. client requested SSL despite us not advertising support for it. {client_handshake_rsp:?}");
+            }
             info!("MySQLStaticProxy init_handshake success. TSL=true");
             return Ok((
                 true,
@@ -268,15 +422,14 @@ impl StaticProxyServer {
         reader: &mut PacketReader<R>,
         writer: &mut PacketWriter<W>,
         cmd_handler: &mut impl CmdHandler,
-        #[cfg(feature = "tls")] mut client_handshake: HandshakeResponse,
-        #[cfg(not(feature = "tls"))] client_handshake: HandshakeResponse,
+        mut client_handshake: HandshakeResponse,
         mut seq: u8,
+        salt: [u8; 20],
     ) -> Result<(), std::io::Error>
     where
         R: AsyncRead + Send + Unpin,
         W: AsyncWrite + Send + Unpin,
     {
-        #[cfg(feature = "tls")]
         if client_handshake
             .client_flag
             .contains(CapabilityFlags::CLIENT_SSL)
@@ -288,7 +441,6 @@ impl StaticProxyServer {
             writer.set_seq(seq + 1);
         }
 
-        let salt = default_salt(); //conn_stat.get_slat();
         {
             if !client_handshake
                 .client_flag
@@ -351,6 +503,7 @@ impl StaticProxyServer {
                         ER_ACCESS_DENIED_NO_PASSWORD_ERROR,
                         auth_failed_err.as_bytes(),
                         writer,
+                        client_handshake.client_flag,
                     )
                     .await?;
                     writer.flush_all().await?;
@@ -384,3 +537,254 @@ impl StaticProxyServer {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::protocol::mysql::error_codes::ErrorKind;
+    use crate::protocol::mysql::packet::packet_reader::PacketReader;
+    use crate::protocol::mysql::packet::packet_writer::PacketWriter;
+    use crate::protocol::mysql::packet::{writers, Packet};
+    use crate::server::cmd_handler::{cmd_error, CmdHandler};
+    use crate::server::static_proxy::StaticProxyServer;
+
+    use byteorder::{ByteOrder, LittleEndian};
+    use common::ShutdownMessage;
+    use mysql_common::constants::{CapabilityFlags, StatusFlags};
+    use mysql_common::packets::AuthPlugin;
+    use mysql_common::proto::MySerialize;
+    use std::io::Write;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use tokio::io::{AsyncWrite, DuplexStream, ReadHalf, WriteHalf};
+    use tokio::sync::watch;
+
+    struct TestHandler {
+        quit: Arc<AtomicBool>,
+    }
+
+    #[async_trait::async_trait]
+    impl CmdHandler for TestHandler {
+        async fn auth(
+            &mut self,
+            _auth_plugin: &str,
+            user: &[u8],
+            _salt: &[u8],
+            _auth_data: &[u8],
+        ) -> Result<bool, std::io::Error> {
+            Ok(user == b"root")
+        }
+
+        async fn on_init<'a, W>(
+            &mut self,
+            _database: &[u8],
+            pkt_writer: &mut PacketWriter<W>,
+        ) -> Result<(), std::io::Error>
+        where
+            W: AsyncWrite + Send + Unpin,
+        {
+            writers::write_ok_packet(pkt_writer, 0, 0, StatusFlags::empty()).await
+        }
+
+        async fn on_prepare<'a, W>(
+            &mut self,
+            _packet: &[u8],
+            _pkt_writer: &mut PacketWriter<W>,
+        ) -> Result<(), std::io::Error>
+        where
+            W: AsyncWrite + Send + Unpin,
+        {
+            Err(cmd_error(ErrorKind::ER_NOT_SUPPORTED_YET, "no prepare"))
+        }
+
+        async fn on_query<'a, W>(
+            &mut self,
+            packet: &[u8],
+            pkt_writer: &mut PacketWriter<W>,
+        ) -> Result<(), std::io::Error>
+        where
+            W: AsyncWrite + Send + Unpin,
+        {
+            match packet {
+                b"ok" => writers::write_ok_packet(pkt_writer, 1, 0, StatusFlags::empty()).await,
+                b"fail" => Err(cmd_error(ErrorKind::ER_PARSE_ERROR, "bad query")),
+                _ => Err(std::io::Error::other("storage failure")),
+            }
+        }
+
+        async fn on_execute<'a, W>(
+            &mut self,
+            _stmt_id: u32,
+            _params: &[u8],
+            _pkt_writer: &mut PacketWriter<W>,
+        ) -> Result<(), std::io::Error>
+        where
+            W: AsyncWrite + Send + Unpin,
+        {
+            Err(cmd_error(ErrorKind::ER_UNKNOWN_STMT_HANDLER, "no execute"))
+        }
+
+        async fn on_quit(&mut self) {
+            self.quit.store(true, Ordering::Relaxed);
+        }
+    }
+
+    struct TestClient {
+        reader: PacketReader<ReadHalf<DuplexStream>>,
+        writer: PacketWriter<WriteHalf<DuplexStream>>,
+    }
+
+    impl TestClient {
+        /// Answers the initial handshake of the server as `user`, returns the server response.
+        async fn connect(stream: DuplexStream, user: &str) -> (Self, Packet) {
+            let (read, write) = tokio::io::split(stream);
+            let mut client = Self {
+                reader: PacketReader::new(read),
+                writer: PacketWriter::new(write),
+            };
+            let (seq, _) = client.reader.next_async().await.unwrap().unwrap();
+            let mut response = vec![];
+            mysql_common::packets::HandshakeResponse::new(
+                Some(&[1_u8; 20][..]),
+                (8, 0, 36),
+                Some(user.as_bytes()),
+                None::<&[u8]>,
+                Some(AuthPlugin::MysqlNativePassword),
+                CapabilityFlags::CLIENT_PROTOCOL_41
+                    | CapabilityFlags::CLIENT_SECURE_CONNECTION
+                    | CapabilityFlags::CLIENT_PLUGIN_AUTH,
+                None,
+                16 * 1024 * 1024,
+            )
+            .serialize(&mut response);
+            client.writer.set_seq(seq.wrapping_add(1));
+            client.send(&response).await;
+            let response = client.recv().await;
+            (client, response)
+        }
+
+        async fn send(&mut self, payload: &[u8]) {
+            self.writer.write_all(payload).unwrap();
+            self.writer.end_packet().await.unwrap();
+            self.writer.flush_all().await.unwrap();
+        }
+
+        async fn recv(&mut self) -> Packet {
+            self.reader.next_async().await.unwrap().unwrap().1
+        }
+
+        async fn command(&mut self, payload: &[u8]) -> Packet {
+            self.writer.reset_seq();
+            self.send(payload).await;
+            self.recv().await
+        }
+    }
+
+    fn err_code(packet: &Packet) -> u16 {
+        assert!(packet.is_err_packet());
+        LittleEndian::read_u16(&packet[1..3])
+    }
+
+    #[tokio::test]
+    pub async fn test_static_proxy_commands() {
+        let server = StaticProxyServer::new();
+        let quit = Arc::new(AtomicBool::new(false));
+        let (server_end, client_end) = tokio::io::duplex(4096);
+        let (read, write) = tokio::io::split(server_end);
+        let handler = TestHandler {
+            quit: Arc::clone(&quit),
+        };
+        let serving = tokio::spawn(async move { server.run(read, write, handler).await });
+
+        let (mut client, response) = TestClient::connect(client_end, "root").await;
+        assert!(response.is_ok_packet());
+        assert!(client.command(b"\x03ok").await.is_ok_packet());
+        // A `CmdError` becomes an ERR packet, the connection stays open.
+        let response = client.command(b"\x03fail").await;
+        assert_eq!(err_code(&response), ErrorKind::ER_PARSE_ERROR as u16);
+        let response = client.command(b"\x16SELECT 1").await;
+        assert_eq!(err_code(&response), ErrorKind::ER_NOT_SUPPORTED_YET as u16);
+        let response = client.command(b"\x7f").await;
+        assert_eq!(err_code(&response), ErrorKind::ER_UNKNOWN_COM_ERROR as u16);
+        assert!(client.command(b"\x0e").await.is_ok_packet());
+
+        // Any other error closes the connection.
+        client.writer.reset_seq();
+        client.send(b"\x03crash").await;
+        let err = serving.await.unwrap().unwrap_err();
+        assert_eq!(err.to_string(), "storage failure");
+        assert!(quit.load(Ordering::Relaxed));
+    }
+
+    #[tokio::test]
+    pub async fn test_static_proxy_auth_failure() {
+        let server = StaticProxyServer::new();
+        let (server_end, client_end) = tokio::io::duplex(4096);
+        let (read, write) = tokio::io::split(server_end);
+        let handler = TestHandler {
+            quit: Arc::new(AtomicBool::new(false)),
+        };
+        let serving = tokio::spawn(async move { server.run(read, write, handler).await });
+
+        let (_client, response) = TestClient::connect(client_end, "guest").await;
+        assert_eq!(
+            err_code(&response),
+            ErrorKind::ER_ACCESS_DENIED_NO_PASSWORD_ERROR as u16
+        );
+        let err = serving.await.unwrap().unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+    }
+
+    #[tokio::test]
+    pub async fn test_static_proxy_run_tls_plaintext() {
+        // Without TLS configured `run_tls` serves the client over the plain stream.
+        let server = StaticProxyServer::new();
+        let (server_end, client_end) = tokio::io::duplex(4096);
+        let handler = TestHandler {
+            quit: Arc::new(AtomicBool::new(false)),
+        };
+        let serving = tokio::spawn(async move { server.run_tls(server_end, handler, None).await });
+
+        let (mut client, response) = TestClient::connect(client_end, "root").await;
+        assert!(response.is_ok_packet());
+        assert!(client.command(b"\x0e").await.is_ok_packet());
+        client.writer.reset_seq();
+        client.send(b"\x01").await;
+        serving.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    pub async fn test_static_proxy_shutdown() {
+        let server = Arc::new(StaticProxyServer::new());
+        let (shutdown_tx, shutdown_rx) = watch::channel(ShutdownMessage::Init);
+        let (conn_tx, conn_rx) = tokio::sync::mpsc::channel(4);
+        let serving = tokio::spawn(server.on_recv(conn_rx, shutdown_rx));
+
+        // The first connection stays open while the second one is served.
+        let mut clients = vec![];
+        let mut quits = vec![];
+        for _ in 0..2 {
+            let (server_end, client_end) = tokio::io::duplex(4096);
+            let (read, write) = tokio::io::split(server_end);
+            let quit = Arc::new(AtomicBool::new(false));
+            let handler = TestHandler {
+                quit: Arc::clone(&quit),
+            };
+            conn_tx.send((read, write, handler)).await.unwrap();
+            let (client, response) = TestClient::connect(client_end, "root").await;
+            assert!(response.is_ok_packet());
+            clients.push(client);
+            quits.push(quit);
+        }
+        assert!(clients[0].command(b"\x03ok").await.is_ok_packet());
+
+        shutdown_tx
+            .send(ShutdownMessage::Cancel("test".to_string()))
+            .unwrap();
+        serving.await.unwrap().unwrap();
+        // The server closes every connection on shutdown.
+        for client in clients.iter_mut() {
+            assert!(client.reader.next_async().await.unwrap().is_none());
+        }
+        assert!(quits.iter().all(|quit| quit.load(Ordering::Relaxed)));
+    }
+}
@@ -0,0 +1,243 @@
+//! Transparent mode, for sidecars intercepting database traffic with iptables `REDIRECT` or an
+//! eBPF redirect. Applications keep connecting to the database address, the connection lands on
+//! the proxy and the tenant is chosen by the address the application dialed, recovered with
+//! `SO_ORIGINAL_DST`, instead of the tenant encoded in the user name.
+
+use crate::backend::encode_tenant_key;
+use crate::prost::common_proto::TenantKey;
+use crate::protocol::mysql::basic::HandshakeResponse;
+use crate::server::slow_log::tenant_label;
+
+use common::metrics::metric_def::PROXY_TRANSPARENT_CONNS;
+use common::metrics::{common_labels, counter_inc};
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{OnceLock, RwLock};
+use tokio::net::TcpStream;
+use tracing::{debug, info, warn};
+
+/// Routes the connections originally sent to `destination` to `tenant`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TransparentRoute {
+    /// Network or address of the original destination, e.g. `10.0.3.0/24` or `10.0.3.17`.
+    pub destination: String,
+    /// Port of the original destination, every port if not set.
+    #[serde(default)]
+    pub port: Option<u16>,
+    pub tenant: TenantKey,
+}
+
+impl TransparentRoute {
+    fn network(&self) -> Result<IpNet, Error> {
+        let destination = self.destination.trim();
+        destination
+            .parse::<IpNet>()
+            .or_else(|_| destination.parse::<IpAddr>().map(IpNet::from))
+            .map_err(|_| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("invalid transparent route destination {destination}"),
+                )
+            })
+    }
+}
+
+/// The original destination of an intercepted connection, `None` if the client connected to the
+/// proxy directly.
+#[cfg(target_os = "linux")]
+pub fn original_dst(stream: &TcpStream) -> Result<Option<SocketAddr>, Error> {
+    let local_addr = stream.local_addr()?;
+    let socket = socket2::SockRef::from(stream);
+    let original_dst = match local_addr {
+        SocketAddr::V4(_) => socket.original_dst()?,
+        SocketAddr::V6(_) => socket.original_dst_ipv6()?,
+    };
+    let original_dst = original_dst.as_socket().ok_or_else(|| {
+        Error::new(
+            ErrorKind::InvalidData,
+            "original destination is not an IP address",
+        )
+    })?;
+    Ok((original_dst != local_addr).then_some(original_dst))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn original_dst(_stream: &TcpStream) -> Result<Option<SocketAddr>, Error> {
+    Err(Error::new(
+        ErrorKind::Unsupported,
+        "transparent mode is only supported on Linux",
+    ))
+}
+
+/// `TransparentRouter` keeps the routes of intercepted connections, managed through the REST
+/// API. The most specific route wins: the longest network, then a route of the port over a route
+/// of every port.
+#[derive(Default)]
+pub struct TransparentRouter {
+    routes: RwLock<Vec<(IpNet, TransparentRoute)>>,
+}
+
+static TRANSPARENT_ROUTER_ONCE: OnceLock<TransparentRouter> = OnceLock::new();
+
+pub fn transparent_router() -> &'static TransparentRouter {
+    TRANSPARENT_ROUTER_ONCE.get_or_init(TransparentRouter::default)
+}
+
+impl TransparentRouter {
+    /// Adds `route`, replacing the route of the same destination and port.
+    pub fn set_route(&self, route: TransparentRoute) -> Result<(), Error> {
+        let network = route.network()?;
+        info!(
+            "ProxySrv transparent route {network} port {:?} to {:?}",
+            route.port, route.tenant
+        );
+        let mut routes = self.routes.write().unwrap();
+        routes.retain(|(net, r)| !(*net == network && r.port == route.port));
+        routes.push((network, route));
+        Ok(())
+    }
+
+    pub fn remove_route(&self, destination: &str, port: Option<u16>) -> Option<TransparentRoute> {
+        let network = TransparentRoute {
+            destination: destination.to_string(),
+            port,
+            tenant: TenantKey::default(),
+        }
+        .network()
+        .ok()?;
+        let mut routes = self.routes.write().unwrap();
+        let index = routes
+            .iter()
+            .position(|(net, r)| *net == network && r.port == port)?;
+        Some(routes.remove(index).1)
+    }
+
+    pub fn list(&self) -> Vec<TransparentRoute> {
+        let routes = self.routes.read().unwrap();
+        routes.iter().map(|(_, route)| route.clone()).collect()
+    }
+
+    /// The tenant of the connections originally sent to `original_dst`.
+    pub fn resolve(&self, original_dst: SocketAddr) -> Option<TenantKey> {
+        let routes = self.routes.read().unwrap();
+        routes
+            .iter()
+            .filter(|(net, route)| {
+                net.contains(&original_dst.ip()) && route.port.is_none()
+                    || route.port == Some(original_dst.port())
+            })
+            .max_by_key(|(net, route)| (net.prefix_len(), route.port.is_some()))
+            .map(|(_, route)| route.tenant.clone())
+    }
+
+    /// Sets the tenant of an intercepted connection from its original destination, it replaces
+    /// any tenant of the user name. Connections to a destination without a route are refused,
+    /// they would otherwise land on whatever tenant the user name selects.
+    pub fn route(
+        &self,
+        original_dst: Option<SocketAddr>,
+        handshake: &mut HandshakeResponse,
+    ) -> Result<(), Error> {
+        let Some(original_dst) = original_dst else {
+            return Ok(());
+        };
+        let tenant = self.resolve(original_dst);
+        let mut labels = common_labels().clone();
+        let result = if tenant.is_some() {
+            "routed"
+        } else {
+            "no_route"
+        };
+        labels.push(("result", result.to_string()));
+        counter_inc(PROXY_TRANSPARENT_CONNS, 1, Some(&labels));
+        let Some(tenant) = tenant else {
+            warn!("ProxySrv no transparent route for {original_dst}");
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                format!("no route for the database at {original_dst}"),
+            ));
+        };
+        debug!(
+            "ProxySrv transparent {original_dst} routed to {}",
+            tenant_label(&tenant)
+        );
+        handshake.tenant_key = Some(encode_tenant_key(&tenant).into_bytes());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::handshake_tenant_key;
+    use crate::prost::common_proto::TenantKey;
+    use crate::protocol::mysql::basic::HandshakeResponse;
+    use crate::server::transparent::{TransparentRoute, TransparentRouter};
+    use mysql_common::constants::CapabilityFlags;
+
+    fn route(destination: &str, port: Option<u16>, cluster_name: &str) -> TransparentRoute {
+        TransparentRoute {
+            destination: destination.to_string(),
+            port,
+            tenant: TenantKey {
+                namespace: "transparent".to_string(),
+                cluster_name: cluster_name.to_string(),
+                ..Default::default()
+            },
+        }
+    }
+
+    #[test]
+    pub fn test_transparent_route() {
+        let router = TransparentRouter::default();
+        assert!(router.set_route(route("db.internal", None, "a")).is_err());
+        router.set_route(route("10.0.0.0/8", None, "a")).unwrap();
+        router.set_route(route("10.0.3.17", None, "b")).unwrap();
+        router
+            .set_route(route("10.0.3.17", Some(3307), "c"))
+            .unwrap();
+        let cluster = |addr: &str| {
+            router
+                .resolve(addr.parse().unwrap())
+                .map(|tenant| tenant.cluster_name)
+        };
+        assert_eq!(cluster("10.1.2.3:3306").as_deref(), Some("a"));
+        assert_eq!(cluster("10.0.3.17:3306").as_deref(), Some("b"));
+        assert_eq!(cluster("10.0.3.17:3307").as_deref(), Some("c"));
+        assert_eq!(cluster("192.168.0.1:3306"), None);
+
+        router.set_route(route("10.0.3.17/32", None, "d")).unwrap();
+        assert_eq!(cluster("10.0.3.17:3306").as_deref(), Some("d"));
+        assert_eq!(router.list().len(), 3);
+        assert!(router.remove_route("10.0.3.17", Some(3307)).is_some());
+        assert!(router.remove_route("10.0.3.17", Some(3307)).is_none());
+        assert_eq!(cluster("10.0.3.17:3307").as_deref(), Some("d"));
+
+        let mut handshake = HandshakeResponse {
+            client_flag: CapabilityFlags::empty(),
+            max_packet_len: 0,
+            collation: 0,
+            tenant_key: None,
+            username: Some(b"app".to_vec()),
+            auth_response: vec![],
+            auth_plugin: vec![],
+            database: None,
+            connect_attributes: None,
+            shard: None,
+            identity: None,
+            reconnect: None,
+            reconnect_token: None,
+            server_name: None,
+        };
+        router.route(None, &mut handshake).unwrap();
+        assert_eq!(handshake.tenant_key, None);
+        router
+            .route(Some("10.1.2.3:3306".parse().unwrap()), &mut handshake)
+            .unwrap();
+        assert_eq!(handshake_tenant_key(&handshake).cluster_name, "a");
+        assert!(router
+            .route(Some("192.168.0.1:3306".parse().unwrap()), &mut handshake)
+            .is_err());
+    }
+}
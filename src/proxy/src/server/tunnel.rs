@@ -0,0 +1,279 @@
+use crate::server::auth::Authenticator;
+use crate::server::haentgl_server::HaentglServer;
+use crate::server::handshake_profile::HandshakeProfile;
+
+use common::ShutdownMessage;
+use futures::{SinkExt, StreamExt};
+use std::io::{Error, ErrorKind};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, Take};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+use tokio_tungstenite::tungstenite::Message;
+use tracing::{debug, info, warn};
+
+const TUNNEL_BUFFER_SIZE: usize = 64 * 1024;
+const MAX_CONNECT_HEAD_SIZE: usize = 8 * 1024;
+const HTTP_CONNECT_ESTABLISHED: &[u8] = b"HTTP/1.1 200 Connection Established\r\n\r\n";
+const HTTP_CONNECT_METHOD: &[u8] = b"CONNECT ";
+const PEEK_RETRY_INTERVAL: Duration = Duration::from_millis(5);
+const PEEK_RETRIES: usize = 200;
+
+fn ws_err(e: tokio_tungstenite::tungstenite::Error) -> Error {
+    Error::new(ErrorKind::Other, e)
+}
+
+/// Reads one line of the request head, which must end within [`MAX_CONNECT_HEAD_SIZE`] bytes.
+async fn read_head_line<R>(head: &mut Take<R>, line: &mut String) -> Result<(), Error>
+where
+    R: AsyncBufRead + Unpin,
+{
+    line.clear();
+    head.read_line(line).await?;
+    if line.ends_with('\n') {
+        Ok(())
+    } else if head.limit() == 0 {
+        Err(Error::new(
+            ErrorKind::InvalidData,
+            "tunnel request head is too large",
+        ))
+    } else {
+        Err(Error::new(
+            ErrorKind::UnexpectedEof,
+            "tunnel request head is incomplete",
+        ))
+    }
+}
+
+/// Reads an `HTTP CONNECT` request head and returns the requested authority. The authority is
+/// informational only, the tenant is still routed by the MySQL handshake.
+async fn read_connect_request<R>(reader: &mut R) -> Result<String, Error>
+where
+    R: AsyncBufRead + Unpin,
+{
+    // Nothing past the head is consumed, the MySQL stream follows it in the buffer.
+    let mut head = reader.take(MAX_CONNECT_HEAD_SIZE as u64);
+    let mut request_line = String::new();
+    read_head_line(&mut head, &mut request_line).await?;
+    let mut parts = request_line.split_whitespace();
+    let authority = match (parts.next(), parts.next()) {
+        (Some(method), Some(authority)) if method.eq_ignore_ascii_case("CONNECT") => {
+            authority.to_string()
+        }
+        _ => {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("unexpected tunnel request {request_line:?}"),
+            ))
+        }
+    };
+    let mut header = String::new();
+    loop {
+        read_head_line(&mut head, &mut header).await?;
+        if header == "\r\n" || header == "\n" {
+            return Ok(authority);
+        }
+    }
+}
+
+/// Whether the client opens with `CONNECT `. The method may arrive split over several segments,
+/// so the stream is peeked again until it tells. A stream that ends within the method still
+/// peeks the partial method, the peek gives up after [`PEEK_RETRIES`] attempts.
+async fn is_http_connect(stream: &TcpStream) -> Result<bool, Error> {
+    let mut method = [0_u8; HTTP_CONNECT_METHOD.len()];
+    for _ in 0..PEEK_RETRIES {
+        let n = stream.peek(&mut method).await?;
+        let is_prefix = method[..n].eq_ignore_ascii_case(&HTTP_CONNECT_METHOD[..n]);
+        if n == method.len() || n == 0 || !is_prefix {
+            return Ok(n == method.len() && is_prefix);
+        }
+        // Peeking returns at once while the partial method is buffered.
+        tokio::time::sleep(PEEK_RETRY_INTERVAL).await;
+    }
+    Ok(false)
+}
+
+/// `TunnelServer` accepts MySQL protocol tunneled over WebSocket binary frames or HTTP CONNECT,
+/// for clients that can only reach the proxy through HTTP(S) egress. Unwrapped streams go through
+/// the same [`HaentglServer::connect`] path as plain TCP clients.
+pub struct TunnelServer<A> {
+    proxy_srv: Arc<HaentglServer<A>>,
+    profile: HandshakeProfile,
+}
+
+impl<A> TunnelServer<A>
+where
+    A: Authenticator + Send + Sync + 'static,
+{
+    pub fn new(proxy_srv: Arc<HaentglServer<A>>, profile: HandshakeProfile) -> Self {
+        Self { proxy_srv, profile }
+    }
+
+    pub async fn start(
+        self: Arc<Self>,
+        listen_addr: String,
+        mut shutdown_rx: watch::Receiver<ShutdownMessage>,
+    ) -> Result<(), Error> {
+        let listener = TcpListener::bind(&listen_addr).await?;
+        info!("ProxySrv tunnel listening on {listen_addr}");
+        loop {
+            tokio::select! {
+                _ = shutdown_rx.changed() => {
+                    info!("ProxySrv tunnel listener on {listen_addr} shutdown");
+                    return Ok(());
+                }
+                rs = listener.accept() => {
+                    match rs {
+                        Ok((stream, addr)) => {
+                            let tunnel_srv = Arc::clone(&self);
+                            tokio::spawn(async move {
+                                if let Err(e) = tunnel_srv.serve(stream).await {
+                                    debug!("ProxySrv tunnel {addr} closed. cause by {e:?}");
+                                }
+                            });
+                        }
+                        Err(e) => {
+                            warn!("ProxySrv tunnel accept connection err. cause by {e:?}");
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    async fn serve(&self, stream: TcpStream) -> Result<(), Error> {
+        if is_http_connect(&stream).await? {
+            self.serve_http_connect(stream).await
+        } else {
+            self.serve_websocket(stream).await
+        }
+    }
+
+    async fn serve_http_connect(&self, stream: TcpStream) -> Result<(), Error> {
+        let mut reader = BufReader::new(stream);
+        let authority = read_connect_request(&mut reader).await?;
+        debug!("ProxySrv tunnel HTTP CONNECT {authority}");
+        reader.get_mut().write_all(HTTP_CONNECT_ESTABLISHED).await?;
+        // The BufReader keeps whatever the client sent after the request head.
+        let (client_reader, client_writer) = tokio::io::split(reader);
+        self.proxy_srv
+            .connect(
+                client_reader,
+                client_writer,
+                &self.profile,
+                #[cfg(feature = "tls")]
+                &None,
+            )
+            .await
+    }
+
+    async fn serve_websocket(&self, stream: TcpStream) -> Result<(), Error> {
+        let ws_stream = tokio_tungstenite::accept_async(stream)
+            .await
+            .map_err(ws_err)?;
+        let (mut ws_sink, mut ws_source) = ws_stream.split();
+        let (proxy_side, tunnel_side) = tokio::io::duplex(TUNNEL_BUFFER_SIZE);
+        let (mut tunnel_reader, mut tunnel_writer) = tokio::io::split(tunnel_side);
+
+        let inbound = async move {
+            while let Some(msg) = ws_source.next().await {
+                match msg.map_err(ws_err)? {
+                    Message::Binary(data) => tunnel_writer.write_all(&data).await?,
+                    Message::Close(_) => break,
+                    // Pings are answered by tungstenite, text frames are not part of the tunnel.
+                    _ => {}
+                }
+            }
+            tunnel_writer.shutdown().await
+        };
+        let outbound = async move {
+            let mut buf = vec![0_u8; TUNNEL_BUFFER_SIZE];
+            loop {
+                let n = tunnel_reader.read(&mut buf).await?;
+                if n == 0 {
+                    break;
+                }
+                ws_sink
+                    .send(Message::Binary(buf[..n].to_vec()))
+                    .await
+                    .map_err(ws_err)?;
+            }
+            ws_sink.close().await.map_err(ws_err)
+        };
+
+        let (proxy_reader, proxy_writer) = tokio::io::split(proxy_side);
+        let connect = async move {
+            self.proxy_srv
+                .connect(
+                    proxy_reader,
+                    proxy_writer,
+                    &self.profile,
+                    #[cfg(feature = "tls")]
+                    &None,
+                )
+                .await
+        };
+        let (connect_rs, pump_rs) =
+            tokio::join!(connect, async { tokio::try_join!(inbound, outbound) });
+        if let Err(e) = pump_rs {
+            debug!("ProxySrv tunnel websocket closed. cause by {e:?}");
+        }
+        connect_rs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::server::tunnel::{is_http_connect, read_connect_request, MAX_CONNECT_HEAD_SIZE};
+    use std::io::ErrorKind;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::{TcpListener, TcpStream};
+
+    #[tokio::test]
+    pub async fn test_read_connect_request() {
+        let request: &[u8] =
+            b"CONNECT db.example.com:3306 HTTP/1.1\r\nHost: db.example.com:3306\r\n\r\n\x01\x02";
+        let mut reader = BufReader::new(request);
+        let authority = read_connect_request(&mut reader).await.unwrap();
+        assert_eq!(authority, "db.example.com:3306");
+        let mut rest = vec![];
+        reader.read_to_end(&mut rest).await.unwrap();
+        assert_eq!(rest, vec![1, 2]);
+
+        let mut reader = BufReader::new(&b"GET / HTTP/1.1\r\n\r\n"[..]);
+        assert!(read_connect_request(&mut reader).await.is_err());
+
+        // The head is bounded even without a line break.
+        let endless = vec![b'a'; MAX_CONNECT_HEAD_SIZE * 2];
+        let mut reader = BufReader::new(&endless[..]);
+        let err = read_connect_request(&mut reader).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        let mut reader = BufReader::new(&b"CONNECT db:3306 HTTP/1.1\r\nHost: db"[..]);
+        let err = read_connect_request(&mut reader).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    pub async fn test_is_http_connect() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        for (segments, expected) in [
+            (vec![&b"CON"[..], b"NECT db:3306"], true),
+            (vec![&b"GET / HTTP/1.1"[..]], false),
+            (vec![&b"CONN"[..]], false),
+        ] {
+            let client = tokio::spawn(async move {
+                let mut client = TcpStream::connect(addr).await.unwrap();
+                for segment in segments {
+                    client.write_all(segment).await.unwrap();
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                }
+            });
+            let (stream, _) = listener.accept().await.unwrap();
+            assert_eq!(is_http_connect(&stream).await.unwrap(), expected);
+            client.await.unwrap();
+        }
+    }
+}
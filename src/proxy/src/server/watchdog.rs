@@ -0,0 +1,178 @@
+use crate::backend::backend_mgr::BackendMgr;
+
+use common::metrics::common_labels;
+use common::metrics::metric_def::PROXY_WATCHDOG_SHED;
+use common::metrics::process_unix::{process_usage, ProcessUsage};
+use common::ShutdownMessage;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+#[derive(Debug, Clone)]
+pub struct WatchdogConfig {
+    pub interval: Duration,
+    /// Sheds once the RSS exceeds this, 0 disables the memory check.
+    pub max_rss_bytes: u64,
+    /// Sheds once the open FDs exceed this percentage of `RLIMIT_NOFILE`, 0 disables the check.
+    pub max_fds_percent: u64,
+}
+
+impl WatchdogConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.max_rss_bytes > 0 || self.max_fds_percent > 0
+    }
+
+    pub fn pressure(&self, usage: &ProcessUsage) -> Pressure {
+        Pressure {
+            memory: self.max_rss_bytes > 0 && usage.rss_bytes > self.max_rss_bytes,
+            fds: self.max_fds_percent > 0
+                && usage.max_fds > 0
+                && usage.open_fds * 100 > usage.max_fds * self.max_fds_percent,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Pressure {
+    pub memory: bool,
+    pub fds: bool,
+}
+
+/// What the watchdog does with the idle connections of the backend pools.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShedAction {
+    /// Releases the packet buffers of idle connections, they stay in the pool.
+    ShrinkBuffers,
+    /// Closes idle connections, the pools reconnect on demand.
+    CloseIdle,
+}
+
+impl ShedAction {
+    fn label(&self) -> &'static str {
+        match self {
+            ShedAction::ShrinkBuffers => "shrink_buffers",
+            ShedAction::CloseIdle => "close_idle",
+        }
+    }
+}
+
+/// FD pressure closes idle connections right away; memory pressure first shrinks their buffers
+/// and closes them if the pressure persists on the next check.
+#[derive(Debug, Default)]
+pub struct ShedEscalation {
+    buffers_shrunk: bool,
+}
+
+impl ShedEscalation {
+    pub fn next_action(&mut self, pressure: Pressure) -> Option<ShedAction> {
+        if !pressure.memory {
+            self.buffers_shrunk = false;
+        }
+        if pressure.fds || pressure.memory && self.buffers_shrunk {
+            Some(ShedAction::CloseIdle)
+        } else if pressure.memory {
+            self.buffers_shrunk = true;
+            Some(ShedAction::ShrinkBuffers)
+        } else {
+            None
+        }
+    }
+}
+
+/// `ResourceWatchdog` samples the process RSS and open FDs and sheds idle pooled backend
+/// connections before the pod is OOM killed or runs out of FDs, see [`ShedEscalation`].
+pub struct ResourceWatchdog {
+    config: WatchdogConfig,
+    backend_mgr: Arc<BackendMgr>,
+    escalation: ShedEscalation,
+}
+
+impl ResourceWatchdog {
+    pub fn new(config: WatchdogConfig, backend_mgr: Arc<BackendMgr>) -> Self {
+        Self {
+            config,
+            backend_mgr,
+            escalation: ShedEscalation::default(),
+        }
+    }
+
+    fn check(&mut self) {
+        let usage = match process_usage() {
+            Ok(usage) => usage,
+            Err(e) => {
+                warn!("ProxySrv watchdog process usage err. cause by {e:?}");
+                return;
+            }
+        };
+        let Some(action) = self.escalation.next_action(self.config.pressure(&usage)) else {
+            return;
+        };
+        let shed = self.backend_mgr.shed_idle_conns(action);
+        warn!(
+            "ProxySrv watchdog {:?} shed {} idle connections, usage {:?}",
+            action, shed, usage
+        );
+        let mut labels = common_labels().clone();
+        labels.push(("action", action.label().to_string()));
+        common::metrics::counter_inc(PROXY_WATCHDOG_SHED, shed as u64, Some(&labels));
+    }
+
+    pub async fn run(mut self, mut shutdown_rx: watch::Receiver<ShutdownMessage>) {
+        let mut interval = tokio::time::interval(self.config.interval);
+        info!("ProxySrv watchdog started {:?}", self.config);
+        loop {
+            tokio::select! {
+                _ = shutdown_rx.changed() => {
+                    info!("ProxySrv watchdog shutdown");
+                    return;
+                }
+                _ = interval.tick() => self.check(),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::server::watchdog::{Pressure, ShedAction, ShedEscalation, WatchdogConfig};
+    use common::metrics::process_unix::ProcessUsage;
+    use std::time::Duration;
+
+    #[test]
+    pub fn test_watchdog_escalation() {
+        let config = WatchdogConfig {
+            interval: Duration::from_secs(1),
+            max_rss_bytes: 1 << 30,
+            max_fds_percent: 90,
+        };
+        let usage = ProcessUsage {
+            rss_bytes: 1 << 20,
+            open_fds: 950,
+            max_fds: 1024,
+        };
+        assert_eq!(
+            config.pressure(&usage),
+            Pressure {
+                memory: false,
+                fds: true
+            }
+        );
+
+        let mut escalation = ShedEscalation::default();
+        let memory = Pressure {
+            memory: true,
+            fds: false,
+        };
+        assert_eq!(
+            escalation.next_action(memory),
+            Some(ShedAction::ShrinkBuffers)
+        );
+        assert_eq!(escalation.next_action(memory), Some(ShedAction::CloseIdle));
+        assert_eq!(escalation.next_action(Pressure::default()), None);
+        assert_eq!(
+            escalation.next_action(memory),
+            Some(ShedAction::ShrinkBuffers)
+        );
+    }
+}
@@ -0,0 +1,100 @@
+use common::metrics::metric_def::PROXY_WRONG_PROTOCOL_CONN;
+use common::metrics::{common_labels, counter_inc};
+use std::fmt::{Display, Formatter};
+use std::io::{Error, ErrorKind};
+use tracing::warn;
+
+/// Bytes the first packet of a client is sniffed on, those of a MySQL packet header.
+pub const SNIFF_LEN: usize = 4;
+
+/// Request lines of HTTP/1 and the HTTP/2 preface, cut to [`SNIFF_LEN`].
+const HTTP_PREFIXES: [&[u8; SNIFF_LEN]; 10] = [
+    b"GET ", b"POST", b"PUT ", b"HEAD", b"DELE", b"OPTI", b"PATC", b"CONN", b"TRAC", b"PRI ",
+];
+
+/// A protocol clients speak to a MySQL port by mistake. As a MySQL packet header their first
+/// bytes announce a payload of hundreds of KiB or more, the proxy would wait for it until the
+/// client gives up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WrongProtocol {
+    /// A TLS ClientHello, from a client expecting TLS on connect instead of the SSLRequest
+    /// upgrade.
+    Tls,
+    Http,
+}
+
+impl WrongProtocol {
+    /// The wrong protocol the first bytes of a client are in, `None` if they may be MySQL.
+    pub fn sniff(first: &[u8]) -> Option<Self> {
+        match first {
+            // Handshake record of SSL 3.0 up to TLS 1.3.
+            [0x16, 0x03, 0x00..=0x04, ..] => Some(WrongProtocol::Tls),
+            [a, b, c, d, ..] if HTTP_PREFIXES.contains(&&[*a, *b, *c, *d]) => {
+                Some(WrongProtocol::Http)
+            }
+            _ => None,
+        }
+    }
+
+    fn label(&self) -> &'static str {
+        match self {
+            WrongProtocol::Tls => "tls",
+            WrongProtocol::Http => "http",
+        }
+    }
+}
+
+impl Display for WrongProtocol {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.label())
+    }
+}
+
+/// Counts a client of `listener` that speaks `protocol`, and returns the error its connection is
+/// closed with. It is closed without a reply: the client already got the initial handshake, an
+/// HTTP 400 or a TLS alert after it would be as unreadable to it.
+pub fn reject_wrong_protocol(listener: &'static str, protocol: WrongProtocol) -> Error {
+    warn!("ProxySrv {listener} listener closed a {protocol} client, it does not speak MySQL");
+    let mut labels = common_labels().clone();
+    labels.push(("listener", listener.to_string()));
+    labels.push(("protocol", protocol.label().to_string()));
+    counter_inc(PROXY_WRONG_PROTOCOL_CONN, 1, Some(&labels));
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("{protocol} client on the MySQL {listener} listener"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::protocol::mysql::packet::packet_reader::PacketReader;
+    use crate::server::wrong_protocol::{WrongProtocol, SNIFF_LEN};
+
+    #[tokio::test]
+    pub async fn test_sniff() {
+        let client_hello = [0x16, 0x03, 0x01, 0x02, 0x00, 0x01];
+        assert_eq!(
+            WrongProtocol::sniff(&client_hello),
+            Some(WrongProtocol::Tls)
+        );
+        let request = b"GET /metrics HTTP/1.1\r\n";
+        assert_eq!(WrongProtocol::sniff(request), Some(WrongProtocol::Http));
+        assert_eq!(
+            WrongProtocol::sniff(b"PRI * HTTP/2.0\r\n"),
+            Some(WrongProtocol::Http)
+        );
+        // A MySQL packet, and a truncated ClientHello.
+        let packet_bytes = [0x02, 0x00, 0x00, 0x01, 0x05, 0xae];
+        assert_eq!(WrongProtocol::sniff(&packet_bytes), None);
+        assert_eq!(WrongProtocol::sniff(&[0x16, 0x03]), None);
+
+        // Peeking leaves the packet to the reader.
+        let mut reader = PacketReader::new(&packet_bytes[..]);
+        assert_eq!(reader.peek(SNIFF_LEN).await.unwrap(), &packet_bytes[..]);
+        let (seq, packet) = reader.next_async().await.unwrap().unwrap();
+        assert_eq!(seq, 1);
+        assert_eq!(&packet[..], &packet_bytes[4..]);
+        let mut reader = PacketReader::new(&request[..2]);
+        assert_eq!(reader.peek(SNIFF_LEN).await.unwrap(), &request[..2]);
+    }
+}
@@ -0,0 +1,295 @@
+//! Runs the proxy inside the integration tests of other services. [`TestProxyBuilder`] starts a
+//! [`HaentglServer`] routing every client to one backend on an ephemeral port of the loopback,
+//! without the globals `my-proxy` initializes: no metrics, no REST API and, with
+//! [`TestProxyBuilder::with_captured_logs`], no logs on the subscriber of the test.
+
+use crate::backend::backend_mgr::{BackendManagerOptions, BackendMgr};
+use crate::backend::pool::BackendPoolConfig;
+use crate::backend::router::new_backend_router;
+use crate::protocol::mysql::packet::packet_reader::PacketReader;
+use crate::server::auth::authenticator::ProxyAuthenticator;
+use crate::server::haentgl_server::HaentglServer;
+use crate::server::handshake_profile::HandshakeProfile;
+use crate::server::proxy_cli_args::{BackendConfigArgs, ProxyServerArgs};
+
+use common::ShutdownMessage;
+use std::io::{Error, ErrorKind, Write};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tracing::instrument::WithSubscriber;
+use tracing::{debug, dispatcher, info, warn, Dispatch, Level};
+use tracing_subscriber::fmt::MakeWriter;
+
+/// The first byte of the initial handshake, the protocol version.
+const HANDSHAKE_V10: u8 = 10;
+/// Time between two connection attempts of [`wait_for_handshake`].
+const READY_RETRY_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Logs of a [`TestProxy`] kept in memory.
+#[derive(Clone, Default)]
+struct LogBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl Write for LogBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> MakeWriter<'a> for LogBuffer {
+    type Writer = LogBuffer;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self.clone()
+    }
+}
+
+pub struct TestProxyBuilder {
+    backend_addr: String,
+    pool_config: BackendPoolConfig,
+    profile: HandshakeProfile,
+    capture_logs: bool,
+}
+
+impl TestProxyBuilder {
+    /// A proxy of the backend listening on `backend_addr`, e.g. `127.0.0.1:3306`.
+    pub fn new(backend_addr: impl Into<String>) -> Self {
+        Self {
+            backend_addr: backend_addr.into(),
+            pool_config: BackendPoolConfig::default(),
+            profile: HandshakeProfile::default(),
+            capture_logs: false,
+        }
+    }
+
+    pub fn with_pool_config(mut self, pool_config: BackendPoolConfig) -> Self {
+        self.pool_config = pool_config;
+        self
+    }
+
+    pub fn with_handshake_profile(mut self, profile: HandshakeProfile) -> Self {
+        self.profile = profile;
+        self
+    }
+
+    /// Keeps the logs of the listener and its sessions in memory, see [`TestProxy::logs`].
+    /// Tasks the sessions spawn, e.g. those of the pools, still log to the subscriber of the
+    /// test.
+    pub fn with_captured_logs(mut self) -> Self {
+        self.capture_logs = true;
+        self
+    }
+
+    /// Starts the proxy once the pool of the backend is initialized. The backend is not
+    /// connected to before the first client, it may start after the proxy.
+    pub async fn start(self) -> Result<TestProxy, Error> {
+        let logs = self.capture_logs.then(LogBuffer::default);
+        let dispatch = match &logs {
+            Some(logs) => Dispatch::new(
+                tracing_subscriber::fmt()
+                    .with_writer(logs.clone())
+                    .with_ansi(false)
+                    .with_max_level(Level::DEBUG)
+                    .finish(),
+            ),
+            None => dispatcher::get_default(Dispatch::clone),
+        };
+        let (shutdown_tx, shutdown_rx) = watch::channel(ShutdownMessage::Init);
+        let args = ProxyServerArgs {
+            backend: Some(BackendConfigArgs::Backend {
+                backend_addr: self.backend_addr.clone(),
+            }),
+            ..Default::default()
+        };
+        let router = new_backend_router(&args, &shutdown_rx).await;
+        let backend_mgr = Arc::new(BackendMgr::new(
+            router,
+            BackendManagerOptions {
+                pool_config: self.pool_config,
+                ..Default::default()
+            },
+        ));
+        let proxy_srv = Arc::new(HaentglServer::new(backend_mgr, ProxyAuthenticator));
+        proxy_srv
+            .initialize_async()
+            .with_subscriber(dispatch.clone())
+            .await?;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let serving = serve(
+            proxy_srv,
+            listener,
+            addr,
+            Arc::new(self.profile),
+            self.backend_addr,
+            shutdown_rx,
+        );
+        Ok(TestProxy {
+            addr,
+            shutdown_tx,
+            task: tokio::spawn(serving.with_subscriber(dispatch)),
+            logs,
+        })
+    }
+}
+
+/// A running proxy started by [`TestProxyBuilder`]. Dropping it stops the listener, sessions
+/// already connected go on until their client disconnects.
+pub struct TestProxy {
+    addr: SocketAddr,
+    shutdown_tx: watch::Sender<ShutdownMessage>,
+    task: JoinHandle<()>,
+    logs: Option<LogBuffer>,
+}
+
+impl TestProxy {
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn port(&self) -> u16 {
+        self.addr.port()
+    }
+
+    /// Waits until the proxy answers a connection with its initial handshake.
+    pub async fn wait_ready(&self, timeout: Duration) -> Result<(), Error> {
+        wait_for_handshake(&self.addr.to_string(), timeout).await
+    }
+
+    /// The logs captured since the start, empty unless built with
+    /// [`TestProxyBuilder::with_captured_logs`].
+    pub fn logs(&self) -> String {
+        self.logs
+            .as_ref()
+            .map(|logs| String::from_utf8_lossy(&logs.0.lock().unwrap()).into_owned())
+            .unwrap_or_default()
+    }
+
+    /// Stops the listener and waits for it to close.
+    pub async fn shutdown(self) {
+        let _ = self
+            .shutdown_tx
+            .send(ShutdownMessage::Cancel("test proxy shutdown".to_string()));
+        let _ = self.task.await;
+    }
+}
+
+async fn serve(
+    proxy_srv: Arc<HaentglServer<ProxyAuthenticator>>,
+    listener: TcpListener,
+    addr: SocketAddr,
+    profile: Arc<HandshakeProfile>,
+    backend_addr: String,
+    mut shutdown_rx: watch::Receiver<ShutdownMessage>,
+) {
+    info!("ProxySrv test proxy listening on {addr}, backend {backend_addr}");
+    loop {
+        tokio::select! {
+            // Also completes once the TestProxy is dropped.
+            _ = shutdown_rx.changed() => {
+                info!("ProxySrv test proxy on {addr} shutdown");
+                return;
+            }
+            rs = listener.accept() => {
+                match rs {
+                    Ok((stream, client_addr)) => {
+                        let (client_reader, client_writer) = stream.into_split();
+                        let proxy_srv = Arc::clone(&proxy_srv);
+                        let profile = Arc::clone(&profile);
+                        let session = async move {
+                            let connect_rs = proxy_srv
+                                .connect(
+                                    client_reader,
+                                    client_writer,
+                                    &profile,
+                                    #[cfg(feature = "tls")]
+                                    &None,
+                                )
+                                .await;
+                            if let Err(e) = connect_rs {
+                                debug!("ProxySrv test proxy {client_addr} closed. cause by {e:?}");
+                            }
+                        };
+                        tokio::spawn(session.with_current_subscriber());
+                    }
+                    Err(e) => {
+                        warn!("ProxySrv test proxy accept connection err. cause by {e:?}");
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Waits until `addr` answers a connection with a MySQL initial handshake, e.g. the backend a
+/// [`TestProxy`] is started for.
+pub async fn wait_for_handshake(addr: &str, timeout: Duration) -> Result<(), Error> {
+    let waiting = async {
+        loop {
+            match read_handshake(addr).await {
+                Ok(()) => return,
+                Err(e) => {
+                    debug!("ProxySrv {addr} not ready {e:?}");
+                    tokio::time::sleep(READY_RETRY_INTERVAL).await;
+                }
+            }
+        }
+    };
+    tokio::time::timeout(timeout, waiting).await.map_err(|_| {
+        Error::new(
+            ErrorKind::TimedOut,
+            format!("{addr} sent no initial handshake within {timeout:?}"),
+        )
+    })
+}
+
+async fn read_handshake(addr: &str) -> Result<(), Error> {
+    let mut reader = PacketReader::new(TcpStream::connect(addr).await?);
+    match reader.next_async().await? {
+        Some((_, packet)) if packet.first() == Some(&HANDSHAKE_V10) => Ok(()),
+        _ => Err(Error::new(
+            ErrorKind::InvalidData,
+            "not a MySQL initial handshake",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::{wait_for_handshake, TestProxyBuilder};
+    use std::time::Duration;
+    use tokio::net::{TcpListener, TcpStream};
+
+    #[tokio::test]
+    pub async fn test_test_proxy() {
+        // A backend that never answers, the proxy only connects to it for its first client.
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend_addr = backend.local_addr().unwrap().to_string();
+        let timeout = Duration::from_millis(300);
+        assert!(wait_for_handshake(&backend_addr, timeout).await.is_err());
+
+        let proxy = TestProxyBuilder::new(backend_addr.clone())
+            .with_captured_logs()
+            .start()
+            .await
+            .unwrap();
+        assert_ne!(proxy.port(), 0);
+        proxy.wait_ready(Duration::from_secs(5)).await.unwrap();
+        assert!(proxy
+            .logs()
+            .contains(&format!("test proxy listening on {}", proxy.addr())));
+
+        let addr = proxy.addr();
+        proxy.shutdown().await;
+        assert!(TcpStream::connect(addr).await.is_err());
+    }
+}
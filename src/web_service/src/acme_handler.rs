@@ -0,0 +1,13 @@
+use axum::extract::Path;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use proxy::server::acme::solver::http01_key_authorization;
+
+/// Answers the HTTP-01 challenges of the ACME server with their key authorization, in plain
+/// text as RFC 8555 requires.
+pub async fn get_acme_challenge(Path(token): Path<String>) -> impl IntoResponse {
+    match http01_key_authorization(&token) {
+        Some(key_authorization) => (StatusCode::OK, key_authorization),
+        None => (StatusCode::NOT_FOUND, String::new()),
+    }
+}
@@ -0,0 +1,24 @@
+use crate::http_server::ApiResponse;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::Json;
+use proxy::server::auth_limiter::{auth_limiter, AuthLimits};
+
+pub async fn get_auth_limits() -> impl IntoResponse {
+    let resp = ApiResponse {
+        code: u16::from(StatusCode::OK),
+        message: "success".to_string(),
+        data: auth_limiter().status(),
+    };
+    Json(resp)
+}
+
+pub async fn set_auth_limits(Json(payload): Json<AuthLimits>) -> impl IntoResponse {
+    auth_limiter().set_limits(payload);
+    let resp = ApiResponse {
+        code: u16::from(StatusCode::OK),
+        message: "success".to_string(),
+        data: "",
+    };
+    Json(resp)
+}
@@ -0,0 +1,72 @@
+use crate::http_server::ApiResponse;
+use axum::extract::Path;
+use axum::http::{header, StatusCode};
+use axum::response::IntoResponse;
+use axum::Json;
+use proxy::prost::common_proto::TenantKey;
+use proxy::server::packet_capture::{packet_capture, CaptureRequest, CaptureStatus};
+use std::io::ErrorKind;
+
+pub async fn list_captures() -> impl IntoResponse {
+    let resp = match packet_capture().status() {
+        Ok(status) => ApiResponse {
+            code: u16::from(StatusCode::OK),
+            message: "success".to_string(),
+            data: Some(status),
+        },
+        Err(e) => ApiResponse::<Option<CaptureStatus>> {
+            code: u16::from(StatusCode::INTERNAL_SERVER_ERROR),
+            message: e.to_string(),
+            data: None,
+        },
+    };
+    Json(resp)
+}
+
+pub async fn arm_capture(Json(payload): Json<CaptureRequest>) -> impl IntoResponse {
+    let mut resp = ApiResponse {
+        code: u16::from(StatusCode::CREATED),
+        message: "success".to_string(),
+        data: "",
+    };
+    if let Err(e) = packet_capture().arm(payload) {
+        resp.code = u16::from(StatusCode::BAD_REQUEST);
+        resp.message = e.to_string();
+    }
+    Json(resp)
+}
+
+pub async fn disarm_capture(Json(payload): Json<TenantKey>) -> impl IntoResponse {
+    let mut resp = ApiResponse {
+        code: u16::from(StatusCode::OK),
+        message: "success".to_string(),
+        data: "",
+    };
+    if packet_capture().disarm(&payload).is_none() {
+        resp.code = u16::from(StatusCode::NOT_FOUND);
+        resp.message = format!("no capture armed for {:?}", payload);
+    }
+    Json(resp)
+}
+
+/// Downloads a trace in plain text.
+pub async fn download_capture(Path(name): Path<String>) -> impl IntoResponse {
+    match packet_capture().read_trace(&name) {
+        Ok(trace) => (
+            StatusCode::OK,
+            [(header::CONTENT_TYPE, "text/plain; charset=utf-8")],
+            trace,
+        ),
+        Err(e) => {
+            let status = match e.kind() {
+                ErrorKind::NotFound => StatusCode::NOT_FOUND,
+                _ => StatusCode::BAD_REQUEST,
+            };
+            (
+                status,
+                [(header::CONTENT_TYPE, "text/plain; charset=utf-8")],
+                e.to_string().into_bytes(),
+            )
+        }
+    }
+}
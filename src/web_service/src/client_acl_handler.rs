@@ -0,0 +1,67 @@
+use crate::http_server::ApiResponse;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::Json;
+use proxy::prost::common_proto::TenantKey;
+use proxy::server::auth::client_acl::{client_acl, ClientAclRules, TenantClientAcl};
+use std::io::Error;
+
+fn updated(rs: Result<(), Error>, code: StatusCode) -> ApiResponse<&'static str> {
+    match rs {
+        Ok(()) => ApiResponse {
+            code: u16::from(code),
+            message: "success".to_string(),
+            data: "",
+        },
+        Err(e) => ApiResponse {
+            code: u16::from(StatusCode::BAD_REQUEST),
+            message: e.to_string(),
+            data: "",
+        },
+    }
+}
+
+pub async fn list_client_acls() -> impl IntoResponse {
+    let resp = ApiResponse {
+        code: u16::from(StatusCode::OK),
+        message: "success".to_string(),
+        data: client_acl().list(),
+    };
+    Json(resp)
+}
+
+pub async fn set_client_acl(Json(payload): Json<TenantClientAcl>) -> impl IntoResponse {
+    Json(updated(
+        client_acl().set_tenant_acl(payload),
+        StatusCode::CREATED,
+    ))
+}
+
+pub async fn remove_client_acl(Json(payload): Json<TenantKey>) -> impl IntoResponse {
+    let mut resp = ApiResponse {
+        code: u16::from(StatusCode::OK),
+        message: "success".to_string(),
+        data: "",
+    };
+    if client_acl().remove_tenant_acl(&payload).is_none() {
+        resp.code = u16::from(StatusCode::NOT_FOUND);
+        resp.message = format!("no client acl found for {:?}", payload);
+    }
+    Json(resp)
+}
+
+pub async fn get_default_client_acl() -> impl IntoResponse {
+    let resp = ApiResponse {
+        code: u16::from(StatusCode::OK),
+        message: "success".to_string(),
+        data: client_acl().global_rules(),
+    };
+    Json(resp)
+}
+
+pub async fn set_default_client_acl(Json(payload): Json<ClientAclRules>) -> impl IntoResponse {
+    Json(updated(
+        client_acl().set_global_rules(payload),
+        StatusCode::OK,
+    ))
+}
@@ -0,0 +1,67 @@
+use crate::http_server::ApiResponse;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::Json;
+use proxy::prost::common_proto::TenantKey;
+use proxy::protocol::mysql::constants::CommandCode;
+use proxy::server::command_policy::{command_policy, TenantCommandPolicy};
+
+pub async fn list_command_policies() -> impl IntoResponse {
+    let resp = ApiResponse {
+        code: u16::from(StatusCode::OK),
+        message: "success".to_string(),
+        data: command_policy().list(),
+    };
+    Json(resp)
+}
+
+pub async fn set_command_policy(Json(payload): Json<TenantCommandPolicy>) -> impl IntoResponse {
+    command_policy().set_tenant_policy(payload);
+    let resp = ApiResponse {
+        code: u16::from(StatusCode::CREATED),
+        message: "success".to_string(),
+        data: "",
+    };
+    Json(resp)
+}
+
+pub async fn remove_command_policy(Json(payload): Json<TenantKey>) -> impl IntoResponse {
+    let mut resp = ApiResponse {
+        code: u16::from(StatusCode::OK),
+        message: "success".to_string(),
+        data: "",
+    };
+    if command_policy().remove_tenant_policy(&payload).is_none() {
+        resp.code = u16::from(StatusCode::NOT_FOUND);
+        resp.message = format!("no command policy found for {:?}", payload);
+    }
+    Json(resp)
+}
+
+pub async fn get_default_deny() -> impl IntoResponse {
+    let resp = ApiResponse {
+        code: u16::from(StatusCode::OK),
+        message: "success".to_string(),
+        data: command_policy().default_deny(),
+    };
+    Json(resp)
+}
+
+pub async fn set_default_deny(Json(payload): Json<Vec<CommandCode>>) -> impl IntoResponse {
+    command_policy().set_default_deny(payload);
+    let resp = ApiResponse {
+        code: u16::from(StatusCode::OK),
+        message: "success".to_string(),
+        data: "",
+    };
+    Json(resp)
+}
+
+pub async fn list_replication_attempts() -> impl IntoResponse {
+    let resp = ApiResponse {
+        code: u16::from(StatusCode::OK),
+        message: "success".to_string(),
+        data: command_policy().replication_attempts(),
+    };
+    Json(resp)
+}
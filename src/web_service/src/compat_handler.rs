@@ -0,0 +1,24 @@
+use crate::http_server::ApiResponse;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::Json;
+use proxy::server::compat;
+use proxy::server::protocol_features::protocol_features;
+
+pub async fn client_compat_report() -> impl IntoResponse {
+    let resp = ApiResponse {
+        code: u16::from(StatusCode::OK),
+        message: "success".to_string(),
+        data: compat::client_compat_report(),
+    };
+    Json(resp)
+}
+
+pub async fn list_protocol_features() -> impl IntoResponse {
+    let resp = ApiResponse {
+        code: u16::from(StatusCode::OK),
+        message: "success".to_string(),
+        data: protocol_features().list(),
+    };
+    Json(resp)
+}
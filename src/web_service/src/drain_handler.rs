@@ -0,0 +1,56 @@
+use crate::http_server::{ApiResponse, HaentglProxyRestState};
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::Json;
+use proxy::prost::common_proto::TenantKey;
+use proxy::server::drain::{drain_registry, drain_tenant, DrainRequest};
+
+pub async fn list_drains() -> impl IntoResponse {
+    let resp = ApiResponse {
+        code: u16::from(StatusCode::OK),
+        message: "success".to_string(),
+        data: drain_registry().list(),
+    };
+    Json(resp)
+}
+
+pub async fn start_drain(
+    State(state): State<HaentglProxyRestState>,
+    Json(payload): Json<DrainRequest>,
+) -> impl IntoResponse {
+    let tenant = payload.tenant.clone();
+    if drain_registry().start(payload) {
+        tokio::spawn(drain_tenant(state.backend_mgr_ref(), tenant));
+    }
+    let resp = ApiResponse {
+        code: u16::from(StatusCode::CREATED),
+        message: "success".to_string(),
+        data: "",
+    };
+    Json(resp)
+}
+
+pub async fn remove_drain(
+    State(state): State<HaentglProxyRestState>,
+    Json(payload): Json<TenantKey>,
+) -> impl IntoResponse {
+    let mut resp = ApiResponse {
+        code: u16::from(StatusCode::OK),
+        message: "success".to_string(),
+        data: 0,
+    };
+    if drain_registry().remove(&payload).is_none() {
+        resp.code = u16::from(StatusCode::NOT_FOUND);
+        resp.message = format!("no drain found for {:?}", payload);
+        return Json(resp);
+    }
+    match state.backend_mgr_ref().restore_tenant_pools(&payload).await {
+        Ok(restored) => resp.data = restored,
+        Err(e) => {
+            resp.code = u16::from(StatusCode::INTERNAL_SERVER_ERROR);
+            resp.message = format!("pools of {:?} not restored: {e}", payload);
+        }
+    }
+    Json(resp)
+}
@@ -0,0 +1,14 @@
+use crate::http_server::ApiResponse;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::Json;
+use proxy::server::error_stats::error_stats;
+
+pub async fn list_error_codes() -> impl IntoResponse {
+    let resp = ApiResponse {
+        code: u16::from(StatusCode::OK),
+        message: "success".to_string(),
+        data: error_stats().summary(),
+    };
+    Json(resp)
+}
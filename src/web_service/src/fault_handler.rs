@@ -0,0 +1,47 @@
+use crate::http_server::ApiResponse;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::Json;
+use proxy::server::fault_injection::{fault_injector, FaultRule, FaultTarget};
+
+pub async fn list_faults() -> impl IntoResponse {
+    let resp = ApiResponse {
+        code: u16::from(StatusCode::OK),
+        message: "success".to_string(),
+        data: fault_injector().list(),
+    };
+    Json(resp)
+}
+
+pub async fn inject_fault(Json(payload): Json<FaultRule>) -> impl IntoResponse {
+    fault_injector().inject(payload);
+    let resp = ApiResponse {
+        code: u16::from(StatusCode::CREATED),
+        message: "success".to_string(),
+        data: "",
+    };
+    Json(resp)
+}
+
+pub async fn remove_fault(Json(payload): Json<FaultTarget>) -> impl IntoResponse {
+    let mut resp = ApiResponse {
+        code: u16::from(StatusCode::OK),
+        message: "success".to_string(),
+        data: "",
+    };
+    if fault_injector().remove(&payload).is_none() {
+        resp.code = u16::from(StatusCode::NOT_FOUND);
+        resp.message = format!("no fault found for {:?}", payload);
+    }
+    Json(resp)
+}
+
+pub async fn clear_faults() -> impl IntoResponse {
+    fault_injector().clear();
+    let resp = ApiResponse {
+        code: u16::from(StatusCode::OK),
+        message: "success".to_string(),
+        data: "",
+    };
+    Json(resp)
+}
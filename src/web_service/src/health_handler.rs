@@ -0,0 +1,36 @@
+use crate::http_server::{ApiResponse, HaentglProxyRestState};
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::Json;
+use proxy::server::readiness::Readiness;
+
+/// Liveness, the proxy answers as long as its runtime is not stuck.
+pub async fn healthz() -> impl IntoResponse {
+    let resp = ApiResponse {
+        code: u16::from(StatusCode::OK),
+        message: "success".to_string(),
+        data: "",
+    };
+    Json(resp)
+}
+
+/// Readiness, 503 until the router is initialized, a backend is Ready and, with backends synced
+/// from the control plane, the control plane is connected. Probes only read the HTTP status, so
+/// it is set along with the code of the response.
+pub async fn readyz(State(state): State<HaentglProxyRestState>) -> impl IntoResponse {
+    let readiness = Readiness::of(&state.backend_mgr_ref()).await;
+    let status = match readiness.not_ready_reason() {
+        Some(_) => StatusCode::SERVICE_UNAVAILABLE,
+        None => StatusCode::OK,
+    };
+    let resp = ApiResponse {
+        code: u16::from(status),
+        message: readiness
+            .not_ready_reason()
+            .unwrap_or("success")
+            .to_string(),
+        data: readiness,
+    };
+    (status, Json(resp))
+}
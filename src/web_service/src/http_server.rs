@@ -1,13 +1,44 @@
+use crate::acme_handler::*;
+use crate::auth_limits_handler::*;
+use crate::capture_handler::*;
+use crate::client_acl_handler::*;
+use crate::command_policy_handler::*;
+use crate::compat_handler::*;
+use crate::drain_handler::*;
+use crate::error_codes_handler::*;
+use crate::fault_handler::*;
+use crate::health_handler::*;
+use crate::identity_handler::*;
+use crate::long_data_handler::*;
+use crate::maintenance_handler::*;
 use crate::metrics_handler::*;
+use crate::mirror_handler::*;
 use crate::proxy_handler::*;
+use crate::quarantine_handler::*;
+use crate::query_digest_handler::*;
+use crate::rate_limit_handler::*;
+use crate::reload_handler::*;
+use crate::replica_handler::*;
+use crate::route_policy_handler::*;
+use crate::session_handler::*;
+use crate::shard_handler::*;
+use crate::sni_handler::*;
+use crate::sql_privacy_handler::*;
+use crate::status_handler::*;
+use crate::transparent_handler::*;
 
 use anyhow::anyhow;
-use axum::routing::{get, post};
+use axum::extract::Request;
+use axum::http::HeaderValue;
+use axum::middleware::{self, Next};
+use axum::response::Response;
+use axum::routing::{delete, get, post};
 use axum::Router;
 use common::profiling::head_profiler::{HeapProfileOpts, HeapProfiler};
 use common::profiling::prof::Prof;
 use proxy::backend::backend_discovery::{get_backend_discovery, BackendDiscovery};
 use proxy::backend::backend_mgr::BackendMgr;
+use proxy::server::request_id::{new_request_id, with_request_id, REQUEST_ID_HEADER};
 use serde::{Deserialize, Serialize};
 use std::future::Future;
 use std::sync::Arc;
@@ -62,11 +93,28 @@ impl HaentglProxyRestState {
     }
 }
 
+/// Serves every request within a request id, so the log lines of admin actions and the effects
+/// they leave on the data path can be traced back to the operator request.
+async fn request_id_middleware(request: Request, next: Next) -> Response {
+    let request_id = request
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+        .unwrap_or_else(new_request_id);
+    let mut response = with_request_id(request_id.clone(), next.run(request)).await;
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    response
+}
+
 impl HaentglProxyRest {
     pub async fn start_server<F>(
         addr: String,
         port: u16,
         enable_metric: bool,
+        enable_fault_injection: bool,
         app_state: HaentglProxyRestState,
         shutdown: F,
     ) -> anyhow::Result<()>
@@ -75,23 +123,120 @@ impl HaentglProxyRest {
     {
         let mut app = Router::new()
             .route("/", get("Hi I'm Haentgl Proxy WebService"))
+            .route("/healthz", get(healthz))
+            .route("/readyz", get(readyz))
             .route("/mem_dump", get(dump_mem_profile))
             .route("/mem_prof_analysis/:dump_path", get(heap_analysis))
             .route("/start_cpu_prof", get(start_cpu_prof))
             .route("/stop_cpu_prof", get(stop_cpu_prof))
             .route("/list_cpu_profile", get(list_cpu_profile))
             .route("/print_cpu_prof", get(print_cpu_prof))
+            .route("/admin/reload", post(reload_config))
+            .route("/backends/pools", get(list_backend_pools))
+            .route("/backends/pools/draining", get(list_draining_pools))
             .route("/tenant", post(add_tenant))
             .route(
                 "/tenant/:region/:az/:namespace/:cluster/status",
                 get(tenant_status),
             )
+            .route("/auth_limits", get(get_auth_limits).post(set_auth_limits))
+            .route("/capture", get(list_captures).post(arm_capture))
+            .route("/capture/remove", post(disarm_capture))
+            .route("/capture/trace/:name", get(download_capture))
+            .route(
+                "/command_policy",
+                get(list_command_policies).post(set_command_policy),
+            )
+            .route("/command_policy/remove", post(remove_command_policy))
+            .route("/rate_limit", get(list_rate_limits).post(set_rate_limit))
+            .route("/rate_limit/remove", post(remove_rate_limit))
+            .route(
+                "/rate_limit/default",
+                get(get_default_rate_limit).post(set_default_rate_limit),
+            )
+            .route("/rate_limit/status", get(list_rate_limit_statuses))
+            .route("/client_acl", get(list_client_acls).post(set_client_acl))
+            .route("/client_acl/remove", post(remove_client_acl))
+            .route(
+                "/client_acl/default",
+                get(get_default_client_acl).post(set_default_client_acl),
+            )
+            .route("/compat/clients", get(client_compat_report))
+            .route("/compat/features", get(list_protocol_features))
+            .route("/drain", get(list_drains).post(start_drain))
+            .route("/drain/remove", post(remove_drain))
+            .route("/error_codes", get(list_error_codes))
+            .route(
+                "/command_policy/default_deny",
+                get(get_default_deny).post(set_default_deny),
+            )
+            .route(
+                "/command_policy/replication_attempts",
+                get(list_replication_attempts),
+            )
+            .route(
+                "/long_data",
+                get(list_long_data_limits).post(set_long_data_limits),
+            )
+            .route("/long_data/remove", post(remove_long_data_limits))
+            .route(
+                "/long_data/default",
+                get(get_default_long_data_limits).post(set_default_long_data_limits),
+            )
+            .route("/identity", get(list_identities).post(set_identity))
+            .route(
+                "/maintenance",
+                get(list_maintenances).post(schedule_maintenance),
+            )
+            .route("/maintenance/remove", post(cancel_maintenance))
+            .route("/identity/remove", post(remove_identity))
+            .route("/mirror", get(list_mirrors).post(set_mirror))
+            .route("/mirror/remove", post(remove_mirror))
+            .route("/quarantine", get(list_quarantines))
+            .route("/quarantine/release", post(release_quarantine))
+            .route("/replica", get(list_replicas).post(update_replica))
+            .route("/replica/max_lag", post(set_replica_max_lag))
+            .route("/route_policy/cache", delete(clear_route_policy_cache))
+            .route("/session", get(list_sessions))
+            .route("/session/kill", post(kill_sessions))
+            .route(
+                "/session/namespace",
+                get(list_conn_id_namespaces).post(set_conn_id_namespace),
+            )
+            .route("/session/namespace/remove", post(remove_conn_id_namespace))
+            .route("/status", get(status_page))
+            .route("/status/pools", get(list_pools))
+            .route("/status/errors", get(list_recent_errors))
+            .route("/startup-report", get(get_startup_report))
+            .route(
+                "/.well-known/acme-challenge/:token",
+                get(get_acme_challenge),
+            )
+            .route("/shard", get(list_sharded_tenants).post(set_sharded_tenant))
+            .route("/shard/remove", post(remove_sharded_tenant))
+            .route("/sni", get(list_sni_routes).post(set_sni_route))
+            .route("/sni/remove", post(remove_sni_route))
+            .route("/sql_privacy", get(list_sql_privacy).post(set_sql_privacy))
+            .route("/query_digests", get(list_query_digests))
+            .route("/sql_privacy/remove", post(remove_sql_privacy))
+            .route(
+                "/transparent",
+                get(list_transparent_routes).post(set_transparent_route),
+            )
+            .route("/transparent/remove", post(remove_transparent_route))
             .with_state(app_state);
 
         if enable_metric {
             app = app.nest("", route_metrics(MetricsHandler {}));
         }
+        if enable_fault_injection {
+            app = app
+                .route("/fault", get(list_faults).post(inject_fault))
+                .route("/fault/remove", post(remove_fault))
+                .route("/fault/clear", delete(clear_faults));
+        }
 
+        app = app.layer(middleware::from_fn(request_id_middleware));
         app = app.layer(TraceLayer::new_for_http().make_span_with(DefaultMakeSpan::new()));
         // .layer(TimeoutLayer::new(Duration::from_secs(10)));
         let listener = tokio::net::TcpListener::bind(format!("{addr}:{port}"))
@@ -0,0 +1,37 @@
+use crate::http_server::ApiResponse;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::Json;
+use proxy::server::auth::identity::{identity_registry, IdentityKey, IdentityMapping};
+
+pub async fn list_identities() -> impl IntoResponse {
+    let resp = ApiResponse {
+        code: u16::from(StatusCode::OK),
+        message: "success".to_string(),
+        data: identity_registry().list(),
+    };
+    Json(resp)
+}
+
+pub async fn set_identity(Json(payload): Json<IdentityMapping>) -> impl IntoResponse {
+    identity_registry().set_mapping(payload);
+    let resp = ApiResponse {
+        code: u16::from(StatusCode::CREATED),
+        message: "success".to_string(),
+        data: "",
+    };
+    Json(resp)
+}
+
+pub async fn remove_identity(Json(payload): Json<IdentityKey>) -> impl IntoResponse {
+    let mut resp = ApiResponse {
+        code: u16::from(StatusCode::OK),
+        message: "success".to_string(),
+        data: "",
+    };
+    if identity_registry().remove_mapping(&payload).is_none() {
+        resp.code = u16::from(StatusCode::NOT_FOUND);
+        resp.message = format!("no identity mapping found for {:?}", payload);
+    }
+    Json(resp)
+}
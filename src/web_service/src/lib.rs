@@ -1,6 +1,32 @@
 #![feature(once_cell_try)]
 
 // pub(crate) mod http_handler;
+mod acme_handler;
+mod auth_limits_handler;
+mod capture_handler;
+mod client_acl_handler;
+mod command_policy_handler;
+mod compat_handler;
+mod drain_handler;
+mod error_codes_handler;
+mod fault_handler;
+mod health_handler;
 pub mod http_server;
+mod identity_handler;
+mod long_data_handler;
+mod maintenance_handler;
 mod metrics_handler;
+mod mirror_handler;
 mod proxy_handler;
+mod quarantine_handler;
+mod query_digest_handler;
+mod rate_limit_handler;
+mod reload_handler;
+mod replica_handler;
+mod route_policy_handler;
+mod session_handler;
+mod shard_handler;
+mod sni_handler;
+mod sql_privacy_handler;
+mod status_handler;
+mod transparent_handler;
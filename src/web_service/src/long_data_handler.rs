@@ -0,0 +1,59 @@
+use crate::http_server::ApiResponse;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::Json;
+use proxy::prost::common_proto::TenantKey;
+use proxy::server::long_data::{long_data_policy, LongDataLimits, TenantLongDataLimits};
+
+pub async fn list_long_data_limits() -> impl IntoResponse {
+    let resp = ApiResponse {
+        code: u16::from(StatusCode::OK),
+        message: "success".to_string(),
+        data: long_data_policy().list(),
+    };
+    Json(resp)
+}
+
+pub async fn set_long_data_limits(Json(payload): Json<TenantLongDataLimits>) -> impl IntoResponse {
+    long_data_policy().set_tenant_limits(payload);
+    let resp = ApiResponse {
+        code: u16::from(StatusCode::CREATED),
+        message: "success".to_string(),
+        data: "",
+    };
+    Json(resp)
+}
+
+pub async fn remove_long_data_limits(Json(payload): Json<TenantKey>) -> impl IntoResponse {
+    let mut resp = ApiResponse {
+        code: u16::from(StatusCode::OK),
+        message: "success".to_string(),
+        data: "",
+    };
+    if long_data_policy().remove_tenant_limits(&payload).is_none() {
+        resp.code = u16::from(StatusCode::NOT_FOUND);
+        resp.message = format!("no long data limits found for {:?}", payload);
+    }
+    Json(resp)
+}
+
+pub async fn get_default_long_data_limits() -> impl IntoResponse {
+    let resp = ApiResponse {
+        code: u16::from(StatusCode::OK),
+        message: "success".to_string(),
+        data: long_data_policy().default_limits(),
+    };
+    Json(resp)
+}
+
+pub async fn set_default_long_data_limits(
+    Json(payload): Json<LongDataLimits>,
+) -> impl IntoResponse {
+    long_data_policy().set_default_limits(payload);
+    let resp = ApiResponse {
+        code: u16::from(StatusCode::OK),
+        message: "success".to_string(),
+        data: "",
+    };
+    Json(resp)
+}
@@ -0,0 +1,44 @@
+use crate::http_server::ApiResponse;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::Json;
+use proxy::prost::common_proto::TenantKey;
+use proxy::server::maintenance::{maintenance_registry, MaintenanceNotice};
+
+pub async fn list_maintenances() -> impl IntoResponse {
+    let resp = ApiResponse {
+        code: u16::from(StatusCode::OK),
+        message: "success".to_string(),
+        data: maintenance_registry().list(),
+    };
+    Json(resp)
+}
+
+pub async fn schedule_maintenance(Json(payload): Json<MaintenanceNotice>) -> impl IntoResponse {
+    let resp = match maintenance_registry().schedule(payload) {
+        Ok(()) => ApiResponse {
+            code: u16::from(StatusCode::CREATED),
+            message: "success".to_string(),
+            data: "",
+        },
+        Err(e) => ApiResponse {
+            code: u16::from(StatusCode::BAD_REQUEST),
+            message: e.to_string(),
+            data: "",
+        },
+    };
+    Json(resp)
+}
+
+pub async fn cancel_maintenance(Json(payload): Json<TenantKey>) -> impl IntoResponse {
+    let mut resp = ApiResponse {
+        code: u16::from(StatusCode::OK),
+        message: "success".to_string(),
+        data: "",
+    };
+    if maintenance_registry().cancel(&payload).is_none() {
+        resp.code = u16::from(StatusCode::NOT_FOUND);
+        resp.message = format!("no maintenance found for {:?}", payload);
+    }
+    Json(resp)
+}
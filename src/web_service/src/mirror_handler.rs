@@ -0,0 +1,44 @@
+use crate::http_server::ApiResponse;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::Json;
+use proxy::prost::common_proto::TenantKey;
+use proxy::server::mirror::{mirror_policy, TenantMirror};
+
+pub async fn list_mirrors() -> impl IntoResponse {
+    let resp = ApiResponse {
+        code: u16::from(StatusCode::OK),
+        message: "success".to_string(),
+        data: mirror_policy().list(),
+    };
+    Json(resp)
+}
+
+pub async fn set_mirror(Json(payload): Json<TenantMirror>) -> impl IntoResponse {
+    let resp = match mirror_policy().set_mirror(payload) {
+        Ok(()) => ApiResponse {
+            code: u16::from(StatusCode::CREATED),
+            message: "success".to_string(),
+            data: "",
+        },
+        Err(e) => ApiResponse {
+            code: u16::from(StatusCode::BAD_REQUEST),
+            message: e.to_string(),
+            data: "",
+        },
+    };
+    Json(resp)
+}
+
+pub async fn remove_mirror(Json(payload): Json<TenantKey>) -> impl IntoResponse {
+    let mut resp = ApiResponse {
+        code: u16::from(StatusCode::OK),
+        message: "success".to_string(),
+        data: "",
+    };
+    if mirror_policy().remove_mirror(&payload).is_none() {
+        resp.code = u16::from(StatusCode::NOT_FOUND);
+        resp.message = format!("no mirror found for {:?}", payload);
+    }
+    Json(resp)
+}
@@ -0,0 +1,27 @@
+use crate::http_server::ApiResponse;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::Json;
+use proxy::backend::quarantine::{quarantine_registry, QuarantineRelease};
+
+pub async fn list_quarantines() -> impl IntoResponse {
+    let resp = ApiResponse {
+        code: u16::from(StatusCode::OK),
+        message: "success".to_string(),
+        data: quarantine_registry().list(),
+    };
+    Json(resp)
+}
+
+pub async fn release_quarantine(Json(payload): Json<QuarantineRelease>) -> impl IntoResponse {
+    let mut resp = ApiResponse {
+        code: u16::from(StatusCode::OK),
+        message: "success".to_string(),
+        data: "",
+    };
+    if !quarantine_registry().release(&payload.addr) {
+        resp.code = u16::from(StatusCode::NOT_FOUND);
+        resp.message = format!("no quarantined backend found for {:?}", payload.addr);
+    }
+    Json(resp)
+}
@@ -0,0 +1,22 @@
+use crate::http_server::ApiResponse;
+use axum::extract::Query;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::Json;
+use proxy::server::query_digest::query_digests;
+use std::collections::HashMap;
+
+/// The statements aggregated by their fingerprint, the longest total time first.
+pub async fn list_query_digests(
+    Query(params): Query<HashMap<String, String>>,
+) -> impl IntoResponse {
+    let limit = params
+        .get("limit")
+        .and_then(|limit| limit.parse::<usize>().ok());
+    let resp = ApiResponse {
+        code: u16::from(StatusCode::OK),
+        message: "success".to_string(),
+        data: query_digests().statuses(limit),
+    };
+    Json(resp)
+}
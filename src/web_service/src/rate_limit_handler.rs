@@ -0,0 +1,66 @@
+use crate::http_server::ApiResponse;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::Json;
+use proxy::server::rate_limit::{command_rate_limiter, RateLimit, RateLimitKey, UserRateLimit};
+
+pub async fn list_rate_limits() -> impl IntoResponse {
+    let resp = ApiResponse {
+        code: u16::from(StatusCode::OK),
+        message: "success".to_string(),
+        data: command_rate_limiter().list(),
+    };
+    Json(resp)
+}
+
+pub async fn set_rate_limit(Json(payload): Json<UserRateLimit>) -> impl IntoResponse {
+    command_rate_limiter().set_user_limit(payload);
+    let resp = ApiResponse {
+        code: u16::from(StatusCode::CREATED),
+        message: "success".to_string(),
+        data: "",
+    };
+    Json(resp)
+}
+
+pub async fn remove_rate_limit(Json(payload): Json<RateLimitKey>) -> impl IntoResponse {
+    let mut resp = ApiResponse {
+        code: u16::from(StatusCode::OK),
+        message: "success".to_string(),
+        data: "",
+    };
+    if command_rate_limiter().remove_user_limit(&payload).is_none() {
+        resp.code = u16::from(StatusCode::NOT_FOUND);
+        resp.message = format!("no rate limit found for {:?}", payload);
+    }
+    Json(resp)
+}
+
+pub async fn get_default_rate_limit() -> impl IntoResponse {
+    let resp = ApiResponse {
+        code: u16::from(StatusCode::OK),
+        message: "success".to_string(),
+        data: command_rate_limiter().default_limit(),
+    };
+    Json(resp)
+}
+
+pub async fn set_default_rate_limit(Json(payload): Json<RateLimit>) -> impl IntoResponse {
+    command_rate_limiter().set_default_limit(payload);
+    let resp = ApiResponse {
+        code: u16::from(StatusCode::OK),
+        message: "success".to_string(),
+        data: "",
+    };
+    Json(resp)
+}
+
+/// The users whose commands were delayed or rejected above their rate.
+pub async fn list_rate_limit_statuses() -> impl IntoResponse {
+    let resp = ApiResponse {
+        code: u16::from(StatusCode::OK),
+        message: "success".to_string(),
+        data: command_rate_limiter().statuses(),
+    };
+    Json(resp)
+}
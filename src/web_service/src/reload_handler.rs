@@ -0,0 +1,28 @@
+use crate::http_server::ApiResponse;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::Json;
+use proxy::server::reload::{config_reloader, ReloadReport};
+
+pub async fn reload_config() -> impl IntoResponse {
+    let reloaded = match config_reloader() {
+        Some(reloader) => reloader.reload(),
+        None => Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            "configuration reload is not enabled",
+        )),
+    };
+    let resp = match reloaded {
+        Ok(report) => ApiResponse {
+            code: u16::from(StatusCode::OK),
+            message: "success".to_string(),
+            data: report,
+        },
+        Err(e) => ApiResponse {
+            code: u16::from(StatusCode::BAD_REQUEST),
+            message: e.to_string(),
+            data: ReloadReport::default(),
+        },
+    };
+    Json(resp)
+}
@@ -0,0 +1,36 @@
+use crate::http_server::ApiResponse;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::Json;
+use proxy::backend::replica::{replica_registry, ReplicaStatus, TenantMaxLag};
+use std::time::Duration;
+
+pub async fn list_replicas() -> impl IntoResponse {
+    let resp = ApiResponse {
+        code: u16::from(StatusCode::OK),
+        message: "success".to_string(),
+        data: replica_registry().list(),
+    };
+    Json(resp)
+}
+
+pub async fn update_replica(Json(payload): Json<ReplicaStatus>) -> impl IntoResponse {
+    replica_registry().update(payload);
+    let resp = ApiResponse {
+        code: u16::from(StatusCode::OK),
+        message: "success".to_string(),
+        data: "",
+    };
+    Json(resp)
+}
+
+pub async fn set_replica_max_lag(Json(payload): Json<TenantMaxLag>) -> impl IntoResponse {
+    replica_registry()
+        .set_tenant_max_lag(payload.tenant, Duration::from_millis(payload.max_lag_ms));
+    let resp = ApiResponse {
+        code: u16::from(StatusCode::OK),
+        message: "success".to_string(),
+        data: "",
+    };
+    Json(resp)
+}
@@ -0,0 +1,16 @@
+use crate::http_server::ApiResponse;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::Json;
+use proxy::server::route_policy::route_policy;
+
+/// Forgets the cached route policy decisions, e.g. once the policy changed. Returns the number of
+/// decisions forgotten.
+pub async fn clear_route_policy_cache() -> impl IntoResponse {
+    let resp = ApiResponse {
+        code: u16::from(StatusCode::OK),
+        message: "success".to_string(),
+        data: route_policy().clear_cache(),
+    };
+    Json(resp)
+}
@@ -0,0 +1,79 @@
+use crate::http_server::ApiResponse;
+use axum::extract::Query;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::Json;
+use proxy::prost::common_proto::TenantKey;
+use proxy::server::session::{session_registry, ConnIdNamespace, KillSessions};
+use std::collections::HashMap;
+
+pub async fn list_sessions(Query(params): Query<HashMap<String, String>>) -> impl IntoResponse {
+    let limit = params
+        .get("limit")
+        .and_then(|limit| limit.parse::<usize>().ok());
+    let resp = ApiResponse {
+        code: u16::from(StatusCode::OK),
+        message: "success".to_string(),
+        data: session_registry().top_by_memory(limit),
+    };
+    Json(resp)
+}
+
+pub async fn kill_sessions(Json(payload): Json<KillSessions>) -> impl IntoResponse {
+    let registry = session_registry();
+    let mut killed = payload
+        .ids
+        .into_iter()
+        .filter(|id| registry.kill(*id))
+        .collect::<Vec<_>>();
+    if let Some(heaviest) = payload.heaviest {
+        killed.extend(registry.kill_heaviest(heaviest));
+    }
+    let resp = ApiResponse {
+        code: u16::from(StatusCode::OK),
+        message: "success".to_string(),
+        data: killed,
+    };
+    Json(resp)
+}
+
+pub async fn list_conn_id_namespaces() -> impl IntoResponse {
+    let resp = ApiResponse {
+        code: u16::from(StatusCode::OK),
+        message: "success".to_string(),
+        data: session_registry().conn_id_namespaces(),
+    };
+    Json(resp)
+}
+
+pub async fn set_conn_id_namespace(Json(payload): Json<ConnIdNamespace>) -> impl IntoResponse {
+    let resp = match session_registry().set_conn_id_namespace(payload) {
+        Ok(()) => ApiResponse {
+            code: u16::from(StatusCode::CREATED),
+            message: "success".to_string(),
+            data: "",
+        },
+        Err(e) => ApiResponse {
+            code: u16::from(StatusCode::BAD_REQUEST),
+            message: e.to_string(),
+            data: "",
+        },
+    };
+    Json(resp)
+}
+
+pub async fn remove_conn_id_namespace(Json(payload): Json<TenantKey>) -> impl IntoResponse {
+    let mut resp = ApiResponse {
+        code: u16::from(StatusCode::OK),
+        message: "success".to_string(),
+        data: "",
+    };
+    if session_registry()
+        .remove_conn_id_namespace(&payload)
+        .is_none()
+    {
+        resp.code = u16::from(StatusCode::NOT_FOUND);
+        resp.message = format!("no conn id namespace found for {:?}", payload);
+    }
+    Json(resp)
+}
@@ -0,0 +1,44 @@
+use crate::http_server::ApiResponse;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::Json;
+use proxy::backend::shard::{shard_registry, ShardedTenant};
+use proxy::prost::common_proto::TenantKey;
+
+pub async fn list_sharded_tenants() -> impl IntoResponse {
+    let resp = ApiResponse {
+        code: u16::from(StatusCode::OK),
+        message: "success".to_string(),
+        data: shard_registry().list(),
+    };
+    Json(resp)
+}
+
+pub async fn set_sharded_tenant(Json(payload): Json<ShardedTenant>) -> impl IntoResponse {
+    let resp = match shard_registry().set_tenant(payload) {
+        Ok(()) => ApiResponse {
+            code: u16::from(StatusCode::CREATED),
+            message: "success".to_string(),
+            data: "",
+        },
+        Err(e) => ApiResponse {
+            code: u16::from(StatusCode::BAD_REQUEST),
+            message: e.to_string(),
+            data: "",
+        },
+    };
+    Json(resp)
+}
+
+pub async fn remove_sharded_tenant(Json(payload): Json<TenantKey>) -> impl IntoResponse {
+    let mut resp = ApiResponse {
+        code: u16::from(StatusCode::OK),
+        message: "success".to_string(),
+        data: "",
+    };
+    if shard_registry().remove_tenant(&payload).is_none() {
+        resp.code = u16::from(StatusCode::NOT_FOUND);
+        resp.message = format!("no sharded tenant found for {:?}", payload);
+    }
+    Json(resp)
+}
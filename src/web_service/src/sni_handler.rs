@@ -0,0 +1,49 @@
+use crate::http_server::ApiResponse;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::Json;
+use proxy::server::sni_router::{sni_router, SniRoute};
+use serde::Deserialize;
+
+#[derive(Debug, Deserialize)]
+pub struct RemoveSniRoute {
+    pub hostname: String,
+}
+
+pub async fn list_sni_routes() -> impl IntoResponse {
+    let resp = ApiResponse {
+        code: u16::from(StatusCode::OK),
+        message: "success".to_string(),
+        data: sni_router().list(),
+    };
+    Json(resp)
+}
+
+pub async fn set_sni_route(Json(payload): Json<SniRoute>) -> impl IntoResponse {
+    let resp = match sni_router().set_route(payload) {
+        Ok(()) => ApiResponse {
+            code: u16::from(StatusCode::CREATED),
+            message: "success".to_string(),
+            data: "",
+        },
+        Err(e) => ApiResponse {
+            code: u16::from(StatusCode::BAD_REQUEST),
+            message: e.to_string(),
+            data: "",
+        },
+    };
+    Json(resp)
+}
+
+pub async fn remove_sni_route(Json(payload): Json<RemoveSniRoute>) -> impl IntoResponse {
+    let mut resp = ApiResponse {
+        code: u16::from(StatusCode::OK),
+        message: "success".to_string(),
+        data: "",
+    };
+    if sni_router().remove_route(&payload.hostname).is_none() {
+        resp.code = u16::from(StatusCode::NOT_FOUND);
+        resp.message = format!("no SNI route found for {:?}", payload);
+    }
+    Json(resp)
+}
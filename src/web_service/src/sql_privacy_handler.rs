@@ -0,0 +1,38 @@
+use crate::http_server::ApiResponse;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::Json;
+use proxy::prost::common_proto::TenantKey;
+use proxy::server::sql_privacy::{sql_privacy, TenantSqlPrivacy};
+
+pub async fn list_sql_privacy() -> impl IntoResponse {
+    let resp = ApiResponse {
+        code: u16::from(StatusCode::OK),
+        message: "success".to_string(),
+        data: sql_privacy().settings(),
+    };
+    Json(resp)
+}
+
+pub async fn set_sql_privacy(Json(payload): Json<TenantSqlPrivacy>) -> impl IntoResponse {
+    sql_privacy().set(payload);
+    let resp = ApiResponse {
+        code: u16::from(StatusCode::CREATED),
+        message: "success".to_string(),
+        data: "",
+    };
+    Json(resp)
+}
+
+pub async fn remove_sql_privacy(Json(payload): Json<TenantKey>) -> impl IntoResponse {
+    let mut resp = ApiResponse {
+        code: u16::from(StatusCode::OK),
+        message: "success".to_string(),
+        data: "",
+    };
+    if sql_privacy().remove(&payload).is_none() {
+        resp.code = u16::from(StatusCode::NOT_FOUND);
+        resp.message = format!("no sql privacy setting found for {:?}", payload);
+    }
+    Json(resp)
+}
@@ -0,0 +1,180 @@
+use crate::http_server::{ApiResponse, HaentglProxyRestState};
+use axum::extract::{Query, State};
+use axum::http::StatusCode;
+use axum::response::{Html, IntoResponse};
+use axum::Json;
+use proxy::backend::replica::replica_registry;
+use proxy::server::recent_errors::recent_errors;
+use proxy::server::session::session_registry;
+use proxy::server::startup_report::startup_report;
+use std::collections::HashMap;
+use std::fmt::Write;
+
+const DEFAULT_REFRESH_SECS: u64 = 5;
+/// Sessions listed on the status page, the heaviest first.
+const STATUS_SESSION_LIMIT: usize = 50;
+
+pub async fn list_pools(State(state): State<HaentglProxyRestState>) -> impl IntoResponse {
+    let resp = ApiResponse {
+        code: u16::from(StatusCode::OK),
+        message: "success".to_string(),
+        data: state.backend_mgr_ref().pool_statuses(),
+    };
+    Json(resp)
+}
+
+pub async fn list_backend_pools(State(state): State<HaentglProxyRestState>) -> impl IntoResponse {
+    let resp = ApiResponse {
+        code: u16::from(StatusCode::OK),
+        message: "success".to_string(),
+        data: state.backend_mgr_ref().pool_stats(),
+    };
+    Json(resp)
+}
+
+/// The pools of Offline backends waiting for their sessions to hand back the connections.
+pub async fn list_draining_pools(State(state): State<HaentglProxyRestState>) -> impl IntoResponse {
+    let resp = ApiResponse {
+        code: u16::from(StatusCode::OK),
+        message: "success".to_string(),
+        data: state.backend_mgr_ref().draining_pools(),
+    };
+    Json(resp)
+}
+
+pub async fn list_recent_errors() -> impl IntoResponse {
+    let resp = ApiResponse {
+        code: u16::from(StatusCode::OK),
+        message: "success".to_string(),
+        data: recent_errors().list(),
+    };
+    Json(resp)
+}
+
+/// The configuration the proxy came up in, 503 while it is still starting.
+pub async fn get_startup_report() -> impl IntoResponse {
+    let mut resp = ApiResponse {
+        code: u16::from(StatusCode::OK),
+        message: "success".to_string(),
+        data: startup_report().cloned(),
+    };
+    if resp.data.is_none() {
+        resp.code = u16::from(StatusCode::SERVICE_UNAVAILABLE);
+        resp.message = "the proxy is starting".to_string();
+    }
+    Json(resp)
+}
+
+fn escape_html(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn write_table(html: &mut String, title: &str, header: &[&str], rows: Vec<Vec<String>>) {
+    let _ = write!(html, "<h2>{title}</h2><table><tr>");
+    header.iter().for_each(|column| {
+        let _ = write!(html, "<th>{column}</th>");
+    });
+    html.push_str("</tr>");
+    rows.iter().for_each(|row| {
+        html.push_str("<tr>");
+        row.iter().for_each(|cell| {
+            let _ = write!(html, "<td>{}</td>", escape_html(cell));
+        });
+        html.push_str("</tr>");
+    });
+    html.push_str("</table>");
+}
+
+/// Renders sessions, backend pools and recent errors as a self refreshing HTML page, for
+/// deployments without a metrics dashboard. `refresh` sets the refresh interval in seconds.
+pub async fn status_page(
+    State(state): State<HaentglProxyRestState>,
+    Query(params): Query<HashMap<String, String>>,
+) -> impl IntoResponse {
+    let refresh = params
+        .get("refresh")
+        .and_then(|refresh| refresh.parse::<u64>().ok())
+        .unwrap_or(DEFAULT_REFRESH_SECS);
+    let mut html = String::new();
+    let _ = write!(
+        html,
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\">\
+         <meta http-equiv=\"refresh\" content=\"{refresh}\"><title>Haentgl Proxy Status</title>\
+         <style>body{{font-family:sans-serif}}table{{border-collapse:collapse;margin-bottom:1em}}\
+         th,td{{border:1px solid #ccc;padding:2px 8px;text-align:left}}</style></head><body>\
+         <h1>Haentgl Proxy Status</h1>"
+    );
+
+    let replicas = replica_registry();
+    let pools: Vec<_> = state
+        .backend_mgr_ref()
+        .pool_statuses()
+        .into_iter()
+        .map(|pool| {
+            vec![
+                format!("{:?}", replicas.role(&pool.addr)),
+                pool.cluster,
+                pool.status,
+                format!("{}/{}", pool.size, pool.max_size),
+                pool.available.to_string(),
+                pool.waiting.to_string(),
+                pool.in_flight.to_string(),
+                pool.addr,
+            ]
+        })
+        .collect();
+    write_table(
+        &mut html,
+        &format!("Backends ({})", pools.len()),
+        &[
+            "Role",
+            "Cluster",
+            "Status",
+            "Pool Size",
+            "Available",
+            "Waiting",
+            "In Flight",
+            "Address",
+        ],
+        pools,
+    );
+
+    let registry = session_registry();
+    let sessions = registry
+        .top_by_memory(Some(STATUS_SESSION_LIMIT))
+        .into_iter()
+        .map(|session| {
+            vec![
+                session.id.to_string(),
+                session.tenant,
+                session.user,
+                session.connected_at,
+                session.memory_bytes.to_string(),
+            ]
+        })
+        .collect();
+    write_table(
+        &mut html,
+        &format!("Sessions ({} connected, heaviest first)", registry.len()),
+        &["Id", "Tenant", "User", "Connected At", "Memory Bytes"],
+        sessions,
+    );
+
+    let errors: Vec<_> = recent_errors()
+        .list()
+        .into_iter()
+        .map(|error| vec![error.time, error.source, error.message])
+        .collect();
+    write_table(
+        &mut html,
+        &format!("Recent Errors ({})", errors.len()),
+        &["Time", "Source", "Message"],
+        errors,
+    );
+    html.push_str("</body></html>");
+    Html(html)
+}
@@ -0,0 +1,56 @@
+use crate::http_server::ApiResponse;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::Json;
+use proxy::server::transparent::{transparent_router, TransparentRoute};
+use serde::Deserialize;
+
+#[derive(Debug, Deserialize)]
+pub struct RemoveTransparentRoute {
+    pub destination: String,
+    #[serde(default)]
+    pub port: Option<u16>,
+}
+
+pub async fn list_transparent_routes() -> impl IntoResponse {
+    let resp = ApiResponse {
+        code: u16::from(StatusCode::OK),
+        message: "success".to_string(),
+        data: transparent_router().list(),
+    };
+    Json(resp)
+}
+
+pub async fn set_transparent_route(Json(payload): Json<TransparentRoute>) -> impl IntoResponse {
+    let resp = match transparent_router().set_route(payload) {
+        Ok(()) => ApiResponse {
+            code: u16::from(StatusCode::CREATED),
+            message: "success".to_string(),
+            data: "",
+        },
+        Err(e) => ApiResponse {
+            code: u16::from(StatusCode::BAD_REQUEST),
+            message: e.to_string(),
+            data: "",
+        },
+    };
+    Json(resp)
+}
+
+pub async fn remove_transparent_route(
+    Json(payload): Json<RemoveTransparentRoute>,
+) -> impl IntoResponse {
+    let mut resp = ApiResponse {
+        code: u16::from(StatusCode::OK),
+        message: "success".to_string(),
+        data: "",
+    };
+    if transparent_router()
+        .remove_route(&payload.destination, payload.port)
+        .is_none()
+    {
+        resp.code = u16::from(StatusCode::NOT_FOUND);
+        resp.message = format!("no transparent route found for {:?}", payload);
+    }
+    Json(resp)
+}